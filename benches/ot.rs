@@ -0,0 +1,120 @@
+//! Benchmarks for applying and transforming Events, run with `cargo
+//! bench`
+#[macro_use]
+extern crate criterion;
+extern crate tamawiki;
+
+use criterion::Criterion;
+use tamawiki::document::{Delete, Document, Edit, Event, Insert, Join, Operation, Role};
+
+// The number of concurrent events a late edit is transformed against
+const CONCURRENT_EVENTS: usize = 1000;
+
+fn insert(author: usize, pos: usize, content: &str) -> Event {
+    Event::Edit(Edit {
+        author,
+        operations: vec![Operation::Insert(Insert {
+            pos,
+            content: String::from(content),
+        })],
+    })
+}
+
+fn delete(author: usize, start: usize, end: usize) -> Event {
+    Event::Edit(Edit {
+        author,
+        operations: vec![Operation::Delete(Delete { start, end })],
+    })
+}
+
+// A document with 10,000 characters of content and one participant
+fn document() -> Document {
+    let mut doc = Document::default();
+    doc.apply(&Event::Join(Join {
+        id: 1,
+        role: Role::Editor,
+        user: None,
+    })).unwrap();
+    doc.apply(&insert(1, 0, &"Lorem ipsum ".repeat(834)[..10000]))
+        .unwrap();
+    doc
+}
+
+// Edits by another participant, alternately inserting and deleting
+// throughout the document
+fn concurrent_events() -> Vec<Event> {
+    (0..CONCURRENT_EVENTS)
+        .map(|i| {
+            let pos = (i * 37) % 5000;
+            if i % 2 == 0 {
+                insert(2, pos, "dolor ")
+            } else {
+                delete(2, pos, pos + 3)
+            }
+        }).collect()
+}
+
+fn apply(c: &mut Criterion) {
+    let doc = document();
+    let edit = insert(1, 5000, "sit amet ");
+    c.bench_function("Document::apply", move |b| {
+        b.iter_with_setup(|| doc.clone(), |mut doc| doc.apply(&edit).unwrap())
+    });
+}
+
+fn transform(c: &mut Criterion) {
+    let edit = Event::Edit(Edit {
+        author: 1,
+        operations: vec![
+            Operation::Insert(Insert {
+                pos: 100,
+                content: String::from("sit amet "),
+            }),
+            Operation::Delete(Delete {
+                start: 200,
+                end: 210,
+            }),
+        ],
+    });
+    let concurrent = Event::Edit(Edit {
+        author: 2,
+        operations: vec![
+            Operation::Delete(Delete {
+                start: 50,
+                end: 150,
+            }),
+            Operation::Insert(Insert {
+                pos: 205,
+                content: String::from("dolor "),
+            }),
+        ],
+    });
+    c.bench_function("Event::transform", move |b| {
+        b.iter_with_setup(
+            || edit.clone(),
+            |mut edit| {
+                edit.transform(&concurrent);
+                edit
+            },
+        )
+    });
+}
+
+fn catchup_transform(c: &mut Criterion) {
+    let edit = insert(1, 2500, "sit amet ");
+    let concurrent = concurrent_events();
+    c.bench_function("Event::transform 1k concurrent events", move |b| {
+        b.iter_with_setup(
+            || edit.clone(),
+            |mut edit| {
+                for event in &concurrent {
+                    edit.transform(event);
+                }
+                edit
+            },
+        )
+    });
+}
+
+criterion_group!(benches, apply, transform, catchup_transform);
+criterion_main!(benches);
@@ -22,9 +22,9 @@ use std::io::{Read, Write};
 use std::path::Path;
 use syn::Ident;
 
-const SHARED_APPLY_TESTS: &'static str = "./public/src/_static/js/tests/shared/apply.json";
+const SHARED_APPLY_TESTS: &str = "./public/src/_static/js/tests/shared/apply.json";
 
-const SHARED_TRANSFORM_TESTS: &'static str = "./public/src/_static/js/tests/shared/transform.json";
+const SHARED_TRANSFORM_TESTS: &str = "./public/src/_static/js/tests/shared/transform.json";
 
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
@@ -35,7 +35,7 @@ fn main() {
     println!("cargo:rerun-if-changed={}", SHARED_TRANSFORM_TESTS);
 
     outfile
-        .write(b"// WARNING this file is auto-generated by build.rs. Do not edit directly!\n")
+        .write_all(b"// WARNING this file is auto-generated by build.rs. Do not edit directly!\n")
         .unwrap();
 
     let mut apply_file = File::open(SHARED_APPLY_TESTS).unwrap();
@@ -45,7 +45,7 @@ fn main() {
     let apply_configs: Vec<ApplyTestConfig> = serde_json::from_str(&apply_content).unwrap();
 
     for config in apply_configs.into_iter() {
-        write_apply_test(&mut outfile, config);
+        write_apply_test(&outfile, config);
     }
 
     let mut transform_file = File::open(SHARED_TRANSFORM_TESTS).unwrap();
@@ -58,7 +58,7 @@ fn main() {
         serde_json::from_str(&transform_content).unwrap();
 
     for config in transform_configs.into_iter() {
-        write_transform_test(&mut outfile, config);
+        write_transform_test(&outfile, config);
     }
 
     // Format the generated tests using rustfmt, otherwise the test
@@ -94,7 +94,7 @@ struct TransformTestConfig {
 
 fn field<F>(tokens: &mut TokenStream, name: &'static str, mut f: F)
 where
-    F: FnMut(&mut TokenStream) -> (),
+    F: FnMut(&mut TokenStream),
 {
     tokens.append(Ident::new(name, Span::call_site()));
     tokens.append(Punct::new(':', Spacing::Joint));
@@ -102,6 +102,38 @@ where
     tokens.append(Punct::new(',', Spacing::Joint));
 }
 
+// Emits Some(String::from("...")) or None
+fn optional_string(tokens: &mut TokenStream, value: &Option<String>) {
+    match *value {
+        Some(ref value) => {
+            tokens.append(Ident::new("Some", Span::call_site()));
+            let mut inner = TokenStream::new();
+            inner.append(Ident::new("String", Span::call_site()));
+            inner.append(Punct::new(':', Spacing::Joint));
+            inner.append(Punct::new(':', Spacing::Alone));
+            inner.append(Ident::new("from", Span::call_site()));
+            let mut arg = TokenStream::new();
+            value.to_tokens(&mut arg);
+            inner.append(Group::new(Delimiter::Parenthesis, arg));
+            tokens.append(Group::new(Delimiter::Parenthesis, inner));
+        }
+        None => tokens.append(Ident::new("None", Span::call_site())),
+    }
+}
+
+// Emits Some(seq) or None
+fn optional_seq(tokens: &mut TokenStream, value: &Option<u64>) {
+    match *value {
+        Some(value) => {
+            tokens.append(Ident::new("Some", Span::call_site()));
+            let mut inner = TokenStream::new();
+            value.to_tokens(&mut inner);
+            tokens.append(Group::new(Delimiter::Parenthesis, inner));
+        }
+        None => tokens.append(Ident::new("None", Span::call_site())),
+    }
+}
+
 impl ToTokens for Document {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let mut fields = TokenStream::new();
@@ -117,6 +149,8 @@ impl ToTokens for Document {
         field(&mut fields, "participants", |tokens| {
             self.participants.to_tokens(tokens)
         });
+        field(&mut fields, "deleted", |tokens| self.deleted.to_tokens(tokens));
+        field(&mut fields, "published", |tokens| optional_seq(tokens, &self.published));
         tokens.append(Ident::new("Document", Span::call_site()));
         tokens.append(Group::new(Delimiter::Brace, fields));
     }
@@ -153,6 +187,7 @@ impl ToTokens for DocumentParticipant {
         field(&mut fields, "cursor_pos", |tokens| {
             self.cursor_pos.to_tokens(tokens)
         });
+        field(&mut fields, "user", |tokens| optional_string(tokens, &self.user));
         tokens.append(Ident::new("DocumentParticipant", Span::call_site()));
         tokens.append(Group::new(Delimiter::Brace, fields));
     }
@@ -180,6 +215,21 @@ impl ToTokens for Event {
                 data.to_tokens(&mut inner);
                 tokens.append(Group::new(Delimiter::Parenthesis, inner));
             }
+            Event::Tombstone(ref data) => {
+                tokens.append(Ident::new("Tombstone", Span::call_site()));
+                data.to_tokens(&mut inner);
+                tokens.append(Group::new(Delimiter::Parenthesis, inner));
+            }
+            Event::Restore(ref data) => {
+                tokens.append(Ident::new("Restore", Span::call_site()));
+                data.to_tokens(&mut inner);
+                tokens.append(Group::new(Delimiter::Parenthesis, inner));
+            }
+            Event::Publish(ref data) => {
+                tokens.append(Ident::new("Publish", Span::call_site()));
+                data.to_tokens(&mut inner);
+                tokens.append(Group::new(Delimiter::Parenthesis, inner));
+            }
         }
     }
 }
@@ -188,11 +238,29 @@ impl ToTokens for Join {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let mut fields = TokenStream::new();
         field(&mut fields, "id", |tokens| self.id.to_tokens(tokens));
+        field(&mut fields, "role", |tokens| self.role.to_tokens(tokens));
+        field(&mut fields, "user", |tokens| optional_string(tokens, &self.user));
         tokens.append(Ident::new("Join", Span::call_site()));
         tokens.append(Group::new(Delimiter::Brace, fields));
     }
 }
 
+impl ToTokens for Role {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        tokens.append(Ident::new("Role", Span::call_site()));
+        tokens.append(Punct::new(':', Spacing::Joint));
+        tokens.append(Punct::new(':', Spacing::Alone));
+        tokens.append(Ident::new(
+            match *self {
+                Role::Editor => "Editor",
+                Role::Commenter => "Commenter",
+                Role::Reader => "Reader",
+            },
+            Span::call_site(),
+        ));
+    }
+}
+
 impl ToTokens for Leave {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let mut fields = TokenStream::new();
@@ -202,6 +270,34 @@ impl ToTokens for Leave {
     }
 }
 
+impl ToTokens for Tombstone {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let mut fields = TokenStream::new();
+        field(&mut fields, "id", |tokens| self.id.to_tokens(tokens));
+        tokens.append(Ident::new("Tombstone", Span::call_site()));
+        tokens.append(Group::new(Delimiter::Brace, fields));
+    }
+}
+
+impl ToTokens for Restore {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let mut fields = TokenStream::new();
+        field(&mut fields, "id", |tokens| self.id.to_tokens(tokens));
+        tokens.append(Ident::new("Restore", Span::call_site()));
+        tokens.append(Group::new(Delimiter::Brace, fields));
+    }
+}
+
+impl ToTokens for Publish {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let mut fields = TokenStream::new();
+        field(&mut fields, "id", |tokens| self.id.to_tokens(tokens));
+        field(&mut fields, "seq", |tokens| self.seq.to_tokens(tokens));
+        tokens.append(Ident::new("Publish", Span::call_site()));
+        tokens.append(Group::new(Delimiter::Brace, fields));
+    }
+}
+
 impl ToTokens for Edit {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let mut fields = TokenStream::new();
@@ -302,64 +398,58 @@ impl ToTokens for EditError {
 }
 
 fn write_apply_test(mut output: &File, config: ApplyTestConfig) {
-    match config {
-        ApplyTestConfig {
-            name,
-            initial,
-            events,
-            error,
-            expected,
-        } => {
-            let name = Ident::new(&name, Span::call_site());
-            write!(
-                output,
-                "{}\n",
-                if error.is_some() {
-                    quote! {
-                        #[test]
-                        fn #name() {
-                            let mut doc = #initial;
-                            #(let last_result = doc.apply(&#events);)*
-                            assert_eq!(last_result, Err(#error));
-                            assert_eq!(doc, #expected);
-                        }
-                    }
-                } else {
-                    quote! {
-                        #[test]
-                        fn #name() {
-                            let mut doc = #initial;
-                            #(doc.apply(&#events).unwrap();)*
-                            assert_eq!(doc, #expected);
-                        }
-                    }
+    let ApplyTestConfig {
+        name,
+        initial,
+        events,
+        error,
+        expected,
+    } = config;
+    let name = Ident::new(&name, Span::call_site());
+    writeln!(
+        output,
+        "{}",
+        if error.is_some() {
+            quote! {
+                #[test]
+                fn #name() {
+                    let mut doc = #initial;
+                    #(let last_result = doc.apply(&#events);)*
+                    assert_eq!(last_result, Err(#error));
+                    assert_eq!(doc, #expected);
                 }
-            ).unwrap();
+            }
+        } else {
+            quote! {
+                #[test]
+                fn #name() {
+                    let mut doc = #initial;
+                    #(doc.apply(&#events).unwrap();)*
+                    assert_eq!(doc, #expected);
+                }
+            }
         }
-    }
+    ).unwrap();
 }
 
 fn write_transform_test(mut output: &File, config: TransformTestConfig) {
-    match config {
-        TransformTestConfig {
-            name,
-            initial,
-            concurrent,
-            expected,
-        } => {
-            let name = Ident::new(&name, Span::call_site());
-            write!(
-                output,
-                "{}\n",
-                quote! {
-                    #[test]
-                    fn #name() {
-                        let mut event = #initial;
-                        #(event.transform(&#concurrent);)*
-                        assert_eq!(event, #expected);
-                    }
-                }
-            ).unwrap();
+    let TransformTestConfig {
+        name,
+        initial,
+        concurrent,
+        expected,
+    } = config;
+    let name = Ident::new(&name, Span::call_site());
+    writeln!(
+        output,
+        "{}",
+        quote! {
+            #[test]
+            fn #name() {
+                let mut event = #initial;
+                #(event.transform(&#concurrent);)*
+                assert_eq!(event, #expected);
+            }
         }
-    }
+    ).unwrap();
 }
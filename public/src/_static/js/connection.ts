@@ -9,13 +9,21 @@ export interface IConnectionConstructor {
     new(path: string, seq: number): Connection;
 }
 
+// The message format version requested from the server. The editor
+// does not yet handle the Lock, Error and Shutdown messages added in
+// version 2.
+export const PROTOCOL = "tamawiki.v1.json";
+
 export class WebSocketConnection extends Connection {
     private websocket: WebSocket;
 
     constructor(path: string, seq: number) {
         super();
         const host = window.location.host;
-        this.websocket = new WebSocket(`ws://${host}${path}?seq=${seq}`);
+        this.websocket = new WebSocket(
+            `ws://${host}${path}?seq=${seq}`,
+            PROTOCOL,
+        );
         this.websocket.onopen = (_event) => {
             console.log("websocket open");
         };
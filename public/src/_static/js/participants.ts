@@ -4,12 +4,14 @@ import * as protocol from "./protocol";
 
 export class ParticipantsElement extends HTMLElement {
     private participants: protocol.Participant[];
+    private roles: Map<number, protocol.Role>;
     private ul?: HTMLUListElement;
     private localId?: number;
 
     constructor() {
         super();
         this.participants = [];
+        this.roles = new Map();
     }
 
     public connectedCallback(): void {
@@ -25,6 +27,7 @@ export class ParticipantsElement extends HTMLElement {
             this.setLocalParticipantId(msg.id);
         } else if (msg instanceof protocol.ServerEvent) {
             if (msg.event instanceof protocol.Join) {
+                this.roles.set(msg.event.id, msg.event.role);
                 this.addParticipant(
                     new protocol.Participant(msg.event.id, 0),
                 );
@@ -40,6 +43,7 @@ export class ParticipantsElement extends HTMLElement {
     }
 
     public removeParticipant(id: number): void {
+        this.roles.delete(id);
         this.participants = this.participants.filter((p) => {
             return p.id !== id;
         });
@@ -67,6 +71,12 @@ export class ParticipantsElement extends HTMLElement {
             for (const participant of this.participants) {
                 const li = document.createElement("li");
                 li.textContent = "Participant " + participant.id;
+                const role = this.roles.get(participant.id);
+                if (role === "Commenter") {
+                    li.textContent += " (can comment)";
+                } else if (role === "Reader") {
+                    li.textContent += " (read only)";
+                }
                 if (participant.id === this.localId) {
                     li.className = "you";
                 }
@@ -287,6 +287,12 @@ export abstract class Event {
             return Join.fromJSON(data);
         } else if (data.Leave) {
             return Leave.fromJSON(data);
+        } else if (data.Tombstone) {
+            return Tombstone.fromJSON(data);
+        } else if (data.Restore) {
+            return Restore.fromJSON(data);
+        } else if (data.Publish) {
+            return Publish.fromJSON(data);
         } else {
             throw new Error("Unknown Event type");
         }
@@ -341,12 +347,14 @@ export class Edit extends Event {
     }
 }
 
+export type Role = "Editor" | "Commenter" | "Reader";
+
 export class Join extends Event {
     public static fromJSON(data: any): Join {
-        return new Join(data.Join.id);
+        return new Join(data.Join.id, data.Join.role || "Editor");
     }
 
-    constructor(public id: number) {
+    constructor(public id: number, public role: Role = "Editor") {
         super();
     }
 
@@ -355,7 +363,7 @@ export class Join extends Event {
     }
 
     public toJSON(): any {
-        return { Join: { id: this.id } };
+        return { Join: { id: this.id, role: this.role } };
     }
 }
 
@@ -376,3 +384,57 @@ export class Leave extends Event {
         return { Leave: { id: this.id } };
     }
 }
+
+export class Tombstone extends Event {
+    public static fromJSON(data: any): Tombstone {
+        return new Tombstone(data.Tombstone.id);
+    }
+
+    constructor(public id: number) {
+        super();
+    }
+
+    public transform(_other: Event): void {
+        return;
+    }
+
+    public toJSON(): any {
+        return { Tombstone: { id: this.id } };
+    }
+}
+
+export class Restore extends Event {
+    public static fromJSON(data: any): Restore {
+        return new Restore(data.Restore.id);
+    }
+
+    constructor(public id: number) {
+        super();
+    }
+
+    public transform(_other: Event): void {
+        return;
+    }
+
+    public toJSON(): any {
+        return { Restore: { id: this.id } };
+    }
+}
+
+export class Publish extends Event {
+    public static fromJSON(data: any): Publish {
+        return new Publish(data.Publish.id, data.Publish.seq);
+    }
+
+    constructor(public id: number, public seq: number) {
+        super();
+    }
+
+    public transform(_other: Event): void {
+        return;
+    }
+
+    public toJSON(): any {
+        return { Publish: { id: this.id, seq: this.seq } };
+    }
+}
@@ -48,7 +48,7 @@ suite("protocol", () => {
         assert.deepEqual(serialized, {
             Event: {
                 client_seq: 0,
-                event: { Join: { id: 1 } },
+                event: { Join: { id: 1, role: "Editor" } },
                 seq: 1,
             },
         });
@@ -145,12 +145,17 @@ suite("protocol", () => {
     });
 
     test("Join.fromJSON / toJSON", function() {
-        const join = new protocol.Join(123);
+        const join = new protocol.Join(123, "Reader");
         const serialized = join.toJSON();
         const deserialized = protocol.Join.fromJSON(serialized);
-        assert.deepEqual(serialized, { Join: { id: 123 } });
+        assert.deepEqual(serialized, { Join: { id: 123, role: "Reader" } });
         assert.deepEqual(deserialized, join);
         assert.ok(deserialized instanceof protocol.Join);
+        // events written before roles existed were made by editors
+        assert.deepEqual(
+            protocol.Join.fromJSON({ Join: { id: 123 } }),
+            new protocol.Join(123, "Editor"),
+        );
     });
 
     test("Leave.fromJSON / toJSON", function() {
@@ -0,0 +1,297 @@
+//! Access control lists, restricting who may view and edit the
+//! documents under a path.
+//!
+//! Rules are read from a file with one entry per line:
+//!
+//! ```text
+//! # members of the staff group
+//! group:staff = alice, bob
+//!
+//! /** -> * (read)
+//! /** -> group:staff (read, write)
+//! /private/** -> group:staff (read, write)
+//! /drafts/* -> user:carol (read, write)
+//! ```
+//!
+//! A rule's pattern matches document paths, where `*` matches one
+//! path segment and `**` matches any number of segments. The rule
+//! applies to everyone (`*`), a single user (`user:<name>`) or the
+//! members of a group (`group:<name>`). Only the rules with the
+//! longest pattern matching a document apply to it, so above the
+//! rules for `/private/**` replace those for `/**`, and only staff
+//! can view private documents. Documents which no rule matches are
+//! open to everyone.
+//!
+//! Besides the groups defined in the file, users may be members of
+//! groups recorded in Memberships, such as their groups in an LDAP
+//! directory.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// The actions a rule may allow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Viewing the document, its history and its events
+    Read,
+    /// Editing the document
+    Write,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Principal {
+    Everyone,
+    User(String),
+    Group(String),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: String,
+    principal: Principal,
+    read: bool,
+    write: bool,
+}
+
+/// A set of rules deciding which users may view and edit documents
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+    groups: HashMap<String, HashSet<String>>,
+    memberships: Option<Memberships>,
+}
+
+/// The groups each user is a member of, from outside the ACL file.
+/// This is a cloneable handle to memberships held in memory.
+#[derive(Debug, Clone, Default)]
+pub struct Memberships {
+    users: Arc<RwLock<HashMap<String, HashSet<String>>>>,
+}
+
+impl Memberships {
+    /// Replaces the groups 'user' is a member of
+    pub fn set<I: IntoIterator<Item = String>>(&self, user: &str, groups: I) {
+        self.users
+            .write()
+            .unwrap()
+            .insert(String::from(user), groups.into_iter().collect());
+    }
+
+    /// Returns true if 'user' is a member of 'group'
+    pub fn contains(&self, user: &str, group: &str) -> bool {
+        self.users
+            .read()
+            .unwrap()
+            .get(user)
+            .is_some_and(|groups| groups.contains(group))
+    }
+}
+
+impl Acl {
+    /// Parses rules and group definitions, in the format described in
+    /// the module documentation
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut acl = Acl::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("ACL line {}: {}", i + 1, reason),
+                )
+            };
+            if let Some(group) = line.strip_prefix("group:") {
+                let mut parts = group.splitn(2, '=');
+                let (group, members) = match (parts.next(), parts.next()) {
+                    (Some(group), Some(members)) => (group.trim(), members),
+                    _ => return Err(invalid("expected group:<name> = <users>")),
+                };
+                acl.groups.entry(String::from(group)).or_default().extend(
+                    members
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|user| !user.is_empty())
+                        .map(String::from),
+                );
+                continue;
+            }
+            let mut parts = line.splitn(2, "->");
+            let (pattern, grant) = match (parts.next(), parts.next()) {
+                (Some(pattern), Some(grant)) => (pattern.trim(), grant.trim()),
+                _ => return Err(invalid("expected <pattern> -> <who> (<permissions>)")),
+            };
+            if !pattern.starts_with('/') {
+                return Err(invalid("patterns must start with '/'"));
+            }
+            let open = grant.find('(').ok_or_else(|| invalid("missing permissions"))?;
+            if !grant.ends_with(')') {
+                return Err(invalid("missing ')'"));
+            }
+            let principal = match grant[..open].trim() {
+                "*" => Principal::Everyone,
+                who if who.starts_with("user:") => Principal::User(String::from(&who[5..])),
+                who if who.starts_with("group:") => Principal::Group(String::from(&who[6..])),
+                _ => return Err(invalid("expected *, user:<name> or group:<name>")),
+            };
+            let mut rule = Rule {
+                pattern: String::from(pattern.trim_start_matches('/')),
+                principal,
+                read: false,
+                write: false,
+            };
+            for permission in grant[open + 1..grant.len() - 1].split(',') {
+                match permission.trim() {
+                    "read" => rule.read = true,
+                    "write" => rule.write = true,
+                    _ => return Err(invalid("permissions must be read or write")),
+                }
+            }
+            acl.rules.push(rule);
+        }
+        Ok(acl)
+    }
+
+    /// Reads rules from a file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Acl::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Also treats users as members of the groups recorded for them
+    /// in 'memberships'
+    pub fn with_memberships(mut self, memberships: Memberships) -> Self {
+        self.memberships = Some(memberships);
+        self
+    }
+
+    /// Returns true if 'user', or an anonymous user if None, has
+    /// 'permission' for the document at 'path'
+    pub fn allows(&self, user: Option<&str>, path: &Path, permission: Permission) -> bool {
+        let path = path.to_string_lossy();
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let matching: Vec<&Rule> = self
+            .rules
+            .iter()
+            .filter(|rule| matches(&rule.pattern, &segments))
+            .collect();
+        let longest = match matching.iter().map(|rule| rule.pattern.len()).max() {
+            Some(longest) => longest,
+            None => return true,
+        };
+        matching
+            .into_iter()
+            .filter(|rule| rule.pattern.len() == longest)
+            .filter(|rule| match permission {
+                Permission::Read => rule.read,
+                Permission::Write => rule.write,
+            }).any(|rule| match (&rule.principal, user) {
+                (&Principal::Everyone, _) => true,
+                (&Principal::User(ref name), Some(user)) => name == user,
+                (&Principal::Group(ref group), Some(user)) => {
+                    self.groups
+                        .get(group)
+                        .is_some_and(|members| members.contains(user))
+                        || self
+                            .memberships
+                            .as_ref()
+                            .is_some_and(|memberships| memberships.contains(user, group))
+                }
+                _ => false,
+            })
+    }
+}
+
+/// Returns true if the document 'path' matches 'pattern', written
+/// the same way as the patterns of ACL rules
+pub fn path_matches(pattern: &str, path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    matches(pattern, &segments)
+}
+
+// Returns true if the path 'segments' match the glob 'pattern'
+fn matches(pattern: &str, segments: &[&str]) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    matches_segments(&pattern, segments)
+}
+
+fn matches_segments(pattern: &[&str], segments: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => segments.is_empty(),
+        Some((&"**", rest)) => {
+            (0..segments.len() + 1).any(|i| matches_segments(rest, &segments[i..]))
+        }
+        Some((first, rest)) => match segments.split_first() {
+            Some((segment, remaining)) => {
+                (*first == "*" || first == segment) && matches_segments(rest, remaining)
+            }
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = "
+        # members of the staff group
+        group:staff = alice, bob
+
+        /** -> * (read)
+        /** -> group:staff (read, write)
+        /private/** -> group:staff (read, write)
+        /drafts/* -> user:carol (read, write)
+    ";
+
+    #[test]
+    fn evaluate_rules() {
+        let acl = Acl::parse(RULES).unwrap();
+        let allows = |user, path, permission| acl.allows(user, Path::new(path), permission);
+
+        assert!(allows(None, "index.html", Permission::Read));
+        assert!(!allows(None, "index.html", Permission::Write));
+        assert!(allows(Some("alice"), "index.html", Permission::Write));
+
+        assert!(!allows(None, "private/plans", Permission::Read));
+        assert!(!allows(Some("carol"), "private/plans", Permission::Read));
+        assert!(allows(Some("bob"), "private/a/b", Permission::Write));
+        assert!(allows(Some("bob"), "private", Permission::Read));
+
+        assert!(allows(Some("carol"), "drafts/idea", Permission::Write));
+        assert!(!allows(Some("alice"), "drafts/idea", Permission::Read));
+        // '*' only matches one segment
+        assert!(allows(Some("alice"), "drafts/a/b", Permission::Write));
+
+        // without rules, documents are open to everyone
+        let open = Acl::default();
+        assert!(open.allows(None, Path::new("private/plans"), Permission::Write));
+    }
+
+    #[test]
+    fn external_memberships() {
+        let memberships = Memberships::default();
+        let acl = Acl::parse(RULES)
+            .unwrap()
+            .with_memberships(memberships.clone());
+        let path = Path::new("private/plans");
+        assert!(!acl.allows(Some("dave"), path, Permission::Read));
+        memberships.set("dave", vec![String::from("staff")]);
+        assert!(acl.allows(Some("dave"), path, Permission::Write));
+        memberships.set("dave", vec![]);
+        assert!(!acl.allows(Some("dave"), path, Permission::Read));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert!(Acl::parse("private/** -> * (read)").is_err());
+        assert!(Acl::parse("/private/** -> staff (read)").is_err());
+        assert!(Acl::parse("/private/** -> * (delete)").is_err());
+        assert!(Acl::parse("/private/** -> * read").is_err());
+        assert!(Acl::parse("group:staff alice").is_err());
+    }
+}
@@ -0,0 +1,302 @@
+//! Routine maintenance of a backup made by `backup::dump`, so the
+//! wiki a server restores at startup can be tidied up while it is
+//! not running.
+//!
+//! A document's history can also be replayed with `verify`, which
+//! finds the first Event that does not apply cleanly or whose content
+//! does not match a checksum reported elsewhere, e.g. by a client
+//! which saw different content at that SequenceId.
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::backup::{Backup, BackupError};
+use crate::document::{Document, Event};
+use crate::import;
+use crate::markup;
+use crate::store::SequenceId;
+
+/// A document in a backup
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    /// The document's path
+    pub path: PathBuf,
+    /// The SequenceId of its latest Event
+    pub seq: SequenceId,
+    /// The length of its content, in characters
+    pub size: usize,
+}
+
+/// Lists every document in the backup
+pub fn list(backup: &Backup) -> Result<Vec<Summary>, BackupError> {
+    backup
+        .documents
+        .iter()
+        .map(|(path, events)| {
+            Ok(Summary {
+                path: path.clone(),
+                seq: events.len() as SequenceId,
+                size: content(path, events)?.chars().count(),
+            })
+        }).collect()
+}
+
+/// Removes the document at 'path' and its history from the backup,
+/// returning false if there is no such document
+pub fn delete(backup: &mut Backup, path: &Path) -> bool {
+    backup.documents.remove(path).is_some()
+}
+
+/// Adds a document with the history 'events' to the backup, unless
+/// there is already a document at 'path'. Returns false if there was.
+pub fn add(backup: &mut Backup, path: PathBuf, events: Vec<Event>) -> bool {
+    if backup.documents.contains_key(&path) {
+        return false;
+    }
+    backup.documents.insert(path, events);
+    true
+}
+
+/// Replaces the history of every document with a single Edit
+/// inserting its current content, returning the number of Events
+/// removed. Earlier revisions and their authors are lost.
+pub fn compact(backup: &mut Backup) -> Result<usize, BackupError> {
+    let mut removed = 0;
+    for (path, events) in &mut backup.documents {
+        let compacted = import::events(1, content(path, events)?);
+        if compacted.len() < events.len() {
+            removed += events.len() - compacted.len();
+            *events = compacted;
+        }
+    }
+    Ok(removed)
+}
+
+/// Removes the attachments no document refers to, returning their
+/// paths
+pub fn gc(backup: &mut Backup) -> Result<Vec<PathBuf>, BackupError> {
+    let mut contents = Vec::new();
+    for (path, events) in &backup.documents {
+        contents.push(content(path, events)?);
+    }
+    let referenced: Vec<&str> = contents
+        .iter()
+        .flat_map(|content| markup::attachments(content))
+        .collect();
+    let unused: Vec<PathBuf> = backup
+        .attachments
+        .keys()
+        .filter(|path| !referenced.iter().any(|used| Path::new(used) == path.as_path()))
+        .cloned()
+        .collect();
+    for path in &unused {
+        backup.attachments.remove(path);
+    }
+    Ok(unused)
+}
+
+/// The result of replaying a document's Events from the start
+#[derive(Debug, PartialEq)]
+pub struct Replay {
+    /// The checksum of the content after each Event which applied,
+    /// so the first is the checksum at SequenceId 1
+    pub checksums: Vec<String>,
+    /// Where the history first went wrong, if it did
+    pub divergence: Option<Divergence>,
+}
+
+/// The first Event in a document's history found to be wrong
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    /// The Event's SequenceId
+    pub seq: SequenceId,
+    /// Why the Event is wrong
+    pub reason: String,
+}
+
+/// Returns the checksum of a document's content: the SHA-256 digest
+/// of its UTF-8 encoding, as lower case hex
+pub fn checksum(content: &str) -> String {
+    Sha256::digest(content.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Replays 'events' from an empty document, checking each one
+/// applies cleanly and recomputing the content's checksum after it.
+/// Replaying stops at the first Event which does not apply, or whose
+/// checksum differs from the one 'expected' for its SequenceId.
+pub fn verify(events: &[Event], expected: &BTreeMap<SequenceId, String>) -> Replay {
+    let mut doc = Document::default();
+    let mut checksums = Vec::with_capacity(events.len());
+    for (i, event) in events.iter().enumerate() {
+        let seq = i as SequenceId + 1;
+        if let Err(err) = doc.apply(event) {
+            let reason = format!(
+                "{} does not apply: {}",
+                serde_json::to_string(event).unwrap_or_else(|_| format!("{:?}", event)),
+                err
+            );
+            return Replay {
+                checksums,
+                divergence: Some(Divergence { seq, reason }),
+            };
+        }
+        let actual = checksum(&doc.content);
+        if let Some(wanted) = expected.get(&seq) {
+            if !wanted.eq_ignore_ascii_case(&actual) {
+                let reason = format!("checksum is {}, expected {}", actual, wanted);
+                checksums.push(actual);
+                return Replay {
+                    checksums,
+                    divergence: Some(Divergence { seq, reason }),
+                };
+            }
+        }
+        checksums.push(actual);
+    }
+    let divergence = expected
+        .keys()
+        .find(|seq| **seq > events.len() as SequenceId)
+        .map(|seq| Divergence {
+            seq: *seq,
+            reason: format!("the document only has {} events", events.len()),
+        });
+    Replay {
+        checksums,
+        divergence,
+    }
+}
+
+// Returns the content of the document at 'path' after its Events
+fn content(path: &Path, events: &[Event]) -> Result<String, BackupError> {
+    let mut doc = Document::default();
+    for event in events {
+        doc.apply(event)
+            .map_err(|err| BackupError::Invalid(format!("{}: {}", path.display(), err)))?;
+    }
+    Ok(doc.content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backup() -> Backup {
+        let mut backup = Backup::default();
+        let mut events = import::events(1, String::from("See [[attachment:cat.png]]"));
+        events.extend(import::events(2, String::from("Hello. ")));
+        backup.documents.insert(PathBuf::from("index.html"), events);
+        backup
+            .documents
+            .insert(PathBuf::from("old.html"), import::events(1, String::from("Old")));
+        backup
+            .attachments
+            .insert(PathBuf::from("cat.png"), b"meow".to_vec());
+        backup
+            .attachments
+            .insert(PathBuf::from("dog.png"), b"woof".to_vec());
+        backup
+    }
+
+    #[test]
+    fn list_documents() {
+        assert_eq!(
+            list(&backup()).unwrap(),
+            vec![
+                Summary {
+                    path: PathBuf::from("index.html"),
+                    seq: 6,
+                    size: 33,
+                },
+                Summary {
+                    path: PathBuf::from("old.html"),
+                    seq: 3,
+                    size: 3,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn delete_document() {
+        let mut backup = backup();
+        assert!(delete(&mut backup, Path::new("old.html")));
+        assert!(!delete(&mut backup, Path::new("old.html")));
+        assert_eq!(backup.documents.len(), 1);
+    }
+
+    #[test]
+    fn add_document() {
+        let mut backup = backup();
+        let events = import::events(1, String::from("New"));
+        assert!(add(&mut backup, PathBuf::from("new.html"), events.clone()));
+        assert!(!add(&mut backup, PathBuf::from("old.html"), events));
+        assert_eq!(backup.documents.len(), 3);
+    }
+
+    #[test]
+    fn compact_history() {
+        let mut backup = backup();
+        assert_eq!(compact(&mut backup).unwrap(), 3);
+        assert_eq!(compact(&mut backup).unwrap(), 0);
+        let summaries = list(&backup).unwrap();
+        assert_eq!(summaries[0].seq, 3);
+        assert_eq!(summaries[0].size, 33);
+    }
+
+    #[test]
+    fn verify_history() {
+        let backup = backup();
+        let events = &backup.documents[Path::new("old.html")];
+        let replay = verify(events, &BTreeMap::new());
+        assert_eq!(replay.checksums.len(), 3);
+        assert_eq!(replay.checksums[2], checksum("Old"));
+        assert_eq!(replay.divergence, None);
+        assert_eq!(
+            checksum(""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let mut expected = BTreeMap::new();
+        expected.insert(2, checksum("Old").to_uppercase());
+        expected.insert(3, checksum("New"));
+        let replay = verify(events, &expected);
+        assert_eq!(replay.checksums.len(), 3);
+        assert_eq!(replay.divergence.unwrap().seq, 3);
+
+        let mut expected = BTreeMap::new();
+        expected.insert(5, checksum("Old"));
+        assert_eq!(
+            verify(events, &expected).divergence,
+            Some(Divergence {
+                seq: 5,
+                reason: String::from("the document only has 3 events"),
+            })
+        );
+    }
+
+    #[test]
+    fn verify_broken_history() {
+        let mut events = import::events(1, String::from("Old"));
+        // the participant has already left, so cannot edit
+        let edit = events.remove(1);
+        events.push(edit);
+        let replay = verify(&events, &BTreeMap::new());
+        assert_eq!(replay.checksums.len(), 2);
+        let divergence = replay.divergence.unwrap();
+        assert_eq!(divergence.seq, 3);
+        assert!(divergence.reason.starts_with("{\"Edit\""));
+    }
+
+    #[test]
+    fn gc_attachments() {
+        let mut backup = backup();
+        assert_eq!(gc(&mut backup).unwrap(), vec![PathBuf::from("dog.png")]);
+        assert_eq!(
+            backup.attachments.keys().collect::<Vec<_>>(),
+            vec![Path::new("cat.png")]
+        );
+    }
+}
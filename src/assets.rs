@@ -0,0 +1,87 @@
+//! Fingerprints of the static files served under /_static/. Pages
+//! link to each file with its fingerprint in the URL, which changes
+//! whenever the file does, so browsers can cache the files for as
+//! long as they like.
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The fingerprint of each file in a static files directory, keyed
+/// by its path relative to the directory, such as "css/main.css".
+/// Fingerprints are taken when the manifest is loaded, so it must be
+/// loaded again after the files change.
+#[derive(Debug, Default, Clone)]
+pub struct Assets {
+    files: BTreeMap<String, String>,
+}
+
+impl Assets {
+    /// Fingerprints every file under 'dir'
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let mut assets = Assets::default();
+        assets.add_files(dir.as_ref(), "")?;
+        Ok(assets)
+    }
+
+    /// Returns the fingerprint of the file at 'path', if it exists
+    pub fn fingerprint(&self, path: &str) -> Option<&str> {
+        self.files
+            .get(path.trim_start_matches('/'))
+            .map(|fingerprint| fingerprint.as_str())
+    }
+
+    /// Returns the cache-busting URL of every file, keyed by its
+    /// path, for files served under the URL 'prefix'
+    pub fn urls(&self, prefix: &str) -> BTreeMap<String, String> {
+        self.files
+            .iter()
+            .map(|(path, fingerprint)| {
+                let url = format!("{}{}?v={}", prefix, path, fingerprint);
+                (path.clone(), url)
+            }).collect()
+    }
+
+    fn add_files(&mut self, dir: &Path, prefix: &str) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                self.add_files(&entry.path(), &format!("{}/", name))?;
+            } else {
+                let digest = Sha256::digest(&fs::read(entry.path())?);
+                let fingerprint = base64::encode_config(&digest[..9], base64::URL_SAFE_NO_PAD);
+                self.files.insert(name, fingerprint);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn fingerprint_files() {
+        let dir = env::temp_dir().join("tamawiki-assets-test");
+        fs::create_dir_all(dir.join("css")).unwrap();
+        fs::write(dir.join("css/main.css"), "body {}").unwrap();
+        let assets = Assets::from_dir(&dir).unwrap();
+        let fingerprint = String::from(assets.fingerprint("/css/main.css").unwrap());
+        assert_eq!(fingerprint.len(), 12);
+        assert_eq!(
+            assets.urls("/_static/")["css/main.css"],
+            format!("/_static/css/main.css?v={}", fingerprint)
+        );
+        assert_eq!(assets.fingerprint("js/main.js"), None);
+
+        // fingerprints change with the content
+        fs::write(dir.join("css/main.css"), "body { color: red }").unwrap();
+        let assets = Assets::from_dir(&dir).unwrap();
+        assert_ne!(assets.fingerprint("css/main.css"), Some(fingerprint.as_str()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+//! Resolves credentials presented by clients to user identities.
+use futures::future::{self, Future};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::document::Role;
+
+/// Name of the cookie browsers may use to present a session token,
+/// since they cannot set an Authorization header on websocket
+/// requests
+pub const SESSION_COOKIE: &str = "tamawiki_session";
+
+/// An authenticated user
+#[derive(Debug, PartialEq, Clone)]
+pub struct User {
+    /// Unique identifier for the user, recorded in the Join events of
+    /// any document sessions they join so their edits can be
+    /// attributed to them
+    pub id: String,
+    /// The user's level of access to documents
+    pub role: Role,
+}
+
+/// Resolves a bearer token or session cookie value to a User.
+/// Implementations may look up tokens in a database or validate
+/// signed tokens, so the result is returned as a Future.
+pub trait Authenticator: Send + Sync + 'static {
+    /// Returns the User the token belongs to, or None if the token is
+    /// not valid.
+    fn authenticate(
+        &self,
+        token: &str,
+    ) -> Box<dyn Future<Item = Option<User>, Error = AuthError> + Send>;
+}
+
+/// Error conditions which may occur when authenticating a token
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    /// There was a problem communicating with the authentication
+    /// backend
+    ConnectionError,
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthError::ConnectionError => write!(f, "ConnectionError"),
+        }
+    }
+}
+
+impl Error for AuthError {
+    fn description(&self) -> &str {
+        match *self {
+            AuthError::ConnectionError => "AuthError: connection error",
+        }
+    }
+}
+
+/// Authenticates a fixed set of tokens, useful for testing or small
+/// deployments.
+#[derive(Debug, Default, Clone)]
+pub struct StaticTokens {
+    tokens: HashMap<String, User>,
+}
+
+impl StaticTokens {
+    /// Creates an empty set of tokens
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds a token which authenticates as 'user'
+    pub fn insert<S: Into<String>>(&mut self, token: S, user: User) {
+        self.tokens.insert(token.into(), user);
+    }
+}
+
+impl Authenticator for StaticTokens {
+    fn authenticate(
+        &self,
+        token: &str,
+    ) -> Box<dyn Future<Item = Option<User>, Error = AuthError> + Send> {
+        Box::new(future::ok(self.tokens.get(token).cloned()))
+    }
+}
+
+/// Usernames and passwords for HTTP Basic authentication, which
+/// requires every request to present a valid username and password.
+/// Only SHA-1 digests of the passwords are kept.
+#[derive(Debug, Default, Clone)]
+pub struct BasicAuth {
+    users: HashMap<String, Vec<u8>>,
+}
+
+impl BasicAuth {
+    /// Creates an empty set of users
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds a user who signs in with 'password'
+    pub fn insert<S: Into<String>>(&mut self, user: S, password: &str) {
+        self.users.insert(user.into(), sha1_digest(password));
+    }
+
+    /// Reads users from an htpasswd file, with one "user:{SHA}hash"
+    /// entry per line as written by `htpasswd -s`. Other password
+    /// hash formats are not supported.
+    pub fn from_htpasswd<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut auth = BasicAuth::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("htpasswd line {}: {}", i + 1, reason),
+                )
+            };
+            let mut parts = line.splitn(2, ':');
+            let (user, hash) = match (parts.next(), parts.next()) {
+                (Some(user), Some(hash)) => (user, hash),
+                _ => return Err(invalid("expected user:hash")),
+            };
+            if !hash.starts_with("{SHA}") {
+                return Err(invalid("only {SHA} password hashes are supported"));
+            }
+            let digest = base64::decode(&hash["{SHA}".len()..])
+                .map_err(|_| invalid("invalid base64 in password hash"))?;
+            auth.users.insert(String::from(user), digest);
+        }
+        Ok(auth)
+    }
+
+    /// Returns true if 'password' is the password for 'user'
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(expected) => {
+                let actual = sha1_digest(password);
+                // compare every byte, so the time taken does not
+                // reveal how much of the digest matched
+                expected.len() == actual.len()
+                    && expected.iter().zip(actual.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+            }
+            None => false,
+        }
+    }
+}
+
+fn sha1_digest(password: &str) -> Vec<u8> {
+    let mut sha1 = Sha1::default();
+    sha1.input(password.as_bytes());
+    sha1.result().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn static_tokens() {
+        let user = User {
+            id: String::from("alice"),
+            role: Role::Commenter,
+        };
+        let mut tokens = StaticTokens::new();
+        tokens.insert("secret", user.clone());
+        assert_eq!(tokens.authenticate("secret").wait(), Ok(Some(user)));
+        assert_eq!(tokens.authenticate("guess").wait(), Ok(None));
+    }
+
+    #[test]
+    fn basic_auth() {
+        let mut auth = BasicAuth::new();
+        auth.insert("alice", "secret");
+        assert!(auth.verify("alice", "secret"));
+        assert!(!auth.verify("alice", "guess"));
+        assert!(!auth.verify("bob", "secret"));
+    }
+
+    #[test]
+    fn basic_auth_from_htpasswd() {
+        let path = ::std::env::temp_dir()
+            .join(format!("tamawiki-htpasswd-{}", ::std::process::id()));
+        // "secret", as hashed by `htpasswd -s`
+        fs::write(&path, "# users\nalice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n").unwrap();
+        let auth = BasicAuth::from_htpasswd(&path).unwrap();
+        assert!(auth.verify("alice", "secret"));
+
+        fs::write(&path, "alice:$apr1$salt$hash\n").unwrap();
+        assert!(BasicAuth::from_htpasswd(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,525 @@
+//! Backs up the whole wiki to a single file, and restores it again.
+//!
+//! A backup is a zip archive containing:
+//!
+//! ```text
+//! tamawiki.json       the backup's format version, and the latest
+//!                     SequenceId of every document
+//! documents/<path>    each document's Events, as a JSON array
+//! attachments/<path>  each uploaded attachment
+//! ```
+//!
+//! Restoring a backup pushes each document's Events to the store in
+//! order, so the documents keep their whole history. Nothing is
+//! written unless none of the backup's documents and attachments
+//! exist already.
+//!
+//! Documents can also be copied from one store straight to another
+//! using `migrate`, which keeps their history the same way.
+use futures::future::{self, Either, Future};
+use futures::stream::{self, Stream};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use zip::read::ZipArchive;
+use zip::result::ZipError;
+use zip::write::{FileOptions, ZipWriter};
+
+use crate::document::Event;
+use crate::sanitize;
+use crate::store::{SequenceId, Store, StoreError};
+
+// The version of the backup format written by `dump`
+const FORMAT_VERSION: u64 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u64,
+    documents: BTreeMap<PathBuf, SequenceId>,
+}
+
+/// Reasons a backup could not be made or restored
+#[derive(Debug)]
+pub enum BackupError {
+    /// Reading or writing a file failed
+    Io(io::Error),
+    /// Reading from or writing to the store failed
+    Store(StoreError),
+    /// The backup is not one `dump` wrote, or does not match the
+    /// store it is restored to
+    Invalid(String),
+}
+
+impl Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BackupError::Io(ref err) => write!(f, "{}", err),
+            BackupError::Store(ref err) => write!(f, "{}", err),
+            BackupError::Invalid(ref reason) => write!(f, "Invalid backup: {}", reason),
+        }
+    }
+}
+
+impl Error for BackupError {
+    fn description(&self) -> &str {
+        match *self {
+            BackupError::Io(_) => "BackupError: I/O error",
+            BackupError::Store(_) => "BackupError: store error",
+            BackupError::Invalid(_) => "BackupError: invalid backup",
+        }
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(err: io::Error) -> Self {
+        BackupError::Io(err)
+    }
+}
+
+impl From<StoreError> for BackupError {
+    fn from(err: StoreError) -> Self {
+        BackupError::Store(err)
+    }
+}
+
+impl From<ZipError> for BackupError {
+    fn from(err: ZipError) -> Self {
+        match err {
+            ZipError::Io(err) => BackupError::Io(err),
+            err => BackupError::Invalid(format!("{}", err)),
+        }
+    }
+}
+
+impl From<serde_json::Error> for BackupError {
+    fn from(err: serde_json::Error) -> Self {
+        BackupError::Invalid(format!("{}", err))
+    }
+}
+
+/// The documents and attachments in a backup
+#[derive(Debug, Default)]
+pub struct Backup {
+    /// The Events of each document, keyed by its path
+    pub documents: BTreeMap<PathBuf, Vec<Event>>,
+    /// The content of each attachment, keyed by its path in the
+    /// attachments directory
+    pub attachments: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl Backup {
+    /// Reads a backup made by `dump`
+    pub fn read(data: &[u8]) -> Result<Self, BackupError> {
+        let mut archive = ZipArchive::new(Cursor::new(data))?;
+        let manifest: Manifest = serde_json::from_reader(archive.by_name("tamawiki.json")?)?;
+        if manifest.version != FORMAT_VERSION {
+            return Err(BackupError::Invalid(format!(
+                "unsupported version {}",
+                manifest.version
+            )));
+        }
+        let mut backup = Backup::default();
+        for (path, head) in manifest.documents {
+            if let Err(err) = sanitize::check(&path) {
+                return Err(BackupError::Invalid(format!("{}: {}", path.display(), err)));
+            }
+            let name = Path::new("documents").join(&path);
+            let events: Vec<Event> =
+                serde_json::from_reader(archive.by_name(&name.to_string_lossy())?)?;
+            if events.len() as SequenceId != head {
+                return Err(BackupError::Invalid(format!(
+                    "{} has {} events, expected {}",
+                    path.display(),
+                    events.len(),
+                    head
+                )));
+            }
+            backup.documents.insert(path, events);
+        }
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            let path = match Path::new(file.name()).strip_prefix("attachments") {
+                Ok(path) => path.to_path_buf(),
+                Err(_) => continue,
+            };
+            // attachments must stay inside the directory they are
+            // restored to
+            let inside = path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+            if !inside {
+                return Err(BackupError::Invalid(format!("{}", path.display())));
+            }
+            if path.as_os_str().is_empty() {
+                continue;
+            }
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            backup.attachments.insert(path, data);
+        }
+        Ok(backup)
+    }
+
+    /// Returns the backup as a zip archive
+    pub fn write(&self) -> Result<Vec<u8>, BackupError> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let mut manifest = Manifest {
+            version: FORMAT_VERSION,
+            documents: BTreeMap::new(),
+        };
+        for (path, events) in &self.documents {
+            let name = Path::new("documents").join(path);
+            writer.start_file(name.to_string_lossy(), FileOptions::default())?;
+            writer.write_all(&serde_json::to_vec(events)?)?;
+            manifest
+                .documents
+                .insert(path.clone(), events.len() as SequenceId);
+        }
+        for (path, data) in &self.attachments {
+            let name = Path::new("attachments").join(path);
+            writer.start_file(name.to_string_lossy(), FileOptions::default())?;
+            writer.write_all(data)?;
+        }
+        writer.start_file("tamawiki.json", FileOptions::default())?;
+        writer.write_all(&serde_json::to_vec(&manifest)?)?;
+        Ok(writer.finish()?.into_inner())
+    }
+}
+
+/// Resolves to a backup of every document in the store, and of the
+/// attachments stored in the 'attachments' directory if there is one
+pub fn dump<T: Store>(
+    store: &T,
+    attachments: Option<PathBuf>,
+) -> impl Future<Item = Vec<u8>, Error = BackupError> {
+    let store = store.clone();
+    let paths = store.changes().fold(BTreeSet::new(), |mut paths, change| {
+        paths.insert(change.path);
+        Ok::<_, StoreError>(paths)
+    });
+    paths
+        .and_then(move |paths| {
+            let logs = paths.into_iter().map(|path| {
+                store
+                    .since(&path, 0, None)
+                    .and_then(|events| events.map(|(_, event)| event).collect())
+                    .map(move |events| (path, events))
+            });
+            future::join_all(logs.collect::<Vec<_>>())
+        }).map_err(BackupError::from)
+        .and_then(move |logs| -> Result<Vec<u8>, BackupError> {
+            let mut backup = Backup::default();
+            backup.documents.extend(logs);
+            if let Some(dir) = attachments {
+                let mut files = Vec::new();
+                // no attachments have been uploaded yet
+                if dir.is_dir() {
+                    add_files(&dir, Path::new(""), &mut files)?;
+                }
+                for path in files {
+                    let data = fs::read(dir.join(&path))?;
+                    backup.attachments.insert(path, data);
+                }
+            }
+            backup.write()
+        })
+}
+
+/// Restores the documents from a backup made by `dump` to the store,
+/// and its attachments to the 'attachments' directory if there is
+/// one. None of the documents may exist in the store already, nor
+/// the attachments in the directory, and nothing is written if any
+/// do. Resolves to the number of documents restored.
+pub fn restore<T: Store>(
+    store: &T,
+    data: &[u8],
+    attachments: Option<&Path>,
+) -> impl Future<Item = usize, Error = BackupError> {
+    let store = store.clone();
+    let backup = match Backup::read(data) {
+        Ok(backup) => backup,
+        Err(err) => return Either::A(future::err(err)),
+    };
+    let attachments = attachments.map(Path::to_path_buf);
+    let checks: Vec<_> = backup
+        .documents
+        .keys()
+        .map(|path| absent(&store, path.clone()))
+        .collect();
+    let restored = future::join_all(checks)
+        .and_then(move |_| -> Result<_, BackupError> {
+            if let Some(dir) = attachments {
+                write_attachments(&dir, &backup.attachments)?;
+            }
+            Ok(backup.documents)
+        }).and_then(move |documents| {
+            stream::iter_ok(documents)
+                .and_then(move |(path, events)| {
+                    let head = events.len() as SequenceId;
+                    restore_document(store.clone(), path, events, head)
+                }).fold(0, |count, _| Ok::<_, BackupError>(count + 1))
+        });
+    Either::B(restored)
+}
+
+/// Copies every document's Events from one store to another, a
+/// document at a time, checking each ends up at the same SequenceId
+/// in both. None of the documents may exist in the 'to' store
+/// already. Calls 'progress' with the path and SequenceId of each
+/// document once it has been copied, and resolves to the number of
+/// documents copied.
+pub fn migrate<F, T, P>(
+    from: &F,
+    to: &T,
+    mut progress: P,
+) -> impl Future<Item = usize, Error = BackupError>
+where
+    F: Store,
+    T: Store,
+    P: FnMut(&Path, SequenceId) + Send + 'static,
+{
+    let from = from.clone();
+    let to = to.clone();
+    let paths = from.changes().fold(BTreeSet::new(), |mut paths, change| {
+        paths.insert(change.path);
+        Ok::<_, StoreError>(paths)
+    });
+    paths
+        .map_err(BackupError::from)
+        .and_then(move |paths| {
+            stream::iter_ok(paths)
+                .and_then(move |path| {
+                    let to = to.clone();
+                    from.since(&path, 0, None)
+                        .and_then(|events| events.collect())
+                        .map_err(BackupError::from)
+                        .and_then(move |events: Vec<(SequenceId, Event)>| {
+                            let head = events.last().map_or(0, |&(seq, _)| seq);
+                            let events = events.into_iter().map(|(_, event)| event).collect();
+                            restore_document(to, path.clone(), events, head)
+                                .map(move |_| (path, head))
+                        })
+                }).fold(0, move |count, (path, head)| {
+                    progress(&path, head);
+                    Ok::<_, BackupError>(count + 1)
+                })
+        })
+}
+
+// Writes the attachments to 'dir', failing before any are written if
+// one of them exists already
+fn write_attachments(
+    dir: &Path,
+    attachments: &BTreeMap<PathBuf, Vec<u8>>,
+) -> Result<(), BackupError> {
+    for path in attachments.keys() {
+        if dir.join(path).symlink_metadata().is_ok() {
+            return Err(BackupError::Invalid(format!(
+                "attachment {} already exists",
+                path.display()
+            )));
+        }
+    }
+    for (path, data) in attachments {
+        let path = dir.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // a file created since the check is not replaced either
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.write_all(data)?;
+    }
+    Ok(())
+}
+
+// Resolves if the store has no document at 'path'
+fn absent<T: Store>(store: &T, path: PathBuf) -> impl Future<Item = (), Error = BackupError> {
+    store.seq(&path).then(move |result| match result {
+        Ok(_) => Err(BackupError::Invalid(format!(
+            "{} already exists",
+            path.display()
+        ))),
+        Err(StoreError::NotFound) => Ok(()),
+        Err(err) => Err(BackupError::from(err)),
+    })
+}
+
+// Pushes the Events of a document which is not in the store yet,
+// checking it ends up at the SequenceId 'head' it had before
+fn restore_document<T: Store>(
+    mut store: T,
+    path: PathBuf,
+    events: Vec<Event>,
+    head: SequenceId,
+) -> impl Future<Item = (), Error = BackupError> {
+    store.seq(&path).then(move |result| match result {
+        Ok(_) => Either::A(future::err(BackupError::Invalid(format!(
+            "{} already exists",
+            path.display()
+        )))),
+        Err(StoreError::NotFound) => {
+            let name = path.clone();
+            let pushed = stream::iter_ok::<_, StoreError>(events)
+                .fold(0, move |_, event| store.push(path.clone(), event))
+                .map_err(BackupError::from)
+                .and_then(move |seq| {
+                    if seq == head {
+                        Ok(())
+                    } else {
+                        Err(BackupError::Invalid(format!(
+                            "{} was restored to {}, expected {}",
+                            name.display(),
+                            seq,
+                            head
+                        )))
+                    }
+                });
+            Either::B(pushed)
+        }
+        Err(err) => Either::A(future::err(BackupError::from(err))),
+    })
+}
+
+fn add_files(dir: &Path, prefix: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = prefix.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            add_files(&entry.path(), &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env;
+    use std::sync::{Arc, Mutex};
+    use crate::store::memory::MemoryStore;
+
+    fn store(documents: &[(&str, &str)]) -> MemoryStore {
+        let mut data = HashMap::new();
+        for &(path, content) in documents {
+            data.insert(String::from(path), String::from(content));
+        }
+        MemoryStore::from(data)
+    }
+
+    #[test]
+    fn dump_and_restore() {
+        let dir = env::temp_dir().join("tamawiki-backup-test");
+        fs::create_dir_all(dir.join("attachments/images")).unwrap();
+        fs::write(dir.join("attachments/images/cat.png"), "meow").unwrap();
+
+        let original = store(&[("index.html", "Welcome"), ("guides/setup.html", "Install")]);
+        let data = dump(&original, Some(dir.join("attachments")))
+            .wait()
+            .unwrap();
+
+        let restored = MemoryStore::default();
+        let count = restore(&restored, &data, Some(&dir.join("restored")))
+            .wait()
+            .unwrap();
+        assert_eq!(count, 2);
+        let (seq, doc) = restored
+            .content(Path::new("guides/setup.html"))
+            .wait()
+            .unwrap();
+        assert_eq!(seq, 3);
+        assert_eq!(doc.content, "Install");
+        assert_eq!(
+            fs::read(dir.join("restored/images/cat.png")).unwrap(),
+            b"meow"
+        );
+
+        // documents are never overwritten
+        let existing = store(&[("index.html", "Changed")]);
+        match restore(&existing, &data, None).wait() {
+            Err(BackupError::Invalid(_)) => (),
+            result => panic!("expected invalid backup, got {:?}", result),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejected_restore_writes_nothing() {
+        let dir = env::temp_dir().join("tamawiki-backup-rejected-test");
+        fs::create_dir_all(dir.join("attachments/images")).unwrap();
+        fs::write(dir.join("attachments/images/cat.png"), "meow").unwrap();
+        let original = store(&[("index.html", "Welcome"), ("guides/setup.html", "Install")]);
+        let data = dump(&original, Some(dir.join("attachments")))
+            .wait()
+            .unwrap();
+
+        // a document exists, so no attachments are written
+        let existing = store(&[("index.html", "Changed")]);
+        let restored = dir.join("restored");
+        match restore(&existing, &data, Some(&restored)).wait() {
+            Err(BackupError::Invalid(_)) => (),
+            result => panic!("expected invalid backup, got {:?}", result),
+        }
+        assert!(!restored.join("images/cat.png").exists());
+        assert!(existing.seq(Path::new("guides/setup.html")).wait().is_err());
+
+        // an attachment exists, so it is not replaced and no
+        // documents are restored
+        fs::create_dir_all(restored.join("images")).unwrap();
+        fs::write(restored.join("images/cat.png"), "woof").unwrap();
+        let empty = MemoryStore::default();
+        match restore(&empty, &data, Some(&restored)).wait() {
+            Err(BackupError::Invalid(_)) => (),
+            result => panic!("expected invalid backup, got {:?}", result),
+        }
+        assert_eq!(fs::read(restored.join("images/cat.png")).unwrap(), b"woof");
+        assert!(empty.seq(Path::new("index.html")).wait().is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn migrate_between_stores() {
+        let from = store(&[("index.html", "Welcome"), ("guides/setup.html", "Install")]);
+        let to = MemoryStore::default();
+        let copied = Arc::new(Mutex::new(Vec::new()));
+        let progress = copied.clone();
+        let count = migrate(&from, &to, move |path, seq| {
+            progress.lock().unwrap().push((path.to_path_buf(), seq))
+        }).wait()
+        .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            *copied.lock().unwrap(),
+            vec![
+                (PathBuf::from("guides/setup.html"), 3),
+                (PathBuf::from("index.html"), 3),
+            ]
+        );
+        let (seq, doc) = to.content(Path::new("index.html")).wait().unwrap();
+        assert_eq!(seq, 3);
+        assert_eq!(doc.content, "Welcome");
+
+        // documents are never overwritten
+        match migrate(&from, &to, |_, _| ()).wait() {
+            Err(BackupError::Invalid(_)) => (),
+            result => panic!("expected invalid migration, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn restore_invalid_backup() {
+        let store = MemoryStore::default();
+        match restore(&store, b"not a zip file", None).wait() {
+            Err(BackupError::Invalid(_)) => (),
+            result => panic!("expected invalid backup, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Runs the server in the background, for init systems which expect
+//! a server to detach itself and record its process ID in a file.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Detaches the process from its terminal, leaving a child process
+/// running in a new session while the original process exits. The
+/// child's stdout and stderr, and with them its log messages, are
+/// appended to 'log_file', or discarded if there is none.
+///
+/// This must be called before starting any threads, as only the
+/// calling thread continues in the child.
+pub fn daemonize(log_file: Option<&Path>) -> io::Result<()> {
+    // the output is opened first so errors are reported to the
+    // terminal before detaching from it
+    let output = match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let input = File::open("/dev/null")?;
+    fork()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // forking again means the daemon is not a session leader, so it
+    // can never acquire a controlling terminal
+    fork()?;
+    redirect(&input, libc::STDIN_FILENO)?;
+    redirect(&output, libc::STDOUT_FILENO)?;
+    redirect(&output, libc::STDERR_FILENO)?;
+    Ok(())
+}
+
+// Forks the process, exiting the parent
+fn fork() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => process::exit(0),
+    }
+}
+
+// Makes 'fd' refer to the same file as 'file'
+fn redirect(file: &File, fd: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A file containing the process ID of the server, removed again
+/// when it is dropped
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Writes the current process ID to the file at 'path', which
+    /// must not exist unless it was left behind by a process which
+    /// is no longer running
+    pub fn create<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        if let Ok(contents) = fs::read_to_string(&path) {
+            if let Ok(pid) = contents.trim().parse::<libc::pid_t>() {
+                // signal 0 only checks the process exists
+                if unsafe { libc::kill(pid, 0) } == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("process {} in {} is still running", pid, path.display()),
+                    ));
+                }
+            }
+        }
+        let mut file = File::create(&path)?;
+        writeln!(file, "{}", process::id())?;
+        Ok(Pidfile { path })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn pidfile() {
+        let path = env::temp_dir().join(format!("tamawiki-{}.pid", process::id()));
+        let pidfile = Pidfile::create(&path).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.trim(), process::id().to_string());
+        // this process is still running
+        assert!(Pidfile::create(&path).is_err());
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
@@ -14,7 +14,7 @@ impl Document {
     /// Operations contained in the Edit are applied, or no
     /// Operations are applied and an EditError is returned.
     pub fn apply(&mut self, event: &Event) -> Result<(), EditError> {
-        self.can_apply(&event)?;
+        self.can_apply(event)?;
 
         match event {
             Event::Edit(edit) => {
@@ -22,14 +22,21 @@ impl Document {
                     self.perform_operation(edit.author, op);
                 }
             }
-            Event::Join(Join { id }) => {
-                self.participants
-                    .entries
-                    .insert(*id, DocumentParticipant { cursor_pos: 0 });
+            Event::Join(Join { id, user, .. }) => {
+                self.participants.entries.insert(
+                    *id,
+                    DocumentParticipant {
+                        cursor_pos: 0,
+                        user: user.clone(),
+                    },
+                );
             }
             Event::Leave(Leave { id }) => {
-                self.participants.entries.remove(&id);
+                self.participants.entries.remove(id);
             }
+            Event::Tombstone(_) => self.deleted = true,
+            Event::Restore(_) => self.deleted = false,
+            Event::Publish(Publish { seq, .. }) => self.published = Some(*seq),
         }
         Ok(())
     }
@@ -44,6 +51,10 @@ impl Document {
                     // edit author is not currently a participant
                     return Err(EditError::InvalidOperation);
                 }
+                if self.deleted {
+                    // deleted documents must be restored first
+                    return Err(EditError::InvalidOperation);
+                }
                 let mut length = self.content.chars().count();
 
                 for op in &edit.operations {
@@ -74,8 +85,8 @@ impl Document {
                 }
                 Ok(())
             }
-            Event::Join(Join { id }) => {
-                if self.participants.entries.contains_key(&id) {
+            Event::Join(Join { id, .. }) => {
+                if self.participants.entries.contains_key(id) {
                     // id is already a participant
                     Err(EditError::InvalidOperation)
                 } else {
@@ -83,13 +94,42 @@ impl Document {
                 }
             }
             Event::Leave(Leave { id }) => {
-                if self.participants.entries.contains_key(&id) {
+                if self.participants.entries.contains_key(id) {
                     Ok(())
                 } else {
                     // id is not a current participant
                     Err(EditError::InvalidOperation)
                 }
             }
+            Event::Tombstone(Tombstone { id }) => {
+                if self.participants.entries.contains_key(id) && !self.deleted {
+                    Ok(())
+                } else {
+                    // id is not a current participant, or the
+                    // document is already deleted
+                    Err(EditError::InvalidOperation)
+                }
+            }
+            Event::Restore(Restore { id }) => {
+                if self.participants.entries.contains_key(id) && self.deleted {
+                    Ok(())
+                } else {
+                    // id is not a current participant, or the
+                    // document is not deleted
+                    Err(EditError::InvalidOperation)
+                }
+            }
+            Event::Publish(Publish { id, seq }) => {
+                let newer = self.published.is_none_or(|published| *seq > published);
+                if self.participants.entries.contains_key(id) && !self.deleted && newer {
+                    Ok(())
+                } else {
+                    // id is not a current participant, the document
+                    // is deleted, or a later revision is already
+                    // published
+                    Err(EditError::InvalidOperation)
+                }
+            }
         }
     }
 
@@ -165,10 +205,19 @@ impl<'a> From<&'a str> for Document {
         Document {
             content: String::from(content),
             participants: Default::default(),
+            deleted: false,
+            published: None,
         }
     }
 }
 
+impl Participants {
+    /// Returns true if there are no participants
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
 impl Operation {
     /// Returns false if the Operation would never describe a
     /// meaningful change for any given Document.
@@ -186,6 +235,21 @@ impl Operation {
     }
 }
 
+impl Role {
+    /// Returns true if a participant with this Role may make an Edit
+    /// consisting of the given Operations. Commenters may only move
+    /// their cursor, and Readers may not make any changes.
+    pub fn may_edit(&self, operations: &[Operation]) -> bool {
+        match *self {
+            Role::Editor => true,
+            Role::Commenter => operations
+                .iter()
+                .all(|op| matches!(op, Operation::MoveCursor(_))),
+            Role::Reader => false,
+        }
+    }
+}
+
 impl fmt::Display for EditError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -205,6 +269,19 @@ impl error::Error for EditError {
 }
 
 impl Event {
+    /// Returns the participant who joined, edited, left, deleted,
+    /// restored or published the document
+    pub fn participant(&self) -> ParticipantId {
+        match *self {
+            Event::Edit(Edit { author, .. }) => author,
+            Event::Join(Join { id, .. })
+            | Event::Leave(Leave { id })
+            | Event::Tombstone(Tombstone { id })
+            | Event::Restore(Restore { id })
+            | Event::Publish(Publish { id, .. }) => id,
+        }
+    }
+
     /// Modifies the Event struct to accommodate a concurrent Event
     /// which has already been applied locally.
     pub fn transform(&mut self, concurrent: &Event) {
@@ -291,7 +368,7 @@ impl Operation {
         other_author: ParticipantId,
         output: &mut Vec<Operation>,
     ) {
-        println!("transforming: {:?} for {:?}", self, other_op);
+        trace!("transforming: {:?} for {:?}", self, other_op);
         match self {
             Operation::Insert(this) => this.transform(author, other_op, other_author, output),
             Operation::Delete(this) => this.transform(author, other_op, other_author, output),
@@ -370,7 +447,7 @@ impl Delete {
                     // the end second part of the range
                     let len = other.content.chars().count();
                     self.start = other.pos + len;
-                    self.end = self.end + len;
+                    self.end += len;
 
                     // push the operation covering the first
                     // part of the range to transformed.
@@ -384,7 +461,7 @@ impl Delete {
                 }
             }
             Operation::Delete(ref other) => {
-                let mut chars_deleted_before = if other.start < self.start {
+                let chars_deleted_before = if other.start < self.start {
                     let end = cmp::min(self.start, other.end);
                     end - other.start
                 } else {
@@ -452,11 +529,117 @@ mod tests {
     // and use the JSON test specifications found in tests/shared
     include!(concat!(env!("OUT_DIR"), "/shared_tests.rs"));
 
+    #[test]
+    fn role_may_edit() {
+        let insert = Operation::Insert(Insert {
+            pos: 0,
+            content: String::from("a"),
+        });
+        let cursor = Operation::MoveCursor(MoveCursor { pos: 1 });
+        assert!(Role::Editor.may_edit(&[insert.clone(), cursor.clone()]));
+        assert!(Role::Commenter.may_edit(std::slice::from_ref(&cursor)));
+        assert!(!Role::Commenter.may_edit(&[insert, cursor.clone()]));
+        assert!(!Role::Reader.may_edit(&[cursor]));
+    }
+
+    #[test]
+    fn join_role_defaults_to_editor() {
+        let event: Event = ::serde_json::from_str("{\"Join\":{\"id\":1}}").unwrap();
+        assert_eq!(
+            event,
+            Event::Join(Join {
+                id: 1,
+                role: Role::Editor,
+                user: None,
+            })
+        );
+    }
+
+    #[test]
+    fn tombstone_and_restore() {
+        let mut doc = Document::from("ab");
+        let join = Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: None,
+        });
+        let edit = Event::Edit(Edit {
+            author: 1,
+            operations: vec![Operation::Insert(Insert {
+                pos: 2,
+                content: String::from("c"),
+            })],
+        });
+        doc.apply(&join).unwrap();
+        // only participants may delete the document
+        assert_eq!(
+            doc.apply(&Event::Tombstone(Tombstone { id: 2 })),
+            Err(EditError::InvalidOperation)
+        );
+        assert_eq!(
+            doc.apply(&Event::Restore(Restore { id: 1 })),
+            Err(EditError::InvalidOperation)
+        );
+        doc.apply(&Event::Tombstone(Tombstone { id: 1 })).unwrap();
+        assert!(doc.deleted);
+        assert_eq!(doc.content, "ab");
+        assert_eq!(doc.apply(&edit), Err(EditError::InvalidOperation));
+        assert_eq!(
+            doc.apply(&Event::Tombstone(Tombstone { id: 1 })),
+            Err(EditError::InvalidOperation)
+        );
+        doc.apply(&Event::Restore(Restore { id: 1 })).unwrap();
+        assert!(!doc.deleted);
+        doc.apply(&edit).unwrap();
+        assert_eq!(doc.content, "abc");
+    }
+
+    #[test]
+    fn publish_revisions() {
+        let mut doc = Document::from("ab");
+        let join = Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: None,
+        });
+        doc.apply(&join).unwrap();
+        assert_eq!(doc.published, None);
+        // only participants may publish the document
+        assert_eq!(
+            doc.apply(&Event::Publish(Publish { id: 2, seq: 1 })),
+            Err(EditError::InvalidOperation)
+        );
+        doc.apply(&Event::Publish(Publish { id: 1, seq: 2 })).unwrap();
+        assert_eq!(doc.published, Some(2));
+        assert_eq!(doc.content, "ab");
+        // earlier revisions cannot be published over later ones
+        assert_eq!(
+            doc.apply(&Event::Publish(Publish { id: 1, seq: 1 })),
+            Err(EditError::InvalidOperation)
+        );
+        doc.apply(&Event::Publish(Publish { id: 1, seq: 3 })).unwrap();
+        assert_eq!(doc.published, Some(3));
+        // nor can deleted documents
+        doc.apply(&Event::Tombstone(Tombstone { id: 1 })).unwrap();
+        assert_eq!(
+            doc.apply(&Event::Publish(Publish { id: 1, seq: 4 })),
+            Err(EditError::InvalidOperation)
+        );
+    }
+
     #[test]
     fn concurrent_delete_and_insert() {
         let mut doc = Document::from("ab");
-        doc.apply(&Event::Join(Join { id: 1 })).unwrap();
-        doc.apply(&Event::Join(Join { id: 2 })).unwrap();
+        doc.apply(&Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: None,
+        })).unwrap();
+        doc.apply(&Event::Join(Join {
+            id: 2,
+            role: Role::Editor,
+            user: None,
+        })).unwrap();
 
         let op1 = Operation::Delete(Delete { start: 0, end: 1 });
         let op2 = Operation::Insert(Insert {
@@ -502,8 +685,14 @@ mod tests {
         assert_eq!(
             doc1.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 0 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -512,8 +701,14 @@ mod tests {
         assert_eq!(
             doc1.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 2 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 2,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -522,8 +717,14 @@ mod tests {
         assert_eq!(
             doc1.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 1 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 1,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -533,8 +734,14 @@ mod tests {
         assert_eq!(
             doc2.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 0 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -543,8 +750,14 @@ mod tests {
         assert_eq!(
             doc2.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 0 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -553,8 +766,14 @@ mod tests {
         assert_eq!(
             doc2.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 1 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 1,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -566,8 +785,16 @@ mod tests {
     #[test]
     fn concurrent_delete_and_insert_2() {
         let mut doc = Document::from("a");
-        doc.apply(&Event::Join(Join { id: 1 })).unwrap();
-        doc.apply(&Event::Join(Join { id: 2 })).unwrap();
+        doc.apply(&Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: None,
+        })).unwrap();
+        doc.apply(&Event::Join(Join {
+            id: 2,
+            role: Role::Editor,
+            user: None,
+        })).unwrap();
 
         let op1 = Operation::Delete(Delete { start: 0, end: 1 });
         let op2 = Operation::Insert(Insert {
@@ -595,8 +822,14 @@ mod tests {
         assert_eq!(
             doc1.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 0 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -605,8 +838,14 @@ mod tests {
         assert_eq!(
             doc1.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 1 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 1,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -615,8 +854,14 @@ mod tests {
         assert_eq!(
             doc1.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 1 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 1,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -626,8 +871,14 @@ mod tests {
         assert_eq!(
             doc2.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 0 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -636,8 +887,14 @@ mod tests {
         assert_eq!(
             doc2.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 0 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -646,8 +903,14 @@ mod tests {
         assert_eq!(
             doc2.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 1 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 1,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -659,8 +922,16 @@ mod tests {
     #[test]
     fn concurrent_delete_and_insert_3() {
         let mut doc = Document::from("ab");
-        doc.apply(&Event::Join(Join { id: 1 })).unwrap();
-        doc.apply(&Event::Join(Join { id: 2 })).unwrap();
+        doc.apply(&Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: None,
+        })).unwrap();
+        doc.apply(&Event::Join(Join {
+            id: 2,
+            role: Role::Editor,
+            user: None,
+        })).unwrap();
 
         let op1 = Operation::Delete(Delete { start: 0, end: 2 });
         let op2 = Operation::Insert(Insert {
@@ -688,8 +959,14 @@ mod tests {
         assert_eq!(
             doc1.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 0 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -698,8 +975,14 @@ mod tests {
         assert_eq!(
             doc1.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 2 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 2,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -708,8 +991,14 @@ mod tests {
         assert_eq!(
             doc1.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 1 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 1,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -719,8 +1008,14 @@ mod tests {
         assert_eq!(
             doc2.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 0 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -729,8 +1024,14 @@ mod tests {
         assert_eq!(
             doc2.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 0 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -739,8 +1040,14 @@ mod tests {
         assert_eq!(
             doc2.participants,
             vec![
-                (1, DocumentParticipant { cursor_pos: 0 }),
-                (2, DocumentParticipant { cursor_pos: 1 }),
+                (1, DocumentParticipant {
+                    cursor_pos: 0,
+                    user: None,
+                }),
+                (2, DocumentParticipant {
+                    cursor_pos: 1,
+                    user: None,
+                }),
             ].into_iter()
             .collect()
         );
@@ -814,8 +1121,7 @@ mod tests {
                         let next_req_size = match op {
                             Operation::Insert(ref data) => {
                                 let len = data.content.chars().count();
-                                let tmp = if len < req_size { req_size - len } else { 0 };
-                                cmp::max(tmp, data.pos)
+                                cmp::max(req_size.saturating_sub(len), data.pos)
                             }
                             Operation::Delete(ref data) => {
                                 let removed = data.end - data.start;
@@ -834,8 +1140,8 @@ mod tests {
         max: usize,
     ) -> BoxedStrategy<(String, Vec<Operation>, Vec<Operation>)> {
         (
-            (1..(max + 1)).prop_flat_map(|num| operations_strategy(num)),
-            (1..(max + 1)).prop_flat_map(|num| operations_strategy(num)),
+            (1..(max + 1)).prop_flat_map(operations_strategy),
+            (1..(max + 1)).prop_flat_map(operations_strategy),
         )
             .prop_flat_map(move |((mut ops1, req_size1), (mut ops2, req_size2))| {
                 let req_size = cmp::max(req_size1, req_size2);
@@ -845,15 +1151,246 @@ mod tests {
             }).boxed()
     }
 
+    // A step in a generated history, which is turned into an Event
+    // by `History::event` using the participants in the document at
+    // that point
+    #[derive(Debug, Clone)]
+    enum Action {
+        // a new participant joins
+        Join,
+        // the nth current participant leaves
+        Leave(usize),
+        // the nth current participant makes an edit, with positions
+        // limited to the length of the document
+        Edit(usize, Operation),
+        // an Event the document must reject
+        Invalid(usize),
+    }
+
+    fn action_strategy() -> BoxedStrategy<Action> {
+        prop_oneof![
+            2 => Just(Action::Join),
+            1 => any::<usize>().prop_map(Action::Leave),
+            4 => (any::<usize>(), Operation::generate_strategy())
+                .prop_map(|(n, op)| Action::Edit(n, op)),
+            1 => any::<usize>().prop_map(Action::Invalid),
+        ].boxed()
+    }
+
+    // Turns Actions into Events for the document they are applied to
+    struct History {
+        next_id: ParticipantId,
+    }
+
+    impl History {
+        // Returns the Event for 'action', and whether it should
+        // apply, or None if there is no participant to act as
+        fn event(&mut self, doc: &Document, action: &Action) -> Option<(Event, bool)> {
+            let mut ids: Vec<ParticipantId> = doc.participants.entries.keys().cloned().collect();
+            ids.sort();
+            let length = doc.content.chars().count();
+            let nth = |n: usize| ids.get(n % cmp::max(ids.len(), 1)).cloned();
+            let event = match *action {
+                Action::Join => {
+                    self.next_id += 1;
+                    Event::Join(Join {
+                        id: self.next_id,
+                        role: Role::Editor,
+                        user: None,
+                    })
+                }
+                Action::Leave(n) => Event::Leave(Leave { id: nth(n)? }),
+                Action::Edit(n, ref op) => {
+                    let op = match op.clone() {
+                        Operation::Insert(Insert { pos, content }) => Operation::Insert(Insert {
+                            pos: cmp::min(pos, length),
+                            content,
+                        }),
+                        Operation::Delete(Delete { start, end }) => Operation::Delete(Delete {
+                            start: cmp::min(start, length),
+                            end: cmp::min(end, length),
+                        }),
+                        Operation::MoveCursor(MoveCursor { pos }) => {
+                            Operation::MoveCursor(MoveCursor {
+                                pos: cmp::min(pos, length),
+                            })
+                        }
+                    };
+                    Event::Edit(Edit {
+                        author: nth(n)?,
+                        operations: vec![op],
+                    })
+                }
+                Action::Invalid(n) => {
+                    // someone who has never joined
+                    let stranger = self.next_id + 1;
+                    let event = match n % 4 {
+                        0 => Event::Join(Join {
+                            id: nth(n)?,
+                            role: Role::Editor,
+                            user: None,
+                        }),
+                        1 => Event::Leave(Leave { id: stranger }),
+                        2 => Event::Edit(Edit {
+                            author: stranger,
+                            operations: vec![Operation::MoveCursor(MoveCursor { pos: 0 })],
+                        }),
+                        _ => Event::Edit(Edit {
+                            author: nth(n)?,
+                            operations: vec![Operation::Insert(Insert {
+                                pos: length + 1,
+                                content: String::from("x"),
+                            })],
+                        }),
+                    };
+                    return Some((event, false));
+                }
+            };
+            Some((event, true))
+        }
+    }
+
+    // Returns the Join and Leave events of participants from id 3
+    // onwards coming and going, as 'joins' describes
+    fn membership_events(joins: &[bool]) -> Vec<Event> {
+        let mut present = vec![];
+        let mut events = vec![];
+        for (i, join) in joins.iter().enumerate() {
+            match present.pop() {
+                Some(id) if !join => events.push(Event::Leave(Leave { id })),
+                left => {
+                    present.extend(left);
+                    let id = i + 3;
+                    present.push(id);
+                    events.push(Event::Join(Join {
+                        id,
+                        role: Role::Editor,
+                        user: None,
+                    }));
+                }
+            }
+        }
+        events
+    }
+
     proptest! {
 
+        #[test]
+        fn check_event_histories_with_joins_and_leaves
+            ((ref initial, ref actions) in (
+                generate_document_data(1),
+                proptest::collection::vec(action_strategy(), 1..40),
+            ))
+        {
+            let mut doc = Document::from(initial.as_str());
+            let mut history = History { next_id: 0 };
+            for action in actions {
+                let (event, valid) = match history.event(&doc, action) {
+                    Some(found) => found,
+                    None => continue,
+                };
+                let before = doc.clone();
+                let checked = doc.can_apply(&event);
+                let applied = doc.apply(&event);
+                prop_assert_eq!(checked.is_ok(), valid, "can_apply({:?})", event);
+                prop_assert_eq!(applied.is_ok(), valid, "apply({:?})", event);
+                if !valid {
+                    // rejected events leave the document untouched
+                    prop_assert_eq!(&doc, &before);
+                    continue;
+                }
+                let length = doc.content.chars().count();
+                for participant in doc.participants.entries.values() {
+                    prop_assert!(participant.cursor_pos <= length);
+                }
+                let cursor = |id| doc.participants.entries.get(&id).map(|p| p.cursor_pos);
+                match event {
+                    Event::Join(Join { id, .. }) => prop_assert_eq!(cursor(id), Some(0)),
+                    Event::Leave(Leave { id }) => prop_assert_eq!(cursor(id), None),
+                    Event::Tombstone(_) | Event::Restore(_) | Event::Publish(_) => (),
+                    Event::Edit(Edit { author, ref operations }) => {
+                        let expected = match operations[0] {
+                            Operation::Insert(ref op) => op.pos + op.content.chars().count(),
+                            Operation::Delete(ref op) => op.start,
+                            Operation::MoveCursor(ref op) => op.pos,
+                        };
+                        prop_assert_eq!(cursor(author), Some(expected));
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn check_transform_across_joins_and_leaves
+            ((ref initial, ref ops1, ref ops2) in conflicting_operations(2),
+             ref joins in proptest::collection::vec(any::<bool>(), 0..6),
+             split in any::<usize>())
+        {
+            let mut doc = Document::from(initial.as_str());
+            for id in 1..3 {
+                doc.apply(&Event::Join(Join {
+                    id,
+                    role: Role::Editor,
+                    user: None,
+                })).unwrap();
+            }
+            let a = Event::Edit(Edit {
+                author: 1,
+                operations: ops1.clone(),
+            });
+            let b = Event::Edit(Edit {
+                author: 2,
+                operations: ops2.clone(),
+            });
+            // the events sequenced before 'a', in which participant 2
+            // edits and leaves while others come and go
+            let mut concurrent = membership_events(joins);
+            let split = split % (concurrent.len() + 1);
+            concurrent.insert(split, b.clone());
+            concurrent.push(Event::Leave(Leave { id: 2 }));
+
+            // as the server sees it, 'a' is transformed against
+            // every concurrent event in turn
+            let mut server = doc.clone();
+            let mut a2 = a.clone();
+            for event in &concurrent {
+                server.apply(event).unwrap();
+                a2.transform(event);
+            }
+            server.apply(&a2).unwrap();
+
+            // only the edit changes how 'a' is transformed
+            let mut a3 = a.clone();
+            a3.transform(&b);
+            prop_assert_eq!(&a2, &a3);
+
+            // participant 1 applies 'a' first, then each concurrent
+            // event transformed against it
+            let mut client = doc.clone();
+            client.apply(&a).unwrap();
+            for event in &concurrent {
+                let mut event = event.clone();
+                event.transform(&a);
+                client.apply(&event).unwrap();
+            }
+            prop_assert_eq!(client, server);
+        }
+
         #[test]
         fn check_application_order_for_one_conflicting_operation_each
             ((ref initial, ref ops1, ref ops2) in conflicting_operations(1))
         {
             let mut doc = Document::from(initial.as_str());
-            doc.apply(&Event::Join(Join { id: 1 })).unwrap();
-            doc.apply(&Event::Join(Join { id: 2 })).unwrap();
+            doc.apply(&Event::Join(Join {
+                id: 1,
+                role: Role::Editor,
+                user: None,
+            })).unwrap();
+            doc.apply(&Event::Join(Join {
+                id: 2,
+                role: Role::Editor,
+                user: None,
+            })).unwrap();
             let mut a1 = Event::Edit(Edit {
                 author: 1,
                 operations: ops1.clone(),
@@ -883,8 +1420,16 @@ mod tests {
             ((ref initial, ref ops1, ref ops2) in conflicting_operations(2))
         {
             let mut doc = Document::from(initial.as_str());
-            doc.apply(&Event::Join(Join { id: 1 })).unwrap();
-            doc.apply(&Event::Join(Join { id: 2 })).unwrap();
+            doc.apply(&Event::Join(Join {
+                id: 1,
+                role: Role::Editor,
+                user: None,
+            })).unwrap();
+            doc.apply(&Event::Join(Join {
+                id: 2,
+                role: Role::Editor,
+                user: None,
+            })).unwrap();
             let mut a1 = Event::Edit(Edit {
                 author: 1,
                 operations: ops1.clone(),
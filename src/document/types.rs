@@ -57,8 +57,8 @@ impl<'de> Visitor<'de> for ParticipantsVisitor {
         let mut entries = HashMap::with_capacity(
             access.size_hint().unwrap_or(0)
         );
-        while let Some(SerializedParticipant {id, cursor_pos}) = access.next_element()? {
-            entries.insert(id, DocumentParticipant { cursor_pos });
+        while let Some(SerializedParticipant {id, cursor_pos, user}) = access.next_element()? {
+            entries.insert(id, DocumentParticipant { cursor_pos, user });
         }
         Ok(Participants {entries})
     }
@@ -77,6 +77,8 @@ impl<'de> Deserialize<'de> for Participants {
 struct SerializedParticipant {
     id: ParticipantId,
     cursor_pos: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
 }
 
 impl Serialize for Participants {
@@ -84,9 +86,13 @@ impl Serialize for Participants {
     where
         S: Serializer,
     {
-        serializer.collect_seq(self.entries.iter().map(|(k, v)| SerializedParticipant {
+        // sorted by id so the output does not depend on HashMap order
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|&(k, _)| *k);
+        serializer.collect_seq(entries.into_iter().map(|(k, v)| SerializedParticipant {
             id: *k,
-            cursor_pos: v.cursor_pos
+            cursor_pos: v.cursor_pos,
+            user: v.user.clone(),
         }))
     }
 }
@@ -95,7 +101,11 @@ impl Serialize for Participants {
 #[derive(Debug, PartialEq, Eq, Hash, Default, Deserialize, Serialize, Clone)]
 pub struct DocumentParticipant {
     /// Unicode Scalar Value index of the participant's cursor
-    pub cursor_pos: usize
+    pub cursor_pos: usize,
+    /// The authenticated user the participant is acting on behalf
+    /// of, from their Join event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
 }
 
 /// Represents some String content at a point in time
@@ -104,7 +114,19 @@ pub struct Document {
     /// Current document content
     pub content: String,
     /// Current active editors
-    pub participants: Participants
+    pub participants: Participants,
+    /// True if the document has been moved to the trash. Its content
+    /// and history are kept so it can be restored.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deleted: bool,
+    /// The SequenceId of the last published revision, None if the
+    /// document has never been published
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<u64>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 
 /// Inserts new content at a single position in the Document
@@ -146,6 +168,12 @@ pub enum Event {
     Leave (Leave),
     /// An update was made to the document
     Edit (Edit),
+    /// A participant moved the document to the trash
+    Tombstone (Tombstone),
+    /// A participant restored the document from the trash
+    Restore (Restore),
+    /// A participant published a revision of the document
+    Publish (Publish),
 }
 
 /// Describes incremental changes to a Document's content. Through the
@@ -161,11 +189,35 @@ pub enum Operation {
     MoveCursor(MoveCursor),
 }
 
+/// The level of access a participant has to the document. Roles are
+/// ordered from most to least access.
+#[derive(
+    Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize, Clone, Copy,
+)]
+pub enum Role {
+    /// Can edit the document content
+    #[default]
+    Editor,
+    /// Can move their cursor to comment on the document, but cannot
+    /// edit its content
+    Commenter,
+    /// Read only access
+    Reader,
+}
+
 /// A new participant has joined the DocumentSession
 #[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
 pub struct Join {
     /// The id of the newly joined participant
-    pub id: ParticipantId
+    pub id: ParticipantId,
+    /// The newly joined participant's access level. Events written
+    /// before roles existed are treated as joined by an Editor.
+    #[serde(default)]
+    pub role: Role,
+    /// The authenticated user the participant is acting on behalf
+    /// of, None for anonymous participants
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
 }
 
 /// A participant has left the DocumentSession
@@ -175,6 +227,31 @@ pub struct Leave {
     pub id: ParticipantId
 }
 
+/// The document was moved to the trash, it no longer appears in
+/// listings but keeps its history
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+pub struct Tombstone {
+    /// The id of the participant who deleted the document
+    pub id: ParticipantId
+}
+
+/// The document was restored from the trash
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+pub struct Restore {
+    /// The id of the participant who restored the document
+    pub id: ParticipantId
+}
+
+/// A revision of the document was published, it is shown to readers
+/// who may not see drafts until a later revision is published
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+pub struct Publish {
+    /// The id of the participant who published the revision
+    pub id: ParticipantId,
+    /// The SequenceId of the published revision
+    pub seq: u64,
+}
+
 /// An Edit combines multiple operations into a single Document
 /// change (i.e. all the operations are applied together, or not at
 /// all).
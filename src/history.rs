@@ -0,0 +1,315 @@
+//! Summarises the changes made to a document over time
+use futures::future::Future;
+use futures::stream::Stream;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::document::{Delete, Event, Insert, Join, Operation, ParticipantId};
+use crate::store::{SequenceId, Store, StoreError};
+
+/// A change to a document's content made by a single Edit event
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Revision {
+    /// The SequenceId of the Edit event
+    pub seq: SequenceId,
+    /// The participant who made the Edit
+    pub author: ParticipantId,
+    /// The authenticated user the author was acting on behalf of,
+    /// from their Join event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// The change in the document's length, in Unicode Scalar Values
+    pub size_delta: i64,
+}
+
+/// Requests every Revision of the document at 'path', oldest first.
+/// Edits which only move the author's cursor are not included.
+pub fn revisions<T: Store>(
+    store: &T,
+    path: &Path,
+) -> impl Future<Item = Vec<Revision>, Error = StoreError> {
+    store.since(path, 0, None).flatten_stream().fold(
+        (HashMap::new(), Vec::new()),
+        |(mut users, mut revisions), (seq, event)| -> Result<_, StoreError> {
+            match event {
+                Event::Join(Join { id, user, .. }) => {
+                    users.insert(id, user);
+                }
+                Event::Edit(edit) => if let Some(size_delta) = size_delta(&edit.operations) {
+                    revisions.push(Revision {
+                        seq,
+                        author: edit.author,
+                        user: users.get(&edit.author).cloned().unwrap_or(None),
+                        size_delta,
+                    });
+                },
+                Event::Leave(_)
+                | Event::Tombstone(_)
+                | Event::Restore(_)
+                | Event::Publish(_) => (),
+            }
+            Ok((users, revisions))
+        },
+    ).map(|(_, revisions)| revisions)
+}
+
+/// Returns the change in length caused by the operations, or None if
+/// they do not change the content.
+pub fn size_delta(operations: &[Operation]) -> Option<i64> {
+    let mut changed = false;
+    let mut delta = 0;
+    for op in operations {
+        match *op {
+            Operation::Insert(ref insert) => {
+                changed = true;
+                delta += insert.content.chars().count() as i64;
+            }
+            Operation::Delete(ref delete) => {
+                changed = true;
+                delta -= (delete.end - delete.start) as i64;
+            }
+            Operation::MoveCursor(_) => (),
+        }
+    }
+    if changed {
+        Some(delta)
+    } else {
+        None
+    }
+}
+
+/// A line in the difference between two versions of a document
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "text", rename_all = "lowercase")]
+pub enum LineChange {
+    /// The line is in both versions
+    Equal(String),
+    /// The line was added in the new version
+    Insert(String),
+    /// The line was removed from the old version
+    Delete(String),
+}
+
+/// Returns the lines of 'old' and 'new' with each line marked as
+/// unchanged, inserted, or deleted, using Myers' diff algorithm to
+/// find the fewest insertions and deletions.
+pub fn diff_lines(old: &str, new: &str) -> Vec<LineChange> {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m) as usize;
+    // diagonals are numbered -max..max, offset to index into 'v'
+    let offset = max as isize + 1;
+    let index = |k: isize| (k + offset) as usize;
+
+    // v[k] holds the furthest x reached on diagonal k, and trace[d]
+    // a copy of v before searching with d insertions and deletions
+    let mut v = vec![0isize; 2 * max + 3];
+    let mut trace = Vec::new();
+    'search: for d in 0..(max as isize + 1) {
+        trace.push(v.clone());
+        for k in (-d..d + 1).filter(|k| (k + d) % 2 == 0) {
+            let mut x = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+                v[index(k + 1)]
+            } else {
+                v[index(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    // walk back from the end to recover the path taken
+    let mut changes = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[index(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            changes.push(LineChange::Equal(a[x as usize - 1].to_string()));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                changes.push(LineChange::Insert(b[y as usize - 1].to_string()));
+            } else {
+                changes.push(LineChange::Delete(a[x as usize - 1].to_string()));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    changes.reverse();
+    changes
+}
+
+/// Returns the Operations which turn 'old' into 'new'. The content
+/// between their common prefix and suffix is deleted and replaced,
+/// so unchanged content either side keeps its position.
+pub fn edit_operations(old: &str, new: &str) -> Vec<Operation> {
+    let a: Vec<char> = old.chars().collect();
+    let b: Vec<char> = new.chars().collect();
+    let prefix = a.iter().zip(b.iter()).take_while(|&(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|&(x, y)| x == y)
+        .count();
+    let mut operations = Vec::new();
+    if a.len() > prefix + suffix {
+        operations.push(Operation::Delete(Delete {
+            start: prefix,
+            end: a.len() - suffix,
+        }));
+    }
+    if b.len() > prefix + suffix {
+        operations.push(Operation::Insert(Insert {
+            pos: prefix,
+            content: b[prefix..b.len() - suffix].iter().collect(),
+        }));
+    }
+    operations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Document, Edit, MoveCursor, Role};
+    use std::path::PathBuf;
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn list_revisions() {
+        let mut store = MemoryStore::default();
+        let path = PathBuf::from("test");
+        let events = vec![
+            Event::Join(Join {
+                id: 1,
+                role: Role::Editor,
+                user: Some(String::from("alice")),
+            }),
+            Event::Edit(Edit {
+                author: 1,
+                operations: vec![Operation::Insert(Insert {
+                    pos: 0,
+                    content: String::from("Hello, world"),
+                })],
+            }),
+            Event::Edit(Edit {
+                author: 1,
+                operations: vec![Operation::MoveCursor(MoveCursor { pos: 5 })],
+            }),
+            Event::Join(Join {
+                id: 2,
+                role: Role::Editor,
+                user: None,
+            }),
+            Event::Edit(Edit {
+                author: 2,
+                operations: vec![
+                    Operation::Delete(Delete { start: 5, end: 12 }),
+                    Operation::Insert(Insert {
+                        pos: 5,
+                        content: String::from("!"),
+                    }),
+                ],
+            }),
+        ];
+        for event in events {
+            store.push(path.clone(), event).wait().unwrap();
+        }
+        assert_eq!(
+            revisions(&store, &path).wait().unwrap(),
+            vec![
+                Revision {
+                    seq: 2,
+                    author: 1,
+                    user: Some(String::from("alice")),
+                    size_delta: 12,
+                },
+                Revision {
+                    seq: 5,
+                    author: 2,
+                    user: None,
+                    size_delta: -6,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_changed_lines() {
+        use self::LineChange::*;
+        assert_eq!(
+            diff_lines("one\ntwo\nthree\nfour", "one\n2\nthree\nfour\nfive"),
+            vec![
+                Equal(String::from("one")),
+                Delete(String::from("two")),
+                Insert(String::from("2")),
+                Equal(String::from("three")),
+                Equal(String::from("four")),
+                Insert(String::from("five")),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_empty_documents() {
+        assert_eq!(diff_lines("", ""), vec![]);
+        assert_eq!(
+            diff_lines("", "hello"),
+            vec![LineChange::Insert(String::from("hello"))]
+        );
+        assert_eq!(
+            diff_lines("hello", ""),
+            vec![LineChange::Delete(String::from("hello"))]
+        );
+    }
+
+    #[test]
+    fn edit_operations_between_versions() {
+        assert_eq!(
+            edit_operations("Hello, world", "Hello there, world"),
+            vec![Operation::Insert(Insert {
+                pos: 5,
+                content: String::from(" there"),
+            })]
+        );
+        assert_eq!(
+            edit_operations("aaa", "a"),
+            vec![Operation::Delete(Delete { start: 1, end: 3 })]
+        );
+        assert_eq!(edit_operations("same", "same"), vec![]);
+
+        // operations are applied in order within a single Edit
+        let mut doc = Document::default();
+        doc.apply(&Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: None,
+        })).unwrap();
+        for (old, new) in [("", "héllo wörld"), ("héllo wörld", "hello, wörld!")] {
+            doc.apply(&Event::Edit(Edit {
+                author: 1,
+                operations: edit_operations(old, new),
+            })).unwrap();
+            assert_eq!(doc.content, new);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Translations of the wiki's UI. Each locale has a catalogue of
+//! messages keyed by name, which templates show using `t`, for
+//! example `{{ t.edit }}`. Messages may contain placeholders such as
+//! `{path}`, which templates fill in using the `replace` filter.
+//!
+//! The built-in English catalogue is in locales/en.json. Other
+//! catalogues are JSON files of the same shape named after their
+//! locale, such as "fr.json", and any message they leave out is
+//! shown in English.
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The cookie storing the locale a reader has chosen
+pub const LOCALE_COOKIE: &str = "tamawiki_locale";
+
+/// The locale of the built-in catalogue
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// A catalogue of UI messages, keyed by name
+pub type Messages = HashMap<String, String>;
+
+lazy_static! {
+    static ref ENGLISH: Messages = serde_json::from_str(include_str!("../locales/en.json"))
+        .expect("locales/en.json must be a JSON object of strings");
+}
+
+/// The locales the UI can be shown in
+#[derive(Clone)]
+pub struct Locales {
+    catalogues: BTreeMap<String, Arc<Messages>>,
+    default: String,
+}
+
+impl Default for Locales {
+    fn default() -> Self {
+        let mut catalogues = BTreeMap::new();
+        catalogues.insert(String::from(DEFAULT_LOCALE), Arc::new(ENGLISH.clone()));
+        Locales {
+            catalogues,
+            default: String::from(DEFAULT_LOCALE),
+        }
+    }
+}
+
+impl Locales {
+    /// Creates the built-in English locale
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the catalogue for 'locale', a language tag such as "fr"
+    /// or "pt-BR". Messages it does not include are shown in English.
+    pub fn with_catalogue<S: AsRef<str>>(mut self, locale: S, messages: Messages) -> Self {
+        let mut merged = ENGLISH.clone();
+        merged.extend(messages);
+        self.catalogues
+            .insert(locale.as_ref().to_lowercase(), Arc::new(merged));
+        self
+    }
+
+    /// Adds a catalogue for each "<locale>.json" file in 'dir'
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let mut locales = Self::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let locale = match path.file_stem() {
+                Some(stem) => stem.to_string_lossy().into_owned(),
+                None => continue,
+            };
+            let messages = serde_json::from_slice(&fs::read(&path)?).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), err),
+                )
+            })?;
+            locales = locales.with_catalogue(locale, messages);
+        }
+        Ok(locales)
+    }
+
+    /// Shows the UI in 'locale' when the reader's preferences match
+    /// none of the catalogues. The locale must have a catalogue.
+    pub fn with_default<S: AsRef<str>>(mut self, locale: S) -> Self {
+        self.default = locale.as_ref().to_lowercase();
+        self
+    }
+
+    /// The locales which have a catalogue
+    pub fn names(&self) -> Vec<String> {
+        self.catalogues.keys().cloned().collect()
+    }
+
+    /// Returns the locale and catalogue to show the UI with, using
+    /// the locale the reader chose if there is one, then the
+    /// languages from their Accept-Language header
+    pub fn negotiate(
+        &self,
+        preferred: Option<&str>,
+        accept_language: Option<&str>,
+    ) -> (String, Arc<Messages>) {
+        let ranges = preferred
+            .map(String::from)
+            .into_iter()
+            .chain(accept_language.map(language_ranges).unwrap_or_default());
+        for range in ranges {
+            if let Some(found) = self.lookup(&range) {
+                return found;
+            }
+        }
+        self.lookup(&self.default)
+            .unwrap_or_else(|| (String::from(DEFAULT_LOCALE), Arc::new(ENGLISH.clone())))
+    }
+
+    // Finds the catalogue for a language range, falling back to more
+    // general locales, so "en-GB" uses "en" if there is no "en-gb"
+    fn lookup(&self, range: &str) -> Option<(String, Arc<Messages>)> {
+        let mut range = range.trim().to_lowercase();
+        if range == "*" {
+            range = self.default.clone();
+        }
+        loop {
+            if let Some(messages) = self.catalogues.get(&range) {
+                return Some((range, messages.clone()));
+            }
+            let end = range.rfind('-')?;
+            range.truncate(end);
+        }
+    }
+}
+
+/// Returns the key of the message translating the English 'text',
+/// used to translate page titles, for example "Not Found" is the
+/// message "not_found"
+pub fn message_key(text: &str) -> String {
+    text.to_lowercase().replace(' ', "_")
+}
+
+// The language ranges from an Accept-Language header, most preferred
+// first
+fn language_ranges(header: &str) -> Vec<String> {
+    // the quality, in thousandths, of each range
+    let mut ranges: Vec<(u32, String)> = header
+        .split(',')
+        .filter_map(|range| {
+            let mut params = range.split(';');
+            let tag = params.next().unwrap_or("").trim();
+            let quality = params
+                .filter_map(|param| {
+                    let param = param.trim();
+                    if let Some(q) = param.strip_prefix("q=") {
+                        q.parse::<f32>().ok().map(|q| (q * 1000.0) as u32)
+                    } else {
+                        None
+                    }
+                }).next()
+                .unwrap_or(1000);
+            if tag.is_empty() || quality == 0 {
+                None
+            } else {
+                Some((quality, String::from(tag)))
+            }
+        }).collect();
+    // the sort is stable, so equally ranked languages keep their order
+    ranges.sort_by_key(|range| Reverse(range.0));
+    ranges.into_iter().map(|(_, tag)| tag).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn french() -> Locales {
+        let mut messages = Messages::new();
+        messages.insert(String::from("edit"), String::from("Modifier"));
+        Locales::new().with_catalogue("fr", messages)
+    }
+
+    #[test]
+    fn accept_language_ranges() {
+        assert_eq!(
+            language_ranges("de;q=0.5, fr-CA, en;q=0.8, *;q=0.1, es;q=0"),
+            vec!["fr-CA", "en", "de", "*"]
+        );
+        assert!(language_ranges("").is_empty());
+    }
+
+    #[test]
+    fn negotiate_locale() {
+        let locales = french();
+        let locale = |preferred, accept| locales.negotiate(preferred, accept).0;
+        assert_eq!(locale(None, None), "en");
+        assert_eq!(locale(None, Some("fr-CA, en;q=0.5")), "fr");
+        assert_eq!(locale(None, Some("de, en;q=0.9, fr;q=0.8")), "en");
+        assert_eq!(locale(None, Some("de")), "en");
+        // a locale the reader chose wins over their browser's
+        assert_eq!(locale(Some("fr"), Some("en")), "fr");
+        assert_eq!(locale(Some("de"), Some("en;q=0.1, fr")), "fr");
+        assert_eq!(french().with_default("fr").negotiate(None, Some("*")).0, "fr");
+    }
+
+    #[test]
+    fn fall_back_to_english() {
+        let (_, messages) = french().negotiate(None, Some("fr"));
+        assert_eq!(messages["edit"], "Modifier");
+        assert_eq!(messages["view"], "View");
+        assert_eq!(message_key("Not Found"), "not_found");
+        assert!(ENGLISH.contains_key(&message_key("Internal Server Error")));
+    }
+}
@@ -0,0 +1,170 @@
+//! Imports the pages of a Gollum wiki, the git repository behind a
+//! GitHub wiki, keeping the history of each page's commits.
+//!
+//! The repository is read using the `git` command, so it must be
+//! installed. Every page at the HEAD commit is imported, with a
+//! revision for each commit which changed it. A page's extension is
+//! replaced by ".html", so `Getting-Started.md` is imported as
+//! `Getting-Started.html`, and its markup is imported unchanged.
+use chrono::{DateTime, Utc};
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::Revision;
+
+// The extensions of the markup formats Gollum renders as pages
+const PAGE_EXTENSIONS: &[&str] = &[
+    "asciidoc", "creole", "markdown", "md", "mediawiki", "org", "pod", "rdoc", "rest", "rst",
+    "textile", "txt", "wiki",
+];
+
+/// Reads the pages in the git repository at 'dir', returning the
+/// path each is imported as with its revisions, oldest first. Pages
+/// which are not valid UTF-8 are left out.
+pub fn read_repo<P: AsRef<Path>>(dir: P) -> io::Result<Vec<(PathBuf, Vec<Revision>)>> {
+    let dir = dir.as_ref();
+    let mut pages = Vec::new();
+    for file in git(dir, &["ls-tree", "-r", "-z", "--name-only", "HEAD"])?.split('\0') {
+        let is_page = Path::new(file)
+            .extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|ext| PAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()));
+        if !is_page {
+            continue;
+        }
+        if let Some(revisions) = revisions(dir, file)? {
+            pages.push((Path::new(file).with_extension("html"), revisions));
+        }
+    }
+    Ok(pages)
+}
+
+// Returns the content of 'file' after each commit which changed it,
+// or None if it is not valid UTF-8
+fn revisions(dir: &Path, file: &str) -> io::Result<Option<Vec<Revision>>> {
+    let log = git(
+        dir,
+        &["log", "--reverse", "-z", "--format=%H%x01%an%x01%aI", "--", file],
+    )?;
+    let mut revisions = Vec::new();
+    for commit in log.split('\0').filter(|commit| !commit.is_empty()) {
+        let fields: Vec<&str> = commit.trim().split('\x01').collect();
+        if fields.len() != 3 {
+            return Err(invalid(format!("unexpected git log output: {}", commit)));
+        }
+        let timestamp = DateTime::parse_from_rfc3339(fields[2])
+            .map_err(|_| invalid(format!("invalid commit date: {}", fields[2])))?;
+        // the file may have been deleted by this commit and added
+        // again later
+        let text = match show(dir, fields[0], file)? {
+            Some(data) => match String::from_utf8(data) {
+                Ok(text) => text,
+                Err(_) => return Ok(None),
+            },
+            None => String::new(),
+        };
+        revisions.push(Revision {
+            author: Some(String::from(fields[1])),
+            timestamp: Some(timestamp.with_timezone(&Utc)),
+            text,
+        });
+    }
+    Ok(Some(revisions))
+}
+
+// Returns the content of 'file' as of 'commit', or None if it did not
+// exist in that commit
+fn show(dir: &Path, commit: &str, file: &str) -> io::Result<Option<Vec<u8>>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .arg("show")
+        .arg(format!("{}:{}", commit, file))
+        .output()?;
+    Ok(if output.status.success() {
+        Some(output.stdout)
+    } else {
+        None
+    })
+}
+
+// Runs a git command in the repository at 'dir', returning its output
+fn git(dir: &Path, args: &[&str]) -> io::Result<String> {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(invalid(format!("git {} failed: {}", args[0], stderr.trim())));
+    }
+    String::from_utf8(output.stdout).map_err(|_| invalid(String::from("git output is not UTF-8")))
+}
+
+// An error for git output which could not be read
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    // Commits the repository's working tree as 'author'
+    fn commit(dir: &Path, author: &str) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.email=test@example.com", "-c"])
+            .arg(format!("user.name={}", author))
+            .args(["commit", "-q", "-a", "-m", "Update"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn read_page_history() {
+        let dir = env::temp_dir().join("tamawiki-gollum-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("guides")).unwrap();
+        git(&dir, &["init", "-q"]).unwrap();
+        fs::write(dir.join("Home.md"), "Welcome").unwrap();
+        fs::write(dir.join("logo.png"), [0xff, 0xfe]).unwrap();
+        git(&dir, &["add", "."]).unwrap();
+        commit(&dir, "Alice");
+        fs::write(dir.join("Home.md"), "Welcome!").unwrap();
+        fs::write(dir.join("guides/Setup.textile"), "Install it").unwrap();
+        git(&dir, &["add", "."]).unwrap();
+        commit(&dir, "Bob");
+
+        let pages = read_repo(&dir).unwrap();
+        let summary: Vec<_> = pages
+            .iter()
+            .map(|&(ref path, ref revisions)| {
+                let history: Vec<_> = revisions
+                    .iter()
+                    .map(|r| (r.author.clone().unwrap(), r.text.clone()))
+                    .collect();
+                (path.clone(), history)
+            }).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    PathBuf::from("Home.html"),
+                    vec![
+                        (String::from("Alice"), String::from("Welcome")),
+                        (String::from("Bob"), String::from("Welcome!")),
+                    ]
+                ),
+                (
+                    PathBuf::from("guides/Setup.html"),
+                    vec![(String::from("Bob"), String::from("Install it"))]
+                ),
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+//! Imports pages from a MediaWiki XML export, as made by
+//! Special:Export or dumpBackup.php, keeping each page's revisions
+//! and their authors.
+//!
+//! Page titles become document paths by replacing spaces with
+//! underscores and adding ".html", so "Main Page" is imported as
+//! `Main_Page.html` and subpages such as "Help/Editing" are nested
+//! under their parent's path. The wikitext is imported unchanged.
+use chrono::{DateTime, Utc};
+use futures::future::Future;
+use quick_xml::events::Event as XmlEvent;
+use quick_xml::Reader;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+
+use super::{history, import_history, Revision};
+use crate::document::{Event, ParticipantId};
+use crate::store::{Store, StoreError};
+
+/// A page in a MediaWiki export
+#[derive(Debug, PartialEq, Clone)]
+pub struct Page {
+    /// The page's title, including its namespace, e.g. "Help:Contents"
+    pub title: String,
+    /// The page's revisions, oldest first
+    pub revisions: Vec<Revision>,
+}
+
+/// Reasons an export could not be read
+#[derive(Debug, PartialEq)]
+pub enum MediaWikiError {
+    /// The export is not well-formed XML
+    Xml(String),
+    /// A revision's timestamp could not be parsed
+    InvalidTimestamp(String),
+}
+
+impl Display for MediaWikiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MediaWikiError::Xml(ref reason) => write!(f, "Invalid XML: {}", reason),
+            MediaWikiError::InvalidTimestamp(ref value) => {
+                write!(f, "Invalid timestamp: {}", value)
+            }
+        }
+    }
+}
+
+impl Error for MediaWikiError {
+    fn description(&self) -> &str {
+        match *self {
+            MediaWikiError::Xml(_) => "MediaWikiError: invalid XML",
+            MediaWikiError::InvalidTimestamp(_) => "MediaWikiError: invalid timestamp",
+        }
+    }
+}
+
+impl Page {
+    /// Returns the path of the document the page is imported as
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(format!("{}.html", self.title.replace(' ', "_")))
+    }
+
+    /// Returns the Events recreating the page's history, see
+    /// `import::history`
+    pub fn events(&self, id: ParticipantId) -> Vec<Event> {
+        history(id, &self.revisions)
+    }
+}
+
+/// Reads the pages in a MediaWiki export. Each page's revisions are
+/// sorted by their timestamp, since exports may list them in any
+/// order.
+pub fn parse(xml: &str) -> Result<Vec<Page>, MediaWikiError> {
+    let mut reader = Reader::from_str(xml);
+    let mut buf = Vec::new();
+    // the names of the elements enclosing the current position
+    let mut elements: Vec<Vec<u8>> = Vec::new();
+    let mut pages = Vec::new();
+    let mut page = None;
+    let mut revision = None;
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(XmlEvent::Start(ref e)) => {
+                match e.name() {
+                    b"page" => {
+                        page = Some(Page {
+                            title: String::new(),
+                            revisions: Vec::new(),
+                        })
+                    }
+                    b"revision" => {
+                        revision = Some(Revision {
+                            author: None,
+                            timestamp: None,
+                            text: String::new(),
+                        })
+                    }
+                    _ => (),
+                }
+                elements.push(e.name().to_vec());
+            }
+            Ok(XmlEvent::End(_)) => match elements.pop() {
+                Some(ref name) if name == b"revision" => {
+                    if let (Some(page), Some(revision)) = (page.as_mut(), revision.take()) {
+                        page.revisions.push(revision);
+                    }
+                }
+                Some(ref name) if name == b"page" => {
+                    if let Some(mut page) = page.take() {
+                        page.revisions.sort_by_key(|revision| revision.timestamp);
+                        pages.push(page);
+                    }
+                }
+                _ => (),
+            },
+            Ok(XmlEvent::Text(ref e)) => {
+                let text = e
+                    .unescape_and_decode(&reader)
+                    .map_err(|err| MediaWikiError::Xml(format!("{}", err)))?;
+                // text is read from the element it is directly inside
+                let n = elements.len();
+                let names = if n < 2 {
+                    (&b""[..], &b""[..])
+                } else {
+                    (&elements[n - 2][..], &elements[n - 1][..])
+                };
+                match names {
+                    (b"page", b"title") => {
+                        if let Some(ref mut page) = page {
+                            page.title.push_str(&text);
+                        }
+                    }
+                    (b"revision", b"timestamp") => {
+                        let timestamp = DateTime::parse_from_rfc3339(&text)
+                            .map_err(|_| MediaWikiError::InvalidTimestamp(text.clone()))?;
+                        if let Some(ref mut revision) = revision {
+                            revision.timestamp = Some(timestamp.with_timezone(&Utc));
+                        }
+                    }
+                    (b"contributor", b"username") | (b"contributor", b"ip") => {
+                        if let Some(ref mut revision) = revision {
+                            revision.author = Some(text);
+                        }
+                    }
+                    (b"revision", b"text") => {
+                        if let Some(ref mut revision) = revision {
+                            revision.text.push_str(&text);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            Ok(XmlEvent::Eof) => return Ok(pages),
+            Ok(_) => (),
+            Err(err) => {
+                let reason = format!("{} at byte {}", err, reader.buffer_position());
+                return Err(MediaWikiError::Xml(reason));
+            }
+        }
+        buf.clear();
+    }
+}
+
+/// Creates a document for each of 'pages' with its revision history,
+/// using `import::import_history`
+pub fn import<T: Store>(
+    store: &T,
+    pages: Vec<Page>,
+) -> impl Future<Item = Vec<PathBuf>, Error = StoreError> {
+    let documents = pages
+        .into_iter()
+        .map(|page| (page.path(), page.revisions))
+        .collect();
+    import_history(store, documents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::Stream;
+    use std::path::Path;
+    use crate::store::memory::MemoryStore;
+
+    const EXPORT: &str = r#"<mediawiki xmlns="http://www.mediawiki.org/xml/export-0.10/">
+  <siteinfo>
+    <sitename>Example</sitename>
+  </siteinfo>
+  <page>
+    <title>Main Page</title>
+    <ns>0</ns>
+    <revision>
+      <id>2</id>
+      <timestamp>2018-06-02T12:00:00Z</timestamp>
+      <contributor><ip>192.0.2.1</ip></contributor>
+      <text xml:space="preserve">Hello &amp; welcome</text>
+    </revision>
+    <revision>
+      <id>1</id>
+      <timestamp>2018-06-01T12:00:00Z</timestamp>
+      <contributor><username>Alice</username><id>1</id></contributor>
+      <text xml:space="preserve">Hello</text>
+    </revision>
+  </page>
+  <page>
+    <title>Help/Editing</title>
+    <ns>0</ns>
+    <revision>
+      <timestamp>2018-06-03T12:00:00Z</timestamp>
+      <contributor deleted="deleted" />
+      <text xml:space="preserve">Click edit</text>
+    </revision>
+  </page>
+</mediawiki>"#;
+
+    #[test]
+    fn parse_export() {
+        let pages = parse(EXPORT).unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].path(), PathBuf::from("Main_Page.html"));
+        assert_eq!(pages[1].path(), PathBuf::from("Help/Editing.html"));
+        // revisions are put in order
+        let authors: Vec<_> = pages[0].revisions.iter().map(|r| r.author.clone()).collect();
+        assert_eq!(
+            authors,
+            vec![Some(String::from("Alice")), Some(String::from("192.0.2.1"))]
+        );
+        assert_eq!(pages[0].revisions[1].text, "Hello & welcome");
+        assert_eq!(
+            pages[0].revisions[0].timestamp.unwrap().to_rfc3339(),
+            "2018-06-01T12:00:00+00:00"
+        );
+        assert_eq!(pages[1].revisions[0].author, None);
+    }
+
+    #[test]
+    fn reject_invalid_export() {
+        assert!(parse("<mediawiki><page></mediawiki>").is_err());
+        let invalid = "<revision><timestamp>yesterday</timestamp></revision>";
+        assert_eq!(
+            parse(invalid),
+            Err(MediaWikiError::InvalidTimestamp(String::from("yesterday")))
+        );
+    }
+
+    #[test]
+    fn import_revisions() {
+        let store = MemoryStore::default();
+        let pages = parse(EXPORT).unwrap();
+        let created = import(&store, pages.clone()).wait().unwrap();
+        assert_eq!(
+            created,
+            vec![
+                PathBuf::from("Main_Page.html"),
+                PathBuf::from("Help/Editing.html"),
+            ]
+        );
+        let (seq, doc) = store.content(Path::new("Main_Page.html")).wait().unwrap();
+        assert_eq!(seq, 6);
+        assert_eq!(doc.content, "Hello & welcome");
+        let events = store
+            .since(Path::new("Main_Page.html"), 0, None)
+            .flatten_stream()
+            .collect()
+            .wait()
+            .unwrap();
+        match events[3] {
+            (4, Event::Join(ref join)) => assert_eq!(join.user, Some(String::from("192.0.2.1"))),
+            ref event => panic!("Expected the second author to join, got: {:?}", event),
+        }
+        // pages are only imported once
+        assert!(import(&store, pages).wait().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,264 @@
+//! Imports existing content, such as a directory of markdown or text
+//! files, as new documents
+//!
+//! Content from other wikis, with its revision history, is imported
+//! by the submodules for each wiki.
+use chrono::{DateTime, Utc};
+use futures::future::{self, Either, Future};
+use futures::stream::{self, Stream};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::document::{Edit, Event, Insert, Join, Leave, Operation, ParticipantId, Role};
+use crate::history::edit_operations;
+use crate::sanitize;
+use crate::store::{Store, StoreError};
+
+pub mod gollum;
+pub mod mediawiki;
+
+/// A revision of a document in another wiki
+#[derive(Debug, PartialEq, Clone)]
+pub struct Revision {
+    /// The name of the revision's author, None if it is unknown
+    pub author: Option<String>,
+    /// When the revision was saved
+    pub timestamp: Option<DateTime<Utc>>,
+    /// The document's content as of this revision
+    pub text: String,
+}
+
+/// Reads every text file under 'dir', returning the content of each
+/// with its path relative to 'dir', which becomes its document path.
+/// Hidden files and files which are not valid UTF-8 are left out.
+pub fn read_dir<P: AsRef<Path>>(dir: P) -> io::Result<Vec<(PathBuf, String)>> {
+    let mut files = Vec::new();
+    add_files(dir.as_ref(), Path::new(""), &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+fn add_files(dir: &Path, prefix: &Path, files: &mut Vec<(PathBuf, String)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = prefix.join(&name);
+        if entry.file_type()?.is_dir() {
+            add_files(&entry.path(), &path, files)?;
+        } else if let Ok(content) = String::from_utf8(fs::read(entry.path())?) {
+            files.push((path, content));
+        }
+    }
+    Ok(())
+}
+
+/// Creates a document for each of 'files', as an initial Edit
+/// inserting its content. Files whose document already exists, or
+/// whose path is not a safe document path, are skipped. Resolves
+/// to the paths of the documents created.
+pub fn import<T: Store>(
+    store: &T,
+    files: Vec<(PathBuf, String)>,
+) -> impl Future<Item = Vec<PathBuf>, Error = StoreError> {
+    let documents = files
+        .into_iter()
+        .map(|(path, content)| (path, move |id: ParticipantId| events(id, content)))
+        .collect();
+    create_all(store, documents)
+}
+
+/// Creates a document for each of 'documents' with its revision
+/// history, see `history`. Documents which already exist, or whose
+/// path is not a safe document path, are skipped. Resolves to the
+/// paths of the documents created.
+///
+/// The store records when each Event was pushed, so the history of
+/// an imported document shows when it was imported rather than each
+/// revision's timestamp.
+pub fn import_history<T: Store>(
+    store: &T,
+    documents: Vec<(PathBuf, Vec<Revision>)>,
+) -> impl Future<Item = Vec<PathBuf>, Error = StoreError> {
+    let documents = documents
+        .into_iter()
+        .map(|(path, revisions)| (path, move |id: ParticipantId| history(id, &revisions)))
+        .collect();
+    create_all(store, documents)
+}
+
+/// Imports 'files' like `import`, but only if the store has no
+/// documents yet, so initial content is added on a wiki's first run
+/// and never again. Resolves to the paths of the documents created.
+pub fn seed<T: Store>(
+    store: &T,
+    files: Vec<(PathBuf, String)>,
+) -> impl Future<Item = Vec<PathBuf>, Error = StoreError> {
+    let store = store.clone();
+    store
+        .changes()
+        .into_future()
+        .map_err(|(err, _)| err)
+        .and_then(move |(change, _)| match change {
+            Some(_) => Either::A(future::ok(Vec::new())),
+            None => Either::B(import(&store, files)),
+        })
+}
+
+// Creates each of the 'documents' which does not exist yet, pushing
+// the Events returned when its function is called with a
+// ParticipantId. Resolves to the paths of the documents created.
+fn create_all<T, F>(
+    store: &T,
+    documents: Vec<(PathBuf, F)>,
+) -> impl Future<Item = Vec<PathBuf>, Error = StoreError>
+where
+    T: Store,
+    F: FnOnce(ParticipantId) -> Vec<Event>,
+{
+    let store = store.clone();
+    let documents: Vec<_> = documents
+        .into_iter()
+        .filter(|&(ref path, _)| sanitize::check(path).is_ok())
+        .collect();
+    stream::iter_ok(documents)
+        .and_then(move |(path, events)| {
+            let store = store.clone();
+            store.seq(&path).then(move |result| match result {
+                Ok(_) => Either::A(future::ok(None)),
+                Err(StoreError::NotFound) => {
+                    Either::B(create(store, path.clone(), events).map(|_| Some(path)))
+                }
+                Err(err) => Either::A(future::err(err)),
+            })
+        }).filter_map(|created| created)
+        .collect()
+}
+
+// Pushes the Events creating the document at 'path'
+fn create<T, F>(
+    mut store: T,
+    path: PathBuf,
+    events: F,
+) -> impl Future<Item = (), Error = StoreError>
+where
+    T: Store,
+    F: FnOnce(ParticipantId) -> Vec<Event>,
+{
+    store.next_participant_id(&path).and_then(move |id| {
+        stream::iter_ok(events(id))
+            .for_each(move |event| store.push(path.clone(), event).map(|_| ()))
+    })
+}
+
+/// Returns the Events creating a document with 'content', as the
+/// participant 'id' joining, inserting the content, then leaving
+pub fn events(id: ParticipantId, content: String) -> Vec<Event> {
+    vec![
+        Event::Join(Join {
+            id,
+            role: Role::Editor,
+            user: None,
+        }),
+        Event::Edit(Edit {
+            author: id,
+            operations: vec![Operation::Insert(Insert { pos: 0, content })],
+        }),
+        Event::Leave(Leave { id }),
+    ]
+}
+
+/// Returns the Events recreating a document's 'revisions', as the
+/// participant 'id' joining as each revision's author, editing the
+/// content to match the revision, then leaving
+pub fn history(id: ParticipantId, revisions: &[Revision]) -> Vec<Event> {
+    let mut events = Vec::new();
+    let mut content = "";
+    for revision in revisions {
+        events.push(Event::Join(Join {
+            id,
+            role: Role::Editor,
+            user: revision.author.clone(),
+        }));
+        let operations = edit_operations(content, &revision.text);
+        if !operations.is_empty() {
+            events.push(Event::Edit(Edit {
+                author: id,
+                operations,
+            }));
+        }
+        events.push(Event::Leave(Leave { id }));
+        content = revision.text.as_str();
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env;
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn import_directory() {
+        let dir = env::temp_dir().join("tamawiki-import-test");
+        fs::create_dir_all(dir.join("guides")).unwrap();
+        fs::write(dir.join("index.md"), "Welcome").unwrap();
+        fs::write(dir.join("guides/setup.txt"), "Install it").unwrap();
+        fs::write(dir.join(".hidden"), "Secret").unwrap();
+        fs::write(dir.join("logo.png"), [0xff, 0xfe]).unwrap();
+
+        let files = read_dir(&dir).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                (PathBuf::from("guides/setup.txt"), String::from("Install it")),
+                (PathBuf::from("index.md"), String::from("Welcome")),
+            ]
+        );
+
+        let mut existing = HashMap::new();
+        existing.insert(String::from("index.md"), String::from("Existing"));
+        let store = MemoryStore::from(existing);
+        let created = import(&store, files).wait().unwrap();
+        assert_eq!(created, vec![PathBuf::from("guides/setup.txt")]);
+        let (seq, doc) = store.content(Path::new("guides/setup.txt")).wait().unwrap();
+        assert_eq!(seq, 3);
+        assert_eq!(doc.content, "Install it");
+        // existing documents are left alone
+        let (_, doc) = store.content(Path::new("index.md")).wait().unwrap();
+        assert_eq!(doc.content, "Existing");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skip_unsafe_paths() {
+        let files = vec![
+            (PathBuf::from("../escape.md"), String::from("Escaped")),
+            (PathBuf::from("/etc/passwd"), String::from("Absolute")),
+            (PathBuf::from("index.md"), String::from("Welcome")),
+        ];
+        let store = MemoryStore::default();
+        let created = import(&store, files).wait().unwrap();
+        assert_eq!(created, vec![PathBuf::from("index.md")]);
+    }
+
+    #[test]
+    fn seed_empty_store() {
+        let files = vec![(PathBuf::from("index.html"), String::from("Welcome"))];
+        let store = MemoryStore::default();
+        let created = seed(&store, files.clone()).wait().unwrap();
+        assert_eq!(created, vec![PathBuf::from("index.html")]);
+
+        // stores with documents are left alone
+        let mut existing = HashMap::new();
+        existing.insert(String::from("other.html"), String::from("Existing"));
+        let store = MemoryStore::from(existing);
+        assert!(seed(&store, files).wait().unwrap().is_empty());
+        assert!(store.content(Path::new("index.html")).wait().is_err());
+    }
+}
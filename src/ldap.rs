@@ -0,0 +1,321 @@
+//! Login with directory credentials, checked against an LDAP server
+//! or Active Directory.
+//!
+//! The user's entry is found by searching the directory with a
+//! filter such as `(uid={username})`, or `(sAMAccountName={username})`
+//! for Active Directory, then the user's password is checked by
+//! binding as that entry. The common names of the groups listed in
+//! the entry's `memberOf` attribute become the user's ACL groups, so
+//! a rule for `group:staff` applies to members of
+//! `cn=staff,ou=groups,dc=example,dc=com`.
+//!
+//! Group memberships are recorded when users log in, so changes in
+//! the directory apply from their next login. They are only recorded
+//! once the user's wiki account has been found or created, so a
+//! local account with the same username never gains them.
+use futures::future::Future;
+use futures::sync::oneshot;
+use ldap3::{ldap_escape, LdapConn, ResultEntry, Scope, SearchEntry};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+use std::thread;
+
+use crate::acl::Memberships;
+
+/// The filter used to find users by default, where `{username}` is
+/// replaced by the username they log in with
+pub const DEFAULT_USER_FILTER: &str = "(uid={username})";
+
+/// The attribute listing the groups a user is a member of by default
+pub const DEFAULT_GROUP_ATTRIBUTE: &str = "memberOf";
+
+// The LDAP result code for a bind with the wrong password
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// An LDAP directory users log in with
+#[derive(Clone)]
+pub struct Directory {
+    url: String,
+    base_dn: String,
+    user_filter: String,
+    group_attribute: String,
+    // The entry and password the wiki searches the directory as,
+    // anonymously when None
+    bind: Option<(String, String)>,
+    // Only members of this group may log in, when set
+    required_group: Option<String>,
+    memberships: Memberships,
+}
+
+impl Directory {
+    /// Finds users under 'base_dn' in the directory at 'url', such
+    /// as "ldaps://dc.example.com"
+    pub fn new<S: Into<String>>(url: S, base_dn: S) -> Self {
+        Directory {
+            url: url.into(),
+            base_dn: base_dn.into(),
+            user_filter: String::from(DEFAULT_USER_FILTER),
+            group_attribute: String::from(DEFAULT_GROUP_ATTRIBUTE),
+            bind: None,
+            required_group: None,
+            memberships: Memberships::default(),
+        }
+    }
+
+    /// Sets the filter used to find a user's entry, where
+    /// `{username}` is replaced by their escaped username
+    pub fn with_user_filter<S: Into<String>>(mut self, filter: S) -> Self {
+        self.user_filter = filter.into();
+        self
+    }
+
+    /// Sets the attribute of a user's entry listing their groups
+    pub fn with_group_attribute<S: Into<String>>(mut self, attribute: S) -> Self {
+        self.group_attribute = attribute.into();
+        self
+    }
+
+    /// Searches the directory as 'dn', for directories which do not
+    /// allow anonymous searches
+    pub fn with_bind<S: Into<String>>(mut self, dn: S, password: S) -> Self {
+        self.bind = Some((dn.into(), password.into()));
+        self
+    }
+
+    /// Only lets members of 'group' log in
+    pub fn with_required_group<S: Into<String>>(mut self, group: S) -> Self {
+        self.required_group = Some(group.into());
+        self
+    }
+
+    /// Records the groups of users who log in in 'memberships', which
+    /// should be shared with the wiki's ACL
+    pub fn with_memberships(mut self, memberships: Memberships) -> Self {
+        self.memberships = memberships;
+        self
+    }
+
+    /// Records the groups `login` resolved to for 'username', once
+    /// they have been logged in to their wiki account
+    pub fn record_groups<I: IntoIterator<Item = String>>(&self, username: &str, groups: I) {
+        self.memberships.set(username, groups);
+    }
+
+    /// Resolves to true if the directory has an entry for 'username',
+    /// so the username must not be used by a local account
+    pub fn exists(
+        &self,
+        username: &str,
+    ) -> Box<dyn Future<Item = bool, Error = LdapError> + Send> {
+        let directory = self.clone();
+        let username = String::from(username);
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(directory.find(&username).map(|entries| !entries.is_empty()));
+        });
+        Box::new(
+            rx.map_err(|_| LdapError::Connection(String::from("search was cancelled")))
+                .and_then(|result| result),
+        )
+    }
+
+    /// Checks the user's password and groups, resolving to their
+    /// groups if they may log in. Fails with UnknownUser if the
+    /// directory has no entry for them. LDAP requests block, so they
+    /// are made on a thread of their own.
+    pub fn login(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Box<dyn Future<Item = Vec<String>, Error = LdapError> + Send> {
+        let directory = self.clone();
+        let username = String::from(username);
+        let password = String::from(password);
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(directory.check(&username, &password));
+        });
+        Box::new(
+            rx.map_err(|_| LdapError::Connection(String::from("login was cancelled")))
+                .and_then(|result| result),
+        )
+    }
+
+    fn check(&self, username: &str, password: &str) -> Result<Vec<String>, LdapError> {
+        // binding without a password succeeds as an anonymous user
+        if username.is_empty() || password.is_empty() {
+            return Err(LdapError::InvalidCredentials);
+        }
+        let ldap = self.connect()?;
+        let entries = self.search(&ldap, username)?;
+        // an ambiguous filter must not let one user log in as another
+        if entries.len() != 1 {
+            let _ = ldap.unbind();
+            return Err(if entries.is_empty() {
+                LdapError::UnknownUser
+            } else {
+                LdapError::InvalidCredentials
+            });
+        }
+        let entry = SearchEntry::construct(entries.into_iter().next().unwrap());
+        let bound = ldap.simple_bind(&entry.dn, password)?;
+        let _ = ldap.unbind();
+        match bound.rc {
+            0 => (),
+            INVALID_CREDENTIALS => return Err(LdapError::InvalidCredentials),
+            rc => return Err(LdapError::Connection(format!("bind failed with code {}", rc))),
+        }
+        let groups: Vec<String> = entry
+            .attrs
+            .get(&self.group_attribute)
+            .map_or(vec![], |dns| dns.iter().filter_map(|dn| group_name(dn)).collect());
+        if let Some(ref required) = self.required_group {
+            if !groups.contains(required) {
+                return Err(LdapError::NotPermitted);
+            }
+        }
+        Ok(groups)
+    }
+
+    // Returns the entries matching the user filter for 'username'
+    fn find(&self, username: &str) -> Result<Vec<ResultEntry>, LdapError> {
+        if username.is_empty() {
+            return Ok(vec![]);
+        }
+        let ldap = self.connect()?;
+        let entries = self.search(&ldap, username);
+        let _ = ldap.unbind();
+        entries
+    }
+
+    // Connects to the directory, binding as the wiki's search entry if
+    // it has one
+    fn connect(&self) -> Result<LdapConn, LdapError> {
+        let ldap = LdapConn::new(&self.url)?;
+        if let Some((ref dn, ref password)) = self.bind {
+            ldap.simple_bind(dn, password)?.success()?;
+        }
+        Ok(ldap)
+    }
+
+    fn search(&self, ldap: &LdapConn, username: &str) -> Result<Vec<ResultEntry>, LdapError> {
+        let filter = user_filter(&self.user_filter, username);
+        let (entries, _) = ldap
+            .search(
+                &self.base_dn,
+                Scope::Subtree,
+                &filter,
+                vec![self.group_attribute.as_str()],
+            )?.success()?;
+        Ok(entries)
+    }
+}
+
+/// Error conditions which may occur when logging in with the
+/// directory
+#[derive(Debug, PartialEq)]
+pub enum LdapError {
+    /// The username or password is wrong
+    InvalidCredentials,
+    /// The directory has no entry for the username
+    UnknownUser,
+    /// The user is not a member of the group required to log in
+    NotPermitted,
+    /// The directory could not be reached, or refused the wiki's
+    /// requests
+    Connection(String),
+}
+
+impl Display for LdapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LdapError::InvalidCredentials => write!(f, "InvalidCredentials"),
+            LdapError::UnknownUser => write!(f, "UnknownUser"),
+            LdapError::NotPermitted => write!(f, "NotPermitted"),
+            LdapError::Connection(ref reason) => write!(f, "Connection({})", reason),
+        }
+    }
+}
+
+impl Error for LdapError {
+    fn description(&self) -> &str {
+        match *self {
+            LdapError::InvalidCredentials => "LdapError: invalid username or password",
+            LdapError::UnknownUser => "LdapError: no such user in the directory",
+            LdapError::NotPermitted => "LdapError: not a member of the required group",
+            LdapError::Connection(_) => "LdapError: could not search the directory",
+        }
+    }
+}
+
+impl From<io::Error> for LdapError {
+    fn from(err: io::Error) -> Self {
+        LdapError::Connection(format!("{}", err))
+    }
+}
+
+// Replaces "{username}" in 'template' with 'username', escaped so it
+// matches only that username
+fn user_filter(template: &str, username: &str) -> String {
+    template.replace("{username}", &ldap_escape(username))
+}
+
+// The common name of the group with the distinguished name 'dn', e.g.
+// "staff" for "cn=staff,ou=groups,dc=example,dc=com"
+fn group_name(dn: &str) -> Option<String> {
+    let mut name = String::new();
+    let mut chars = dn.chars();
+    // the first relative name ends at the first unescaped ',' or '+'
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => name.push(chars.next()?),
+            ',' | '+' => break,
+            c => name.push(c),
+        }
+    }
+    let mut parts = name.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(attribute), Some(value)) if attribute.trim().eq_ignore_ascii_case("cn") => {
+            Some(String::from(value.trim()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_usernames() {
+        assert_eq!(user_filter(DEFAULT_USER_FILTER, "alice"), "(uid=alice)");
+        assert_eq!(
+            user_filter("(&(objectClass=user)(sAMAccountName={username}))", "*)(uid=*"),
+            "(&(objectClass=user)(sAMAccountName=\\2a\\29\\28uid=\\2a))"
+        );
+    }
+
+    #[test]
+    fn group_names() {
+        assert_eq!(
+            group_name("cn=staff,ou=groups,dc=example,dc=com"),
+            Some(String::from("staff"))
+        );
+        assert_eq!(
+            group_name("CN=Wiki Editors,OU=Groups,DC=corp,DC=example"),
+            Some(String::from("Wiki Editors"))
+        );
+        assert_eq!(group_name("cn=R\\, and D,ou=groups"), Some(String::from("R, and D")));
+        assert_eq!(group_name("ou=groups,dc=example"), None);
+    }
+
+    #[test]
+    fn reject_empty_passwords() {
+        // no connection is made, so the unreachable directory is fine
+        let directory = Directory::new("ldap://127.0.0.1:1", "dc=example,dc=com");
+        assert_eq!(directory.check("alice", ""), Err(LdapError::InvalidCredentials));
+        assert_eq!(directory.check("", "secret"), Err(LdapError::InvalidCredentials));
+        assert_eq!(directory.find("").map(|entries| entries.len()), Ok(0));
+    }
+}
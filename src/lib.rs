@@ -14,22 +14,72 @@ extern crate lazy_static;
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
-extern crate base64;
+#[macro_use]
 extern crate futures;
+#[macro_use]
+extern crate log;
+extern crate argon2;
+extern crate base64;
+extern crate chrono;
+extern crate hmac;
 extern crate http;
 extern crate hyper;
+extern crate hyper_rustls;
 extern crate hyper_staticfile;
+extern crate lettre;
+extern crate lettre_email;
+extern crate ldap3;
+#[cfg(unix)]
+extern crate libc;
+extern crate quick_xml;
+extern crate rand;
+extern crate rmp_serde;
 extern crate serde;
 extern crate serde_urlencoded;
 extern crate sha1;
+extern crate sha2;
 extern crate tokio;
+extern crate tokio_rustls;
+extern crate tracing;
+extern crate tracing_futures;
+extern crate tracing_subscriber;
 extern crate tungstenite;
+extern crate unicode_normalization;
+extern crate url;
+extern crate zip;
 
+pub mod acl;
+pub mod admin;
+pub mod assets;
+pub mod auth;
+pub mod backup;
+#[cfg(unix)]
+pub mod daemon;
 pub mod document;
+pub mod history;
+pub mod i18n;
+pub mod import;
+pub mod ldap;
+pub mod logging;
+pub mod markup;
+pub mod notify;
+pub mod oidc;
+pub mod persist;
+pub mod quota;
+pub mod robots;
+pub mod sanitize;
+pub mod search;
 pub mod service;
 pub mod session;
+pub mod slug;
 pub mod store;
-mod templates;
+pub mod telemetry;
+pub mod templates;
+pub mod testing;
+pub mod theme;
+pub mod tokens;
+pub mod users;
+pub mod webhooks;
 mod websocket;
 
-pub use service::TamaWiki;
+pub use crate::service::TamaWiki;
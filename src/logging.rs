@@ -0,0 +1,173 @@
+//! Writes the wiki's log messages to stderr, filtered by level.
+//!
+//! Levels are given as a comma separated list of a default level and
+//! overrides for the modules whose messages are too noisy or too
+//! quiet, for example:
+//!
+//! ```text
+//! info,tamawiki::websocket=warn,tamawiki::store=debug
+//! ```
+use chrono::Utc;
+use log::{self, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::cmp::Reverse;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// How each message is written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// A line of text for people to read
+    Pretty,
+    /// A JSON object per line, for log collectors
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(Format::Pretty),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format {:?}, expected json or pretty", s)),
+        }
+    }
+}
+
+/// Filters log messages by their level and module, writing them to
+/// stderr
+#[derive(Debug)]
+pub struct Logger {
+    level: LevelFilter,
+    // the most specific module comes first
+    modules: Vec<(String, LevelFilter)>,
+    format: Format,
+}
+
+impl Logger {
+    /// Parses levels such as "info,tamawiki::websocket=warn"
+    pub fn parse(levels: &str, format: Format) -> Result<Self, String> {
+        let mut logger = Logger {
+            level: LevelFilter::Info,
+            modules: Vec::new(),
+            format,
+        };
+        for filter in levels.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let mut parts = filter.splitn(2, '=');
+            let first = parts.next().unwrap_or("");
+            match parts.next() {
+                Some(level) => logger
+                    .modules
+                    .push((String::from(first.trim()), parse_level(level)?)),
+                None => logger.level = parse_level(first)?,
+            }
+        }
+        logger.modules.sort_by_key(|module| Reverse(module.0.len()));
+        Ok(logger)
+    }
+
+    /// Sends every log message to this logger
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let max = self
+            .modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.level, |max, level| if level > max { level } else { max });
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max);
+        Ok(())
+    }
+
+    // The most verbose level logged for messages from 'target'
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|&&(ref module, _)| {
+                target == module || target.starts_with(&format!("{}::", module))
+            }).map_or(self.level, |&(_, level)| level)
+    }
+
+    fn line(&self, record: &Record) -> String {
+        let time = Utc::now().to_rfc3339();
+        match self.format {
+            Format::Pretty => format!(
+                "{} {:5} {}: {}",
+                time,
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            Format::Json => json!({
+                "time": time,
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            }).to_string(),
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(io::stderr(), "{}", self.line(record));
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level
+        .trim()
+        .parse()
+        .map_err(|_| format!("unknown log level {:?}", level.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn module_levels() {
+        let logger = Logger::parse(
+            "warn, tamawiki::store=debug, tamawiki::store::memory=error",
+            Format::Pretty,
+        ).unwrap();
+        assert_eq!(logger.level_for("tamawiki::service"), LevelFilter::Warn);
+        assert_eq!(logger.level_for("tamawiki::store"), LevelFilter::Debug);
+        assert_eq!(logger.level_for("tamawiki::store::memory"), LevelFilter::Error);
+        assert_eq!(logger.level_for("tamawiki::storefront"), LevelFilter::Warn);
+        assert!(logger.enabled(
+            &Metadata::builder()
+                .level(Level::Debug)
+                .target("tamawiki::store")
+                .build()
+        ));
+        assert!(Logger::parse("loud", Format::Json).is_err());
+        assert_eq!("json".parse(), Ok(Format::Json));
+    }
+
+    #[test]
+    fn json_lines() {
+        let logger = Logger::parse("info", Format::Json).unwrap();
+        let line = logger.line(
+            &Record::builder()
+                .level(Level::Info)
+                .target("tamawiki::session")
+                .args(format_args!("Participant {} joined", 1))
+                .build(),
+        );
+        let value: ::serde_json::Value = ::serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["target"], "tamawiki::session");
+        assert_eq!(value["message"], "Participant 1 joined");
+    }
+}
@@ -1,30 +1,282 @@
-#[macro_use]
 extern crate tamawiki;
 #[macro_use]
 extern crate clap;
 extern crate futures;
 extern crate hyper;
+#[macro_use]
+extern crate log;
+extern crate tokio;
+extern crate tokio_rustls;
+extern crate tokio_signal;
 
-use futures::future::Future;
+use clap::ArgMatches;
+use futures::future::{self, Future, FutureResult, Shared};
+use futures::stream::Stream;
+use hyper::server::conn::AddrStream;
+use hyper::service::make_service_fn;
 use hyper::Server;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
 use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tamawiki::acl::{Acl, Memberships};
+use tamawiki::admin;
+use tamawiki::auth::BasicAuth;
+use tamawiki::backup::{self, Backup};
+#[cfg(unix)]
+use tamawiki::daemon::{self, Pidfile};
+use tamawiki::i18n::Locales;
+use tamawiki::import::{self, gollum, mediawiki};
+use tamawiki::ldap::{Directory, DEFAULT_USER_FILTER};
+use tamawiki::logging::{Format, Logger};
+use tamawiki::notify::{Notifier, SmtpMailer, Watchlists};
+use tamawiki::oidc;
+use tamawiki::persist;
+use tamawiki::quota::Quotas;
+use tamawiki::robots::CrawlPolicy;
+use tamawiki::search::builtin::MemoryIndex;
+use tamawiki::search::elasticsearch::Elasticsearch;
+use tamawiki::search::meilisearch::Meilisearch;
+use tamawiki::search::{Indexer, SearchBackend};
+use tamawiki::service::ratelimit::RateLimiter;
+use tamawiki::service::{tls, DEFAULT_RESERVED_PREFIX};
 use tamawiki::store::memory::MemoryStore;
+use tamawiki::store::traced::TracedStore;
+use tamawiki::telemetry::Exporter;
+use tamawiki::templates::Templates;
+use tamawiki::theme::Theme;
+use tamawiki::tokens::ApiTokens;
+use tamawiki::users::Accounts;
+use tamawiki::webhooks::matrix::Matrix;
+use tamawiki::webhooks::slack::Slack;
+use tamawiki::webhooks::{Dispatcher, Webhook};
 use tamawiki::TamaWiki;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio_rustls::rustls::{ServerConfig, ServerSession};
+use tokio_rustls::TlsStream;
+
+// How often the store is checked for changes to email watchers about
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often the store is checked for changes to post to webhooks
+const WEBHOOK_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often the search index is updated with changed documents
+const INDEX_INTERVAL: Duration = Duration::from_secs(5);
+
+// How often finished spans are sent to the OpenTelemetry collector
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// How often changed quota usage is saved to --state-dir
+const QUOTA_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Every call to the store is traced
+type Wiki = TamaWiki<TracedStore<MemoryStore>>;
 
 fn main() {
     let matches = clap_app!(TamaWiki =>
         (version: "0.1.0")
         (author: "Caolan McMahon")
         (about: "A wiki written in Rust")
-        (@arg address: -a --address +takes_value "IP address to bind to")
-        (@arg port: -p --port +takes_value "Port to bind to")
+        (@arg address: -a --address +takes_value +multiple number_of_values(1)
+            "IP address to bind to, optionally with a port such as [::1]:8080, \
+             may be given more than once")
+        (@arg port: -p --port +takes_value "Port to bind to when an address has none")
+        (@arg base_path: --("base-path") +takes_value
+            "URL path to serve the wiki under, e.g. /wiki/ behind a reverse proxy")
+        (@arg reserved_prefix: --("reserved-prefix") +takes_value
+            "Serve the wiki's own routes, such as /_static/, under this prefix instead of _, \
+             e.g. -/ so page titles may start with _")
+        (@arg templates: --templates +takes_value
+            "Directory of templates overriding the built-in ones")
+        (@arg themes: --themes +takes_value
+            "Directory containing a directory for each theme readers may choose")
+        (@arg theme: --theme +takes_value requires[themes]
+            "The theme used for readers who have not chosen one")
+        (@arg site_name: --("site-name") +takes_value "Name of the wiki shown on every page")
+        (@arg logo: --logo +takes_value
+            "Path of a logo under the static files directory, shown instead of the site name")
+        (@arg footer: --footer +takes_value "Text shown at the bottom of every page")
+        (@arg sidebar: --sidebar +takes_value
+            "Path of a document shown beside every document as navigation, e.g. sidebar.html")
+        (@arg locales: --locales +takes_value
+            "Directory of message catalogues named after their locale, e.g. fr.json")
+        (@arg locale: --locale +takes_value
+            "The locale used when a reader's preferred languages are unavailable")
+        (@arg dev: --dev
+            "Reload templates when their files change, for working on the UI")
+        (@arg attachments: --attachments +takes_value "Directory to store uploaded attachments in")
+        (@arg cert: --cert +takes_value requires[key] "PEM encoded TLS certificate chain")
+        (@arg key: --key +takes_value requires[cert] "PEM encoded TLS private key")
+        (@arg http_address: --("http-address") +takes_value +multiple number_of_values(1)
+            requires[cert] "Also serve plain HTTP on this address, alongside HTTPS")
+        (@arg htpasswd: --htpasswd +takes_value
+            "Require HTTP Basic authentication using users from an htpasswd file")
+        (@arg acl: --acl +takes_value
+            "Restrict who may view and edit documents using rules from a file")
+        (@arg robots_txt: --("robots-txt") +takes_value
+            "Serve this file as /robots.txt instead of the default rules")
+        (@arg noindex: --noindex +takes_value +multiple number_of_values(1)
+            "Ask search engines not to index documents matching this pattern, written like \
+             the patterns in an ACL, e.g. /drafts/**")
+        (@arg accounts: --accounts
+            "Let users sign up and log in, signing sessions with $TAMAWIKI_SESSION_KEY. \
+             Accounts are lost when the wiki stops unless --state-dir is given")
+        (@arg secure_cookies: --("secure-cookies") requires[accounts]
+            "Only send session cookies over HTTPS, for wikis behind a proxy which terminates \
+             TLS. This is the default when --cert is given")
+        (@arg oidc_issuer: --("oidc-issuer") +takes_value requires[accounts oidc_client_id]
+            "Let users log in with an OpenID Connect provider")
+        (@arg oidc_client_id: --("oidc-client-id") +takes_value requires[oidc_issuer]
+            "Client ID registered with the provider, the secret is read from \
+             $TAMAWIKI_OIDC_CLIENT_SECRET")
+        (@arg oidc_redirect_uri: --("oidc-redirect-uri") +takes_value requires[oidc_issuer]
+            "The wiki's <reserved-prefix>oidc/callback URL \
+             (default <scheme>://<address>:<port><base-path><reserved-prefix>oidc/callback, \
+             https with --cert)")
+        (@arg ldap_url: --("ldap-url") +takes_value requires[accounts ldap_base_dn]
+            "Let users log in with their username and password in this LDAP directory or \
+             Active Directory, e.g. ldaps://dc.example.com, searching it as \
+             $TAMAWIKI_LDAP_BIND_DN with $TAMAWIKI_LDAP_BIND_PASSWORD if set. Their directory \
+             groups can be used as groups in the ACL.")
+        (@arg ldap_base_dn: --("ldap-base-dn") +takes_value requires[ldap_url]
+            "Where to search the directory for users, e.g. ou=people,dc=example,dc=com")
+        (@arg ldap_user_filter: --("ldap-user-filter") +takes_value requires[ldap_url]
+            "Filter finding a user's entry, e.g. (sAMAccountName={username}) for Active \
+             Directory (default (uid={username}))")
+        (@arg ldap_required_group: --("ldap-required-group") +takes_value requires[ldap_url]
+            "Only let members of this directory group log in")
+        (@arg admin: --admin +takes_value +multiple number_of_values(1) requires[accounts]
+            "Let this user restore documents from the trash")
+        (@arg api_tokens: --("api-tokens") requires[accounts]
+            "Let logged in users create API tokens for scripts at /_tokens, logging requests \
+             made with them to the tamawiki::audit target. Tokens are lost when the wiki stops \
+             unless --state-dir is given")
+        (@arg api_token_rate_limit: --("api-token-rate-limit") +takes_value requires[api_tokens]
+            "The most requests a minute an API token may make (default 600)")
+        (@arg smtp_server: --("smtp-server") +takes_value requires[accounts mail_from]
+            "Let users watch pages, emailing them changes through this SMTP server, logging in \
+             with $TAMAWIKI_SMTP_USERNAME and $TAMAWIKI_SMTP_PASSWORD if set. Watchlists are \
+             lost when the wiki stops unless --state-dir is given")
+        (@arg mail_from: --("mail-from") +takes_value requires[smtp_server]
+            "The address emails about changes are sent from")
+        (@arg public_url: --("public-url") +takes_value
+            "The URL readers visit the wiki at, for links in emails, webhooks and the Atom \
+             feed (default <scheme>://<address>:<port><base-path>, https with --cert)")
+        (@arg webhook: --webhook +takes_value +multiple number_of_values(1)
+            "Post changes to documents as JSON to this URL, or only changes under a path by \
+             giving [<path>=]<url>")
+        (@arg slack_webhook: --("slack-webhook") +takes_value +multiple number_of_values(1)
+            "Post changes to a Slack channel through this incoming webhook URL, given as \
+             [<path>=]<url> to only post changes under a path")
+        (@arg matrix_room: --("matrix-room") +takes_value +multiple number_of_values(1)
+            requires[matrix_homeserver]
+            "Post changes to this Matrix room, given as [<path>=]<room id> to only post \
+             changes under a path, as the account $TAMAWIKI_MATRIX_ACCESS_TOKEN belongs to")
+        (@arg matrix_homeserver: --("matrix-homeserver") +takes_value requires[matrix_room]
+            "The URL of the homeserver Matrix rooms are posted to")
+        (@arg search: --search +takes_value
+            "Let readers search documents indexed by the builtin index, meilisearch or \
+             elasticsearch")
+        (@arg search_url: --("search-url") +takes_value requires[search]
+            "URL of the Meilisearch server or Elasticsearch cluster, logging in with \
+             $TAMAWIKI_SEARCH_API_KEY or $TAMAWIKI_SEARCH_USERNAME and $TAMAWIKI_SEARCH_PASSWORD \
+             if set")
+        (@arg search_index: --("search-index") +takes_value requires[search]
+            "Name of the search service's index to keep documents in (default tamawiki)")
+        (@arg rate_limit: --("rate-limit") +takes_value
+            "Most saves, API writes and websocket connections allowed per minute, per client \
+             address and per user")
+        (@arg quota: --quota +takes_value
+            "Most bytes each logged in user may add to documents and upload as attachments, \
+             reported to them at /_quota. Usage starts again from zero when the wiki stops \
+             unless --state-dir is given")
+        (@arg drafts: --drafts
+            "Keep changes to documents as drafts only logged in editors see until they are \
+             published, other readers see the last published revision")
+        (@arg seed: --seed +takes_value
+            "Directory of documents to start the wiki with, imported only when it is empty")
+        (@arg restore: --restore +takes_value
+            "Start with the documents and attachments from a backup made using --dump")
+        (@arg dump: --dump +takes_value
+            "Back up every document and attachment to a file when shutting down")
+        (@arg state_dir: --("state-dir") +takes_value
+            "Directory to keep user accounts, API tokens, watchlists and quota usage in, so \
+             they are kept when the wiki restarts")
+        (@arg log_level: --("log-level") +takes_value
+            "Most verbose level of messages to log, with overrides per module such as \
+             info,tamawiki::websocket=warn (default info)")
+        (@arg log_format: --("log-format") +takes_value
+            "Write log messages as json or pretty text (default pretty)")
+        (@arg otlp_endpoint: --("otlp-endpoint") +takes_value
+            "Send traces of requests, edits and store calls to this OpenTelemetry collector's \
+             OTLP/HTTP endpoint, e.g. http://localhost:4318, naming the service \
+             $OTEL_SERVICE_NAME (default tamawiki) and adding the headers in \
+             $OTEL_EXPORTER_OTLP_HEADERS")
+        (@arg daemon: --daemon "Run in the background, detached from the terminal")
+        (@arg pidfile: --pidfile +takes_value "File to write the server's process ID to")
+        (@arg log_file: --("log-file") +takes_value requires[daemon]
+            "File to append log messages and other output to when running in the background")
+        (@arg warm_up: --("warm-up") +takes_value
+            "Render this many of the most recently changed documents before serving requests")
+        (@arg shutdown_timeout: --("shutdown-timeout") +takes_value
+            "Seconds to wait for connections to close when shutting down (default 30)")
+        (@subcommand admin =>
+            (about: "Maintain a backup made using --dump while the server is not running")
+            (@setting SubcommandRequiredElseHelp)
+            (@arg backup: --backup +takes_value +required "The backup file")
+            (@subcommand list => (about: "List every document, its latest revision and size"))
+            (@subcommand delete => (about: "Delete a document and its history")
+                (@arg path: +required "Path of the document, e.g. old-page.html"))
+            (@subcommand compact =>
+                (about: "Replace the history of every document with its current content"))
+            (@subcommand gc => (about: "Delete the attachments no document refers to"))
+            (@subcommand verify =>
+                (about: "Replay a document's history, reporting the first event which does \
+                         not apply cleanly or does not match an expected checksum")
+                (@arg path: +required "Path of the document, e.g. index.html")
+                (@arg checksum: --checksum +takes_value +multiple number_of_values(1)
+                    "The SHA-256 checksum of the content expected after an event, given as \
+                     <seq>=<checksum>, e.g. one reported by a client")
+                (@arg checksums: --checksums "Print the checksum of the content after every event"))
+            (@subcommand gollum =>
+                (about: "Import pages and their history from a Gollum or GitHub wiki \
+                         repository, creating the backup if it does not exist")
+                (@arg repository: +required "Path of a clone of the wiki's git repository"))
+            (@subcommand mediawiki =>
+                (about: "Import pages and their history from a MediaWiki XML export, \
+                         creating the backup if it does not exist")
+                (@arg export: +required "The XML file made by Special:Export or dumpBackup.php"))
+        )
     ).get_matches();
 
-    let address: IpAddr = matches
-        .value_of("address")
-        .unwrap_or("127.0.0.1")
+    if let Some(admin_matches) = matches.subcommand_matches("admin") {
+        return run_admin(admin_matches);
+    }
+
+    let log_format: Format = matches
+        .value_of("log_format")
+        .unwrap_or("pretty")
         .parse()
-        .expect("Invalid IP address");
+        .expect("Invalid log format");
+    Logger::parse(matches.value_of("log_level").unwrap_or("info"), log_format)
+        .expect("Invalid log level")
+        .init()
+        .expect("Unable to start logging");
+    if let Some(endpoint) = matches.value_of("otlp_endpoint") {
+        start_tracing(endpoint);
+    }
+    // detach before any threads are started, the pidfile is removed
+    // when main returns
+    let _pidfile = detach(&matches);
 
     let port: u16 = matches
         .value_of("port")
@@ -32,16 +284,728 @@ fn main() {
         .parse()
         .expect("Invalid port");
 
-    let store = memorystore! {
-        "index.html" => "Welcome to TamaWiki.\n"
+    let addresses: Vec<SocketAddr> = match matches.values_of("address") {
+        Some(addresses) => addresses.map(|addr| listen_address(addr, port)).collect(),
+        None => vec![listen_address("127.0.0.1", port)],
+    };
+    let http_addresses: Vec<SocketAddr> = matches
+        .values_of("http_address")
+        .map_or(vec![], |addresses| {
+            addresses.map(|addr| listen_address(addr, port)).collect()
+        });
+
+    let shutdown_timeout = Duration::from_secs(
+        matches
+            .value_of("shutdown_timeout")
+            .unwrap_or("30")
+            .parse()
+            .expect("Invalid shutdown timeout"),
+    );
+
+    let attachments = matches.value_of("attachments");
+    let store = match matches.value_of("restore") {
+        Some(path) => {
+            let store = MemoryStore::default();
+            let data = fs::read(path).expect("Unable to read backup");
+            let restored = Runtime::new()
+                .expect("Unable to start runtime")
+                .block_on(backup::restore(&store, &data, attachments.map(Path::new)))
+                .expect("Unable to restore backup");
+            info!("Restored {} documents from {}", restored, path);
+            store
+        }
+        None => MemoryStore::default(),
+    };
+    if let Some(dir) = matches.value_of("seed") {
+        let files = import::read_dir(dir).expect("Unable to read seed directory");
+        let created = Runtime::new()
+            .expect("Unable to start runtime")
+            .block_on(import::seed(&store, files))
+            .expect("Unable to import seed documents");
+        if !created.is_empty() {
+            info!("Imported {} documents from {}", created.len(), dir);
+        }
+    }
+    let backup_store = store.clone();
+
+    let state_dir = matches.value_of("state_dir").map(Path::new);
+    if let Some(dir) = state_dir {
+        fs::create_dir_all(dir).expect("Unable to create state directory");
+    }
+
+    // the address and scheme used to build absolute URLs to the wiki
+    let bind_to = addresses[0];
+    let scheme = if matches.is_present("cert") { "https" } else { "http" };
+
+    let base_path = match matches.value_of("base_path").unwrap_or("/").trim_matches('/') {
+        "" => String::from("/"),
+        base => format!("/{}/", base),
+    };
+
+    let reserved_prefix = matches
+        .value_of("reserved_prefix")
+        .unwrap_or(DEFAULT_RESERVED_PREFIX);
+    if reserved_prefix.is_empty() {
+        eprintln!("--reserved-prefix must not be empty");
+        process::exit(1);
+    }
+
+    let mut wiki = TamaWiki::new(TracedStore::new(store), "public/dist")
+        .with_base_path(base_path.as_str())
+        .with_reserved_prefix(reserved_prefix);
+    let dev = matches.is_present("dev");
+    // in development the built-in templates are reloaded too
+    let templates_dir = matches
+        .value_of("templates")
+        .or(if dev { Some("templates") } else { None });
+    if let Some(path) = templates_dir {
+        let templates = Templates::from_dir(path)
+            .expect("Invalid templates")
+            .hot_reload(dev);
+        wiki = wiki.with_templates(templates);
+    }
+    if let Some(dir) = matches.value_of("themes") {
+        let entries = fs::read_dir(dir).expect("Unable to read themes directory");
+        for entry in entries {
+            let path = entry.expect("Unable to read themes directory").path();
+            if path.is_dir() {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                let theme = Theme::from_dir(name, &path).expect("Invalid theme");
+                wiki = wiki.with_theme(theme.hot_reload(dev));
+            }
+        }
+    }
+    if let Some(name) = matches.value_of("theme") {
+        wiki = wiki.with_default_theme(name);
+    }
+    if let Some(name) = matches.value_of("site_name") {
+        wiki = wiki.with_site_name(name);
+    }
+    if let Some(path) = matches.value_of("logo") {
+        wiki = wiki.with_logo(path);
+    }
+    if let Some(text) = matches.value_of("footer") {
+        wiki = wiki.with_footer(text);
+    }
+    if let Some(path) = matches.value_of("sidebar") {
+        wiki = wiki.with_sidebar(path);
+    }
+    if matches.is_present("locales") || matches.is_present("locale") {
+        let mut locales = match matches.value_of("locales") {
+            Some(dir) => Locales::from_dir(dir).expect("Invalid message catalogues"),
+            None => Locales::new(),
+        };
+        if let Some(locale) = matches.value_of("locale") {
+            locales = locales.with_default(locale);
+        }
+        wiki = wiki.with_locales(locales);
+    }
+    if let Some(path) = attachments {
+        wiki = wiki.with_attachments(path);
+    }
+    if let Some(path) = matches.value_of("htpasswd") {
+        let auth = BasicAuth::from_htpasswd(path).expect("Invalid htpasswd file");
+        wiki = wiki.with_basic_auth(auth);
+    }
+    if matches.is_present("accounts") {
+        let key = env::var("TAMAWIKI_SESSION_KEY").expect("TAMAWIKI_SESSION_KEY must be set");
+        let secure = matches.is_present("secure_cookies") || matches.is_present("cert");
+        let admins = matches.values_of("admin").into_iter().flatten().map(String::from);
+        let accounts = match state_dir {
+            Some(dir) => Accounts::open(key.as_bytes(), &dir.join("accounts.json"))
+                .expect("Unable to read accounts"),
+            None => Accounts::new(key.as_bytes()),
+        };
+        let accounts = accounts
+            .with_secure_cookies(secure)
+            .with_admins(admins);
+        wiki = wiki.with_accounts(accounts);
+    }
+    if let Some(issuer) = matches.value_of("oidc_issuer") {
+        let client_id = matches.value_of("oidc_client_id").unwrap();
+        let client_secret = env::var("TAMAWIKI_OIDC_CLIENT_SECRET")
+            .expect("TAMAWIKI_OIDC_CLIENT_SECRET must be set");
+        let redirect_uri = match matches.value_of("oidc_redirect_uri") {
+            Some(uri) => String::from(uri),
+            None => format!(
+                "{}://{}{}{}oidc/callback",
+                scheme, bind_to, base_path, reserved_prefix
+            ),
+        };
+        let provider = Runtime::new()
+            .expect("Unable to start runtime")
+            .block_on(oidc::discover(
+                String::from(issuer),
+                String::from(client_id),
+                client_secret,
+                redirect_uri,
+            )).expect("Unable to discover OpenID Connect provider");
+        wiki = wiki.with_oidc(provider);
+    }
+    // groups users are members of in the directory, which ACL rules
+    // may refer to
+    let memberships = Memberships::default();
+    if let Some(url) = matches.value_of("ldap_url") {
+        let base_dn = matches.value_of("ldap_base_dn").unwrap();
+        let mut directory = Directory::new(url, base_dn)
+            .with_user_filter(matches.value_of("ldap_user_filter").unwrap_or(DEFAULT_USER_FILTER))
+            .with_memberships(memberships.clone());
+        if let Some(group) = matches.value_of("ldap_required_group") {
+            directory = directory.with_required_group(group);
+        }
+        if let (Ok(dn), Ok(password)) = (
+            env::var("TAMAWIKI_LDAP_BIND_DN"),
+            env::var("TAMAWIKI_LDAP_BIND_PASSWORD"),
+        ) {
+            directory = directory.with_bind(dn, password);
+        }
+        wiki = wiki.with_ldap(directory);
+    }
+    if matches.is_present("api_tokens") {
+        let mut tokens = match state_dir {
+            Some(dir) => {
+                ApiTokens::open(&dir.join("tokens.json")).expect("Unable to read API tokens")
+            }
+            None => ApiTokens::default(),
+        };
+        if let Some(limit) = matches.value_of("api_token_rate_limit") {
+            tokens = tokens.with_rate_limit(limit.parse().expect("Invalid API token rate limit"));
+        }
+        wiki = wiki.with_api_tokens(tokens);
+    }
+    let acl = matches.value_of("acl").map(|path| {
+        Acl::from_file(path)
+            .expect("Invalid ACL file")
+            .with_memberships(memberships)
+    });
+    let public_url = match matches.value_of("public_url") {
+        Some(url) => String::from(url),
+        None => format!("{}://{}{}", scheme, bind_to, base_path),
+    };
+    wiki = wiki.with_public_url(public_url.as_str());
+    if let Some(server) = matches.value_of("smtp_server") {
+        let credentials = match (
+            env::var("TAMAWIKI_SMTP_USERNAME"),
+            env::var("TAMAWIKI_SMTP_PASSWORD"),
+        ) {
+            (Ok(username), Ok(password)) => Some((username, password)),
+            _ => None,
+        };
+        let from = matches.value_of("mail_from").unwrap();
+        let mailer = SmtpMailer::new(server, credentials, from).expect("Invalid SMTP server");
+        let watchlists = match state_dir {
+            Some(dir) => {
+                Watchlists::open(&dir.join("watchlists.json")).expect("Unable to read watchlists")
+            }
+            None => Watchlists::default(),
+        };
+        let store = backup_store.clone();
+        let mut notifier = Notifier::new(store, watchlists.clone(), mailer, &public_url);
+        if let Some(ref acl) = acl {
+            notifier = notifier.with_acl(Arc::new(acl.clone()));
+        }
+        if matches.is_present("drafts") {
+            notifier = notifier.with_drafts();
+        }
+        // sending email blocks, so changes are checked for on a thread
+        // of their own
+        thread::spawn(move || {
+            let mut rt = Runtime::new().expect("Unable to start runtime");
+            loop {
+                thread::sleep(NOTIFY_INTERVAL);
+                match rt.block_on(notifier.check(SystemTime::now())) {
+                    Ok(0) => (),
+                    Ok(sent) => info!("Emailed {} users about changes", sent),
+                    Err(err) => error!("Unable to check for changes to email: {}", err),
+                }
+            }
+        });
+        wiki = wiki.with_watchlists(watchlists);
+    }
+    let mut dispatcher = Dispatcher::new(backup_store.clone(), &public_url);
+    let mut hooks = 0;
+    for value in matches.values_of("webhook").into_iter().flatten() {
+        let (prefix, url) = path_prefixed(value);
+        dispatcher = dispatcher.with_hook(prefix, Webhook::new(url));
+        hooks += 1;
+    }
+    for value in matches.values_of("slack_webhook").into_iter().flatten() {
+        let (prefix, url) = path_prefixed(value);
+        dispatcher = dispatcher.with_hook(prefix, Slack::new(url));
+        hooks += 1;
+    }
+    if let Some(homeserver) = matches.value_of("matrix_homeserver") {
+        let token = env::var("TAMAWIKI_MATRIX_ACCESS_TOKEN")
+            .expect("$TAMAWIKI_MATRIX_ACCESS_TOKEN is required to post to Matrix rooms");
+        for value in matches.values_of("matrix_room").into_iter().flatten() {
+            let (prefix, room) = path_prefixed(value);
+            let matrix = Matrix::new(homeserver, room, token.as_str());
+            dispatcher = dispatcher.with_hook(prefix, matrix);
+            hooks += 1;
+        }
+    }
+    if hooks > 0 {
+        thread::spawn(move || {
+            let mut rt = Runtime::new().expect("Unable to start runtime");
+            loop {
+                thread::sleep(WEBHOOK_INTERVAL);
+                match rt.block_on(dispatcher.check(SystemTime::now())) {
+                    Ok(0) => (),
+                    Ok(sent) => info!("Posted changes to {} webhooks", sent),
+                    Err(err) => error!("Unable to check for changes to post: {}", err),
+                }
+            }
+        });
+    }
+    if let Some(backend) = matches.value_of("search") {
+        let store = backup_store.clone();
+        let drafts = matches.is_present("drafts");
+        let url = matches.value_of("search_url");
+        let index = matches.value_of("search_index").unwrap_or("tamawiki");
+        wiki = match backend {
+            "meilisearch" => {
+                let url = url.expect("--search-url is required for Meilisearch");
+                let mut meilisearch = Meilisearch::new(url, index);
+                if let Ok(key) = env::var("TAMAWIKI_SEARCH_API_KEY") {
+                    meilisearch = meilisearch.with_api_key(key);
+                }
+                Runtime::new()
+                    .expect("Unable to start runtime")
+                    .block_on(meilisearch.configure())
+                    .expect("Unable to configure the Meilisearch index");
+                with_search(wiki, store, meilisearch, drafts)
+            }
+            "elasticsearch" => {
+                let url = url.expect("--search-url is required for Elasticsearch");
+                let mut elasticsearch = Elasticsearch::new(url, index);
+                if let (Ok(username), Ok(password)) = (
+                    env::var("TAMAWIKI_SEARCH_USERNAME"),
+                    env::var("TAMAWIKI_SEARCH_PASSWORD"),
+                ) {
+                    elasticsearch = elasticsearch.with_credentials(&username, &password);
+                }
+                Runtime::new()
+                    .expect("Unable to start runtime")
+                    .block_on(elasticsearch.configure())
+                    .expect("Unable to configure the Elasticsearch index");
+                with_search(wiki, store, elasticsearch, drafts)
+            }
+            "builtin" => with_search(wiki, store, MemoryIndex::default(), drafts),
+            other => panic!("Unknown search backend: {}", other),
+        };
+    }
+    let mut crawl = CrawlPolicy::default();
+    if let Some(path) = matches.value_of("robots_txt") {
+        crawl = crawl.with_robots_txt(fs::read_to_string(path).expect("Unable to read robots.txt"));
+    }
+    if let Some(patterns) = matches.values_of("noindex") {
+        for pattern in patterns {
+            crawl = crawl.with_noindex(pattern);
+        }
+    }
+    wiki = wiki.with_crawl_policy(crawl);
+    if let Some(acl) = acl {
+        wiki = wiki.with_acl(acl);
+    }
+    if let Some(limit) = matches.value_of("rate_limit") {
+        let limit = limit.parse().expect("Invalid rate limit");
+        wiki = wiki.with_rate_limit(RateLimiter::new(limit, Duration::from_secs(60)));
+    }
+    let mut saved_quotas = None;
+    if let Some(quota) = matches.value_of("quota") {
+        let limit = quota.parse().expect("Invalid quota");
+        let quotas = match state_dir {
+            Some(dir) => {
+                let quotas = Quotas::open(limit, &dir.join("quotas.json"))
+                    .expect("Unable to read quota usage");
+                // usage is saved on a thread of its own, so edits do
+                // not wait for the file to be written. flush logs any
+                // error and tries again next time.
+                let flushed = quotas.clone();
+                thread::spawn(move || loop {
+                    thread::sleep(QUOTA_SAVE_INTERVAL);
+                    let _ = flushed.flush();
+                });
+                saved_quotas = Some(quotas.clone());
+                quotas
+            }
+            None => Quotas::new(limit),
+        };
+        wiki = wiki.with_quotas(quotas);
+    }
+    if matches.is_present("drafts") {
+        wiki = wiki.with_drafts();
+    }
+    if let Some(documents) = matches.value_of("warm_up") {
+        let documents = documents.parse().expect("Invalid number of documents to warm up");
+        let warmed = Runtime::new()
+            .expect("Unable to start runtime")
+            .block_on(wiki.warm_up(documents))
+            .expect("Unable to warm up documents");
+        info!("Rendered {} recently changed documents", warmed);
+    }
+    let sessions = wiki.clone();
+    // the watchdog is cancelled once every server has stopped, so it
+    // cannot exit part way through the backup
+    let (servers_stopped, watchdog) = mpsc::channel::<()>();
+    // every listener shuts down on the same signal
+    let shutdown = shutdown_signal()
+        .map(move |_| {
+            info!("Shutting down");
+            // the servers stop accepting connections, then wait for
+            // requests in progress and editors' pending changes
+            sessions.shutdown();
+            thread::spawn(move || {
+                if let Err(RecvTimeoutError::Timeout) = watchdog.recv_timeout(shutdown_timeout) {
+                    error!("Timed out waiting for connections to close");
+                    process::exit(1);
+                }
+            });
+        }).shared();
+
+    let tls_config = match (matches.value_of("cert"), matches.value_of("key")) {
+        (Some(cert), Some(key)) => {
+            Some(tls::server_config(cert, key).expect("Invalid TLS certificate or key"))
+        }
+        _ => None,
+    };
+    // the listeners share one wiki, so editors connected through any
+    // of them edit the same document sessions
+    let mut servers = Vec::new();
+    for &addr in &addresses {
+        servers.push(match tls_config {
+            Some(ref config) => serve_https(&wiki, addr, config.clone(), stopped(&shutdown)),
+            None => serve_http(&wiki, addr, stopped(&shutdown)),
+        });
+        let scheme = if tls_config.is_some() { "https" } else { "http" };
+        info!("Server running at {}://{}{}", scheme, addr, base_path);
+    }
+    for &addr in &http_addresses {
+        servers.push(serve_http(&wiki, addr, stopped(&shutdown)));
+        info!("Server running at http://{}{}", addr, base_path);
+    }
+    hyper::rt::run(future::join_all(servers).map(|_| ()));
+    // save usage from the last edits before exiting
+    if let Some(quotas) = saved_quotas {
+        let _ = quotas.flush();
+    }
+    drop(servers_stopped);
+
+    if let Some(path) = matches.value_of("dump") {
+        let data = Runtime::new()
+            .expect("Unable to start runtime")
+            .block_on(backup::dump(&backup_store, attachments.map(PathBuf::from)))
+            .expect("Unable to back up the wiki");
+        // an earlier backup is only replaced once this one is complete
+        persist::write(Path::new(path), &data).expect("Unable to write backup");
+        info!("Backed up the wiki to {}", path);
+    }
+}
+
+// Splits a webhook option given as [<path>=]<target> into the path
+// prefix, empty for every document, and the target. An '=' after the
+// start of a URL is part of the URL.
+fn path_prefixed(value: &str) -> (&str, &str) {
+    let end = value.find("://").unwrap_or(value.len());
+    match value[..end].find('=') {
+        Some(i) => (&value[..i], &value[i + 1..]),
+        None => ("", value),
+    }
+}
+
+// Lets readers search using 'backend', updating it with changed
+// documents on a thread of its own. When 'drafts' is true only
+// published revisions are indexed.
+fn with_search<S>(
+    wiki: Wiki,
+    store: MemoryStore,
+    backend: S,
+    drafts: bool,
+) -> Wiki
+where
+    S: SearchBackend + Clone,
+{
+    let mut indexer = Indexer::new(store, backend.clone());
+    if drafts {
+        indexer = indexer.with_drafts();
+    }
+    thread::spawn(move || {
+        let mut rt = Runtime::new().expect("Unable to start runtime");
+        loop {
+            match rt.block_on(indexer.update()) {
+                Ok(0) => (),
+                Ok(indexed) => info!("Indexed {} changed documents", indexed),
+                Err(err) => error!("Unable to index documents: {}", err),
+            }
+            thread::sleep(INDEX_INTERVAL);
+        }
+    });
+    wiki.with_search(backend)
+}
+
+// Records spans and sends them to the collector at 'endpoint' on a
+// thread of its own
+fn start_tracing(endpoint: &str) {
+    let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| String::from("tamawiki"));
+    let mut exporter = Exporter::new(endpoint, service_name.as_str());
+    // headers are given as a comma separated list of name=value
+    if let Ok(headers) = env::var("OTEL_EXPORTER_OTLP_HEADERS") {
+        for header in headers.split(',').filter(|header| !header.trim().is_empty()) {
+            let mut parts = header.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let value = parts.next().expect("Invalid OTEL_EXPORTER_OTLP_HEADERS");
+            exporter = exporter
+                .with_header(name, value)
+                .expect("Invalid OTEL_EXPORTER_OTLP_HEADERS");
+        }
+    }
+    exporter.clone().init().expect("Unable to start tracing");
+    thread::spawn(move || {
+        let mut rt = Runtime::new().expect("Unable to start runtime");
+        loop {
+            thread::sleep(EXPORT_INTERVAL);
+            if let Err(err) = rt.block_on(exporter.export()) {
+                error!("Unable to export traces: {}", err);
+            }
+        }
+    });
+}
+
+// Runs in the background if --daemon was given, and writes the
+// process ID to the --pidfile
+#[cfg(unix)]
+fn detach(matches: &ArgMatches) -> Option<Pidfile> {
+    if matches.is_present("daemon") {
+        daemon::daemonize(matches.value_of("log_file").map(Path::new))
+            .expect("Unable to run in the background");
+    }
+    matches
+        .value_of("pidfile")
+        .map(|path| Pidfile::create(path).expect("Unable to write pidfile"))
+}
+
+#[cfg(not(unix))]
+fn detach(matches: &ArgMatches) -> Option<()> {
+    if matches.is_present("daemon") || matches.is_present("pidfile") {
+        eprintln!("--daemon and --pidfile are only supported on Unix");
+        process::exit(1);
+    }
+    None
+}
+
+// Runs an admin subcommand, saving the backup again if it changed
+fn run_admin(matches: &ArgMatches) {
+    let path = matches.value_of("backup").unwrap();
+    let mut backup = match fs::read(path) {
+        Ok(data) => Backup::read(&data).expect("Invalid backup"),
+        // a new wiki can be started from imported pages
+        Err(ref err)
+            if err.kind() == io::ErrorKind::NotFound
+                && (matches.subcommand_name() == Some("gollum")
+                    || matches.subcommand_name() == Some("mediawiki")) =>
+        {
+            Backup::default()
+        }
+        Err(err) => panic!("Unable to read backup: {}", err),
     };
+    match matches.subcommand() {
+        ("list", _) => {
+            for doc in admin::list(&backup).expect("Invalid backup") {
+                println!("{}\t{}\t{}", doc.path.display(), doc.seq, doc.size);
+            }
+            return;
+        }
+        ("delete", Some(args)) => {
+            let doc = args.value_of("path").unwrap();
+            if !admin::delete(&mut backup, Path::new(doc)) {
+                eprintln!("Document not found: {}", doc);
+                process::exit(1);
+            }
+            println!("Deleted {}", doc);
+        }
+        ("compact", _) => {
+            let removed = admin::compact(&mut backup).expect("Invalid backup");
+            println!("Removed {} events", removed);
+        }
+        ("gc", _) => {
+            for attachment in admin::gc(&mut backup).expect("Invalid backup") {
+                println!("Deleted {}", attachment.display());
+            }
+        }
+        ("verify", Some(args)) => {
+            verify(&backup, args);
+            return;
+        }
+        ("gollum", Some(args)) => {
+            let repository = args.value_of("repository").unwrap();
+            let pages = gollum::read_repo(repository).expect("Unable to read repository");
+            let mut imported = 0;
+            for (path, revisions) in pages {
+                if admin::add(&mut backup, path.clone(), import::history(1, &revisions)) {
+                    imported += 1;
+                } else {
+                    println!("Skipped {}, it already exists", path.display());
+                }
+            }
+            println!("Imported {} pages", imported);
+        }
+        ("mediawiki", Some(args)) => {
+            let export = args.value_of("export").unwrap();
+            let xml = fs::read_to_string(export).expect("Unable to read export");
+            let pages = mediawiki::parse(&xml).expect("Invalid export");
+            let mut imported = 0;
+            for page in pages {
+                let path = page.path();
+                if admin::add(&mut backup, path.clone(), page.events(1)) {
+                    imported += 1;
+                } else {
+                    println!("Skipped {}, it already exists", path.display());
+                }
+            }
+            println!("Imported {} pages", imported);
+        }
+        _ => unreachable!(),
+    }
+    let data = backup.write().expect("Unable to write backup");
+    persist::write(Path::new(path), &data).expect("Unable to write backup");
+}
 
-    let bind_to: SocketAddr = (address, port).into();
+// Replays a document in the backup, exiting with an error status if
+// its history diverges
+fn verify(backup: &Backup, args: &ArgMatches) {
+    let doc = args.value_of("path").unwrap();
+    let events = match backup.documents.get(Path::new(doc)) {
+        Some(events) => events,
+        None => {
+            eprintln!("Document not found: {}", doc);
+            process::exit(1);
+        }
+    };
+    let mut expected = BTreeMap::new();
+    if let Some(values) = args.values_of("checksum") {
+        for value in values {
+            let mut parts = value.splitn(2, '=');
+            let seq = parts.next().and_then(|seq| seq.parse().ok());
+            match (seq, parts.next()) {
+                (Some(seq), Some(checksum)) => expected.insert(seq, String::from(checksum)),
+                _ => panic!("Invalid checksum, expected <seq>=<checksum>: {}", value),
+            };
+        }
+    }
+    let replay = admin::verify(events, &expected);
+    if args.is_present("checksums") {
+        for (i, checksum) in replay.checksums.iter().enumerate() {
+            println!("{}\t{}", i + 1, checksum);
+        }
+    }
+    match replay.divergence {
+        Some(divergence) => {
+            eprintln!("Diverged at event {}: {}", divergence.seq, divergence.reason);
+            process::exit(1);
+        }
+        None => println!(
+            "Verified {} events, checksum {}",
+            events.len(),
+            replay
+                .checksums
+                .last()
+                .cloned()
+                .unwrap_or_else(|| admin::checksum(""))
+        ),
+    }
+}
 
-    let server = Server::bind(&bind_to)
-        .serve(TamaWiki::new(store, "public/dist"))
-        .map_err(|err| eprintln!("Server error: {}", err));
+// Resolves when the shared shutdown signal does, for each listener
+fn stopped<F>(shutdown: &Shared<F>) -> impl Future<Item = (), Error = ()> + Send
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    shutdown.clone().then(|_| Ok(()))
+}
+
+// Parses a listening address, either an IP address which uses 'port'
+// or an address with its own port
+fn listen_address(address: &str, port: u16) -> SocketAddr {
+    address.parse().unwrap_or_else(|_| {
+        let ip: IpAddr = address.parse().expect("Invalid IP address");
+        (ip, port).into()
+    })
+}
+
+// Serves the wiki over plain HTTP on 'addr' until 'shutdown' resolves
+fn serve_http<F>(
+    wiki: &Wiki,
+    addr: SocketAddr,
+    shutdown: F,
+) -> Box<dyn Future<Item = (), Error = ()> + Send>
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    let wiki = wiki.clone();
+    let server = Server::bind(&addr)
+        .serve(make_service_fn(move |conn: &AddrStream| {
+            with_peer_addr(&wiki, Some(conn.remote_addr()))
+        })).with_graceful_shutdown(shutdown)
+        .map_err(|err| error!("Server error: {}", err));
+    Box::new(server)
+}
+
+// Serves the wiki over HTTPS on 'addr' until 'shutdown' resolves
+fn serve_https<F>(
+    wiki: &Wiki,
+    addr: SocketAddr,
+    config: Arc<ServerConfig>,
+    shutdown: F,
+) -> Box<dyn Future<Item = (), Error = ()> + Send>
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    let wiki = wiki.clone();
+    let listener = TcpListener::bind(&addr).expect("Unable to bind to address");
+    let server = Server::builder(tls::incoming(listener, config))
+        .serve(make_service_fn(move |stream: &TlsStream<TcpStream, ServerSession>| {
+            let (tcp, _) = stream.get_ref();
+            with_peer_addr(&wiki, tcp.peer_addr().ok())
+        })).with_graceful_shutdown(shutdown)
+        .map_err(|err| error!("Server error: {}", err));
+    Box::new(server)
+}
+
+// Clones the wiki for a new connection, so requests can be rate
+// limited by the client's address
+fn with_peer_addr(
+    wiki: &Wiki,
+    addr: Option<SocketAddr>,
+) -> FutureResult<Wiki, hyper::Error> {
+    let wiki = wiki.clone();
+    future::ok(match addr {
+        Some(addr) => wiki.with_remote_addr(addr),
+        None => wiki,
+    })
+}
+
+// Resolves when the process receives a SIGTERM or SIGINT
+#[cfg(unix)]
+fn shutdown_signal() -> impl Future<Item = (), Error = ()> {
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+    Signal::new(SIGTERM)
+        .flatten_stream()
+        .select(Signal::new(SIGINT).flatten_stream())
+        .into_future()
+        .map(|_| ())
+        .map_err(|(err, _)| error!("Error listening for signals: {}", err))
+        // keep serving requests if we're unable to listen for signals
+        .or_else(|_| future::empty())
+}
 
-    println!("Server running at http://{}", bind_to);
-    hyper::rt::run(server);
+// Resolves when the process receives Ctrl-C
+#[cfg(not(unix))]
+fn shutdown_signal() -> impl Future<Item = (), Error = ()> {
+    tokio_signal::ctrl_c()
+        .flatten_stream()
+        .into_future()
+        .map(|_| ())
+        .map_err(|(err, _)| error!("Error listening for Ctrl-C: {}", err))
+        .or_else(|_| future::empty())
 }
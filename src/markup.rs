@@ -0,0 +1,303 @@
+//! Converts document content to HTML for display.
+//!
+//! Content is shown as preformatted text, except for references to
+//! attachments written as `[[attachment:path]]`, which are embedded
+//! as images or linked to depending on the file type.
+//!
+//! Markdown headings, lines starting with `#`, divide the content into
+//! sections, which are named after the heading's text in lowercase
+//! with anything other than letters and digits replaced by `-`, e.g.
+//! `getting-started` for `## Getting started`.
+//!
+//! A document whose content starts with `#REDIRECT [[path]]` is a
+//! redirect page, viewing it sends the reader to the document at
+//! 'path' instead.
+//!
+//! Content may start with front matter, lines of `key: value` fields
+//! between two `---` lines, which is not displayed:
+//!
+//! ```text
+//! ---
+//! title: Getting started
+//! ---
+//! ```
+
+use std::cmp;
+use std::path::Path;
+
+// File extensions of attachments to embed as images
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "svg", "webp"];
+
+/// Returns the content as HTML, suitable for placing inside a `pre`
+/// element. Attachments are linked to under the 'attachments' URL.
+pub fn render(content: &str, attachments: &str) -> String {
+    let content = body(content);
+    let mut html = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find("[[attachment:") {
+        let (before, tag) = rest.split_at(start);
+        let end = match tag.find("]]") {
+            Some(end) => end,
+            None => break,
+        };
+        html.push_str(&escape(before));
+        html.push_str(&attachment(attachments, &tag["[[attachment:".len()..end]));
+        rest = &tag[end + 2..];
+    }
+    html.push_str(&escape(rest));
+    html
+}
+
+// Returns the HTML to embed the attachment at 'path'
+fn attachment(attachments: &str, path: &str) -> String {
+    let path = path.trim().trim_start_matches('/');
+    let url = escape(&format!("{}/{}", attachments, path));
+    let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+    if IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+        format!("<img src=\"{}\" alt=\"{}\">", url, escape(path))
+    } else {
+        format!("<a href=\"{}\">{}</a>", url, escape(path))
+    }
+}
+
+/// Returns the paths of the attachments the content refers to
+pub fn attachments(content: &str) -> Vec<&str> {
+    let mut paths = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find("[[attachment:") {
+        let tag = &rest[start + "[[attachment:".len()..];
+        let end = match tag.find("]]") {
+            Some(end) => end,
+            None => break,
+        };
+        paths.push(tag[..end].trim().trim_start_matches('/'));
+        rest = &tag[end + 2..];
+    }
+    paths
+}
+
+/// Returns the path a redirect page points to, or None if the
+/// content is not a redirect
+pub fn redirect_target(content: &str) -> Option<&str> {
+    let content = content.trim_start();
+    match content.get(.."#REDIRECT".len()) {
+        Some(keyword) if keyword.eq_ignore_ascii_case("#REDIRECT") => (),
+        _ => return None,
+    }
+    let rest = content["#REDIRECT".len()..].trim_start();
+    if !rest.starts_with("[[") {
+        return None;
+    }
+    let end = rest.find("]]")?;
+    let target = rest[2..end].trim().trim_start_matches('/');
+    if target.is_empty() {
+        None
+    } else {
+        Some(target)
+    }
+}
+
+/// Returns the title set in the content's front matter, if any
+pub fn title(content: &str) -> Option<&str> {
+    let (front_matter, _) = split_front_matter(content)?;
+    front_matter
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key.trim() == "title" => Some(value.trim()),
+                _ => None,
+            }
+        }).find(|title| !title.is_empty())
+}
+
+/// Returns the title of the document at 'path', set in the front
+/// matter of its 'content' or else taken from its filename
+pub fn document_title(path: &Path, content: &str) -> String {
+    match title(content) {
+        Some(title) => String::from(title),
+        None => path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().replace(['-', '_'], " "))
+            .unwrap_or_default(),
+    }
+}
+
+/// Returns the section of the content under the heading named
+/// 'name', from the heading up to the next heading of the same or a
+/// higher level
+pub fn section<'a>(content: &'a str, name: &str) -> Option<&'a str> {
+    let content = body(content);
+    let mut start = None;
+    let mut offset = 0;
+    while offset < content.len() {
+        let end = content[offset..].find('\n').map_or(content.len(), |i| offset + i + 1);
+        if let Some((level, text)) = heading(&content[offset..end]) {
+            match start {
+                Some((_, start_level)) if level <= start_level => break,
+                None if heading_name(text) == name => start = Some((offset, level)),
+                _ => (),
+            }
+        }
+        offset = end;
+    }
+    start.map(|(start, _)| &content[start..offset])
+}
+
+/// Returns the name of the section under a heading with 'text'
+pub fn heading_name(text: &str) -> String {
+    let mut name = String::with_capacity(text.len());
+    for c in text.trim().chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    let len = name.trim_end_matches('-').len();
+    name.truncate(len);
+    name
+}
+
+// Returns the level and text of a Markdown heading line, e.g.
+// (2, "Setup") for "## Setup"
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    let text = &line[level..];
+    if level == 0 || level > 6 || !(text.starts_with(' ') || text.trim().is_empty()) {
+        return None;
+    }
+    Some((level, text.trim().trim_end_matches('#').trim_end()))
+}
+
+/// Returns the content readers see, without any front matter
+pub fn body(content: &str) -> &str {
+    split_front_matter(content).map_or(content, |(_, body)| body)
+}
+
+// Splits content starting with front matter into the fields between
+// the `---` lines and the rest of the content
+fn split_front_matter(content: &str) -> Option<(&str, &str)> {
+    let first = content.find('\n')?;
+    if content[..first].trim_end() != "---" {
+        return None;
+    }
+    let start = first + 1;
+    let mut offset = start;
+    loop {
+        let end = content[offset..].find('\n').map_or(content.len(), |i| offset + i);
+        if content[offset..end].trim_end() == "---" {
+            let body = cmp::min(end + 1, content.len());
+            return Some((&content[start..offset], &content[body..]));
+        }
+        if end == content.len() {
+            return None;
+        }
+        offset = end + 1;
+    }
+}
+
+/// Escapes text for including in HTML content or attribute values
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_plain_text() {
+        assert_eq!(
+            render("Hello, <world> & \"you\"", "/_attachments"),
+            "Hello, &lt;world&gt; &amp; &quot;you&quot;"
+        );
+    }
+
+    #[test]
+    fn render_attachments() {
+        assert_eq!(
+            render(
+                "See [[attachment:images/cat.PNG]] and [[attachment: notes.pdf ]].",
+                "/_attachments"
+            ),
+            "See <img src=\"/_attachments/images/cat.PNG\" alt=\"images/cat.PNG\"> \
+             and <a href=\"/_attachments/notes.pdf\">notes.pdf</a>."
+        );
+    }
+
+    #[test]
+    fn list_attachments() {
+        assert_eq!(
+            attachments("See [[attachment:images/cat.PNG]] and [[attachment: /notes.pdf ]]."),
+            vec!["images/cat.PNG", "notes.pdf"]
+        );
+        assert!(attachments("[[attachment:cat.png").is_empty());
+    }
+
+    #[test]
+    fn find_sections() {
+        let content = "---\ntitle: Guide\n---\nIntro\n# Setup\nInstall it\n\
+                       ## On Linux ##\nUse apt\n# Usage\nRun it\n";
+        assert_eq!(
+            section(content, "setup"),
+            Some("# Setup\nInstall it\n## On Linux ##\nUse apt\n")
+        );
+        assert_eq!(section(content, "on-linux"), Some("## On Linux ##\nUse apt\n"));
+        assert_eq!(section(content, "usage"), Some("# Usage\nRun it\n"));
+        assert_eq!(section(content, "intro"), None);
+        assert_eq!(section("#REDIRECT [[setup]]", "redirect"), None);
+    }
+
+    #[test]
+    fn name_headings() {
+        assert_eq!(heading_name(" Getting  started: the basics! "), "getting-started-the-basics");
+        assert_eq!(heading_name("Ünïcode 2"), "ünïcode-2");
+    }
+
+    #[test]
+    fn render_unterminated_attachment() {
+        assert_eq!(render("[[attachment:cat.png", "/files"), "[[attachment:cat.png");
+    }
+
+    #[test]
+    fn redirect_targets() {
+        assert_eq!(redirect_target("#REDIRECT [[new/page]]"), Some("new/page"));
+        assert_eq!(redirect_target("\n#redirect[[ /new ]]\nold notes"), Some("new"));
+        assert_eq!(redirect_target("See #REDIRECT [[new]]"), None);
+        assert_eq!(redirect_target("#REDIRECT new"), None);
+        assert_eq!(redirect_target("#REDIRECT [[]]"), None);
+        assert_eq!(redirect_target("#RED"), None);
+    }
+
+    #[test]
+    fn front_matter() {
+        let content = "---\ntags: intro\ntitle:  Getting started \n---\nHello";
+        assert_eq!(title(content), Some("Getting started"));
+        assert_eq!(render(content, "/_attachments"), "Hello");
+        assert_eq!(title("---\ntitle: Unterminated\nHello"), None);
+        assert_eq!(title("Hello\n---\ntitle: Later\n---\n"), None);
+        assert_eq!(title("---\n---"), None);
+        assert_eq!(render("---\ntitle: Empty\n---", "/files"), "");
+    }
+
+    #[test]
+    fn render_escapes_attachment_paths() {
+        assert_eq!(
+            render("[[attachment:\"><script>.png]]", "/_attachments"),
+            "<img src=\"/_attachments/&quot;&gt;&lt;script&gt;.png\" \
+             alt=\"&quot;&gt;&lt;script&gt;.png\">"
+        );
+    }
+}
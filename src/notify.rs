@@ -0,0 +1,788 @@
+//! Emails logged in users about changes to the pages they watch.
+//!
+//! Users watch pages, or every page under a path using a pattern
+//! ending with `*` such as `guides/*`, in their Watchlists entry.
+//! A Notifier checks the store for changes, batching them for each
+//! watching user until their chosen Frequency allows another email,
+//! then sends one email listing every changed page with a diff and a
+//! link to it. Users are not told about their own changes, nor about
+//! pages an ACL does not let them read. When changes are drafts,
+//! users are only told about revisions once they are published.
+//!
+//! Watchlists opened from a file are saved to it whenever a user
+//! changes theirs.
+use futures::future::{self, Future};
+use futures::stream::Stream;
+use lettre::smtp::authentication::Credentials;
+use lettre::{SmtpClient, SmtpTransport, Transport};
+use lettre_email::EmailBuilder;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::acl::{Acl, Permission};
+use crate::document::Event;
+use crate::history::{diff_lines, LineChange};
+use crate::persist;
+use crate::store::{SequenceId, Store, StoreError};
+
+// The most changed lines shown for each page in an email
+const MAX_DIFF_LINES: usize = 50;
+
+/// How often a user may be emailed about changes
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Frequency {
+    /// An email for each check which finds changes
+    Immediate,
+    /// At most one email an hour
+    Hourly,
+    /// At most one email a day
+    Daily,
+}
+
+impl Frequency {
+    /// Every frequency, most frequent first
+    pub const ALL: [Frequency; 3] = [Frequency::Immediate, Frequency::Hourly, Frequency::Daily];
+
+    /// The shortest time between two emails
+    pub fn interval(self) -> Duration {
+        match self {
+            Frequency::Immediate => Duration::from_secs(0),
+            Frequency::Hourly => Duration::from_secs(60 * 60),
+            Frequency::Daily => Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    /// The frequency's name, as used in forms
+    pub fn name(self) -> &'static str {
+        match self {
+            Frequency::Immediate => "immediate",
+            Frequency::Hourly => "hourly",
+            Frequency::Daily => "daily",
+        }
+    }
+}
+
+impl FromStr for Frequency {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        Frequency::ALL
+            .iter()
+            .cloned()
+            .find(|frequency| frequency.name() == name)
+            .ok_or(())
+    }
+}
+
+/// A user's email settings and the pages they watch
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Watcher {
+    /// The address changes are sent to, no emails are sent without
+    /// one
+    pub email: Option<String>,
+    /// How often they may be emailed
+    pub frequency: Frequency,
+    /// The paths of the pages they watch, or patterns ending with
+    /// `*` matching every path starting with the rest of the pattern
+    pub pages: BTreeSet<String>,
+}
+
+impl Default for Watcher {
+    fn default() -> Self {
+        Watcher {
+            email: None,
+            frequency: Frequency::Daily,
+            pages: BTreeSet::new(),
+        }
+    }
+}
+
+impl Watcher {
+    /// Returns true if the user watches the document at 'path'
+    pub fn watches(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        self.pages.iter().any(|page| {
+            if page.ends_with('*') {
+                path.starts_with(&page[..page.len() - 1])
+            } else {
+                *page == path
+            }
+        })
+    }
+}
+
+/// The pages each user watches. This is a cloneable interface to
+/// watchlists held in memory, and saved to a file if they were opened
+/// from one.
+#[derive(Clone, Default)]
+pub struct Watchlists {
+    watchers: Arc<RwLock<HashMap<String, Watcher>>>,
+    // File the watchlists are saved to whenever they change
+    file: Option<Arc<PathBuf>>,
+}
+
+impl Watchlists {
+    /// Opens the watchlists saved in the file at 'path', which is
+    /// created when a user first watches a page
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Watchlists {
+            watchers: Arc::new(RwLock::new(persist::load(path)?)),
+            file: Some(Arc::new(path.to_path_buf())),
+        })
+    }
+
+    /// Returns the user's settings and watched pages
+    pub fn get(&self, user: &str) -> Watcher {
+        let watchers = self.watchers.read().unwrap();
+        watchers.get(user).cloned().unwrap_or_default()
+    }
+
+    /// Adds a page, or a pattern ending with `*`, to the user's
+    /// watchlist
+    pub fn watch(&self, user: &str, page: &str) {
+        self.update(user, |watcher| {
+            watcher.pages.insert(String::from(page));
+        });
+    }
+
+    /// Removes a page, or pattern, from the user's watchlist
+    pub fn unwatch(&self, user: &str, page: &str) {
+        self.update(user, |watcher| {
+            watcher.pages.remove(page);
+        });
+    }
+
+    /// Sets the address the user is emailed at, and how often
+    pub fn set_email(&self, user: &str, email: Option<String>, frequency: Frequency) {
+        self.update(user, |watcher| {
+            watcher.email = email;
+            watcher.frequency = frequency;
+        });
+    }
+
+    /// Returns the users watching the document at 'path'
+    pub fn watchers(&self, path: &Path) -> Vec<(String, Watcher)> {
+        let watchers = self.watchers.read().unwrap();
+        watchers
+            .iter()
+            .filter(|&(_, watcher)| watcher.watches(path))
+            .map(|(user, watcher)| (user.clone(), watcher.clone()))
+            .collect()
+    }
+
+    fn update<F: FnOnce(&mut Watcher)>(&self, user: &str, f: F) {
+        let mut watchers = self.watchers.write().unwrap();
+        f(watchers.entry(String::from(user)).or_default());
+        if let Some(ref path) = self.file {
+            if let Err(err) = persist::save(path, &*watchers) {
+                error!("Unable to save watchlists to {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// An email to send
+#[derive(Debug, PartialEq, Clone)]
+pub struct Email {
+    /// The recipient's address
+    pub to: String,
+    /// The subject line
+    pub subject: String,
+    /// The plain text body
+    pub body: String,
+}
+
+/// Reasons an email could not be sent
+#[derive(Debug, PartialEq)]
+pub struct MailError(pub String);
+
+impl Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unable to send email: {}", self.0)
+    }
+}
+
+impl Error for MailError {
+    fn description(&self) -> &str {
+        "MailError: unable to send email"
+    }
+}
+
+/// Sends emails. Sending may block, so a Notifier should be run on
+/// its own thread.
+pub trait Mailer: Send + Sync + 'static {
+    /// Sends 'email', returning once it has been accepted
+    fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+/// Sends emails through an SMTP server, using TLS
+pub struct SmtpMailer {
+    transport: Mutex<SmtpTransport>,
+    from: String,
+}
+
+impl SmtpMailer {
+    /// Connects to the SMTP server at 'server' when sending, logging
+    /// in with 'credentials' (a username and password) if given.
+    /// Emails are sent from the address 'from'.
+    pub fn new(
+        server: &str,
+        credentials: Option<(String, String)>,
+        from: &str,
+    ) -> Result<Self, MailError> {
+        let mut client =
+            SmtpClient::new_simple(server).map_err(|err| MailError(format!("{}", err)))?;
+        if let Some((username, password)) = credentials {
+            client = client.credentials(Credentials::new(username, password));
+        }
+        Ok(SmtpMailer {
+            transport: Mutex::new(client.transport()),
+            from: String::from(from),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = EmailBuilder::new()
+            .to(email.to.as_str())
+            .from(self.from.as_str())
+            .subject(email.subject.as_str())
+            .text(email.body.as_str())
+            .build()
+            .map_err(|err| MailError(format!("{}", err)))?;
+        let mut transport = self.transport.lock().unwrap();
+        transport
+            .send(message.into())
+            .map(|_| ())
+            .map_err(|err| MailError(format!("{}", err)))
+    }
+}
+
+/// Checks for changes to watched pages and emails their watchers.
+/// Clones share the changes waiting to be sent.
+pub struct Notifier<T: Store, M: Mailer> {
+    store: T,
+    watchlists: Watchlists,
+    mailer: Arc<M>,
+    // The URL the wiki is served at, ending with "/"
+    base_url: String,
+    acl: Option<Arc<Acl>>,
+    // Whether only published revisions are emailed
+    drafts: bool,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    // Changes up to this time have been read
+    checked: SystemTime,
+    // Changes waiting to be sent to each user, by the path changed
+    pending: HashMap<String, BTreeMap<PathBuf, Batch>>,
+    // When each user was last emailed
+    sent: HashMap<String, SystemTime>,
+}
+
+// The changes made to a page since a user was last emailed about it
+struct Batch {
+    // The revision before the first change. When changes are drafts,
+    // the revision published before the first change is the one
+    // published as of this revision.
+    from: SequenceId,
+    // The revision after the last change, or the last revision
+    // published
+    to: SequenceId,
+    authors: BTreeSet<String>,
+}
+
+impl<T: Store, M: Mailer> Clone for Notifier<T, M> {
+    fn clone(&self) -> Self {
+        Notifier {
+            store: self.store.clone(),
+            watchlists: self.watchlists.clone(),
+            mailer: self.mailer.clone(),
+            base_url: self.base_url.clone(),
+            acl: self.acl.clone(),
+            drafts: self.drafts,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T: Store, M: Mailer> Notifier<T, M> {
+    /// Creates a Notifier for changes made from now on. Links in
+    /// emails start with 'base_url', the URL readers reach the wiki's
+    /// home page at.
+    pub fn new(store: T, watchlists: Watchlists, mailer: M, base_url: &str) -> Self {
+        let mut base_url = String::from(base_url);
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+        Notifier {
+            store,
+            watchlists,
+            mailer: Arc::new(mailer),
+            base_url,
+            acl: None,
+            drafts: false,
+            state: Arc::new(Mutex::new(State {
+                checked: SystemTime::now(),
+                pending: HashMap::new(),
+                sent: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Only emails users about pages 'acl' lets them read
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Emails users about revisions when they are published rather
+    /// than about each edit, for wikis using `TamaWiki::with_drafts`,
+    /// so drafts are not sent to users who may not see them
+    pub fn with_drafts(mut self) -> Self {
+        self.drafts = true;
+        self
+    }
+
+    /// Reads the changes made since the last check, up to 'now', and
+    /// emails each watcher whose frequency allows it about the
+    /// changes waiting for them. Emails which cannot be sent are
+    /// logged and not retried. Resolves to the number of emails sent.
+    pub fn check(
+        &self,
+        now: SystemTime,
+    ) -> Box<dyn Future<Item = usize, Error = StoreError> + Send> {
+        let since = {
+            let mut state = self.state.lock().unwrap();
+            let since = state.checked;
+            state.checked = now;
+            since
+        };
+        let notifier = self.clone();
+        let drafts = self.drafts;
+        let changes = self
+            .store
+            .changes()
+            .take_while(move |change| Ok(change.time > since))
+            .filter(move |change| change.time <= now)
+            .collect();
+        Box::new(changes.and_then(move |changes| {
+            notifier.queue(changes.into_iter().rev().map(|change| match change.event {
+                Event::Edit(_) if !drafts => {
+                    Some((change.path, change.seq - 1, change.seq, change.user))
+                }
+                Event::Publish(ref publish) if drafts => {
+                    Some((change.path, change.seq - 1, publish.seq, change.user))
+                }
+                _ => None,
+            }));
+            let emails: Vec<_> = notifier
+                .due(now)
+                .into_iter()
+                .map(|(email, batches)| notifier.email(email, batches))
+                .collect();
+            future::join_all(emails).map(move |emails| {
+                emails
+                    .iter()
+                    .filter(|email| match notifier.mailer.send(email) {
+                        Ok(()) => true,
+                        Err(err) => {
+                            warn!("{}", err);
+                            false
+                        }
+                    }).count()
+            })
+        }))
+    }
+
+    // Adds changes from one revision to another, oldest first, to the
+    // batches of their watchers
+    fn queue<I>(&self, changes: I)
+    where
+        I: Iterator<Item = Option<(PathBuf, SequenceId, SequenceId, Option<String>)>>,
+    {
+        let mut state = self.state.lock().unwrap();
+        for (path, from, to, author) in changes.flatten() {
+            for (user, _) in self.watchlists.watchers(&path) {
+                if author.as_ref() == Some(&user) || !self.may_read(&user, &path) {
+                    continue;
+                }
+                let batches = state.pending.entry(user).or_default();
+                let batch = batches.entry(path.clone()).or_insert(Batch {
+                    from,
+                    to,
+                    authors: BTreeSet::new(),
+                });
+                batch.to = to;
+                if let Some(ref author) = author {
+                    batch.authors.insert(author.clone());
+                }
+            }
+        }
+    }
+
+    // Removes the batches of the users who may be emailed at 'now',
+    // returning them with the address to send them to
+    fn due(&self, now: SystemTime) -> Vec<(String, BTreeMap<PathBuf, Batch>)> {
+        let mut state = self.state.lock().unwrap();
+        let users: Vec<String> = state.pending.keys().cloned().collect();
+        let mut due = Vec::new();
+        for user in users {
+            let watcher = self.watchlists.get(&user);
+            let email = match watcher.email {
+                Some(email) => email,
+                // changes are kept until the user adds an address
+                None => continue,
+            };
+            let sent = state.sent.get(&user).cloned().unwrap_or(UNIX_EPOCH);
+            if now < sent + watcher.frequency.interval() {
+                continue;
+            }
+            if let Some(mut batches) = state.pending.remove(&user) {
+                // the rules may have changed since the batches were
+                // queued
+                batches.retain(|path, _| self.may_read(&user, path));
+                if !batches.is_empty() {
+                    state.sent.insert(user, now);
+                    due.push((email, batches));
+                }
+            }
+        }
+        due
+    }
+
+    // Resolves to the revision changes to 'path' are shown from and
+    // its content, given a batch starting at 'from'. When changes are
+    // drafts this is the revision published as of 'from', as readers
+    // have not seen any later drafts.
+    fn previous(
+        &self,
+        path: &Path,
+        from: SequenceId,
+    ) -> Box<dyn Future<Item = (SequenceId, String), Error = StoreError> + Send> {
+        if from == 0 {
+            return Box::new(future::ok((0, String::new())));
+        }
+        let content = self.store.content_at(path, from);
+        if !self.drafts {
+            return Box::new(content.map(move |doc| (from, doc.content)));
+        }
+        let store = self.store.clone();
+        let path = path.to_path_buf();
+        Box::new(content.and_then(move |doc| match doc.published {
+            Some(published) => future::Either::A(
+                store
+                    .content_at(&path, published)
+                    .map(move |doc| (published, doc.content)),
+            ),
+            None => future::Either::B(future::ok((0, String::new()))),
+        }))
+    }
+
+    // Returns true if the ACL, if any, lets 'user' read 'path'
+    fn may_read(&self, user: &str, path: &Path) -> bool {
+        self.acl
+            .as_ref()
+            .is_none_or(|acl| acl.allows(Some(user), path, Permission::Read))
+    }
+
+    // Resolves to an email to 'to' describing the changes in 'batches'
+    fn email(
+        &self,
+        to: String,
+        batches: BTreeMap<PathBuf, Batch>,
+    ) -> impl Future<Item = Email, Error = StoreError> {
+        let count = batches.len();
+        let subject = match batches.keys().next() {
+            Some(path) if count == 1 => format!("{} was changed", path.display()),
+            _ => format!("{} watched pages were changed", count),
+        };
+        let base_url = self.base_url.clone();
+        let sections = batches.into_iter().map(|(path, batch)| {
+            let old = self.previous(&path, batch.from);
+            let new = self.store.content_at(&path, batch.to).map(|doc| doc.content);
+            let base_url = base_url.clone();
+            old.join(new).map(move |((from, old), new)| {
+                let batch = Batch { from, ..batch };
+                section(&base_url, &path, &batch, &old, &new)
+            })
+        });
+        future::join_all(sections.collect::<Vec<_>>()).map(move |sections| Email {
+            to,
+            subject,
+            body: sections.join("\n"),
+        })
+    }
+}
+
+// Describes the changes to one page in an email: who made them, a
+// link to them, and the lines added and removed
+fn section(base_url: &str, path: &Path, batch: &Batch, old: &str, new: &str) -> String {
+    let authors: Vec<&str> = batch.authors.iter().map(|author| author.as_str()).collect();
+    let mut text = if authors.is_empty() {
+        format!("{} was changed\n", path.display())
+    } else {
+        format!("{} was changed by {}\n", path.display(), authors.join(", "))
+    };
+    text.push_str(&format!(
+        "{}{}?action=diff&from={}&to={}\n\n",
+        base_url,
+        path.display(),
+        batch.from,
+        batch.to
+    ));
+    let changed: Vec<String> = diff_lines(old, new)
+        .into_iter()
+        .filter_map(|change| match change {
+            LineChange::Insert(line) => Some(format!("+ {}", line)),
+            LineChange::Delete(line) => Some(format!("- {}", line)),
+            LineChange::Equal(_) => None,
+        }).collect();
+    for line in changed.iter().take(MAX_DIFF_LINES) {
+        text.push_str(line);
+        text.push('\n');
+    }
+    if changed.len() > MAX_DIFF_LINES {
+        text.push_str(&format!("... and {} more\n", changed.len() - MAX_DIFF_LINES));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::Memberships;
+    use crate::document::{Edit, Insert, Join, Leave, Operation, Publish, Role};
+    use crate::store::memory::MemoryStore;
+
+    #[derive(Default)]
+    struct Outbox(Mutex<Vec<Email>>);
+
+    impl Mailer for Arc<Outbox> {
+        fn send(&self, email: &Email) -> Result<(), MailError> {
+            self.0.lock().unwrap().push(email.clone());
+            Ok(())
+        }
+    }
+
+    // Appends 'content' to the document at 'path' as 'user', who
+    // joins, edits and leaves
+    fn edit(store: &mut MemoryStore, path: &str, user: &str, content: &str) {
+        let pos = match store.content(Path::new(path)).wait() {
+            Ok((_, doc)) => doc.content.chars().count(),
+            Err(_) => 0,
+        };
+        let events = vec![
+            Event::Join(Join {
+                id: 1,
+                role: Role::Editor,
+                user: Some(String::from(user)),
+            }),
+            Event::Edit(Edit {
+                author: 1,
+                operations: vec![Operation::Insert(Insert {
+                    pos,
+                    content: String::from(content),
+                })],
+            }),
+            Event::Leave(Leave { id: 1 }),
+        ];
+        for event in events {
+            store.push(PathBuf::from(path), event).wait().unwrap();
+        }
+    }
+
+    // Publishes the latest revision of the document at 'path' as
+    // 'user'
+    fn publish(store: &mut MemoryStore, path: &str, user: &str) {
+        let seq = store.seq(Path::new(path)).wait().unwrap() + 1;
+        let events = vec![
+            Event::Join(Join {
+                id: 1,
+                role: Role::Editor,
+                user: Some(String::from(user)),
+            }),
+            Event::Publish(Publish { id: 1, seq }),
+            Event::Leave(Leave { id: 1 }),
+        ];
+        for event in events {
+            store.push(PathBuf::from(path), event).wait().unwrap();
+        }
+    }
+
+    #[test]
+    fn watch_pages_and_prefixes() {
+        let watchlists = Watchlists::default();
+        watchlists.watch("alice", "index.html");
+        watchlists.watch("bob", "guides/*");
+        let users = |path| -> Vec<String> {
+            let mut users: Vec<_> = watchlists
+                .watchers(Path::new(path))
+                .into_iter()
+                .map(|(user, _)| user)
+                .collect();
+            users.sort();
+            users
+        };
+        assert_eq!(users("index.html"), vec!["alice"]);
+        assert_eq!(users("guides/setup.html"), vec!["bob"]);
+        assert!(users("other.html").is_empty());
+        watchlists.unwatch("alice", "index.html");
+        assert!(users("index.html").is_empty());
+    }
+
+    #[test]
+    fn saved_watchlists() {
+        let path = ::std::env::temp_dir()
+            .join(format!("tamawiki-watchlists-{}.json", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let watchlists = Watchlists::open(&path).unwrap();
+        watchlists.watch("alice", "guides/*");
+        watchlists.set_email("alice", Some(String::from("alice@example.com")), Frequency::Hourly);
+
+        let reopened = Watchlists::open(&path).unwrap();
+        assert_eq!(reopened.get("alice"), watchlists.get("alice"));
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn email_batched_changes() {
+        let mut store = MemoryStore::default();
+        let watchlists = Watchlists::default();
+        watchlists.watch("alice", "guides/*");
+        watchlists.set_email("alice", Some(String::from("alice@example.com")), Frequency::Hourly);
+        let outbox = Arc::new(Outbox::default());
+        let notifier = Notifier::new(
+            store.clone(),
+            watchlists,
+            outbox.clone(),
+            "https://wiki.example.com",
+        );
+        edit(&mut store, "guides/setup.html", "bob", "Install it");
+        // watchers are not told about their own changes
+        edit(&mut store, "guides/intro.html", "alice", "Welcome");
+        assert_eq!(notifier.check(SystemTime::now()).wait(), Ok(1));
+        {
+            let emails = outbox.0.lock().unwrap();
+            assert_eq!(emails[0].to, "alice@example.com");
+            assert_eq!(emails[0].subject, "guides/setup.html was changed");
+            assert_eq!(
+                emails[0].body,
+                "guides/setup.html was changed by bob\n\
+                 https://wiki.example.com/guides/setup.html?action=diff&from=1&to=2\n\n\
+                 + Install it\n"
+            );
+        }
+
+        // later changes wait until an hour after the last email
+        edit(&mut store, "guides/setup.html", "carol", "\nThen run it");
+        assert_eq!(notifier.check(SystemTime::now()).wait(), Ok(0));
+        edit(&mut store, "guides/intro.html", "bob", "!");
+        let later = SystemTime::now() + Duration::from_secs(61 * 60);
+        assert_eq!(notifier.check(later).wait(), Ok(1));
+        let emails = outbox.0.lock().unwrap();
+        assert_eq!(emails[1].subject, "2 watched pages were changed");
+        assert_eq!(
+            emails[1].body,
+            "guides/intro.html was changed by bob\n\
+             https://wiki.example.com/guides/intro.html?action=diff&from=4&to=5\n\n\
+             - Welcome\n\
+             + Welcome!\n\n\
+             guides/setup.html was changed by carol\n\
+             https://wiki.example.com/guides/setup.html?action=diff&from=4&to=5\n\n\
+             + Then run it\n"
+        );
+    }
+
+    #[test]
+    fn skip_pages_watchers_cannot_read() {
+        let mut store = MemoryStore::default();
+        let watchlists = Watchlists::default();
+        watchlists.watch("alice", "*");
+        watchlists.set_email("alice", Some(String::from("alice@example.com")), Frequency::Hourly);
+        let memberships = Memberships::default();
+        memberships.set("alice", vec![String::from("staff")]);
+        let acl = Acl::parse(
+            "/** -> * (read, write)
+             /private/** -> group:staff (read, write)",
+        ).unwrap()
+        .with_memberships(memberships.clone());
+        let outbox = Arc::new(Outbox::default());
+        let notifier = Notifier::new(
+            store.clone(),
+            watchlists,
+            outbox.clone(),
+            "https://wiki.example.com",
+        ).with_acl(Arc::new(acl));
+
+        memberships.set("alice", vec![]);
+        edit(&mut store, "private/plans.html", "bob", "Secret plans");
+        edit(&mut store, "index.html", "bob", "Welcome");
+        assert_eq!(notifier.check(SystemTime::now()).wait(), Ok(1));
+        assert_eq!(outbox.0.lock().unwrap()[0].subject, "index.html was changed");
+
+        // access lost after a change was queued is checked again
+        // before sending
+        memberships.set("alice", vec![String::from("staff")]);
+        edit(&mut store, "private/plans.html", "bob", "!");
+        assert_eq!(notifier.check(SystemTime::now()).wait(), Ok(0));
+        memberships.set("alice", vec![]);
+        let later = SystemTime::now() + Duration::from_secs(61 * 60);
+        assert_eq!(notifier.check(later).wait(), Ok(0));
+        assert_eq!(outbox.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn email_published_revisions() {
+        let mut store = MemoryStore::default();
+        let watchlists = Watchlists::default();
+        watchlists.watch("alice", "*");
+        watchlists.set_email(
+            "alice",
+            Some(String::from("alice@example.com")),
+            Frequency::Immediate,
+        );
+        let outbox = Arc::new(Outbox::default());
+        let notifier = Notifier::new(
+            store.clone(),
+            watchlists,
+            outbox.clone(),
+            "https://wiki.example.com",
+        ).with_drafts();
+
+        // drafts are not sent
+        edit(&mut store, "index.html", "bob", "Draft");
+        assert_eq!(notifier.check(SystemTime::now()).wait(), Ok(0));
+        publish(&mut store, "index.html", "carol");
+        assert_eq!(notifier.check(SystemTime::now()).wait(), Ok(1));
+
+        // changes are shown from the revision published before them
+        edit(&mut store, "index.html", "bob", " changes");
+        assert_eq!(notifier.check(SystemTime::now()).wait(), Ok(0));
+        publish(&mut store, "index.html", "carol");
+        assert_eq!(notifier.check(SystemTime::now()).wait(), Ok(1));
+        let emails = outbox.0.lock().unwrap();
+        assert_eq!(
+            emails[0].body,
+            "index.html was changed by carol\n\
+             https://wiki.example.com/index.html?action=diff&from=0&to=4\n\n\
+             + Draft\n"
+        );
+        assert_eq!(
+            emails[1].body,
+            "index.html was changed by carol\n\
+             https://wiki.example.com/index.html?action=diff&from=4&to=10\n\n\
+             - Draft\n\
+             + Draft changes\n"
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! Delegated login using an OpenID Connect provider, with the
+//! authorization code flow.
+//!
+//! The wiki redirects the user to the provider, which redirects them
+//! back with a code. The code is exchanged for an ID token at the
+//! provider's token endpoint, and a claim from the token is used as
+//! the user's wiki username. Their account is bound to the token's
+//! issuer and subject, which the user cannot change, so another user
+//! of the provider cannot log in to it by taking the same username.
+//! The token is received directly from the
+//! provider over TLS, so as the specification allows, the connection
+//! authenticates the provider in place of the token's signature. Its
+//! issuer, audience, expiry and nonce are still checked.
+use futures::future::{self, Future};
+use futures::stream::Stream;
+use http::header::{ACCEPT, CONTENT_TYPE};
+use hyper::client::HttpConnector;
+use hyper::{self, Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use serde_json::{self, Value};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The claim used as a username by default, the subject, which is
+/// unique at the provider and never reassigned
+pub const DEFAULT_USERNAME_CLAIM: &str = "sub";
+
+/// A user the provider has authenticated
+#[derive(Debug, PartialEq)]
+pub struct Identity {
+    /// Who the user is at the provider, made from the issuer and
+    /// subject of their ID token
+    pub id: String,
+    /// The user's wiki username
+    pub username: String,
+}
+
+/// An OpenID Connect provider users can log in with
+#[derive(Clone)]
+pub struct OidcProvider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    client_id: String,
+    client_secret: String,
+    // Where the provider sends users after they log in, the wiki's
+    // callback route
+    redirect_uri: String,
+    scopes: Vec<String>,
+    username_claim: String,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl OidcProvider {
+    /// Creates a provider from its issuer and endpoint URLs, and the
+    /// client credentials registered with it. 'redirect_uri' must be
+    /// the full URL of the wiki's `/_oidc/callback` route, or the
+    /// same route under the wiki's reserved prefix.
+    pub fn new<S: Into<String>>(
+        issuer: S,
+        authorization_endpoint: S,
+        token_endpoint: S,
+        client_id: S,
+        client_secret: S,
+        redirect_uri: S,
+    ) -> Self {
+        OidcProvider {
+            issuer: issuer.into(),
+            authorization_endpoint: authorization_endpoint.into(),
+            token_endpoint: token_endpoint.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            redirect_uri: redirect_uri.into(),
+            scopes: vec![String::from("openid"), String::from("profile")],
+            username_claim: String::from(DEFAULT_USERNAME_CLAIM),
+            client: https_client(),
+        }
+    }
+
+    /// Sets the scopes requested from the provider, "openid" is
+    /// always included
+    pub fn with_scopes<I, S>(mut self, scopes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.scopes = vec![String::from("openid")];
+        self.scopes.extend(
+            scopes
+                .into_iter()
+                .map(Into::<String>::into)
+                .filter(|scope| scope != "openid"),
+        );
+        self
+    }
+
+    /// Sets the ID token claim used as the user's wiki username. If
+    /// the token does not include it, the "sub" claim is used. Users
+    /// may be able to choose claims such as "preferred_username", in
+    /// which case the first user to log in with a username keeps it.
+    pub fn with_username_claim<S: Into<String>>(mut self, claim: S) -> Self {
+        self.username_claim = claim.into();
+        self
+    }
+
+    /// Returns the URL to send the user to in order to log in. The
+    /// provider returns 'state' to the callback unchanged, and
+    /// includes 'nonce' in the ID token.
+    pub fn authorization_url(&self, state: &str, nonce: &str) -> String {
+        let scope = self.scopes.join(" ");
+        let query = serde_urlencoded::to_string([
+            ("response_type", "code"),
+            ("client_id", self.client_id.as_str()),
+            ("redirect_uri", self.redirect_uri.as_str()),
+            ("scope", scope.as_str()),
+            ("state", state),
+            ("nonce", nonce),
+        ]).unwrap();
+        let separator = if self.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        format!("{}{}{}", self.authorization_endpoint, separator, query)
+    }
+
+    /// Exchanges the code passed to the callback for an ID token,
+    /// and returns the user it identifies
+    pub fn exchange(
+        &self,
+        code: &str,
+        nonce: &str,
+    ) -> Box<dyn Future<Item = Identity, Error = OidcError> + Send> {
+        let form = serde_urlencoded::to_string([
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.redirect_uri.as_str()),
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+        ]).unwrap();
+        let req = Request::post(self.token_endpoint.as_str())
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(ACCEPT, "application/json")
+            .body(Body::from(form));
+        let req = match req {
+            Ok(req) => req,
+            Err(err) => {
+                return Box::new(future::err(OidcError::InvalidResponse(format!("{}", err))))
+            }
+        };
+        let provider = self.clone();
+        let nonce = String::from(nonce);
+        Box::new(fetch_json(&self.client, req).and_then(move |body| {
+            let token = body["id_token"]
+                .as_str()
+                .ok_or_else(|| OidcError::InvalidResponse(String::from("missing id_token")))?;
+            let claims = decode_claims(token)?;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            provider.identity(&claims, &nonce, now)
+        }))
+    }
+
+    // Checks the ID token's claims and returns the user they identify
+    fn identity(&self, claims: &Value, nonce: &str, now: u64) -> Result<Identity, OidcError> {
+        if claims["iss"].as_str() != Some(self.issuer.as_str()) {
+            return Err(OidcError::InvalidToken("issuer"));
+        }
+        let audience = match claims["aud"] {
+            Value::String(ref aud) => *aud == self.client_id,
+            Value::Array(ref auds) => auds.iter().any(|aud| *aud == *self.client_id),
+            _ => false,
+        };
+        if !audience {
+            return Err(OidcError::InvalidToken("audience"));
+        }
+        match claims["exp"].as_u64() {
+            Some(exp) if exp > now => (),
+            _ => return Err(OidcError::InvalidToken("expired")),
+        }
+        if claims["nonce"].as_str() != Some(nonce) {
+            return Err(OidcError::InvalidToken("nonce"));
+        }
+        let subject = claims["sub"]
+            .as_str()
+            .ok_or(OidcError::InvalidToken("subject"))?;
+        let username = claims[self.username_claim.as_str()]
+            .as_str()
+            .unwrap_or(subject);
+        Ok(Identity {
+            id: format!("oidc:{}#{}", self.issuer, subject),
+            username: String::from(username),
+        })
+    }
+}
+
+/// Creates a provider using the configuration published by the
+/// issuer at `/.well-known/openid-configuration`
+pub fn discover<S: Into<String>>(
+    issuer: S,
+    client_id: S,
+    client_secret: S,
+    redirect_uri: S,
+) -> impl Future<Item = OidcProvider, Error = OidcError> + Send {
+    let issuer = issuer.into();
+    let client_id = client_id.into();
+    let client_secret = client_secret.into();
+    let redirect_uri = redirect_uri.into();
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    let req = Request::get(url.as_str())
+        .header(ACCEPT, "application/json")
+        .body(Body::empty())
+        .map_err(|err| OidcError::InvalidResponse(format!("{}", err)));
+    future::result(req)
+        .and_then(|req| fetch_json(&https_client(), req))
+        .and_then(move |config| {
+            let endpoint = |name: &str| {
+                config[name]
+                    .as_str()
+                    .map(String::from)
+                    .ok_or_else(|| OidcError::InvalidResponse(format!("missing {}", name)))
+            };
+            if config["issuer"].as_str() != Some(issuer.as_str()) {
+                return Err(OidcError::InvalidResponse(String::from("issuer mismatch")));
+            }
+            Ok(OidcProvider::new(
+                issuer.clone(),
+                endpoint("authorization_endpoint")?,
+                endpoint("token_endpoint")?,
+                client_id,
+                client_secret,
+                redirect_uri,
+            ))
+        })
+}
+
+fn https_client() -> Client<HttpsConnector<HttpConnector>> {
+    Client::builder().build(HttpsConnector::new(4))
+}
+
+// Makes a request to the provider and parses the JSON response
+fn fetch_json(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    req: Request<Body>,
+) -> impl Future<Item = Value, Error = OidcError> + Send {
+    client
+        .request(req)
+        .map_err(OidcError::Http)
+        .and_then(|res| {
+            let status = res.status();
+            res.into_body()
+                .concat2()
+                .map_err(OidcError::Http)
+                .and_then(move |body| {
+                    if !status.is_success() {
+                        return Err(OidcError::InvalidResponse(format!(
+                            "{}: {}",
+                            status,
+                            String::from_utf8_lossy(&body)
+                        )));
+                    }
+                    serde_json::from_slice(&body)
+                        .map_err(|err| OidcError::InvalidResponse(format!("{}", err)))
+                })
+        })
+}
+
+// Returns the claims in the payload of a JSON Web Token
+fn decode_claims(token: &str) -> Result<Value, OidcError> {
+    let payload = token
+        .split('.')
+        .nth(1)
+        .ok_or(OidcError::InvalidToken("format"))?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| OidcError::InvalidToken("format"))?;
+    serde_json::from_slice(&payload).map_err(|_| OidcError::InvalidToken("format"))
+}
+
+/// Error conditions which may occur when logging in with a provider
+#[derive(Debug)]
+pub enum OidcError {
+    /// The provider could not be reached
+    Http(hyper::Error),
+    /// The provider responded with an error or an unexpected body
+    InvalidResponse(String),
+    /// The ID token failed validation, naming the check which failed
+    InvalidToken(&'static str),
+}
+
+impl Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OidcError::Http(ref err) => write!(f, "Http({})", err),
+            OidcError::InvalidResponse(ref reason) => write!(f, "InvalidResponse({})", reason),
+            OidcError::InvalidToken(check) => write!(f, "InvalidToken({})", check),
+        }
+    }
+}
+
+impl Error for OidcError {
+    fn description(&self) -> &str {
+        match *self {
+            OidcError::Http(_) => "OidcError: could not reach provider",
+            OidcError::InvalidResponse(_) => "OidcError: invalid response from provider",
+            OidcError::InvalidToken(_) => "OidcError: invalid ID token",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider() -> OidcProvider {
+        OidcProvider::new(
+            "https://id.example.com",
+            "https://id.example.com/authorize",
+            "https://id.example.com/token",
+            "wiki",
+            "secret",
+            "https://wiki.example.com/_oidc/callback",
+        )
+    }
+
+    #[test]
+    fn authorization_url() {
+        let url = provider()
+            .with_scopes(vec!["email"])
+            .authorization_url("abc", "xyz");
+        assert_eq!(
+            url,
+            "https://id.example.com/authorize?response_type=code&client_id=wiki\
+             &redirect_uri=https%3A%2F%2Fwiki.example.com%2F_oidc%2Fcallback\
+             &scope=openid+email&state=abc&nonce=xyz"
+        );
+    }
+
+    #[test]
+    fn validate_claims() {
+        let provider = provider();
+        let claims = json!({
+            "iss": "https://id.example.com",
+            "aud": ["wiki", "other"],
+            "exp": 2000,
+            "nonce": "xyz",
+            "sub": "1234",
+            "preferred_username": "alice",
+        });
+        assert_eq!(
+            provider.identity(&claims, "xyz", 1000).unwrap(),
+            Identity {
+                id: String::from("oidc:https://id.example.com#1234"),
+                username: String::from("1234"),
+            }
+        );
+        let named = provider.clone().with_username_claim("preferred_username");
+        assert_eq!(
+            named.identity(&claims, "xyz", 1000).unwrap(),
+            Identity {
+                id: String::from("oidc:https://id.example.com#1234"),
+                username: String::from("alice"),
+            }
+        );
+        assert_eq!(
+            provider
+                .clone()
+                .with_username_claim("email")
+                .identity(&claims, "xyz", 1000)
+                .unwrap()
+                .username,
+            "1234"
+        );
+        let check = |result: Result<Identity, OidcError>| match result {
+            Err(OidcError::InvalidToken(check)) => check,
+            result => panic!("expected InvalidToken, got {:?}", result),
+        };
+        assert_eq!(check(provider.identity(&claims, "xyz", 3000)), "expired");
+        assert_eq!(check(provider.identity(&claims, "abc", 1000)), "nonce");
+        let mut anonymous = claims.clone();
+        anonymous.as_object_mut().unwrap().remove("sub");
+        assert_eq!(check(provider.identity(&anonymous, "xyz", 1000)), "subject");
+        let mut other = claims.clone();
+        other["aud"] = json!("other");
+        assert_eq!(check(provider.identity(&other, "xyz", 1000)), "audience");
+        other["iss"] = json!("https://evil.example.com");
+        assert_eq!(check(provider.identity(&other, "xyz", 1000)), "issuer");
+    }
+
+    #[test]
+    fn decode_token_claims() {
+        let payload = base64::encode_config(b"{\"sub\":\"1234\"}", base64::URL_SAFE_NO_PAD);
+        let token = format!("eyJhbGciOiJSUzI1NiJ9.{}.signature", payload);
+        assert_eq!(decode_claims(&token).unwrap(), json!({ "sub": "1234" }));
+        assert!(decode_claims("garbage").is_err());
+    }
+}
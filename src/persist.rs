@@ -0,0 +1,106 @@
+//! Saves state kept in memory, such as user accounts, to JSON files
+//! so it survives a restart.
+//!
+//! Files are replaced rather than rewritten in place: the new
+//! contents are written to a temporary file alongside, which is then
+//! renamed over the old one, so a crash part way through saving
+//! leaves the previous contents intact.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Reads the value saved in the file at 'path', or returns the
+/// default value if nothing has been saved there yet
+pub fn load<T: DeserializeOwned + Default>(path: &Path) -> io::Result<T> {
+    match fs::read(path) {
+        Ok(data) => {
+            serde_json::from_slice(&data).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+        }
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Saves 'value' as JSON in the file at 'path'
+pub fn save<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let data =
+        serde_json::to_vec(value).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    write(path, &data)
+}
+
+/// Replaces the contents of the file at 'path' with 'data'. Only the
+/// owner may read the file, as it may hold secrets such as password
+/// hashes.
+pub fn write(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = temporary_path(path)?;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let written = options.open(&tmp).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+    match written.and_then(|_| fs::rename(&tmp, path)) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let _ = fs::remove_file(&tmp);
+            Err(err)
+        }
+    }
+}
+
+// The file new contents are written to before replacing 'path',
+// named after the process so two processes saving the same file do
+// not write to the same temporary file
+fn temporary_path(path: &Path) -> io::Result<PathBuf> {
+    let mut name = match path.file_name() {
+        Some(name) => name.to_os_string(),
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a file", path.display()),
+            ))
+        }
+    };
+    name.push(format!(".{}.tmp", process::id()));
+    Ok(path.with_file_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::env;
+
+    #[test]
+    fn save_and_load() {
+        let dir = env::temp_dir().join(format!("tamawiki-persist-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        let _ = fs::remove_file(&path);
+
+        let empty: BTreeMap<String, u32> = load(&path).unwrap();
+        assert!(empty.is_empty());
+
+        let mut state = BTreeMap::new();
+        state.insert(String::from("alice"), 1);
+        save(&path, &state).unwrap();
+        state.insert(String::from("bob"), 2);
+        save(&path, &state).unwrap();
+        assert_eq!(load::<BTreeMap<String, u32>>(&path).unwrap(), state);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::write(&path, "not json").unwrap();
+        assert_eq!(
+            load::<BTreeMap<String, u32>>(&path).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
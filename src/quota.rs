@@ -0,0 +1,270 @@
+//! Storage quotas for authenticated users.
+//!
+//! Quotas count the bytes each user has contributed: the content
+//! their edits insert and the attachments they upload. Deleting
+//! content does not give any back, since it is kept in the document's
+//! history. Anonymous edits are not counted, access to them is
+//! controlled by the ACL instead. Anonymous uploads are refused while
+//! quotas are in use, since nothing would limit their size.
+//!
+//! Usage is kept in memory, and saved to a file if the quotas were
+//! opened from one. Otherwise it starts again from zero when the
+//! server restarts. Contributions only mark the usage as changed, so
+//! edits are not held up writing the file: it is saved by `flush`,
+//! which the server calls every few seconds and once it has stopped.
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::document::{Event, Operation};
+use crate::persist;
+
+/// How many bytes a user has contributed, and may contribute
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub struct Usage {
+    /// Bytes contributed so far
+    pub used: u64,
+    /// The most bytes the user may contribute
+    pub limit: u64,
+}
+
+/// The bytes contributed by each user and their limits. This is a
+/// cloneable interface to usage held in memory, and saved to a file
+/// if it was opened from one.
+#[derive(Clone, Debug)]
+pub struct Quotas {
+    used: Arc<RwLock<HashMap<String, u64>>>,
+    default_limit: u64,
+    limits: HashMap<String, u64>,
+    // File the usage is saved to when flushed
+    file: Option<Arc<PathBuf>>,
+    // Set when the usage has changed since it was last saved
+    unsaved: Arc<AtomicBool>,
+    // Held while saving, so two flushes do not write the file at once
+    saving: Arc<Mutex<()>>,
+}
+
+impl Quotas {
+    /// Limits every user to 'limit' bytes, unless given a limit of
+    /// their own
+    pub fn new(limit: u64) -> Self {
+        Quotas {
+            used: Default::default(),
+            default_limit: limit,
+            limits: HashMap::new(),
+            file: None,
+            unsaved: Default::default(),
+            saving: Default::default(),
+        }
+    }
+
+    /// Limits every user to 'limit' bytes, counting on from the usage
+    /// saved in the file at 'path', which is created when a user
+    /// first contributes
+    pub fn open(limit: u64, path: &Path) -> io::Result<Self> {
+        Ok(Quotas {
+            used: Arc::new(RwLock::new(persist::load(path)?)),
+            file: Some(Arc::new(path.to_path_buf())),
+            ..Quotas::new(limit)
+        })
+    }
+
+    /// Gives 'user' a limit of 'limit' bytes instead of the default
+    pub fn with_limit<S: Into<String>>(mut self, user: S, limit: u64) -> Self {
+        self.limits.insert(user.into(), limit);
+        self
+    }
+
+    /// Returns how many bytes 'user' has contributed, and their limit
+    pub fn usage(&self, user: &str) -> Usage {
+        Usage {
+            used: self.used.read().unwrap().get(user).cloned().unwrap_or(0),
+            limit: self.limits.get(user).cloned().unwrap_or(self.default_limit),
+        }
+    }
+
+    /// Counts 'bytes' more bytes towards the usage of 'user', if
+    /// they may contribute them. The bytes are reserved before the
+    /// contribution is written, so concurrent contributions cannot
+    /// both fit the same space, and given back with `release` if
+    /// writing it fails.
+    pub fn reserve(&self, user: &str, bytes: u64) -> Result<(), QuotaExceeded> {
+        if bytes == 0 {
+            return Ok(());
+        }
+        let limit = self.limits.get(user).cloned().unwrap_or(self.default_limit);
+        let mut used = self.used.write().unwrap();
+        let total = used.entry(String::from(user)).or_insert(0);
+        if total.saturating_add(bytes) > limit {
+            return Err(QuotaExceeded {
+                usage: Usage {
+                    used: *total,
+                    limit,
+                },
+                bytes,
+            });
+        }
+        *total += bytes;
+        self.unsaved.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Gives back 'bytes' bytes reserved for a contribution by 'user'
+    /// which was not written
+    pub fn release(&self, user: &str, bytes: u64) {
+        let mut used = self.used.write().unwrap();
+        if let Some(total) = used.get_mut(user) {
+            *total = total.saturating_sub(bytes);
+            self.unsaved.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Saves the usage to its file, if it has one and the usage has
+    /// changed since it was last saved. Contributions are not held
+    /// up while the file is written, and usage which could not be
+    /// saved is tried again by the next flush.
+    pub fn flush(&self) -> io::Result<()> {
+        let path = match self.file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let _saving = self.saving.lock().unwrap();
+        if !self.unsaved.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        // contributions made after the copy mark the usage as changed
+        // again, so they are saved by the next flush
+        let used = self.used.read().unwrap().clone();
+        persist::save(path, &used).map_err(|err| {
+            error!("Unable to save quota usage to {}: {}", path.display(), err);
+            self.unsaved.store(true, Ordering::SeqCst);
+            err
+        })
+    }
+}
+
+/// Returns the number of bytes of content an Event adds to the
+/// document
+pub fn contributed(event: &Event) -> u64 {
+    match *event {
+        Event::Edit(ref edit) => edit
+            .operations
+            .iter()
+            .map(|op| match *op {
+                Operation::Insert(ref insert) => insert.content.len() as u64,
+                _ => 0,
+            }).sum(),
+        _ => 0,
+    }
+}
+
+/// Contributing more would take a user over their quota
+#[derive(Debug, PartialEq)]
+pub struct QuotaExceeded {
+    /// The user's usage before the contribution
+    pub usage: Usage,
+    /// The size of the contribution, in bytes
+    pub bytes: u64,
+}
+
+impl Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Adding {} bytes would exceed the storage quota, {} of {} bytes are used",
+            self.bytes, self.usage.used, self.usage.limit
+        )
+    }
+}
+
+impl Error for QuotaExceeded {
+    fn description(&self) -> &str {
+        "QuotaExceeded"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Delete, Edit, Insert, Join, Role};
+
+    #[test]
+    fn limit_contributions() {
+        let quotas = Quotas::new(10).with_limit("bob", 20);
+        assert_eq!(quotas.usage("alice"), Usage { used: 0, limit: 10 });
+        assert_eq!(quotas.reserve("alice", 6), Ok(()));
+        // clones share usage
+        let clone = quotas.clone();
+        assert_eq!(clone.usage("alice").used, 6);
+        assert_eq!(
+            clone.reserve("alice", 5),
+            Err(QuotaExceeded {
+                usage: Usage { used: 6, limit: 10 },
+                bytes: 5,
+            })
+        );
+        // refused contributions reserve nothing
+        assert_eq!(quotas.usage("alice").used, 6);
+        assert_eq!(quotas.reserve("alice", 0), Ok(()));
+        assert_eq!(quotas.reserve("bob", 15), Ok(()));
+        assert_eq!(quotas.usage("bob"), Usage { used: 15, limit: 20 });
+    }
+
+    #[test]
+    fn release_unwritten_contributions() {
+        let quotas = Quotas::new(10);
+        assert_eq!(quotas.reserve("alice", 8), Ok(()));
+        assert!(quotas.reserve("alice", 4).is_err());
+        quotas.release("alice", 8);
+        assert_eq!(quotas.usage("alice").used, 0);
+        assert_eq!(quotas.reserve("alice", 4), Ok(()));
+        // releasing more than was reserved does not go below zero
+        quotas.release("alice", 8);
+        assert_eq!(quotas.usage("alice").used, 0);
+    }
+
+    #[test]
+    fn saved_usage() {
+        let path =
+            ::std::env::temp_dir().join(format!("tamawiki-quotas-{}.json", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let quotas = Quotas::open(10, &path).unwrap();
+        assert_eq!(quotas.reserve("alice", 6), Ok(()));
+        assert_eq!(quotas.reserve("bob", 8), Ok(()));
+        quotas.release("bob", 3);
+        // nothing is written until the usage is flushed
+        assert_eq!(Quotas::open(10, &path).unwrap().usage("alice").used, 0);
+        quotas.flush().unwrap();
+
+        let reopened = Quotas::open(10, &path).unwrap();
+        assert_eq!(reopened.usage("alice"), Usage { used: 6, limit: 10 });
+        assert_eq!(reopened.usage("bob").used, 5);
+        assert!(reopened.reserve("alice", 5).is_err());
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn count_inserted_bytes() {
+        let edit = Event::Edit(Edit {
+            author: 1,
+            operations: vec![
+                Operation::Insert(Insert {
+                    pos: 0,
+                    content: String::from("caf\u{e9}"),
+                }),
+                Operation::Delete(Delete { start: 0, end: 2 }),
+            ],
+        });
+        assert_eq!(contributed(&edit), 5);
+        let join = Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: None,
+        });
+        assert_eq!(contributed(&join), 0);
+    }
+}
@@ -0,0 +1,90 @@
+//! Controls how search engines crawl the wiki: the /robots.txt file,
+//! and which documents they are asked not to index.
+//!
+//! Documents matching a noindex pattern, written like the patterns of
+//! ACL rules (e.g. `/drafts/**`), are served with a `robots` meta tag
+//! and an `X-Robots-Tag` header asking search engines not to index
+//! them. They are not disallowed in robots.txt, as a crawler has to
+//! fetch a page to see that it should not be indexed.
+use std::path::Path;
+
+use crate::acl::path_matches;
+
+/// What search engines may crawl and index
+#[derive(Debug, Clone, Default)]
+pub struct CrawlPolicy {
+    // Served as /robots.txt instead of the default rules, if set
+    robots_txt: Option<String>,
+    // Patterns of documents search engines should not index
+    noindex: Vec<String>,
+}
+
+impl CrawlPolicy {
+    /// Serves 'text' as /robots.txt instead of the default rules
+    pub fn with_robots_txt<S: Into<String>>(mut self, text: S) -> Self {
+        self.robots_txt = Some(text.into());
+        self
+    }
+
+    /// Asks search engines not to index documents matching 'pattern'
+    pub fn with_noindex<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.noindex.push(pattern.into());
+        self
+    }
+
+    /// The contents of /robots.txt for a wiki served under 'base',
+    /// with its own routes under 'reserved'. By default crawlers are
+    /// kept out of the wiki's own routes, apart from attachments, and
+    /// away from the history and editor of each document.
+    pub fn robots_txt(&self, base: &str, reserved: &str) -> String {
+        match self.robots_txt {
+            Some(ref text) => text.clone(),
+            None => format!(
+                "User-agent: *\n\
+                 Allow: {base}{reserved}attachments/\n\
+                 Disallow: {base}{reserved}\n\
+                 Disallow: {base}*?action=\n\
+                 Disallow: {base}*?rev=\n",
+                base = base,
+                reserved = reserved
+            ),
+        }
+    }
+
+    /// Returns true if search engines should not index the document
+    /// at 'path'
+    pub fn noindex(&self, path: &Path) -> bool {
+        self.noindex.iter().any(|pattern| path_matches(pattern, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_robots_txt() {
+        let policy = CrawlPolicy::default();
+        assert_eq!(
+            policy.robots_txt("/wiki/", "_"),
+            "User-agent: *\n\
+             Allow: /wiki/_attachments/\n\
+             Disallow: /wiki/_\n\
+             Disallow: /wiki/*?action=\n\
+             Disallow: /wiki/*?rev=\n"
+        );
+        let policy = policy.with_robots_txt("User-agent: *\nDisallow: /\n");
+        assert_eq!(policy.robots_txt("/", "_"), "User-agent: *\nDisallow: /\n");
+    }
+
+    #[test]
+    fn noindex_patterns() {
+        let policy = CrawlPolicy::default()
+            .with_noindex("/drafts/**")
+            .with_noindex("/*/private.html");
+        assert!(policy.noindex(Path::new("drafts/plans.html")));
+        assert!(policy.noindex(Path::new("team/private.html")));
+        assert!(!policy.noindex(Path::new("team/public.html")));
+        assert!(!policy.noindex(Path::new("index.html")));
+    }
+}
@@ -0,0 +1,122 @@
+//! Checks document paths before they are used as store keys, so a
+//! path cannot name anything outside the wiki in backends which keep
+//! documents on the filesystem, or become a second key for an
+//! existing document.
+//!
+//! Paths are checked after they are percent-decoded and normalized,
+//! so encoded dots, slashes and NUL bytes are caught the same way as
+//! literal ones.
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::path::{Component, Path};
+
+/// Why a document path was rejected
+#[derive(Debug, PartialEq)]
+pub enum UnsafePath {
+    /// The path has a `.` or `..` segment
+    Relative,
+    /// The path starts at the root of the filesystem or a drive
+    Absolute,
+    /// The path contains a control character, such as a NUL byte
+    Control(char),
+}
+
+impl Display for UnsafePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UnsafePath::Relative => write!(f, "Path has a '.' or '..' segment"),
+            UnsafePath::Absolute => write!(f, "Path is absolute"),
+            UnsafePath::Control(c) => write!(f, "Path contains control character {:?}", c),
+        }
+    }
+}
+
+impl Error for UnsafePath {
+    fn description(&self) -> &str {
+        match *self {
+            UnsafePath::Relative => "UnsafePath: relative segment",
+            UnsafePath::Absolute => "UnsafePath: absolute path",
+            UnsafePath::Control(_) => "UnsafePath: control character",
+        }
+    }
+}
+
+/// Returns an error if the decoded document path 'path' is not safe
+/// to use as a store key. Backslashes count as separators, since
+/// they are on some filesystems.
+pub fn check(path: &Path) -> Result<(), UnsafePath> {
+    let text = path.to_string_lossy();
+    if let Some(c) = text.chars().find(|c| c.is_control()) {
+        return Err(UnsafePath::Control(c));
+    }
+    if path.has_root() || text.starts_with('\\') || has_drive(&text) {
+        return Err(UnsafePath::Absolute);
+    }
+    if text.split(['/', '\\']).any(|s| s == "." || s == "..") {
+        return Err(UnsafePath::Relative);
+    }
+    // anything the checks above missed on this platform
+    let normal = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if normal {
+        Ok(())
+    } else {
+        Err(UnsafePath::Absolute)
+    }
+}
+
+// Returns true if 'path' starts with a drive letter, such as "C:"
+fn has_drive(path: &str) -> bool {
+    let mut chars = path.chars();
+    match (chars.next(), chars.next()) {
+        (Some(drive), Some(':')) => drive.is_ascii_alphabetic(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_paths() {
+        let paths = [
+            "",
+            "index.html",
+            "guides/setup/linux.html",
+            "Caf\u{e9} Notes",
+            "a..b",
+            ".hidden",
+            "Note: C:",
+        ];
+        for path in paths.iter() {
+            assert_eq!(check(Path::new(path)), Ok(()), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn unsafe_paths() {
+        let cases = [
+            ("..", UnsafePath::Relative),
+            ("../etc/passwd", UnsafePath::Relative),
+            ("guides/../../secret", UnsafePath::Relative),
+            ("guides/./setup", UnsafePath::Relative),
+            ("guides/..", UnsafePath::Relative),
+            ("..\\windows", UnsafePath::Relative),
+            ("guides\\..\\..\\secret", UnsafePath::Relative),
+            ("/etc/passwd", UnsafePath::Absolute),
+            ("\\\\server\\share", UnsafePath::Absolute),
+            ("C:\\Windows", UnsafePath::Absolute),
+            ("c:/windows", UnsafePath::Absolute),
+            ("index.html\0.png", UnsafePath::Control('\0')),
+            ("two\nlines", UnsafePath::Control('\n')),
+            ("tab\there", UnsafePath::Control('\t')),
+            ("del\u{7f}", UnsafePath::Control('\u{7f}')),
+            ("next\u{85}line", UnsafePath::Control('\u{85}')),
+        ];
+        for &(path, ref expected) in cases.iter() {
+            assert_eq!(check(Path::new(path)).as_ref(), Err(expected), "{:?}", path);
+        }
+    }
+}
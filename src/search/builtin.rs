@@ -0,0 +1,281 @@
+//! An index of documents held in memory, for wikis without a search
+//! service of their own.
+//!
+//! Documents match a query if they contain every word in it, where
+//! the last word may be the start of a longer one so results can be
+//! shown while typing. Matches are ranked using TF-IDF, counting
+//! words in the title three times.
+use futures::future::{self, Future};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use super::{Hit, Indexed, Query, Results, SearchBackend, SearchError};
+
+// How many times more a word in the title counts than one in the
+// content
+const TITLE_WEIGHT: usize = 3;
+
+// The most characters in a snippet
+const SNIPPET_LENGTH: usize = 160;
+
+/// A cloneable handle to an index held in memory
+#[derive(Clone, Default)]
+pub struct MemoryIndex {
+    inner: Arc<RwLock<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    documents: HashMap<PathBuf, Entry>,
+    // The documents containing each word, with the word's weighted
+    // count in each
+    words: BTreeMap<String, HashMap<PathBuf, usize>>,
+}
+
+struct Entry {
+    doc: Indexed,
+    directory: String,
+    // The weighted count of every word in the document
+    length: usize,
+}
+
+impl MemoryIndex {
+    /// The number of documents in the index
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().documents.len()
+    }
+
+    /// Returns true if no documents are indexed
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Inner {
+    fn insert(&mut self, doc: Indexed) {
+        self.remove(&doc.path);
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for word in words(&doc.title) {
+            *counts.entry(word).or_insert(0) += TITLE_WEIGHT;
+        }
+        for word in words(&doc.content) {
+            *counts.entry(word).or_insert(0) += 1;
+        }
+        let length = counts.values().sum();
+        for (word, count) in counts {
+            let documents = self.words.entry(word).or_default();
+            documents.insert(doc.path.clone(), count);
+        }
+        let directory = doc.directory();
+        self.documents.insert(
+            doc.path.clone(),
+            Entry {
+                doc,
+                directory,
+                length,
+            },
+        );
+    }
+
+    fn remove(&mut self, path: &Path) {
+        let entry = match self.documents.remove(path) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let mut indexed = words(&entry.doc.title);
+        indexed.extend(words(&entry.doc.content));
+        for word in indexed {
+            let unused = match self.words.get_mut(&word) {
+                Some(documents) => {
+                    documents.remove(path);
+                    documents.is_empty()
+                }
+                None => false,
+            };
+            if unused {
+                self.words.remove(&word);
+            }
+        }
+    }
+
+    fn search(&self, query: &Query) -> Results {
+        let terms = words(&query.text);
+        let prefix = !query.text.ends_with(char::is_whitespace);
+        let total = self.documents.len() as f64;
+        let mut scores: Option<HashMap<&PathBuf, f64>> = None;
+        for (i, term) in terms.iter().enumerate() {
+            // the word being typed matches any word it starts
+            let matching: Vec<&HashMap<PathBuf, usize>> = if prefix && i == terms.len() - 1 {
+                self.words
+                    .range(term.clone()..)
+                    .take_while(|&(word, _)| word.starts_with(term.as_str()))
+                    .map(|(_, documents)| documents)
+                    .collect()
+            } else {
+                self.words.get(term).into_iter().collect()
+            };
+            let mut term_scores: HashMap<&PathBuf, f64> = HashMap::new();
+            for documents in matching {
+                let idf = (1.0 + total / documents.len() as f64).ln();
+                for (path, &count) in documents {
+                    let length = self.documents[path].length as f64;
+                    *term_scores.entry(path).or_insert(0.0) += count as f64 / length * idf;
+                }
+            }
+            // documents must contain every word
+            scores = Some(match scores {
+                None => term_scores,
+                Some(scores) => scores
+                    .into_iter()
+                    .filter_map(|(path, score)| Some((path, score + term_scores.get(path)?)))
+                    .collect(),
+            });
+        }
+        let mut matches: Vec<(&Entry, f64)> = scores
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(path, score)| (&self.documents[path], score))
+            .filter(|&(entry, _)| match query.directory {
+                Some(ref dir) => entry.doc.path.starts_with(dir),
+                None => true,
+            }).collect();
+        // ties are broken by path so results are stable
+        matches.sort_by(|&(a, a_score), &(b, b_score)| {
+            b_score
+                .partial_cmp(&a_score)
+                .unwrap()
+                .then_with(|| a.doc.path.cmp(&b.doc.path))
+        });
+        let mut directories = BTreeMap::new();
+        for &(entry, _) in &matches {
+            *directories.entry(entry.directory.clone()).or_insert(0) += 1;
+        }
+        Results {
+            total: matches.len(),
+            hits: matches
+                .into_iter()
+                .take(query.limit)
+                .map(|(entry, score)| Hit {
+                    path: entry.doc.path.clone(),
+                    title: entry.doc.title.clone(),
+                    snippet: snippet(&entry.doc.content, &terms),
+                    score,
+                }).collect(),
+            directories,
+        }
+    }
+}
+
+impl SearchBackend for MemoryIndex {
+    fn index(&self, doc: Indexed) -> Box<dyn Future<Item = (), Error = SearchError> + Send> {
+        self.inner.write().unwrap().insert(doc);
+        Box::new(future::ok(()))
+    }
+
+    fn remove(&self, path: &Path) -> Box<dyn Future<Item = (), Error = SearchError> + Send> {
+        self.inner.write().unwrap().remove(path);
+        Box::new(future::ok(()))
+    }
+
+    fn search(&self, query: &Query) -> Box<dyn Future<Item = Results, Error = SearchError> + Send> {
+        Box::new(future::ok(self.inner.read().unwrap().search(query)))
+    }
+}
+
+// Splits text into lowercase words
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+// Returns the line of 'content' containing the first of 'terms' found,
+// or its first line, shortened to SNIPPET_LENGTH characters
+fn snippet(content: &str, terms: &[String]) -> String {
+    let line = content
+        .lines()
+        .find(|line| {
+            let found = words(line);
+            terms
+                .iter()
+                .any(|term| found.iter().any(|word| word.starts_with(term.as_str())))
+        }).or_else(|| content.lines().find(|line| !line.trim().is_empty()))
+        .unwrap_or("")
+        .trim();
+    if line.chars().count() <= SNIPPET_LENGTH {
+        String::from(line)
+    } else {
+        let mut short: String = line.chars().take(SNIPPET_LENGTH).collect();
+        short.push('…');
+        short
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(docs: &[(&str, &str)]) -> MemoryIndex {
+        let index = MemoryIndex::default();
+        for &(path, content) in docs {
+            index.index(Indexed::new(Path::new(path), content)).wait().unwrap();
+        }
+        index
+    }
+
+    fn paths(results: &Results) -> Vec<&str> {
+        results
+            .hits
+            .iter()
+            .map(|hit| hit.path.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rank_matching_documents() {
+        let index = index(&[
+            ("animals.html", "Cats and dogs\nCats sleep a lot"),
+            ("dogs.html", "Dogs like walks"),
+            ("guides/pets.html", "---\ntitle: Cats\n---\nLooking after pets"),
+        ]);
+        let results = index.search(&Query::new("cats ")).wait().unwrap();
+        assert_eq!(results.total, 2);
+        // the title counts for more
+        assert_eq!(paths(&results), vec!["guides/pets.html", "animals.html"]);
+        assert_eq!(results.hits[1].snippet, "Cats and dogs");
+        assert_eq!(results.hits[0].title, "Cats");
+        let mut directories = BTreeMap::new();
+        directories.insert(String::from(""), 1);
+        directories.insert(String::from("guides"), 1);
+        assert_eq!(results.directories, directories);
+
+        // every word must match, and the last may be unfinished
+        let results = index.search(&Query::new("dogs wal")).wait().unwrap();
+        assert_eq!(paths(&results), vec!["dogs.html"]);
+        assert!(index.search(&Query::new("dogs wal ")).wait().unwrap().hits.is_empty());
+
+        let mut query = Query::new("cats");
+        query.directory = Some(String::from("guides"));
+        let results = index.search(&query).wait().unwrap();
+        assert_eq!(paths(&results), vec!["guides/pets.html"]);
+        query.directory = None;
+        query.limit = 1;
+        let results = index.search(&query).wait().unwrap();
+        assert_eq!((results.total, results.hits.len()), (2, 1));
+    }
+
+    #[test]
+    fn replace_and_remove_documents() {
+        let index = index(&[("a.html", "apples"), ("b.html", "apples and bananas")]);
+        index.index(Indexed::new(Path::new("a.html"), "cherries")).wait().unwrap();
+        let results = index.search(&Query::new("apples")).wait().unwrap();
+        assert_eq!(paths(&results), vec!["b.html"]);
+        index.remove(Path::new("b.html")).wait().unwrap();
+        assert!(index.search(&Query::new("apples")).wait().unwrap().hits.is_empty());
+        assert_eq!(index.len(), 1);
+        // words no document contains are forgotten
+        assert!(!index.inner.read().unwrap().words.contains_key("bananas"));
+    }
+}
@@ -0,0 +1,268 @@
+//! Indexes documents in an Elasticsearch index, ranking matches with
+//! its relevance scoring and counting them by directory using an
+//! aggregation.
+//!
+//! Elasticsearch makes changes searchable after its refresh interval,
+//! one second by default. The index's field mappings must be created
+//! with `configure()` before the first document is indexed.
+use futures::future::Future;
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use http::{self, Method};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use super::{
+    document_id, fetch_json, https_client, Hit, Indexed, Query, Results, SearchBackend,
+    SearchError,
+};
+
+// The most characters in a snippet
+const FRAGMENT_SIZE: usize = 160;
+
+// The most directories results are counted in
+const MAX_DIRECTORIES: usize = 100;
+
+/// An Elasticsearch cluster and the index documents are kept in
+#[derive(Clone)]
+pub struct Elasticsearch {
+    // The cluster's URL, without a trailing "/"
+    url: String,
+    index: String,
+    // The value of the Authorization header, if any
+    authorization: Option<String>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Elasticsearch {
+    /// Uses the index called 'index' in the cluster at 'url'
+    pub fn new<S: Into<String>>(url: S, index: S) -> Self {
+        Elasticsearch {
+            url: url.into().trim_end_matches('/').to_string(),
+            index: index.into(),
+            authorization: None,
+            client: https_client(),
+        }
+    }
+
+    /// Logs in to the cluster with a username and password using
+    /// HTTP Basic authentication
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        let credentials = base64::encode(&format!("{}:{}", username, password));
+        self.authorization = Some(format!("Basic {}", credentials));
+        self
+    }
+
+    /// Creates the index, unless it already exists, with mappings
+    /// which let documents be filtered and counted by directory
+    pub fn configure(&self) -> Box<dyn Future<Item = (), Error = SearchError> + Send> {
+        let req = self.request(Method::PUT, "", json!({ "mappings": mappings() }));
+        Box::new(fetch_json(&self.client, req).then(|result| match result {
+            Err(SearchError::InvalidResponse(ref reason))
+                if reason.contains("resource_already_exists_exception") =>
+            {
+                Ok(())
+            }
+            result => result.map(|_| ()),
+        }))
+    }
+
+    // Builds a request to 'path' under the index's URL, with 'body'
+    // unless it is null
+    fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Value,
+    ) -> Result<Request<Body>, http::Error> {
+        let mut req = Request::builder();
+        req.method(method)
+            .uri(format!("{}/{}{}", self.url, self.index, path).as_str())
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(ref authorization) = self.authorization {
+            req.header(AUTHORIZATION, authorization.as_str());
+        }
+        req.body(match body {
+            Value::Null => Body::empty(),
+            body => Body::from(body.to_string()),
+        })
+    }
+}
+
+impl SearchBackend for Elasticsearch {
+    fn index(&self, doc: Indexed) -> Box<dyn Future<Item = (), Error = SearchError> + Send> {
+        let path = format!("/_doc/{}", document_id(&doc.path));
+        let req = self.request(Method::PUT, &path, document(&doc));
+        Box::new(fetch_json(&self.client, req).map(|_| ()))
+    }
+
+    fn remove(&self, path: &Path) -> Box<dyn Future<Item = (), Error = SearchError> + Send> {
+        let path = format!("/_doc/{}", document_id(path));
+        let req = self.request(Method::DELETE, &path, Value::Null);
+        Box::new(fetch_json(&self.client, req).then(|result| match result {
+            // the document was never indexed
+            Err(SearchError::InvalidResponse(ref reason)) if reason.starts_with("404") => Ok(()),
+            result => result.map(|_| ()),
+        }))
+    }
+
+    fn search(&self, query: &Query) -> Box<dyn Future<Item = Results, Error = SearchError> + Send> {
+        let req = self.request(Method::POST, "/_search", search_body(query));
+        Box::new(fetch_json(&self.client, req).and_then(|body| parse_results(&body)))
+    }
+}
+
+// The types of the fields stored for each document
+fn mappings() -> Value {
+    json!({
+        "properties": {
+            "path": {"type": "keyword"},
+            "title": {"type": "text"},
+            "content": {"type": "text"},
+            "directory": {"type": "keyword"},
+            "directories": {"type": "keyword"},
+        }
+    })
+}
+
+// The fields stored for a document
+fn document(doc: &Indexed) -> Value {
+    json!({
+        "path": doc.path,
+        "title": doc.title,
+        "content": doc.content,
+        "directory": doc.directory(),
+        "directories": doc.directories(),
+    })
+}
+
+// The body of a search request for 'query'. Every word must match,
+// and words in the title count three times.
+fn search_body(query: &Query) -> Value {
+    let filter = match query.directory {
+        Some(ref dir) => json!([{ "term": { "directories": dir } }]),
+        None => json!([]),
+    };
+    json!({
+        "size": query.limit,
+        "_source": ["path", "title"],
+        "query": {
+            "bool": {
+                "must": {
+                    "multi_match": {
+                        "query": query.text,
+                        "fields": ["title^3", "content"],
+                        "operator": "and",
+                    }
+                },
+                "filter": filter,
+            }
+        },
+        "highlight": {
+            "fields": {
+                "content": {
+                    "fragment_size": FRAGMENT_SIZE,
+                    "number_of_fragments": 1,
+                    "no_match_size": FRAGMENT_SIZE,
+                }
+            }
+        },
+        "aggs": {
+            "directories": {
+                "terms": { "field": "directory", "size": MAX_DIRECTORIES }
+            }
+        },
+    })
+}
+
+// Reads the Results from the body of a search response
+fn parse_results(body: &Value) -> Result<Results, SearchError> {
+    let invalid = |field: &str| SearchError::InvalidResponse(format!("missing {}", field));
+    let hits = body["hits"]["hits"].as_array().ok_or_else(|| invalid("hits"))?;
+    let hits = hits
+        .iter()
+        .map(|hit| {
+            let source = &hit["_source"];
+            // matches are highlighted with tags, which snippets leave out
+            let snippet = hit["highlight"]["content"][0].as_str().unwrap_or("");
+            Ok(Hit {
+                path: PathBuf::from(source["path"].as_str().ok_or_else(|| invalid("path"))?),
+                title: String::from(source["title"].as_str().unwrap_or("")),
+                snippet: snippet.replace("<em>", "").replace("</em>", ""),
+                score: hit["_score"].as_f64().unwrap_or(0.0),
+            })
+        }).collect::<Result<Vec<_>, SearchError>>()?;
+    // Elasticsearch 7 reports the total as an object
+    let total = body["hits"]["total"]["value"]
+        .as_u64()
+        .or_else(|| body["hits"]["total"].as_u64())
+        .map_or(hits.len(), |total| total as usize);
+    let directories = match body["aggregations"]["directories"]["buckets"].as_array() {
+        Some(buckets) => buckets
+            .iter()
+            .filter_map(|bucket| {
+                let count = bucket["doc_count"].as_u64()? as usize;
+                Some((String::from(bucket["key"].as_str()?), count))
+            }).collect(),
+        None => Default::default(),
+    };
+    Ok(Results {
+        total,
+        hits,
+        directories,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_request() {
+        let mut query = Query::new("install");
+        let body = search_body(&query);
+        assert_eq!(body["size"], 20);
+        assert_eq!(body["query"]["bool"]["filter"], json!([]));
+        query.directory = Some(String::from("guides"));
+        let body = search_body(&query);
+        assert_eq!(
+            body["query"]["bool"]["filter"],
+            json!([{"term": {"directories": "guides"}}])
+        );
+        let doc = document(&Indexed::new(Path::new("guides/setup.html"), "Install it"));
+        assert_eq!(doc["directory"], "guides");
+    }
+
+    #[test]
+    fn search_response() {
+        let hits = json!([{
+            "_id": "6775696465732f73657475702e68746d6c",
+            "_score": 1.5,
+            "_source": {"path": "guides/setup.html", "title": "setup"},
+            "highlight": {"content": ["<em>Install</em> it"]},
+        }]);
+        let buckets = json!([{"key": "guides", "doc_count": 3}]);
+        let body = json!({
+            "hits": {"total": {"value": 3, "relation": "eq"}, "hits": hits},
+            "aggregations": {"directories": {"buckets": buckets}},
+        });
+        let results = parse_results(&body).unwrap();
+        assert_eq!(results.total, 3);
+        assert_eq!(
+            results.hits,
+            vec![Hit {
+                path: PathBuf::from("guides/setup.html"),
+                title: String::from("setup"),
+                snippet: String::from("Install it"),
+                score: 1.5,
+            }]
+        );
+        assert_eq!(results.directories["guides"], 3);
+
+        // and earlier versions as a number
+        let body = json!({"hits": {"total": 2, "hits": []}});
+        assert_eq!(parse_results(&body).unwrap().total, 2);
+    }
+}
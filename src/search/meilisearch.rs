@@ -0,0 +1,207 @@
+//! Indexes documents in a Meilisearch index, which ranks matches
+//! with typo tolerance and counts them by directory.
+//!
+//! Meilisearch applies changes to its indexes in the background, so
+//! documents can be found a moment after they are indexed. The index
+//! must be set up with `configure()` before searching by directory.
+use futures::future::Future;
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use http::{self, Method};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use super::{
+    document_id, fetch_json, https_client, Hit, Indexed, Query, Results, SearchBackend,
+    SearchError,
+};
+
+// The number of words around the matched words in a snippet
+const CROP_LENGTH: usize = 30;
+
+/// A Meilisearch server and the index documents are kept in
+#[derive(Clone)]
+pub struct Meilisearch {
+    // The server's URL, without a trailing "/"
+    url: String,
+    index: String,
+    api_key: Option<String>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Meilisearch {
+    /// Uses the index called 'index' on the server at 'url', which
+    /// is created when the first document is added
+    pub fn new<S: Into<String>>(url: S, index: S) -> Self {
+        Meilisearch {
+            url: url.into().trim_end_matches('/').to_string(),
+            index: index.into(),
+            api_key: None,
+            client: https_client(),
+        }
+    }
+
+    /// Sets the API key sent with every request
+    pub fn with_api_key<S: Into<String>>(mut self, key: S) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    /// Lets the index filter and count documents by directory
+    pub fn configure(&self) -> Box<dyn Future<Item = (), Error = SearchError> + Send> {
+        let req = self.request(
+            Method::PUT,
+            "settings/filterable-attributes",
+            json!(["directory", "directories"]),
+        );
+        Box::new(fetch_json(&self.client, req).map(|_| ()))
+    }
+
+    // Builds a request to 'path' under the index's URL, with 'body'
+    // unless it is null
+    fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Value,
+    ) -> Result<Request<Body>, http::Error> {
+        let mut req = Request::builder();
+        req.method(method)
+            .uri(format!("{}/indexes/{}/{}", self.url, self.index, path).as_str())
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(ref key) = self.api_key {
+            req.header(AUTHORIZATION, format!("Bearer {}", key).as_str());
+        }
+        req.body(match body {
+            Value::Null => Body::empty(),
+            body => Body::from(body.to_string()),
+        })
+    }
+}
+
+impl SearchBackend for Meilisearch {
+    fn index(&self, doc: Indexed) -> Box<dyn Future<Item = (), Error = SearchError> + Send> {
+        let req = self.request(Method::PUT, "documents?primaryKey=id", json!([document(&doc)]));
+        Box::new(fetch_json(&self.client, req).map(|_| ()))
+    }
+
+    fn remove(&self, path: &Path) -> Box<dyn Future<Item = (), Error = SearchError> + Send> {
+        let id = document_id(path);
+        let req = self.request(Method::DELETE, &format!("documents/{}", id), Value::Null);
+        Box::new(fetch_json(&self.client, req).map(|_| ()))
+    }
+
+    fn search(&self, query: &Query) -> Box<dyn Future<Item = Results, Error = SearchError> + Send> {
+        let req = self.request(Method::POST, "search", search_body(query));
+        Box::new(fetch_json(&self.client, req).and_then(|body| parse_results(&body)))
+    }
+}
+
+// The fields stored for a document
+fn document(doc: &Indexed) -> Value {
+    json!({
+        "id": document_id(&doc.path),
+        "path": doc.path,
+        "title": doc.title,
+        "content": doc.content,
+        "directory": doc.directory(),
+        "directories": doc.directories(),
+    })
+}
+
+// The body of a search request for 'query'
+fn search_body(query: &Query) -> Value {
+    let mut body = json!({
+        "q": query.text,
+        "limit": query.limit,
+        "facets": ["directory"],
+        "attributesToRetrieve": ["path", "title", "content"],
+        "attributesToCrop": ["content"],
+        "cropLength": CROP_LENGTH,
+        "showRankingScore": true,
+    });
+    if let Some(ref dir) = query.directory {
+        let quoted = dir.replace('\\', "\\\\").replace('"', "\\\"");
+        body["filter"] = json!(format!("directories = \"{}\"", quoted));
+    }
+    body
+}
+
+// Reads the Results from the body of a search response
+fn parse_results(body: &Value) -> Result<Results, SearchError> {
+    let invalid = |field: &str| SearchError::InvalidResponse(format!("missing {}", field));
+    let hits = body["hits"].as_array().ok_or_else(|| invalid("hits"))?;
+    let hits = hits
+        .iter()
+        .map(|hit| {
+            Ok(Hit {
+                path: PathBuf::from(hit["path"].as_str().ok_or_else(|| invalid("path"))?),
+                title: String::from(hit["title"].as_str().unwrap_or("")),
+                snippet: String::from(hit["_formatted"]["content"].as_str().unwrap_or("")),
+                score: hit["_rankingScore"].as_f64().unwrap_or(0.0),
+            })
+        }).collect::<Result<Vec<_>, SearchError>>()?;
+    let directories = match body["facetDistribution"]["directory"].as_object() {
+        Some(counts) => counts
+            .iter()
+            .map(|(dir, count)| (dir.clone(), count.as_u64().unwrap_or(0) as usize))
+            .collect(),
+        None => Default::default(),
+    };
+    Ok(Results {
+        total: body["estimatedTotalHits"]
+            .as_u64()
+            .map_or(hits.len(), |total| total as usize),
+        hits,
+        directories,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_request() {
+        let mut query = Query::new("install");
+        query.directory = Some(String::from("guides/\"setup\""));
+        let body = search_body(&query);
+        assert_eq!(body["q"], "install");
+        assert_eq!(body["limit"], 20);
+        assert_eq!(body["filter"], "directories = \"guides/\\\"setup\\\"\"");
+        let doc = document(&Indexed::new(Path::new("guides/setup.html"), "Install it"));
+        assert_eq!(doc["id"], "6775696465732f73657475702e68746d6c");
+        assert_eq!(doc["directories"], json!(["guides"]));
+    }
+
+    #[test]
+    fn search_response() {
+        let body = json!({
+            "hits": [{
+                "id": "6775696465732f73657475702e68746d6c",
+                "path": "guides/setup.html",
+                "title": "setup",
+                "content": "Install it then run it",
+                "_formatted": {"content": "Install it…"},
+                "_rankingScore": 0.9,
+            }],
+            "estimatedTotalHits": 3,
+            "facetDistribution": {"directory": {"guides": 3}},
+        });
+        let results = parse_results(&body).unwrap();
+        assert_eq!(results.total, 3);
+        assert_eq!(
+            results.hits,
+            vec![Hit {
+                path: PathBuf::from("guides/setup.html"),
+                title: String::from("setup"),
+                snippet: String::from("Install it…"),
+                score: 0.9,
+            }]
+        );
+        assert_eq!(results.directories["guides"], 3);
+        assert!(parse_results(&json!({"message": "Index not found"})).is_err());
+    }
+}
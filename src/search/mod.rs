@@ -0,0 +1,443 @@
+//! Full text search of documents.
+//!
+//! A SearchBackend indexes each document's title and content, and
+//! answers queries with the best matching documents, most relevant
+//! first, along with how many matches there are in each directory.
+//! The built-in MemoryIndex needs no other services, while larger
+//! wikis can offload indexing and ranking to Meilisearch or
+//! Elasticsearch.
+//!
+//! Backends are kept up to date by an Indexer, which reads the
+//! documents changed since it last ran from the store.
+use futures::future::{self, Future};
+use futures::stream::Stream;
+use hyper::client::HttpConnector;
+use hyper::{self, Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use serde_json::{self, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::markup;
+use crate::store::{Store, StoreError};
+
+pub mod builtin;
+pub mod elasticsearch;
+pub mod meilisearch;
+
+/// The number of results returned unless a query sets its own limit
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// A document as it is indexed
+#[derive(Debug, PartialEq, Clone)]
+pub struct Indexed {
+    /// The document's path
+    pub path: PathBuf,
+    /// The document's title, from its front matter or path
+    pub title: String,
+    /// The text readers see, without front matter
+    pub content: String,
+}
+
+impl Indexed {
+    /// Prepares the document at 'path' for indexing
+    pub fn new(path: &Path, content: &str) -> Self {
+        Indexed {
+            path: path.to_path_buf(),
+            title: markup::document_title(path, content),
+            content: String::from(markup::body(content)),
+        }
+    }
+
+    /// The directory containing the document, "" for documents at
+    /// the top of the wiki. Results are counted by this directory.
+    pub fn directory(&self) -> String {
+        directory(&self.path)
+    }
+
+    /// The directory containing the document and each of its
+    /// parents, which queries for any of them match
+    pub fn directories(&self) -> Vec<String> {
+        self.path
+            .ancestors()
+            .skip(1)
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .filter(|dir| !dir.is_empty())
+            .collect()
+    }
+}
+
+/// What to search for
+#[derive(Debug, PartialEq, Clone)]
+pub struct Query {
+    /// The words to search for
+    pub text: String,
+    /// Only match documents under this directory, if set
+    pub directory: Option<String>,
+    /// The most results to return
+    pub limit: usize,
+}
+
+impl Query {
+    /// Searches every document for 'text'
+    pub fn new<S: Into<String>>(text: S) -> Self {
+        Query {
+            text: text.into(),
+            directory: None,
+            limit: DEFAULT_LIMIT,
+        }
+    }
+}
+
+/// A document matching a query
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Hit {
+    /// The document's path
+    pub path: PathBuf,
+    /// The document's title
+    pub title: String,
+    /// Plain text from the document around the words matched
+    pub snippet: String,
+    /// How relevant the document is, higher is better. Scores are
+    /// only comparable between hits from the same backend.
+    pub score: f64,
+}
+
+/// The answer to a query
+#[derive(Debug, PartialEq, Clone, Default, Serialize)]
+pub struct Results {
+    /// The number of documents matching the query, which may be more
+    /// than the hits returned
+    pub total: usize,
+    /// The best matching documents, most relevant first
+    pub hits: Vec<Hit>,
+    /// The number of matching documents directly inside each
+    /// directory, "" for the top of the wiki
+    pub directories: BTreeMap<String, usize>,
+}
+
+/// Indexes documents and searches them. Backends are cloneable
+/// handles to their index.
+pub trait SearchBackend: Send + Sync + 'static {
+    /// Adds a document to the index, replacing any earlier version
+    fn index(&self, doc: Indexed) -> Box<dyn Future<Item = (), Error = SearchError> + Send>;
+
+    /// Removes the document at 'path' from the index, if it is there
+    fn remove(&self, path: &Path) -> Box<dyn Future<Item = (), Error = SearchError> + Send>;
+
+    /// Finds the documents best matching 'query'
+    fn search(&self, query: &Query) -> Box<dyn Future<Item = Results, Error = SearchError> + Send>;
+}
+
+/// Error conditions which may occur when indexing or searching
+#[derive(Debug)]
+pub enum SearchError {
+    /// The search service could not be reached
+    Http(hyper::Error),
+    /// The search service responded with an error or an unexpected
+    /// body
+    InvalidResponse(String),
+    /// Documents could not be read from the store
+    Store(StoreError),
+}
+
+impl Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SearchError::Http(ref err) => write!(f, "Http({})", err),
+            SearchError::InvalidResponse(ref reason) => write!(f, "InvalidResponse({})", reason),
+            SearchError::Store(ref err) => write!(f, "Store({})", err),
+        }
+    }
+}
+
+impl Error for SearchError {
+    fn description(&self) -> &str {
+        match *self {
+            SearchError::Http(_) => "SearchError: could not reach search service",
+            SearchError::InvalidResponse(_) => "SearchError: invalid response from search service",
+            SearchError::Store(_) => "SearchError: could not read documents",
+        }
+    }
+}
+
+impl From<StoreError> for SearchError {
+    fn from(err: StoreError) -> Self {
+        SearchError::Store(err)
+    }
+}
+
+/// Keeps a SearchBackend up to date with the documents in a store.
+/// Clones share the time of the last update.
+pub struct Indexer<T: Store> {
+    store: T,
+    backend: Arc<dyn SearchBackend>,
+    // Documents changed up to this time have been indexed
+    indexed: Arc<Mutex<SystemTime>>,
+    // Whether only published revisions are indexed
+    drafts: bool,
+}
+
+impl<T: Store> Clone for Indexer<T> {
+    fn clone(&self) -> Self {
+        Indexer {
+            store: self.store.clone(),
+            backend: self.backend.clone(),
+            indexed: self.indexed.clone(),
+            drafts: self.drafts,
+        }
+    }
+}
+
+impl<T: Store> Indexer<T> {
+    /// Creates an Indexer whose first update indexes every document
+    /// in the store
+    pub fn new<S: SearchBackend>(store: T, backend: S) -> Self {
+        Indexer {
+            store,
+            backend: Arc::new(backend),
+            indexed: Arc::new(Mutex::new(UNIX_EPOCH)),
+            drafts: false,
+        }
+    }
+
+    /// Indexes the last published revision of each document instead
+    /// of its drafts, for wikis using `TamaWiki::with_drafts`.
+    /// Documents which have never been published are not indexed.
+    pub fn with_drafts(mut self) -> Self {
+        self.drafts = true;
+        self
+    }
+
+    /// Indexes the latest content, or published revision, of each
+    /// document changed since the last update, and removes documents
+    /// which can no longer be read or are in the trash. Resolves to
+    /// the number of documents updated. If the update fails, the next
+    /// one tries those documents again.
+    pub fn update(&self) -> Box<dyn Future<Item = usize, Error = SearchError> + Send> {
+        let now = SystemTime::now();
+        let since = *self.indexed.lock().unwrap();
+        let store = self.store.clone();
+        let backend = self.backend.clone();
+        let indexed = self.indexed.clone();
+        let drafts = self.drafts;
+        // each document is only indexed once, however often it changed
+        let mut seen = HashSet::new();
+        let paths = self
+            .store
+            .changes()
+            .take_while(move |change| Ok(change.time > since))
+            .filter(move |change| change.time <= now)
+            .map(|change| change.path)
+            .filter(move |path| seen.insert(path.clone()))
+            .map_err(SearchError::Store);
+        let updated = paths.and_then(move |path| {
+            let backend = backend.clone();
+            let revisions = store.clone();
+            store.content(&path).then(
+                move |result| -> Box<dyn Future<Item = (), Error = SearchError> + Send> {
+                    match result {
+                        Ok((_, ref doc)) if doc.deleted => backend.remove(&path),
+                        Ok((seq, doc)) if drafts => match doc.published {
+                            Some(published) if published < seq => Box::new(
+                                revisions
+                                    .content_at(&path, published)
+                                    .map_err(SearchError::Store)
+                                    .and_then(move |doc| {
+                                        backend.index(Indexed::new(&path, &doc.content))
+                                    }),
+                            ),
+                            Some(_) => backend.index(Indexed::new(&path, &doc.content)),
+                            None => backend.remove(&path),
+                        },
+                        Ok((_, doc)) => backend.index(Indexed::new(&path, &doc.content)),
+                        Err(StoreError::NotFound) | Err(StoreError::InvalidDocument) => {
+                            backend.remove(&path)
+                        }
+                        Err(err) => Box::new(future::err(SearchError::Store(err))),
+                    }
+                },
+            )
+        });
+        Box::new(updated.fold(0, |count, ()| Ok::<_, SearchError>(count + 1)).map(
+            move |count| {
+                *indexed.lock().unwrap() = now;
+                count
+            },
+        ))
+    }
+}
+
+// The directory containing the document at 'path'
+fn directory(path: &Path) -> String {
+    path.parent()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// An identifier for the document at 'path' made only of characters
+// every search service accepts in document ids and URLs
+fn document_id(path: &Path) -> String {
+    path.to_string_lossy()
+        .bytes()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn https_client() -> Client<HttpsConnector<HttpConnector>> {
+    Client::builder().build(HttpsConnector::new(4))
+}
+
+// Makes a request to a search service and parses the JSON response
+fn fetch_json(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    req: Result<Request<Body>, ::http::Error>,
+) -> Box<dyn Future<Item = Value, Error = SearchError> + Send> {
+    let req = match req {
+        Ok(req) => req,
+        Err(err) => return Box::new(future::err(SearchError::InvalidResponse(format!("{}", err)))),
+    };
+    Box::new(
+        client
+            .request(req)
+            .map_err(SearchError::Http)
+            .and_then(|res| {
+                let status = res.status();
+                res.into_body()
+                    .concat2()
+                    .map_err(SearchError::Http)
+                    .and_then(move |body| {
+                        if !status.is_success() {
+                            return Err(SearchError::InvalidResponse(format!(
+                                "{}: {}",
+                                status,
+                                String::from_utf8_lossy(&body)
+                            )));
+                        }
+                        serde_json::from_slice(&body)
+                            .map_err(|err| SearchError::InvalidResponse(format!("{}", err)))
+                    })
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::builtin::MemoryIndex;
+    use super::*;
+    use crate::document::{Edit, Event, Insert, Join, Leave, Operation, Publish, Role, Tombstone};
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn indexed_documents() {
+        let doc = Indexed::new(
+            Path::new("guides/setup/linux.html"),
+            "---\ntitle: Linux setup\n---\nInstall it",
+        );
+        assert_eq!(doc.title, "Linux setup");
+        assert_eq!(doc.content, "Install it");
+        assert_eq!(doc.directory(), "guides/setup");
+        assert_eq!(doc.directories(), vec!["guides/setup", "guides"]);
+        let doc = Indexed::new(Path::new("getting-started.html"), "Welcome");
+        assert_eq!(doc.title, "getting started");
+        assert_eq!(doc.directory(), "");
+        assert!(doc.directories().is_empty());
+        assert_eq!(document_id(Path::new("a/b.html")), "612f622e68746d6c");
+    }
+
+    // Inserts 'content' at the start of the document at 'path'
+    fn write(store: &mut MemoryStore, path: &str, content: &str) {
+        let events = vec![
+            Event::Join(Join {
+                id: 1,
+                role: Role::Editor,
+                user: None,
+            }),
+            Event::Edit(Edit {
+                author: 1,
+                operations: vec![Operation::Insert(Insert {
+                    pos: 0,
+                    content: String::from(content),
+                })],
+            }),
+            Event::Leave(Leave { id: 1 }),
+        ];
+        for event in events {
+            store.push(PathBuf::from(path), event).wait().unwrap();
+        }
+    }
+
+    #[test]
+    fn index_changed_documents() {
+        let mut store = MemoryStore::default();
+        let index = MemoryIndex::default();
+        let indexer = Indexer::new(store.clone(), index.clone());
+        let found = |query: &str| -> Vec<PathBuf> {
+            let results = index.search(&Query::new(query)).wait().unwrap();
+            results.hits.into_iter().map(|hit| hit.path).collect()
+        };
+        write(&mut store, "a.html", "apples");
+        write(&mut store, "b.html", "bananas");
+        assert_eq!(indexer.update().wait().unwrap(), 2);
+        assert_eq!(found("apples"), vec![PathBuf::from("a.html")]);
+
+        // only documents changed since the last update are indexed
+        assert_eq!(indexer.update().wait().unwrap(), 0);
+        write(&mut store, "a.html", "green ");
+        assert_eq!(indexer.update().wait().unwrap(), 1);
+        assert_eq!(found("green apples"), vec![PathBuf::from("a.html")]);
+
+        // deleted documents are removed from the index
+        let join = Event::Join(Join {
+            id: 2,
+            role: Role::Editor,
+            user: None,
+        });
+        store.push(PathBuf::from("a.html"), join).wait().unwrap();
+        let tombstone = Event::Tombstone(Tombstone { id: 2 });
+        store.push(PathBuf::from("a.html"), tombstone).wait().unwrap();
+        assert_eq!(indexer.update().wait().unwrap(), 1);
+        assert!(found("apples").is_empty());
+    }
+
+    #[test]
+    fn index_published_revisions() {
+        let mut store = MemoryStore::default();
+        let index = MemoryIndex::default();
+        let indexer = Indexer::new(store.clone(), index.clone()).with_drafts();
+        let found = |query: &str| -> Vec<PathBuf> {
+            let results = index.search(&Query::new(query)).wait().unwrap();
+            results.hits.into_iter().map(|hit| hit.path).collect()
+        };
+
+        // documents which have never been published are not indexed
+        write(&mut store, "a.html", "apples");
+        assert_eq!(indexer.update().wait().unwrap(), 1);
+        assert!(found("apples").is_empty());
+
+        let events = vec![
+            Event::Join(Join {
+                id: 2,
+                role: Role::Editor,
+                user: None,
+            }),
+            Event::Publish(Publish { id: 2, seq: 4 }),
+            Event::Leave(Leave { id: 2 }),
+        ];
+        for event in events {
+            store.push(PathBuf::from("a.html"), event).wait().unwrap();
+        }
+        assert_eq!(indexer.update().wait().unwrap(), 1);
+        assert_eq!(found("apples"), vec![PathBuf::from("a.html")]);
+
+        // later drafts are not searchable until they are published
+        write(&mut store, "a.html", "pears and ");
+        assert_eq!(indexer.update().wait().unwrap(), 1);
+        assert!(found("pears").is_empty());
+        assert_eq!(found("apples"), vec![PathBuf::from("a.html")]);
+    }
+}
@@ -0,0 +1,98 @@
+//! Stores files uploaded alongside documents on the filesystem
+use futures::future::Future;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::write_all;
+
+/// The largest attachment accepted by default, in bytes
+pub const DEFAULT_MAX_ATTACHMENT_SIZE: usize = 10 * 1024 * 1024;
+
+/// Returns the location under 'root' for the attachment at the
+/// decoded document path 'path', or None if the path is empty or
+/// would escape the root directory.
+pub fn attachment_path(root: &Path, path: &Path) -> Option<PathBuf> {
+    let mut components = path.components().peekable();
+    components.peek()?;
+    if components.all(|c| matches!(c, Component::Normal(_))) {
+        Some(root.join(path))
+    } else {
+        None
+    }
+}
+
+/// Returns the media type to serve the attachment at 'path' as,
+/// based on its file extension
+pub fn content_type(path: &str) -> &'static str {
+    let extension = match path.rfind('.') {
+        Some(i) => path[i + 1..].to_lowercase(),
+        None => String::new(),
+    };
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Returns true if browsers may show the attachment at 'path' in
+/// place, rather than downloading it. Only raster images are shown,
+/// as other types such as SVG may run scripts in the wiki's origin.
+pub fn inline(path: &str) -> bool {
+    matches!(
+        content_type(path),
+        "image/png" | "image/jpeg" | "image/gif" | "image/webp"
+    )
+}
+
+/// Writes the attachment to 'path', replacing any existing file and
+/// creating its parent directories if required.
+pub fn save(path: PathBuf, data: Vec<u8>) -> impl Future<Item = (), Error = io::Error> {
+    let dir = path.parent().map(PathBuf::from).unwrap_or_default();
+    fs::create_dir_all(dir)
+        .and_then(move |_| File::create(path))
+        .and_then(move |file| write_all(file, data))
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_attachment_paths() {
+        let root = Path::new("/data");
+        let path = |path: &str| attachment_path(root, Path::new(path));
+        assert_eq!(
+            path("images/cat.png"),
+            Some(PathBuf::from("/data/images/cat.png"))
+        );
+        assert_eq!(path("my notes.txt"), Some(PathBuf::from("/data/my notes.txt")));
+        assert_eq!(path(""), None);
+        assert_eq!(path("/secret"), None);
+        assert_eq!(path("../secret"), None);
+        assert_eq!(path("images/../../secret"), None);
+    }
+
+    #[test]
+    fn attachment_content_types() {
+        assert_eq!(content_type("/images/cat.png"), "image/png");
+        assert_eq!(content_type("/images/CAT.JPG"), "image/jpeg");
+        assert_eq!(content_type("/notes"), "application/octet-stream");
+    }
+
+    #[test]
+    fn only_raster_images_inline() {
+        assert!(inline("/images/cat.png"));
+        assert!(inline("/images/CAT.JPG"));
+        assert!(!inline("/images/cat.svg"));
+        assert!(!inline("/notes.txt"));
+        assert!(!inline("/page.html"));
+        assert!(!inline("/notes"));
+    }
+}
@@ -0,0 +1,69 @@
+//! Links to the ancestors of a document in a nested path, so readers
+//! can navigate up deep hierarchies
+use futures::future::{self, Future};
+use std::path::{Path, PathBuf};
+
+use crate::store::{Store, StoreError};
+
+/// One of the ancestors of a document
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Breadcrumb {
+    /// The last segment of the ancestor's path
+    pub name: String,
+    /// The ancestor's document path
+    pub path: PathBuf,
+    /// True if there is a document at the ancestor's path, which
+    /// can be linked to
+    pub exists: bool,
+}
+
+/// Returns the paths of the ancestors of the document at 'path',
+/// outermost first
+pub fn ancestors(path: &Path) -> Vec<PathBuf> {
+    let mut ancestors: Vec<PathBuf> = path.ancestors().skip(1).map(PathBuf::from).collect();
+    // the root is the wiki itself
+    ancestors.retain(|ancestor| ancestor.file_name().is_some());
+    ancestors.reverse();
+    ancestors
+}
+
+/// Resolves to the breadcrumbs for the document at 'path', checking
+/// which of its ancestors are documents themselves
+pub fn breadcrumbs<T: Store>(
+    store: &T,
+    path: &Path,
+) -> impl Future<Item = Vec<Breadcrumb>, Error = StoreError> {
+    let crumbs = ancestors(path).into_iter().map(|path| {
+        store.seq(&path).then(move |result| {
+            let exists = match result {
+                Ok(_) => true,
+                Err(StoreError::NotFound) => false,
+                Err(err) => return Err(err),
+            };
+            Ok(Breadcrumb {
+                name: path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                path,
+                exists,
+            })
+        })
+    });
+    future::join_all(crumbs.collect::<Vec<_>>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn document_ancestors() {
+        assert_eq!(
+            ancestors(Path::new("guides/setup/linux.html")),
+            vec![PathBuf::from("guides"), PathBuf::from("guides/setup")]
+        );
+        assert!(ancestors(Path::new("index.html")).is_empty());
+        assert!(ancestors(Path::new("")).is_empty());
+    }
+}
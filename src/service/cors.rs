@@ -0,0 +1,182 @@
+//! Cross-Origin Resource Sharing, so browser-based tools hosted on
+//! other sites can read wiki content.
+use http::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use http::{Method, StatusCode};
+use hyper::{Body, Request, Response};
+
+/// Which cross-origin requests are allowed, and what they may send.
+/// By default only GET and HEAD requests are allowed, so other sites
+/// can read content but not make changes.
+#[derive(Debug, Clone)]
+pub struct Cors {
+    // Allowed origins, or None to allow any origin
+    origins: Option<Vec<String>>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    // How long browsers may cache preflight responses, in seconds
+    max_age: u32,
+}
+
+impl Cors {
+    /// Allows requests from any origin
+    pub fn any_origin() -> Self {
+        Cors {
+            origins: None,
+            methods: vec![Method::GET, Method::HEAD],
+            headers: vec![String::from("authorization"), String::from("last-event-id")],
+            max_age: 3600,
+        }
+    }
+
+    /// Allows requests only from the listed origins, for example
+    /// "https://example.com"
+    pub fn origins<I, S>(origins: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Cors {
+            origins: Some(origins.into_iter().map(Into::into).collect()),
+            ..Cors::any_origin()
+        }
+    }
+
+    /// Sets the request methods which other origins may use
+    pub fn with_methods(mut self, methods: Vec<Method>) -> Self {
+        self.methods = methods;
+        self
+    }
+
+    /// Sets the request headers which other origins may send
+    pub fn with_headers<I, S>(mut self, headers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets how long, in seconds, browsers may cache the result of a
+    /// preflight request
+    pub fn with_max_age(mut self, seconds: u32) -> Self {
+        self.max_age = seconds;
+        self
+    }
+
+    /// Returns the value of the Access-Control-Allow-Origin header
+    /// for the request, or None if it is not a cross-origin request
+    /// or its origin is not allowed
+    pub fn allow_origin(&self, req: &Request<Body>) -> Option<HeaderValue> {
+        let origin = req.headers().get(ORIGIN)?;
+        match self.origins {
+            None => Some(HeaderValue::from_static("*")),
+            Some(ref origins) => if origins.iter().any(|o| o.as_bytes() == origin.as_bytes()) {
+                Some(origin.clone())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns the response to a preflight request from an allowed
+    /// origin, or None if the request is not a preflight request
+    pub fn preflight(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        if *req.method() != Method::OPTIONS
+            || !req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return None;
+        }
+        let allow_origin = self.allow_origin(req)?;
+        let methods = self
+            .methods
+            .iter()
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut res = Response::builder();
+        res.status(StatusCode::NO_CONTENT)
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)
+            .header(ACCESS_CONTROL_ALLOW_METHODS, methods.as_str())
+            .header(ACCESS_CONTROL_MAX_AGE, self.max_age.to_string().as_str())
+            .header(VARY, "Origin");
+        if !self.headers.is_empty() {
+            res.header(ACCESS_CONTROL_ALLOW_HEADERS, self.headers.join(", ").as_str());
+        }
+        Some(res.body(Body::empty()).unwrap())
+    }
+
+    /// Adds headers to 'res' allowing the origin 'allow_origin' to
+    /// read it, if the request used an allowed method
+    pub fn apply(&self, method: &Method, allow_origin: HeaderValue, res: &mut Response<Body>) {
+        if !self.methods.contains(method) {
+            return;
+        }
+        let headers = res.headers_mut();
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static("etag"));
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn allowed_origins() {
+        let cors = Cors::origins(vec!["https://example.com"]);
+        assert_eq!(
+            cors.allow_origin(&request(Method::GET, "https://example.com")),
+            Some(HeaderValue::from_static("https://example.com"))
+        );
+        assert_eq!(
+            cors.allow_origin(&request(Method::GET, "https://example.org")),
+            None
+        );
+        assert_eq!(
+            Cors::any_origin().allow_origin(&request(Method::GET, "https://example.org")),
+            Some(HeaderValue::from_static("*"))
+        );
+        let same_origin = Request::get("/").body(Body::empty()).unwrap();
+        assert_eq!(Cors::any_origin().allow_origin(&same_origin), None);
+    }
+
+    #[test]
+    fn preflight_response() {
+        let cors = Cors::origins(vec!["https://example.com"]);
+        let res = cors
+            .preflight(&request(Method::OPTIONS, "https://example.com"))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers().get(ACCESS_CONTROL_ALLOW_METHODS).unwrap(),
+            "GET, HEAD"
+        );
+        assert_eq!(
+            res.headers().get(ACCESS_CONTROL_ALLOW_HEADERS).unwrap(),
+            "authorization, last-event-id"
+        );
+        assert!(
+            cors.preflight(&request(Method::OPTIONS, "https://example.org"))
+                .is_none()
+        );
+        assert!(
+            cors.preflight(&request(Method::GET, "https://example.com"))
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! Protects requests made with a session cookie from cross-site
+//! request forgery. Each session has a token derived from it, which
+//! the wiki's pages include in their forms and other sites cannot
+//! read.
+use http::Request;
+use hyper::Body;
+use std::collections::HashMap;
+
+use crate::auth::SESSION_COOKIE;
+use crate::service::error::HttpError;
+use crate::service::request::cookie;
+use crate::users::Accounts;
+
+/// Header scripts may send the token in, instead of a form field
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Name of the form field holding the token
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// The session a request was made with, so the token it includes
+/// can be checked once the request body has been read
+pub struct CsrfCheck {
+    // The accounts and session cookie, when the request was made by a
+    // logged in user
+    session: Option<(Accounts, String)>,
+    header: Option<String>,
+}
+
+impl CsrfCheck {
+    /// Reads the session cookie and token header from 'req'
+    pub fn new(accounts: &Option<Accounts>, req: &Request<Body>) -> Self {
+        let session = accounts.as_ref().and_then(|accounts| {
+            let session = cookie(req, SESSION_COOKIE)?;
+            accounts.session_user(&session)?;
+            Some((accounts.clone(), session))
+        });
+        let header = req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        CsrfCheck { session, header }
+    }
+
+    /// Returns the token pages should include in their forms, if the
+    /// request was made with a session cookie
+    pub fn token(&self) -> Option<String> {
+        self.session
+            .as_ref()
+            .map(|&(ref accounts, ref session)| accounts.csrf_token(session))
+    }
+
+    /// Succeeds if the request was not made with a session cookie,
+    /// or it includes the session's token in the header or 'form'
+    pub fn verify(&self, form: Option<&HashMap<String, String>>) -> Result<(), HttpError> {
+        let (accounts, session) = match self.session {
+            Some((ref accounts, ref session)) => (accounts, session),
+            None => return Ok(()),
+        };
+        let token = self
+            .header
+            .as_ref()
+            .or_else(|| form.and_then(|form| form.get(CSRF_FIELD)));
+        match token {
+            Some(token) if accounts.verify_csrf_token(session, token) => Ok(()),
+            _ => Err(HttpError::Forbidden),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Lets other sites and tools embed the wiki's pages using oEmbed
+//! (https://oembed.com). Consumers ask /_embed about the URL of a
+//! page and are given an iframe showing a minimal rendering of the
+//! page, or of the section named by the URL's fragment.
+
+use crate::markup::escape;
+
+/// The width of the iframe in pixels, unless the consumer asks for a
+/// narrower one
+pub const DEFAULT_WIDTH: u32 = 600;
+
+/// The height of the iframe in pixels, unless the consumer asks for a
+/// shorter one
+pub const DEFAULT_HEIGHT: u32 = 400;
+
+/// A page an oEmbed consumer asked about
+#[derive(Debug, PartialEq)]
+pub struct Target {
+    /// The scheme and host of the page's URL, e.g.
+    /// "https://wiki.example.com", or None if only a path was given
+    pub origin: Option<String>,
+    /// The page's percent-encoded URL path, relative to the path the
+    /// wiki is served under
+    pub path: String,
+    /// The section named by the URL's fragment, if any
+    pub section: Option<String>,
+}
+
+impl Target {
+    /// The host the page's URL names, if it has one
+    pub fn host(&self) -> Option<&str> {
+        let origin = self.origin.as_ref()?;
+        origin.split_once("://").map(|(_, host)| host)
+    }
+}
+
+/// Parses the 'url' of a page on a wiki served under the 'base' path.
+/// Returns None if it is not a http or https URL under the base path.
+pub fn parse_url(url: &str, base: &str) -> Option<Target> {
+    let (url, section) = match url.find('#') {
+        Some(i) if i + 1 < url.len() => (&url[..i], Some(String::from(&url[i + 1..]))),
+        Some(i) => (&url[..i], None),
+        None => (url, None),
+    };
+    let (origin, path) = match url.find("://") {
+        Some(i) => {
+            let scheme = &url[..i];
+            if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+                return None;
+            }
+            let rest = &url[i + "://".len()..];
+            let end = rest.find('/').unwrap_or(rest.len());
+            (Some(String::from(&url[..url.len() - rest.len() + end])), &rest[end..])
+        }
+        None => (None, url),
+    };
+    // the query string does not change which page is shown
+    let path = path.split('?').next().unwrap_or("");
+    // the wiki's homepage may be given without its trailing slash
+    let path = if path.is_empty() || format!("{}/", path) == base {
+        ""
+    } else {
+        path.strip_prefix(base)?
+    };
+    Some(Target {
+        origin,
+        path: String::from(path),
+        section,
+    })
+}
+
+/// The iframe's width and height, no larger than the maximum the
+/// consumer asked for
+pub fn size(max_width: Option<u32>, max_height: Option<u32>) -> (u32, u32) {
+    (
+        max_width.map_or(DEFAULT_WIDTH, |max| max.min(DEFAULT_WIDTH)),
+        max_height.map_or(DEFAULT_HEIGHT, |max| max.min(DEFAULT_HEIGHT)),
+    )
+}
+
+/// The oEmbed response embedding the page at 'src' in an iframe
+pub fn response(
+    site_name: &str,
+    provider_url: &str,
+    title: &str,
+    src: &str,
+    (width, height): (u32, u32),
+) -> serde_json::Value {
+    let html = format!(
+        "<iframe src=\"{}\" width=\"{}\" height=\"{}\" title=\"{}\" frameborder=\"0\"></iframe>",
+        escape(src),
+        width,
+        height,
+        escape(title)
+    );
+    json!({
+        "version": "1.0",
+        "type": "rich",
+        "title": title,
+        "provider_name": site_name,
+        "provider_url": provider_url,
+        "html": html,
+        "width": width,
+        "height": height,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_page_urls() {
+        assert_eq!(
+            parse_url("https://wiki.example.com/docs/guides/setup.html?rev=2#linux", "/docs/"),
+            Some(Target {
+                origin: Some(String::from("https://wiki.example.com")),
+                path: String::from("guides/setup.html"),
+                section: Some(String::from("linux")),
+            })
+        );
+        assert_eq!(
+            parse_url("/setup.html", "/"),
+            Some(Target {
+                origin: None,
+                path: String::from("setup.html"),
+                section: None,
+            })
+        );
+        let home = parse_url("http://localhost:8080", "/").unwrap();
+        assert_eq!((home.host(), home.path.as_str()), (Some("localhost:8080"), ""));
+        assert_eq!(parse_url("http://localhost/docs#", "/docs/").unwrap().section, None);
+        assert_eq!(parse_url("https://wiki.example.com/other/page.html", "/docs/"), None);
+        assert_eq!(parse_url("javascript://alert(1)", "/"), None);
+    }
+
+    #[test]
+    fn limit_size() {
+        assert_eq!(size(None, None), (DEFAULT_WIDTH, DEFAULT_HEIGHT));
+        assert_eq!(size(Some(320), Some(1000)), (320, DEFAULT_HEIGHT));
+    }
+
+    #[test]
+    fn escape_iframe() {
+        let res = response("Wiki", "https://w/", "\"Setup\"", "https://w/_embed/a?b=1&c=2", (1, 2));
+        assert_eq!(
+            res["html"],
+            "<iframe src=\"https://w/_embed/a?b=1&amp;c=2\" width=\"1\" height=\"2\" \
+             title=\"&quot;Setup&quot;\" frameborder=\"0\"></iframe>"
+        );
+        assert_eq!(res["type"], "rich");
+    }
+}
@@ -1,11 +1,13 @@
+use http::header::{CONTENT_TYPE, RETRY_AFTER};
 use http::{Response, StatusCode};
 use hyper::Body;
-use serde_json;
 use std::error::Error;
 use std::fmt::{self, Display};
-use tera;
+use std::time::Duration;
 
-use templates::TERA;
+use crate::i18n::message_key;
+use crate::service::layout::Layout;
+use crate::service::reserved_route;
 
 /// Error conditions that could not be handled as a HTTP response
 #[derive(Debug)]
@@ -26,6 +28,16 @@ pub enum HttpError {
     BadRequest,
     NotFound,
     Unauthorized,
+    Forbidden,
+    // The client must wait before trying again
+    TooManyRequests(Duration),
+    // A client error reported with a JSON body instead of an HTML
+    // page, for requests made by scripts rather than browsers
+    Json {
+        status: StatusCode,
+        code: &'static str,
+        reason: String,
+    },
 }
 
 impl HttpError {
@@ -36,40 +48,85 @@ impl HttpError {
             BadRequest => StatusCode::BAD_REQUEST,
             NotFound => StatusCode::NOT_FOUND,
             Unauthorized => StatusCode::UNAUTHORIZED,
+            Forbidden => StatusCode::FORBIDDEN,
+            TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
             InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Json { status, .. } => status,
         }
     }
 
-    fn default_context(&self) -> serde_json::Value {
+    // The context for rendering the error page for a request to
+    // 'path'. Documents can be created at any path outside the
+    // reserved prefix.
+    fn default_context(&self, path: &str, layout: &Layout) -> serde_json::Value {
         use self::HttpError::*;
-        match *self {
+        // routes are matched relative to the base path
+        let route_path = match path.get(layout.base.len() - 1..) {
+            Some(rest) if path.starts_with(&layout.base) => rest,
+            _ => path,
+        };
+        // titles are translated if the catalogue has a message for
+        // them, which JSON error codes do not
+        let title = format!("{}", self);
+        let title = layout.messages.get(&message_key(&title)).unwrap_or(&title);
+        let mut ctx = match *self {
             InternalServerError(ref err) => json!({
-                    "title": "Internal Server Error",
+                    "title": title,
+                    "path": path,
                     "error": err
                 }),
-            _ => json!({ "title": format!("{}", self) }),
-        }
+            _ => json!({
+                "title": title,
+                "path": path,
+                "can_create": reserved_route(route_path, &layout.reserved).is_none(),
+            }),
+        };
+        layout.extend(&mut ctx);
+        ctx
     }
 
     fn default_template(&self) -> String {
         format!("{}.html", self.status_code().as_u16())
     }
 
-    fn render_html(&self) -> tera::Result<String> {
-        TERA.render(&self.default_template(), &self.default_context())
+    fn render_html(&self, path: &str, layout: &Layout) -> tera::Result<String> {
+        layout.render(
+            &self.default_template(),
+            &self.default_context(path, layout),
+        )
     }
-}
 
-impl Into<Response<Body>> for HttpError {
-    fn into(self) -> Response<Body> {
-        match self.render_html() {
-            Ok(html) => Response::builder()
-                .status(self.status_code())
-                .body(Body::from(html))
-                .unwrap(),
+    /// Converts the error into a response to a request for 'path',
+    /// rendering an HTML page using the template named after the
+    /// status code, or a JSON body for Json errors.
+    pub fn into_response(self, path: &str, layout: &Layout) -> Response<Body> {
+        if let HttpError::Json {
+            status,
+            code,
+            ref reason,
+        } = self
+        {
+            let body = json!({ "code": code, "reason": reason });
+            return Response::builder()
+                .status(status)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+        }
+        match self.render_html(path, layout) {
+            Ok(html) => {
+                let mut res = Response::builder();
+                res.status(self.status_code());
+                if let HttpError::TooManyRequests(ref wait) = self {
+                    // whole seconds, rounded up
+                    let seconds = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+                    res.header(RETRY_AFTER, seconds.to_string().as_str());
+                }
+                res.body(Body::from(html)).unwrap()
+            }
             Err(err) => {
                 for e in err.iter() {
-                    eprintln!("{}", e);
+                    error!("{}", e);
                 }
                 if let HttpError::InternalServerError(_) = self {
                     // already failed to render a 500 error, use
@@ -80,7 +137,8 @@ impl Into<Response<Body>> for HttpError {
                         .unwrap()
                 } else {
                     // convert to a 500 error with the template error
-                    HttpError::InternalServerError(format!("Template error: {}", err)).into()
+                    HttpError::InternalServerError(format!("Template error: {}", err))
+                        .into_response(path, layout)
                 }
             }
         }
@@ -95,7 +153,10 @@ impl Display for HttpError {
             BadRequest => write!(f, "Bad Request"),
             NotFound => write!(f, "Not Found"),
             Unauthorized => write!(f, "Unauthorized"),
+            Forbidden => write!(f, "Forbidden"),
+            TooManyRequests(_) => write!(f, "Too Many Requests"),
             InternalServerError(_) => write!(f, "Internal Server Error"),
+            Json { code, .. } => write!(f, "{}", code),
         }
     }
 }
@@ -108,7 +169,10 @@ impl Error for HttpError {
             BadRequest => "bad request",
             NotFound => "not found",
             Unauthorized => "unauthorized",
+            Forbidden => "forbidden",
+            TooManyRequests(_) => "too many requests",
             InternalServerError(ref err) => err,
+            Json { ref reason, .. } => reason,
         }
     }
 }
@@ -0,0 +1,149 @@
+//! Exports documents as files to download, either one at a time or
+//! bundled into a zip archive
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use zip::write::{FileOptions, ZipWriter};
+
+use crate::markup::{self, document_title};
+use crate::service::attachments_url;
+use crate::service::layout::Layout;
+
+/// The file formats documents can be exported to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// The document's content as written
+    Markdown,
+    /// A standalone HTML page showing the content as it is viewed
+    Html,
+}
+
+impl Format {
+    /// Returns the format for the 'format' query parameter
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "markdown" | "md" => Some(Format::Markdown),
+            "html" => Some(Format::Html),
+            _ => None,
+        }
+    }
+
+    /// The value of the Content-Type header for a single document
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Markdown => "text/markdown; charset=utf-8",
+            Format::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Html => "html",
+        }
+    }
+}
+
+/// Returns the name to save the document at 'path' as, replacing its
+/// extension with the format's
+pub fn filename(path: &Path, format: Format) -> PathBuf {
+    path.with_extension(format.extension())
+}
+
+/// Returns the name to save a bundle of the documents under
+/// 'prefix' as
+pub fn bundle_filename(prefix: &Path) -> PathBuf {
+    match prefix.file_stem() {
+        Some(stem) => Path::new(stem).with_extension("zip"),
+        None => PathBuf::from("wiki.zip"),
+    }
+}
+
+/// Returns the value of a Content-Disposition header to download a
+/// file called 'name'
+pub fn content_disposition(name: &Path) -> String {
+    let name = name
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+        .replace(|c: char| c == '"' || c == '\\' || c.is_control(), "_");
+    format!("attachment; filename=\"{}\"", name)
+}
+
+/// Renders the 'content' of the document at 'path' in 'format',
+/// using the templates and attachment URLs from 'layout'. Markdown
+/// is the content itself, so is returned without copying it.
+pub fn render(
+    layout: &Layout,
+    path: &Path,
+    content: String,
+    format: Format,
+) -> Result<String, String> {
+    match format {
+        Format::Markdown => Ok(content),
+        Format::Html => {
+            let attachments = attachments_url(&layout.base, &layout.reserved);
+            let ctx = json!({
+                "title": document_title(path, &content),
+                "content_html": markup::render(&content, &attachments),
+            });
+            layout.render("export.html", &ctx).map_err(|err| format!("{}", err))
+        }
+    }
+}
+
+/// Bundles rendered documents into a zip archive, each stored under
+/// its filename for the format
+pub fn bundle(documents: Vec<(PathBuf, String)>, format: Format) -> io::Result<Vec<u8>> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    for (path, body) in documents {
+        let name = filename(&path, format);
+        writer.start_file(name.to_string_lossy(), FileOptions::default())?;
+        writer.write_all(body.as_bytes())?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use zip::read::ZipArchive;
+
+    #[test]
+    fn export_filenames() {
+        let path = Path::new("guides/setup.html");
+        assert_eq!(
+            filename(path, Format::Markdown),
+            PathBuf::from("guides/setup.md")
+        );
+        assert_eq!(filename(Path::new("guides"), Format::Html), PathBuf::from("guides.html"));
+        assert_eq!(
+            content_disposition(&filename(path, Format::Markdown)),
+            "attachment; filename=\"setup.md\""
+        );
+        assert_eq!(bundle_filename(Path::new("guides")), PathBuf::from("guides.zip"));
+        assert_eq!(bundle_filename(Path::new("")), PathBuf::from("wiki.zip"));
+        assert_eq!(
+            content_disposition(Path::new("a\"b.md")),
+            "attachment; filename=\"a_b.md\""
+        );
+    }
+
+    #[test]
+    fn zip_documents() {
+        let documents = vec![
+            (PathBuf::from("guides/a.html"), String::from("First")),
+            (PathBuf::from("guides/b/c.html"), String::from("Second")),
+        ];
+        let data = bundle(documents, Format::Markdown).unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(data)).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut content = String::new();
+        archive
+            .by_name("guides/b/c.md")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "Second");
+    }
+}
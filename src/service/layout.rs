@@ -0,0 +1,128 @@
+//! Details shared by every HTML page the wiki renders
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::assets::Assets;
+use crate::i18n::Messages;
+use crate::templates::{Templates, TERA};
+use crate::theme::Theme;
+
+/// What the base template needs to know about a request: where the
+/// wiki's own routes live and who, if anyone, is logged in
+#[derive(Clone)]
+pub struct Layout {
+    /// The name of the wiki, shown in the header and page titles
+    pub site_name: String,
+    /// The logo shown in the header instead of the site name, as a
+    /// path relative to /_static/
+    pub logo: Option<String>,
+    /// Text shown at the bottom of every page
+    pub footer: Option<String>,
+    /// The URL path the wiki is served under, ending with "/"
+    pub base: String,
+    /// Prefix of the wiki's own routes
+    pub reserved: String,
+    /// The user logged in to the wiki, if any
+    pub user: Option<String>,
+    /// True if users can sign up and log in
+    pub accounts: bool,
+    /// True if readers can search documents
+    pub search: bool,
+    /// True if the logged in user has a watchlist
+    pub watchlist: bool,
+    /// True if the logged in user can manage API tokens
+    pub api_tokens: bool,
+    /// True if search engines should not index the page
+    pub noindex: bool,
+    /// Whether the logged in user watches the page's document, None
+    /// on pages which are not for a document
+    pub watching: Option<bool>,
+    /// True if the page shows an unpublished draft of its document,
+    /// which the reader may publish
+    pub draft: bool,
+    /// True if the reader may move the page's document to the trash,
+    /// which needs them to be logged in with write access
+    pub deletable: bool,
+    /// The token forms must include, if the user is logged in with a
+    /// session cookie
+    pub csrf_token: Option<String>,
+    /// Templates overriding the built-in ones, if any
+    pub templates: Option<Arc<Templates>>,
+    /// The theme chosen by the reader, or the default theme
+    pub theme: Option<Arc<Theme>>,
+    /// The names of the themes readers may choose between
+    pub themes: Vec<String>,
+    /// The locale the page is shown in
+    pub locale: String,
+    /// The UI messages for the locale
+    pub messages: Arc<Messages>,
+    /// The locales readers may choose between, empty if the UI is
+    /// only available in one
+    pub locales: Vec<String>,
+    /// The sidebar's HTML, on pages which show it
+    pub sidebar: Option<String>,
+    /// Fingerprints of the built-in static files
+    pub assets: Arc<Assets>,
+}
+
+impl Layout {
+    /// Adds the layout details to a template context
+    pub fn extend(&self, ctx: &mut serde_json::Value) {
+        ctx["site_name"] = json!(self.site_name);
+        ctx["logo"] = json!(self.logo);
+        ctx["footer"] = json!(self.footer);
+        ctx["base"] = json!(self.base);
+        ctx["reserved"] = json!(self.reserved);
+        ctx["user"] = json!(self.user);
+        ctx["accounts"] = json!(self.accounts);
+        ctx["search"] = json!(self.search);
+        ctx["watchlist"] = json!(self.watchlist);
+        ctx["api_tokens"] = json!(self.api_tokens);
+        ctx["noindex"] = json!(self.noindex);
+        ctx["watchable"] = json!(self.watching.is_some());
+        ctx["watching"] = json!(self.watching == Some(true));
+        ctx["draft"] = json!(self.draft);
+        ctx["deletable"] = json!(self.deletable);
+        ctx["csrf_token"] = json!(self.csrf_token);
+        ctx["theme"] = json!(self.theme.as_ref().map(|theme| theme.name()));
+        ctx["themes"] = json!(self.themes);
+        ctx["locale"] = json!(self.locale);
+        ctx["locales"] = json!(self.locales);
+        ctx["t"] = json!(*self.messages);
+        ctx["sidebar"] = json!(self.sidebar);
+        ctx["assets"] = json!(self.asset_urls());
+    }
+
+    /// Returns the message called 'key' in the page's locale, or the
+    /// key itself if no catalogue includes it
+    pub fn message(&self, key: &str) -> String {
+        self.messages
+            .get(key)
+            .cloned()
+            .unwrap_or_else(|| String::from(key))
+    }
+
+    /// Returns the fingerprinted URL of each static file, keyed by
+    /// its path under /_static/, for templates to link to as
+    /// `{{ assets | get(key="css/main.css") }}`, since Tera reads the
+    /// dots in `assets["css/main.css"]` as nested lookups. Files the
+    /// theme overrides use the theme's fingerprints.
+    pub fn asset_urls(&self) -> BTreeMap<String, String> {
+        let prefix = format!("{}{}static/", self.base, self.reserved);
+        let mut urls = self.assets.urls(&prefix);
+        if let Some(ref theme) = self.theme {
+            urls.extend(theme.assets().urls(&prefix));
+        }
+        urls
+    }
+
+    /// Renders the named template, using the theme's templates or
+    /// the overriding templates if there are any
+    pub fn render(&self, template: &str, ctx: &serde_json::Value) -> tera::Result<String> {
+        let theme = self.theme.as_ref().and_then(|theme| theme.templates());
+        match theme.or(self.templates.as_deref()) {
+            Some(templates) => templates.render(template, ctx),
+            None => TERA.render(template, ctx),
+        }
+    }
+}
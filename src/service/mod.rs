@@ -20,30 +20,155 @@
 //!     .map_err(|err| eprintln!("Server error: {}", err));
 //! ```
 
-use futures::future::{self, Future, FutureResult};
+use chrono::{DateTime, Utc};
+use futures::future::{self, Either, Future, FutureResult, Loop};
 use futures::sink::Sink;
-use futures::stream::Stream;
-use http::StatusCode;
+use futures::stream::{self, Stream};
+use http::header::{
+    HeaderValue, ACCEPT_LANGUAGE, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH,
+    CONTENT_SECURITY_POLICY, CONTENT_TYPE, ETAG, HOST, IF_NONE_MATCH, LOCATION, REFERER,
+    SET_COOKIE, VARY, WWW_AUTHENTICATE, X_CONTENT_TYPE_OPTIONS,
+};
+use http::{Method, StatusCode, Uri};
 use hyper::body::Body;
 use hyper::service::{NewService, Service};
-use hyper::{Request, Response};
+use hyper::{Chunk, Request, Response};
 use hyper_staticfile::{self, resolve};
-use std::path::PathBuf;
+use rand::{self, Rng};
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{field, info_span};
+use tracing_futures::Instrument;
+use tungstenite::protocol::frame::coding::CloseCode;
 
-use session::message::{message_stream, ConnectedMessage, MessageStreamError, ServerMessage};
-use session::DocumentSessionManager;
-use store::{Store, StoreError};
-use templates::TERA;
-use websocket::websocket_text;
+use crate::acl::{Acl, Permission};
+use crate::assets::Assets;
+use crate::auth::{Authenticator, BasicAuth, User, SESSION_COOKIE};
+use crate::document::{Document, Event, Operation, ParticipantId, Role};
+use crate::history::{diff_lines, edit_operations, revisions, size_delta};
+use crate::i18n::{Locales, DEFAULT_LOCALE, LOCALE_COOKIE};
+use crate::ldap::{Directory, LdapError};
+use crate::markup::{self, document_title};
+use crate::notify::{Frequency, Watchlists};
+use crate::oidc::OidcProvider;
+use crate::quota::Quotas;
+use crate::robots::CrawlPolicy;
+use crate::search::{Query, Results, SearchBackend};
+use crate::session::feed::feed;
+use crate::session::message::{
+    message_stream, multiplexed_message_stream, ErrorCode, ErrorMessage, MessageStreamError,
+    ServerMessage, DEFAULT_MAX_MESSAGE_SIZE,
+};
+use crate::session::multiplex::Multiplexer;
+use crate::session::playback::{self, playback};
+use crate::session::DocumentSessionManager;
+use crate::slug::{percent_encode, SlugPolicy};
+use crate::store::{Store, StoreError};
+use crate::templates::Templates;
+use crate::theme::{Theme, THEME_COOKIE};
+use crate::tokens::{is_api_token, ApiToken, ApiTokens, Scope, TokenError, AUDIT_TARGET};
+use crate::users::{AccountError, Accounts};
+use crate::websocket::{self, websocket_frames, CLOSE_KICKED, CLOSE_TOO_SLOW};
 
+mod attachments;
+mod breadcrumbs;
+pub mod cors;
+mod csrf;
+mod embed;
 mod error;
+mod export;
+mod layout;
+mod outbound;
+mod pages;
+pub mod ratelimit;
 mod request;
+mod sidebar;
+pub mod tls;
 mod upgrade;
 
-use service::error::{HttpError, TamaWikiError};
-use service::request::query_params;
-use service::upgrade::{is_websocket_upgrade_request, websocket_upgrade};
-use store::SequenceId;
+use crate::service::attachments::{
+    attachment_path, content_type as attachment_content_type, inline as attachment_inline,
+    save as save_attachment, DEFAULT_MAX_ATTACHMENT_SIZE,
+};
+use crate::service::breadcrumbs::{breadcrumbs, Breadcrumb};
+use crate::service::cors::Cors;
+use crate::service::csrf::CsrfCheck;
+use crate::service::error::{HttpError, TamaWikiError};
+use crate::service::layout::Layout;
+use crate::service::outbound::{Outbound, OUTBOUND_CAPACITY};
+use crate::service::pages::PageCache;
+use crate::service::ratelimit::RateLimiter;
+use crate::service::request::{
+    accepts_json, basic_credentials, cookie, credentials, etag_matches, query_params, read_body,
+};
+use crate::service::sidebar::Sidebar;
+use crate::service::upgrade::{is_websocket_upgrade_request, websocket_upgrade};
+use crate::store::SequenceId;
+
+// The number of revisions listed on each page of a document's history
+const HISTORY_PAGE_SIZE: usize = 50;
+
+// The number of edits listed on the recent changes page
+const RECENT_CHANGES: u64 = 100;
+
+// The largest chunk of a response body sent at once, in bytes
+const BODY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The default prefix of the wiki's own routes, such as /_static/.
+/// Documents cannot be created at paths starting with the prefix.
+pub const DEFAULT_RESERVED_PREFIX: &str = "_";
+
+/// The default name of the page a directory's URL shows, so
+/// /guides/ shows the document at guides/index.
+pub const DEFAULT_DIRECTORY_INDEX: &str = "index";
+
+// The cookie holding the state and nonce of an OpenID Connect login
+const OIDC_COOKIE: &str = "tamawiki_oidc";
+
+// How long users have to log in with the OpenID Connect provider
+const OIDC_LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// How long browsers may cache static files requested with their
+// fingerprint, which changes with their content
+const FINGERPRINTED_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+// The site name used unless one is configured
+const DEFAULT_SITE_NAME: &str = "TamaWiki";
+
+// How long a reader's choice of theme or locale is remembered, in
+// seconds
+const PREFERENCE_COOKIE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+// The longest chain of redirect pages followed before giving up
+const MAX_REDIRECTS: usize = 10;
+
+// Header asking search engines not to index a response
+const X_ROBOTS_TAG: &str = "x-robots-tag";
+
+// The revision of a document a reader may see, and whether it is a
+// draft they may publish
+type VisibleRevision = Box<
+    dyn Future<Item = (Result<(SequenceId, Document), StoreError>, bool), Error = HttpError> + Send,
+>;
+
+// A change an editor makes to a whole document rather than its
+// content
+#[derive(Clone, Copy)]
+enum DocumentAction {
+    // Moves the document to the trash
+    Delete,
+    // Restores the document from the trash
+    Restore,
+    // Publishes the revision at a SequenceId
+    Publish(SequenceId),
+}
 
 /// Handles a request and returns a response
 #[derive(Clone)]
@@ -55,144 +180,2708 @@ pub struct TamaWiki<T: Store + Sync> {
     static_path: PathBuf,
     // Co-ordinates document access for editors
     document_sessions: DocumentSessionManager<T>,
+    // Resolves credentials to users, when None websocket connections
+    // are anonymous
+    authenticator: Option<Arc<dyn Authenticator>>,
+    // Usernames and passwords required for every request, when None
+    // the wiki is public
+    basic_auth: Option<Arc<BasicAuth>>,
+    // The largest message accepted from websocket clients, in bytes
+    max_message_size: usize,
+    // Directory to store uploaded attachments in, when None uploads
+    // are disabled
+    attachments_path: Option<PathBuf>,
+    // The largest attachment accepted, in bytes
+    max_attachment_size: usize,
+    // Maps request paths to document paths
+    slugs: SlugPolicy,
+    // The URL path the wiki is served under, starting and ending
+    // with "/"
+    base_path: String,
+    // The URL readers reach the wiki at, ending with "/", for links
+    // which must be absolute. When None they start with the base path.
+    public_url: Option<String>,
+    // Paths starting with "/" followed by this prefix are the wiki's
+    // own routes rather than documents
+    reserved_prefix: String,
+    // The name of the document a directory's URL redirects to, if
+    // the directory has one
+    directory_index: String,
+    // Which cross-origin requests are allowed, when None browsers
+    // only allow pages from the wiki itself to read its responses
+    cors: Option<Cors>,
+    // Accounts users can sign up for and log in to, when None the
+    // signup and login pages are disabled
+    accounts: Option<Accounts>,
+    // Provider users can log in with instead of a password
+    oidc: Option<OidcProvider>,
+    // Directory checking the passwords users log in with, when None
+    // only local accounts are used
+    ldap: Option<Directory>,
+    // Limits who may view and edit documents, when None every
+    // document is open to everyone
+    acl: Option<Arc<Acl>>,
+    // Limits how often each client may make changes or connect to
+    // documents, when None there is no limit
+    rate_limit: Option<RateLimiter>,
+    // Address of the client connected to this instance, if known
+    remote_addr: Option<IpAddr>,
+    // Templates overriding the built-in ones, if any
+    templates: Option<Arc<Templates>>,
+    // Themes readers may choose between
+    themes: Vec<Arc<Theme>>,
+    // The theme for readers who have not chosen one, when None the
+    // built-in templates and static files are used
+    default_theme: Option<String>,
+    // The locales the UI can be shown in
+    locales: Locales,
+    // The name shown in the header and page titles
+    site_name: String,
+    // Path of the logo under /_static/, if any
+    logo: Option<String>,
+    // Text shown at the bottom of every page, if any
+    footer: Option<String>,
+    // The page shown beside every document as site navigation, if
+    // any
+    sidebar: Option<Sidebar>,
+    // Fingerprints of the files under /_static/
+    assets: Arc<Assets>,
+    // Recently rendered document pages
+    pages: PageCache,
+    // The pages each user watches, when None the watchlist page is
+    // disabled
+    watchlists: Option<Watchlists>,
+    // Answers queries from the search page, when None searching is
+    // disabled
+    search: Option<Arc<dyn SearchBackend>>,
+    // Tokens machine clients may authenticate with, when None the
+    // tokens page is disabled
+    api_tokens: Option<ApiTokens>,
+    // How much each user may contribute, when None there is no limit
+    quotas: Option<Quotas>,
+    // Whether changes are drafts, which only users who may edit a
+    // document see until they are published
+    drafts: bool,
+    // What search engines may crawl and index
+    crawl: Arc<CrawlPolicy>,
 }
 
-impl<T: Store + Sync> TamaWiki<T> {
-    /// Creates a new instace of TamaWiki
-    pub fn new<P: Into<PathBuf>>(store: T, static_path: P) -> Self {
-        let document_sessions = DocumentSessionManager::new(store.clone());
-        Self {
-            static_path: static_path.into(),
-            document_sessions,
-            store,
+impl<T: Store + Sync> TamaWiki<T> {
+    /// Creates a new instace of TamaWiki
+    pub fn new<P: Into<PathBuf>>(store: T, static_path: P) -> Self {
+        let document_sessions = DocumentSessionManager::new(store.clone());
+        let static_path = static_path.into();
+        // without static files there is nothing to fingerprint
+        let assets = Assets::from_dir(static_path.join("_static")).unwrap_or_default();
+        Self {
+            static_path,
+            document_sessions,
+            store,
+            authenticator: None,
+            basic_auth: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            attachments_path: None,
+            max_attachment_size: DEFAULT_MAX_ATTACHMENT_SIZE,
+            slugs: SlugPolicy::default(),
+            base_path: String::from("/"),
+            public_url: None,
+            reserved_prefix: String::from(DEFAULT_RESERVED_PREFIX),
+            directory_index: String::from(DEFAULT_DIRECTORY_INDEX),
+            cors: None,
+            accounts: None,
+            oidc: None,
+            ldap: None,
+            acl: None,
+            rate_limit: None,
+            remote_addr: None,
+            templates: None,
+            themes: Vec::new(),
+            default_theme: None,
+            locales: Locales::new(),
+            site_name: String::from(DEFAULT_SITE_NAME),
+            logo: None,
+            footer: None,
+            sidebar: None,
+            assets: Arc::new(assets),
+            pages: PageCache::default(),
+            watchlists: None,
+            search: None,
+            api_tokens: None,
+            quotas: None,
+            drafts: false,
+            crawl: Arc::new(CrawlPolicy::default()),
+        }
+    }
+
+    /// Sets the largest message, in bytes, accepted from websocket
+    /// clients. Clients sending larger messages are disconnected.
+    pub fn with_max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = bytes;
+        self
+    }
+
+    /// Accepts file uploads to /_attachments/<path>, stored under
+    /// the 'path' directory and served from the same URL. Documents
+    /// can embed them using `[[attachment:<path>]]`. The ACL applies
+    /// to attachments as it does to documents at the same path.
+    pub fn with_attachments<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.attachments_path = Some(path.into());
+        self
+    }
+
+    /// Sets the largest attachment, in bytes, which may be uploaded.
+    /// Larger uploads are refused with 413 Payload Too Large.
+    pub fn with_max_attachment_size(mut self, bytes: usize) -> Self {
+        self.max_attachment_size = bytes;
+        self
+    }
+
+    /// Sets how request paths are mapped to documents. Paths are
+    /// normalized the same way for page views, edits and websocket
+    /// connections, so every spelling the policy allows finds the
+    /// same document.
+    pub fn with_slug_policy(mut self, slugs: SlugPolicy) -> Self {
+        self.slugs = slugs;
+        self
+    }
+
+    /// Sets the name of the page a directory shows, see
+    /// DEFAULT_DIRECTORY_INDEX. URLs ending with a slash, and paths
+    /// with no document but documents under them, redirect to the
+    /// directory's index page if it has one, and list the documents
+    /// under them otherwise.
+    pub fn with_directory_index<S: Into<String>>(mut self, name: S) -> Self {
+        self.directory_index = name.into();
+        self
+    }
+
+    /// Renders pages using 'templates', which override the built-in
+    /// templates of the same name, so deployments can customise the
+    /// UI without recompiling
+    pub fn with_templates(mut self, templates: Templates) -> Self {
+        self.templates = Some(Arc::new(templates));
+        self
+    }
+
+    /// Lets readers choose 'theme', storing their choice in a cookie.
+    /// A theme's templates and static files take the place of the
+    /// built-in ones, including any set using `with_templates`.
+    pub fn with_theme(mut self, theme: Theme) -> Self {
+        self.themes.push(Arc::new(theme));
+        self
+    }
+
+    /// Uses the theme called 'name' for readers who have not chosen
+    /// one. The theme must be added using `with_theme`.
+    pub fn with_default_theme<S: Into<String>>(mut self, name: S) -> Self {
+        self.default_theme = Some(name.into());
+        self
+    }
+
+    /// Sets the name of the wiki, shown in the header and the title
+    /// of every page
+    pub fn with_site_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.site_name = name.into();
+        self
+    }
+
+    /// Shows the image at 'path', relative to /_static/, in the
+    /// header instead of the site name
+    pub fn with_logo<S: Into<String>>(mut self, path: S) -> Self {
+        self.logo = Some(path.into().trim_start_matches('/').to_string());
+        self
+    }
+
+    /// Shows 'text' at the bottom of every page, for example a
+    /// copyright or licence notice
+    pub fn with_footer<S: Into<String>>(mut self, text: S) -> Self {
+        self.footer = Some(text.into());
+        self
+    }
+
+    /// Shows the content of the document at 'path', for example
+    /// "sidebar.html", beside every document as site navigation.
+    /// Editing the document changes the sidebar on every page.
+    pub fn with_sidebar<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.sidebar = Some(Sidebar::new(path));
+        self
+    }
+
+    /// Shows the UI in the locales from 'locales', chosen by each
+    /// reader or negotiated using their Accept-Language header.
+    /// Without it the UI is only available in English.
+    pub fn with_locales(mut self, locales: Locales) -> Self {
+        self.locales = locales;
+        self
+    }
+
+    /// Serves the whole wiki under the URL path 'base', for example
+    /// "/wiki/" when a reverse proxy forwards requests for /wiki/* to
+    /// it. Requests for paths outside the base path are not found,
+    /// and links, redirects and cookies all include it.
+    pub fn with_base_path<S: Into<String>>(mut self, base: S) -> Self {
+        let base = base.into();
+        let base = base.trim_matches('/');
+        self.base_path = if base.is_empty() {
+            String::from("/")
+        } else {
+            format!("/{}/", base)
+        };
+        self
+    }
+
+    /// Sets the URL readers reach the wiki at, including the base
+    /// path, for example "https://example.com/wiki/". Links which
+    /// must be absolute, such as those in the Atom feed of recent
+    /// changes, start with it.
+    pub fn with_public_url<S: Into<String>>(mut self, url: S) -> Self {
+        let mut url = url.into();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        self.public_url = Some(url);
+        self
+    }
+
+    /// Serves the wiki's own routes, such as static files and the
+    /// recent changes page, under "/<prefix>" instead of "/_". For
+    /// example, "-/" serves static files from /-/static/. Paths
+    /// starting with the prefix are never documents, so choose one
+    /// which page titles will not start with. The prefix must not be
+    /// empty.
+    pub fn with_reserved_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.reserved_prefix = prefix.into();
+        self
+    }
+
+    /// Allows pages on other sites to read the wiki's responses,
+    /// including the event and recent changes feeds, as permitted by
+    /// 'cors'. Preflight requests are answered automatically.
+    pub fn with_cors(mut self, cors: Cors) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    /// Requires websocket connections to present a bearer token or
+    /// session cookie which 'authenticator' resolves to a User. The
+    /// User's id is recorded when they join a document, and their
+    /// Role limits which changes they can make. Connections without
+    /// valid credentials are refused with 401 Unauthorized.
+    pub fn with_authenticator<A: Authenticator>(mut self, authenticator: A) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Requires every request, including websocket connections, to
+    /// present a username and password from 'auth' using HTTP Basic
+    /// authentication. Only the health check is public. Unless an
+    /// Authenticator is also configured, participants join documents
+    /// as editors on behalf of their username.
+    pub fn with_basic_auth(mut self, auth: BasicAuth) -> Self {
+        self.basic_auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Lets users sign up at /_signup and log in at /_login, using
+    /// 'accounts'. Logging in sets a signed session cookie, which
+    /// identifies the user in the Join events of documents they
+    /// edit. This replaces any Authenticator, so editing requires
+    /// logging in, while pages can still be read anonymously.
+    /// Requests made with the session cookie which change anything
+    /// must include the session's CSRF token, which pages include in
+    /// their forms and a `csrf-token` meta tag.
+    pub fn with_accounts(mut self, accounts: Accounts) -> Self {
+        self.authenticator = Some(Arc::new(accounts.clone()));
+        self.accounts = Some(accounts);
+        self
+    }
+
+    /// Checks the usernames and passwords users log in with at
+    /// /_login against 'directory'. The first time they log in, an
+    /// account is created for them without a local password. Local
+    /// accounts are only tried for users the directory does not know,
+    /// and /_signup refuses usernames it does know. While the
+    /// directory cannot be reached, neither is possible. Sessions are
+    /// held by the Accounts given to `with_accounts`, which is
+    /// required.
+    pub fn with_ldap(mut self, directory: Directory) -> Self {
+        self.ldap = Some(directory);
+        self
+    }
+
+    /// Lets users log in with an OpenID Connect provider by visiting
+    /// /_oidc/login. The first time they log in, an account is
+    /// created named after the username claim in their ID token,
+    /// unless a local account already has the name. Sessions are held
+    /// by the Accounts given to `with_accounts`, which is required.
+    pub fn with_oidc(mut self, provider: OidcProvider) -> Self {
+        self.oidc = Some(provider);
+        self
+    }
+
+    /// Lets logged in users watch documents from their pages, and
+    /// manage the pages they watch and how often they are emailed
+    /// about changes at /_watchlist. Emails are sent by a
+    /// `notify::Notifier` sharing the same Watchlists. Requires the
+    /// Accounts given to `with_accounts`.
+    pub fn with_watchlists(mut self, watchlists: Watchlists) -> Self {
+        self.watchlists = Some(watchlists);
+        self
+    }
+
+    /// Serves /robots.txt and asks search engines not to index
+    /// documents according to 'policy'
+    pub fn with_crawl_policy(mut self, policy: CrawlPolicy) -> Self {
+        self.crawl = Arc::new(policy);
+        self
+    }
+
+    /// Lets logged in users create and revoke API tokens at
+    /// /_tokens. Scripts present a token as a bearer token, on REST
+    /// requests and websocket upgrades, to act on behalf of its owner
+    /// within the token's scope. Requests made with a token count
+    /// against its own rate limit and are logged to the
+    /// `tamawiki::audit` target. Requires the Accounts given to
+    /// `with_accounts`.
+    pub fn with_api_tokens(mut self, tokens: ApiTokens) -> Self {
+        self.api_tokens = Some(tokens);
+        self
+    }
+
+    /// Limits how many bytes each authenticated user may add to
+    /// documents and upload as attachments. Changes which would take
+    /// a user over their quota are refused with 413 Payload Too Large
+    /// and a QuotaExceeded code, or a QuotaExceeded error message for
+    /// websocket edits. Anonymous users may no longer upload
+    /// attachments. Users can see their usage at /_quota.
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.document_sessions.set_quotas(quotas.clone());
+        self.quotas = Some(quotas);
+        self
+    }
+
+    /// Keeps changes to documents as drafts until an editor publishes
+    /// them with a POST to ?action=publish. Only logged in users who
+    /// may edit a document see its drafts, its history, its live
+    /// changes and its edits in /_changes, other readers are shown
+    /// the last published revision and documents which have never
+    /// been published are not found. The `search::Indexer` and
+    /// `notify::Notifier` need their own `with_drafts` so that search
+    /// and emails only show published revisions.
+    pub fn with_drafts(mut self) -> Self {
+        self.drafts = true;
+        self
+    }
+
+    /// Lets readers search documents at /_search using 'backend',
+    /// which should be kept up to date by a `search::Indexer`.
+    /// Documents readers may not view are left out of the results.
+    pub fn with_search<S: SearchBackend>(mut self, backend: S) -> Self {
+        self.search = Some(Arc::new(backend));
+        self
+    }
+
+    /// Checks 'acl' before every page view, edit and websocket join,
+    /// responding with 403 Forbidden to users it does not allow.
+    /// Users who may view a document but not edit it join its
+    /// session as readers, and documents they may not view are left
+    /// out of the recent changes.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Limits how often each client address, and each logged in
+    /// user, may save documents, make other POST requests and open
+    /// websocket connections. Clients over the limit are refused with
+    /// 429 Too Many Requests and a Retry-After header. Client
+    /// addresses are only known if set using `with_remote_addr`.
+    pub fn with_rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limit = Some(limiter);
+        self
+    }
+
+    /// Sets the address of the client this instance serves, for rate
+    /// limiting. Servers should call this on a clone of the wiki for
+    /// each connection, for example using hyper's `make_service_fn`.
+    pub fn with_remote_addr(mut self, addr: SocketAddr) -> Self {
+        self.remote_addr = Some(addr.ip());
+        self
+    }
+
+    /// Notifies connected editors that the server is shutting down.
+    /// Their pending edits are written to the store before each
+    /// websocket connection is closed.
+    pub fn shutdown(&self) {
+        self.document_sessions.shutdown();
+    }
+
+    /// Disconnects a participant from the document at 'path'.
+    /// Returns false if the document has no connected editors.
+    pub fn kick(&self, path: &Path, id: ParticipantId) -> bool {
+        self.document_sessions.kick(path, id)
+    }
+
+    /// Reads and renders the content of up to 'documents' of the most
+    /// recently changed documents, so readers arriving just after a
+    /// restart don't all wait for documents to be rebuilt from their
+    /// history at once. Resolves to the number of documents rendered.
+    pub fn warm_up(
+        &self,
+        documents: usize,
+    ) -> Box<dyn Future<Item = usize, Error = StoreError> + Send> {
+        let store = self.store.clone();
+        let pages = self.pages.clone();
+        let attachments = attachments_url(&self.base_path, &self.reserved_prefix);
+        let mut seen = HashSet::new();
+        let warmed = self
+            .store
+            .changes()
+            .map(|change| change.path)
+            .filter(move |path| seen.insert(path.clone()))
+            .take(documents as u64)
+            .and_then(move |path| {
+                store.content(&path).then(|result| match result {
+                    Ok((seq, doc)) => Ok(Some((path, seq, doc))),
+                    // documents which can't be read are rendered
+                    // when requested, as usual
+                    Err(StoreError::ConnectionError) => Err(StoreError::ConnectionError),
+                    Err(_) => Ok(None),
+                })
+            }).fold(0, move |warmed, found| -> Result<_, StoreError> {
+                Ok(match found {
+                    Some((path, seq, doc)) => {
+                        pages.content(&path, seq, || markup::render(&doc.content, &attachments));
+                        warmed + 1
+                    }
+                    None => warmed,
+                })
+            });
+        Box::new(warmed)
+    }
+
+    fn serve_document(
+        &mut self,
+        req: Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let path = match self.document_path(req.uri().path()) {
+            Ok(path) => path,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let user = self.request_user(&req);
+        if !self.allows(&user, &path, Permission::Read) {
+            return Box::new(future::err(HttpError::Forbidden));
+        }
+        let q = query_params(&req);
+        // readers who may not see drafts only see the published
+        // revision, not the history or editor which would show them
+        if !self.sees_drafts(&user, &path) {
+            let revisions = match q.get("action").map(|a| a.as_str()) {
+                Some("history") | Some("diff") | Some("export") | Some("edit") => true,
+                _ => q.contains_key("rev"),
+            };
+            if revisions {
+                return Box::new(future::err(HttpError::Forbidden));
+            }
+        }
+        match q.get("action").map(|a| a.as_str()) {
+            Some("history") => return self.serve_history(path, &q, self.layout(&req)),
+            Some("diff") => return self.serve_diff(path, &q, self.layout(&req)),
+            Some("restore") => return self.restore_revision(req, path, &q),
+            Some("delete") => return self.delete_document(req, path, &q),
+            Some("publish") => return self.publish_document(req, path, &q),
+            Some("export") => return self.serve_export(path, &q, user, self.layout(&req)),
+            _ => (),
+        }
+        if let Some(rev) = q.get("rev") {
+            return self.serve_revision(path, rev, self.layout(&req));
+        }
+        let edit = match q.get("action") {
+            Some(value) => value == "edit",
+            _ => false,
+        };
+        // programs asking for JSON get the document's content and
+        // participants instead of the view page
+        let json = !edit && accepts_json(&req);
+        // ?redirect=no shows a redirect page instead of following it
+        let follow_redirect = !edit && q.get("redirect").map(|r| r.as_str()) != Some("no");
+        let if_none_match = req
+            .headers()
+            .get(IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let slugs = self.slugs.clone();
+        let layout = self.layout(&req);
+        let pages = self.pages.clone();
+        // redirects are followed as the reader sees the pages
+        let redirects = self.clone();
+        let redirect_user = user.clone();
+        // an active editing session has the document to hand
+        let wiki = self.clone();
+        let reader = user.clone();
+        let doc_path = path.clone();
+        let content = self
+            .document_sessions
+            .content(path.as_path())
+            .then(|result| -> Result<_, HttpError> { Ok(result) })
+            .and_then(move |result| wiki.visible_revision(&reader, &doc_path, result));
+        // pages link to the document's ancestors
+        let crumbs = if json {
+            Either::A(future::ok(Vec::new()))
+        } else {
+            Either::B(
+                breadcrumbs(&self.store, &path)
+                    .map_err(|err| HttpError::InternalServerError(format!("{}", err))),
+            )
+        };
+        // readers only see the sidebar if they may read its page, and
+        // its drafts if they may see them
+        let sidebar = match self.sidebar {
+            Some(ref sidebar) if !json && self.allows(&user, sidebar.path(), Permission::Read) => {
+                let attachments = attachments_url(&layout.base, &layout.reserved);
+                let published = !self.sees_drafts(&user, sidebar.path());
+                Either::B(
+                    sidebar
+                        .render(&self.store, attachments, published)
+                        .map_err(|err| HttpError::InternalServerError(format!("{}", err))),
+                )
+            }
+            _ => Either::A(future::ok(None)),
+        };
+        let deletable = user.is_some() && self.allows(&user, &path, Permission::Write);
+        let watching = match (&self.watchlists, &user) {
+            (&Some(ref watchlists), &Some(ref user)) if layout.watchlist => {
+                Some(watchlists.get(&user.id).watches(&path))
+            }
+            _ => None,
+        };
+        // directories show their index page or the documents under
+        // them instead
+        let directory = if edit || json {
+            Either::A(future::ok(None))
+        } else {
+            let slash = req.uri().path().ends_with('/');
+            Either::B(self.serve_directory(&path, slash, &user, &layout))
+        };
+        let page = content.join3(crumbs, sidebar).and_then(move |(found, crumbs, sidebar)| {
+            let (result, draft) = found;
+            if let Ok((_, ref doc)) = result {
+                // deleted redirects are not followed either
+                let content = if doc.deleted { "" } else { doc.content.as_str() };
+                // redirects to unsafe paths are not followed
+                let target = markup::redirect_target(content)
+                    .and_then(|target| slugs.document_path(target).ok());
+                if let Some(target) = target {
+                    if follow_redirect {
+                        let base = layout.base.clone();
+                        return Either::A(follow_redirects(
+                            redirects,
+                            redirect_user,
+                            base,
+                            path,
+                            target,
+                        ));
+                    }
+                }
+            }
+            Either::B(future::result(if json {
+                document_json(path, if_none_match, result)
+            } else {
+                let mut layout = layout;
+                let sidebar_seq = sidebar.as_ref().map(|&(seq, _)| seq);
+                layout.sidebar = sidebar.map(|(_, html)| html);
+                layout.watching = watching;
+                layout.draft = draft;
+                layout.deletable = deletable;
+                let request = PageRequest {
+                    path,
+                    edit,
+                    breadcrumbs: crumbs,
+                    sidebar_seq,
+                    if_none_match,
+                };
+                document_page(request, &layout, &pages, result)
+            }))
+        });
+        Box::new(directory.and_then(move |found| match found {
+            Some(response) => Either::A(future::ok(response)),
+            None => Either::B(page),
+        }))
+    }
+
+    // Resolves to the response for the directory at 'path', when the
+    // URL ended with a slash or there is no document at 'path': a
+    // redirect to the directory's index page if it has one, or else
+    // a list of the documents under it. Resolves to None if there
+    // are no documents under it either, so the document is served.
+    fn serve_directory(
+        &self,
+        path: &Path,
+        slash: bool,
+        user: &Option<User>,
+        layout: &Layout,
+    ) -> Box<dyn Future<Item = Option<Response<Body>>, Error = HttpError> + Send> {
+        let path = PathBuf::from(path);
+        let index = path.join(&self.directory_index);
+        let exists = if slash {
+            Either::A(future::ok(false))
+        } else {
+            Either::B(document_exists(&self.store, &path))
+        };
+        let store = self.store.clone();
+        let wiki = self.clone();
+        let user = user.clone();
+        let layout = layout.clone();
+        Box::new(exists.and_then(move |exists| {
+            if exists {
+                return Either::A(future::ok(None));
+            }
+            let listed = store
+                .list(&path)
+                .map_err(|err| HttpError::InternalServerError(format!("{}", err)));
+            let crumbs = breadcrumbs(&store, &path)
+                .map_err(|err| HttpError::InternalServerError(format!("{}", err)));
+            let found = document_exists(&store, &index).join3(listed, crumbs);
+            Either::B(found.and_then(move |(has_index, paths, crumbs)| {
+                let index = if has_index { Some(index) } else { None };
+                // readers only see the documents they may read
+                let paths: Vec<_> = paths
+                    .into_iter()
+                    .filter(|path| wiki.allows(&user, path, Permission::Read))
+                    .collect();
+                directory_page(&path, index, &paths, crumbs, &layout)
+            }))
+        }))
+    }
+
+    // Lists the document's revisions, most recent first
+    fn serve_history(
+        &self,
+        path: PathBuf,
+        q: &HashMap<String, String>,
+        layout: Layout,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let page: usize = match q.get("page").map(|page| page.parse()) {
+            Some(Ok(page)) if page > 0 => page,
+            Some(_) => return Box::new(future::err(HttpError::BadRequest)),
+            None => 1,
+        };
+        Box::new(revisions(&self.store, path.as_path()).then(move |result| {
+            let mut revisions = match result {
+                Ok(revisions) => revisions,
+                Err(StoreError::NotFound) => return Err(HttpError::NotFound),
+                Err(err) => return Err(HttpError::InternalServerError(format!("{}", err))),
+            };
+            revisions.reverse();
+            let start = cmp::min((page - 1) * HISTORY_PAGE_SIZE, revisions.len());
+            let end = cmp::min(start + HISTORY_PAGE_SIZE, revisions.len());
+            let ctx = json!({
+                "title": layout.message("history"),
+                "revisions": &revisions[start..end],
+                "prev_page": if page > 1 { Some(page - 1) } else { None },
+                "next_page": if end < revisions.len() { Some(page + 1) } else { None },
+            });
+            render("history.html", &layout, ctx)
+        }))
+    }
+
+    // Responds with the document as a file to download, in the format
+    // named by ?format=markdown (the default) or ?format=html. With
+    // ?bundle=zip, every document under the path which the user may
+    // view is exported into a zip archive instead.
+    fn serve_export(
+        &self,
+        path: PathBuf,
+        q: &HashMap<String, String>,
+        user: Option<User>,
+        layout: Layout,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let format = match q.get("format").map(|format| export::Format::parse(format)) {
+            Some(Some(format)) => format,
+            Some(None) => return Box::new(future::err(HttpError::BadRequest)),
+            None => export::Format::Markdown,
+        };
+        match q.get("bundle").map(|bundle| bundle.as_str()) {
+            Some("zip") => return self.serve_export_bundle(path, format, user, layout),
+            Some(_) => return Box::new(future::err(HttpError::BadRequest)),
+            None => (),
+        }
+        Box::new(self.store.content(path.as_path()).then(
+            move |result| -> Result<Response<Body>, HttpError> {
+                let (seq, doc) = result.map_err(revision_error)?;
+                if doc.deleted {
+                    return deleted_page(&path, &doc.content, seq, &[], &layout);
+                }
+                let body = export::render(&layout, &path, doc.content, format)
+                    .map_err(HttpError::InternalServerError)?;
+                let filename = export::filename(&path, format);
+                Ok(download(format.content_type(), &filename, Arc::new(body)))
+            },
+        ))
+    }
+
+    // Exports the documents under 'prefix' which the user may view
+    // into a zip archive. Readers who may not see a document's drafts
+    // get its last published revision, and documents which have never
+    // been published or are in the trash are left out.
+    fn serve_export_bundle(
+        &self,
+        prefix: PathBuf,
+        format: export::Format,
+        user: Option<User>,
+        layout: Layout,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let store = self.store.clone();
+        let wiki = self.clone();
+        let reader = user.clone();
+        let under_prefix = prefix.clone();
+        let paths = self
+            .store
+            .changes()
+            .filter(move |change| {
+                change.path.starts_with(&under_prefix)
+                    && wiki.allows(&reader, &change.path, Permission::Read)
+            }).map(|change| change.path)
+            .collect()
+            .map_err(|err| HttpError::InternalServerError(format!("{}", err)));
+        let wiki = self.clone();
+        let documents = paths.and_then(move |paths| {
+            // each document appears once per event in the changes
+            let paths: BTreeSet<PathBuf> = paths.into_iter().collect();
+            if paths.is_empty() {
+                return Either::A(future::err(HttpError::NotFound));
+            }
+            let contents = paths.into_iter().map(move |path| {
+                let wiki = wiki.clone();
+                let user = user.clone();
+                store.content(path.as_path()).then(move |result| {
+                    wiki.visible_revision(&user, &path, result)
+                        .and_then(move |(revision, _)| match revision {
+                            Ok((_, ref doc)) if doc.deleted => Ok(None),
+                            Ok((_, doc)) => Ok(Some((path, doc))),
+                            Err(StoreError::NotFound) => Ok(None),
+                            Err(err) => Err(revision_error(err)),
+                        })
+                })
+            });
+            Either::B(future::join_all(contents))
+        });
+        Box::new(documents.and_then(
+            move |documents| -> Result<Response<Body>, HttpError> {
+                let documents: Vec<_> = documents.into_iter().flatten().collect();
+                if documents.is_empty() {
+                    return Err(HttpError::NotFound);
+                }
+                let rendered = documents
+                    .into_iter()
+                    .map(|(path, doc)| {
+                        let body = export::render(&layout, &path, doc.content, format)?;
+                        Ok((path, body))
+                    }).collect::<Result<Vec<_>, String>>()
+                    .map_err(HttpError::InternalServerError)?;
+                let data = export::bundle(rendered, format)
+                    .map_err(|err| HttpError::InternalServerError(format!("{}", err)))?;
+                let filename = export::bundle_filename(&prefix);
+                // zip archives are written with seeks, so the whole
+                // archive is built before it is sent
+                Ok(download("application/zip", &filename, Arc::new(data)))
+            },
+        ))
+    }
+
+    // Shows the changes made to the document between two revisions
+    fn serve_diff(
+        &self,
+        path: PathBuf,
+        q: &HashMap<String, String>,
+        layout: Layout,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let seq = |name: &str| q.get(name).and_then(|x| x.parse::<SequenceId>().ok());
+        let (from, to) = match (seq("from"), seq("to")) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Box::new(future::err(HttpError::BadRequest)),
+        };
+        // revisions of documents in the trash are not shown either
+        let deleted = self.store.deleted(path.as_path());
+        let old = self.store.content_at(path.as_path(), from);
+        let new = self.store.content_at(path.as_path(), to);
+        Box::new(deleted.join3(old, new).then(move |result| {
+            let (deleted, old, new) = result.map_err(revision_error)?;
+            if deleted {
+                return deleted_page(&path, &new.content, to, &[], &layout);
+            }
+            let ctx = json!({
+                "title": layout.message("changes"),
+                "from": from,
+                "to": to,
+                "lines": diff_lines(&old.content, &new.content),
+            });
+            render("diff.html", &layout, ctx)
+        }))
+    }
+
+    // Shows the document as it was at revision 'rev', read-only
+    fn serve_revision(
+        &self,
+        path: PathBuf,
+        rev: &str,
+        layout: Layout,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let rev: SequenceId = match rev.parse() {
+            Ok(rev) => rev,
+            Err(_) => return Box::new(future::err(HttpError::BadRequest)),
+        };
+        // revisions of documents in the trash are not shown either
+        let deleted = self.store.deleted(path.as_path());
+        let revision = self.store.content_at(path.as_path(), rev);
+        Box::new(deleted.join(revision).then(move |result| {
+            let (deleted, doc) = result.map_err(revision_error)?;
+            if deleted {
+                return deleted_page(&path, &doc.content, rev, &[], &layout);
+            }
+            let attachments = attachments_url(&layout.base, &layout.reserved);
+            let ctx = json!({
+                "title": document_title(&path, &doc.content),
+                "content_html": markup::render(&doc.content, &attachments),
+                "content": doc.content,
+                "seq": rev
+            });
+            render("revision.html", &layout, ctx)
+        }))
+    }
+
+    // Appends an Edit which returns the document's content to how it
+    // was at revision 'rev', then redirects to the document
+    fn restore_revision(
+        &self,
+        req: Request<Body>,
+        path: PathBuf,
+        q: &HashMap<String, String>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        if *req.method() != Method::POST {
+            return Box::new(future::err(HttpError::MethodNotAllowed));
+        }
+        let rev: SequenceId = match q.get("rev").and_then(|rev| rev.parse().ok()) {
+            Some(rev) => rev,
+            None => return Box::new(future::err(HttpError::BadRequest)),
+        };
+        let q = q.clone();
+        let wiki = self.clone();
+        let csrf = CsrfCheck::new(&self.accounts, &req);
+        let user = self.authenticate(&req);
+        let body = read_body(req.into_body(), self.max_message_size);
+        Box::new(user.join(body).and_then(move |(user, body)| -> Result<_, HttpError> {
+            let form: HashMap<String, String> =
+                serde_urlencoded::from_bytes(&body).map_err(|_| HttpError::BadRequest)?;
+            csrf.verify(Some(&form))?;
+            Ok(user)
+        }).and_then(move |user| {
+            let current = wiki.store.content(path.as_path());
+            let old = wiki.store.content_at(path.as_path(), rev);
+            current
+                .join(old)
+                .map_err(revision_error)
+                .and_then(move |((head, current), old)| {
+                    let operations = edit_operations(&current.content, &old.content);
+                    let role = participant_role(&q, &user);
+                    wiki.save_edit(path, head, operations, role, user)
+                })
+        }))
+    }
+
+    // Moves the document to the trash, then redirects to it. Only
+    // logged in users with write access may delete documents.
+    fn delete_document(
+        &self,
+        req: Request<Body>,
+        path: PathBuf,
+        q: &HashMap<String, String>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        if *req.method() != Method::POST {
+            return Box::new(future::err(HttpError::MethodNotAllowed));
+        }
+        let q = q.clone();
+        let wiki = self.clone();
+        let csrf = CsrfCheck::new(&self.accounts, &req);
+        let user = self.authenticate(&req);
+        let body = read_body(req.into_body(), self.max_message_size);
+        Box::new(user.join(body).and_then(move |(user, body)| -> Result<_, HttpError> {
+            let form: HashMap<String, String> =
+                serde_urlencoded::from_bytes(&body).map_err(|_| HttpError::BadRequest)?;
+            csrf.verify(Some(&form))?;
+            // anonymous users may not delete, even where they may edit
+            match user {
+                Some(user) => Ok(user),
+                None => Err(HttpError::Unauthorized),
+            }
+        }).and_then(move |user| {
+            let user = Some(user);
+            let role = participant_role(&q, &user);
+            wiki.save_action(path, DocumentAction::Delete, role, user)
+        }))
+    }
+
+    // Publishes the revision of the document submitted using a HTML
+    // form, then redirects to it. The form's 'seq' is the revision
+    // the editor saw, so later changes stay drafts.
+    fn publish_document(
+        &self,
+        req: Request<Body>,
+        path: PathBuf,
+        q: &HashMap<String, String>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        if *req.method() != Method::POST {
+            return Box::new(future::err(HttpError::MethodNotAllowed));
+        }
+        let q = q.clone();
+        let wiki = self.clone();
+        let csrf = CsrfCheck::new(&self.accounts, &req);
+        let user = self.authenticate(&req);
+        let body = read_body(req.into_body(), self.max_message_size);
+        Box::new(user.join(body).and_then(move |(user, body)| -> Result<_, HttpError> {
+            let form: HashMap<String, String> =
+                serde_urlencoded::from_bytes(&body).map_err(|_| HttpError::BadRequest)?;
+            csrf.verify(Some(&form))?;
+            let seq = match form.get("seq") {
+                Some(seq) => parse_seq(seq)?,
+                None => return Err(HttpError::BadRequest),
+            };
+            Ok((user, seq))
+        }).and_then(move |(user, seq)| {
+            let role = participant_role(&q, &user);
+            wiki.save_action(path, DocumentAction::Publish(seq), role, user)
+        }))
+    }
+
+    // Restores the document at 'doc_path' from the trash, then
+    // redirects to it. Only administrators may restore documents,
+    // logged in or using an admin API token.
+    fn serve_trash(
+        &self,
+        req: Request<Body>,
+        doc_path: &str,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        if *req.method() != Method::POST {
+            return Box::new(future::err(HttpError::MethodNotAllowed));
+        }
+        let path = match self.document_path(doc_path) {
+            Ok(path) => path,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        match self.api_token(&req) {
+            Ok(Some(ref token)) if token.scope < Scope::Admin => {
+                return Box::new(future::err(HttpError::Forbidden))
+            }
+            Ok(_) => (),
+            Err(err) => return Box::new(future::err(err)),
+        }
+        if let Err(err) = CsrfCheck::new(&self.accounts, &req).verify(None) {
+            return Box::new(future::err(err));
+        }
+        let q = query_params(&req);
+        let wiki = self.clone();
+        Box::new(self.authenticate(&req).and_then(move |user| {
+            // anyone may create an admin API token, so the account
+            // itself must be an administrator
+            let admin = match (&user, &wiki.accounts) {
+                (None, _) => return Either::A(future::err(HttpError::Unauthorized)),
+                (Some(user), Some(accounts)) => accounts.is_admin(&user.id),
+                (Some(_), None) => false,
+            };
+            if !admin {
+                return Either::A(future::err(HttpError::Forbidden));
+            }
+            let role = participant_role(&q, &user);
+            Either::B(wiki.save_action(path, DocumentAction::Restore, role, user))
+        }))
+    }
+
+    // Writes the event for 'action' to the document at 'path', as a
+    // participant who joins the document session as of its latest
+    // event and then leaves. Redirects to the document once the
+    // event is written.
+    fn save_action(
+        &self,
+        path: PathBuf,
+        action: DocumentAction,
+        role: Role,
+        user: Option<User>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        if !self.allows(&user, &path, Permission::Write) {
+            return Box::new(future::err(HttpError::Forbidden));
+        }
+        let location = format!("{}{}", self.base_path, percent_encode(&path.to_string_lossy()));
+        let sessions = self.document_sessions.clone();
+        let user = user.map(|user| user.id);
+        let saved = self.store.seq(&path).and_then(move |head| {
+            sessions
+                .join(path.as_path(), head, role, user)
+                .and_then(move |participant| {
+                    let written = match action {
+                        DocumentAction::Delete => participant.delete(head),
+                        DocumentAction::Restore => participant.restore(head),
+                        DocumentAction::Publish(seq) => participant.publish(seq),
+                    };
+                    // the participant must stay in the session until
+                    // the event is written
+                    written.map(move |result| {
+                        drop(participant);
+                        result
+                    })
+                })
+        });
+        Box::new(saved.then(move |result| match result {
+            Ok(Ok(_)) => Ok(Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, location)
+                .body(Body::empty())
+                .unwrap()),
+            Ok(Err(err)) => Err(HttpError::Json {
+                status: match err.code {
+                    ErrorCode::PermissionDenied => StatusCode::FORBIDDEN,
+                    _ => StatusCode::CONFLICT,
+                },
+                code: match action {
+                    DocumentAction::Delete => "DeleteRejected",
+                    DocumentAction::Restore => "RestoreRejected",
+                    DocumentAction::Publish(_) => "PublishRejected",
+                },
+                reason: err.reason,
+            }),
+            Err(StoreError::NotFound) => Err(HttpError::NotFound),
+            Err(err) => Err(HttpError::InternalServerError(format!("{}", err))),
+        }))
+    }
+
+    // Saves a new version of the document submitted using a HTML
+    // form, for clients which cannot use the editor. The form's
+    // 'seq' is the revision the new 'content' was based on, the edit
+    // is transformed against any changes made since.
+    fn save_document(
+        &self,
+        req: Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let path = match self.document_path(req.uri().path()) {
+            Ok(path) => path,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let q = query_params(&req);
+        let max_size = self.max_message_size;
+        let wiki = self.clone();
+        let csrf = CsrfCheck::new(&self.accounts, &req);
+        let user = self.authenticate(&req);
+        let body = read_body(req.into_body(), max_size);
+        Box::new(user.join(body).and_then(move |(user, body)| -> Result<_, HttpError> {
+            let form: HashMap<String, String> =
+                serde_urlencoded::from_bytes(&body).map_err(|_| HttpError::BadRequest)?;
+            csrf.verify(Some(&form))?;
+            let seq = match form.get("seq") {
+                Some(seq) => parse_seq(seq)?,
+                None => 0,
+            };
+            // browsers submit textarea content with CRLF line endings
+            let content = match form.get("content") {
+                Some(content) => content.replace("\r\n", "\n"),
+                None => return Err(HttpError::BadRequest),
+            };
+            Ok((user, seq, content))
+        }).and_then(move |(user, seq, content)| {
+            wiki.store
+                .content_at(path.as_path(), seq)
+                .then(move |result| match result {
+                    Ok(doc) => Ok(doc),
+                    // the document has not been created yet
+                    Err(StoreError::NotFound) if seq == 0 => Ok(Default::default()),
+                    Err(err) => Err(revision_error(err)),
+                }).and_then(move |base: Document| {
+                    let operations = edit_operations(&base.content, &content);
+                    let role = participant_role(&q, &user);
+                    wiki.save_edit(path, seq, operations, role, user)
+                })
+        }))
+    }
+
+    // Writes an Edit to the document at 'path' as a participant who
+    // joins the document session, makes the edit as of 'parent_seq'
+    // and then leaves, so editors currently in the session see the
+    // change. Redirects to the document once the edit is written.
+    fn save_edit(
+        &self,
+        path: PathBuf,
+        parent_seq: SequenceId,
+        operations: Vec<Operation>,
+        role: Role,
+        user: Option<User>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        if !self.allows(&user, &path, Permission::Write) {
+            return Box::new(future::err(HttpError::Forbidden));
+        }
+        let location = format!("{}{}", self.base_path, percent_encode(&path.to_string_lossy()));
+        let saved: Box<
+            dyn Future<Item = Result<SequenceId, ErrorMessage>, Error = StoreError> + Send,
+        > = if operations.is_empty() {
+            Box::new(future::ok(Ok(parent_seq)))
+        } else {
+            let user = user.map(|user| user.id);
+            let join = self
+                .document_sessions
+                .join(path.as_path(), parent_seq, role, user);
+            Box::new(join.and_then(move |participant| {
+                // the participant must stay in the session until the
+                // edit is written
+                participant.edit(parent_seq, operations).map(move |result| {
+                    drop(participant);
+                    result
+                })
+            }))
+        };
+        Box::new(
+            saved
+                .map_err(|err| HttpError::InternalServerError(format!("{}", err)))
+                .and_then(move |result| match result {
+                    Ok(_) => Ok(Response::builder()
+                        .status(StatusCode::SEE_OTHER)
+                        .header(LOCATION, location)
+                        .body(Body::empty())
+                        .unwrap()),
+                    Err(ErrorMessage {
+                        code: ErrorCode::QuotaExceeded,
+                        reason,
+                    }) => Err(quota_exceeded(reason)),
+                    Err(err) => Err(HttpError::Json {
+                        status: match err.code {
+                            ErrorCode::PermissionDenied => StatusCode::FORBIDDEN,
+                            _ => StatusCode::CONFLICT,
+                        },
+                        code: "EditRejected",
+                        reason: err.reason,
+                    }),
+                }),
+        )
+    }
+
+    // Lists the most recent edits to any document, optionally only
+    // those by a given user or under a given path. Rendered as an
+    // Atom feed when 'atom' is true, or an HTML page otherwise.
+    fn serve_changes(
+        &self,
+        req: &Request<Body>,
+        atom: bool,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let q = query_params(req);
+        // feed readers need absolute links
+        let site_url = self
+            .public_url
+            .clone()
+            .unwrap_or_else(|| self.base_path.clone());
+        let author = q.get("author").cloned().unwrap_or_default();
+        let prefix = q.get("prefix").cloned().unwrap_or_default();
+        let by_user = author.clone();
+        let under_path = PathBuf::from(prefix.trim_start_matches('/'));
+        // documents the user may not view are left out, as are edits
+        // to those whose drafts they may not see
+        let wiki = self.clone();
+        let user = self.request_user(req);
+        let changes = self
+            .store
+            .changes()
+            .filter(move |change| {
+                (by_user.is_empty() || change.user.as_ref() == Some(&by_user))
+                    && change.path.starts_with(&under_path)
+                    && wiki.allows(&user, &change.path, Permission::Read)
+                    && wiki.sees_drafts(&user, &change.path)
+            }).filter_map(|change| {
+                // only edits which changed the content are listed
+                let (author, delta) = match change.event {
+                    Event::Edit(ref edit) => (edit.author, size_delta(&edit.operations)?),
+                    _ => return None,
+                };
+                Some(json!({
+                    "path": change.path,
+                    "seq": change.seq,
+                    "user": change.user,
+                    "author": author,
+                    "size_delta": delta,
+                    "updated": DateTime::<Utc>::from(change.time).to_rfc3339(),
+                }))
+            }).take(RECENT_CHANGES)
+            .collect();
+        let layout = self.layout(req);
+        Box::new(changes.then(move |result| {
+            let changes =
+                result.map_err(|err| HttpError::InternalServerError(format!("{}", err)))?;
+            let updated = match changes.first() {
+                Some(change) => change["updated"].clone(),
+                None => json!(Utc::now().to_rfc3339()),
+            };
+            let ctx = json!({
+                "title": layout.message("recent_changes"),
+                "changes": changes,
+                "author": author,
+                "prefix": prefix,
+                "site_url": site_url,
+                "updated": updated,
+            });
+            if !atom {
+                return render("changes.html", &layout, ctx);
+            }
+            let mut response = render("changes.xml", &layout, ctx)?;
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/atom+xml"),
+            );
+            Ok(response)
+        }))
+    }
+
+    // Shows the documents matching the 'q' parameter, only those under
+    // the 'dir' directory if it is given. Programs asking for JSON get
+    // the results as JSON.
+    fn serve_search(
+        &self,
+        req: &Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let backend = match self.search {
+            Some(ref backend) => backend.clone(),
+            None => return Box::new(future::err(HttpError::NotFound)),
+        };
+        if *req.method() != Method::GET {
+            return Box::new(future::err(HttpError::MethodNotAllowed));
+        }
+        let q = query_params(req);
+        let mut query = Query::new(q.get("q").map_or("", |text| text.trim()));
+        query.directory = q
+            .get("dir")
+            .map(|dir| String::from(dir.trim_matches('/')))
+            .filter(|dir| !dir.is_empty());
+        let json = accepts_json(req);
+        let layout = self.layout(req);
+        let results = if query.text.is_empty() {
+            Either::A(future::ok(Results::default()))
+        } else {
+            Either::B(
+                backend
+                    .search(&query)
+                    .map_err(|err| HttpError::InternalServerError(format!("{}", err))),
+            )
+        };
+        // documents the user may not view are left out
+        let wiki = self.clone();
+        let user = self.request_user(req);
+        Box::new(results.and_then(move |mut results| {
+            let (visible, hidden): (Vec<_>, Vec<_>) = results
+                .hits
+                .drain(..)
+                .partition(|hit| wiki.allows(&user, &hit.path, Permission::Read));
+            results.hits = visible;
+            for hit in hidden {
+                let dir = hit.path.parent().map_or(String::new(), |dir| {
+                    dir.to_string_lossy().into_owned()
+                });
+                if let Some(count) = results.directories.get_mut(&dir) {
+                    *count = count.saturating_sub(1);
+                }
+                results.total = results.total.saturating_sub(1);
+            }
+            results.directories.retain(|_, count| *count > 0);
+            if json {
+                return Ok(Response::builder()
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(json!(results).to_string()))
+                    .unwrap());
+            }
+            let ctx = json!({
+                "title": layout.message("search"),
+                "q": query.text,
+                "dir": query.directory,
+                "results": results,
+            });
+            render("search.html", &layout, ctx)
+        }))
+    }
+
+    // Serves uploaded attachments, and stores new ones sent with a
+    // POST request. Only editors may upload attachments.
+    fn handle_attachment(
+        &self,
+        req: Request<Body>,
+        uri_path: String,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let root = match self.attachments_path {
+            Some(ref root) => root.clone(),
+            None => return Box::new(future::err(HttpError::NotFound)),
+        };
+        // the path is decoded and checked once, so the ACL applies to
+        // exactly the file which is served or saved
+        let path = match self.document_path(&uri_path) {
+            Ok(path) => path,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let file_path = match attachment_path(&root, &path) {
+            Some(file_path) => file_path,
+            None => return Box::new(future::err(HttpError::BadRequest)),
+        };
+        let uri_path = format!("/{}", percent_encode(&path.to_string_lossy()));
+        if *req.method() != Method::POST {
+            // attachments are readable by those who may read the
+            // documents at the same path
+            if !self.allows(&self.request_user(&req), &path, Permission::Read) {
+                return Box::new(future::err(HttpError::Forbidden));
+            }
+            let content_type = attachment_content_type(&uri_path);
+            let inline = attachment_inline(&uri_path);
+            let files_req = match with_path(req, &uri_path) {
+                Ok(files_req) => files_req,
+                Err(err) => return Box::new(future::err(err)),
+            };
+            return Box::new(serve_files(&root, files_req).map(move |mut response| {
+                let ok = response.status() == StatusCode::OK;
+                let headers = response.headers_mut();
+                // uploads must not run scripts in the wiki's origin,
+                // whatever the browser takes them to be
+                headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+                headers.insert(
+                    CONTENT_SECURITY_POLICY,
+                    HeaderValue::from_static("default-src 'none'; sandbox"),
+                );
+                if ok {
+                    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+                    if !inline {
+                        headers.insert(CONTENT_DISPOSITION, HeaderValue::from_static("attachment"));
+                    }
+                }
+                response
+            }));
+        }
+        // uploads are made by scripts, which send the token in a header
+        if let Err(err) = CsrfCheck::new(&self.accounts, &req).verify(None) {
+            return Box::new(future::err(err));
+        }
+        let q = query_params(&req);
+        let user = self.authenticate(&req);
+        let body = read_body(req.into_body(), self.max_attachment_size);
+        let attachments = attachments_url(&self.base_path, &self.reserved_prefix);
+        let location = format!("{}{}", attachments, uri_path);
+        let quotas = self.quotas.clone();
+        let wiki = self.clone();
+        Box::new(user.join(body).and_then(move |(user, data)| {
+            let role = participant_role(&q, &user);
+            if role != Role::Editor {
+                return Either::A(future::err(HttpError::Json {
+                    status: StatusCode::FORBIDDEN,
+                    code: "PermissionDenied",
+                    reason: format!("{:?} may not upload attachments", role),
+                }));
+            }
+            if !wiki.allows(&user, &path, Permission::Write) {
+                return Either::A(future::err(HttpError::Json {
+                    status: StatusCode::FORBIDDEN,
+                    code: "PermissionDenied",
+                    reason: format!("You may not upload attachments to {}", path.display()),
+                }));
+            }
+            // uploads count towards the uploader's quota, so there
+            // must be one
+            let bytes = data.len() as u64;
+            let quota = match (quotas, user) {
+                (Some(quotas), Some(user)) => Some((quotas, user.id)),
+                (Some(_), None) => {
+                    return Either::A(future::err(HttpError::Json {
+                        status: StatusCode::UNAUTHORIZED,
+                        code: "PermissionDenied",
+                        reason: String::from("Log in to upload attachments"),
+                    }))
+                }
+                (None, _) => None,
+            };
+            if let Some((ref quotas, ref user)) = quota {
+                if let Err(err) = quotas.reserve(user, bytes) {
+                    return Either::A(future::err(quota_exceeded(format!("{}", err))));
+                }
+            }
+            Either::B(
+                save_attachment(file_path, data)
+                    .map_err(move |err| {
+                        if let Some((quotas, user)) = quota {
+                            quotas.release(&user, bytes);
+                        }
+                        HttpError::InternalServerError(format!("{}", err))
+                    }).map(move |_| {
+                        Response::builder()
+                            .status(StatusCode::CREATED)
+                            .header(LOCATION, location.as_str())
+                            .body(Body::empty())
+                            .unwrap()
+                    }),
+            )
+        }))
+    }
+
+    // Serves robots.txt for the site's crawl policy
+    fn serve_robots(&self) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let text = self.crawl.robots_txt(&self.base_path, &self.reserved_prefix);
+        Box::new(future::ok(
+            Response::builder()
+                .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from(text))
+                .unwrap(),
+        ))
+    }
+
+    // Reports whether the server is live and ready to serve
+    // documents, for load balancers. Responds with 503 Service
+    // Unavailable if the store cannot be reached or the server is
+    // shutting down.
+    fn serve_health(&self) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let sessions_ready = self.document_sessions.is_ready();
+        Box::new(self.store.seq(Path::new("/_health")).then(move |result| {
+            let store = match result {
+                // any answer from the store shows it is reachable
+                Ok(_) | Err(StoreError::NotFound) => String::from("ok"),
+                Err(err) => format!("{}", err),
+            };
+            let sessions = if sessions_ready { "ok" } else { "unavailable" };
+            let ready = store == "ok" && sessions_ready;
+            let body = json!({
+                "live": true,
+                "ready": ready,
+                "checks": {
+                    "store": store,
+                    "sessions": sessions,
+                }
+            });
+            Ok(Response::builder()
+                .status(if ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }).header(CONTENT_TYPE, "application/json")
+                .header(CACHE_CONTROL, "no-store")
+                .body(Body::from(body.to_string()))
+                .unwrap())
+        }))
+    }
+
+    // Lists the fingerprinted URL of each static file, for tools and
+    // scripts linking to them
+    fn serve_assets(
+        &self,
+        req: &Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let body = json!(self.layout(req).asset_urls());
+        Box::new(future::ok(
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .header(CACHE_CONTROL, "no-cache")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        ))
+    }
+
+    // Reports activity in each open document session, leaving out
+    // documents the requester may not read
+    fn serve_metrics(
+        &self,
+        req: &Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let wiki = self.clone();
+        Box::new(self.authenticate(req).map(move |user| {
+            let sessions: BTreeMap<_, _> = wiki
+                .document_sessions
+                .metrics()
+                .into_iter()
+                .filter(|(path, _)| wiki.allows(&user, path, Permission::Read))
+                .collect();
+            let body = json!({ "sessions": sessions });
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        }))
+    }
+
+    // Resolves the request's credentials to a User. If no
+    // Authenticator is configured, this is the user signed in with
+    // HTTP Basic authentication, if any.
+    fn authenticate(
+        &self,
+        req: &Request<Body>,
+    ) -> Box<dyn Future<Item = Option<User>, Error = HttpError> + Send> {
+        match self.api_token(req) {
+            Ok(Some(token)) => {
+                return Box::new(match self.token_user(&token) {
+                    Some(user) => future::ok(Some(user)),
+                    None => future::err(HttpError::Unauthorized),
+                })
+            }
+            Ok(None) => (),
+            Err(err) => return Box::new(future::err(err)),
+        }
+        let authenticator = match self.authenticator {
+            Some(ref authenticator) => authenticator,
+            None => return Box::new(future::ok(self.basic_auth_user(req))),
+        };
+        match credentials(req) {
+            Some(token) => Box::new(
+                authenticator
+                    .authenticate(&token)
+                    .map_err(|err| HttpError::InternalServerError(format!("{}", err)))
+                    .and_then(|user| match user {
+                        Some(user) => Ok(Some(user)),
+                        None => Err(HttpError::Unauthorized),
+                    }),
+            ),
+            None => Box::new(future::err(HttpError::Unauthorized)),
+        }
+    }
+
+    // Routes the request to its handler, responding with an error
+    // page if it fails
+    fn respond(
+        &mut self,
+        req: Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = TamaWikiError> + Send> {
+        // browsers do not apply CORS to websocket connections
+        let cors = match self.cors {
+            Some(ref cors) if !is_websocket_upgrade_request(&req) => {
+                if let Some(res) = cors.preflight(&req) {
+                    return Box::new(future::ok(res));
+                }
+                cors.allow_origin(&req)
+                    .map(|origin| (cors.clone(), req.method().clone(), origin))
+            }
+            _ => None,
+        };
+        // error pages link to the path as the client requested it
+        let url_path = String::from(req.uri().path());
+        let layout = self.layout(&req);
+        let req = match strip_base_path(req, &self.base_path) {
+            Some(req) => req,
+            None => {
+                let res = HttpError::NotFound.into_response(&url_path, &layout);
+                return Box::new(future::ok(res));
+            }
+        };
+        let path = String::from(req.uri().path());
+        let reserved = self.reserved_prefix.clone();
+        let noindex = self.noindex(&path);
+        let token = match self.check_api_token(&req) {
+            Ok(token) => token,
+            Err(err) => return Box::new(future::ok(err.into_response(&url_path, &layout))),
+        };
+        // API tokens stand in for Basic credentials
+        if self.basic_auth.is_some()
+            && reserved_route(&path, &reserved) != Some("health")
+            && token.is_none()
+            && self.basic_auth_user(&req).is_none()
+        {
+            let mut res = HttpError::Unauthorized.into_response(&url_path, &layout);
+            res.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"TamaWiki\", charset=\"UTF-8\""),
+            );
+            return Box::new(future::ok(res));
+        }
+        let res = if let Err(err) = self.check_rate_limit(&req) {
+            Box::new(future::err(err))
+        } else if path == "/robots.txt" && *req.method() == Method::GET {
+            self.serve_robots()
+        } else if let Some(route) = reserved_route(&path, &reserved) {
+            self.serve_reserved(req, route)
+        } else if is_websocket_upgrade_request(&req) {
+            self.handle_websocket(req)
+        } else if *req.method() == Method::POST && req.uri().query().is_none() {
+            self.save_document(req)
+        } else {
+            self.serve_document(req)
+        };
+        let res = res.then(move |result| -> FutureResult<Response<Body>, TamaWikiError> {
+            match result {
+                Ok(response) => future::ok(response),
+                Err(err) => future::ok(err.into_response(&url_path, &layout)),
+            }
+        });
+        Box::new(res.map(move |mut res| {
+            if let Some((cors, method, origin)) = cors {
+                cors.apply(&method, origin, &mut res);
+            }
+            if noindex {
+                res.headers_mut().insert(X_ROBOTS_TAG, HeaderValue::from_static("noindex"));
+            }
+            res
+        }))
+    }
+
+    // Routes a request for one of the wiki's own pages, 'route' is
+    // the request path following the reserved prefix
+    fn serve_reserved(
+        &self,
+        req: Request<Body>,
+        route: &str,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        if route.starts_with("static/") {
+            let path = &route["static".len()..];
+            // themes only replace the files they include
+            let theme = self.theme(&req);
+            let (static_path, assets) = match theme
+                .as_ref()
+                .and_then(|theme| Some((theme.static_file(path)?, theme.assets())))
+            {
+                Some(found) => found,
+                None => (self.static_path.join("_static"), &*self.assets),
+            };
+            // a file requested with its current fingerprint never
+            // changes, so may be cached for good
+            let fingerprinted = match query_params(&req).get("v") {
+                Some(v) => assets.fingerprint(path) == Some(v.as_str()),
+                None => false,
+            };
+            let response = match with_path(req, path) {
+                Ok(req) => serve_files(&static_path, req),
+                Err(err) => return Box::new(future::err(err)),
+            };
+            Box::new(response.map(move |mut response| {
+                if fingerprinted && response.status() == StatusCode::OK {
+                    response.headers_mut().insert(
+                        CACHE_CONTROL,
+                        HeaderValue::from_static(FINGERPRINTED_CACHE_CONTROL),
+                    );
+                }
+                response
+            }))
+        } else if route == "assets.json" {
+            self.serve_assets(&req)
+        } else if route.starts_with("attachments/") {
+            self.handle_attachment(req, String::from(&route["attachments".len()..]))
+        } else if let Some(rest) = route.strip_prefix("events/") {
+            self.serve_events(req, rest)
+        } else if let Some(rest) = route.strip_prefix("trash/") {
+            self.serve_trash(req, rest)
+        } else if route == "changes" || route == "changes.atom" {
+            self.serve_changes(&req, route == "changes.atom")
+        } else if route == "signup" || route == "login" || route == "logout" {
+            self.serve_account(req, route)
+        } else if route == "search" {
+            self.serve_search(&req)
+        } else if route == "quota" {
+            self.serve_quota(&req)
+        } else if route == "watchlist" {
+            self.serve_watchlist(req)
+        } else if route == "tokens" {
+            self.serve_tokens(req)
+        } else if route == "embed" {
+            self.serve_oembed(&req)
+        } else if let Some(rest) = route.strip_prefix("embed/") {
+            self.serve_embed(&req, rest)
+        } else if route == "oidc/login" || route == "oidc/callback" {
+            self.serve_oidc(req, route)
+        } else if route == "theme" {
+            let themes = self.themes.iter().map(|theme| String::from(theme.name()));
+            self.save_choice(req, "theme", THEME_COOKIE, themes.collect())
+        } else if route == "locale" {
+            self.save_choice(req, "locale", LOCALE_COOKIE, self.locales.names())
+        } else if route == "health" {
+            self.serve_health()
+        } else if route == "metrics" {
+            self.serve_metrics(&req)
+        } else if route == "ws" && is_websocket_upgrade_request(&req) {
+            let wiki = self.clone();
+            Box::new(
+                self.authenticate(&req)
+                    .and_then(move |user| wiki.handle_multiplexed_websocket(req, user)),
+            )
+        } else {
+            // documents are never served under the reserved prefix
+            Box::new(future::err(HttpError::NotFound))
+        }
+    }
+
+    // Serves the signup and login forms. Submitting either form
+    // starts a session for the user, and posting to the logout route
+    // ends it.
+    fn serve_account(
+        &self,
+        req: Request<Body>,
+        route: &str,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let accounts = match self.accounts {
+            Some(ref accounts) => accounts.clone(),
+            None => return Box::new(future::err(HttpError::NotFound)),
+        };
+        let layout = self.layout(&req);
+        let oidc = self.oidc.is_some();
+        if *req.method() == Method::GET && route != "logout" {
+            return Box::new(future::result(account_form(route, &layout, oidc, "", None)));
+        } else if *req.method() != Method::POST {
+            return Box::new(future::err(HttpError::MethodNotAllowed));
+        } else if route == "logout" {
+            let csrf = CsrfCheck::new(&self.accounts, &req);
+            let session = credentials(&req);
+            let body = read_body(req.into_body(), self.max_message_size);
+            return Box::new(body.and_then(move |body| -> Result<_, HttpError> {
+                let form: HashMap<String, String> =
+                    serde_urlencoded::from_bytes(&body).map_err(|_| HttpError::BadRequest)?;
+                csrf.verify(Some(&form))?;
+                // a copy of the cookie kept elsewhere no longer works
+                if let Some(session) = session {
+                    accounts.logout(&session);
+                }
+                let secure = accounts.secure_cookies();
+                Ok(session_response(&layout.base, "", Duration::from_secs(0), secure))
+            }));
+        }
+        let route = String::from(route);
+        let signup = route == "signup";
+        let ldap = self.ldap.clone();
+        let body = read_body(req.into_body(), self.max_message_size);
+        Box::new(body.and_then(move |body| {
+            let form: HashMap<String, String> = match serde_urlencoded::from_bytes(&body) {
+                Ok(form) => form,
+                Err(_) => return Either::A(future::err(HttpError::BadRequest)),
+            };
+            let username = form.get("username").cloned().unwrap_or_default();
+            let password = form.get("password").cloned().unwrap_or_default();
+            let result = match ldap {
+                Some(ref directory) if signup => Either::A(ldap_signup(
+                    directory,
+                    &accounts,
+                    username.clone(),
+                    password,
+                )),
+                Some(ref directory) => Either::A(ldap_login(
+                    directory,
+                    &accounts,
+                    username.clone(),
+                    password,
+                )),
+                _ => Either::B(future::result(if signup {
+                    accounts
+                        .signup(&username, &password)
+                        .and_then(|_| accounts.login(&username, &password))
+                } else {
+                    accounts.login(&username, &password)
+                })),
+            };
+            Either::B(result.then(move |result| -> Result<_, HttpError> {
+                let err = match result {
+                    Ok(token) => {
+                        let max_age = accounts.session_length();
+                        let secure = accounts.secure_cookies();
+                        return Ok(session_response(&layout.base, &token, max_age, secure));
+                    }
+                    Err(err) => err,
+                };
+                let status = match err {
+                    AccountError::Internal => {
+                        return Err(HttpError::InternalServerError(format!("{}", err)))
+                    }
+                    AccountError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+                    AccountError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::BAD_REQUEST,
+                };
+                // show the form again, explaining what went wrong
+                let error = Some(format!("{}", err));
+                let mut response = account_form(&route, &layout, oidc, &username, error)?;
+                *response.status_mut() = status;
+                Ok(response)
+            }))
+        }))
+    }
+
+    // Sends the user to the OpenID Connect provider to log in, and
+    // starts a session for them when the provider sends them back to
+    // the callback route with a code
+    fn serve_oidc(
+        &self,
+        req: Request<Body>,
+        route: &str,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let (oidc, accounts) = match (&self.oidc, &self.accounts) {
+            (&Some(ref oidc), &Some(ref accounts)) => (oidc.clone(), accounts.clone()),
+            _ => return Box::new(future::err(HttpError::NotFound)),
+        };
+        if *req.method() != Method::GET {
+            return Box::new(future::err(HttpError::MethodNotAllowed));
+        }
+        // the state and nonce are only needed by the callback route
+        let cookie_path = format!("{}{}oidc/", self.base_path, self.reserved_prefix);
+        if route == "oidc/login" {
+            let state = random_token();
+            let nonce = random_token();
+            let login = format!(
+                "{}={}.{}; Path={}; HttpOnly; SameSite=Lax; Max-Age={}{}",
+                OIDC_COOKIE,
+                state,
+                nonce,
+                cookie_path,
+                OIDC_LOGIN_TIMEOUT.as_secs(),
+                if accounts.secure_cookies() { "; Secure" } else { "" }
+            );
+            return Box::new(future::ok(
+                Response::builder()
+                    .status(StatusCode::FOUND)
+                    .header(LOCATION, oidc.authorization_url(&state, &nonce).as_str())
+                    .header(SET_COOKIE, login.as_str())
+                    .body(Body::empty())
+                    .unwrap(),
+            ));
+        }
+        let login = cookie(&req, OIDC_COOKIE).unwrap_or_default();
+        let mut parts = login.splitn(2, '.');
+        let (state, nonce) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let q = query_params(&req);
+        // the state proves this browser started the login
+        let code = match (q.get("state"), q.get("code")) {
+            (Some(s), Some(code)) if !state.is_empty() && s == state => code,
+            _ => return Box::new(future::err(HttpError::BadRequest)),
+        };
+        let layout = self.layout(&req);
+        Box::new(oidc.exchange(code, nonce).then(move |result| -> Result<_, HttpError> {
+            let token = match result {
+                Ok(identity) => accounts.login_external(&identity.username, &identity.id),
+                Err(err) => {
+                    warn!("OpenID Connect login failed: {}", err);
+                    Err(AccountError::InvalidCredentials)
+                }
+            };
+            let mut response = match token {
+                Ok(token) => session_response(
+                    &layout.base,
+                    &token,
+                    accounts.session_length(),
+                    accounts.secure_cookies(),
+                ),
+                Err(AccountError::Internal) => {
+                    return Err(HttpError::InternalServerError(format!(
+                        "{}",
+                        AccountError::Internal
+                    )))
+                }
+                Err(err) => {
+                    let error = Some(format!("{}", err));
+                    let mut response = account_form("login", &layout, true, "", error)?;
+                    *response.status_mut() = StatusCode::UNAUTHORIZED;
+                    response
+                }
+            };
+            let expired = format!("{}=; Path={}; HttpOnly; Max-Age=0", OIDC_COOKIE, cookie_path);
+            response
+                .headers_mut()
+                .append(SET_COOKIE, HeaderValue::from_str(&expired).unwrap());
+            Ok(response)
+        }))
+    }
+
+    // Stores the theme or locale the reader chose from the 'field' of
+    // a form in the base template in the cookie 'name', then sends
+    // them back to the page they came from
+    fn save_choice(
+        &self,
+        req: Request<Body>,
+        field: &'static str,
+        name: &'static str,
+        choices: Vec<String>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        if *req.method() != Method::POST {
+            return Box::new(future::err(HttpError::MethodNotAllowed));
+        }
+        let csrf = CsrfCheck::new(&self.accounts, &req);
+        let base = self.base_path.clone();
+        let back = referring_page(&req, &base).unwrap_or_else(|| base.clone());
+        let body = read_body(req.into_body(), self.max_message_size);
+        Box::new(body.and_then(move |body| -> Result<_, HttpError> {
+            let form: HashMap<String, String> =
+                serde_urlencoded::from_bytes(&body).map_err(|_| HttpError::BadRequest)?;
+            csrf.verify(Some(&form))?;
+            let cookie = match form.get(field).map(|choice| choice.as_str()) {
+                // the default, chosen by the wiki
+                None | Some("") => format!("{}=; Path={}; Max-Age=0", name, base),
+                Some(choice) if choices.iter().any(|allowed| allowed == choice) => format!(
+                    "{}={}; Path={}; SameSite=Lax; Max-Age={}",
+                    name, choice, base, PREFERENCE_COOKIE_MAX_AGE
+                ),
+                Some(_) => return Err(HttpError::BadRequest),
+            };
+            Ok(Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, back.as_str())
+                .header(SET_COOKIE, cookie.as_str())
+                .body(Body::empty())
+                .unwrap())
+        }))
+    }
+
+    // Lists the pages the logged in user watches, with their email
+    // settings. Posting the form with an 'action' of "watch" or
+    // "unwatch" changes whether they watch 'page', and "settings"
+    // saves their 'email' and 'frequency'.
+    fn serve_watchlist(
+        &self,
+        req: Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let watchlists = match (&self.watchlists, &self.accounts) {
+            (&Some(ref watchlists), &Some(_)) => watchlists.clone(),
+            _ => return Box::new(future::err(HttpError::NotFound)),
+        };
+        let user = match self.request_user(&req) {
+            Some(user) => user.id,
+            None => return Box::new(future::err(HttpError::Unauthorized)),
+        };
+        let layout = self.layout(&req);
+        if *req.method() == Method::GET {
+            let watcher = watchlists.get(&user);
+            // patterns have no page to link to
+            let pages: Vec<_> = watcher
+                .pages
+                .iter()
+                .map(|page| json!({"page": page, "pattern": page.ends_with('*')}))
+                .collect();
+            let frequencies: Vec<_> = Frequency::ALL
+                .iter()
+                .map(|f| {
+                    let label = layout.message(&format!("frequency_{}", f.name()));
+                    json!({"name": f.name(), "label": label})
+                }).collect();
+            let ctx = json!({
+                "title": layout.message("watchlist"),
+                "pages": pages,
+                "email": watcher.email,
+                "frequency": watcher.frequency.name(),
+                "frequencies": frequencies,
+            });
+            return Box::new(future::result(render("watchlist.html", &layout, ctx)));
+        } else if *req.method() != Method::POST {
+            return Box::new(future::err(HttpError::MethodNotAllowed));
+        }
+        let csrf = CsrfCheck::new(&self.accounts, &req);
+        let acl = self.acl.clone();
+        let watchlist = format!("{}{}watchlist", self.base_path, self.reserved_prefix);
+        // watching from a document's page returns to it
+        let back = referring_page(&req, &self.base_path).unwrap_or_else(|| watchlist.clone());
+        let body = read_body(req.into_body(), self.max_message_size);
+        Box::new(body.and_then(move |body| -> Result<_, HttpError> {
+            let form: HashMap<String, String> =
+                serde_urlencoded::from_bytes(&body).map_err(|_| HttpError::BadRequest)?;
+            csrf.verify(Some(&form))?;
+            let field = |name: &str| form.get(name).map_or("", |value| value.trim());
+            let page = field("page");
+            let location = match field("action") {
+                "watch" | "unwatch" if page.is_empty() => return Err(HttpError::BadRequest),
+                // patterns may match pages the user cannot read, which
+                // they are not emailed about
+                "watch"
+                    if !page.ends_with('*')
+                        && acl.as_ref().is_some_and(|acl| {
+                            !acl.allows(Some(&user), Path::new(page), Permission::Read)
+                        }) =>
+                {
+                    return Err(HttpError::Forbidden)
+                }
+                "watch" => {
+                    watchlists.watch(&user, page);
+                    back
+                }
+                "unwatch" => {
+                    watchlists.unwatch(&user, page);
+                    back
+                }
+                "settings" => {
+                    let email = match field("email") {
+                        "" => None,
+                        email if email.contains('@') => Some(String::from(email)),
+                        _ => return Err(HttpError::BadRequest),
+                    };
+                    let frequency = field("frequency")
+                        .parse()
+                        .map_err(|_| HttpError::BadRequest)?;
+                    watchlists.set_email(&user, email, frequency);
+                    watchlist
+                }
+                _ => return Err(HttpError::BadRequest),
+            };
+            Ok(Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, location.as_str())
+                .body(Body::empty())
+                .unwrap())
+        }))
+    }
+
+    // Answers oEmbed requests for the page at the 'url' query
+    // parameter with an iframe showing the page, or the section named
+    // by the URL's fragment, from /_embed/<path>
+    fn serve_oembed(
+        &self,
+        req: &Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let q = query_params(req);
+        match q.get("format").map(|format| format.as_str()) {
+            Some("json") | None => (),
+            // as the oEmbed spec asks of providers
+            Some(_) => {
+                return Box::new(future::err(HttpError::Json {
+                    status: StatusCode::NOT_IMPLEMENTED,
+                    code: "unsupported_format",
+                    reason: String::from("Only the json format is supported"),
+                }))
+            }
+        }
+        let target = match q.get("url").and_then(|url| embed::parse_url(url, &self.base_path)) {
+            Some(target) => target,
+            None => return Box::new(future::err(HttpError::NotFound)),
+        };
+        // only answer for the wiki's own pages
+        let host = req.headers().get(HOST).and_then(|host| host.to_str().ok());
+        if let (Some(requested), Some(host)) = (target.host(), host) {
+            if !requested.eq_ignore_ascii_case(host) {
+                return Box::new(future::err(HttpError::NotFound));
+            }
+        }
+        let dimension = |name: &str| match q.get(name).map(|value| value.parse()) {
+            Some(Ok(value)) => Ok(Some(value)),
+            Some(Err(_)) => Err(HttpError::BadRequest),
+            None => Ok(None),
+        };
+        let size = match (dimension("maxwidth"), dimension("maxheight")) {
+            (Ok(width), Ok(height)) => embed::size(width, height),
+            _ => return Box::new(future::err(HttpError::BadRequest)),
+        };
+        let path = match self.document_path(&target.path) {
+            Ok(path) => path,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let user = self.request_user(req);
+        if !self.allows(&user, &path, Permission::Read) {
+            return Box::new(future::err(HttpError::Forbidden));
+        }
+        let origin = target.origin.clone().unwrap_or_default();
+        let provider_url = format!("{}{}", origin, self.base_path);
+        let mut src = format!("{}{}embed/{}", provider_url, self.reserved_prefix, target.path);
+        if let Some(ref section) = target.section {
+            src.push('?');
+            src.push_str(&serde_urlencoded::to_string([("section", section)]).unwrap());
+        }
+        let site_name = self.site_name.clone();
+        let wiki = self.clone();
+        let content = self
+            .store
+            .content(&path)
+            .then(|result| -> Result<_, HttpError> { Ok(result) });
+        Box::new(content.and_then(move |result| {
+            wiki.visible_revision(&user, &path, result).and_then(move |(result, _)| {
+                let (_, doc) = result.map_err(revision_error)?;
+                if let Some(ref section) = target.section {
+                    markup::section(&doc.content, section).ok_or(HttpError::NotFound)?;
+                }
+                let title = document_title(&path, &doc.content);
+                let body = embed::response(&site_name, &provider_url, &title, &src, size);
+                Ok(Response::builder()
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap())
+            })
+        }))
+    }
+
+    // Shows the document at 'uri_path', or the section of it named by
+    // the 'section' query parameter, on a page of its own for other
+    // sites to show in an iframe
+    fn serve_embed(
+        &self,
+        req: &Request<Body>,
+        uri_path: &str,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let path = match self.document_path(uri_path) {
+            Ok(path) => path,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let user = self.request_user(req);
+        if !self.allows(&user, &path, Permission::Read) {
+            return Box::new(future::err(HttpError::Forbidden));
+        }
+        let section = query_params(req).get("section").cloned();
+        let url = format!("{}{}", self.base_path, uri_path);
+        let layout = self.layout(req);
+        let wiki = self.clone();
+        let content = self
+            .store
+            .content(&path)
+            .then(|result| -> Result<_, HttpError> { Ok(result) });
+        Box::new(content.and_then(move |result| {
+            wiki.visible_revision(&user, &path, result).and_then(move |(result, _)| {
+                let (_, doc) = result.map_err(revision_error)?;
+                let content = match section {
+                    Some(ref section) => markup::section(&doc.content, section)
+                        .ok_or(HttpError::NotFound)?,
+                    None => markup::body(&doc.content),
+                };
+                let attachments = attachments_url(&layout.base, &layout.reserved);
+                let ctx = json!({
+                    "title": document_title(&path, &doc.content),
+                    "content_html": markup::render(content, &attachments),
+                    "url": url,
+                    "section": section,
+                });
+                render("embed.html", &layout, ctx)
+            })
+        }))
+    }
+
+    // Reports how many bytes the user has contributed, and their
+    // quota, as JSON
+    fn serve_quota(
+        &self,
+        req: &Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let quotas = match self.quotas {
+            Some(ref quotas) => quotas.clone(),
+            None => return Box::new(future::err(HttpError::NotFound)),
+        };
+        if *req.method() != Method::GET {
+            return Box::new(future::err(HttpError::MethodNotAllowed));
+        }
+        Box::new(self.authenticate(req).and_then(move |user| -> Result<_, HttpError> {
+            let user = user.ok_or(HttpError::Unauthorized)?;
+            let usage = quotas.usage(&user.id);
+            let body = json!({
+                "user": user.id,
+                "used": usage.used,
+                "limit": usage.limit,
+            });
+            Ok(Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .header(CACHE_CONTROL, "no-cache")
+                .body(Body::from(body.to_string()))
+                .unwrap())
+        }))
+    }
+
+    // Lists the logged in user's API tokens. Posting the form with an
+    // 'action' of "create" creates a token with the 'name', 'scope'
+    // and optional 'rate_limit' given, showing it once, and "revoke"
+    // revokes the token with 'id'. Admin tokens may manage their
+    // owner's tokens, other tokens may not.
+    fn serve_tokens(
+        &self,
+        req: Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let tokens = match (&self.api_tokens, &self.accounts) {
+            (&Some(ref tokens), &Some(_)) => tokens.clone(),
+            _ => return Box::new(future::err(HttpError::NotFound)),
+        };
+        let owner = match (self.api_token(&req), self.request_user(&req)) {
+            (Ok(Some(ref token)), _) if token.scope < Scope::Admin => {
+                return Box::new(future::err(HttpError::Forbidden))
+            }
+            (_, Some(user)) => user.id,
+            (_, None) => return Box::new(future::err(HttpError::Unauthorized)),
+        };
+        let json = accepts_json(&req);
+        let layout = self.layout(&req);
+        if *req.method() == Method::GET {
+            if json {
+                let listed: Vec<_> = tokens.list(&owner).iter().map(token_json).collect();
+                return Box::new(future::ok(
+                    Response::builder()
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Body::from(json!(listed).to_string()))
+                        .unwrap(),
+                ));
+            }
+            return Box::new(future::result(tokens_page(&tokens, &owner, &layout, None, None)));
+        } else if *req.method() != Method::POST {
+            return Box::new(future::err(HttpError::MethodNotAllowed));
+        }
+        let csrf = CsrfCheck::new(&self.accounts, &req);
+        let location = format!("{}{}tokens", self.base_path, self.reserved_prefix);
+        let body = read_body(req.into_body(), self.max_message_size);
+        Box::new(body.and_then(move |body| -> Result<_, HttpError> {
+            let form: HashMap<String, String> =
+                serde_urlencoded::from_bytes(&body).map_err(|_| HttpError::BadRequest)?;
+            csrf.verify(Some(&form))?;
+            let field = |name: &str| form.get(name).map_or("", |value| value.trim());
+            match field("action") {
+                "create" => {
+                    let scope = field("scope").parse().map_err(|_| HttpError::BadRequest)?;
+                    let rate_limit = match field("rate_limit") {
+                        "" => None,
+                        limit => Some(limit.parse().map_err(|_| HttpError::BadRequest)?),
+                    };
+                    let (token, secret) =
+                        match tokens.create(&owner, field("name"), scope, rate_limit) {
+                            Ok(created) => created,
+                            Err(err @ TokenError::Internal) => {
+                                return Err(HttpError::InternalServerError(format!("{}", err)))
+                            }
+                            Err(err) if json => {
+                                return Err(HttpError::Json {
+                                    status: StatusCode::BAD_REQUEST,
+                                    code: "invalid_token",
+                                    reason: format!("{}", err),
+                                })
+                            }
+                            Err(err) => {
+                                let error = Some(format!("{}", err));
+                                let mut res = tokens_page(&tokens, &owner, &layout, None, error)?;
+                                *res.status_mut() = StatusCode::BAD_REQUEST;
+                                return Ok(res);
+                            }
+                        };
+                    let mut res = if json {
+                        let mut created = token_json(&token);
+                        created["token"] = json!(secret);
+                        Response::builder()
+                            .header(CONTENT_TYPE, "application/json")
+                            .body(Body::from(created.to_string()))
+                            .unwrap()
+                    } else {
+                        tokens_page(&tokens, &owner, &layout, Some(secret), None)?
+                    };
+                    *res.status_mut() = StatusCode::CREATED;
+                    Ok(res)
+                }
+                "revoke" => {
+                    match tokens.revoke(&owner, field("id")) {
+                        Ok(true) => (),
+                        Ok(false) => return Err(HttpError::NotFound),
+                        Err(err) => return Err(HttpError::InternalServerError(format!("{}", err))),
+                    }
+                    let mut res = Response::builder();
+                    if json {
+                        res.status(StatusCode::NO_CONTENT);
+                    } else {
+                        res.status(StatusCode::SEE_OTHER)
+                            .header(LOCATION, location.as_str());
+                    }
+                    Ok(res.body(Body::empty()).unwrap())
+                }
+                _ => Err(HttpError::BadRequest),
+            }
+        }))
+    }
+
+    // The details the base template needs about the request,
+    // including who is logged in
+    fn layout(&self, req: &Request<Body>) -> Layout {
+        let accept_language = req
+            .headers()
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok());
+        let preferred = cookie(req, LOCALE_COOKIE);
+        let (locale, messages) = self
+            .locales
+            .negotiate(preferred.as_deref(), accept_language);
+        let locales = self.locales.names();
+        let user = self.request_user(req);
+        let watchlist = user.is_some() && self.watchlists.is_some() && self.accounts.is_some();
+        let api_tokens = user.is_some() && self.api_tokens.is_some() && self.accounts.is_some();
+        Layout {
+            site_name: self.site_name.clone(),
+            logo: self.logo.clone(),
+            footer: self.footer.clone(),
+            base: self.base_path.clone(),
+            reserved: self.reserved_prefix.clone(),
+            user: user.map(|user| user.id),
+            accounts: self.accounts.is_some(),
+            watchlist,
+            api_tokens,
+            noindex: self.noindex(req.uri().path()),
+            watching: None,
+            draft: false,
+            deletable: false,
+            search: self.search.is_some(),
+            csrf_token: CsrfCheck::new(&self.accounts, req).token(),
+            templates: self.templates.clone(),
+            theme: self.theme(req),
+            themes: self.themes.iter().map(|theme| String::from(theme.name())).collect(),
+            locale,
+            messages,
+            locales: if locales.len() > 1 { locales } else { Vec::new() },
+            sidebar: None,
+            assets: self.assets.clone(),
         }
     }
 
-    fn serve_static(
-        &mut self,
-        req: Request<Body>,
-    ) -> Box<Future<Item = Response<Body>, Error = HttpError> + Send> {
-        // First, resolve the request. Returns a `ResolveFuture` for a `ResolveResult`.
-        let result = resolve(&self.static_path.as_path(), &req)
-            .map_err(|err| {
-                eprintln!("Error serving static file: {}", err);
-                HttpError::InternalServerError(format!("{}", err))
-            }).and_then(|res| {
-                use hyper_staticfile::ResolveResult::*;
-                match res {
-                    // The request was not `GET` or `HEAD` request,
-                    MethodNotMatched => Err(HttpError::MethodNotAllowed),
-                    // The request URI was not just a path.
-                    UriNotMatched => Err(HttpError::BadRequest),
-                    // The requested file does not exist.
-                    NotFound => Err(HttpError::NotFound),
-                    // The requested file could not be accessed.
-                    PermissionDenied => Err(HttpError::Unauthorized),
-                    // A directory was requested as a file.
-                    IsDirectory => Ok(IsDirectory),
-                    // The requested file was found.
-                    Found(file, metadata) => Ok(Found(file, metadata)),
-                }
-            });
+    // The theme the reader chose with the theme cookie, or the
+    // default theme if they have not chosen one
+    fn theme(&self, req: &Request<Body>) -> Option<Arc<Theme>> {
+        let named = |name: &str| {
+            self.themes
+                .iter()
+                .find(|theme| theme.name() == name)
+                .cloned()
+        };
+        cookie(req, THEME_COOKIE)
+            .and_then(|name| named(&name))
+            .or_else(|| self.default_theme.as_ref().and_then(|name| named(name)))
+    }
 
-        // Then, build a response based on the result.
-        // The `ResponseBuilder` is typically a short-lived, per-request instance.
-        Box::new(result.map(move |result| {
-            hyper_staticfile::ResponseBuilder::new()
-                .build(&req, result)
-                .unwrap()
-        }))
+    // Returns the user logged in with a session cookie, API token or
+    // HTTP Basic authentication, if any
+    fn request_user(&self, req: &Request<Body>) -> Option<User> {
+        if let Ok(Some(token)) = self.api_token(req) {
+            return self.token_user(&token);
+        }
+        let user = match self.accounts {
+            Some(ref accounts) => credentials(req).and_then(|token| accounts.session_user(&token)),
+            None => None,
+        };
+        user.or_else(|| self.basic_auth_user(req))
     }
 
-    fn serve_document(
-        &mut self,
-        req: &Request<Body>,
-    ) -> Box<Future<Item = Response<Body>, Error = HttpError> + Send> {
-        let path = PathBuf::from(&req.uri().path()[1..]);
-        let q = query_params(req);
-        let edit = match q.get("action") {
-            Some(value) => value == "edit",
-            _ => false,
+    // Counts a request which changes something or connects to a
+    // document against the rate limits for the client's address and
+    // logged in user
+    fn check_rate_limit(&self, req: &Request<Body>) -> Result<(), HttpError> {
+        let limiter = match self.rate_limit {
+            Some(ref limiter) => limiter,
+            None => return Ok(()),
         };
-        Box::new(
-            self.store
-                .content(&path.as_path())
-                .then(move |result| match result {
-                    Ok((seq, doc)) => {
-                        let ctx = json!({
-                            "title": "Document",
-                            "content": doc.content,
-                            "participants": doc.participants,
-                            "seq": seq
-                        });
-                        let tmpl = if edit { "editor.html" } else { "document.html" };
-                        let text = TERA.render(tmpl, &ctx).unwrap();
-                        Ok(Response::builder().body(Body::from(text)).unwrap())
-                    }
-                    Err(StoreError::NotFound) => {
-                        let text = if edit {
-                            TERA.render(
-                                "editor.html",
-                                &json!({
-                                "title": "Document",
-                                "content": "",
-                                "participants": [],
-                                "seq": 0
-                            }),
-                            ).unwrap()
-                        } else {
-                            TERA.render(
-                                "new_document.html",
-                                &json!({
-                                "title": "Document"
-                            }),
-                            ).unwrap()
-                        };
-                        Ok(Response::builder()
-                            .status(StatusCode::NOT_FOUND)
-                            .body(Body::from(text))
-                            .unwrap())
-                    }
-                    Err(err) => Err(HttpError::InternalServerError(format!("{}", err))),
-                }),
-        )
+        if *req.method() != Method::POST && !is_websocket_upgrade_request(req) {
+            return Ok(());
+        }
+        if let Some(addr) = self.remote_addr {
+            limiter
+                .check(&format!("ip:{}", addr))
+                .map_err(HttpError::TooManyRequests)?;
+        }
+        if let Some(user) = self.request_user(req) {
+            limiter
+                .check(&format!("user:{}", user.id))
+                .map_err(HttpError::TooManyRequests)?;
+        }
+        Ok(())
+    }
+
+    // Returns true if search engines should not index the document
+    // at the URL 'path', or its embeddable page
+    fn noindex(&self, path: &str) -> bool {
+        let path = match reserved_route(path, &self.reserved_prefix) {
+            Some(route) if route.starts_with("embed/") => &route["embed".len()..],
+            Some(_) => return false,
+            None => path,
+        };
+        // unsafe paths are rejected when the request is handled
+        self.slugs
+            .document_path(path)
+            .map(|path| self.crawl.noindex(&path))
+            .unwrap_or(true)
+    }
+
+    // Returns the API token the request was made with, if any. An
+    // unknown or revoked token is unauthorized.
+    fn api_token(&self, req: &Request<Body>) -> Result<Option<ApiToken>, HttpError> {
+        let tokens = match self.api_tokens {
+            Some(ref tokens) => tokens,
+            None => return Ok(None),
+        };
+        match credentials(req) {
+            Some(ref token) if is_api_token(token) => {
+                tokens.verify(token).map(Some).ok_or(HttpError::Unauthorized)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Returns the user requests made with 'token' act as, or None if
+    // its owner no longer has an account
+    fn token_user(&self, token: &ApiToken) -> Option<User> {
+        self.accounts.as_ref().and_then(|accounts| token.user(accounts))
+    }
+
+    // Counts a request made with an API token against the token's
+    // rate limit and records it in the audit log. Read tokens may
+    // not make POST requests.
+    fn check_api_token(&self, req: &Request<Body>) -> Result<Option<ApiToken>, HttpError> {
+        let token = match (self.api_token(req)?, &self.api_tokens) {
+            (Some(token), &Some(ref tokens)) => {
+                tokens
+                    .record_use(&token.id)
+                    .map_err(HttpError::TooManyRequests)?;
+                token
+            }
+            _ => return Ok(None),
+        };
+        info!(
+            target: AUDIT_TARGET,
+            "{} {} by {} with token {} ({}, {})",
+            req.method(),
+            req.uri().path(),
+            token.owner,
+            token.id,
+            token.name,
+            token.scope.name()
+        );
+        if token.scope == Scope::Read && *req.method() == Method::POST {
+            return Err(HttpError::Forbidden);
+        }
+        Ok(Some(token))
+    }
+
+    // Returns the document path for the URL path 'uri_path', a bad
+    // request if it is not safe to use as a store key
+    fn document_path(&self, uri_path: &str) -> Result<PathBuf, HttpError> {
+        let path = self.slugs.document_path(uri_path).map_err(|err| {
+            debug!("Rejected path {:?}: {}", uri_path, err);
+            HttpError::BadRequest
+        })?;
+        // an encoded prefix, e.g. /%5Fstatic/, would otherwise reach
+        // a document the wiki's own routes hide
+        if path.to_string_lossy().starts_with(&self.reserved_prefix) {
+            debug!("Rejected path {:?}: starts with the reserved prefix", uri_path);
+            return Err(HttpError::BadRequest);
+        }
+        Ok(path)
+    }
+
+    // Returns true if the ACL, if any, gives 'user' 'permission' for
+    // the document at 'path'. When changes are drafts, anonymous
+    // users may not edit, as they are not shown the drafts.
+    fn allows(&self, user: &Option<User>, path: &Path, permission: Permission) -> bool {
+        if self.drafts && permission == Permission::Write && user.is_none() {
+            return false;
+        }
+        match self.acl {
+            Some(ref acl) => {
+                let user = user.as_ref().map(|user| user.id.as_str());
+                acl.allows(user, path, permission)
+            }
+            None => true,
+        }
+    }
+
+    // Returns true if 'user' may see drafts of the document at 'path'
+    fn sees_drafts(&self, user: &Option<User>, path: &Path) -> bool {
+        !self.drafts || self.allows(user, path, Permission::Write)
+    }
+
+    // Resolves to the revision of a document 'user' may see, given
+    // the document's latest revision, and whether that is a draft
+    // they may publish. Readers who may not see drafts get the last
+    // published revision instead, and documents which have never
+    // been published are not found.
+    fn visible_revision(
+        &self,
+        user: &Option<User>,
+        path: &Path,
+        result: Result<(SequenceId, Document), StoreError>,
+    ) -> VisibleRevision {
+        let (seq, doc) = match result {
+            Ok(found) => found,
+            Err(err) => return Box::new(future::ok((Err(err), false))),
+        };
+        if !self.drafts || doc.deleted {
+            return Box::new(future::ok((Ok((seq, doc)), false)));
+        }
+        let editor = self.allows(user, path, Permission::Write);
+        let published = match doc.published {
+            Some(published) if published < seq => Either::A(
+                self.store
+                    .content_at(path, published)
+                    .map(move |revision| Some((published, revision.content)))
+                    .map_err(|err| HttpError::InternalServerError(format!("{}", err))),
+            ),
+            Some(published) => Either::B(future::ok(Some((published, doc.content.clone())))),
+            None => Either::B(future::ok(None)),
+        };
+        Box::new(published.map(move |published| match published {
+            Some((_, ref content)) if editor => {
+                let draft = *content != doc.content;
+                (Ok((seq, doc)), draft)
+            }
+            None if editor => (Ok((seq, doc)), true),
+            Some((published, content)) => {
+                let mut doc = doc;
+                doc.content = content;
+                (Ok((published, doc)), false)
+            }
+            None => (Err(StoreError::NotFound), false),
+        }))
+    }
+
+    // Returns the user signed in with valid HTTP Basic credentials
+    fn basic_auth_user(&self, req: &Request<Body>) -> Option<User> {
+        let auth = self.basic_auth.as_ref()?;
+        match basic_credentials(req) {
+            Some((id, password)) => if auth.verify(&id, &password) {
+                Some(User {
+                    id,
+                    role: Role::Editor,
+                })
+            } else {
+                None
+            },
+            None => None,
+        }
     }
 
     fn handle_websocket(
         &self,
         req: Request<Body>,
-    ) -> Box<Future<Item = Response<Body>, Error = HttpError> + Send> {
-        let path = PathBuf::from(&req.uri().path()[1..]);
-        let q = query_params(&req);
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let path = match self.document_path(req.uri().path()) {
+            Ok(path) => path,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let since = match requested_seq(&req) {
+            Ok(seq) => seq,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let wiki = self.clone();
+        Box::new(self.authenticate(&req).and_then(move |user| {
+            // live changes would show readers the drafts
+            if !wiki.allows(&user, &path, Permission::Read) || !wiki.sees_drafts(&user, &path) {
+                return Either::A(future::err(HttpError::Forbidden));
+            }
+            Either::B(wiki.check_seq(&path, since).and_then(move |_| {
+                wiki.handle_authenticated_websocket(req, path, since, user)
+            }))
+        }))
+    }
 
-        let since: SequenceId = match q.get("seq") {
-            // TODO: respond with http error message if seq parameter is invalid
-            Some(x) => x.parse().unwrap_or(0),
-            None => 0,
+    // Streams the document's events to the client as Server-Sent
+    // Events. This is read-only, the client does not join the
+    // document session.
+    fn serve_events(
+        &self,
+        req: Request<Body>,
+        doc_path: &str,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let path = match self.document_path(doc_path) {
+            Ok(path) => path,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        // browsers reconnecting after an error resume from the id of
+        // the last event they received
+        let since = match req.headers().get("last-event-id") {
+            Some(id) => parse_seq(id.to_str().unwrap_or("")),
+            None => requested_seq(&req),
         };
+        let since = match since {
+            Ok(seq) => seq,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let wiki = self.clone();
+        Box::new(self.authenticate(&req).and_then(move |user| {
+            // live changes would show readers the drafts
+            if !wiki.allows(&user, &path, Permission::Read) || !wiki.sees_drafts(&user, &path) {
+                return Either::A(future::err(HttpError::Forbidden));
+            }
+            Either::B(wiki.check_seq(&path, since).map(move |_| {
+                let events = feed(&wiki.store, &path, since).map(|msg| {
+                    format!("id: {}\ndata: {}\n\n", msg.seq, serde_json::to_string(&msg).unwrap())
+                });
+                Response::builder()
+                    .header(CONTENT_TYPE, "text/event-stream")
+                    .header(CACHE_CONTROL, "no-cache")
+                    .body(Body::wrap_stream(events))
+                    .unwrap()
+            }))
+        }))
+    }
+
+    // Checks the document has reached 'since', so clients cannot
+    // wait for events from a future SequenceId. Documents which do
+    // not exist yet are at SequenceId 0.
+    fn check_seq(
+        &self,
+        path: &Path,
+        since: SequenceId,
+    ) -> Box<dyn Future<Item = (), Error = HttpError> + Send> {
+        Box::new(self.store.seq(path).then(move |result| {
+            let head = match result {
+                Ok(head) => head,
+                Err(StoreError::NotFound) => 0,
+                Err(err) => return Err(HttpError::InternalServerError(format!("{}", err))),
+            };
+            if since > head {
+                return Err(HttpError::Json {
+                    status: StatusCode::CONFLICT,
+                    code: "InvalidSeq",
+                    reason: format!("seq {} is ahead of the document (at {})", since, head),
+                });
+            }
+            Ok(())
+        }))
+    }
+
+    fn handle_authenticated_websocket(
+        &self,
+        req: Request<Body>,
+        path: PathBuf,
+        since: SequenceId,
+        user: Option<User>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let q = query_params(&req);
+
+        // spectators replaying the document's history do not join
+        // the document session
+        if let Some(speed) = q.get("playback") {
+            let speed = speed.parse().unwrap_or(playback::DEFAULT_SPEED);
+            return self.handle_playback(req, path, since, speed);
+        }
+
+        let mut role = participant_role(&q, &user);
+        if !self.allows(&user, &path, Permission::Write) {
+            role = cmp::max(role, Role::Reader);
+        }
+        let user = user.map(|user| user.id);
 
         let document_sessions = self.document_sessions.clone();
+        let max_message_size = self.max_message_size;
 
-        websocket_upgrade(req, move |websocket| {
+        websocket_upgrade(req, move |websocket, protocol| {
+            let close = websocket.close_handle();
+            let join_failed = close.clone();
             document_sessions
-                .join(&path.as_path(), since)
-                .map_err(|e| {
-                    eprintln!("Error joining document session: {:?}", e);
+                .join(path.as_path(), since, role, user.clone())
+                .map_err(move |e| {
+                    error!("Error joining document session: {:?}", e);
+                    join_failed.set(CloseCode::Error, "Could not join document");
                 }).and_then(move |participant| {
-                    let id = participant.get_id();
-                    let ws = message_stream(websocket_text(websocket));
+                    let connected = participant.connected();
+                    let resync = participant.resync_handle();
+                    let frames = websocket_frames(websocket, protocol.encoding.is_binary());
+                    let ws = message_stream(frames, protocol, max_message_size);
                     let (wtx, wrx) = ws.split();
                     let (ptx, prx) = participant.split();
 
+                    // the participant's message stream ends after these
+                    // messages, which closes the websocket
+                    let closing = close.clone();
+                    let prx = prx.inspect(move |msg| match msg {
+                        ServerMessage::Shutdown => {
+                            closing.set(CloseCode::Away, "Server shutting down")
+                        }
+                        ServerMessage::Kicked => {
+                            closing.set(CloseCode::Library(CLOSE_KICKED), "Removed from document")
+                        }
+                        _ => (),
+                    });
+
                     let send_client_msgs = wrx.forward(ptx).map(|_| ());
-                    let send_server_msgs = wtx
-                        .send(ServerMessage::Connected(ConnectedMessage { id }))
-                        .and_then(|wtx| wtx.send_all(prx).map(|_| ()));
+                    let send_server_msgs =
+                        wtx.send(ServerMessage::Connected(connected)).and_then(move |wtx| {
+                            let mut outbound = Outbound::new(prx, wtx, OUTBOUND_CAPACITY);
+                            // clients which fall behind are resynced
+                            // if they can be, otherwise disconnected
+                            if protocol.supports_resync() {
+                                outbound = outbound.with_resync(resync);
+                            }
+                            outbound.map(|_| ())
+                        });
 
+                    // Both halves of the websocket and participant are
+                    // dropped once either side finishes, which closes
+                    // the connection and sends the participant's Leave
+                    // event however the connection ended.
                     send_client_msgs.select(send_server_msgs).then(
                         move |result: Result<_, (MessageStreamError, _)>| {
                             if let Err((err, _)) = result {
-                                eprintln!("WebSocket error: {:?}", err);
+                                warn!(target: websocket::LOG_TARGET, "WebSocket error: {:?}", err);
+                                if let Some((code, reason)) = close_reason(&err) {
+                                    close.set(code, reason);
+                                }
                             }
                             Ok(())
                         },
@@ -200,6 +2889,807 @@ impl<T: Store + Sync> TamaWiki<T> {
                 })
         })
     }
+
+    // Handles websocket connections to /_ws, which may subscribe to
+    // several documents
+    fn handle_multiplexed_websocket(
+        &self,
+        req: Request<Body>,
+        user: Option<User>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let role = participant_role(&query_params(&req), &user);
+        let user = user.map(|user| user.id);
+        let document_sessions = self.document_sessions.clone();
+        let max_message_size = self.max_message_size;
+        let slugs = self.slugs.clone();
+        let acl = self.acl.clone();
+        let drafts = self.drafts;
+
+        websocket_upgrade(req, move |websocket, protocol| {
+            let close = websocket.close_handle();
+            let frames = websocket_frames(websocket, protocol.encoding.is_binary());
+            let ws = multiplexed_message_stream(frames, protocol, max_message_size);
+            let (wtx, wrx) = ws.split();
+            let mut multiplexer = Multiplexer::new(document_sessions.clone(), role, user.clone())
+                .with_slug_policy(slugs.clone());
+            if let Some(ref acl) = acl {
+                multiplexer = multiplexer.with_acl(acl.clone());
+            }
+            if drafts {
+                multiplexer = multiplexer.with_drafts();
+            }
+            let resync = multiplexer.resync_handle();
+            let (mtx, mrx) = multiplexer.split();
+
+            // the multiplexer's stream ends once the server shuts down
+            let closing = close.clone();
+            let mrx = mrx.inspect(move |msg| {
+                if let ServerMessage::Shutdown = msg.message {
+                    closing.set(CloseCode::Away, "Server shutting down")
+                }
+            });
+
+            let send_client_msgs = wrx.forward(mtx).map(|_| ());
+            let mut outbound = Outbound::new(mrx, wtx, OUTBOUND_CAPACITY);
+            if protocol.supports_resync() {
+                outbound = outbound.with_resync(resync);
+            }
+            let send_server_msgs = outbound.map(|_| ());
+
+            send_client_msgs.select(send_server_msgs).then(
+                move |result: Result<_, (MessageStreamError, _)>| {
+                    if let Err((err, _)) = result {
+                        warn!(target: websocket::LOG_TARGET, "WebSocket error: {:?}", err);
+                        if let Some((code, reason)) = close_reason(&err) {
+                            close.set(code, reason);
+                        }
+                    }
+                    Ok(())
+                },
+            )
+        })
+    }
+
+    fn handle_playback(
+        &self,
+        req: Request<Body>,
+        path: PathBuf,
+        since: SequenceId,
+        speed: u32,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+        let store = self.store.clone();
+        let max_message_size = self.max_message_size;
+
+        websocket_upgrade(req, move |websocket, protocol| {
+            let frames = websocket_frames(websocket, protocol.encoding.is_binary());
+            let ws = message_stream(frames, protocol, max_message_size);
+            ws.send_all(playback(&store, path.as_path(), since, speed))
+                .map(|_| ())
+                .map_err(|err| warn!(target: websocket::LOG_TARGET, "WebSocket error: {:?}", err))
+        })
+    }
+}
+
+// Returns the rest of 'path' if it starts with the 'reserved' prefix
+fn reserved_route<'a>(path: &'a str, reserved: &str) -> Option<&'a str> {
+    if path.starts_with('/') && path[1..].starts_with(reserved) {
+        Some(&path[1 + reserved.len()..])
+    } else {
+        None
+    }
+}
+
+// Returns the URL prefix attachments are served from
+fn attachments_url(base: &str, reserved: &str) -> String {
+    format!("{}{}attachments", base, reserved)
+}
+
+// Removes the URL path the wiki is served under from the request's
+// URL, so routes are matched as if the wiki were served from "/".
+// Returns None if the URL is outside the base path.
+fn strip_base_path(req: Request<Body>, base: &str) -> Option<Request<Body>> {
+    if base == "/" {
+        return Some(req);
+    }
+    let rest = {
+        let path = req.uri().path();
+        if let Some(path) = path.strip_prefix(base) {
+            String::from(path)
+        } else if path == base.trim_end_matches('/') {
+            String::new()
+        } else {
+            return None;
+        }
+    };
+    let uri = match req.uri().query() {
+        Some(query) => format!("/{}?{}", rest, query),
+        None => format!("/{}", rest),
+    };
+    let (mut parts, body) = req.into_parts();
+    parts.uri = uri.parse().ok()?;
+    Some(Request::from_parts(parts, body))
+}
+
+// Replaces the path of the request's URL with 'path', keeping its
+// method and headers
+fn with_path(req: Request<Body>, path: &str) -> Result<Request<Body>, HttpError> {
+    let (mut parts, body) = req.into_parts();
+    parts.uri = path.parse().map_err(|_| HttpError::BadRequest)?;
+    Ok(Request::from_parts(parts, body))
+}
+
+// Serves the file under 'root' at the request's path
+fn serve_files(
+    root: &Path,
+    req: Request<Body>,
+) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+    // First, resolve the request. Returns a `ResolveFuture` for a `ResolveResult`.
+    let result = resolve(root, &req)
+        .map_err(|err| {
+            error!("Error serving static file: {}", err);
+            HttpError::InternalServerError(format!("{}", err))
+        }).and_then(|res| {
+            use hyper_staticfile::ResolveResult::*;
+            match res {
+                // The request was not `GET` or `HEAD` request,
+                MethodNotMatched => Err(HttpError::MethodNotAllowed),
+                // The request URI was not just a path.
+                UriNotMatched => Err(HttpError::BadRequest),
+                // The requested file does not exist.
+                NotFound => Err(HttpError::NotFound),
+                // The requested file could not be accessed.
+                PermissionDenied => Err(HttpError::Unauthorized),
+                // A directory was requested as a file.
+                IsDirectory => Ok(IsDirectory),
+                // The requested file was found.
+                Found(file, metadata) => Ok(Found(file, metadata)),
+            }
+        });
+
+    // Then, build a response based on the result.
+    // The `ResponseBuilder` is typically a short-lived, per-request instance.
+    Box::new(result.map(move |result| {
+        hyper_staticfile::ResponseBuilder::new()
+            .build(&req, result)
+            .unwrap()
+    }))
+}
+
+// The document a page is requested for and how it is shown
+struct PageRequest {
+    path: PathBuf,
+    // Shows the editor rather than the document
+    edit: bool,
+    breadcrumbs: Vec<Breadcrumb>,
+    // The revision of the sidebar shown on the page, if it has one
+    sidebar_seq: Option<SequenceId>,
+    // The request's If-None-Match header
+    if_none_match: Option<String>,
+}
+
+// Renders the view or editor page for the requested document, or
+// responds with 304 Not Modified if the client's cached copy of the
+// page matches its If-None-Match header. Pages showing the sidebar
+// are cached until the sidebar's revision changes.
+fn document_page(
+    request: PageRequest,
+    layout: &Layout,
+    pages: &PageCache,
+    result: Result<(SequenceId, Document), StoreError>,
+) -> Result<Response<Body>, HttpError> {
+    let PageRequest {
+        path,
+        edit,
+        breadcrumbs,
+        sidebar_seq,
+        if_none_match,
+    } = request;
+    match result {
+        // deleted documents are not shown or edited until restored
+        Ok((seq, ref doc)) if doc.deleted => {
+            deleted_page(&path, &doc.content, seq, &breadcrumbs, layout)
+        }
+        Ok((seq, doc)) => {
+            let mut variant = String::from(if edit { "edit" } else { "view" });
+            // pages are shown in the reader's language
+            if layout.locale != DEFAULT_LOCALE {
+                variant = format!("{}-{}", variant, layout.locale);
+            }
+            // and change when the sidebar does
+            if let Some(sidebar_seq) = sidebar_seq {
+                variant = format!("{}-sidebar{}", variant, sidebar_seq);
+            }
+            // or the reader starts watching the document
+            if layout.watching == Some(true) {
+                variant = format!("{}-watching", variant);
+            }
+            // or may delete it
+            if layout.deletable {
+                variant = format!("{}-deletable", variant);
+            }
+            let etag = document_etag(&path, seq, &variant);
+            if let Some(response) = not_modified(&if_none_match, &etag) {
+                return Ok(response);
+            }
+            let attachments = attachments_url(&layout.base, &layout.reserved);
+            let tmpl = if edit { "editor.html" } else { "document.html" };
+            let render_page = || {
+                let content_html = pages.content(&path, seq, || {
+                    markup::render(&doc.content, &attachments)
+                });
+                let ctx = json!({
+                    "title": document_title(&path, &doc.content),
+                    "path": path,
+                    "breadcrumbs": breadcrumbs,
+                    "content_html": content_html.as_str(),
+                    "content": doc.content,
+                    "participants": doc.participants,
+                    "seq": seq
+                });
+                render_text(tmpl, layout, ctx)
+            };
+            // pages for logged in users include their name and CSRF
+            // token, so only pages for anonymous readers are shared
+            let html = if layout.user.is_none() && layout.csrf_token.is_none() {
+                let theme = layout.theme.as_ref().map_or("", |theme| theme.name());
+                let crumbs: String = breadcrumbs
+                    .iter()
+                    .map(|crumb| if crumb.exists { '1' } else { '0' })
+                    .collect();
+                let key = format!("{}-{}-{}", variant, theme, crumbs);
+                pages.page(&path, seq, &key, render_page)?
+            } else {
+                Arc::new(render_page()?)
+            };
+            let mut response = stream_body(html);
+            // pages change whenever the document does, so caches must
+            // revalidate before reusing them
+            let headers = response.headers_mut();
+            headers.insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+            headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+            headers.insert(VARY, HeaderValue::from_static("Accept, Accept-Language"));
+            Ok(response)
+        }
+        Err(StoreError::NotFound) => {
+            let mut response = if edit {
+                render(
+                    "editor.html",
+                    layout,
+                    json!({
+                        "title": document_title(&path, ""),
+                        "path": path,
+                        "breadcrumbs": breadcrumbs,
+                        "content": "",
+                        "participants": [],
+                        "seq": 0
+                    }),
+                )?
+            } else {
+                let ctx = json!({
+                    "title": document_title(&path, ""),
+                    "breadcrumbs": breadcrumbs,
+                });
+                render("new_document.html", layout, ctx)?
+            };
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            Ok(response)
+        }
+        Err(err) => Err(HttpError::InternalServerError(format!("{}", err))),
+    }
+}
+
+// Responds with a redirect to the directory's 'index' page if it has
+// one, or else a list of the 'paths' under it, or None if there are
+// no paths to list
+fn directory_page(
+    path: &Path,
+    index: Option<PathBuf>,
+    paths: &[PathBuf],
+    breadcrumbs: Vec<Breadcrumb>,
+    layout: &Layout,
+) -> Result<Option<Response<Body>>, HttpError> {
+    if let Some(index) = index {
+        let location = format!("{}{}", layout.base, percent_encode(&index.to_string_lossy()));
+        return Ok(Some(
+            Response::builder()
+                .status(StatusCode::FOUND)
+                .header(LOCATION, location.as_str())
+                .header(CACHE_CONTROL, "no-cache")
+                .body(Body::empty())
+                .unwrap(),
+        ));
+    }
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    let title = if path.as_os_str().is_empty() {
+        layout.message("home")
+    } else {
+        document_title(path, "")
+    };
+    let ctx = json!({
+        "title": title,
+        "path": path,
+        "breadcrumbs": breadcrumbs,
+        "entries": directory_entries(path, paths),
+    });
+    render("directory.html", layout, ctx).map(Some)
+}
+
+// Resolves to true if there is a document at 'path' which is not in
+// the trash
+fn document_exists<T: Store>(
+    store: &T,
+    path: &Path,
+) -> impl Future<Item = bool, Error = HttpError> {
+    store.deleted(path).then(|result| match result {
+        Ok(deleted) => Ok(!deleted),
+        Err(StoreError::NotFound) => Ok(false),
+        Err(err) => Err(HttpError::InternalServerError(format!("{}", err))),
+    })
+}
+
+// Returns the documents and directories directly under 'dir', given
+// the paths of the documents anywhere under it, sorted by name
+fn directory_entries(dir: &Path, paths: &[PathBuf]) -> Vec<serde_json::Value> {
+    // whether each name is a document, and a directory of others
+    let mut entries: BTreeMap<String, (bool, bool)> = BTreeMap::new();
+    for path in paths {
+        let mut rest = match path.strip_prefix(dir) {
+            Ok(rest) => rest.components(),
+            Err(_) => continue,
+        };
+        let name = match rest.next() {
+            Some(name) => name.as_os_str().to_string_lossy().into_owned(),
+            None => continue,
+        };
+        let entry = entries.entry(name).or_insert((false, false));
+        if rest.next().is_some() {
+            entry.1 = true;
+        } else {
+            entry.0 = true;
+        }
+    }
+    entries
+        .into_iter()
+        .map(|(name, (document, directory))| {
+            json!({
+                "path": dir.join(&name),
+                "name": name,
+                "document": document,
+                "directory": directory,
+            })
+        }).collect()
+}
+
+// Responds with 410 Gone and a page saying the document at 'path',
+// which had 'content' at revision 'seq', is in the trash
+fn deleted_page(
+    path: &Path,
+    content: &str,
+    seq: SequenceId,
+    breadcrumbs: &[Breadcrumb],
+    layout: &Layout,
+) -> Result<Response<Body>, HttpError> {
+    let ctx = json!({
+        "title": document_title(path, content),
+        "breadcrumbs": breadcrumbs,
+        "seq": seq
+    });
+    let mut response = render("deleted.html", layout, ctx)?;
+    *response.status_mut() = StatusCode::GONE;
+    Ok(response)
+}
+
+// Responds with the document at 'path' as JSON, or 304 Not Modified
+// if the client's cached copy matches 'if_none_match'
+fn document_json(
+    path: PathBuf,
+    if_none_match: Option<String>,
+    result: Result<(SequenceId, Document), StoreError>,
+) -> Result<Response<Body>, HttpError> {
+    let (seq, doc) = match result {
+        Ok((_, ref doc)) if doc.deleted => {
+            return Err(HttpError::Json {
+                status: StatusCode::GONE,
+                code: "Deleted",
+                reason: format!("The document at /{} was deleted", path.display()),
+            })
+        }
+        Ok(found) => found,
+        Err(StoreError::NotFound) => {
+            return Err(HttpError::Json {
+                status: StatusCode::NOT_FOUND,
+                code: "NotFound",
+                reason: format!("No document at /{}", path.display()),
+            })
+        }
+        Err(err) => return Err(HttpError::InternalServerError(format!("{}", err))),
+    };
+    let etag = document_etag(&path, seq, "json");
+    if let Some(response) = not_modified(&if_none_match, &etag) {
+        return Ok(response);
+    }
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .header(ETAG, etag)
+        .header(CACHE_CONTROL, "no-cache")
+        .header(VARY, "Accept")
+        .body(Body::from(document_state(seq, &doc).to_string()))
+        .unwrap())
+}
+
+// The JSON representation of a document as of 'seq', used wherever
+// programs read documents
+fn document_state(seq: SequenceId, doc: &Document) -> serde_json::Value {
+    json!({
+        "content": doc.content,
+        "seq": seq,
+        "participants": doc.participants,
+    })
+}
+
+// Responds with 304 Not Modified if the list of entity tags from an
+// If-None-Match header includes 'etag'
+fn not_modified(if_none_match: &Option<String>, etag: &str) -> Option<Response<Body>> {
+    match *if_none_match {
+        Some(ref if_none_match) if etag_matches(if_none_match, etag) => Some(
+            Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(ETAG, etag)
+                .header(CACHE_CONTROL, "no-cache")
+                .body(Body::empty())
+                .unwrap(),
+        ),
+        _ => None,
+    }
+}
+
+// Redirects to the end of a chain of redirect pages, starting at
+// the page 'from' which redirects to 'target'. If the chain loops,
+// or is too long, redirects to the 'from' page with ?redirect=no so
+// the reader can see and fix it. Pages are followed as 'user' sees
+// them, so readers who may not see drafts follow published redirects.
+fn follow_redirects<T: Store + Sync>(
+    wiki: TamaWiki<T>,
+    user: Option<User>,
+    base: String,
+    from: PathBuf,
+    target: PathBuf,
+) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send> {
+    let visited = vec![from.clone()];
+    let chain = future::loop_fn((visited, target), move |(mut visited, target)| {
+        if visited.contains(&target) || visited.len() > MAX_REDIRECTS {
+            return Either::A(future::ok(Loop::Break(None)));
+        }
+        let slugs = wiki.slugs.clone();
+        let wiki = wiki.clone();
+        let user = user.clone();
+        let content = wiki
+            .store
+            .content(target.as_path())
+            .then(|result| -> Result<_, HttpError> { Ok(result) });
+        Either::B(content.and_then(move |result| {
+            wiki.visible_revision(&user, &target, result)
+                .and_then(move |(result, _)| match result {
+                    Ok((_, doc)) => match markup::redirect_target(&doc.content) {
+                        Some(next) => match slugs.document_path(next) {
+                            Ok(next) => {
+                                visited.push(target);
+                                Ok(Loop::Continue((visited, next)))
+                            }
+                            Err(_) => Ok(Loop::Break(None)),
+                        },
+                        None => Ok(Loop::Break(Some(target))),
+                    },
+                    // the reader is offered to create the missing page
+                    Err(StoreError::NotFound) => Ok(Loop::Break(Some(target))),
+                    Err(err) => Err(HttpError::InternalServerError(format!("{}", err))),
+                })
+        }))
+    });
+    Box::new(chain.map(move |target| {
+        let location = match target {
+            Some(target) => format!("{}{}", base, percent_encode(&target.to_string_lossy())),
+            None => format!(
+                "{}{}?redirect=no",
+                base,
+                percent_encode(&from.to_string_lossy())
+            ),
+        };
+        Response::builder()
+            .status(StatusCode::FOUND)
+            .header(LOCATION, location.as_str())
+            .header(CACHE_CONTROL, "no-cache")
+            .body(Body::empty())
+            .unwrap()
+    }))
+}
+
+// Returns the entity tag for the document at 'path' as of 'seq',
+// which differs between the view page, the editor and JSON
+fn document_etag(path: &Path, seq: SequenceId, variant: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    match variant {
+        "view" => format!("\"{:x}-{}\"", hasher.finish(), seq),
+        _ => format!("\"{:x}-{}-{}\"", hasher.finish(), seq, variant),
+    }
+}
+
+// Responds with a file called 'filename' for the browser to save
+// rather than display
+fn download<T>(content_type: &'static str, filename: &Path, body: Arc<T>) -> Response<Body>
+where
+    T: AsRef<[u8]> + Send + Sync + 'static,
+{
+    let mut response = stream_body(body);
+    {
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers.insert(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_str(&export::content_disposition(filename)).unwrap(),
+        );
+    }
+    response
+}
+
+// Responds with 'body' in chunks of up to BODY_CHUNK_SIZE bytes, so
+// only one chunk at a time is copied into the response. Responses
+// sharing a body, such as cached pages, share a single copy of it.
+fn stream_body<T>(body: Arc<T>) -> Response<Body>
+where
+    T: AsRef<[u8]> + Send + Sync + 'static,
+{
+    let len = (*body).as_ref().len();
+    let chunks = stream::iter_ok::<_, io::Error>((0..len).step_by(BODY_CHUNK_SIZE)).map(
+        move |start| {
+            let end = cmp::min(start + BODY_CHUNK_SIZE, len);
+            Chunk::from((*body).as_ref()[start..end].to_vec())
+        },
+    );
+    Response::builder()
+        .header(CONTENT_LENGTH, HeaderValue::from(len))
+        .body(Body::wrap_stream(chunks))
+        .unwrap()
+}
+
+// Redirects to the front page at 'base', setting the session cookie
+// to 'token' for 'max_age'. An empty token with no max age ends the
+// session. The cookie is only sent over HTTPS when 'secure' is true.
+fn session_response(base: &str, token: &str, max_age: Duration, secure: bool) -> Response<Body> {
+    let cookie = format!(
+        "{}={}; Path={}; HttpOnly; SameSite=Lax; Max-Age={}{}",
+        SESSION_COOKIE,
+        token,
+        base,
+        max_age.as_secs(),
+        if secure { "; Secure" } else { "" }
+    );
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, base)
+        .header(SET_COOKIE, cookie.as_str())
+        .body(Body::empty())
+        .unwrap()
+}
+
+// Returns the path of the page on this site, under 'base', which
+// linked to or submitted the request, if known
+fn referring_page(req: &Request<Body>, base: &str) -> Option<String> {
+    req.headers()
+        .get(REFERER)
+        .and_then(|referer| referer.to_str().ok())
+        .and_then(|referer| referer.parse::<Uri>().ok())
+        .map(|uri| String::from(uri.path()))
+        .filter(|path| path.starts_with(base))
+}
+
+// Renders the signup or login form for 'route', explaining why the
+// last attempt failed if there is an 'error'
+fn account_form(
+    route: &str,
+    layout: &Layout,
+    oidc: bool,
+    username: &str,
+    error: Option<String>,
+) -> Result<Response<Body>, HttpError> {
+    let ctx = json!({
+        "title": layout.message(if route == "signup" { "sign_up" } else { "log_in" }),
+        "username": username,
+        "error": error,
+        "oidc": oidc,
+    });
+    render(&format!("{}.html", route), layout, ctx)
+}
+
+// Checks the user's credentials with the directory, starting a
+// session if it accepts them. Only users the directory does not know
+// may log in to a local account instead, so while the directory
+// cannot be reached nobody can log in.
+fn ldap_login(
+    directory: &Directory,
+    accounts: &Accounts,
+    username: String,
+    password: String,
+) -> Box<dyn Future<Item = String, Error = AccountError> + Send> {
+    let accounts = accounts.clone();
+    let groups = directory.clone();
+    Box::new(directory.login(&username, &password).then(
+        move |result| -> Result<String, AccountError> {
+            match result {
+                Ok(memberships) => {
+                    let identity = format!("ldap:{}", username);
+                    let token = accounts.login_external(&username, &identity)?;
+                    groups.record_groups(&username, memberships);
+                    Ok(token)
+                }
+                Err(LdapError::NotPermitted) => {
+                    info!("{} is not in the group required to log in", username);
+                    Err(AccountError::InvalidCredentials)
+                }
+                Err(LdapError::UnknownUser) => accounts.login(&username, &password),
+                Err(LdapError::InvalidCredentials) => Err(AccountError::InvalidCredentials),
+                Err(err) => {
+                    error!("Unable to log in with the directory: {}", err);
+                    Err(AccountError::Unavailable)
+                }
+            }
+        },
+    ))
+}
+
+// Creates a local account and starts a session for it, unless the
+// directory has a user with the same username
+fn ldap_signup(
+    directory: &Directory,
+    accounts: &Accounts,
+    username: String,
+    password: String,
+) -> Box<dyn Future<Item = String, Error = AccountError> + Send> {
+    let accounts = accounts.clone();
+    Box::new(directory.exists(&username).then(move |result| match result {
+        Ok(false) => accounts
+            .signup(&username, &password)
+            .and_then(|_| accounts.login(&username, &password)),
+        Ok(true) => Err(AccountError::UsernameTaken),
+        Err(err) => {
+            error!("Unable to search the directory: {}", err);
+            Err(AccountError::Unavailable)
+        }
+    }))
+}
+
+// Returns a random string which cannot be guessed, for use in URLs
+// and cookies
+fn random_token() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+}
+
+// Renders an HTML page using the named template, with the details
+// from 'layout' added to the context
+fn render(
+    template: &str,
+    layout: &Layout,
+    ctx: serde_json::Value,
+) -> Result<Response<Body>, HttpError> {
+    let text = render_text(template, layout, ctx)?;
+    Ok(Response::builder().body(Body::from(text)).unwrap())
+}
+
+// Renders the page listing 'owner's API tokens, showing a token
+// which has just been 'created' or the 'error' creating one
+fn tokens_page(
+    tokens: &ApiTokens,
+    owner: &str,
+    layout: &Layout,
+    created: Option<String>,
+    error: Option<String>,
+) -> Result<Response<Body>, HttpError> {
+    let listed: Vec<_> = tokens.list(owner).iter().map(token_json).collect();
+    let scopes: Vec<_> = Scope::ALL
+        .iter()
+        .map(|scope| {
+            let label = layout.message(&format!("scope_{}", scope.name()));
+            json!({"name": scope.name(), "label": label})
+        }).collect();
+    let ctx = json!({
+        "title": layout.message("api_tokens"),
+        "tokens": listed,
+        "scopes": scopes,
+        "rate_limit": tokens.rate_limit(),
+        "created": created,
+        "error": error,
+    });
+    render("tokens.html", layout, ctx)
+}
+
+// The details of an API token listed to its owner
+fn token_json(token: &ApiToken) -> serde_json::Value {
+    json!({
+        "id": token.id,
+        "name": token.name,
+        "scope": token.scope,
+        "rate_limit": token.rate_limit,
+        "created": DateTime::<Utc>::from(token.created).to_rfc3339(),
+        "last_used": token.last_used.map(|time| DateTime::<Utc>::from(time).to_rfc3339()),
+    })
+}
+
+// Renders 'template' with the layout details added to 'ctx'
+fn render_text(
+    template: &str,
+    layout: &Layout,
+    mut ctx: serde_json::Value,
+) -> Result<String, HttpError> {
+    layout.extend(&mut ctx);
+    layout
+        .render(template, &ctx)
+        .map_err(|err| HttpError::InternalServerError(format!("{}", err)))
+}
+
+// Converts errors from requesting a revision of a document into the
+// HttpError to respond with
+fn revision_error(err: StoreError) -> HttpError {
+    match err {
+        StoreError::NotFound => HttpError::NotFound,
+        StoreError::InvalidSequenceId => HttpError::BadRequest,
+        err => HttpError::InternalServerError(format!("{}", err)),
+    }
+}
+
+// Returns the SequenceId from the request's 'seq' query parameter,
+// or 0 if there is none.
+fn requested_seq(req: &Request<Body>) -> Result<SequenceId, HttpError> {
+    match query_params(req).get("seq") {
+        Some(x) => parse_seq(x),
+        None => Ok(0),
+    }
+}
+
+fn parse_seq(value: &str) -> Result<SequenceId, HttpError> {
+    value.parse().map_err(|_| HttpError::Json {
+        status: StatusCode::BAD_REQUEST,
+        code: "InvalidSeq",
+        reason: format!("Invalid seq parameter: {:?}", value),
+    })
+}
+
+// Returns the Role for a participant joining a document. Clients may
+// choose to restrict their own access further than the authenticated
+// user's role allows.
+fn participant_role(q: &HashMap<String, String>, user: &Option<User>) -> Role {
+    let requested = match q.get("role").map(|r| r.as_str()) {
+        Some("commenter") => Role::Commenter,
+        Some("reader") => Role::Reader,
+        _ => Role::Editor,
+    };
+    match *user {
+        Some(ref user) => cmp::max(user.role, requested),
+        None => requested,
+    }
+}
+
+// The error for a change which would take its user over their quota
+fn quota_exceeded(reason: String) -> HttpError {
+    HttpError::Json {
+        status: StatusCode::PAYLOAD_TOO_LARGE,
+        code: "QuotaExceeded",
+        reason,
+    }
+}
+
+// Returns the close code and reason to send when a websocket
+// connection ends with an error.
+fn close_reason(err: &MessageStreamError) -> Option<(CloseCode, &'static str)> {
+    match *err {
+        MessageStreamError::InvalidMessage { .. } => Some((CloseCode::Invalid, "Invalid message")),
+        MessageStreamError::TooSlow => Some((CloseCode::Library(CLOSE_TOO_SLOW), "Too slow")),
+        MessageStreamError::TooLarge { .. } => Some((CloseCode::Size, "Message too large")),
+        MessageStreamError::Transport { .. } => None,
+    }
 }
 
 impl<T: Store + Sync> NewService for TamaWiki<T> {
@@ -219,22 +3709,32 @@ impl<T: Store + Sync> Service for TamaWiki<T> {
     type ReqBody = Body;
     type ResBody = Body;
     type Error = TamaWikiError;
-    type Future = Box<Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
+    type Future = Box<dyn Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
 
     fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
-        let res = if req.uri().path().starts_with("/_static/") {
-            self.serve_static(req)
-        } else if is_websocket_upgrade_request(&req) {
-            self.handle_websocket(req)
-        } else {
-            self.serve_document(&req)
-        };
-        let res = res.then(|result| -> FutureResult<Response<Body>, TamaWikiError> {
-            match result {
-                Ok(response) => future::ok(response),
-                Err(err) => future::ok(err.into()),
-            }
-        });
-        Box::new(res)
+        let span = info_span!(
+            "http.request",
+            otel.kind = "server",
+            otel.status_code = field::Empty,
+            http.method = %req.method(),
+            http.target = %req.uri().path(),
+            http.status_code = field::Empty,
+            traceparent = req
+                .headers()
+                .get("traceparent")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("")
+        );
+        let res = span.in_scope(|| self.respond(req));
+        let recorded = span.clone();
+        Box::new(
+            res.map(move |res| {
+                recorded.record("http.status_code", res.status().as_u16());
+                if res.status().is_server_error() {
+                    recorded.record("otel.status_code", "error");
+                }
+                res
+            }).instrument(span),
+        )
     }
 }
@@ -0,0 +1,255 @@
+//! Bounded buffering of messages sent to websocket clients
+
+use futures::stream::Fuse;
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use std::collections::VecDeque;
+
+use crate::document::{Edit, Event, Operation};
+use crate::session::message::{
+    ChannelServerMessage, MessageStreamError, ServerEventMessage, ServerMessage,
+};
+use crate::session::ResyncHandle;
+
+/// The maximum number of messages waiting to be sent to a client
+/// before presence-only messages are discarded
+pub const OUTBOUND_CAPACITY: usize = 1000;
+
+/// Messages which can be buffered by Outbound
+pub trait OutboundMessage {
+    /// Returns true if the message only moves participants' cursors,
+    /// so the client's copy of the document content is unaffected if
+    /// it is discarded.
+    fn is_presence(&self) -> bool;
+
+    /// Returns true if the message contains document events, which
+    /// are replaced by a snapshot of the document when resyncing
+    fn is_event(&self) -> bool;
+}
+
+/// Forwards ServerMessages from a Stream to a Sink, reading from the
+/// Stream even while the Sink is busy so messages do not queue up
+/// elsewhere. Only 'capacity' messages are buffered: once full,
+/// presence-only messages (cursor moves) are discarded. If the buffer
+/// is still full, the buffered events are discarded and the stream
+/// resynced, or if it can't be the future fails with
+/// MessageStreamError::TooSlow.
+pub struct Outbound<S: Stream, K> {
+    stream: Fuse<S>,
+    sink: Option<K>,
+    buffer: OutboundBuffer<S::Item>,
+    resync: Option<ResyncHandle>,
+}
+
+impl<S, K> Outbound<S, K>
+where
+    S: Stream<Error = MessageStreamError>,
+    S::Item: OutboundMessage,
+    K: Sink<SinkItem = S::Item, SinkError = MessageStreamError>,
+{
+    /// Creates a new Outbound future which sends messages from
+    /// 'stream' to 'sink', closing the sink once the stream ends.
+    pub fn new(stream: S, sink: K, capacity: usize) -> Self {
+        Self {
+            stream: stream.fuse(),
+            sink: Some(sink),
+            buffer: OutboundBuffer::new(capacity),
+            resync: None,
+        }
+    }
+
+    /// Resyncs the stream using 'handle' when the buffer is full,
+    /// instead of failing. The client must support
+    /// ServerMessage::Resync.
+    pub fn with_resync(mut self, handle: ResyncHandle) -> Self {
+        self.resync = Some(handle);
+        self
+    }
+}
+
+impl<S, K> Future for Outbound<S, K>
+where
+    S: Stream<Error = MessageStreamError>,
+    S::Item: OutboundMessage,
+    K: Sink<SinkItem = S::Item, SinkError = MessageStreamError>,
+{
+    type Item = K;
+    type Error = MessageStreamError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // read all available messages into the buffer
+        while let Async::Ready(Some(msg)) = self.stream.poll()? {
+            if let Err(msg) = self.buffer.push(msg) {
+                let resync = self.resync.as_ref().ok_or(MessageStreamError::TooSlow)?;
+                // the client can't keep up with the events, so it is
+                // sent a snapshot of the document in their place
+                self.buffer.discard_events();
+                resync.resync();
+                if !msg.is_event() && self.buffer.push(msg).is_err() {
+                    return Err(MessageStreamError::TooSlow);
+                }
+            }
+        }
+        {
+            let sink = self.sink.as_mut().expect("polled Outbound after completion");
+            while let Some(msg) = self.buffer.pop() {
+                if let AsyncSink::NotReady(msg) = sink.start_send(msg)? {
+                    self.buffer.unpop(msg);
+                    break;
+                }
+            }
+            if !self.buffer.is_empty() || !self.stream.is_done() {
+                sink.poll_complete()?;
+                return Ok(Async::NotReady);
+            }
+            try_ready!(sink.close());
+        }
+        Ok(Async::Ready(self.sink.take().unwrap()))
+    }
+}
+
+// A bounded queue of messages which discards presence-only messages
+// when full.
+struct OutboundBuffer<M> {
+    messages: VecDeque<M>,
+    capacity: usize,
+}
+
+impl<M: OutboundMessage> OutboundBuffer<M> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    // Adds a message to the queue, returning it again if the queue is
+    // full and no presence-only message can make room for it
+    fn push(&mut self, msg: M) -> Result<(), M> {
+        if self.messages.len() >= self.capacity {
+            if msg.is_presence() {
+                return Ok(());
+            }
+            match self.messages.iter().position(M::is_presence) {
+                Some(i) => {
+                    self.messages.remove(i);
+                }
+                None => return Err(msg),
+            }
+        }
+        self.messages.push_back(msg);
+        Ok(())
+    }
+
+    // Removes every message containing events from the queue
+    fn discard_events(&mut self) {
+        self.messages.retain(|msg| !msg.is_event());
+    }
+
+    fn pop(&mut self) -> Option<M> {
+        self.messages.pop_front()
+    }
+
+    // Returns a message the sink was not ready to accept to the
+    // front of the queue.
+    fn unpop(&mut self, msg: M) {
+        self.messages.push_front(msg);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+impl OutboundMessage for ServerMessage {
+    fn is_presence(&self) -> bool {
+        match self {
+            ServerMessage::Event(ServerEventMessage { event, .. }) => match ***event {
+                Event::Edit(Edit { ref operations, .. }) => operations
+                    .iter()
+                    .all(|op| matches!(op, Operation::MoveCursor(_))),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn is_event(&self) -> bool {
+        matches!(self, ServerMessage::Event(_) | ServerMessage::Events(_))
+    }
+}
+
+impl OutboundMessage for ChannelServerMessage {
+    fn is_presence(&self) -> bool {
+        self.message.is_presence()
+    }
+
+    fn is_event(&self) -> bool {
+        self.message.is_event()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Insert, MoveCursor};
+    use crate::session::message::SharedEvent;
+    use std::sync::Arc;
+
+    fn edit(seq: u64, op: Operation) -> ServerMessage {
+        ServerMessage::Event(ServerEventMessage {
+            client_seq: 0,
+            seq,
+            event: Arc::new(SharedEvent::new(Event::Edit(Edit {
+                author: 1,
+                operations: vec![op],
+            }))),
+        })
+    }
+
+    fn insert(seq: u64) -> ServerMessage {
+        edit(
+            seq,
+            Operation::Insert(Insert {
+                pos: 0,
+                content: String::from("a"),
+            }),
+        )
+    }
+
+    fn cursor(seq: u64) -> ServerMessage {
+        edit(seq, Operation::MoveCursor(MoveCursor { pos: 0 }))
+    }
+
+    #[test]
+    fn full_buffer_discards_presence_messages() {
+        let mut buffer = OutboundBuffer::new(2);
+        buffer.push(cursor(1)).unwrap();
+        buffer.push(insert(2)).unwrap();
+        // incoming presence messages are discarded when full
+        buffer.push(cursor(3)).unwrap();
+        // buffered presence messages make room for other messages
+        buffer.push(insert(4)).unwrap();
+        assert_eq!(buffer.pop(), Some(insert(2)));
+        assert_eq!(buffer.pop(), Some(insert(4)));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn full_buffer_without_presence_messages_is_too_slow() {
+        let mut buffer = OutboundBuffer::new(2);
+        buffer.push(insert(1)).unwrap();
+        buffer.push(insert(2)).unwrap();
+        assert_eq!(buffer.push(insert(3)), Err(insert(3)));
+    }
+
+    #[test]
+    fn resync_discards_events() {
+        let mut buffer = OutboundBuffer::new(3);
+        buffer.push(insert(1)).unwrap();
+        buffer.push(ServerMessage::Shutdown).unwrap();
+        buffer.push(cursor(2)).unwrap();
+        buffer.discard_events();
+        assert_eq!(buffer.pop(), Some(ServerMessage::Shutdown));
+        assert_eq!(buffer.pop(), None);
+    }
+}
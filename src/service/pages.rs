@@ -0,0 +1,144 @@
+//! Recently rendered documents, so read-heavy wikis can serve pages
+//! for unchanged documents without rendering their markup and
+//! templates again
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::store::SequenceId;
+
+// The number of documents whose pages are kept
+const MAX_DOCUMENTS: usize = 1000;
+
+/// The HTML rendered for the latest revision of each document. A
+/// document's pages are dropped as soon as a newer revision is
+/// rendered, so only pages for its latest Event are ever served.
+#[derive(Clone, Default)]
+pub struct PageCache {
+    documents: Arc<Mutex<HashMap<PathBuf, Rendered>>>,
+}
+
+// The HTML rendered for one revision of a document
+struct Rendered {
+    seq: SequenceId,
+    // the document's content
+    content: Option<Arc<String>>,
+    // complete pages, keyed by the template and anything else the
+    // page depends on, such as the locale
+    pages: HashMap<String, Arc<String>>,
+}
+
+impl PageCache {
+    /// Returns the content of the document at 'path' as HTML, as of
+    /// 'seq', calling 'render' only if it is not already cached
+    pub fn content<F>(&self, path: &Path, seq: SequenceId, render: F) -> Arc<String>
+    where
+        F: FnOnce() -> String,
+    {
+        if let Some(html) = self.get(path, seq, |rendered| rendered.content.clone()) {
+            return html;
+        }
+        let html = Arc::new(render());
+        self.insert(path, seq, |rendered| rendered.content = Some(html.clone()));
+        html
+    }
+
+    /// Returns the page for the document at 'path' identified by
+    /// 'key', as of 'seq', calling 'render' only if it is not
+    /// already cached
+    pub fn page<F, E>(
+        &self,
+        path: &Path,
+        seq: SequenceId,
+        key: &str,
+        render: F,
+    ) -> Result<Arc<String>, E>
+    where
+        F: FnOnce() -> Result<String, E>,
+    {
+        if let Some(html) = self.get(path, seq, |rendered| rendered.pages.get(key).cloned()) {
+            return Ok(html);
+        }
+        let html = Arc::new(render()?);
+        self.insert(path, seq, |rendered| {
+            rendered.pages.insert(String::from(key), html.clone());
+        });
+        Ok(html)
+    }
+
+    // Looks up HTML rendered for 'seq' using 'f'
+    fn get<F>(&self, path: &Path, seq: SequenceId, f: F) -> Option<Arc<String>>
+    where
+        F: FnOnce(&Rendered) -> Option<Arc<String>>,
+    {
+        let documents = self.documents.lock().unwrap_or_else(|err| err.into_inner());
+        match documents.get(path) {
+            Some(rendered) if rendered.seq == seq => f(rendered),
+            _ => None,
+        }
+    }
+
+    // Adds HTML rendered for 'seq' using 'f', replacing any HTML for
+    // an earlier revision. HTML for a revision older than the cached
+    // one is not kept.
+    fn insert<F>(&self, path: &Path, seq: SequenceId, f: F)
+    where
+        F: FnOnce(&mut Rendered),
+    {
+        let mut documents = self.documents.lock().unwrap_or_else(|err| err.into_inner());
+        if !documents.contains_key(path) && documents.len() >= MAX_DOCUMENTS {
+            // make room by forgetting any one of the other documents
+            if let Some(other) = documents.keys().next().cloned() {
+                documents.remove(&other);
+            }
+        }
+        let rendered = documents.entry(path.to_path_buf()).or_insert(Rendered {
+            seq,
+            content: None,
+            pages: HashMap::new(),
+        });
+        if rendered.seq < seq {
+            *rendered = Rendered {
+                seq,
+                content: None,
+                pages: HashMap::new(),
+            };
+        }
+        if rendered.seq == seq {
+            f(rendered);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_until_document_changes() {
+        let cache = PageCache::default();
+        let path = Path::new("index.html");
+        let render = |html: &str| {
+            let html = String::from(html);
+            move || -> Result<String, ()> { Ok(html) }
+        };
+        assert_eq!(*cache.page(path, 1, "view", render("one")).unwrap(), "one");
+        assert_eq!(*cache.page(path, 1, "view", render("two")).unwrap(), "one");
+        assert_eq!(*cache.page(path, 1, "edit", render("edit")).unwrap(), "edit");
+        assert_eq!(*cache.content(path, 1, || String::from("content")), "content");
+        assert_eq!(*cache.content(path, 1, || String::from("other")), "content");
+
+        // a new revision replaces every page for the document
+        assert_eq!(*cache.page(path, 2, "view", render("three")).unwrap(), "three");
+        assert_eq!(*cache.content(path, 2, || String::from("new")), "new");
+        // and older revisions are not cached again
+        assert_eq!(*cache.page(path, 1, "edit", render("old")).unwrap(), "old");
+        assert_eq!(*cache.page(path, 1, "edit", render("older")).unwrap(), "older");
+        assert_eq!(*cache.page(path, 2, "view", render("four")).unwrap(), "three");
+
+        // render errors are not cached
+        let failed: Result<_, ()> = cache.page(path, 2, "edit", || Err(()));
+        assert!(failed.is_err());
+        assert_eq!(*cache.page(path, 2, "edit", render("edit")).unwrap(), "edit");
+    }
+}
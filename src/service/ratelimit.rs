@@ -0,0 +1,101 @@
+//! Limits how often clients may change documents or connect to them,
+//! so one client cannot flood the wiki with edits or connections.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Once this many clients are tracked, clients which have not made a
+// request for a whole period are forgotten
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Allows each client a number of requests per period, using a token
+/// bucket for each client. A client may make up to the limit in a
+/// burst, after which the bucket refills at a steady rate. This is a
+/// cloneable interface to buckets held in memory.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limit: u32,
+    period: Duration,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Allows each client 'limit' requests per 'period'
+    pub fn new(limit: u32, period: Duration) -> Self {
+        RateLimiter {
+            limit,
+            period,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Records a request by the client identified by 'key'. Returns
+    /// how long the client must wait before trying again if it has
+    /// made too many requests.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let limit = f64::from(self.limit);
+        let period = duration_secs(self.period);
+        // tokens added to a bucket per second
+        let rate = limit / period;
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            // rather than refuse every request
+            Err(_) => return Ok(()),
+        };
+        if buckets.len() >= PRUNE_THRESHOLD && !buckets.contains_key(key) {
+            let period = self.period;
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < period);
+        }
+        let bucket = buckets.entry(String::from(key)).or_insert(Bucket {
+            tokens: limit,
+            updated: now,
+        });
+        let elapsed = duration_secs(now.duration_since(bucket.updated));
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(limit);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) * period / limit;
+            Err(Duration::from_millis((wait * 1000.0).ceil() as u64))
+        }
+    }
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_requests() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        assert_eq!(limiter.check_at("a", start), Ok(()));
+        assert_eq!(limiter.check_at("a", start), Ok(()));
+        assert_eq!(
+            limiter.check_at("a", start),
+            Err(Duration::from_secs(5))
+        );
+        // other clients have their own limit
+        assert_eq!(limiter.check_at("b", start), Ok(()));
+        // the bucket refills over time
+        let later = start + Duration::from_secs(5);
+        assert_eq!(limiter.check_at("a", later), Ok(()));
+        assert!(limiter.check_at("a", later).is_err());
+    }
+}
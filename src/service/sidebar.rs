@@ -0,0 +1,78 @@
+//! Site navigation written as a wiki page, shown beside every
+//! document so it can be edited like any other page
+use futures::future::{self, Either, Future};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::markup;
+use crate::store::{SequenceId, Store, StoreError};
+
+/// The page shown as the sidebar, and its most recently rendered
+/// HTML
+#[derive(Clone)]
+pub struct Sidebar {
+    path: PathBuf,
+    rendered: Arc<Mutex<Option<(SequenceId, String)>>>,
+}
+
+impl Sidebar {
+    /// Shows the document at 'path' as the sidebar
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Sidebar {
+            path: path.into(),
+            rendered: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The path of the sidebar's document
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Resolves to the SequenceId and HTML of the sidebar, or None
+    /// if its document does not exist. When 'published' is true, the
+    /// revision last published is shown instead of any drafts, or
+    /// None if there is none. The document is only rendered again
+    /// once the revision shown has changed.
+    pub fn render<T: Store>(
+        &self,
+        store: &T,
+        attachments: String,
+        published: bool,
+    ) -> impl Future<Item = Option<(SequenceId, String)>, Error = StoreError> {
+        let store = store.clone();
+        let path = self.path.clone();
+        let rendered = self.rendered.clone();
+        let seq = if published {
+            Either::A(store.content(&self.path).map(|(_, doc)| doc.published))
+        } else {
+            Either::B(store.seq(&self.path).map(Some))
+        };
+        seq.then(move |result| {
+            let seq = match result {
+                Ok(Some(seq)) => seq,
+                Ok(None) | Err(StoreError::NotFound) => return Either::A(future::ok(None)),
+                Err(err) => return Either::A(future::err(err)),
+            };
+            {
+                let cached = rendered.lock().unwrap_or_else(|err| err.into_inner());
+                if let Some((cached_seq, ref html)) = *cached {
+                    if cached_seq == seq {
+                        return Either::A(future::ok(Some((seq, html.clone()))));
+                    }
+                }
+            }
+            let content = if published {
+                Either::A(store.content_at(&path, seq).map(move |doc| (seq, doc)))
+            } else {
+                Either::B(store.content(&path))
+            };
+            Either::B(content.map(move |(seq, doc)| {
+                let html = markup::render(&doc.content, &attachments);
+                let mut cached = rendered.lock().unwrap_or_else(|err| err.into_inner());
+                *cached = Some((seq, html.clone()));
+                Some((seq, html))
+            }))
+        })
+    }
+}
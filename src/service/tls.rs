@@ -0,0 +1,162 @@
+//! Terminates TLS connections using rustls, so TamaWiki can serve
+//! `https://` and `wss://` clients without a reverse proxy.
+//!
+//! # Example
+//!
+//! ```no_run
+//! extern crate hyper;
+//! extern crate tamawiki;
+//! extern crate futures;
+//! extern crate tokio;
+//!
+//! use futures::future::Future;
+//! use hyper::Server;
+//! use tamawiki::service::tls;
+//! use tamawiki::store::memory::MemoryStore;
+//! use tamawiki::TamaWiki;
+//! use tokio::net::TcpListener;
+//!
+//! let addr = ([127, 0, 0, 1], 8443).into();
+//! let config = tls::server_config("cert.pem", "key.pem").unwrap();
+//! let listener = TcpListener::bind(&addr).unwrap();
+//!
+//! let server = Server::builder(tls::incoming(listener, config))
+//!     .serve(TamaWiki::new(MemoryStore::default(), "static"))
+//!     .map_err(|err| eprintln!("Server error: {}", err));
+//! ```
+use futures::future::Future;
+use futures::stream::Stream;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig, ServerSession, TLSError};
+use tokio_rustls::{TlsAcceptor, TlsStream};
+
+// The most TLS handshakes performed at once
+const MAX_HANDSHAKES: usize = 128;
+
+/// Error conditions which may occur when loading a TLS configuration
+#[derive(Debug)]
+pub enum TlsError {
+    /// The certificate or key file could not be read
+    Io(io::Error),
+    /// The certificate file contained no PEM encoded certificates
+    InvalidCertificate,
+    /// The key file contained no PEM encoded PKCS#8 or RSA private key
+    InvalidKey,
+    /// rustls rejected the certificate and key
+    Rustls(TLSError),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TlsError::Io(ref err) => write!(f, "Io({})", err),
+            TlsError::InvalidCertificate => write!(f, "InvalidCertificate"),
+            TlsError::InvalidKey => write!(f, "InvalidKey"),
+            TlsError::Rustls(ref err) => write!(f, "Rustls({})", err),
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn description(&self) -> &str {
+        match *self {
+            TlsError::Io(_) => "TlsError: could not read certificate or key",
+            TlsError::InvalidCertificate => "TlsError: no certificates found",
+            TlsError::InvalidKey => "TlsError: no private key found",
+            TlsError::Rustls(_) => "TlsError: invalid certificate or key",
+        }
+    }
+}
+
+impl From<io::Error> for TlsError {
+    fn from(err: io::Error) -> Self {
+        TlsError::Io(err)
+    }
+}
+
+/// Reads a PEM encoded certificate chain and private key, returning
+/// a server configuration which presents them to clients. The key
+/// may be in PKCS#8 or RSA format.
+pub fn server_config<P: AsRef<Path>, Q: AsRef<Path>>(
+    cert_path: P,
+    key_path: Q,
+) -> Result<Arc<ServerConfig>, TlsError> {
+    let mut reader = BufReader::new(File::open(cert_path)?);
+    let certs = match certs(&mut reader) {
+        Ok(ref certs) if certs.is_empty() => return Err(TlsError::InvalidCertificate),
+        Ok(certs) => certs,
+        Err(_) => return Err(TlsError::InvalidCertificate),
+    };
+    let mut reader = BufReader::new(File::open(key_path.as_ref())?);
+    let mut keys = pkcs8_private_keys(&mut reader).map_err(|_| TlsError::InvalidKey)?;
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(key_path.as_ref())?);
+        keys = rsa_private_keys(&mut reader).map_err(|_| TlsError::InvalidKey)?;
+    }
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => return Err(TlsError::InvalidKey),
+    };
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(TlsError::Rustls)?;
+    Ok(Arc::new(config))
+}
+
+/// Accepts connections on 'listener' and performs a TLS handshake
+/// with each, for use with `hyper::Server::builder`. Connections
+/// which fail the handshake are logged and dropped without
+/// affecting the server.
+pub fn incoming(
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+) -> impl Stream<Item = TlsStream<TcpStream, ServerSession>, Error = io::Error> + Send {
+    let acceptor = TlsAcceptor::from(config);
+    listener
+        .incoming()
+        .map(move |tcp| {
+            acceptor.accept(tcp).then(|result| -> Result<_, io::Error> {
+                match result {
+                    Ok(stream) => Ok(Some(stream)),
+                    Err(err) => {
+                        warn!("TLS handshake failed: {}", err);
+                        Ok(None)
+                    }
+                }
+            })
+        }).buffer_unordered(MAX_HANDSHAKES)
+        .filter_map(|stream| stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn missing_certificate() {
+        let dir = env::temp_dir().join(format!("tamawiki-tls-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        fs::write(&cert, "not a certificate").unwrap();
+
+        match server_config(&cert, &cert) {
+            Err(TlsError::InvalidCertificate) => (),
+            result => panic!("expected InvalidCertificate, got {:?}", result.map(|_| ())),
+        }
+        match server_config(dir.join("missing.pem"), &cert) {
+            Err(TlsError::Io(_)) => (),
+            result => panic!("expected Io error, got {:?}", result.map(|_| ())),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
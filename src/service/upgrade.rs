@@ -1,14 +1,12 @@
-use base64;
 use futures::{future, Future};
-use http;
-use http::header::{SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE};
-use hyper;
+use http::header::{SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE};
 use hyper::{Body, Request, Response};
 use sha1::{Digest, Sha1};
 use tungstenite::protocol;
 
-use service::error::HttpError;
-use websocket::WebSocket;
+use crate::service::error::HttpError;
+use crate::session::message::Protocol;
+use crate::websocket::{self, WebSocket};
 
 pub fn is_websocket_upgrade_request(req: &Request<Body>) -> bool {
     match req.headers().get(UPGRADE) {
@@ -24,9 +22,9 @@ pub fn is_websocket_upgrade_request(req: &Request<Body>) -> bool {
 pub fn websocket_upgrade<F, U>(
     req: Request<Body>,
     fun: F,
-) -> Box<Future<Item = Response<Body>, Error = HttpError> + Send>
+) -> Box<dyn Future<Item = Response<Body>, Error = HttpError> + Send>
 where
-    F: Fn(WebSocket) -> U + Sync + Send + 'static,
+    F: Fn(WebSocket, Protocol) -> U + Sync + Send + 'static,
     U: Future<Item = (), Error = ()> + Send + 'static,
 {
     let upgrade = is_websocket_upgrade_request(&req);
@@ -45,6 +43,16 @@ where
         base64::encode(&sha1.result())
     });
 
+    // the client may request a message format version and encoding
+    // using the sub-protocol header, if none of the requested
+    // sub-protocols are supported the response omits the header
+    let negotiated = req
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(Protocol::negotiate);
+    let protocol = negotiated.unwrap_or_default();
+
     if !upgrade || !version || key.is_none() {
         return Box::new(future::err(HttpError::BadRequest));
     }
@@ -52,21 +60,22 @@ where
     let on_upgrade = req
         .into_body()
         .on_upgrade()
-        .map_err(|err| eprintln!("upgrade error: {}", err))
+        .map_err(|err| warn!(target: websocket::LOG_TARGET, "upgrade error: {}", err))
         .and_then(move |upgraded| {
             let io = protocol::WebSocket::from_raw_socket(upgraded, protocol::Role::Server, None);
-            fun(WebSocket::from(io))
+            fun(WebSocket::from(io), protocol)
         });
 
     hyper::rt::spawn(on_upgrade);
 
-    Box::new(future::ok(
-        http::Response::builder()
-            .status(101)
-            .header("connection", "upgrade")
-            .header("upgrade", "websocket")
-            .header("sec-websocket-accept", key.unwrap().as_str())
-            .body(Default::default())
-            .unwrap(),
-    ))
+    let mut response = http::Response::builder();
+    response
+        .status(101)
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-accept", key.unwrap().as_str());
+    if let Some(protocol) = negotiated {
+        response.header(SEC_WEBSOCKET_PROTOCOL, protocol.name().as_str());
+    }
+    Box::new(future::ok(response.body(Default::default()).unwrap()))
 }
@@ -0,0 +1,135 @@
+//! Follows a document's events as they are written to the store,
+//! for read-only clients which do not join the document session.
+use futures::future::{FlattenStream, Future};
+use futures::stream::Stream;
+use futures::{Async, Poll};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::message::{ServerEventMessage, SharedEvent};
+use crate::store::{SequenceId, Store, StoreError};
+
+/// A Stream of ServerEventMessages for every Event in the document
+/// after a given SequenceId, including Events pushed after the Feed
+/// was created. Unlike a Participant, following a document does not
+/// add a Join event to its history.
+pub struct Feed<T: Store> {
+    store: T,
+    path: PathBuf,
+    // The last SequenceId read from the store
+    seq: SequenceId,
+    // Notifications of new Events pushed to the document
+    watch: Box<dyn Stream<Item = SequenceId, Error = StoreError> + Send>,
+    // Events currently being read from the store, if any
+    events: Option<FlattenStream<T::SinceFuture>>,
+}
+
+/// Returns a Feed of the events in the document at 'path' after
+/// 'since'. The document does not need to exist yet.
+pub fn feed<T: Store>(store: &T, path: &Path, since: SequenceId) -> Feed<T> {
+    // watch before requesting events from the store, so no events
+    // can be missed in between
+    let watch = store.watch(path);
+    Feed {
+        events: Some(store.since(path, since, None).flatten_stream()),
+        store: store.clone(),
+        path: PathBuf::from(path),
+        seq: since,
+        watch,
+    }
+}
+
+impl<T: Store> Stream for Feed<T> {
+    type Item = ServerEventMessage;
+    type Error = StoreError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(mut events) = self.events.take() {
+                match events.poll() {
+                    Ok(Async::Ready(Some((seq, event)))) => {
+                        self.seq = seq;
+                        self.events = Some(events);
+                        return Ok(Async::Ready(Some(ServerEventMessage {
+                            client_seq: 0,
+                            seq,
+                            event: Arc::new(SharedEvent::new(event)),
+                        })));
+                    }
+                    Ok(Async::NotReady) => {
+                        self.events = Some(events);
+                        return Ok(Async::NotReady);
+                    }
+                    Ok(Async::Ready(None)) => (),
+                    // the document has not been created yet, wait
+                    // for the first event to be pushed
+                    Err(StoreError::NotFound) => (),
+                    Err(err) => return Err(err),
+                }
+            }
+            match try_ready!(self.watch.poll()) {
+                Some(head) => if head > self.seq {
+                    self.events = Some(
+                        self.store
+                            .since(&self.path, self.seq, None)
+                            .flatten_stream(),
+                    );
+                },
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Event, Leave};
+    use std::collections::HashMap;
+    use crate::store::memory::MemoryStore;
+    use tokio::runtime::current_thread::Runtime;
+
+    // Reads the next message from the feed
+    fn next<T: Store>(rt: &mut Runtime, events: Feed<T>) -> (ServerEventMessage, Feed<T>) {
+        let (msg, events) = rt.block_on(events.into_future()).map_err(|(err, _)| err).unwrap();
+        (msg.unwrap(), events)
+    }
+
+    #[test]
+    fn feed_follows_new_events() {
+        let mut rt = Runtime::new().expect("new test runtime");
+        let mut data = HashMap::new();
+        data.insert(String::from("/test"), String::from("Hello"));
+        let mut store = MemoryStore::from(data);
+
+        let events = feed(&store, Path::new("/test"), 1);
+        let (msg, events) = next(&mut rt, events);
+        assert_eq!(msg.seq, 2);
+        let (msg, events) = next(&mut rt, events);
+        assert_eq!(msg.seq, 3);
+
+        rt.block_on(store.push(PathBuf::from("/test"), Event::Leave(Leave { id: 2 })))
+            .unwrap();
+        let (msg, _) = next(&mut rt, events);
+        assert_eq!(
+            msg,
+            ServerEventMessage {
+                client_seq: 0,
+                seq: 4,
+                event: Arc::new(SharedEvent::new(Event::Leave(Leave { id: 2 }))),
+            }
+        );
+    }
+
+    #[test]
+    fn feed_waits_for_new_document() {
+        let mut rt = Runtime::new().expect("new test runtime");
+        let mut store = MemoryStore::default();
+
+        let events = feed(&store, Path::new("/new"), 0);
+        rt.block_on(store.push(PathBuf::from("/new"), Event::Leave(Leave { id: 1 })))
+            .unwrap();
+        let (msg, _) = next(&mut rt, events);
+        assert_eq!(msg.seq, 1);
+    }
+}
@@ -1,13 +1,17 @@
 //! Defines messages for client/server communication during an EditSession
 
-use document::{Event, Operation, ParticipantId};
+use crate::document::{Event, Leave, Operation, ParticipantId, Participants};
 use futures::future::FutureResult;
 use futures::sink::Sink;
-use futures::stream::Stream;
-use serde_json;
+use futures::stream::{self, Stream};
+use serde::de::DeserializeOwned;
+use serde::{Serialize, Serializer};
 use std::error::Error;
 use std::fmt::{self, Debug, Display};
-use store::{SequenceId, StoreError};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use crate::store::{SequenceId, StoreError};
+use tracing::info_span;
 
 /// Message sent from the server to the client
 #[derive(Serialize, Debug, PartialEq)]
@@ -17,6 +21,24 @@ pub enum ServerMessage {
     Connected(ConnectedMessage),
     /// A new event from the server
     Event(ServerEventMessage),
+    /// Consecutive events from the server, sent together when a
+    /// client is catching up on events it missed
+    Events(Vec<ServerEventMessage>),
+    /// The server is shutting down and will close the connection
+    /// after this message. Any edits sent after this point may be
+    /// lost and should be resent after reconnecting.
+    Shutdown,
+    /// The document's exclusive edit lock has changed hands
+    Lock(LockMessage),
+    /// A message from the client was rejected
+    Error(ErrorMessage),
+    /// The participant was removed from the document session and the
+    /// connection will be closed after this message
+    Kicked,
+    /// The client fell too far behind the document's events, so the
+    /// events it had not yet received were discarded and replaced by
+    /// a snapshot of the document
+    Resync(ResyncMessage),
 }
 
 /// Wraps an Event with client and server sequence id information
@@ -26,8 +48,98 @@ pub struct ServerEventMessage {
     pub client_seq: SequenceId,
     /// The SequenceId of the Event in the Store
     pub seq: SequenceId,
-    /// The event
-    pub event: Event,
+    /// The event, shared with every other participant it is sent to
+    pub event: Arc<SharedEvent>,
+}
+
+/// An Event sent to every participant in a document session. The
+/// event is serialized at most once for each Encoding, and the result
+/// copied into the message sent to each participant.
+#[derive(Debug)]
+pub struct SharedEvent {
+    event: Event,
+    encoded: Mutex<Vec<(Encoding, Arc<Vec<u8>>)>>,
+}
+
+impl SharedEvent {
+    /// Wraps an Event to be sent to participants
+    pub fn new(event: Event) -> Self {
+        SharedEvent {
+            event,
+            encoded: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Returns the event serialized using 'encoding', serializing it
+    // only if this is the first time it was requested
+    fn encoded(&self, encoding: Encoding) -> Result<Arc<Vec<u8>>, MessageStreamError> {
+        let mut encoded = self.encoded.lock().unwrap();
+        if let Some(&(_, ref data)) = encoded.iter().find(|&&(e, _)| e == encoding) {
+            return Ok(data.clone());
+        }
+        let data = Arc::new(encoding.serialize(&self.event)?);
+        encoded.push((encoding, data.clone()));
+        Ok(data)
+    }
+}
+
+impl Deref for SharedEvent {
+    type Target = Event;
+
+    fn deref(&self) -> &Event {
+        &self.event
+    }
+}
+
+impl PartialEq for SharedEvent {
+    fn eq(&self, other: &SharedEvent) -> bool {
+        self.event == other.event
+    }
+}
+
+impl Serialize for SharedEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.event.serialize(serializer)
+    }
+}
+
+lazy_static! {
+    // Stands in for the event when serializing the rest of a
+    // ServerEventMessage, and is then replaced by the event's
+    // shared encoding
+    static ref PLACEHOLDER: Arc<SharedEvent> =
+        Arc::new(SharedEvent::new(Event::Leave(Leave { id: 0 })));
+}
+
+impl ServerEventMessage {
+    // Encodes the message as part of the message returned by 'wrap',
+    // serializing only the fields which differ between participants
+    // and copying in the shared encoding of the event.
+    fn encode_framed<T, F>(
+        &self,
+        encoding: Encoding,
+        wrap: F,
+    ) -> Result<Vec<u8>, MessageStreamError>
+    where
+        T: Serialize,
+        F: FnOnce(ServerEventMessage) -> T,
+    {
+        let mut frame = encoding.serialize(&wrap(ServerEventMessage {
+            client_seq: self.client_seq,
+            seq: self.seq,
+            event: PLACEHOLDER.clone(),
+        }))?;
+        let placeholder = PLACEHOLDER.encoded(encoding)?;
+        let event = self.event.encoded(encoding)?;
+        // the event is the last field, so at most a few closing
+        // delimiters follow the placeholder
+        let start = frame
+            .windows(placeholder.len())
+            .rposition(|window| window == &placeholder[..])
+            .expect("encoded message must contain the placeholder event");
+        frame.splice(start..start + placeholder.len(), event.iter().cloned());
+        Ok(frame)
+    }
 }
 
 /// Client successfully connected
@@ -35,17 +147,94 @@ pub struct ServerEventMessage {
 pub struct ConnectedMessage {
     /// The new client's participant ID
     pub id: ParticipantId,
+    /// The version of the message format used for the connection,
+    /// omitted for version 1 clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// The other participants in the document session when the
+    /// client joined, omitted if there are none. Join and Leave
+    /// events the client catches up on may already be reflected
+    /// here.
+    #[serde(skip_serializing_if = "Participants::is_empty")]
+    pub participants: Participants,
+}
+
+/// A snapshot of the document, replacing the client's copy after it
+/// fell too far behind to be sent every event. Edits the client sent
+/// after 'client_seq' are acknowledged by later events, as usual.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ResyncMessage {
+    /// The most recently applied client SequenceId
+    pub client_seq: SequenceId,
+    /// The SequenceId of the latest Event in the snapshot
+    pub seq: SequenceId,
+    /// The document's content
+    pub content: String,
+    /// The other participants in the document session
+    #[serde(skip_serializing_if = "Participants::is_empty")]
+    pub participants: Participants,
+}
+
+/// Exclusive edit rights for the document have been claimed or
+/// released
+#[derive(Serialize, Debug, PartialEq)]
+pub struct LockMessage {
+    /// The participant holding the lock, or None if the document is
+    /// unlocked. While locked, edits from other participants which
+    /// change the document content are discarded.
+    pub holder: Option<ParticipantId>,
+}
+
+/// A message from the client was rejected. Edits are processed one
+/// at a time, so an error in response to a ClientEdit always refers
+/// to the oldest edit not yet acknowledged via `client_seq`. The
+/// rejected edit is discarded and its `client_seq` is never
+/// acknowledged.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct ErrorMessage {
+    /// The type of error
+    pub code: ErrorCode,
+    /// A human readable description of the error
+    pub reason: String,
+}
+
+/// The types of error which may be reported via a
+/// ServerMessage::Error
+#[derive(Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum ErrorCode {
+    /// The edit's parent_seq is ahead of the latest event in the
+    /// store
+    InvalidParentSeq,
+    /// The edit could not be applied to the current document
+    InvalidEdit,
+    /// The participant's role does not permit the edit
+    PermissionDenied,
+    /// A multiplexed message was sent for a document which is not
+    /// subscribed, or the subscription failed
+    InvalidChannel,
+    /// The edit claims to be by a participant other than the one
+    /// who sent it, or by a different user than the participant
+    /// joined as
+    InvalidAuthor,
+    /// The edit would take the participant's user over their storage
+    /// quota
+    QuotaExceeded,
 }
 
 /// Message sent from the client to the server
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub enum ClientMessage {
     /// A change was made to the document content
     ClientEdit(ClientEditMessage),
+    /// Claim exclusive edit rights for the document. Has no effect if
+    /// another participant already holds the lock.
+    Lock,
+    /// Release exclusive edit rights for the document
+    Unlock,
 }
 
 /// A change made to the document content by the client
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct ClientEditMessage {
     /// The most recently applied server SequenceId before this client
     /// edit was made
@@ -54,6 +243,63 @@ pub struct ClientEditMessage {
     pub client_seq: SequenceId,
     /// The operations which describe the change to the document
     pub operations: Vec<Operation>,
+    /// The client's ParticipantId, from its ConnectedMessage. Edits
+    /// are always attributed to the participant who sent them, so
+    /// this is optional, but an edit naming another participant is
+    /// rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<ParticipantId>,
+}
+
+/// Message sent from the client over a connection shared by several
+/// documents
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub enum MultiplexClientMessage {
+    /// Join the document session at 'path'. The first message on the
+    /// new channel is a ServerMessage::Connected.
+    Subscribe(SubscribeMessage),
+    /// Leave the document session at 'path'
+    Unsubscribe(UnsubscribeMessage),
+    /// A message for the document session at 'path'
+    Message(ChannelClientMessage),
+}
+
+/// Request to join a document session on a multiplexed connection
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct SubscribeMessage {
+    /// The document's path
+    pub path: String,
+    /// The most recent SequenceId the client has seen for the
+    /// document, later events are sent after connecting
+    #[serde(default)]
+    pub seq: SequenceId,
+}
+
+/// Request to leave a document session on a multiplexed connection
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct UnsubscribeMessage {
+    /// The document's path
+    pub path: String,
+}
+
+/// A ClientMessage for one of the documents on a multiplexed
+/// connection
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct ChannelClientMessage {
+    /// The subscribed document's path
+    pub path: String,
+    /// The message for the document session
+    pub message: ClientMessage,
+}
+
+/// A ServerMessage from one of the documents on a multiplexed
+/// connection
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ChannelServerMessage {
+    /// The document's path, as given when subscribing
+    pub path: String,
+    /// The message from the document session
+    pub message: ServerMessage,
 }
 
 /// An error when attempting to read Events from a Stream, or write
@@ -68,7 +314,15 @@ pub enum MessageStreamError {
     /// Errors from underlying protocol or event store
     Transport {
         /// The original error
-        error: Box<Debug + Send>,
+        error: Box<dyn Debug + Send>,
+    },
+    /// The client is not reading messages quickly enough and too
+    /// many messages are waiting to be sent
+    TooSlow,
+    /// The client sent a message larger than the maximum message size
+    TooLarge {
+        /// The size of the rejected message in bytes
+        size: usize,
     },
 }
 
@@ -88,34 +342,475 @@ impl From<StoreError> for MessageStreamError {
     }
 }
 
-/// Wraps an Sink + Stream which handles Strings so it can read
-/// ClientMessages and write ServerMessages.
+/// The current version of the ClientMessage and ServerMessage
+/// format. Version 1 clients predate the Lock, Error, Shutdown and
+/// Kicked messages, version 2 clients predate Events, and version 3
+/// clients predate Resync.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// The oldest version of the message format still supported
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The largest encoded ClientMessage accepted by default, in bytes
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// The formats ClientMessages and ServerMessages may be encoded in
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    /// JSON sent as Text frames, the default
+    Json,
+    /// MessagePack sent as Binary frames, with structs encoded as
+    /// maps using their field names
+    MessagePack,
+}
+
+impl Encoding {
+    /// Returns true if messages should be sent as Binary frames
+    pub fn is_binary(&self) -> bool {
+        *self == Encoding::MessagePack
+    }
+
+    /// Decodes a ClientMessage
+    pub fn decode(&self, data: &[u8]) -> Result<ClientMessage, MessageStreamError> {
+        self.deserialize(data)
+    }
+
+    /// Encodes a ServerMessage
+    pub fn encode(&self, msg: &ServerMessage) -> Result<Vec<u8>, MessageStreamError> {
+        msg.encode(*self)
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, MessageStreamError> {
+        let result = match *self {
+            Encoding::Json => serde_json::from_slice(data).map_err(|err| format!("{}", err)),
+            Encoding::MessagePack => rmp_serde::from_slice(data).map_err(|err| format!("{}", err)),
+        };
+        result.map_err(|reason| MessageStreamError::InvalidMessage { reason })
+    }
+
+    fn serialize<T: Serialize>(&self, msg: &T) -> Result<Vec<u8>, MessageStreamError> {
+        let result = match *self {
+            Encoding::Json => serde_json::to_vec(msg).map_err(|err| format!("{}", err)),
+            Encoding::MessagePack => {
+                rmp_serde::to_vec_named(msg).map_err(|err| format!("{}", err))
+            }
+        };
+        result.map_err(|reason| MessageStreamError::InvalidMessage { reason })
+    }
+}
+
+/// The message format version and Encoding used by a connection.
+/// Clients choose a Protocol by requesting the corresponding
+/// WebSocket sub-protocol via the Sec-WebSocket-Protocol header,
+/// e.g. "tamawiki.v2.json" or "tamawiki.v1.json". Clients which do
+/// not request a sub-protocol use the current version with JSON.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Protocol {
+    /// The message format version
+    pub version: u32,
+    /// The message encoding
+    pub encoding: Encoding,
+}
+
+impl Protocol {
+    /// Returns the WebSocket sub-protocol name for the Protocol
+    pub fn name(&self) -> String {
+        let encoding = match self.encoding {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+        };
+        format!("tamawiki.v{}.{}", self.version, encoding)
+    }
+
+    /// Returns true if clients using the Protocol understand
+    /// ServerMessage::Resync, so can be resynced instead of
+    /// disconnected when they fall behind
+    pub fn supports_resync(&self) -> bool {
+        self.version >= 4
+    }
+
+    /// Picks the first supported Protocol from the comma separated
+    /// list of sub-protocols requested by the client, or None if no
+    /// requested sub-protocol is supported.
+    pub fn negotiate(requested: &str) -> Option<Protocol> {
+        requested
+            .split(',')
+            .filter_map(|name| Protocol::parse(name.trim()))
+            .next()
+    }
+
+    // Parses a sub-protocol name, returning None if the name is not
+    // recognised or the version is not supported.
+    fn parse(name: &str) -> Option<Protocol> {
+        let mut parts = name.splitn(3, '.');
+        if parts.next() != Some("tamawiki") {
+            return None;
+        }
+        let version = match parts.next() {
+            Some(v) if v.starts_with('v') => match v[1..].parse() {
+                Ok(version) => version,
+                Err(_) => return None,
+            },
+            _ => return None,
+        };
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return None;
+        }
+        let encoding = match parts.next() {
+            Some("json") => Encoding::Json,
+            // MessagePack encoding was introduced in version 2
+            Some("msgpack") if version >= 2 => Encoding::MessagePack,
+            _ => return None,
+        };
+        Some(Protocol { version, encoding })
+    }
+
+    /// Converts a ServerMessage to the Protocol's version of the
+    /// message format. The result is empty if the message has no
+    /// equivalent in that version, and may contain multiple messages
+    /// if it must be split up.
+    pub fn downgrade(&self, msg: ServerMessage) -> Vec<ServerMessage> {
+        match msg {
+            ServerMessage::Events(events) if self.version < 3 => {
+                events.into_iter().map(ServerMessage::Event).collect()
+            }
+            ServerMessage::Connected(ConnectedMessage { id, .. }) if self.version < 2 => {
+                vec![ServerMessage::Connected(ConnectedMessage {
+                    id,
+                    version: None,
+                    participants: Participants::new(),
+                })]
+            }
+            ServerMessage::Shutdown
+            | ServerMessage::Lock(_)
+            | ServerMessage::Error(_)
+            | ServerMessage::Kicked
+                if self.version < 2 =>
+            {
+                vec![]
+            }
+            msg => vec![msg],
+        }
+    }
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol {
+            version: PROTOCOL_VERSION,
+            encoding: Encoding::Json,
+        }
+    }
+}
+
+/// Wraps an Sink + Stream which handles encoded message payloads so
+/// it can read ClientMessages and write ServerMessages. Payloads
+/// larger than 'max_size' bytes are not decoded and end the stream
+/// with MessageStreamError::TooLarge.
 pub fn message_stream<T, E>(
     stream: T,
+    protocol: Protocol,
+    max_size: usize,
 ) -> impl Stream<Item = ClientMessage, Error = MessageStreamError>
          + Sink<SinkItem = ServerMessage, SinkError = MessageStreamError>
 where
     E: Debug + Send + 'static,
-    T: Stream<Item = String, Error = E> + Sink<SinkItem = String, SinkError = E>,
+    T: Stream<Item = Vec<u8>, Error = E> + Sink<SinkItem = Vec<u8>, SinkError = E>,
+{
+    encoded_stream(stream, protocol.encoding, max_size)
+        .with_flat_map(move |msg| stream::iter_ok(protocol.downgrade(msg)))
+}
+
+/// Like message_stream, but reads MultiplexClientMessages and writes
+/// ChannelServerMessages for connections shared by several documents.
+pub fn multiplexed_message_stream<T, E>(
+    stream: T,
+    protocol: Protocol,
+    max_size: usize,
+) -> impl Stream<Item = MultiplexClientMessage, Error = MessageStreamError>
+         + Sink<SinkItem = ChannelServerMessage, SinkError = MessageStreamError>
+where
+    E: Debug + Send + 'static,
+    T: Stream<Item = Vec<u8>, Error = E> + Sink<SinkItem = Vec<u8>, SinkError = E>,
+{
+    encoded_stream(stream, protocol.encoding, max_size).with_flat_map(
+        move |msg: ChannelServerMessage| {
+            let path = msg.path;
+            stream::iter_ok(protocol.downgrade(msg.message).into_iter().map(
+                move |message| ChannelServerMessage {
+                    path: path.clone(),
+                    message,
+                },
+            ))
+        },
+    )
+}
+
+// Messages sent to the client, which reuse the shared encoding of
+// any Event they contain
+trait Encode: Serialize {
+    fn encode(&self, encoding: Encoding) -> Result<Vec<u8>, MessageStreamError>;
+}
+
+impl Encode for ServerMessage {
+    fn encode(&self, encoding: Encoding) -> Result<Vec<u8>, MessageStreamError> {
+        match *self {
+            ServerMessage::Event(ref msg) => msg.encode_framed(encoding, ServerMessage::Event),
+            _ => encoding.serialize(self),
+        }
+    }
+}
+
+impl Encode for ChannelServerMessage {
+    fn encode(&self, encoding: Encoding) -> Result<Vec<u8>, MessageStreamError> {
+        match self.message {
+            ServerMessage::Event(ref msg) => msg.encode_framed(encoding, |frame| {
+                ChannelServerMessage {
+                    path: self.path.clone(),
+                    message: ServerMessage::Event(frame),
+                }
+            }),
+            _ => encoding.serialize(self),
+        }
+    }
+}
+
+// Decodes incoming payloads as I and encodes outgoing O messages
+// using 'encoding'.
+fn encoded_stream<T, E, I, O>(
+    stream: T,
+    encoding: Encoding,
+    max_size: usize,
+) -> impl Stream<Item = I, Error = MessageStreamError>
+         + Sink<SinkItem = O, SinkError = MessageStreamError>
+where
+    E: Debug + Send + 'static,
+    T: Stream<Item = Vec<u8>, Error = E> + Sink<SinkItem = Vec<u8>, SinkError = E>,
+    I: DeserializeOwned,
+    O: Encode,
 {
     stream
         .map_err(|err| MessageStreamError::Transport {
             error: Box::new(err),
-        }).and_then(|text| {
-            FutureResult::from(serde_json::from_str(&text).map_err(|err| {
-                MessageStreamError::InvalidMessage {
-                    reason: format!("{}", err),
-                }
-            }))
+        }).and_then(move |data| {
+            let _span = info_span!("websocket.decode", bytes = data.len() as u64).entered();
+            FutureResult::from(if data.len() > max_size {
+                Err(MessageStreamError::TooLarge { size: data.len() })
+            } else {
+                encoding.deserialize::<I>(&data)
+            })
         }).sink_map_err(|err| MessageStreamError::Transport {
             error: Box::new(err),
-        }).with(|msg| {
-            FutureResult::from(serde_json::to_string(&msg).map_err(|err| {
-                MessageStreamError::InvalidMessage {
-                    reason: format!("{}", err),
-                }
-            }))
-        })
+        }).with(move |msg: O| FutureResult::from(msg.encode(encoding)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Edit, Insert};
+    use futures::{Async, AsyncSink, Poll, StartSend};
+    use std::collections::VecDeque;
+
+    // Reads the payloads in 'incoming' and collects written payloads
+    // in 'outgoing'
+    #[derive(Default)]
+    struct Loopback {
+        incoming: VecDeque<Vec<u8>>,
+        outgoing: Vec<Vec<u8>>,
+    }
+
+    impl Stream for Loopback {
+        type Item = Vec<u8>;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+            Ok(Async::Ready(self.incoming.pop_front()))
+        }
+    }
+
+    impl Sink for Loopback {
+        type SinkItem = Vec<u8>;
+        type SinkError = ();
+
+        fn start_send(&mut self, item: Vec<u8>) -> StartSend<Vec<u8>, ()> {
+            self.outgoing.push(item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn negotiate_protocol() {
+        assert_eq!(
+            Protocol::negotiate("tamawiki.v2.json"),
+            Some(Protocol {
+                version: 2,
+                encoding: Encoding::Json,
+            })
+        );
+        assert_eq!(
+            Protocol::negotiate("chat, tamawiki.v5.json, tamawiki.v2.msgpack, tamawiki.v1.json"),
+            Some(Protocol {
+                version: 2,
+                encoding: Encoding::MessagePack,
+            })
+        );
+        assert_eq!(
+            Protocol::negotiate("tamawiki.v1.json").map(|p| p.name()),
+            Some(String::from("tamawiki.v1.json"))
+        );
+        assert_eq!(Protocol::negotiate("tamawiki.v1.msgpack"), None);
+        assert_eq!(Protocol::negotiate("tamawiki.json"), None);
+        assert_eq!(Protocol::negotiate("chat"), None);
+    }
+
+    #[test]
+    fn downgrade_server_messages() {
+        let v1 = Protocol::negotiate("tamawiki.v1.json").unwrap();
+        let mut participants = Participants::new();
+        participants.entries.insert(2, Default::default());
+        let connected = ServerMessage::Connected(ConnectedMessage {
+            id: 1,
+            version: Some(PROTOCOL_VERSION),
+            participants,
+        });
+        assert_eq!(
+            v1.downgrade(connected),
+            vec![ServerMessage::Connected(ConnectedMessage {
+                id: 1,
+                version: None,
+                participants: Participants::new(),
+            })]
+        );
+        assert_eq!(v1.downgrade(ServerMessage::Shutdown), vec![]);
+        assert_eq!(
+            Protocol::default().downgrade(ServerMessage::Shutdown),
+            vec![ServerMessage::Shutdown]
+        );
+    }
+
+    #[test]
+    fn downgrade_batched_events() {
+        let event = |seq| ServerEventMessage {
+            client_seq: 0,
+            seq,
+            event: Arc::new(SharedEvent::new(Event::Leave(Leave { id: 1 }))),
+        };
+        let events = || ServerMessage::Events(vec![event(1), event(2)]);
+        let v2 = Protocol::negotiate("tamawiki.v2.json").unwrap();
+        assert_eq!(
+            v2.downgrade(events()),
+            vec![ServerMessage::Event(event(1)), ServerMessage::Event(event(2))]
+        );
+        assert_eq!(Protocol::default().downgrade(events()), vec![events()]);
+    }
+
+    #[test]
+    fn decode_client_messages() {
+        let msg = ClientMessage::ClientEdit(ClientEditMessage {
+            parent_seq: 1,
+            client_seq: 2,
+            operations: vec![Operation::Insert(Insert {
+                pos: 0,
+                content: String::from("test"),
+            })],
+            author: Some(3),
+        });
+        let json = serde_json::to_vec(&msg).unwrap();
+        assert_eq!(Encoding::Json.decode(&json).unwrap(), msg);
+        let msgpack = rmp_serde::to_vec_named(&msg).unwrap();
+        assert_eq!(Encoding::MessagePack.decode(&msgpack).unwrap(), msg);
+        assert!(Encoding::Json.decode(&msgpack).is_err());
+        // clients need not say who they are
+        let json = br#"{"ClientEdit": {"parent_seq": 1, "client_seq": 2, "operations": []}}"#;
+        match Encoding::Json.decode(json).unwrap() {
+            ClientMessage::ClientEdit(edit) => assert_eq!(edit.author, None),
+            msg => panic!("Expected ClientEdit, got: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn reject_large_messages() {
+        let small = b"\"Lock\"".to_vec();
+        let large = format!("\"Lock\"{}", " ".repeat(64)).into_bytes();
+        let loopback = Loopback {
+            incoming: vec![small, large].into_iter().collect(),
+            ..Default::default()
+        };
+        let mut stream = message_stream(loopback, Protocol::default(), 32);
+        assert_eq!(stream.poll().unwrap(), Async::Ready(Some(ClientMessage::Lock)));
+        match stream.poll() {
+            Err(MessageStreamError::TooLarge { size: 70 }) => (),
+            result => panic!("Expected TooLarge error, got: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn encode_server_messages() {
+        let msg = ServerMessage::Connected(ConnectedMessage {
+            id: 123,
+            version: Some(2),
+            participants: Participants::new(),
+        });
+        assert_eq!(
+            Encoding::Json.encode(&msg).unwrap(),
+            b"{\"Connected\":{\"id\":123,\"version\":2}}".to_vec()
+        );
+        let msg = ServerMessage::Resync(ResyncMessage {
+            client_seq: 1,
+            seq: 5,
+            content: String::from("Hello"),
+            participants: Participants::new(),
+        });
+        assert_eq!(
+            Encoding::Json.encode(&msg).unwrap(),
+            b"{\"Resync\":{\"client_seq\":1,\"seq\":5,\"content\":\"Hello\"}}".to_vec()
+        );
+    }
+
+    #[test]
+    fn encode_shared_events() {
+        let event = Arc::new(SharedEvent::new(Event::Edit(Edit {
+            author: 1,
+            operations: vec![Operation::Insert(Insert {
+                pos: 0,
+                content: String::from("Hello"),
+            })],
+        })));
+        let msg = |client_seq| {
+            ServerMessage::Event(ServerEventMessage {
+                client_seq,
+                seq: 2,
+                event: event.clone(),
+            })
+        };
+        for &encoding in &[Encoding::Json, Encoding::MessagePack] {
+            assert_eq!(
+                encoding.encode(&msg(1)).unwrap(),
+                encoding.serialize(&msg(1)).unwrap()
+            );
+            assert_eq!(
+                encoding.encode(&msg(1000)).unwrap(),
+                encoding.serialize(&msg(1000)).unwrap()
+            );
+            let channel = ChannelServerMessage {
+                path: String::from("index.html"),
+                message: msg(3),
+            };
+            assert_eq!(
+                channel.encode(encoding).unwrap(),
+                encoding.serialize(&channel).unwrap()
+            );
+            // the event was only serialized once
+            assert!(Arc::ptr_eq(
+                &event.encoded(encoding).unwrap(),
+                &event.encoded(encoding).unwrap()
+            ));
+        }
+    }
 }
 
 // #[cfg(test)]
@@ -0,0 +1,184 @@
+//! Activity counters for DocumentSessions, used to find out which
+//! documents are currently being edited the most.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The period over which the edit rate is averaged
+const RATE_WINDOW: u64 = 60;
+
+/// A snapshot of the activity within a single DocumentSession
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SessionMetrics {
+    /// Number of participants currently connected to the session
+    pub participants: usize,
+    /// Total number of edits written during the session
+    pub edits: u64,
+    /// Edits per second, averaged over the last minute
+    pub edits_per_sec: f64,
+    /// Mean time taken to transform an incoming edit against
+    /// concurrent events, in milliseconds
+    pub transform_latency_ms: f64,
+    /// Number of incoming edits which were transformed against at
+    /// least one concurrent edit from another participant
+    pub conflicts: u64,
+    /// Number of additional operations created by splitting incoming
+    /// operations during transformation (e.g. a Delete split by a
+    /// concurrent Insert)
+    pub split_operations: u64,
+    /// Mean number of concurrent edits each incoming edit was
+    /// transformed against
+    pub mean_rebase_depth: f64,
+    /// Number of times a participant fell too far behind the
+    /// session's events and was sent a snapshot of the document
+    /// instead
+    pub resyncs: u64,
+}
+
+/// Collects activity counters for a DocumentSession. A snapshot of
+/// the current values can be taken at any time using `snapshot()`.
+#[derive(Debug, Default)]
+pub struct MetricsRecorder {
+    participants: usize,
+    edits: u64,
+    // Times of edits made within the last RATE_WINDOW seconds
+    recent_edits: VecDeque<Instant>,
+    transforms: u64,
+    transform_time: Duration,
+    conflicts: u64,
+    split_operations: u64,
+    rebase_depth: u64,
+    resyncs: u64,
+}
+
+impl MetricsRecorder {
+    /// Records a new participant joining the session
+    pub fn joined(&mut self) {
+        self.participants += 1;
+    }
+
+    /// Records a participant leaving the session
+    pub fn left(&mut self) {
+        self.participants = self.participants.saturating_sub(1);
+    }
+
+    /// Records an edit being written to the store
+    pub fn edited(&mut self, now: Instant) {
+        self.edits += 1;
+        self.recent_edits.push_back(now);
+        self.expire(now);
+    }
+
+    /// Records the transformation of an incoming edit: the time
+    /// taken, the number of concurrent edits it was transformed
+    /// against, and the number of operations added by splitting.
+    pub fn transformed(&mut self, elapsed: Duration, depth: usize, split: usize) {
+        self.transforms += 1;
+        self.transform_time += elapsed;
+        if depth > 0 {
+            self.conflicts += 1;
+        }
+        self.rebase_depth += depth as u64;
+        self.split_operations += split as u64;
+    }
+
+    /// Records a participant being resynced with a snapshot of the
+    /// document
+    pub fn resynced(&mut self) {
+        self.resyncs += 1;
+    }
+
+    /// Returns the current values of all counters
+    pub fn snapshot(&mut self, now: Instant) -> SessionMetrics {
+        self.expire(now);
+        let (transform_latency_ms, mean_rebase_depth) = if self.transforms == 0 {
+            (0.0, 0.0)
+        } else {
+            (
+                millis(self.transform_time) / self.transforms as f64,
+                self.rebase_depth as f64 / self.transforms as f64,
+            )
+        };
+        SessionMetrics {
+            participants: self.participants,
+            edits: self.edits,
+            edits_per_sec: self.recent_edits.len() as f64 / RATE_WINDOW as f64,
+            transform_latency_ms,
+            conflicts: self.conflicts,
+            split_operations: self.split_operations,
+            mean_rebase_depth,
+            resyncs: self.resyncs,
+        }
+    }
+
+    // Forgets edits which fall outside the rate window
+    fn expire(&mut self, now: Instant) {
+        let window = Duration::from_secs(RATE_WINDOW);
+        while let Some(&t) = self.recent_edits.front() {
+            if now.duration_since(t) > window {
+                self.recent_edits.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn participant_counts() {
+        let mut recorder = MetricsRecorder::default();
+        recorder.joined();
+        recorder.joined();
+        recorder.left();
+        assert_eq!(recorder.snapshot(Instant::now()).participants, 1);
+        recorder.left();
+        recorder.left();
+        assert_eq!(recorder.snapshot(Instant::now()).participants, 0);
+    }
+
+    #[test]
+    fn edit_rate_expires_old_edits() {
+        let mut recorder = MetricsRecorder::default();
+        let start = Instant::now();
+        for _ in 0..30 {
+            recorder.edited(start);
+        }
+        let metrics = recorder.snapshot(start);
+        assert_eq!(metrics.edits, 30);
+        assert_eq!(metrics.edits_per_sec, 0.5);
+
+        let later = start + Duration::from_secs(RATE_WINDOW + 1);
+        let metrics = recorder.snapshot(later);
+        assert_eq!(metrics.edits, 30);
+        assert_eq!(metrics.edits_per_sec, 0.0);
+    }
+
+    #[test]
+    fn mean_transform_latency() {
+        let mut recorder = MetricsRecorder::default();
+        assert_eq!(recorder.snapshot(Instant::now()).transform_latency_ms, 0.0);
+        recorder.transformed(Duration::from_millis(10), 0, 0);
+        recorder.transformed(Duration::from_millis(20), 0, 0);
+        assert_eq!(recorder.snapshot(Instant::now()).transform_latency_ms, 15.0);
+    }
+
+    #[test]
+    fn conflict_counts() {
+        let mut recorder = MetricsRecorder::default();
+        assert_eq!(recorder.snapshot(Instant::now()).mean_rebase_depth, 0.0);
+        recorder.transformed(Duration::from_millis(1), 0, 0);
+        recorder.transformed(Duration::from_millis(1), 3, 1);
+        recorder.transformed(Duration::from_millis(1), 2, 0);
+        let metrics = recorder.snapshot(Instant::now());
+        assert_eq!(metrics.conflicts, 2);
+        assert_eq!(metrics.split_operations, 1);
+        assert_eq!(metrics.mean_rebase_depth, 5.0 / 3.0);
+    }
+}
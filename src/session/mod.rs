@@ -1,17 +1,29 @@
 //! Co-ordinates store updates and notifications
-use futures::future::{self, Future};
+use futures::future::{self, Either, Future};
 use futures::stream::Stream;
-use futures::task::Task;
-use std::collections::HashMap;
+use futures::sync::mpsc::UnboundedSender;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+use tokio::executor::{DefaultExecutor, Executor};
+use tracing::{field, info_span};
+use tracing_futures::Instrument;
 
-use document::{Edit, Event, Join, Leave, ParticipantId};
-use store::{SequenceId, Store, StoreError};
+use crate::document::{Document, Edit, Event, Join, Leave, Operation, ParticipantId, Role};
+use crate::quota::{self, Quotas};
+use crate::store::{SequenceId, Store, StoreError};
 
+pub mod feed;
 pub mod message;
+pub mod metrics;
+pub mod multiplex;
 pub mod participant;
+pub mod playback;
 
+use self::message::{ErrorCode, ErrorMessage, SharedEvent};
+use self::metrics::{MetricsRecorder, SessionMetrics};
 use self::participant::Participant;
 
 /// Provides access to DocumentSessions.
@@ -25,6 +37,11 @@ pub struct DocumentSessionManager<T: Store + Sync> {
 struct DocumentSessionManagerData<T: Store + Sync> {
     sessions: HashMap<PathBuf, WeakDocumentSession<T>>,
     store: T,
+    // Set once shutdown() has been called, so sessions created
+    // afterwards are immediately shut down too.
+    shutting_down: bool,
+    // Limits on how much each user's edits may add, if any
+    quotas: Option<Quotas>,
 }
 
 impl<T: Store + Sync> DocumentSessionManagerData<T> {
@@ -32,6 +49,8 @@ impl<T: Store + Sync> DocumentSessionManagerData<T> {
         Self {
             sessions: Default::default(),
             store,
+            shutting_down: false,
+            quotas: None,
         }
     }
 }
@@ -46,32 +65,144 @@ impl<T: Store + Sync> DocumentSessionManager<T> {
 
     /// Join an existing or new DocumentSession for the given path. A new
     /// DocumentSession is created automatically when the path has no
-    /// active participants.
+    /// active participants. The participant's Role limits which
+    /// changes they can make to the document, and 'user' identifies
+    /// the authenticated user (if any) in the participant's Join event.
     pub fn join(
         &self,
         path: &Path,
         start_seq: SequenceId,
+        role: Role,
+        user: Option<String>,
     ) -> impl Future<Item = Participant<T>, Error = StoreError> {
-        let mut session = {
+        let (mut session, created) = {
             let mut data = self.data.lock().unwrap();
-            data.sessions
-                .get(path)
-                .and_then(|s| s.upgrade())
-                .or_else(|| {
+            match data.sessions.get(path).and_then(|s| s.upgrade()) {
+                Some(s) => (s, false),
+                None => {
                     let s = DocumentSession {
                         data: Arc::new(Mutex::new(DocumentSessionData {
                             store: data.store.clone(),
                             path: PathBuf::from(path),
-                            next_id: Default::default(),
                             last_seq: None,
-                            waiting_tasks: vec![],
+                            subscribers: vec![],
+                            document: None,
+                            metrics: Default::default(),
+                            shutting_down: data.shutting_down,
+                            quotas: data.quotas.clone(),
+                            kicked: vec![],
+                            lock_holder: None,
+                            lock_version: 0,
                         })),
                     };
                     data.sessions.insert(PathBuf::from(path), s.downgrade());
-                    Some(s)
-                }).unwrap()
+                    (s, true)
+                }
+            }
+        };
+        if created {
+            session.watch_store();
+        }
+        session.join(start_seq, role, user)
+    }
+
+    /// Rejects edits which would take the user who made them over
+    /// their quota, and counts accepted edits towards it. Applies to
+    /// sessions started afterwards.
+    pub fn set_quotas(&self, quotas: Quotas) {
+        self.data.lock().unwrap().quotas = Some(quotas);
+    }
+
+    // Returns true once shutdown() has been called
+    fn shutting_down(&self) -> bool {
+        self.data.lock().unwrap().shutting_down
+    }
+
+    /// Returns true if new participants can join documents. This is
+    /// false once shutdown() has been called, or if a thread panicked
+    /// while holding the manager's lock.
+    pub fn is_ready(&self) -> bool {
+        match self.data.lock() {
+            Ok(data) => !data.shutting_down,
+            Err(_) => false,
+        }
+    }
+
+    /// Tells every active DocumentSession the server is shutting
+    /// down. Participants will write any pending edits to the store,
+    /// receive a ServerMessage::Shutdown, then reach the end of
+    /// their message stream.
+    pub fn shutdown(&self) {
+        let sessions: Vec<DocumentSession<T>> = {
+            let mut data = self.data.lock().unwrap();
+            data.shutting_down = true;
+            data.sessions.values().filter_map(|s| s.upgrade()).collect()
         };
-        session.join(start_seq)
+        for session in sessions {
+            session.shutdown();
+        }
+    }
+
+    /// Removes a participant from the active DocumentSession for the
+    /// given path. The participant receives a ServerMessage::Kicked,
+    /// then reaches the end of their message stream. Returns false
+    /// if the document has no active session.
+    pub fn kick(&self, path: &Path, id: ParticipantId) -> bool {
+        let session = {
+            let data = self.data.lock().unwrap();
+            data.sessions.get(path).and_then(|s| s.upgrade())
+        };
+        match session {
+            Some(session) => {
+                session.kick(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the latest content of the document at 'path'. While
+    /// the document has an active DocumentSession, its copy of the
+    /// document is used when it is up to date with the store, instead
+    /// of replaying every Event.
+    pub fn content(
+        &self,
+        path: &Path,
+    ) -> impl Future<Item = (SequenceId, Document), Error = StoreError> {
+        let (store, session) = {
+            let data = self.data.lock().unwrap();
+            let session = data.sessions.get(path).and_then(|s| s.upgrade());
+            (data.store.clone(), session)
+        };
+        let path = PathBuf::from(path);
+        match session.and_then(|session| session.document()) {
+            Some((seq, document)) => Either::A(store.seq(&path).and_then(move |head| {
+                if head == seq {
+                    Either::A(future::ok((seq, document)))
+                } else {
+                    Either::B(store.content(&path))
+                }
+            })),
+            None => Either::B(store.content(&path)),
+        }
+    }
+
+    /// Returns a snapshot of the activity counters for every
+    /// DocumentSession which currently has participants, keyed by
+    /// document path.
+    pub fn metrics(&self) -> BTreeMap<PathBuf, SessionMetrics> {
+        let sessions: Vec<DocumentSession<T>> = {
+            let data = self.data.lock().unwrap();
+            data.sessions.values().filter_map(|s| s.upgrade()).collect()
+        };
+        let now = Instant::now();
+        sessions
+            .into_iter()
+            .map(|session| {
+                let mut data = session.data.lock().unwrap();
+                let metrics = data.metrics.snapshot(now);
+                (data.path.clone(), metrics)
+            }).collect()
     }
 }
 
@@ -94,12 +225,102 @@ struct WeakDocumentSession<T: Store + Sync> {
 struct DocumentSessionData<T: Store + Sync> {
     store: T,
     path: PathBuf,
-    next_id: ParticipantId,
-    // The sequence id of the last event received during this session,
-    // or None if no events have been received yet.
+    // The sequence id of the last event broadcast during this
+    // session, or None if no events have been broadcast yet.
     last_seq: Option<SequenceId>,
-    // Participant event streams parked until a new sequence ID is ready.
-    waiting_tasks: Vec<Task>,
+    // Channels to each Participant for broadcasting new events and
+    // changes to the session state.
+    subscribers: Vec<UnboundedSender<Broadcast>>,
+    // The document content as of the given SequenceId, used to
+    // validate incoming edits and to serve the document without
+    // reading it from the store. Loaded from the store when the first
+    // participant joins and kept up to date as events are broadcast.
+    document: Option<(SequenceId, Document)>,
+    // Activity counters reported by DocumentSessionManager::metrics()
+    metrics: MetricsRecorder,
+    // Participants should disconnect once they have caught up
+    shutting_down: bool,
+    // Limits on how much each user's edits may add, if any
+    quotas: Option<Quotas>,
+    // Participants removed from the session who have not left yet
+    kicked: Vec<ParticipantId>,
+    // The participant with exclusive edit rights, if any
+    lock_holder: Option<ParticipantId>,
+    // Incremented each time lock_holder changes, so Participants
+    // can tell when to send a new ServerMessage::Lock
+    lock_version: u64,
+}
+
+/// Asks a Participant, or every Participant in a Multiplexer, to
+/// resync: to discard the events it has not sent yet, and send a
+/// snapshot of the document in their place. The handle is shared,
+/// so whatever is sending the participant's messages to the client
+/// can ask for a resync when the client falls too far behind.
+#[derive(Clone, Debug, Default)]
+pub struct ResyncHandle(Arc<AtomicBool>);
+
+impl ResyncHandle {
+    /// Resyncs the next time the participant is polled
+    pub fn resync(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    // Returns true if a resync was asked for since the last call
+    fn requested(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+// Messages sent from a DocumentSession to its Participants
+#[derive(Clone)]
+enum Broadcast {
+    // A new Event was written to the store
+    Event(SequenceId, Arc<SharedEvent>),
+    // The lock holder changed or the session is shutting down
+    StateChanged,
+}
+
+impl<T: Store + Sync> DocumentSessionData<T> {
+    // Sends the message to every Participant, forgetting any
+    // Participants which have since been dropped.
+    fn broadcast(&mut self, msg: Broadcast) {
+        self.subscribers
+            .retain(|tx| tx.unbounded_send(msg.clone()).is_ok());
+    }
+
+    // Tells all Participants to check for changes to the session
+    // state.
+    fn wake_participants(&mut self) {
+        self.broadcast(Broadcast::StateChanged);
+    }
+
+    // Applies a newly broadcast event to the session's copy of the
+    // document. Events after a gap are left for update_document() to
+    // read from the store.
+    fn advance_document(&mut self, seq: SequenceId, event: &Event) {
+        let invalid = match self.document {
+            Some((ref mut current, ref mut document)) if seq == *current + 1 => {
+                match document.apply(event) {
+                    Ok(_) => {
+                        *current = seq;
+                        false
+                    }
+                    Err(_) => true,
+                }
+            }
+            _ => false,
+        };
+        if invalid {
+            // reload the document from the store on the next edit
+            self.document = None;
+        }
+    }
+
+    fn set_lock_holder(&mut self, holder: Option<ParticipantId>) {
+        self.lock_holder = holder;
+        self.lock_version += 1;
+        self.wake_participants();
+    }
 }
 
 impl<T: Store + Sync> DocumentSession<T> {
@@ -107,34 +328,79 @@ impl<T: Store + Sync> DocumentSession<T> {
     fn join(
         &mut self,
         start_seq: SequenceId,
+        role: Role,
+        user: Option<String>,
     ) -> impl Future<Item = Participant<T>, Error = StoreError> {
-        let id = {
+        let next_id = {
             let mut data = self.data.lock().unwrap();
-            data.next_id += 1;
-            println!("Participant {} joined {:?}", data.next_id, data.path);
-            data.next_id
+            let path = data.path.clone();
+            data.store.next_participant_id(&path)
         };
         let s2 = self.clone();
-        let event = Event::Join(Join { id });
+        next_id.and_then(move |id| {
+            {
+                let mut data = s2.data.lock().unwrap();
+                data.metrics.joined();
+                info!("Participant {} joined {:?} as {:?}", id, data.path, role);
+            }
+            let s3 = s2.clone();
+            let joined_as = user.clone();
+            s2.write(Event::Join(Join { id, role, user }))
+                .and_then(move |_seq| {
+                    // bring the document up to date so the participant
+                    // can be told who else is connected, the list is
+                    // only informational so a failure here is ignored
+                    s3.update_document().then(move |_| {
+                        Ok(Participant::new(s3, id, role, joined_as, start_seq))
+                    })
+                })
+        })
+    }
 
-        self.write(event)
-            .map(move |_seq| Participant::new(s2, id, start_seq))
+    // Listens for Events pushed to the store by other processes
+    // sharing the same backing store, so Participants in this
+    // session are notified about them too. The listener stops once
+    // the session has been dropped.
+    fn watch_store(&self) {
+        let watch = {
+            let data = self.data.lock().unwrap();
+            data.store.watch(data.path.as_path())
+        };
+        let session = self.downgrade();
+        let listener = watch
+            .map_err(|err| error!("Error watching document for changes: {}", err))
+            .for_each(move |seq| -> Box<dyn Future<Item = (), Error = ()> + Send> {
+                match session.upgrade() {
+                    Some(session) => Box::new(session.fetch_events(seq)),
+                    // the session has ended, stop listening
+                    None => Box::new(future::err(())),
+                }
+            });
+        if let Err(err) = DefaultExecutor::current().spawn(Box::new(listener)) {
+            error!("Error spawning document watcher: {:?}", err);
+        }
     }
 
     // Notifies the other clients that a participant has left
     fn leave(&self, id: ParticipantId) -> impl Future<Item = SequenceId, Error = StoreError> {
         {
-            let data = self.data.lock().unwrap();
-            println!("Participant {} left {:?}", id, data.path);
+            let mut data = self.data.lock().unwrap();
+            data.metrics.left();
+            data.kicked.retain(|&kicked| kicked != id);
+            if data.lock_holder == Some(id) {
+                data.set_lock_holder(None);
+            }
+            info!("Participant {} left {:?}", id, data.path);
         };
         let event = Event::Leave(Leave { id });
         self.write(event)
     }
 
-    // Wakes up the task once a new sequence id is available.
-    fn notify_on_event(&self, task: Task) {
+    // Wakes up all Participants waiting for events so they can check
+    // for changes to the session state.
+    fn wake_participants(&self) {
         let mut data = self.data.lock().unwrap();
-        data.waiting_tasks.push(task);
+        data.wake_participants();
     }
 
     // Returns a weak reference to the session data. Used by
@@ -146,12 +412,94 @@ impl<T: Store + Sync> DocumentSession<T> {
         }
     }
 
-    // Updates last_seq and notifies Participants waiting for events
-    fn update_seq(&self, seq: SequenceId) {
+    // Flags the session as shutting down and wakes up all
+    // Participants so they can notice.
+    fn shutdown(&self) {
         let mut data = self.data.lock().unwrap();
-        data.last_seq = Some(seq);
-        for t in data.waiting_tasks.drain(..) {
-            t.notify();
+        data.shutting_down = true;
+        data.wake_participants();
+    }
+
+    // Flags the participant as removed from the session and wakes up
+    // all Participants so they can notice.
+    fn kick(&self, id: ParticipantId) {
+        let mut data = self.data.lock().unwrap();
+        if !data.kicked.contains(&id) {
+            data.kicked.push(id);
+            data.wake_participants();
+        }
+    }
+
+    // Gives the participant exclusive edit rights over the document,
+    // unless another participant already holds the lock.
+    fn lock(&self, id: ParticipantId) {
+        let mut data = self.data.lock().unwrap();
+        if data.lock_holder.is_none() {
+            data.set_lock_holder(Some(id));
+        }
+    }
+
+    // Releases the lock if it is held by the participant.
+    fn unlock(&self, id: ParticipantId) {
+        let mut data = self.data.lock().unwrap();
+        if data.lock_holder == Some(id) {
+            data.set_lock_holder(None);
+        }
+    }
+
+    // While the document is locked, participants other than the
+    // lock holder are observers and may only move their cursor.
+    fn may_edit(&self, id: ParticipantId, operations: &[Operation]) -> bool {
+        let data = self.data.lock().unwrap();
+        match data.lock_holder {
+            Some(holder) if holder != id => operations
+                .iter()
+                .all(|op| matches!(op, Operation::MoveCursor(_))),
+            _ => true,
+        }
+    }
+
+    // Updates last_seq and sends the event to all Participants. The
+    // same event may be reported more than once (e.g. by both write()
+    // and the store watcher), so events up to last_seq are ignored.
+    // Participants are responsible for catching up using the store if
+    // they notice a gap in the SequenceIds broadcast.
+    fn broadcast(&self, seq: SequenceId, event: Arc<SharedEvent>) {
+        let mut data = self.data.lock().unwrap();
+        if data.last_seq.is_none_or(|last_seq| seq > last_seq) {
+            data.last_seq = Some(seq);
+            data.advance_document(seq, &event);
+            data.broadcast(Broadcast::Event(seq, event));
+        }
+    }
+
+    // Broadcasts Events up to 'seq' which were pushed to the store by
+    // other processes.
+    fn fetch_events(&self, seq: SequenceId) -> impl Future<Item = (), Error = ()> {
+        let since = {
+            let data = self.data.lock().unwrap();
+            match data.last_seq {
+                Some(last_seq) if seq > last_seq => {
+                    Some(data.store.since(data.path.as_path(), last_seq, None))
+                }
+                // Either the events were already broadcast, or nothing
+                // has been written during this session yet. In the
+                // latter case new participants will read any earlier
+                // events from the store when they join.
+                _ => None,
+            }
+        };
+        let s2 = self.clone();
+        match since {
+            Some(since) => Either::A(
+                since
+                    .flatten_stream()
+                    .for_each(move |(seq, event)| {
+                        s2.broadcast(seq, Arc::new(SharedEvent::new(event)));
+                        Ok(())
+                    }).map_err(|err| error!("Error reading new events: {}", err)),
+            ),
+            None => Either::B(future::ok(())),
         }
     }
 
@@ -160,41 +508,250 @@ impl<T: Store + Sync> DocumentSession<T> {
         let mut data = self.data.lock().unwrap();
         let path = data.path.clone();
         let s2 = self.clone();
+        let shared = Arc::new(SharedEvent::new(event.clone()));
         data.store.push(path, event).inspect(move |seq| {
-            s2.update_seq(*seq);
+            s2.broadcast(*seq, shared);
         })
     }
 
-    // First, transforms an event to accomodate concurrent events
-    // already in the store, then, writes the resulting transformed
-    // event to the store.
+    // Brings the session's copy of the document up to date with the
+    // store, returning the SequenceId it is now current as of.
+    fn update_document(&self) -> impl Future<Item = SequenceId, Error = StoreError> {
+        let data = self.data.lock().unwrap();
+        let s2 = self.clone();
+        match data.document {
+            Some((seq, _)) => Either::A(
+                data.store
+                    .since(data.path.as_path(), seq, None)
+                    .and_then(|events| events.collect())
+                    .and_then(move |events| s2.apply_to_document(events)),
+            ),
+            None => Either::B(data.store.content(data.path.as_path()).map(
+                move |(seq, document)| {
+                    let mut data = s2.data.lock().unwrap();
+                    // another participant may have loaded a more
+                    // recent copy in the meantime
+                    match data.document {
+                        Some((current, _)) if current >= seq => current,
+                        _ => {
+                            data.document = Some((seq, document));
+                            seq
+                        }
+                    }
+                },
+            )),
+        }
+    }
+
+    // Applies events read from the store to the session's copy of the
+    // document. Events which have already been applied are skipped.
+    fn apply_to_document(
+        &self,
+        events: Vec<(SequenceId, Event)>,
+    ) -> Result<SequenceId, StoreError> {
+        let mut data = self.data.lock().unwrap();
+        let result = match data.document {
+            Some((ref mut current, ref mut document)) => {
+                let mut result = Ok(());
+                for (seq, event) in events {
+                    if seq > *current {
+                        if document.apply(&event).is_err() {
+                            result = Err(StoreError::InvalidDocument);
+                            break;
+                        }
+                        *current = seq;
+                    }
+                }
+                result.map(|_| *current)
+            }
+            None => Err(StoreError::InvalidDocument),
+        };
+        if result.is_err() {
+            // reload the document from the store on the next edit
+            data.document = None;
+        }
+        result
+    }
+
+    // Returns the session's copy of the document, if it has loaded one
+    fn document(&self) -> Option<(SequenceId, Document)> {
+        let data = self.data.lock().unwrap();
+        data.document.clone()
+    }
+
+    // Checks the transformed event can be applied to the latest
+    // version of the document, and that 'sender' joined the document
+    // as 'user', so the event is attributed to the user who sent it.
+    // Events are rejected while the session's copy of the document
+    // is being reloaded, as neither can be checked.
+    fn validate(
+        &self,
+        sender: ParticipantId,
+        user: &Option<String>,
+        event: &Event,
+    ) -> Result<(), ErrorMessage> {
+        let data = self.data.lock().unwrap();
+        let document = match data.document {
+            Some((_, ref document)) => document,
+            None => {
+                return Err(ErrorMessage {
+                    code: ErrorCode::InvalidEdit,
+                    reason: String::from("The document is being reloaded, please try again"),
+                })
+            }
+        };
+        if let Some(participant) = document.participants.entries.get(&sender) {
+            if participant.user != *user {
+                return Err(ErrorMessage {
+                    code: ErrorCode::InvalidAuthor,
+                    reason: format!("Participant {} joined as a different user", sender),
+                });
+            }
+        }
+        document.can_apply(event).map_err(|err| ErrorMessage {
+            code: ErrorCode::InvalidEdit,
+            reason: format!("{}", err),
+        })
+    }
+
+    // First, checks the event is by 'sender', who joined as 'user',
+    // and its parent_seq is valid, then transforms the event to
+    // accomodate concurrent events already in the store, and
+    // finally, if the resulting transformed event can be applied to
+    // the document, writes it to the store. Returns an ErrorMessage
+    // if the event was rejected.
     fn write_transformed(
         &self,
         sender: ParticipantId,
+        user: Option<String>,
         parent_seq: SequenceId,
         event: Event,
-    ) -> impl Future<Item = SequenceId, Error = StoreError> {
-        let concurrent_events = {
-            let mut data = self.data.lock().unwrap();
-            data.store.since(&data.path.as_path(), parent_seq)
+    ) -> impl Future<Item = Result<SequenceId, ErrorMessage>, Error = StoreError> {
+        let started = Instant::now();
+        let s2 = self.clone();
+        let author = match event {
+            Event::Edit(Edit { author, .. }) => author,
+            _ => sender,
         };
-        let transformed = concurrent_events.and_then(move |stream| {
-            stream.fold(event, move |mut event, (_seq, concurrent)| {
-                let from = match concurrent {
-                    Event::Edit(Edit { author, .. }) => author,
-                    Event::Join(Join { id }) => id,
-                    Event::Leave(Leave { id }) => id,
+        self.update_document().and_then(move |head| {
+            if author != sender {
+                let err = ErrorMessage {
+                    code: ErrorCode::InvalidAuthor,
+                    reason: format!(
+                        "Edit by participant {} was sent by participant {}",
+                        author, sender
+                    ),
+                };
+                return Either::A(future::ok(Err(err)));
+            }
+            if parent_seq > head {
+                let err = ErrorMessage {
+                    code: ErrorCode::InvalidParentSeq,
+                    reason: format!(
+                        "parent_seq {} is ahead of the latest event {}",
+                        parent_seq, head
+                    ),
                 };
-                // TODO: update store.since query to exclude events
-                // from sender at source instead of filtering here
-                if from != sender {
+                return Either::A(future::ok(Err(err)));
+            }
+            Either::B(s2.transform_and_write(sender, user, parent_seq, event, started))
+        })
+    }
+
+    // Transforms the event against concurrent events since
+    // parent_seq and writes it to the store if it is valid.
+    fn transform_and_write(
+        &self,
+        sender: ParticipantId,
+        user: Option<String>,
+        parent_seq: SequenceId,
+        event: Event,
+        started: Instant,
+    ) -> impl Future<Item = Result<SequenceId, ErrorMessage>, Error = StoreError> {
+        let concurrent_events = {
+            let data = self.data.lock().unwrap();
+            // the sender's own events are already reflected in theirs
+            data.store
+                .since(data.path.as_path(), parent_seq, Some(sender))
+        };
+        let operations = operation_count(&event);
+        let span = info_span!(
+            "document.transform",
+            operations = operations as u64,
+            concurrent_edits = field::Empty
+        );
+        let recorded = span.clone();
+        let transformed = concurrent_events
+            .and_then(move |stream| {
+                // also counts the number of concurrent edits the event
+                // was transformed against
+                stream.fold((event, 0), move |(mut event, depth), (_seq, concurrent)| {
                     event.transform(&concurrent);
-                }
-                future::ok(event)
-            })
-        });
+                    match concurrent {
+                        Event::Edit(_) => future::ok((event, depth + 1)),
+                        _ => future::ok((event, depth)),
+                    }
+                })
+            }).instrument(span);
         let s2 = self.clone();
-        transformed.and_then(move |event| s2.write(event))
+        transformed.and_then(move |(event, depth)| {
+            recorded.record("concurrent_edits", depth as u64);
+            let split = operation_count(&event).saturating_sub(operations);
+            s2.record_metrics(|metrics| metrics.transformed(started.elapsed(), depth, split));
+            if let Err(err) = s2.validate(sender, &user, &event) {
+                return Either::A(future::ok(Err(err)));
+            }
+            // anonymous edits do not count towards any quota
+            let bytes = quota::contributed(&event);
+            let quota = match (s2.quotas(), user) {
+                (Some(quotas), Some(user)) => Some((quotas, user)),
+                _ => None,
+            };
+            if let Some((ref quotas, ref user)) = quota {
+                if let Err(err) = quotas.reserve(user, bytes) {
+                    return Either::A(future::ok(Err(ErrorMessage {
+                        code: ErrorCode::QuotaExceeded,
+                        reason: format!("{}", err),
+                    })));
+                }
+            }
+            let s3 = s2.clone();
+            Either::B(
+                s2.write(event)
+                    .map(move |seq| {
+                        s3.record_metrics(|metrics| metrics.edited(Instant::now()));
+                        Ok(seq)
+                    }).map_err(move |err| {
+                        if let Some((quotas, user)) = quota {
+                            quotas.release(&user, bytes);
+                        }
+                        err
+                    }),
+            )
+        })
+    }
+
+    // The quotas edits are checked against, if any
+    fn quotas(&self) -> Option<Quotas> {
+        self.data.lock().unwrap().quotas.clone()
+    }
+
+    // Updates the session's activity counters
+    fn record_metrics<F>(&self, f: F)
+    where
+        F: FnOnce(&mut MetricsRecorder),
+    {
+        let mut data = self.data.lock().unwrap();
+        f(&mut data.metrics);
+    }
+}
+
+// The number of Operations in an Edit event, used to tell how many
+// operations were split during transformation.
+fn operation_count(event: &Event) -> usize {
+    match event {
+        Event::Edit(Edit { operations, .. }) => operations.len(),
+        _ => 0,
     }
 }
 
@@ -203,3 +760,47 @@ impl<T: Store + Sync> WeakDocumentSession<T> {
         self.data.upgrade().map(|data| DocumentSession { data })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn validate_rejects_events_while_document_is_reloaded() {
+        let mut rt = Runtime::new().expect("new test runtime");
+        let sessions = DocumentSessionManager::new(MemoryStore::default());
+        let path = Path::new("/test");
+        let alice = Some(String::from("alice"));
+        let participant = rt
+            .block_on(future::lazy(|| {
+                sessions.join(path, 0, Role::Editor, alice.clone())
+            }))
+            .unwrap();
+        let id = participant.get_id();
+        let session = sessions.data.lock().unwrap().sessions[path]
+            .upgrade()
+            .unwrap();
+        let event = Event::Edit(Edit {
+            author: id,
+            operations: vec![],
+        });
+        assert_eq!(session.validate(id, &alice, &event), Ok(()));
+        assert_eq!(
+            session
+                .validate(id, &Some(String::from("bob")), &event)
+                .map_err(|err| err.code),
+            Err(ErrorCode::InvalidAuthor)
+        );
+
+        // an event which failed to apply discards the session's copy
+        session.data.lock().unwrap().document = None;
+        for user in &[alice, Some(String::from("bob"))] {
+            assert_eq!(
+                session.validate(id, user, &event).map_err(|err| err.code),
+                Err(ErrorCode::InvalidEdit)
+            );
+        }
+    }
+}
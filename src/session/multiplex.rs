@@ -0,0 +1,297 @@
+//! Shares a single client connection between several DocumentSessions
+use futures::future::Future;
+use futures::sink::Sink;
+use futures::stream::Stream;
+use futures::task::{self, Task};
+use futures::{Async, AsyncSink, Poll, StartSend};
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use super::message::*;
+use super::participant::Participant;
+use super::{DocumentSessionManager, ResyncHandle};
+use crate::acl::{Acl, Permission};
+use crate::document::Role;
+use crate::slug::SlugPolicy;
+use crate::store::{Store, StoreError};
+
+// A participant joining a document's session
+type Joining<T> = Box<dyn Future<Item = Participant<T>, Error = StoreError> + Send>;
+
+/// A client subscribed to any number of DocumentSessions over one
+/// connection. Each subscription is a Participant in the document's
+/// session, identified by the path the client subscribed with. This
+/// struct can be used as a Stream, to read ChannelServerMessages for
+/// all subscribed documents, and a Sink, to subscribe to documents
+/// and send ClientMessages to them.
+pub struct Multiplexer<T: Store + Sync> {
+    sessions: DocumentSessionManager<T>,
+    role: Role,
+    user: Option<String>,
+    // Maps subscribed paths to document paths
+    slugs: SlugPolicy,
+    // Limits which documents the user may subscribe to and edit
+    acl: Option<Arc<Acl>>,
+    // Whether only users who may edit a document may subscribe to
+    // it, as its changes are drafts
+    drafts: bool,
+    // Subscriptions waiting for the participant to join
+    joining: Vec<(String, Joining<T>)>,
+    // Active subscriptions
+    channels: Vec<(String, Participant<T>)>,
+    // Errors waiting to be sent to the client
+    errors: VecDeque<ChannelServerMessage>,
+    // The channel to poll first, so busy documents do not starve
+    // the others
+    next: usize,
+    // Used by the Sink to wake the Stream when subscriptions change
+    task: Option<Task>,
+    // Set when every subscription should resync
+    resync: ResyncHandle,
+}
+
+impl<T: Store + Sync> Multiplexer<T> {
+    /// Creates a Multiplexer with no subscriptions. Participants
+    /// joined via the Multiplexer are given 'role', and their Join
+    /// events record 'user'.
+    pub fn new(sessions: DocumentSessionManager<T>, role: Role, user: Option<String>) -> Self {
+        Self {
+            sessions,
+            role,
+            user,
+            slugs: SlugPolicy::default(),
+            acl: None,
+            drafts: false,
+            joining: Vec::new(),
+            channels: Vec::new(),
+            errors: VecDeque::new(),
+            next: 0,
+            task: None,
+            resync: ResyncHandle::default(),
+        }
+    }
+
+    /// Sets how subscribed paths are mapped to documents, which
+    /// should match the SlugPolicy used for document URLs
+    pub fn with_slug_policy(mut self, slugs: SlugPolicy) -> Self {
+        self.slugs = slugs;
+        self
+    }
+
+    /// Refuses subscriptions to documents 'acl' does not let the user
+    /// read, and joins documents they may not write to as a Reader
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Refuses subscriptions to documents the user may not edit, as
+    /// they would be sent drafts. Anonymous users may not edit
+    /// documents with drafts.
+    pub fn with_drafts(mut self) -> Self {
+        self.drafts = true;
+        self
+    }
+
+    /// Returns a handle which resyncs every subscription, which can
+    /// be used after the Multiplexer is split into its Stream and Sink
+    pub fn resync_handle(&self) -> ResyncHandle {
+        self.resync.clone()
+    }
+
+    fn is_subscribed(&self, path: &str) -> bool {
+        self.joining.iter().any(|c| c.0 == path) || self.channels.iter().any(|c| c.0 == path)
+    }
+
+    // Queues an error for the channel at 'path' and wakes the Stream
+    // so it can be read.
+    fn reject(&mut self, path: String, code: ErrorCode, reason: String) {
+        self.errors.push_back(ChannelServerMessage {
+            path,
+            message: ServerMessage::Error(ErrorMessage { code, reason }),
+        });
+        self.notify();
+    }
+
+    fn notify(&self) {
+        if let Some(ref task) = self.task {
+            task.notify();
+        }
+    }
+
+    // Polls pending subscriptions, returning the Connected message
+    // for the first participant to finish joining.
+    fn poll_joining(&mut self) -> Option<ChannelServerMessage> {
+        for i in 0..self.joining.len() {
+            let result = match self.joining[i].1.poll() {
+                Ok(Async::NotReady) => continue,
+                Ok(Async::Ready(participant)) => Ok(participant),
+                Err(err) => Err(err),
+            };
+            let path = self.joining.remove(i).0;
+            return Some(match result {
+                Ok(participant) => {
+                    let connected = participant.connected();
+                    self.channels.push((path.clone(), participant));
+                    ChannelServerMessage {
+                        path,
+                        message: ServerMessage::Connected(connected),
+                    }
+                }
+                Err(err) => ChannelServerMessage {
+                    path,
+                    message: ServerMessage::Error(ErrorMessage {
+                        code: ErrorCode::InvalidChannel,
+                        reason: format!("Could not join document: {}", err),
+                    }),
+                },
+            });
+        }
+        None
+    }
+}
+
+impl<T: Store + Sync> Stream for Multiplexer<T> {
+    type Item = ChannelServerMessage;
+    type Error = MessageStreamError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.task = Some(task::current());
+        if let Some(msg) = self.errors.pop_front() {
+            return Ok(Async::Ready(Some(msg)));
+        }
+        if let Some(msg) = self.poll_joining() {
+            return Ok(Async::Ready(Some(msg)));
+        }
+        if self.resync.requested() {
+            for &mut (_, ref mut participant) in self.channels.iter_mut() {
+                participant.resync();
+            }
+        }
+        let mut n = 0;
+        while n < self.channels.len() {
+            let i = (self.next + n) % self.channels.len();
+            let message = match self.channels[i].1.poll() {
+                Ok(Async::NotReady) => {
+                    n += 1;
+                    continue;
+                }
+                Ok(Async::Ready(Some(message))) => {
+                    self.next = i + 1;
+                    message
+                }
+                // the participant was kicked or the session shut down
+                Ok(Async::Ready(None)) => {
+                    self.channels.remove(i);
+                    n = 0;
+                    continue;
+                }
+                // only this subscription fails, the others continue
+                Err(err) => {
+                    let path = self.channels.remove(i).0;
+                    self.reject(path, ErrorCode::InvalidChannel, format!("{}", err));
+                    return self.poll();
+                }
+            };
+            return Ok(Async::Ready(Some(ChannelServerMessage {
+                path: self.channels[i].0.clone(),
+                message,
+            })));
+        }
+        if self.channels.is_empty() && self.joining.is_empty() && self.sessions.shutting_down() {
+            return Ok(Async::Ready(None));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T: Store + Sync> Sink for Multiplexer<T> {
+    type SinkItem = MultiplexClientMessage;
+    type SinkError = MessageStreamError;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        match item {
+            MultiplexClientMessage::Subscribe(SubscribeMessage { path, seq }) => {
+                if !self.is_subscribed(&path) {
+                    // paths are relative to the root, as in URLs
+                    let doc_path = match self.slugs.document_path(&path) {
+                        Ok(doc_path) => doc_path,
+                        Err(err) => {
+                            let reason = format!("Invalid path {:?}: {}", path, err);
+                            self.reject(path, ErrorCode::InvalidChannel, reason);
+                            return Ok(AsyncSink::Ready);
+                        }
+                    };
+                    let mut role = self.role;
+                    let (read, write) = match self.acl {
+                        Some(ref acl) => {
+                            let user = self.user.as_deref();
+                            (
+                                acl.allows(user, &doc_path, Permission::Read),
+                                acl.allows(user, &doc_path, Permission::Write),
+                            )
+                        }
+                        None => (true, true),
+                    };
+                    let write = write && !(self.drafts && self.user.is_none());
+                    if !read || (self.drafts && !write) {
+                        let reason = format!("Permission denied for {:?}", path);
+                        self.reject(path, ErrorCode::PermissionDenied, reason);
+                        return Ok(AsyncSink::Ready);
+                    }
+                    // users who may not edit the document join it
+                    // read-only
+                    if !write {
+                        role = cmp::max(role, Role::Reader);
+                    }
+                    let join = self.sessions.join(&doc_path, seq, role, self.user.clone());
+                    self.joining.push((path, Box::new(join)));
+                    self.notify();
+                }
+                Ok(AsyncSink::Ready)
+            }
+            MultiplexClientMessage::Unsubscribe(UnsubscribeMessage { path }) => {
+                // dropping the participant leaves the document session
+                self.joining.retain(|c| c.0 != path);
+                self.channels.retain(|c| c.0 != path);
+                self.notify();
+                Ok(AsyncSink::Ready)
+            }
+            MultiplexClientMessage::Message(ChannelClientMessage { path, message }) => {
+                if let Some(i) = self.channels.iter().position(|c| c.0 == path) {
+                    return Ok(match self.channels[i].1.start_send(message)? {
+                        AsyncSink::Ready => AsyncSink::Ready,
+                        AsyncSink::NotReady(message) => AsyncSink::NotReady(
+                            MultiplexClientMessage::Message(ChannelClientMessage { path, message }),
+                        ),
+                    });
+                }
+                if self.joining.iter().any(|c| c.0 == path) {
+                    // wait for the participant to finish joining
+                    self.notify();
+                    return Ok(AsyncSink::NotReady(MultiplexClientMessage::Message(
+                        ChannelClientMessage { path, message },
+                    )));
+                }
+                let reason = format!("Not subscribed to {:?}", path);
+                self.reject(path, ErrorCode::InvalidChannel, reason);
+                Ok(AsyncSink::Ready)
+            }
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        let mut ready = true;
+        for &mut (_, ref mut participant) in self.channels.iter_mut() {
+            if let Async::NotReady = participant.poll_complete()? {
+                ready = false;
+            }
+        }
+        Ok(if ready {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        })
+    }
+}
@@ -1,16 +1,37 @@
 //! Handles client communication with a DocumentSession
-use futures::future::Future;
+use futures::future::{self, Future};
 use futures::sink::Sink;
-use futures::stream::{self, Stream};
-use futures::task;
+use futures::stream::Stream;
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use futures::{Async, AsyncSink, Poll, StartSend};
-use std::mem;
+use std::collections::VecDeque;
+use std::sync::Arc;
 use tokio::executor::{DefaultExecutor, Executor};
+use tracing::info_span;
+use tracing_futures::Instrument;
 
 use super::message::*;
-use super::DocumentSession;
-use document::{Edit, Event, Join, Leave, ParticipantId};
-use store::{SequenceId, Store, StoreError};
+use super::{Broadcast, DocumentSession, ResyncHandle};
+use crate::document::{
+    Document, Edit, Event, Join, Leave, Operation, ParticipantId, Participants, Publish, Restore,
+    Role, Tombstone,
+};
+use crate::store::{SequenceId, Store, StoreError};
+
+// The maximum number of catch-up events sent in a single
+// ServerMessage::Events, and read from the store at a time
+const MAX_BATCH_SIZE: usize = 100;
+
+// A range of catch-up events being read from the store
+type CatchUp = Box<dyn Future<Item = Vec<(SequenceId, Event)>, Error = StoreError> + Send>;
+
+// The document being read from the store after a resync
+type Snapshot = Box<dyn Future<Item = (SequenceId, Document), Error = StoreError> + Send>;
+
+// An edit being written to the store, resolving to its SequenceId or
+// the error to reject it with
+type PendingWrite =
+    Box<dyn Future<Item = Result<SequenceId, ErrorMessage>, Error = StoreError> + Send>;
 
 /// A client connected to a DocumentSession. This struct can be used
 /// as a Stream, to read ServerMessages applicable to the participant,
@@ -18,21 +39,42 @@ use store::{SequenceId, Store, StoreError};
 /// DocumentSession.
 pub struct Participant<T: Store + Sync> {
     id: ParticipantId,
+    role: Role,
+    // The authenticated user the participant joined as, which their
+    // edits are checked against
+    user: Option<String>,
     seq: SequenceId,
     client_seq: SequenceId,
     session: DocumentSession<T>,
-    state: ParticipantStreamState,
-    inner: stream::Flatten<stream::FuturesUnordered<T::SinceFuture>>,
-    writing: Option<(
-        SequenceId,
-        Box<Future<Item = SequenceId, Error = StoreError> + Send>,
-    )>,
-}
-
-#[derive(Debug)]
-enum ParticipantStreamState {
-    ReadingStream,
-    WaitingForEvent,
+    // Events read from the store when first joining, or after
+    // missing a broadcast from the session. Read in preference to
+    // the broadcast channel until exhausted, one range at a time so
+    // the next range is only read once the last has been sent.
+    catchup: Option<CatchUp>,
+    // The document read from the store after a resync, sent in place
+    // of the events the participant fell behind on
+    snapshot: Option<Snapshot>,
+    // Set when the participant should resync
+    resync: ResyncHandle,
+    // Events and state changes broadcast by the DocumentSession
+    broadcasts: UnboundedReceiver<Broadcast>,
+    // Used by the Sink to wake the Stream when errors are queued
+    notify: UnboundedSender<Broadcast>,
+    // Errors waiting to be sent to the participant
+    errors: VecDeque<ErrorMessage>,
+    // The session has shut down and the Shutdown message was sent
+    closed: bool,
+    writing: Option<(SequenceId, PendingWrite)>,
+    // The client_seq of each edit sent through the Sink which has not
+    // been acknowledged yet, oldest first, with the edit's SequenceId
+    // once it is written. An edit is acknowledged when its event is
+    // read, rather than when the write completes, so events read
+    // before it are never marked as following it, or the other way
+    // round, which would have the client transform them wrongly.
+    unacknowledged: VecDeque<(SequenceId, Option<SequenceId>)>,
+    // The session's lock_version when the participant was last sent
+    // a ServerMessage::Lock, or None if one must be sent.
+    lock_version: Option<u64>,
 }
 
 impl<T: Store + Sync> Participant<T> {
@@ -40,18 +82,42 @@ impl<T: Store + Sync> Participant<T> {
     /// should not need to call this directly, instead a Participant
     /// should be obtained by calling `join()` using the
     /// DocuemntSessionManager.
-    pub fn new(session: DocumentSession<T>, id: ParticipantId, since: SequenceId) -> Self {
-        let catchup = {
-            let data = session.data.lock().unwrap();
-            data.store.since(&data.path.as_path(), since)
+    pub fn new(
+        session: DocumentSession<T>,
+        id: ParticipantId,
+        role: Role,
+        user: Option<String>,
+        since: SequenceId,
+    ) -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        let (catchup, lock_version) = {
+            let mut data = session.data.lock().unwrap();
+            // subscribe before requesting events from the store, so
+            // no events can be missed in between
+            data.subscribers.push(tx.clone());
+            let lock_version = match data.lock_holder {
+                Some(_) => None,
+                None => Some(data.lock_version),
+            };
+            let catchup = data.store.since_range(data.path.as_path(), since, MAX_BATCH_SIZE);
+            (catchup, lock_version)
         };
         Self {
-            session: session,
+            session,
             seq: since,
             client_seq: 0,
-            state: ParticipantStreamState::ReadingStream,
-            inner: stream::futures_unordered(vec![catchup]).flatten(),
+            catchup: Some(catchup),
+            snapshot: None,
+            resync: ResyncHandle::default(),
+            broadcasts: rx,
+            notify: tx,
+            errors: VecDeque::new(),
+            closed: false,
             writing: None,
+            unacknowledged: VecDeque::new(),
+            lock_version,
+            role,
+            user,
             id,
         }
     }
@@ -61,6 +127,112 @@ impl<T: Store + Sync> Participant<T> {
         self.id
     }
 
+    /// Returns the ConnectedMessage to send to the client before any
+    /// other ServerMessages, listing the other participants in the
+    /// session's current copy of the document.
+    pub fn connected(&self) -> ConnectedMessage {
+        let data = self.session.data.lock().unwrap();
+        let participants = match data.document {
+            Some((_, ref document)) => self.others(document),
+            None => Participants::new(),
+        };
+        ConnectedMessage {
+            id: self.id,
+            version: Some(PROTOCOL_VERSION),
+            participants,
+        }
+    }
+
+    /// Returns a handle which resyncs the participant, which can be
+    /// used after the participant is split into its Stream and Sink
+    pub fn resync_handle(&self) -> ResyncHandle {
+        self.resync.clone()
+    }
+
+    /// Discards the events the participant has not read yet, and
+    /// reads the latest snapshot of the document from the store to
+    /// send in their place as a ServerMessage::Resync. Events up to
+    /// the snapshot's SequenceId are skipped when read afterwards.
+    pub fn resync(&mut self) {
+        self.catchup = None;
+        self.snapshot = Some({
+            let data = self.session.data.lock().unwrap();
+            data.store.content(data.path.as_path())
+        });
+        self.session.record_metrics(|metrics| metrics.resynced());
+    }
+
+    /// Writes an Edit made by the participant as of 'parent_seq',
+    /// without going through the Sink. This is for edits the server
+    /// makes on the participant's behalf, such as restoring an
+    /// earlier revision. Resolves to the edit's SequenceId, or an
+    /// ErrorMessage if the edit was rejected.
+    pub fn edit(&self, parent_seq: SequenceId, operations: Vec<Operation>) -> PendingWrite {
+        let denied = if !self.role.may_edit(&operations) {
+            Some(format!("{:?} may not make this edit", self.role))
+        } else if !self.session.may_edit(self.id, &operations) {
+            Some(String::from("Another participant holds the lock"))
+        } else {
+            None
+        };
+        if let Some(reason) = denied {
+            return Box::new(future::ok(Err(ErrorMessage {
+                code: ErrorCode::PermissionDenied,
+                reason,
+            })));
+        }
+        let event = Event::Edit(Edit {
+            author: self.id,
+            operations,
+        });
+        Box::new(
+            self.session
+                .write_transformed(self.id, self.user.clone(), parent_seq, event),
+        )
+    }
+
+    /// Moves the document to the trash as the participant, as of
+    /// 'parent_seq'. Its content and history are kept, so it can be
+    /// restored later. Resolves to the Tombstone's SequenceId, or an
+    /// ErrorMessage if the document was already deleted.
+    pub fn delete(&self, parent_seq: SequenceId) -> PendingWrite {
+        self.write_as_editor(parent_seq, "delete", Event::Tombstone(Tombstone { id: self.id }))
+    }
+
+    /// Restores the document from the trash as the participant, as
+    /// of 'parent_seq'. Resolves to the Restore's SequenceId, or an
+    /// ErrorMessage if the document was not deleted.
+    pub fn restore(&self, parent_seq: SequenceId) -> PendingWrite {
+        self.write_as_editor(parent_seq, "restore", Event::Restore(Restore { id: self.id }))
+    }
+
+    /// Publishes the revision at 'parent_seq' as the participant, so
+    /// readers who may not see drafts are shown it. Resolves to the
+    /// Publish event's SequenceId, or an ErrorMessage if a later
+    /// revision was already published.
+    pub fn publish(&self, parent_seq: SequenceId) -> PendingWrite {
+        let event = Event::Publish(Publish {
+            id: self.id,
+            seq: parent_seq,
+        });
+        self.write_as_editor(parent_seq, "publish", event)
+    }
+
+    // Writes an event changing the whole document, which only
+    // editors may do
+    fn write_as_editor(&self, parent_seq: SequenceId, action: &str, event: Event) -> PendingWrite {
+        if self.role != Role::Editor {
+            return Box::new(future::ok(Err(ErrorMessage {
+                code: ErrorCode::PermissionDenied,
+                reason: format!("{:?} may not {} the document", self.role, action),
+            })));
+        }
+        Box::new(
+            self.session
+                .write_transformed(self.id, self.user.clone(), parent_seq, event),
+        )
+    }
+
     // If this function returns true, the event will not be converted
     // to a ServerMessage and sent to the Participant when it reads
     // the next event.
@@ -68,19 +240,154 @@ impl<T: Store + Sync> Participant<T> {
         match event {
             &Event::Edit(Edit { author, .. }) => author == self.id,
             &Event::Leave(Leave { id }) => id == self.id,
-            &Event::Join(Join { id }) => id == self.id,
+            &Event::Join(Join { id, .. }) => id == self.id,
+            &Event::Tombstone(Tombstone { id })
+            | &Event::Restore(Restore { id })
+            | &Event::Publish(Publish { id, .. }) => id == self.id,
+        }
+    }
+
+    // Records that the participant has seen all events up to and
+    // including 'seq', returning the message to send for the event
+    // (if any).
+    fn receive(&mut self, seq: SequenceId, event: Arc<SharedEvent>) -> Option<ServerEventMessage> {
+        self.seq = seq;
+        if let Event::Edit(Edit { author, .. }) = **event {
+            // the participant's own edits are read in the order they
+            // were written
+            if author == self.id {
+                if let Some((client_seq, _)) = self.unacknowledged.pop_front() {
+                    self.client_seq = client_seq;
+                }
+            }
+        }
+        if self.ignored_event(&event) {
+            None
+        } else {
+            Some(self.prepare_server_message(seq, event))
+        }
+    }
+
+    // Returns the participants in 'document' other than this one
+    fn others(&self, document: &Document) -> Participants {
+        document
+            .participants
+            .entries
+            .iter()
+            .filter(|&(id, _)| *id != self.id)
+            .map(|(id, participant)| (*id, participant.clone()))
+            .collect()
+    }
+
+    // Returns a ServerMessage::Lock if the session's lock holder has
+    // changed since the participant was last told about it.
+    fn lock_update(&mut self) -> Option<ServerMessage> {
+        let data = self.session.data.lock().unwrap();
+        if self.lock_version == Some(data.lock_version) {
+            return None;
+        }
+        self.lock_version = Some(data.lock_version);
+        Some(ServerMessage::Lock(LockMessage {
+            holder: data.lock_holder,
+        }))
+    }
+
+    // Queues an error to be sent to the participant and wakes the
+    // Stream so it can be read.
+    fn reject(&mut self, code: ErrorCode, reason: String) {
+        self.errors.push_back(ErrorMessage { code, reason });
+        let _ = self.notify.unbounded_send(Broadcast::StateChanged);
+    }
+
+    // Handles the result of writing an edit to the store, either
+    // acknowledging the edit's client_seq or reporting the error.
+    fn written(&mut self, client_seq: SequenceId, result: Result<SequenceId, ErrorMessage>) {
+        match result {
+            Ok(seq) => {
+                for entry in self.unacknowledged.iter_mut() {
+                    if entry.0 == client_seq {
+                        entry.1 = Some(seq);
+                    }
+                }
+                self.acknowledge_skipped();
+            }
+            Err(err) => {
+                self.unacknowledged.retain(|&(c, _)| c != client_seq);
+                self.reject(err.code, err.reason);
+            }
+        }
+    }
+
+    // Acknowledges written edits whose events the participant skipped
+    // past when resyncing, so will never read.
+    fn acknowledge_skipped(&mut self) {
+        while let Some(&(client_seq, Some(seq))) = self.unacknowledged.front() {
+            if seq > self.seq {
+                break;
+            }
+            self.client_seq = client_seq;
+            self.unacknowledged.pop_front();
         }
     }
 
-    // Converts an event into a ServerMessage, adding the server's
-    // sequence id for the event and the most recently applied client
-    // sequence id from the participant.
-    fn prepare_server_message(&self, seq: SequenceId, event: Event) -> ServerMessage {
-        ServerMessage::Event(ServerEventMessage {
+    // Converts an event into a ServerEventMessage, adding the
+    // server's sequence id for the event and the most recently
+    // applied client sequence id from the participant.
+    fn prepare_server_message(
+        &self,
+        seq: SequenceId,
+        event: Arc<SharedEvent>,
+    ) -> ServerEventMessage {
+        ServerEventMessage {
             client_seq: self.client_seq,
             seq,
             event,
-        })
+        }
+    }
+
+    // Requests the next range of catch-up events after 'seq'
+    fn catch_up(&self, seq: SequenceId) -> CatchUp {
+        let data = self.session.data.lock().unwrap();
+        data.store.since_range(data.path.as_path(), seq, MAX_BATCH_SIZE)
+    }
+
+    // Reads the next range of catch-up events once it is available,
+    // and requests the range after it. The catchup is dropped once
+    // a range reaches the end of the document.
+    fn read_catchup(
+        &mut self,
+        mut catchup: CatchUp,
+    ) -> Result<Vec<ServerEventMessage>, StoreError> {
+        loop {
+            let events = match catchup.poll()? {
+                Async::Ready(events) => events,
+                Async::NotReady => {
+                    self.catchup = Some(catchup);
+                    return Ok(Vec::new());
+                }
+            };
+            // a short range means the participant has caught up, and
+            // can continue reading broadcasts
+            let caught_up = events.len() < MAX_BATCH_SIZE;
+            let mut last = self.seq;
+            let mut batch = Vec::new();
+            for (seq, event) in events {
+                last = seq;
+                if seq > self.seq {
+                    if let Some(msg) = self.receive(seq, Arc::new(SharedEvent::new(event))) {
+                        batch.push(msg);
+                    }
+                }
+            }
+            if caught_up {
+                return Ok(batch);
+            }
+            catchup = self.catch_up(last);
+            if !batch.is_empty() {
+                self.catchup = Some(catchup);
+                return Ok(batch);
+            }
+        }
     }
 }
 
@@ -90,40 +397,90 @@ impl<T: Store + Sync> Stream for Participant<T> {
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         loop {
-            match self.state {
-                ParticipantStreamState::ReadingStream => match self.inner.poll()? {
-                    Async::Ready(None) => {
-                        self.state = ParticipantStreamState::WaitingForEvent;
+            if self.closed {
+                return Ok(Async::Ready(None));
+            }
+            if let Some(err) = self.errors.pop_front() {
+                return Ok(Async::Ready(Some(ServerMessage::Error(err))));
+            }
+            if self.resync.requested() {
+                self.resync();
+            }
+            if let Some(mut snapshot) = self.snapshot.take() {
+                let (seq, document) = match snapshot.poll()? {
+                    Async::Ready(found) => found,
+                    Async::NotReady => {
+                        self.snapshot = Some(snapshot);
+                        return Ok(Async::NotReady);
                     }
-                    Async::Ready(Some((seq, event))) => {
-                        self.seq = seq;
-                        if !self.ignored_event(&event) {
-                            let msg = self.prepare_server_message(seq, event);
-                            return Ok(Async::Ready(Some(msg)));
+                };
+                self.seq = seq;
+                self.acknowledge_skipped();
+                return Ok(Async::Ready(Some(ServerMessage::Resync(ResyncMessage {
+                    client_seq: self.client_seq,
+                    seq,
+                    participants: self.others(&document),
+                    content: document.content,
+                }))));
+            }
+            if let Some(catchup) = self.catchup.take() {
+                // contiguous events are sent together, so clients
+                // catching up on many events receive fewer messages
+                let mut batch = self.read_catchup(catchup)?;
+                match batch.len() {
+                    0 if self.catchup.is_some() => return Ok(Async::NotReady),
+                    0 => continue,
+                    1 => return Ok(Async::Ready(Some(ServerMessage::Event(batch.remove(0))))),
+                    _ => return Ok(Async::Ready(Some(ServerMessage::Events(batch)))),
+                }
+            }
+            match self.broadcasts.poll() {
+                Ok(Async::Ready(Some(Broadcast::Event(seq, event)))) => {
+                    if seq == self.seq + 1 {
+                        if let Some(msg) = self.receive(seq, event) {
+                            return Ok(Async::Ready(Some(ServerMessage::Event(msg))));
                         }
+                    } else if seq > self.seq + 1 {
+                        // missed some events (e.g. writes completed
+                        // out of order), catch up using the store
+                        self.catchup = Some(self.catch_up(self.seq));
                     }
-                    Async::NotReady => return Ok(Async::NotReady),
-                },
-                ParticipantStreamState::WaitingForEvent => {
-                    let last_seq = {
+                }
+                Ok(Async::Ready(Some(Broadcast::StateChanged))) => {
+                    if let Some(msg) = self.lock_update() {
+                        return Ok(Async::Ready(Some(msg)));
+                    }
+                }
+                Ok(Async::Ready(None)) | Err(()) => {
+                    // the session stopped broadcasting
+                    self.closed = true;
+                }
+                Ok(Async::NotReady) => {
+                    if let Some(msg) = self.lock_update() {
+                        return Ok(Async::Ready(Some(msg)));
+                    }
+                    let (shutting_down, kicked) = {
                         let data = self.session.data.lock().unwrap();
-                        data.last_seq
+                        (data.shutting_down, data.kicked.contains(&self.id))
                     };
-                    match last_seq {
-                        Some(last_seq) if last_seq > self.seq => {
-                            self.inner = {
-                                let data = self.session.data.lock().unwrap();
-                                stream::futures_unordered(vec![
-                                    data.store.since(&data.path.as_path(), self.seq),
-                                ]).flatten()
-                            };
-                            self.state = ParticipantStreamState::ReadingStream;
-                        }
-                        _ => {
-                            self.session.notify_on_event(task::current());
-                            return Ok(Async::NotReady);
-                        }
+                    if kicked {
+                        // discard any pending edit
+                        self.writing = None;
+                        self.closed = true;
+                        return Ok(Async::Ready(Some(ServerMessage::Kicked)));
+                    }
+                    if !shutting_down {
+                        return Ok(Async::NotReady);
+                    }
+                    if self.writing.is_some() {
+                        // flush the participant's pending edit to the
+                        // store before disconnecting, then read the
+                        // resulting events
+                        try_ready!(self.poll_complete());
+                        continue;
                     }
+                    self.closed = true;
+                    return Ok(Async::Ready(Some(ServerMessage::Shutdown)));
                 }
             }
         }
@@ -136,22 +493,67 @@ impl<T: Store + Sync> Sink for Participant<T> {
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
         loop {
-            let writing = mem::replace(&mut self.writing, None);
+            let writing = self.writing.take();
             match writing {
                 None => match item {
+                    ClientMessage::Lock => {
+                        // only participants who can edit the document
+                        // may claim exclusive edit rights over it
+                        if self.role == Role::Editor {
+                            self.session.lock(self.id);
+                        }
+                        return Ok(AsyncSink::Ready);
+                    }
+                    ClientMessage::Unlock => {
+                        self.session.unlock(self.id);
+                        return Ok(AsyncSink::Ready);
+                    }
                     ClientMessage::ClientEdit(data) => {
+                        // edits are attributed to the participant who
+                        // sent them, whoever they claim to be by
+                        if let Some(author) = data.author.filter(|&author| author != self.id) {
+                            let reason = format!(
+                                "Participant {} may not edit as participant {}",
+                                self.id, author
+                            );
+                            self.reject(ErrorCode::InvalidAuthor, reason);
+                            return Ok(AsyncSink::Ready);
+                        }
+                        if !self.role.may_edit(&data.operations) {
+                            let reason = format!("{:?} may not make this edit", self.role);
+                            self.reject(ErrorCode::PermissionDenied, reason);
+                            return Ok(AsyncSink::Ready);
+                        }
+                        if !self.session.may_edit(self.id, &data.operations) {
+                            // another participant holds the lock, discard
+                            // the edit and remind the participant who
+                            // the lock holder is
+                            self.lock_version = None;
+                            self.session.wake_participants();
+                            return Ok(AsyncSink::Ready);
+                        }
+                        let parent_seq = data.parent_seq;
                         let event = Event::Edit(Edit {
                             author: self.id,
                             operations: data.operations,
                         });
-                        self.writing = Some((
-                            data.client_seq,
-                            Box::new(self.session.write_transformed(
+                        // the span covers transforming and writing the edit
+                        let span = info_span!(
+                            "websocket.edit",
+                            participant = self.id,
+                            client_seq = data.client_seq,
+                            parent_seq = parent_seq
+                        );
+                        let written = span.in_scope(|| {
+                            self.session.write_transformed(
                                 self.id,
-                                data.parent_seq,
+                                self.user.clone(),
+                                parent_seq,
                                 event,
-                            )),
-                        ));
+                            )
+                        });
+                        self.unacknowledged.push_back((data.client_seq, None));
+                        self.writing = Some((data.client_seq, Box::new(written.instrument(span))));
                         return Ok(AsyncSink::Ready);
                     }
                 },
@@ -160,9 +562,10 @@ impl<T: Store + Sync> Sink for Participant<T> {
                         self.writing = Some((client_seq, push_future));
                         return Ok(AsyncSink::NotReady(item));
                     }
-                    Async::Ready(_) => {
-                        self.client_seq = client_seq;
-                        return Ok(AsyncSink::Ready);
+                    Async::Ready(result) => {
+                        // the previous edit is complete, now handle
+                        // this item
+                        self.written(client_seq, result);
                     }
                 },
             }
@@ -170,7 +573,7 @@ impl<T: Store + Sync> Sink for Participant<T> {
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        let writing = mem::replace(&mut self.writing, None);
+        let writing = self.writing.take();
         match writing {
             None => Ok(Async::Ready(())),
             Some((client_seq, mut push_future)) => match push_future.poll()? {
@@ -178,9 +581,8 @@ impl<T: Store + Sync> Sink for Participant<T> {
                     self.writing = Some((client_seq, push_future));
                     Ok(Async::NotReady)
                 }
-                Async::Ready(_) => {
-                    self.client_seq = client_seq;
-                    self.writing = None;
+                Async::Ready(result) => {
+                    self.written(client_seq, result);
                     Ok(Async::Ready(()))
                 }
             },
@@ -193,7 +595,7 @@ impl<T: Store + Sync> Drop for Participant<T> {
         let id = self.id;
         let result = DefaultExecutor::current().spawn(Box::new(
             self.session.leave(id).map(|_| ()).map_err(move |err| {
-                eprintln!(
+                error!(
                     "Error when participant {} leaving document session: {}",
                     id, err
                 );
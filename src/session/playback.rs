@@ -0,0 +1,75 @@
+//! Replays a document's history at a steady pace, so spectators can
+//! watch how a document evolved without loading its full history
+//! on the client.
+use futures::future::Future;
+use futures::stream::Stream;
+use std::cmp;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+
+use super::message::{MessageStreamError, ServerEventMessage, ServerMessage, SharedEvent};
+use crate::store::{SequenceId, Store};
+
+/// The number of events per second replayed when no speed is given
+pub const DEFAULT_SPEED: u32 = 10;
+
+/// Returns a ServerMessage::Event for every Event in the document
+/// after 'since', yielding at most 'speed' messages per second. The
+/// stream ends once the last event currently in the store has been
+/// replayed.
+pub fn playback<T: Store>(
+    store: &T,
+    path: &Path,
+    since: SequenceId,
+    speed: u32,
+) -> impl Stream<Item = ServerMessage, Error = MessageStreamError> {
+    let period = Duration::from_secs(1) / cmp::max(speed, 1);
+    let ticks = Interval::new(Instant::now(), period).map_err(|err| MessageStreamError::Transport {
+        error: Box::new(err),
+    });
+    store
+        .since(path, since, None)
+        .flatten_stream()
+        .map_err(MessageStreamError::from)
+        .zip(ticks)
+        .map(|((seq, event), _)| {
+            ServerMessage::Event(ServerEventMessage {
+                client_seq: 0,
+                seq,
+                event: Arc::new(SharedEvent::new(event)),
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::store::memory::MemoryStore;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn playback_paced_events() {
+        let mut rt = Runtime::new().expect("new test runtime");
+        let mut data = HashMap::new();
+        data.insert(String::from("/test"), String::from("Hello"));
+        let store = MemoryStore::from(data);
+
+        let started = Instant::now();
+        let messages = rt
+            .block_on(playback(&store, Path::new("/test"), 1, 50).collect())
+            .unwrap();
+
+        let seqs: Vec<SequenceId> = messages
+            .into_iter()
+            .map(|msg| match msg {
+                ServerMessage::Event(ServerEventMessage { seq, .. }) => seq,
+                msg => panic!("Unexpected message: {:?}", msg),
+            }).collect();
+        assert_eq!(seqs, vec![2, 3]);
+        // the first event is sent immediately, the second is delayed
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}
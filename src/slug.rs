@@ -0,0 +1,180 @@
+//! Maps the paths in request URLs to document paths, so different
+//! spellings of a page's URL find the same document.
+use std::path::PathBuf;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::sanitize::{self, UnsafePath};
+
+/// How request paths are normalized before being used as a store
+/// key. By default paths are percent-decoded and converted to
+/// Unicode Normalization Form C, so the same title typed on
+/// different systems finds the same document. Case folding and
+/// mapping spaces to underscores can be enabled as well.
+#[derive(Debug, Clone)]
+pub struct SlugPolicy {
+    fold_case: bool,
+    underscores: bool,
+    nfc: bool,
+}
+
+impl Default for SlugPolicy {
+    fn default() -> Self {
+        SlugPolicy {
+            fold_case: false,
+            underscores: false,
+            nfc: true,
+        }
+    }
+}
+
+impl SlugPolicy {
+    /// Converts paths to lowercase, so `/Foo` and `/foo` are the
+    /// same document
+    pub fn fold_case(mut self, enabled: bool) -> Self {
+        self.fold_case = enabled;
+        self
+    }
+
+    /// Replaces spaces in paths with underscores, so `/foo bar` and
+    /// `/foo_bar` are the same document
+    pub fn spaces_to_underscores(mut self, enabled: bool) -> Self {
+        self.underscores = enabled;
+        self
+    }
+
+    /// Converts paths to Unicode Normalization Form C
+    pub fn unicode_nfc(mut self, enabled: bool) -> Self {
+        self.nfc = enabled;
+        self
+    }
+
+    /// Returns the document path for the percent-encoded URL path
+    /// 'path'. The leading slash is removed, since documents are
+    /// stored relative to the root. Paths which are not safe to use
+    /// as a store key once decoded are rejected, see `sanitize`.
+    pub fn document_path(&self, path: &str) -> Result<PathBuf, UnsafePath> {
+        let path = path.trim_start_matches('/');
+        let decoded = percent_decode(path).unwrap_or_else(|| String::from(path));
+        let path = PathBuf::from(self.normalize(&decoded));
+        sanitize::check(&path)?;
+        Ok(path)
+    }
+
+    /// Applies the policy to an already decoded path
+    pub fn normalize(&self, path: &str) -> String {
+        let mut path: String = if self.nfc {
+            path.nfc().collect()
+        } else {
+            String::from(path)
+        };
+        if self.fold_case {
+            path = path.to_lowercase();
+        }
+        if self.underscores {
+            path = path.replace(' ', "_");
+        }
+        path
+    }
+}
+
+/// Decodes %XX escapes in a URL path, returning None if the result
+/// is not valid UTF-8.
+pub fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' {
+            path.get(i + 1..i + 3)
+                .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Encodes a document path for use in a URL, the reverse of
+/// percent_decode. Slashes are kept, so nested documents link to
+/// the same URLs they are served from.
+pub fn percent_encode(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'-' | b'.' | b'_' | b'~' | b'/' => (b as char).to_string(),
+            b if b.is_ascii_alphanumeric() => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_policy() {
+        let slugs = SlugPolicy::default();
+        assert_eq!(slugs.document_path("/Foo%20Bar"), Ok(PathBuf::from("Foo Bar")));
+        // "e" followed by a combining acute accent
+        assert_eq!(slugs.document_path("/cafe%CC%81"), Ok(PathBuf::from("caf\u{e9}")));
+        // invalid escapes are left as they are
+        assert_eq!(slugs.document_path("/100%"), Ok(PathBuf::from("100%")));
+        assert_eq!(slugs.document_path("/%FF"), Ok(PathBuf::from("%FF")));
+    }
+
+    #[test]
+    fn folded_policy() {
+        let slugs = SlugPolicy::default()
+            .fold_case(true)
+            .spaces_to_underscores(true);
+        assert_eq!(slugs.document_path("/Foo%20Bar"), Ok(PathBuf::from("foo_bar")));
+        assert_eq!(slugs.document_path("/foo_bar"), Ok(PathBuf::from("foo_bar")));
+        assert_eq!(slugs.normalize("Ünïcode Page"), "ünïcode_page");
+    }
+
+    #[test]
+    fn reject_unsafe_paths() {
+        let slugs = SlugPolicy::default();
+        let cases = [
+            ("/../secret", UnsafePath::Relative),
+            ("/%2e%2e/secret", UnsafePath::Relative),
+            ("/guides/%2E%2E%2Fsecret", UnsafePath::Relative),
+            ("/guides%2f%2e%2e", UnsafePath::Relative),
+            ("/%2E/index.html", UnsafePath::Relative),
+            ("/..%5Csecret", UnsafePath::Relative),
+            ("/%2Fetc%2Fpasswd", UnsafePath::Absolute),
+            ("/%5C%5Cserver%5Cshare", UnsafePath::Absolute),
+            ("/C%3A%5CWindows", UnsafePath::Absolute),
+            ("/index.html%00.png", UnsafePath::Control('\0')),
+            ("/index.html%0D%0ALocation:%20x", UnsafePath::Control('\r')),
+            ("/caf%C2%85", UnsafePath::Control('\u{85}')),
+        ];
+        for &(path, ref expected) in cases.iter() {
+            assert_eq!(slugs.document_path(path).as_ref(), Err(expected), "{}", path);
+        }
+        // paths are only decoded once
+        assert_eq!(slugs.document_path("/%252e%252e"), Ok(PathBuf::from("%2e%2e")));
+        // invalid UTF-8 is not decoded at all
+        assert_eq!(slugs.document_path("/%C0%AE%C0%AE"), Ok(PathBuf::from("%C0%AE%C0%AE")));
+    }
+
+    #[test]
+    fn encode_paths() {
+        assert_eq!(percent_encode("guides/setup.html"), "guides/setup.html");
+        assert_eq!(percent_encode("Foo Bar?#%"), "Foo%20Bar%3F%23%25");
+        assert_eq!(percent_encode("caf\u{e9}"), "caf%C3%A9");
+        for path in &["Foo Bar", "caf\u{e9}/na\u{ef}ve", "100%", "a+b&c=d"] {
+            assert_eq!(percent_decode(&percent_encode(path)), Some(path.to_string()));
+        }
+    }
+}
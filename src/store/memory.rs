@@ -1,146 +1,410 @@
 //! An in-memory store, useful for testing.
 use futures::future::{self, Future};
-use futures::stream::Stream;
+use futures::stream::{self, Stream};
+use futures::sync::mpsc::{self, UnboundedSender};
 use futures::{Async, Poll};
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use super::{Change, SequenceId, Store, StoreError};
+use crate::document::{Document, Edit, Event, Insert, Join, Leave, Operation, ParticipantId, Role};
+
+// The number of Events between snapshots of a document's content
+const SNAPSHOT_INTERVAL: usize = 100;
+
+// The number of shards documents are spread across, so pushes to
+// different documents rarely wait on the same lock
+const SHARDS: usize = 16;
+
+type Events = Arc<RwLock<History>>;
+// The path, SequenceId and push time of every Event, in the order
+// they were pushed
+type Log = Arc<Mutex<Vec<(PathBuf, SequenceId, SystemTime)>>>;
+
+// The history of every document, sharded by path. Each document has
+// its own lock, the shard's lock is only held while looking up or
+// adding a document.
+#[derive(Clone, Debug)]
+struct Documents(Arc<Vec<RwLock<HashMap<PathBuf, Events>>>>);
+
+impl Default for Documents {
+    fn default() -> Self {
+        Documents(Arc::new(
+            (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+        ))
+    }
+}
+
+impl Documents {
+    fn shard(&self, path: &Path) -> &RwLock<HashMap<PathBuf, Events>> {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        &self.0[hasher.finish() as usize % SHARDS]
+    }
+
+    // Returns the history of the document at 'path'
+    fn get(&self, path: &Path) -> Result<Events, StoreError> {
+        let shard = self
+            .shard(path)
+            .read()
+            .map_err(|_| StoreError::ConnectionError)?;
+        shard.get(path).cloned().ok_or(StoreError::NotFound)
+    }
+
+    // Returns the path of every document under 'prefix' which is not
+    // in the trash, in no particular order
+    fn under(&self, prefix: &Path) -> Result<Vec<PathBuf>, StoreError> {
+        let mut paths = Vec::new();
+        for shard in self.0.iter() {
+            let shard = shard.read().map_err(|_| StoreError::ConnectionError)?;
+            for (path, history) in shard.iter() {
+                if !path.starts_with(prefix) || path == prefix {
+                    continue;
+                }
+                let history = history.read().map_err(|_| StoreError::ConnectionError)?;
+                if !history.deleted {
+                    paths.push(path.clone());
+                }
+            }
+        }
+        Ok(paths)
+    }
+
+    // Returns the history of the document at 'path', adding an empty
+    // one if the document is new
+    fn get_or_insert(&self, path: &Path) -> Result<Events, StoreError> {
+        match self.get(path) {
+            Err(StoreError::NotFound) => (),
+            result => return result,
+        }
+        let mut shard = self
+            .shard(path)
+            .write()
+            .map_err(|_| StoreError::ConnectionError)?;
+        Ok(shard
+            .entry(PathBuf::from(path))
+            .or_insert_with(|| Arc::new(RwLock::new(History::default())))
+            .clone())
+    }
+}
 
-use super::{SequenceId, Store, StoreError};
-use document::{Document, Edit, Event, Insert, Join, Leave, Operation};
+// A document's Events, and its content after every SNAPSHOT_INTERVAL
+// Events so reading it does not mean replaying its whole history
+#[derive(Default, Debug)]
+struct History {
+    events: Vec<Event>,
+    // snapshots[i] is the content after (i + 1) * SNAPSHOT_INTERVAL
+    // Events
+    snapshots: Vec<Document>,
+    // whether the latest Tombstone has not been followed by a Restore
+    deleted: bool,
+}
 
-type Events = Arc<RwLock<Vec<Event>>>;
-type Documents = HashMap<PathBuf, Events>;
+impl History {
+    fn push(&mut self, event: Event) -> SequenceId {
+        match event {
+            Event::Tombstone(_) => self.deleted = true,
+            Event::Restore(_) => self.deleted = false,
+            _ => (),
+        }
+        self.events.push(event);
+        let len = self.events.len();
+        // once an invalid Event stops the snapshots, reading the
+        // content fails anyway
+        if len.is_multiple_of(SNAPSHOT_INTERVAL)
+            && self.snapshots.len() == len / SNAPSHOT_INTERVAL - 1
+        {
+            if let Ok(doc) = self.content_at(len) {
+                self.snapshots.push(doc);
+            }
+        }
+        len as SequenceId
+    }
+
+    // Returns the content after the first 'seq' Events, replaying
+    // them from the nearest snapshot
+    fn content_at(&self, seq: usize) -> Result<Document, StoreError> {
+        let (mut doc, start) = match cmp::min(seq / SNAPSHOT_INTERVAL, self.snapshots.len()) {
+            0 => (Document::default(), 0),
+            n => (self.snapshots[n - 1].clone(), n * SNAPSHOT_INTERVAL),
+        };
+        for event in &self.events[start..seq] {
+            doc.apply(event).map_err(|_| StoreError::InvalidDocument)?;
+        }
+        Ok(doc)
+    }
+}
 
 /// Holds document data in shared memory location
 #[derive(Default, Clone, Debug)]
 pub struct MemoryStore {
-    documents: Arc<RwLock<Documents>>,
+    documents: Documents,
+    log: Log,
+    participant_ids: Arc<Mutex<HashMap<PathBuf, ParticipantId>>>,
+    watchers: Watchers,
+}
+
+// Senders for the streams returned by watch() calls, keyed by path
+#[derive(Default, Clone)]
+struct Watchers(Arc<Mutex<HashMap<PathBuf, Vec<UnboundedSender<SequenceId>>>>>);
+
+impl Watchers {
+    // Sends the new SequenceId to every watcher of the path,
+    // forgetting any watchers which have since been dropped.
+    fn notify(&self, path: &Path, seq: SequenceId) {
+        if let Ok(mut watchers) = self.0.lock() {
+            if let Some(senders) = watchers.get_mut(path) {
+                senders.retain(|tx| tx.unbounded_send(seq).is_ok());
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Watchers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Watchers").finish()
+    }
 }
 
 impl Store for MemoryStore {
     type Stream = MemoryStoreStream;
-    type SinceFuture = Box<Future<Item = Self::Stream, Error = StoreError> + Send>;
-    type PushFuture = Box<Future<Item = SequenceId, Error = StoreError> + Send>;
+    type SinceFuture = Box<dyn Future<Item = Self::Stream, Error = StoreError> + Send>;
+    type PushFuture = Box<dyn Future<Item = SequenceId, Error = StoreError> + Send>;
 
     fn push(&mut self, path: PathBuf, event: Event) -> Self::PushFuture {
-        let mut documents = match self.documents.write() {
-            Ok(documents) => documents,
-            Err(_) => return Box::new(future::err(StoreError::ConnectionError)),
+        let history = match self.documents.get_or_insert(&path) {
+            Ok(history) => history,
+            Err(err) => return Box::new(future::err(err)),
         };
 
-        let events = documents
-            .entry(path)
-            .or_insert_with(|| Arc::new(RwLock::new(Vec::new())));
-
-        let mut events = match events.write() {
-            Ok(events) => events,
-            Err(_) => return Box::new(future::err(StoreError::ConnectionError)),
+        let seq = {
+            let mut history = match history.write() {
+                Ok(history) => history,
+                Err(_) => return Box::new(future::err(StoreError::ConnectionError)),
+            };
+            let seq = history.push(event);
+            // logged while the document is still locked, so its
+            // Events are logged in order
+            match self.log.lock() {
+                Ok(mut log) => log.push((path.clone(), seq, SystemTime::now())),
+                Err(_) => return Box::new(future::err(StoreError::ConnectionError)),
+            }
+            seq
         };
-
-        events.push(event);
-        Box::new(future::ok(events.len() as u64))
+        self.watchers.notify(&path, seq);
+        Box::new(future::ok(seq))
     }
 
-    fn seq(&self, path: &Path) -> Box<Future<Item = SequenceId, Error = StoreError> + Send> {
-        let documents = match self.documents.read() {
-            Ok(documents) => documents,
+    fn next_participant_id(
+        &mut self,
+        path: &Path,
+    ) -> Box<dyn Future<Item = ParticipantId, Error = StoreError> + Send> {
+        let mut ids = match self.participant_ids.lock() {
+            Ok(ids) => ids,
             Err(_) => return Box::new(future::err(StoreError::ConnectionError)),
         };
+        let id = ids.entry(PathBuf::from(path)).or_insert(0);
+        *id += 1;
+        Box::new(future::ok(*id))
+    }
 
-        let events = match documents.get(path) {
-            Some(events) => match events.read() {
-                Ok(events) => events,
-                Err(_) => return Box::new(future::err(StoreError::ConnectionError)),
-            },
-            None => return Box::new(future::err(StoreError::NotFound)),
-        };
+    fn watch(&self, path: &Path) -> Box<dyn Stream<Item = SequenceId, Error = StoreError> + Send> {
+        let (tx, rx) = mpsc::unbounded();
+        match self.watchers.0.lock() {
+            Ok(mut watchers) => watchers
+                .entry(PathBuf::from(path))
+                .or_default()
+                .push(tx),
+            Err(_) => return Box::new(stream::once(Err(StoreError::ConnectionError))),
+        }
+        Box::new(rx.map_err(|_| StoreError::ConnectionError))
+    }
 
-        Box::new(future::ok(events.len() as u64))
+    fn seq(&self, path: &Path) -> Box<dyn Future<Item = SequenceId, Error = StoreError> + Send> {
+        Box::new(future::result(
+            self.read(path, |history| Ok(history.events.len() as u64)),
+        ))
     }
 
-    fn since(&self, path: &Path, seq: SequenceId) -> Self::SinceFuture {
-        let documents = match self.documents.read() {
-            Ok(documents) => documents,
-            Err(_) => return Box::new(future::err(StoreError::ConnectionError)),
-        };
+    fn deleted(&self, path: &Path) -> Box<dyn Future<Item = bool, Error = StoreError> + Send> {
+        Box::new(future::result(self.read(path, |history| Ok(history.deleted))))
+    }
 
-        let events = match documents.get(path) {
-            Some(events) => events.clone(),
-            None => return Box::new(future::err(StoreError::NotFound)),
+    fn since(
+        &self,
+        path: &Path,
+        seq: SequenceId,
+        exclude: Option<ParticipantId>,
+    ) -> Self::SinceFuture {
+        let events = match self.documents.get(path) {
+            Ok(events) => events,
+            Err(err) => return Box::new(future::err(err)),
         };
 
         // check sequence id is valid
         match events.read() {
-            Ok(events) => if seq > events.len() as u64 {
+            Ok(history) => if seq > history.events.len() as u64 {
                 return Box::new(future::err(StoreError::InvalidSequenceId));
             },
             Err(_) => return Box::new(future::err(StoreError::ConnectionError)),
         }
 
-        Box::new(future::ok(Self::Stream { events, seq }))
+        Box::new(future::ok(Self::Stream {
+            events,
+            seq,
+            exclude,
+        }))
+    }
+
+    fn since_range(
+        &self,
+        path: &Path,
+        seq: SequenceId,
+        limit: usize,
+    ) -> Box<dyn Future<Item = Vec<(SequenceId, Event)>, Error = StoreError> + Send> {
+        Box::new(future::result(self.read(path, |history| {
+            let len = history.events.len() as SequenceId;
+            if seq > len {
+                return Err(StoreError::InvalidSequenceId);
+            }
+            let end = cmp::min(len, seq.saturating_add(limit as SequenceId));
+            let events = &history.events[seq as usize..end as usize];
+            Ok((seq + 1..end + 1).zip(events.iter().cloned()).collect())
+        })))
     }
 
     fn content(
         &self,
         path: &Path,
-    ) -> Box<Future<Item = (SequenceId, Document), Error = StoreError> + Send> {
-        Box::new(self.since(path, 0).and_then(|stream| {
-            stream.fold(
-                (0, Document::default()),
-                |(_seq, mut doc), (seq, ref event)| match doc.apply(event) {
-                    Err(_) => future::err(StoreError::InvalidDocument),
-                    Ok(_) => future::ok((seq, doc)),
-                },
-            )
-        }))
+    ) -> Box<dyn Future<Item = (SequenceId, Document), Error = StoreError> + Send> {
+        Box::new(future::result(self.read(path, |history| {
+            let seq = history.events.len();
+            history
+                .content_at(seq)
+                .map(|doc| (seq as SequenceId, doc))
+        })))
     }
 
     fn content_at(
         &self,
         path: &Path,
         seq: SequenceId,
-    ) -> Box<Future<Item = Document, Error = StoreError> + Send> {
-        let check_seq = self.seq(path).and_then(move |head| {
-            if seq > head {
-                future::err(StoreError::InvalidSequenceId)
+    ) -> Box<dyn Future<Item = Document, Error = StoreError> + Send> {
+        Box::new(future::result(self.read(path, |history| {
+            if seq > history.events.len() as SequenceId {
+                Err(StoreError::InvalidSequenceId)
             } else {
-                future::ok(())
+                history.content_at(seq as usize)
             }
-        });
-        let doc = self.since(path, 0).and_then(move |stream| {
-            stream
-                .take(seq)
-                .fold(
-                    Document::default(),
-                    |mut doc, (_seq, ref event)| match doc.apply(event) {
-                        Err(_) => future::err(StoreError::InvalidDocument),
-                        Ok(_) => future::ok(doc),
-                    },
-                )
-        });
-        Box::new(check_seq.and_then(move |_| doc))
+        })))
+    }
+
+    fn changes(&self) -> Box<dyn Stream<Item = Change, Error = StoreError> + Send> {
+        // push() locks the log while holding a document's lock, so
+        // the log is copied before any documents are locked
+        let log = match self.log.lock() {
+            Ok(log) => log.clone(),
+            Err(_) => return Box::new(stream::once(Err(StoreError::ConnectionError))),
+        };
+        let mut changes = Vec::with_capacity(log.len());
+        for (path, seq, time) in log.into_iter().rev() {
+            let history = match self.documents.get(&path) {
+                Ok(history) => history,
+                Err(err) => return Box::new(stream::once(Err(err))),
+            };
+            let history = match history.read() {
+                Ok(history) => history,
+                Err(_) => return Box::new(stream::once(Err(StoreError::ConnectionError))),
+            };
+            let previous = &history.events[..seq as usize];
+            let event = previous[previous.len() - 1].clone();
+            let id = event.participant();
+            // the participant's most recent Join before the event
+            let user = previous
+                .iter()
+                .rev()
+                .filter_map(|event| match event {
+                    Event::Join(join) if join.id == id => Some(join.user.clone()),
+                    _ => None,
+                }).next()
+                .unwrap_or(None);
+            changes.push(Change {
+                path,
+                seq,
+                user,
+                event,
+                time,
+            });
+        }
+        Box::new(stream::iter_ok(changes))
+    }
+
+    fn list(
+        &self,
+        prefix: &Path,
+    ) -> Box<dyn Future<Item = Vec<PathBuf>, Error = StoreError> + Send> {
+        Box::new(future::result(self.documents.under(prefix).map(|mut paths| {
+            paths.sort();
+            paths
+        })))
+    }
+}
+
+impl MemoryStore {
+    // Calls 'f' with the history of the document at 'path'
+    fn read<F, R>(&self, path: &Path, f: F) -> Result<R, StoreError>
+    where
+        F: FnOnce(&History) -> Result<R, StoreError>,
+    {
+        let history = self.documents.get(path)?;
+        let history = history.read().map_err(|_| StoreError::ConnectionError)?;
+        f(&history)
     }
 }
 
 impl From<HashMap<String, String>> for MemoryStore {
     fn from(data: HashMap<String, String>) -> Self {
-        let mut documents: Documents = Default::default();
+        let documents = Documents::default();
+        let mut log = Vec::new();
+        let now = SystemTime::now();
         for (k, v) in data {
-            documents.insert(
-                PathBuf::from(k),
-                Arc::new(RwLock::new(vec![
-                    Event::Join(Join { id: 1 }),
-                    Event::Edit(Edit {
-                        author: 1,
-                        operations: vec![Operation::Insert(Insert { pos: 0, content: v })],
-                    }),
-                    Event::Leave(Leave { id: 1 }),
-                ])),
-            );
+            for seq in 1..4 {
+                log.push((PathBuf::from(&k), seq, now));
+            }
+            let mut history = History::default();
+            let events = vec![
+                Event::Join(Join {
+                    id: 1,
+                    role: Role::Editor,
+                    user: None,
+                }),
+                Event::Edit(Edit {
+                    author: 1,
+                    operations: vec![Operation::Insert(Insert { pos: 0, content: v })],
+                }),
+                Event::Leave(Leave { id: 1 }),
+            ];
+            for event in events {
+                history.push(event);
+            }
+            let path = PathBuf::from(k);
+            // the store is new, so its locks cannot be poisoned
+            documents
+                .shard(&path)
+                .write()
+                .unwrap()
+                .insert(path, Arc::new(RwLock::new(history)));
         }
         MemoryStore {
-            documents: Arc::new(RwLock::new(documents)),
+            documents,
+            log: Arc::new(Mutex::new(log)),
+            ..Default::default()
         }
     }
 }
@@ -179,6 +443,8 @@ macro_rules! memorystore {
 pub struct MemoryStoreStream {
     events: Events,
     seq: SequenceId,
+    // the participant whose Events are skipped
+    exclude: Option<ParticipantId>,
 }
 
 impl Stream for MemoryStoreStream {
@@ -187,17 +453,16 @@ impl Stream for MemoryStoreStream {
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         match self.events.read() {
-            Ok(events) => {
+            Ok(history) => loop {
                 self.seq += 1;
-                if self.seq <= events.len() as u64 {
-                    Ok(Async::Ready(Some((
-                        self.seq,
-                        events[(self.seq - 1) as usize].clone(),
-                    ))))
-                } else {
-                    Ok(Async::Ready(None))
+                if self.seq > history.events.len() as u64 {
+                    return Ok(Async::Ready(None));
                 }
-            }
+                let event = &history.events[(self.seq - 1) as usize];
+                if self.exclude != Some(event.participant()) {
+                    return Ok(Async::Ready(Some((self.seq, event.clone()))));
+                }
+            },
             Err(_) => Err(StoreError::ConnectionError),
         }
     }
@@ -206,7 +471,7 @@ impl Stream for MemoryStoreStream {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use document::{DocumentParticipant, Insert, Join, Operation};
+    use crate::document::{DocumentParticipant, Insert, Join, Operation, Restore, Role, Tombstone};
 
     #[test]
     fn memory_store_push() {
@@ -293,7 +558,7 @@ mod tests {
         let b0 = b.clone();
         let c0 = c.clone();
         let since0 = store
-            .since(&PathBuf::from("/foo/bar").as_path(), 0)
+            .since(Path::new("/foo/bar"), 0, None)
             .and_then(|stream| {
                 stream.collect().map_err(|err| {
                     panic!("{}", err);
@@ -305,7 +570,7 @@ mod tests {
         let b1 = b.clone();
         let c1 = c.clone();
         let since1 = store
-            .since(&PathBuf::from("/foo/bar").as_path(), 1)
+            .since(Path::new("/foo/bar"), 1, None)
             .and_then(|stream| {
                 stream.collect().map_err(|err| {
                     panic!("{}", err);
@@ -316,7 +581,7 @@ mod tests {
 
         let c2 = c.clone();
         let since2 = store
-            .since(&PathBuf::from("/foo/bar").as_path(), 2)
+            .since(Path::new("/foo/bar"), 2, None)
             .and_then(|stream| {
                 stream.collect().map_err(|err| {
                     panic!("{}", err);
@@ -326,7 +591,7 @@ mod tests {
             });
 
         let since3 = store
-            .since(&PathBuf::from("/foo/bar").as_path(), 3)
+            .since(Path::new("/foo/bar"), 3, None)
             .and_then(|stream| {
                 stream.collect().map_err(|err| {
                     panic!("{}", err);
@@ -338,11 +603,11 @@ mod tests {
             });
 
         let since4 = store
-            .since(&PathBuf::from("/foo/bar").as_path(), 4)
+            .since(Path::new("/foo/bar"), 4, None)
             .map(|_| {
                 // requesting events since a sequence id not in the store
                 // is invalid, however
-                assert!(false)
+                panic!("expected an error");
             }).or_else(|err| match err {
                 StoreError::InvalidSequenceId => future::ok(()),
                 _ => future::err(err),
@@ -358,6 +623,74 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn memory_store_since_excluding() {
+        let mut store = MemoryStore::default();
+        let path = PathBuf::from("/foo/bar");
+        for id in 1..3 {
+            store
+                .push(
+                    path.clone(),
+                    Event::Join(Join {
+                        id,
+                        role: Role::Editor,
+                        user: None,
+                    }),
+                ).wait()
+                .unwrap();
+        }
+        store
+            .push(path.clone(), Event::Leave(Leave { id: 1 }))
+            .wait()
+            .unwrap();
+        let events: Vec<_> = store
+            .since(&path, 0, Some(1))
+            .flatten_stream()
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![(
+                2,
+                Event::Join(Join {
+                    id: 2,
+                    role: Role::Editor,
+                    user: None,
+                })
+            )]
+        );
+    }
+
+    #[test]
+    fn memory_store_since_range() {
+        let mut store = MemoryStore::default();
+        let path = PathBuf::from("/foo/bar");
+        let leave = |id| Event::Leave(Leave { id });
+        for id in 1..6 {
+            store.push(path.clone(), leave(id)).wait().unwrap();
+        }
+        assert_eq!(
+            store.since_range(&path, 0, 2).wait(),
+            Ok(vec![(1, leave(1)), (2, leave(2))])
+        );
+        assert_eq!(
+            store.since_range(&path, 2, 2).wait(),
+            Ok(vec![(3, leave(3)), (4, leave(4))])
+        );
+        // the last range is shorter than the limit
+        assert_eq!(store.since_range(&path, 4, 2).wait(), Ok(vec![(5, leave(5))]));
+        assert_eq!(store.since_range(&path, 5, 2).wait(), Ok(vec![]));
+        assert_eq!(
+            store.since_range(&path, 6, 2).wait(),
+            Err(StoreError::InvalidSequenceId)
+        );
+        assert_eq!(
+            store.since_range(&PathBuf::from("/missing"), 0, 2).wait(),
+            Err(StoreError::NotFound)
+        );
+    }
+
     #[test]
     fn memory_store_seq() {
         let mut store = MemoryStore::default();
@@ -395,19 +728,19 @@ mod tests {
             }),
         );
 
-        let seq1 = store.seq(&PathBuf::from("/foo/bar").as_path()).map(|seq| {
+        let seq1 = store.seq(Path::new("/foo/bar")).map(|seq| {
             assert_eq!(seq, 1);
         });
 
-        let seq2 = store.seq(&PathBuf::from("/asdf").as_path()).map(|seq| {
+        let seq2 = store.seq(Path::new("/asdf")).map(|seq| {
             assert_eq!(seq, 2);
         });
 
         let seq3 = store
-            .seq(&PathBuf::from("/not_found").as_path())
+            .seq(Path::new("/not_found"))
             .map(|_seq| {
                 // requesting a non-existing path is an error
-                assert!(false);
+                panic!("expected an error");
             }).or_else(|err| match err {
                 StoreError::NotFound => future::ok(()),
                 _ => future::err(err),
@@ -429,7 +762,14 @@ mod tests {
         // later we'll test that the cloned store has the same data
         let store_clone = store.clone();
 
-        let push1 = store.push(PathBuf::from("/asdf"), Event::Join(Join { id: 1 }));
+        let push1 = store.push(
+            PathBuf::from("/asdf"),
+            Event::Join(Join {
+                id: 1,
+                role: Role::Editor,
+                user: None,
+            }),
+        );
 
         let push2 = store.push(
             PathBuf::from("/asdf"),
@@ -454,24 +794,29 @@ mod tests {
         );
 
         let content1 = store
-            .content(&PathBuf::from("/asdf").as_path())
+            .content(Path::new("/asdf"))
             .map(|(seq, doc)| {
                 assert_eq!(seq, 3);
                 assert_eq!(
                     doc,
                     Document {
                         content: String::from("Hello, world"),
-                        participants: vec![(1, DocumentParticipant { cursor_pos: 12 }),]
+                        participants: vec![(1, DocumentParticipant {
+                            cursor_pos: 12,
+                            user: None,
+                        }),]
                             .into_iter()
                             .collect(),
+                        deleted: false,
+                        published: None,
                     }
                 );
             });
 
         let content2 = store
-            .content(&PathBuf::from("/missing").as_path())
+            .content(Path::new("/missing"))
             .map(|_| {
-                assert!(false);
+                panic!("expected an error");
             }).or_else(|err| match err {
                 StoreError::NotFound => future::ok(()),
                 _ => future::err(err),
@@ -479,16 +824,21 @@ mod tests {
 
         // check cloned store has same data
         let content3 = store_clone
-            .content(&PathBuf::from("/asdf").as_path())
+            .content(Path::new("/asdf"))
             .map(|(seq, doc)| {
                 assert_eq!(seq, 3);
                 assert_eq!(
                     doc,
                     Document {
                         content: String::from("Hello, world"),
-                        participants: vec![(1, DocumentParticipant { cursor_pos: 12 }),]
+                        participants: vec![(1, DocumentParticipant {
+                            cursor_pos: 12,
+                            user: None,
+                        }),]
                             .into_iter()
                             .collect(),
+                        deleted: false,
+                        published: None,
                     }
                 );
             });
@@ -507,7 +857,14 @@ mod tests {
     fn memory_store_content_at() {
         let mut store = MemoryStore::default();
 
-        let push1 = store.push(PathBuf::from("/asdf"), Event::Join(Join { id: 1 }));
+        let push1 = store.push(
+            PathBuf::from("/asdf"),
+            Event::Join(Join {
+                id: 1,
+                role: Role::Editor,
+                user: None,
+            }),
+        );
 
         let push2 = store.push(
             PathBuf::from("/asdf"),
@@ -532,75 +889,92 @@ mod tests {
         );
 
         let content0 = store
-            .content_at(&PathBuf::from("/asdf").as_path(), 0)
+            .content_at(Path::new("/asdf"), 0)
             .map(|doc| {
                 assert_eq!(
                     doc,
                     Document {
                         content: String::from(""),
                         participants: Default::default(),
+                        deleted: false,
+                        published: None,
                     }
                 );
             });
 
         let content1 = store
-            .content_at(&PathBuf::from("/asdf").as_path(), 1)
+            .content_at(Path::new("/asdf"), 1)
             .map(|doc| {
                 assert_eq!(
                     doc,
                     Document {
                         content: String::from(""),
-                        participants: vec![(1, DocumentParticipant { cursor_pos: 0 }),]
+                        participants: vec![(1, DocumentParticipant {
+                            cursor_pos: 0,
+                            user: None,
+                        }),]
                             .into_iter()
                             .collect(),
+                        deleted: false,
+                        published: None,
                     }
                 );
             });
 
         let content2 = store
-            .content_at(&PathBuf::from("/asdf").as_path(), 2)
+            .content_at(Path::new("/asdf"), 2)
             .map(|doc| {
                 assert_eq!(
                     doc,
                     Document {
                         content: String::from("Hello"),
-                        participants: vec![(1, DocumentParticipant { cursor_pos: 5 }),]
+                        participants: vec![(1, DocumentParticipant {
+                            cursor_pos: 5,
+                            user: None,
+                        }),]
                             .into_iter()
                             .collect(),
+                        deleted: false,
+                        published: None,
                     }
                 );
             });
 
         let content3 = store
-            .content_at(&PathBuf::from("/asdf").as_path(), 3)
+            .content_at(Path::new("/asdf"), 3)
             .map(|doc| {
                 assert_eq!(
                     doc,
                     Document {
                         content: String::from("Hello, world"),
-                        participants: vec![(1, DocumentParticipant { cursor_pos: 12 }),]
+                        participants: vec![(1, DocumentParticipant {
+                            cursor_pos: 12,
+                            user: None,
+                        }),]
                             .into_iter()
                             .collect(),
+                        deleted: false,
+                        published: None,
                     }
                 );
             });
 
         let content4 = store
-            .content_at(&PathBuf::from("/asdf").as_path(), 4)
+            .content_at(Path::new("/asdf"), 4)
             .map(|_| {
                 // requesting a sequence number higher than the number of
                 // events return error
-                assert!(false);
+                panic!("expected an error");
             }).or_else(|err| match err {
                 StoreError::InvalidSequenceId => future::ok(()),
                 _ => future::err(err),
             });
 
         let content5 = store
-            .content_at(&PathBuf::from("/missing").as_path(), 0)
+            .content_at(Path::new("/missing"), 0)
             .map(|_| {
                 // requesting a missing document is an error
-                assert!(false);
+                panic!("expected an error");
             }).or_else(|err| match err {
                 StoreError::NotFound => future::ok(()),
                 _ => future::err(err),
@@ -622,4 +996,212 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn memory_store_snapshots() {
+        let mut store = MemoryStore::default();
+        let path = PathBuf::from("/asdf");
+        store
+            .push(
+                path.clone(),
+                Event::Join(Join {
+                    id: 1,
+                    role: Role::Editor,
+                    user: None,
+                }),
+            ).wait()
+            .unwrap();
+        for _ in 1..250 {
+            store
+                .push(
+                    path.clone(),
+                    Event::Edit(Edit {
+                        author: 1,
+                        operations: vec![Operation::Insert(Insert {
+                            pos: 0,
+                            content: String::from("a"),
+                        })],
+                    }),
+                ).wait()
+                .unwrap();
+        }
+        {
+            let history = store.documents.get(&path).unwrap();
+            assert_eq!(history.read().unwrap().snapshots.len(), 2);
+        }
+        let (seq, doc) = store.content(&path).wait().unwrap();
+        assert_eq!(seq, 250);
+        assert_eq!(doc.content.len(), 249);
+        // before, at and after a snapshot
+        for &(seq, len) in &[(99, 98), (100, 99), (150, 149), (200, 199)] {
+            let doc = store.content_at(&path, seq).wait().unwrap();
+            assert_eq!(doc.content.len(), len);
+        }
+    }
+
+    #[test]
+    fn memory_store_list() {
+        let paths = [
+            "index.html",
+            "guides",
+            "guides/setup.html",
+            "guides/setup/linux.html",
+            "guidesandtips.html",
+        ];
+        let store = MemoryStore::from(
+            paths
+                .iter()
+                .map(|path| (String::from(*path), String::from("Content")))
+                .collect::<HashMap<_, _>>(),
+        );
+        assert_eq!(
+            store.list(Path::new("guides")).wait().unwrap(),
+            vec![
+                PathBuf::from("guides/setup/linux.html"),
+                PathBuf::from("guides/setup.html"),
+            ]
+        );
+        assert_eq!(store.list(Path::new("")).wait().unwrap().len(), 5);
+        assert!(store.list(Path::new("index.html")).wait().unwrap().is_empty());
+        assert!(store.list(Path::new("missing")).wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_store_list_skips_deleted() {
+        let mut store = MemoryStore::from(
+            vec![("guides/setup.html", "Setup"), ("guides/usage.html", "Usage")]
+                .into_iter()
+                .map(|(path, content)| (String::from(path), String::from(content)))
+                .collect::<HashMap<_, _>>(),
+        );
+        let path = PathBuf::from("guides/setup.html");
+        let join = Event::Join(Join {
+            id: 2,
+            role: Role::Editor,
+            user: None,
+        });
+        store.push(path.clone(), join).wait().unwrap();
+        store
+            .push(path.clone(), Event::Tombstone(Tombstone { id: 2 }))
+            .wait()
+            .unwrap();
+        assert_eq!(
+            store.list(Path::new("guides")).wait().unwrap(),
+            vec![PathBuf::from("guides/usage.html")]
+        );
+        // the history is kept, so it can still be read
+        assert!(store.content(&path).wait().unwrap().1.deleted);
+        assert!(store.deleted(&path).wait().unwrap());
+        store
+            .push(path.clone(), Event::Restore(Restore { id: 2 }))
+            .wait()
+            .unwrap();
+        assert_eq!(store.list(Path::new("guides")).wait().unwrap().len(), 2);
+        assert!(!store.deleted(&path).wait().unwrap());
+        assert_eq!(
+            store.deleted(Path::new("guides/missing.html")).wait(),
+            Err(StoreError::NotFound)
+        );
+    }
+
+    #[test]
+    fn memory_store_next_participant_id() {
+        let mut store = MemoryStore::default();
+        // clones share the same participant ids
+        let mut store_clone = store.clone();
+
+        let a = Path::new("/foo");
+        let b = Path::new("/bar");
+
+        assert_eq!(store.next_participant_id(a).wait(), Ok(1));
+        assert_eq!(store_clone.next_participant_id(a).wait(), Ok(2));
+        // participant ids are unique per document
+        assert_eq!(store.next_participant_id(b).wait(), Ok(1));
+        assert_eq!(store.next_participant_id(a).wait(), Ok(3));
+    }
+
+    #[test]
+    fn memory_store_watch() {
+        let mut store = MemoryStore::default();
+        let watcher = store.watch(Path::new("/asdf"));
+        let other = store.watch(Path::new("/other"));
+        let join = Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: None,
+        });
+
+        store
+            .push(PathBuf::from("/asdf"), join.clone())
+            .wait()
+            .unwrap();
+        // pushing to a different document does not notify the watcher
+        store
+            .push(PathBuf::from("/foo"), join.clone())
+            .wait()
+            .unwrap();
+        store
+            .push(PathBuf::from("/asdf"), Event::Leave(Leave { id: 1 }))
+            .wait()
+            .unwrap();
+
+        assert_eq!(watcher.take(2).collect().wait(), Ok(vec![1, 2]));
+
+        // dropped watchers are forgotten by the store
+        drop(other);
+        store
+            .push(PathBuf::from("/other"), join.clone())
+            .wait()
+            .unwrap();
+        assert!(
+            store
+                .watchers
+                .0
+                .lock()
+                .unwrap()
+                .get(Path::new("/other"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn memory_store_changes() {
+        let mut store = MemoryStore::default();
+        let join = Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: Some(String::from("alice")),
+        });
+        let edit = Event::Edit(Edit {
+            author: 1,
+            operations: vec![Operation::Insert(Insert {
+                pos: 0,
+                content: String::from("Hello"),
+            })],
+        });
+        store.push(PathBuf::from("a"), join.clone()).wait().unwrap();
+        store.push(PathBuf::from("b"), edit.clone()).wait().unwrap();
+        store.push(PathBuf::from("a"), edit.clone()).wait().unwrap();
+
+        let changes: Vec<Change> = store.changes().collect().wait().unwrap();
+        assert!(changes.windows(2).all(|pair| pair[0].time >= pair[1].time));
+        let changes: Vec<(PathBuf, SequenceId, Option<String>, Event)> = changes
+            .into_iter()
+            .map(|change| (change.path, change.seq, change.user, change.event))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    PathBuf::from("a"),
+                    2,
+                    Some(String::from("alice")),
+                    edit.clone(),
+                ),
+                // participant 1 did not join document "b"
+                (PathBuf::from("b"), 1, None, edit),
+                (PathBuf::from("a"), 1, Some(String::from("alice")), join),
+            ]
+        );
+    }
 }
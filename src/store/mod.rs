@@ -12,10 +12,13 @@ use futures::stream::Stream;
 use std::error::Error;
 use std::fmt::{self, Debug, Display};
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-use document::{Document, Event};
+use crate::document::{Document, Event, ParticipantId};
 
 pub mod memory;
+pub mod pool;
+pub mod traced;
 
 /// The sequence number for an Event. The first Event for a Document
 /// is SequenceId=1 (not 0). This is so requesting events since
@@ -39,16 +42,61 @@ pub trait Store: Clone + Debug + Send + 'static {
     /// pushing an Event creates it.
     fn push(&mut self, path: PathBuf, event: Event) -> Self::PushFuture;
 
+    /// Allocates a ParticipantId for a new participant editing the
+    /// document at 'path'. The ParticipantId must not be in use by
+    /// any other participant currently editing the document,
+    /// including participants connected via other clients of the
+    /// same backing store.
+    fn next_participant_id(
+        &mut self,
+        path: &Path,
+    ) -> Box<dyn Future<Item = ParticipantId, Error = StoreError> + Send>;
+
+    /// Requests a stream of the document's latest SequenceId, which
+    /// yields a new value each time an Event is pushed to the
+    /// document at 'path' (including Events pushed by other clients
+    /// of the same backing store). Notifications may be coalesced,
+    /// so only the most recent SequenceId is guaranteed to be
+    /// delivered. The document does not need to exist yet.
+    fn watch(&self, path: &Path) -> Box<dyn Stream<Item = SequenceId, Error = StoreError> + Send>;
+
     /// Requests the current SequenceId for the document at 'path',
     /// or StoreError::NotFound if it does not exist.
-    fn seq(&self, path: &Path) -> Box<Future<Item = SequenceId, Error = StoreError> + Send>;
+    fn seq(&self, path: &Path) -> Box<dyn Future<Item = SequenceId, Error = StoreError> + Send>;
+
+    /// Requests whether the document at 'path' is in the trash, or
+    /// StoreError::NotFound if it does not exist. Unlike `content()`,
+    /// this does not need the document's Events to be applied.
+    fn deleted(&self, path: &Path) -> Box<dyn Future<Item = bool, Error = StoreError> + Send>;
 
     /// Requests a stream of Events starting *after* the provided
     /// SequenceId. Requesting the current (head) SequenceId is not an
     /// error, but will return an empty stream. Requesting Events
     /// since a SequenceId that does not exist yet is a
-    /// StoreError::InvalidSequenceId.
-    fn since(&self, path: &Path, seq: SequenceId) -> Self::SinceFuture;
+    /// StoreError::InvalidSequenceId. Events joining, editing or
+    /// leaving as the participant 'exclude' are left out of the
+    /// stream, for participants catching up with everyone else's
+    /// changes.
+    fn since(
+        &self,
+        path: &Path,
+        seq: SequenceId,
+        exclude: Option<ParticipantId>,
+    ) -> Self::SinceFuture;
+
+    /// Requests up to 'limit' Events starting *after* the provided
+    /// SequenceId, in order. Fewer than 'limit' Events are returned
+    /// only once the end of the document is reached, so the next
+    /// range can be requested from the SequenceId of the last Event
+    /// until then. SequenceIds are validated in the same way as
+    /// `since()`. This lets clients which are far behind catch up in
+    /// bounded chunks instead of reading one long stream.
+    fn since_range(
+        &self,
+        path: &Path,
+        seq: SequenceId,
+        limit: usize,
+    ) -> Box<dyn Future<Item = Vec<(SequenceId, Event)>, Error = StoreError> + Send>;
 
     /// Requests the current SequenceId and content for the document
     /// at 'path' (with all events applied), or StoreError::NotFound
@@ -56,7 +104,7 @@ pub trait Store: Clone + Debug + Send + 'static {
     fn content(
         &self,
         path: &Path,
-    ) -> Box<Future<Item = (SequenceId, Document), Error = StoreError> + Send>;
+    ) -> Box<dyn Future<Item = (SequenceId, Document), Error = StoreError> + Send>;
 
     /// Requests a snapshot of the document's content at a specific
     /// SequenceId. All events from SequenceId=1 (inclusive) to
@@ -68,7 +116,38 @@ pub trait Store: Clone + Debug + Send + 'static {
         &self,
         path: &Path,
         seq: SequenceId,
-    ) -> Box<Future<Item = Document, Error = StoreError> + Send>;
+    ) -> Box<dyn Future<Item = Document, Error = StoreError> + Send>;
+
+    /// Requests a stream of the Events pushed to every document in
+    /// the store, most recent first. Unlike `since()`, which follows
+    /// a single document, this interleaves Events from all documents
+    /// in the order they were pushed.
+    fn changes(&self) -> Box<dyn Stream<Item = Change, Error = StoreError> + Send>;
+
+    /// Requests the paths of every document under 'prefix', sorted.
+    /// The document at 'prefix' itself is not included, and an empty
+    /// prefix lists every document in the store. Documents in the
+    /// trash are left out.
+    fn list(
+        &self,
+        prefix: &Path,
+    ) -> Box<dyn Future<Item = Vec<PathBuf>, Error = StoreError> + Send>;
+}
+
+/// An Event pushed to a document, as returned by `Store::changes()`
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Change {
+    /// The document the Event was pushed to
+    pub path: PathBuf,
+    /// The Event's SequenceId within the document
+    pub seq: SequenceId,
+    /// The authenticated user the Event's participant was acting on
+    /// behalf of, from their Join event
+    pub user: Option<String>,
+    /// The Event itself
+    pub event: Event,
+    /// When the Event was pushed to the store
+    pub time: SystemTime,
 }
 
 /// Error conditions for reading data from, or writing data to, the
@@ -0,0 +1,284 @@
+//! A pool of connections to a database, for Store backends to share
+//! connections between requests instead of each managing their own.
+use futures::future::{self, Either, Future};
+use futures::sync::oneshot;
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use super::StoreError;
+
+/// Opens and checks the connections in a Pool
+pub trait Manager: Send + Sync + 'static {
+    /// The connection type
+    type Connection: Send + 'static;
+
+    /// Opens a new connection
+    fn connect(&self) -> Result<Self::Connection, StoreError>;
+
+    /// Checks an idle connection still works before it is reused,
+    /// returning an error if it should be closed instead
+    fn check(&self, conn: &mut Self::Connection) -> Result<(), StoreError>;
+}
+
+/// A cloneable handle to a pool of up to 'max_size' connections.
+/// Connections are opened when needed and kept for reuse once
+/// returned, and requests for a connection while they are all in use
+/// wait for the next one to be returned.
+pub struct Pool<M: Manager> {
+    inner: Arc<Inner<M>>,
+}
+
+struct Inner<M: Manager> {
+    manager: M,
+    max_size: usize,
+    state: Mutex<State<M::Connection>>,
+}
+
+struct State<C> {
+    // connections waiting to be reused
+    idle: Vec<C>,
+    // the number of connections open, including those in use
+    size: usize,
+    // requests waiting for a connection to be returned, oldest first
+    waiting: VecDeque<oneshot::Sender<C>>,
+}
+
+impl<M: Manager> Clone for Pool<M> {
+    fn clone(&self) -> Self {
+        Pool {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<M: Manager> Pool<M> {
+    /// Creates an empty pool, which will open up to 'max_size'
+    /// connections using 'manager'
+    pub fn new(manager: M, max_size: usize) -> Self {
+        assert!(max_size > 0, "a pool must allow at least one connection");
+        Pool {
+            inner: Arc::new(Inner {
+                manager,
+                max_size,
+                state: Mutex::new(State {
+                    idle: Vec::new(),
+                    size: 0,
+                    waiting: VecDeque::new(),
+                }),
+            }),
+        }
+    }
+
+    /// Resolves to a connection, which is returned to the pool when
+    /// dropped. An idle connection is reused if one passes its
+    /// check, otherwise a new one is opened, unless the pool is full
+    /// in which case this waits for a connection to be returned.
+    pub fn get(&self) -> impl Future<Item = Pooled<M>, Error = StoreError> {
+        let pool = self.clone();
+        let pooled = move |conn| Pooled {
+            pool,
+            conn: Some(conn),
+        };
+        match self.checkout() {
+            Ok(Checkout::Ready(conn)) => Either::A(future::ok(pooled(conn))),
+            Ok(Checkout::Waiting(rx)) => {
+                Either::B(rx.map_err(|_| StoreError::ConnectionError).map(pooled))
+            }
+            Err(err) => Either::A(future::err(err)),
+        }
+    }
+
+    /// The number of open connections, and how many of them are idle
+    pub fn size(&self) -> (usize, usize) {
+        let state = self.inner.state.lock().unwrap();
+        (state.size, state.idle.len())
+    }
+
+    // Returns an idle connection, or a new one if there is room for
+    // it, otherwise queues a request for the next connection returned
+    fn checkout(&self) -> Result<Checkout<M::Connection>, StoreError> {
+        loop {
+            let mut state = self.inner.state.lock().unwrap();
+            match state.idle.pop() {
+                Some(mut conn) => {
+                    drop(state);
+                    if self.inner.manager.check(&mut conn).is_ok() {
+                        return Ok(Checkout::Ready(conn));
+                    }
+                    // closed, try the next one
+                    self.inner.state.lock().unwrap().size -= 1;
+                }
+                None if state.size < self.inner.max_size => {
+                    state.size += 1;
+                    drop(state);
+                    return self.connect().map(Checkout::Ready);
+                }
+                None => {
+                    let (tx, rx) = oneshot::channel();
+                    state.waiting.push_back(tx);
+                    return Ok(Checkout::Waiting(rx));
+                }
+            }
+        }
+    }
+
+    // Opens a connection, which has already been counted in the
+    // pool's size
+    fn connect(&self) -> Result<M::Connection, StoreError> {
+        self.inner.manager.connect().inspect_err(|_| {
+            self.inner.state.lock().unwrap().size -= 1;
+        })
+    }
+
+    // Forgets a connection which was closed, opening another in its
+    // place if a request is waiting for one
+    fn closed(&self) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            state.size -= 1;
+            if state.waiting.is_empty() {
+                return;
+            }
+            state.size += 1;
+        }
+        match self.connect() {
+            Ok(conn) => self.checkin(conn),
+            // the oldest request fails rather than waiting for a
+            // connection which may never be returned
+            Err(_) => {
+                self.inner.state.lock().unwrap().waiting.pop_front();
+            }
+        }
+    }
+
+    // Hands a returned connection to the oldest waiting request, or
+    // keeps it for reuse if there is none
+    fn checkin(&self, mut conn: M::Connection) {
+        let mut state = self.inner.state.lock().unwrap();
+        while let Some(tx) = state.waiting.pop_front() {
+            match tx.send(conn) {
+                Ok(()) => return,
+                // the request was dropped while waiting
+                Err(returned) => conn = returned,
+            }
+        }
+        state.idle.push(conn);
+    }
+}
+
+// The result of checking out a connection
+enum Checkout<C> {
+    Ready(C),
+    Waiting(oneshot::Receiver<C>),
+}
+
+/// A connection checked out of a Pool, returned to it when dropped
+pub struct Pooled<M: Manager> {
+    pool: Pool<M>,
+    conn: Option<M::Connection>,
+}
+
+impl<M: Manager> Pooled<M> {
+    /// Closes the connection instead of returning it to the pool,
+    /// for connections which are known to be broken
+    pub fn close(mut self) {
+        self.conn.take();
+        self.pool.closed();
+    }
+}
+
+impl<M: Manager> Deref for Pooled<M> {
+    type Target = M::Connection;
+
+    fn deref(&self) -> &M::Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl<M: Manager> DerefMut for Pooled<M> {
+    fn deref_mut(&mut self) -> &mut M::Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl<M: Manager> Drop for Pooled<M> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.checkin(conn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Connections are numbered in the order they were opened, and
+    // fail their check once 'broken' is set to their number
+    #[derive(Default)]
+    struct Counter {
+        opened: AtomicUsize,
+        broken: AtomicUsize,
+    }
+
+    impl Manager for Arc<Counter> {
+        type Connection = usize;
+
+        fn connect(&self) -> Result<usize, StoreError> {
+            Ok(self.opened.fetch_add(1, Ordering::SeqCst) + 1)
+        }
+
+        fn check(&self, conn: &mut usize) -> Result<(), StoreError> {
+            if *conn == self.broken.load(Ordering::SeqCst) {
+                Err(StoreError::ConnectionError)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn reuse_connections() {
+        let counter = Arc::new(Counter::default());
+        let pool = Pool::new(counter.clone(), 2);
+        let first = pool.get().wait().unwrap();
+        let second = pool.get().wait().unwrap();
+        assert_eq!((*first, *second), (1, 2));
+        assert_eq!(pool.size(), (2, 0));
+
+        // the pool is full, so the next request waits for a
+        // connection to be returned
+        let waiting = pool.get();
+        drop(first);
+        assert_eq!(pool.size(), (2, 0));
+        let third = waiting.wait().unwrap();
+        assert_eq!(*third, 1);
+
+        drop(second);
+        assert_eq!(pool.size(), (2, 1));
+        assert_eq!(*pool.get().wait().unwrap(), 2);
+
+        // closing a connection makes room for a new one
+        let _second = pool.get().wait().unwrap();
+        let waiting = pool.get();
+        third.close();
+        assert_eq!(*waiting.wait().unwrap(), 3);
+        assert_eq!(counter.opened.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn close_broken_connections() {
+        let counter = Arc::new(Counter::default());
+        let pool = Pool::new(counter.clone(), 2);
+        drop(pool.get().wait().unwrap());
+        counter.broken.store(1, Ordering::SeqCst);
+        // the idle connection fails its check, so another is opened
+        let conn = pool.get().wait().unwrap();
+        assert_eq!(*conn, 2);
+        assert_eq!(pool.size(), (1, 0));
+        conn.close();
+        assert_eq!(pool.size(), (0, 0));
+    }
+}
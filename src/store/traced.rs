@@ -0,0 +1,159 @@
+//! Wraps a store, recording a tracing span for each call so the time
+//! spent in the store shows up in request traces.
+//!
+//! The spans cover the work done when the call is made as well as
+//! polling the future it returns, since some backends do all of their
+//! work up front. Streams are not traced, as they may be read for as
+//! long as a document is being edited.
+use futures::future::Future;
+use futures::stream::Stream;
+use std::path::{Path, PathBuf};
+use tracing::{info_span, Span};
+use tracing_futures::{Instrument, Instrumented};
+
+use super::{Change, SequenceId, Store, StoreError};
+use crate::document::{Document, Event, ParticipantId};
+
+/// A Store which traces each call to the store it wraps
+#[derive(Clone, Debug)]
+pub struct TracedStore<T: Store> {
+    inner: T,
+}
+
+impl<T: Store> TracedStore<T> {
+    /// Traces calls to 'inner'
+    pub fn new(inner: T) -> Self {
+        TracedStore { inner }
+    }
+
+    /// The wrapped store
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+// Calls 'f' inside 'span', then instruments the future it returns
+fn traced<F: Future, C: FnOnce() -> F>(span: Span, f: C) -> Instrumented<F> {
+    span.in_scope(f).instrument(span)
+}
+
+impl<T: Store> Store for TracedStore<T> {
+    type Stream = T::Stream;
+    type SinceFuture = Instrumented<T::SinceFuture>;
+    type PushFuture = Instrumented<T::PushFuture>;
+
+    fn push(&mut self, path: PathBuf, event: Event) -> Self::PushFuture {
+        let span = info_span!("store.push", path = %path.display());
+        let inner = &mut self.inner;
+        traced(span, move || inner.push(path, event))
+    }
+
+    fn next_participant_id(
+        &mut self,
+        path: &Path,
+    ) -> Box<dyn Future<Item = ParticipantId, Error = StoreError> + Send> {
+        let span = info_span!("store.next_participant_id", path = %path.display());
+        let inner = &mut self.inner;
+        Box::new(traced(span, move || inner.next_participant_id(path)))
+    }
+
+    fn watch(&self, path: &Path) -> Box<dyn Stream<Item = SequenceId, Error = StoreError> + Send> {
+        self.inner.watch(path)
+    }
+
+    fn seq(&self, path: &Path) -> Box<dyn Future<Item = SequenceId, Error = StoreError> + Send> {
+        let span = info_span!("store.seq", path = %path.display());
+        Box::new(traced(span, || self.inner.seq(path)))
+    }
+
+    fn deleted(&self, path: &Path) -> Box<dyn Future<Item = bool, Error = StoreError> + Send> {
+        let span = info_span!("store.deleted", path = %path.display());
+        Box::new(traced(span, || self.inner.deleted(path)))
+    }
+
+    fn since(
+        &self,
+        path: &Path,
+        seq: SequenceId,
+        exclude: Option<ParticipantId>,
+    ) -> Self::SinceFuture {
+        let span = info_span!("store.since", path = %path.display(), seq = seq);
+        traced(span, || self.inner.since(path, seq, exclude))
+    }
+
+    fn since_range(
+        &self,
+        path: &Path,
+        seq: SequenceId,
+        limit: usize,
+    ) -> Box<dyn Future<Item = Vec<(SequenceId, Event)>, Error = StoreError> + Send> {
+        let span = info_span!(
+            "store.since_range",
+            path = %path.display(),
+            seq = seq,
+            limit = limit as u64
+        );
+        Box::new(traced(span, || self.inner.since_range(path, seq, limit)))
+    }
+
+    fn content(
+        &self,
+        path: &Path,
+    ) -> Box<dyn Future<Item = (SequenceId, Document), Error = StoreError> + Send> {
+        let span = info_span!("store.content", path = %path.display());
+        Box::new(traced(span, || self.inner.content(path)))
+    }
+
+    fn content_at(
+        &self,
+        path: &Path,
+        seq: SequenceId,
+    ) -> Box<dyn Future<Item = Document, Error = StoreError> + Send> {
+        let span = info_span!("store.content_at", path = %path.display(), seq = seq);
+        Box::new(traced(span, || self.inner.content_at(path, seq)))
+    }
+
+    fn changes(&self) -> Box<dyn Stream<Item = Change, Error = StoreError> + Send> {
+        self.inner.changes()
+    }
+
+    fn list(
+        &self,
+        prefix: &Path,
+    ) -> Box<dyn Future<Item = Vec<PathBuf>, Error = StoreError> + Send> {
+        let span = info_span!("store.list", prefix = %prefix.display());
+        Box::new(traced(span, || self.inner.list(prefix)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Edit, Insert, Join, Operation, Role};
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn calls_reach_wrapped_store() {
+        let mut store = TracedStore::new(MemoryStore::default());
+        let path = PathBuf::from("a.html");
+        let join = Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: None,
+        });
+        let edit = Event::Edit(Edit {
+            author: 1,
+            operations: vec![Operation::Insert(Insert {
+                pos: 0,
+                content: String::from("hello"),
+            })],
+        });
+        assert_eq!(store.push(path.clone(), join).wait(), Ok(1));
+        assert_eq!(store.push(path.clone(), edit).wait(), Ok(2));
+        let (seq, doc) = store.content(&path).wait().unwrap();
+        assert_eq!((seq, doc.content.as_str()), (2, "hello"));
+        assert_eq!(store.inner().seq(&path).wait(), Ok(2));
+        assert_eq!(store.seq(Path::new("b.html")).wait(), Err(StoreError::NotFound));
+        assert_eq!(store.list(Path::new("")).wait(), Ok(vec![path]));
+    }
+}
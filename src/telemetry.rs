@@ -0,0 +1,469 @@
+//! Exports the wiki's tracing spans to an OpenTelemetry collector, so
+//! the time taken by each request can be attributed to the handlers,
+//! websocket messages, transforms and store calls it was spent in.
+//!
+//! Spans are recorded by the Exporter, a tracing-subscriber Layer,
+//! as they close, and sent in batches to the collector's OTLP/HTTP
+//! endpoint as JSON by calling `export()`. Requests carrying a W3C
+//! `traceparent` header continue the caller's trace.
+//!
+//! Span fields become attributes, except for these, which follow the
+//! conventions of other OpenTelemetry integrations:
+//!
+//! * `otel.kind` - "server", "client" or "internal" (the default)
+//! * `otel.status_code` - "ok" or "error"
+//! * `traceparent` - the trace and span the span continues
+use futures::future::{self, Future};
+use futures::stream::Stream;
+use http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use http::Method;
+use hyper::client::HttpConnector;
+use hyper::{self, Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::{self, SetGlobalDefaultError};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::{LookupSpan, Registry};
+
+// The most spans waiting to be exported, any more are dropped until
+// the collector catches up
+const MAX_QUEUED: usize = 4096;
+
+// OTLP span kinds
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+const KIND_CLIENT: u8 = 3;
+
+// OTLP status codes
+const STATUS_OK: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+
+/// Records spans as they close and sends them to an OpenTelemetry
+/// collector. Clones share the spans waiting to be exported.
+#[derive(Clone)]
+pub struct Exporter {
+    // The collector's URL, without a trailing "/"
+    endpoint: String,
+    service_name: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    queue: Arc<Mutex<Queue>>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+#[derive(Default)]
+struct Queue {
+    spans: Vec<Value>,
+    // spans dropped since the last export
+    dropped: usize,
+}
+
+// What is known about an open span, kept in its extensions
+struct Recorded {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    start: SystemTime,
+    kind: u8,
+    status: Option<u8>,
+    attributes: Vec<Value>,
+    // the remote parent from a traceparent field
+    remote: Option<(u128, u64)>,
+}
+
+impl Exporter {
+    /// Sends spans to the OTLP/HTTP collector at 'endpoint', such as
+    /// "http://localhost:4318", as the service 'service_name'
+    pub fn new<S: Into<String>>(endpoint: S, service_name: S) -> Self {
+        Exporter {
+            endpoint: endpoint.into().trim_end_matches('/').to_string(),
+            service_name: service_name.into(),
+            headers: Vec::new(),
+            queue: Arc::new(Mutex::new(Queue::default())),
+            client: Client::builder().build(HttpsConnector::new(2)),
+        }
+    }
+
+    /// Adds a header to every export request, usually for the
+    /// collector's credentials
+    pub fn with_header(mut self, name: &str, value: &str) -> Result<Self, ExportError> {
+        let invalid = |_| ExportError::InvalidHeader(String::from(name));
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(invalid)?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|_| {
+            ExportError::InvalidHeader(name.as_str().to_string())
+        })?;
+        self.headers.push((name, value));
+        Ok(self)
+    }
+
+    /// Makes this the subscriber for spans from every thread
+    pub fn init(self) -> Result<(), SetGlobalDefaultError> {
+        subscriber::set_global_default(Registry::default().with(self))
+    }
+
+    /// Sends the spans which closed since the last export to the
+    /// collector, resolving to the number sent. Spans which could not
+    /// be sent are not retried.
+    pub fn export(&self) -> Box<dyn Future<Item = usize, Error = ExportError> + Send> {
+        let (spans, dropped) = {
+            let mut queue = self.queue.lock().unwrap();
+            (
+                mem::take(&mut queue.spans),
+                mem::replace(&mut queue.dropped, 0),
+            )
+        };
+        if dropped > 0 {
+            warn!("Dropped {} spans waiting to be exported", dropped);
+        }
+        if spans.is_empty() {
+            return Box::new(future::ok(0));
+        }
+        let count = spans.len();
+        let mut req = Request::builder();
+        req.method(Method::POST)
+            .uri(format!("{}/v1/traces", self.endpoint).as_str())
+            .header(CONTENT_TYPE, "application/json");
+        for &(ref name, ref value) in &self.headers {
+            req.header(name.clone(), value.clone());
+        }
+        let body = traces_body(&self.service_name, spans).to_string();
+        let req = match req.body(Body::from(body)) {
+            Ok(req) => req,
+            Err(err) => return Box::new(future::err(ExportError::InvalidEndpoint(err))),
+        };
+        Box::new(
+            self.client
+                .request(req)
+                .map_err(ExportError::Http)
+                .and_then(move |res| {
+                    let status = res.status();
+                    res.into_body()
+                        .concat2()
+                        .map_err(ExportError::Http)
+                        .and_then(move |body| {
+                            if status.is_success() {
+                                Ok(count)
+                            } else {
+                                Err(ExportError::Rejected(format!(
+                                    "{}: {}",
+                                    status,
+                                    String::from_utf8_lossy(&body)
+                                )))
+                            }
+                        })
+                }),
+        )
+    }
+
+    fn push(&self, span: Value) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.spans.len() < MAX_QUEUED {
+            queue.spans.push(span);
+        } else {
+            queue.dropped += 1;
+        }
+    }
+}
+
+impl<S> Layer<S> for Exporter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let parent = match span.parent() {
+            Some(parent) => {
+                // the extensions are borrowed from 'parent', so must be
+                // released first
+                let extensions = parent.extensions();
+                let ids = extensions
+                    .get::<Recorded>()
+                    .map(|parent| (parent.trace_id, parent.span_id));
+                ids
+            }
+            None => None,
+        };
+        let mut recorded = Recorded {
+            trace_id: parent.map_or_else(random_trace_id, |(trace_id, _)| trace_id),
+            span_id: random_span_id(),
+            parent_id: parent.map(|(_, span_id)| span_id),
+            start: SystemTime::now(),
+            kind: KIND_INTERNAL,
+            status: None,
+            attributes: Vec::new(),
+            remote: None,
+        };
+        attrs.record(&mut recorded);
+        // only spans without a local parent continue a remote trace
+        if let (None, Some((trace_id, parent_id))) = (parent, recorded.remote) {
+            recorded.trace_id = trace_id;
+            recorded.parent_id = Some(parent_id);
+        }
+        span.extensions_mut().insert(recorded);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(recorded) = span.extensions_mut().get_mut::<Recorded>() {
+                values.record(recorded);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let recorded = span.extensions_mut().remove::<Recorded>();
+        if let Some(recorded) = recorded {
+            self.push(recorded.to_json(span.name(), SystemTime::now()));
+        }
+    }
+}
+
+impl Recorded {
+    fn attribute(&mut self, field: &Field, value: Value) {
+        self.attributes.push(json!({"key": field.name(), "value": value}));
+    }
+
+    // The span in OTLP's JSON encoding
+    fn to_json(&self, name: &str, end: SystemTime) -> Value {
+        let mut span = json!({
+            "traceId": format!("{:032x}", self.trace_id),
+            "spanId": format!("{:016x}", self.span_id),
+            "name": name,
+            "kind": self.kind,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": self.attributes,
+        });
+        if let Some(parent_id) = self.parent_id {
+            span["parentSpanId"] = json!(format!("{:016x}", parent_id));
+        }
+        if let Some(status) = self.status {
+            span["status"] = json!({ "code": status });
+        }
+        span
+    }
+}
+
+impl Visit for Recorded {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "otel.kind" => {
+                self.kind = match value {
+                    "server" => KIND_SERVER,
+                    "client" => KIND_CLIENT,
+                    _ => KIND_INTERNAL,
+                }
+            }
+            "otel.status_code" => {
+                self.status = match value {
+                    "ok" => Some(STATUS_OK),
+                    "error" => Some(STATUS_ERROR),
+                    _ => None,
+                }
+            }
+            "traceparent" => self.remote = parse_traceparent(value),
+            _ => self.attribute(field, json!({ "stringValue": value })),
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        // 64 bit integers are strings in OTLP's JSON encoding
+        self.attribute(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.attribute(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.attribute(field, json!({ "boolValue": value }));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+/// Error conditions which may occur when exporting spans
+#[derive(Debug)]
+pub enum ExportError {
+    /// The collector could not be reached
+    Http(hyper::Error),
+    /// The collector's endpoint is not a valid URL
+    InvalidEndpoint(::http::Error),
+    /// A header given for export requests is not valid
+    InvalidHeader(String),
+    /// The collector responded with an error
+    Rejected(String),
+}
+
+impl Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExportError::Http(ref err) => write!(f, "Http({})", err),
+            ExportError::InvalidEndpoint(ref err) => write!(f, "InvalidEndpoint({})", err),
+            ExportError::InvalidHeader(ref name) => write!(f, "InvalidHeader({})", name),
+            ExportError::Rejected(ref reason) => write!(f, "Rejected({})", reason),
+        }
+    }
+}
+
+impl Error for ExportError {
+    fn description(&self) -> &str {
+        match *self {
+            ExportError::Http(_) => "ExportError: could not reach collector",
+            ExportError::InvalidEndpoint(_) => "ExportError: invalid collector endpoint",
+            ExportError::InvalidHeader(_) => "ExportError: invalid header",
+            ExportError::Rejected(_) => "ExportError: collector rejected spans",
+        }
+    }
+}
+
+/// Reads the trace and parent span ids from a W3C traceparent header,
+/// such as "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+pub fn parse_traceparent(header: &str) -> Option<(u128, u64)> {
+    let parts: Vec<&str> = header.trim().split('-').collect();
+    if parts.len() < 4 || parts[0] == "ff" || parts[1].len() != 32 || parts[2].len() != 16 {
+        return None;
+    }
+    let trace_id = u128::from_str_radix(parts[1], 16).ok()?;
+    let span_id = u64::from_str_radix(parts[2], 16).ok()?;
+    // all zero ids are invalid
+    if trace_id == 0 || span_id == 0 {
+        return None;
+    }
+    Some((trace_id, span_id))
+}
+
+// The body of an export request for 'spans'
+fn traces_body(service_name: &str, spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{
+                    "key": "service.name",
+                    "value": { "stringValue": service_name },
+                }]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "tamawiki", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }]
+        }]
+    })
+}
+
+// Nanoseconds since the epoch, as a string because they may not fit
+// in a JSON number
+fn unix_nanos(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (u128::from(since.as_secs()) * 1_000_000_000 + u128::from(since.subsec_nanos())).to_string()
+}
+
+fn random_trace_id() -> u128 {
+    loop {
+        let id = (u128::from(rand::random::<u64>()) << 64) | u128::from(rand::random::<u64>());
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+fn random_span_id() -> u64 {
+    loop {
+        let id = rand::random::<u64>();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{field, info_span};
+
+    fn queued(exporter: &Exporter) -> Vec<Value> {
+        exporter.queue.lock().unwrap().spans.clone()
+    }
+
+    fn attribute<'a>(span: &'a Value, key: &str) -> &'a Value {
+        let attributes = span["attributes"].as_array().unwrap();
+        &attributes.iter().find(|attr| attr["key"] == key).unwrap()["value"]
+    }
+
+    #[test]
+    fn record_nested_spans() {
+        let exporter = Exporter::new("http://localhost:4318/", "wiki");
+        let registry = Registry::default().with(exporter.clone());
+        subscriber::with_default(registry, || {
+            let request = info_span!(
+                "http.request",
+                otel.kind = "server",
+                http.method = "GET",
+                http.status_code = field::Empty
+            );
+            request.in_scope(|| {
+                info_span!("store.content", path = "index.html").in_scope(|| ());
+            });
+            request.record("http.status_code", 200);
+        });
+        let spans = queued(&exporter);
+        // children close first
+        assert_eq!(spans.len(), 2);
+        let (store, request) = (&spans[0], &spans[1]);
+        assert_eq!(request["name"], "http.request");
+        assert_eq!(request["kind"], KIND_SERVER);
+        assert!(request.get("parentSpanId").is_none());
+        assert_eq!(attribute(request, "http.method")["stringValue"], "GET");
+        assert_eq!(attribute(request, "http.status_code")["intValue"], "200");
+        assert_eq!(store["traceId"], request["traceId"]);
+        assert_eq!(store["parentSpanId"], request["spanId"]);
+        assert_eq!(store["kind"], KIND_INTERNAL);
+        assert_eq!(exporter.endpoint, "http://localhost:4318");
+
+        let body = traces_body("wiki", spans);
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(resource["resource"]["attributes"][0]["value"]["stringValue"], "wiki");
+        assert_eq!(resource["scopeSpans"][0]["spans"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn continue_remote_traces() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        assert_eq!(
+            parse_traceparent(header),
+            Some((0x4bf92f3577b34da6a3ce929d0e0e4736, 0x00f067aa0ba902b7))
+        );
+        assert_eq!(parse_traceparent(""), None);
+        assert_eq!(
+            parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
+        );
+
+        let exporter = Exporter::new("http://localhost:4318", "wiki");
+        let registry = Registry::default().with(exporter.clone());
+        subscriber::with_default(registry, || {
+            info_span!("http.request", traceparent = header).in_scope(|| ());
+        });
+        let spans = queued(&exporter);
+        assert_eq!(spans[0]["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(spans[0]["parentSpanId"], "00f067aa0ba902b7");
+        // the header is not an attribute
+        assert_eq!(spans[0]["attributes"], json!([]));
+    }
+}
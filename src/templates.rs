@@ -1,5 +1,192 @@
-use tera;
+//! The Tera templates used to render the wiki's pages, which
+//! deployments can override to customise the UI
+use serde_json::{self, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::SystemTime;
+use tera::{self, Tera};
+
+use crate::slug;
 
 lazy_static! {
-    pub static ref TERA: tera::Tera = { compile_templates!("templates/**/*") };
+    /// The built-in templates
+    pub static ref TERA: tera::Tera = {
+        let mut tera = compile_templates!("templates/**/*");
+        register_filters(&mut tera);
+        tera
+    };
+}
+
+// Filters available to the built-in templates and any overriding
+// them
+fn register_filters(tera: &mut Tera) {
+    tera.register_filter("urlpath", urlpath);
+}
+
+// Percent-encodes a document path, so links to pages with spaces or
+// non-ASCII titles work: `<a href="{{ base }}{{ path | safe | urlpath }}">`.
+// Tera escapes values before applying filters, so the path must be
+// marked safe to avoid encoding the escapes, and the result only
+// contains characters which are safe in HTML.
+fn urlpath(value: Value, _: HashMap<String, Value>) -> tera::Result<Value> {
+    match value {
+        Value::String(path) => Ok(Value::String(slug::percent_encode(&path))),
+        value => Err(format!("Filter `urlpath` expects a string, got {}", value).into()),
+    }
+}
+
+// The path, modification time and size of each file in a template
+// directory, which changes whenever a template is edited, added or
+// removed
+type Fingerprint = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+/// Templates loaded from a directory at runtime. Each one replaces
+/// the built-in template with the same name, and any the directory
+/// does not include fall back to the built-in templates.
+pub struct Templates {
+    dir: PathBuf,
+    hot_reload: bool,
+    loaded: RwLock<(Tera, Fingerprint)>,
+}
+
+impl Templates {
+    /// Loads every template under 'dir', for example "base.html" to
+    /// change the layout of every page, or "document.html" to change
+    /// how documents are shown.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> tera::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let fingerprint = fingerprint(&dir);
+        let tera = load(&dir)?;
+        Ok(Templates {
+            dir,
+            hot_reload: false,
+            loaded: RwLock::new((tera, fingerprint)),
+        })
+    }
+
+    /// When enabled, templates are parsed again whenever their files
+    /// change, so edits show up on the next page load without
+    /// restarting the server. Every render checks the directory for
+    /// changes, so this is intended for development.
+    pub fn hot_reload(mut self, enabled: bool) -> Self {
+        self.hot_reload = enabled;
+        self
+    }
+
+    /// Renders the named template with the given context
+    pub fn render(&self, name: &str, ctx: &serde_json::Value) -> tera::Result<String> {
+        if self.hot_reload {
+            self.reload_if_changed()?;
+        }
+        let loaded = self.loaded.read().unwrap_or_else(|err| err.into_inner());
+        loaded.0.render(name, ctx)
+    }
+
+    fn reload_if_changed(&self) -> tera::Result<()> {
+        let current = fingerprint(&self.dir);
+        {
+            let loaded = self.loaded.read().unwrap_or_else(|err| err.into_inner());
+            if loaded.1 == current {
+                return Ok(());
+            }
+        }
+        // the previous templates are kept if the new ones are
+        // invalid, so the error is reported until they are fixed
+        let tera = load(&self.dir)?;
+        let mut loaded = self.loaded.write().unwrap_or_else(|err| err.into_inner());
+        *loaded = (tera, current);
+        Ok(())
+    }
+}
+
+// Parses the templates in 'dir' and adds the built-in templates
+// which they do not override
+fn load(dir: &Path) -> tera::Result<Tera> {
+    let glob = format!("{}/**/*", dir.display());
+    // overrides may extend built-in templates, so inheritance
+    // chains are only built once the built-in templates are added
+    let mut tera = Tera::parse(&glob)?;
+    register_filters(&mut tera);
+    tera.extend(&TERA)?;
+    Ok(tera)
+}
+
+fn fingerprint(dir: &Path) -> Fingerprint {
+    let mut fingerprint = Vec::new();
+    // an unreadable directory counts as empty, loading the templates
+    // reports the error
+    let _ = add_files(dir, &mut fingerprint);
+    fingerprint.sort();
+    fingerprint
+}
+
+fn add_files(dir: &Path, fingerprint: &mut Fingerprint) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            add_files(&entry.path(), fingerprint)?;
+        } else {
+            fingerprint.push((entry.path(), metadata.modified().ok(), metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn override_templates() {
+        let dir = env::temp_dir().join("tamawiki-templates-test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("404.html"), "Missing {{ path }}").unwrap();
+        let templates = Templates::from_dir(&dir).unwrap();
+        let ctx = json!({ "path": "page" });
+        assert_eq!(templates.render("404.html", &ctx).unwrap(), "Missing page");
+        // other templates are the built-in ones
+        let loaded = templates.loaded.read().unwrap();
+        assert!(loaded.0.templates.contains_key("base.html"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn urlpath_filter() {
+        let dir = env::temp_dir().join("tamawiki-urlpath-test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("link.txt"), "/{{ path | urlpath }}?action=edit").unwrap();
+        let templates = Templates::from_dir(&dir).unwrap();
+        let ctx = json!({ "path": "Foo Bar/caf\u{e9}.html" });
+        assert_eq!(
+            templates.render("link.txt", &ctx).unwrap(),
+            "/Foo%20Bar/caf%C3%A9.html?action=edit"
+        );
+        assert!(templates.render("link.txt", &json!({ "path": 1 })).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hot_reload_templates() {
+        let dir = env::temp_dir().join("tamawiki-hot-reload-test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("404.html"), "Missing {{ path }}").unwrap();
+        let templates = Templates::from_dir(&dir).unwrap().hot_reload(true);
+        let ctx = json!({ "path": "page" });
+        assert_eq!(templates.render("404.html", &ctx).unwrap(), "Missing page");
+
+        fs::write(dir.join("404.html"), "Not found: {{ path }}").unwrap();
+        assert_eq!(templates.render("404.html", &ctx).unwrap(), "Not found: page");
+
+        // invalid templates are reported until they are fixed
+        fs::write(dir.join("404.html"), "Not found: {{ path").unwrap();
+        assert!(templates.render("404.html", &ctx).is_err());
+        fs::write(dir.join("404.html"), "Gone: {{ path }}").unwrap();
+        assert_eq!(templates.render("404.html", &ctx).unwrap(), "Gone: page");
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
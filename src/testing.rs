@@ -0,0 +1,197 @@
+//! Helpers for testing the wiki, and applications embedding it, over
+//! real connections.
+//!
+//! A TestServer serves a TamaWiki on a random local port from a
+//! thread of its own, so tests can talk to it using blocking
+//! TestClients and check the ServerMessages they receive in order:
+//!
+//! ```no_run
+//! # #[macro_use] extern crate tamawiki;
+//! # #[macro_use] extern crate serde_json;
+//! # fn main() {
+//! use tamawiki::store::memory::MemoryStore;
+//! use tamawiki::testing::TestServer;
+//! use tamawiki::TamaWiki;
+//!
+//! let store = memorystore! {
+//!     "index.html" => "Welcome to TamaWiki.\n"
+//! };
+//! let server = TestServer::start(TamaWiki::new(store, "public/dist"));
+//! let mut client = server.connect("index.html");
+//! client.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+//! # }
+//! ```
+use futures::future::Future;
+use futures::sync::oneshot;
+use hyper::Server;
+use serde_json::{self, Value};
+use std::borrow::Cow;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::current_thread::Runtime;
+use tungstenite::client::client;
+use tungstenite::handshake::client::Request;
+use tungstenite::handshake::HandshakeError;
+use tungstenite::{Error, Message, WebSocket};
+use url::Url;
+
+use crate::session::message::{ClientMessage, ServerMessage};
+use crate::store::Store;
+use crate::TamaWiki;
+
+/// How long a TestClient waits for a message before failing
+pub const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A wiki served on a random local port until it is dropped
+pub struct TestServer<T: Store + Sync> {
+    wiki: TamaWiki<T>,
+    addr: SocketAddr,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl<T: Store + Sync> TestServer<T> {
+    /// Starts serving 'wiki' on 127.0.0.1, on a port chosen by the
+    /// operating system
+    pub fn start(wiki: TamaWiki<T>) -> Self {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(wiki.clone());
+        let addr = server.local_addr();
+        let (stop, stopped) = oneshot::channel::<()>();
+        thread::spawn(move || {
+            let mut rt = Runtime::new().expect("Unable to start test runtime");
+            rt.block_on(
+                server
+                    .with_graceful_shutdown(stopped.then(|_| Ok::<(), ()>(())))
+                    .map_err(|err| panic!("Server error: {}", err)),
+            )
+        });
+        TestServer {
+            wiki,
+            addr,
+            stop: Some(stop),
+        }
+    }
+
+    /// The wiki being served, e.g. to kick participants
+    pub fn wiki(&self) -> &TamaWiki<T> {
+        &self.wiki
+    }
+
+    /// The address the wiki is served on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The http URL of 'path', which may include a query string
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}/{}", self.addr, relative(path))
+    }
+
+    /// The websocket URL of 'path', which may include a query string
+    pub fn ws_url(&self, path: &str) -> String {
+        format!("ws://{}/{}", self.addr, relative(path))
+    }
+
+    /// Connects a client to the document at 'path', e.g.
+    /// "index.html?seq=0", panicking if the connection fails
+    pub fn connect(&self, path: &str) -> TestClient {
+        TestClient::connect(&self.ws_url(path), None)
+            .unwrap_or_else(|err| panic!("Could not connect to {}: {}", path, err))
+    }
+
+    /// Connects a client to the document at 'path' as the user
+    /// 'token' authenticates, panicking if the connection fails
+    pub fn connect_as(&self, path: &str, token: &str) -> TestClient {
+        TestClient::connect(&self.ws_url(path), Some(token))
+            .unwrap_or_else(|err| panic!("Could not connect to {}: {}", path, err))
+    }
+}
+
+impl<T: Store + Sync> Drop for TestServer<T> {
+    fn drop(&mut self) {
+        // close websocket connections so the server can stop
+        self.wiki.shutdown();
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+fn relative(path: &str) -> &str {
+    path.strip_prefix('/').unwrap_or(path)
+}
+
+/// A blocking websocket client, which fails if a message takes
+/// longer than RECEIVE_TIMEOUT to arrive
+pub struct TestClient {
+    ws: WebSocket<TcpStream>,
+}
+
+impl TestClient {
+    /// Connects to the websocket 'url', presenting 'token' as a
+    /// bearer token if there is one
+    pub fn connect(url: &str, token: Option<&str>) -> Result<Self, Error> {
+        let url = Url::parse(url).map_err(|err| Error::Url(format!("{}", err).into()))?;
+        let stream = match (url.host_str(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => TcpStream::connect((host, port))?,
+            _ => return Err(Error::Url("URL has no host or port".into())),
+        };
+        stream.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+        let extra_headers = token.map(|token| {
+            vec![(
+                Cow::from("Authorization"),
+                Cow::from(format!("Bearer {}", token)),
+            )]
+        });
+        let request = Request { url, extra_headers };
+        match client(request, stream) {
+            Ok((ws, _)) => Ok(TestClient { ws }),
+            Err(HandshakeError::Failure(err)) => Err(err),
+            Err(HandshakeError::Interrupted(_)) => unreachable!("the stream is blocking"),
+        }
+    }
+
+    /// Sends a message to the server
+    pub fn send(&mut self, msg: &ClientMessage) {
+        self.send_json(&serde_json::to_value(msg).unwrap());
+    }
+
+    /// Sends a message to the server as JSON, e.g. to send one the
+    /// server should reject
+    pub fn send_json(&mut self, msg: &Value) {
+        self.ws
+            .write_message(Message::text(msg.to_string()))
+            .expect("Unable to send message");
+    }
+
+    /// Returns the next message from the server, panicking if it is
+    /// not JSON or does not arrive in time
+    pub fn receive(&mut self) -> Value {
+        loop {
+            match self.ws.read_message().expect("Unable to receive message") {
+                Message::Text(text) => {
+                    return serde_json::from_str(&text).expect("Invalid JSON message")
+                }
+                // pings are answered by the websocket itself
+                _ => continue,
+            }
+        }
+    }
+
+    /// Asserts the next message from the server is 'expected'
+    pub fn expect(&mut self, expected: &ServerMessage) {
+        self.expect_json(&serde_json::to_value(expected).unwrap());
+    }
+
+    /// Asserts the next message from the server is 'expected' when
+    /// both are represented as JSON
+    pub fn expect_json(&mut self, expected: &Value) {
+        assert_eq!(&self.receive(), expected);
+    }
+
+    /// Closes the connection, so the server sees the participant
+    /// leave
+    pub fn close(mut self) {
+        let _ = self.ws.close(None);
+    }
+}
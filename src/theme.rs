@@ -0,0 +1,120 @@
+//! Themes change how the wiki looks. A theme is a named directory
+//! which may contain:
+//!
+//! ```text
+//! templates/   overriding the built-in templates
+//! static/      overriding the files served under /_static/
+//! ```
+//!
+//! Files a theme does not include fall back to the built-in ones, so
+//! a theme only needs the templates and stylesheets it changes.
+use std::path::{Path, PathBuf};
+
+use crate::assets::Assets;
+use crate::templates::Templates;
+
+/// The cookie storing the theme a reader has chosen
+pub const THEME_COOKIE: &str = "tamawiki_theme";
+
+/// A named set of templates and static files
+pub struct Theme {
+    name: String,
+    templates: Option<Templates>,
+    static_path: Option<PathBuf>,
+    assets: Assets,
+}
+
+impl Theme {
+    /// Loads the theme called 'name' from 'dir'
+    pub fn from_dir<S: Into<String>, P: AsRef<Path>>(name: S, dir: P) -> tera::Result<Self> {
+        let dir = dir.as_ref();
+        let templates = if dir.join("templates").is_dir() {
+            Some(Templates::from_dir(dir.join("templates"))?)
+        } else {
+            None
+        };
+        let static_path = dir.join("static");
+        let (static_path, assets) = if static_path.is_dir() {
+            let assets = Assets::from_dir(&static_path).map_err(|err| {
+                tera::Error::from(format!("{}: {}", static_path.display(), err))
+            })?;
+            (Some(static_path), assets)
+        } else {
+            (None, Assets::default())
+        };
+        Ok(Theme {
+            name: name.into(),
+            templates,
+            static_path,
+            assets,
+        })
+    }
+
+    /// Reloads the theme's templates when their files change, see
+    /// `Templates::hot_reload`
+    pub fn hot_reload(mut self, enabled: bool) -> Self {
+        self.templates = self.templates.map(|templates| templates.hot_reload(enabled));
+        self
+    }
+
+    /// The name readers choose the theme by
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The theme's templates, if it overrides any
+    pub fn templates(&self) -> Option<&Templates> {
+        self.templates.as_ref()
+    }
+
+    /// The fingerprints of the theme's static files
+    pub fn assets(&self) -> &Assets {
+        &self.assets
+    }
+
+    /// Returns the theme's version of the static file at 'path',
+    /// relative to /_static/, or None if the theme does not override
+    /// it
+    pub fn static_file(&self, path: &str) -> Option<PathBuf> {
+        let root = self.static_path.as_ref()?;
+        let path = path.trim_start_matches('/');
+        // the file itself is served by hyper_staticfile, which
+        // rejects paths escaping the root
+        if root.join(path).is_file() {
+            Some(root.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn load_theme() {
+        let dir = env::temp_dir().join("tamawiki-theme-test");
+        fs::create_dir_all(dir.join("templates")).unwrap();
+        fs::create_dir_all(dir.join("static/css")).unwrap();
+        fs::write(dir.join("templates/404.html"), "Missing {{ path }}").unwrap();
+        fs::write(dir.join("static/css/main.css"), "body {}").unwrap();
+
+        let theme = Theme::from_dir("dark", &dir).unwrap();
+        assert_eq!(theme.name(), "dark");
+        let ctx = json!({ "path": "page" });
+        let templates = theme.templates().unwrap();
+        assert_eq!(templates.render("404.html", &ctx).unwrap(), "Missing page");
+        assert_eq!(theme.static_file("/css/main.css"), Some(dir.join("static")));
+        assert_eq!(theme.static_file("/js/main.js"), None);
+        assert!(theme.assets().fingerprint("css/main.css").is_some());
+
+        // themes need not override anything
+        let empty = Theme::from_dir("empty", dir.join("missing")).unwrap();
+        assert!(empty.templates().is_none());
+        assert_eq!(empty.static_file("/css/main.css"), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,502 @@
+//! API tokens for scripts and other machine clients, which present
+//! them as bearer tokens on REST requests and websocket upgrades.
+//!
+//! Each token acts on behalf of the user who created it, limited to
+//! its Scope. Only a SHA-256 digest of each token is kept, so a token
+//! is shown once when it is created and cannot be recovered. Every
+//! token has a rate limit of its own, and requests made with a token
+//! are logged to the `tamawiki::audit` target with the token's id and
+//! owner.
+//!
+//! Tokens opened from a file are saved to it whenever one is created
+//! or revoked, and the change is undone if they cannot be. When a
+//! token was last used is saved along with them, so it may be a
+//! little out of date after a restart.
+use rand::{self, Rng};
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::auth::User;
+use crate::document::Role;
+use crate::persist;
+use crate::service::ratelimit::RateLimiter;
+use crate::users::Accounts;
+
+/// Every API token starts with this, so they can be told apart from
+/// session tokens
+pub const TOKEN_PREFIX: &str = "tw_";
+
+/// The log target requests made with API tokens are recorded under
+pub const AUDIT_TARGET: &str = "tamawiki::audit";
+
+/// How many requests a minute a token may make by default
+pub const DEFAULT_RATE_LIMIT: u32 = 600;
+
+// The longest name a token may be given
+const MAX_NAME_LENGTH: usize = 100;
+
+/// What a token may be used for
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Reading documents and joining them as a reader
+    Read,
+    /// Reading and editing documents
+    Write,
+    /// Editing documents, and creating and revoking the owner's
+    /// tokens
+    Admin,
+}
+
+impl Scope {
+    /// Every scope, narrowest first
+    pub const ALL: [Scope; 3] = [Scope::Read, Scope::Write, Scope::Admin];
+
+    /// The scope's name, as used in forms
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    /// The role documents are joined with using a token of this scope
+    pub fn role(self) -> Role {
+        match self {
+            Scope::Read => Role::Reader,
+            Scope::Write | Scope::Admin => Role::Editor,
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        Scope::ALL
+            .iter()
+            .cloned()
+            .find(|scope| scope.name() == name)
+            .ok_or(())
+    }
+}
+
+/// The details of a token, which do not include the token itself
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Identifies the token in listings and audit logs
+    pub id: String,
+    /// The user the token acts on behalf of
+    pub owner: String,
+    /// A name given by the owner, to remember what the token is for
+    pub name: String,
+    /// What the token may be used for
+    pub scope: Scope,
+    /// How many requests a minute the token may make
+    pub rate_limit: u32,
+    /// When the token was created
+    pub created: SystemTime,
+    /// When the token was last used, if ever
+    pub last_used: Option<SystemTime>,
+}
+
+impl ApiToken {
+    /// The user requests made with the token act as, or None if the
+    /// owner no longer has an account. The token's role is limited to
+    /// the owner's current role, so a token cannot keep access its
+    /// owner has lost.
+    pub fn user(&self, accounts: &Accounts) -> Option<User> {
+        let owner = accounts.role(&self.owner)?;
+        Some(User {
+            id: self.owner.clone(),
+            // roles are ordered from most to least access
+            role: cmp::max(self.scope.role(), owner),
+        })
+    }
+}
+
+/// The API tokens users have created. This is a cloneable interface
+/// to tokens held in memory, and saved to a file if they were opened
+/// from one.
+#[derive(Clone)]
+pub struct ApiTokens {
+    tokens: Arc<RwLock<HashMap<String, Entry>>>,
+    // File the tokens are saved to whenever one is created or revoked
+    file: Option<Arc<PathBuf>>,
+    default_rate_limit: u32,
+}
+
+struct Entry {
+    token: ApiToken,
+    // SHA-256 digest of the whole token
+    digest: Vec<u8>,
+    limiter: RateLimiter,
+}
+
+// A token as it is saved, without the requests it has made recently
+#[derive(Serialize, Deserialize)]
+struct SavedToken {
+    token: ApiToken,
+    // base64 encoded SHA-256 digest of the whole token
+    digest: String,
+}
+
+impl Default for ApiTokens {
+    fn default() -> Self {
+        ApiTokens {
+            tokens: Default::default(),
+            file: None,
+            default_rate_limit: DEFAULT_RATE_LIMIT,
+        }
+    }
+}
+
+impl ApiTokens {
+    /// Opens the tokens saved in the file at 'path', which is created
+    /// when the first token is
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let saved: Vec<SavedToken> = persist::load(path)?;
+        let mut tokens = HashMap::new();
+        for saved in saved {
+            let digest = base64::decode(&saved.digest)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let limiter = RateLimiter::new(saved.token.rate_limit, Duration::from_secs(60));
+            let entry = Entry {
+                token: saved.token,
+                digest,
+                limiter,
+            };
+            tokens.insert(entry.token.id.clone(), entry);
+        }
+        Ok(ApiTokens {
+            tokens: Arc::new(RwLock::new(tokens)),
+            file: Some(Arc::new(path.to_path_buf())),
+            ..Default::default()
+        })
+    }
+
+    /// Sets how many requests a minute tokens may make, unless they
+    /// are created with a lower limit
+    pub fn with_rate_limit(mut self, limit: u32) -> Self {
+        self.default_rate_limit = limit;
+        self
+    }
+
+    /// Returns the most requests a minute a token may make
+    pub fn rate_limit(&self) -> u32 {
+        self.default_rate_limit
+    }
+
+    /// Creates a token for 'owner', returning its details and the
+    /// token itself, which is not stored. When 'rate_limit' is None
+    /// the token gets the default limit.
+    pub fn create(
+        &self,
+        owner: &str,
+        name: &str,
+        scope: Scope,
+        rate_limit: Option<u32>,
+    ) -> Result<(ApiToken, String), TokenError> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            return Err(TokenError::InvalidName);
+        }
+        let rate_limit = match rate_limit {
+            Some(limit) if limit == 0 || limit > self.default_rate_limit => {
+                return Err(TokenError::InvalidRateLimit(self.default_rate_limit))
+            }
+            Some(limit) => limit,
+            None => self.default_rate_limit,
+        };
+        let mut rng = rand::thread_rng();
+        let id: String = (0..12)
+            .map(|_| format!("{:x}", rng.gen_range(0, 16)))
+            .collect();
+        let secret: [u8; 24] = rng.gen();
+        // the id is hex, so the '_' after it ends it
+        let secret = format!(
+            "{}{}_{}",
+            TOKEN_PREFIX,
+            id,
+            base64::encode_config(&secret, base64::URL_SAFE_NO_PAD)
+        );
+        let token = ApiToken {
+            id: id.clone(),
+            owner: String::from(owner),
+            name: String::from(name),
+            scope,
+            rate_limit,
+            created: SystemTime::now(),
+            last_used: None,
+        };
+        let mut tokens = self.tokens.write().unwrap();
+        tokens.insert(
+            id.clone(),
+            Entry {
+                token: token.clone(),
+                digest: sha256_digest(&secret),
+                limiter: RateLimiter::new(rate_limit, Duration::from_secs(60)),
+            },
+        );
+        if self.save(&tokens).is_err() {
+            // a token which was never saved would stop working on restart
+            tokens.remove(&id);
+            return Err(TokenError::Internal);
+        }
+        Ok((token, secret))
+    }
+
+    /// Revokes the token with 'id', if 'owner' owns it. Returns false
+    /// if there is no such token.
+    pub fn revoke(&self, owner: &str, id: &str) -> Result<bool, TokenError> {
+        let mut tokens = self.tokens.write().unwrap();
+        match tokens.get(id) {
+            Some(entry) if entry.token.owner == owner => (),
+            _ => return Ok(false),
+        }
+        let entry = tokens.remove(id).unwrap();
+        if self.save(&tokens).is_err() {
+            // the token would come back on restart, so keep it until
+            // revoking it can be saved
+            tokens.insert(String::from(id), entry);
+            return Err(TokenError::Internal);
+        }
+        Ok(true)
+    }
+
+    /// Returns the tokens 'owner' has created, oldest first
+    pub fn list(&self, owner: &str) -> Vec<ApiToken> {
+        let tokens = self.tokens.read().unwrap();
+        let mut owned: Vec<ApiToken> = tokens
+            .values()
+            .filter(|entry| entry.token.owner == owner)
+            .map(|entry| entry.token.clone())
+            .collect();
+        owned.sort_by(|a, b| (a.created, &a.id).cmp(&(b.created, &b.id)));
+        owned
+    }
+
+    /// Returns the details of 'token', if it is a token which has not
+    /// been revoked
+    pub fn verify(&self, token: &str) -> Option<ApiToken> {
+        let id = token.get(TOKEN_PREFIX.len()..)?.split('_').next()?;
+        let tokens = self.tokens.read().unwrap();
+        let entry = tokens.get(id)?;
+        let actual = sha256_digest(token);
+        // compare every byte, so the time taken does not reveal how
+        // much of the digest matched
+        let matched = entry.digest.len() == actual.len()
+            && entry.digest.iter().zip(actual.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0;
+        if token.starts_with(TOKEN_PREFIX) && matched {
+            Some(entry.token.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Records a request made with the token with 'id'. Returns how
+    /// long the client must wait before trying again if the token has
+    /// made too many requests.
+    pub fn record_use(&self, id: &str) -> Result<(), Duration> {
+        let mut tokens = self.tokens.write().unwrap();
+        let entry = match tokens.get_mut(id) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        entry.limiter.check(id)?;
+        entry.token.last_used = Some(SystemTime::now());
+        Ok(())
+    }
+
+    // Saves the tokens to their file, if they have one
+    fn save(&self, tokens: &HashMap<String, Entry>) -> io::Result<()> {
+        let path = match self.file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let saved: Vec<SavedToken> = tokens
+            .values()
+            .map(|entry| SavedToken {
+                token: entry.token.clone(),
+                digest: base64::encode(&entry.digest),
+            }).collect();
+        persist::save(path, &saved).map_err(|err| {
+            error!("Unable to save API tokens to {}: {}", path.display(), err);
+            err
+        })
+    }
+}
+
+/// Returns true if 'token' looks like an API token rather than a
+/// session token
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+fn sha256_digest(token: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(token.as_bytes());
+    hasher.result().to_vec()
+}
+
+/// Error conditions which may occur when creating or revoking a token
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    /// The name is empty or too long
+    InvalidName,
+    /// The rate limit is zero or above the most allowed, which is
+    /// included
+    InvalidRateLimit(u32),
+    /// The tokens could not be saved
+    Internal,
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TokenError::InvalidName => write!(
+                f,
+                "Token names must be between 1 and {} characters long",
+                MAX_NAME_LENGTH
+            ),
+            TokenError::InvalidRateLimit(max) => write!(
+                f,
+                "Rate limits must be between 1 and {} requests a minute",
+                max
+            ),
+            TokenError::Internal => write!(f, "Unable to save API tokens"),
+        }
+    }
+}
+
+impl Error for TokenError {
+    fn description(&self) -> &str {
+        match *self {
+            TokenError::InvalidName => "TokenError: invalid name",
+            TokenError::InvalidRateLimit(_) => "TokenError: invalid rate limit",
+            TokenError::Internal => "TokenError: internal error",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_created_tokens() {
+        let tokens = ApiTokens::default();
+        let (created, secret) = tokens.create("alice", "deploy", Scope::Write, None).unwrap();
+        assert!(is_api_token(&secret));
+        assert_eq!(created.rate_limit, DEFAULT_RATE_LIMIT);
+        assert_eq!(tokens.verify(&secret), Some(created.clone()));
+        assert_eq!(tokens.verify(&format!("{}x", secret)), None);
+        assert_eq!(tokens.verify(&format!("{}{}_wrong", TOKEN_PREFIX, created.id)), None);
+        assert_eq!(tokens.verify("tw_"), None);
+    }
+
+    #[test]
+    fn limit_token_role_to_owner() {
+        let accounts = Accounts::new(b"test key");
+        accounts.signup("alice", "password1").unwrap();
+        let tokens = ApiTokens::default();
+        let (write, _) = tokens.create("alice", "deploy", Scope::Write, None).unwrap();
+        let (read, _) = tokens.create("alice", "ci", Scope::Read, None).unwrap();
+        let user = write.user(&accounts).unwrap();
+        assert_eq!((user.id.as_str(), user.role), ("alice", Role::Editor));
+        assert_eq!(read.user(&accounts).unwrap().role, Role::Reader);
+
+        assert!(accounts.set_role("alice", Role::Reader));
+        assert_eq!(write.user(&accounts).unwrap().role, Role::Reader);
+
+        // tokens of users without an account act as nobody
+        let (orphan, _) = tokens.create("bob", "bot", Scope::Write, None).unwrap();
+        assert_eq!(orphan.user(&accounts), None);
+    }
+
+    #[test]
+    fn revoke_only_own_tokens() {
+        let tokens = ApiTokens::default();
+        let (created, secret) = tokens.create("alice", "ci", Scope::Read, None).unwrap();
+        assert_eq!(tokens.revoke("bob", &created.id), Ok(false));
+        assert_eq!(tokens.list("alice"), vec![created.clone()]);
+        assert!(tokens.list("bob").is_empty());
+        assert_eq!(tokens.revoke("alice", &created.id), Ok(true));
+        assert_eq!(tokens.verify(&secret), None);
+        assert_eq!(tokens.revoke("alice", &created.id), Ok(false));
+    }
+
+    #[test]
+    fn limit_requests_per_token() {
+        let tokens = ApiTokens::default().with_rate_limit(10);
+        assert_eq!(
+            tokens.create("alice", "bot", Scope::Read, Some(11)),
+            Err(TokenError::InvalidRateLimit(10))
+        );
+        assert_eq!(
+            tokens.create("alice", " ", Scope::Read, None),
+            Err(TokenError::InvalidName)
+        );
+        let (a, _) = tokens.create("alice", "bot", Scope::Read, Some(2)).unwrap();
+        let (b, _) = tokens.create("alice", "other", Scope::Read, Some(2)).unwrap();
+        assert_eq!(tokens.record_use(&a.id), Ok(()));
+        assert_eq!(tokens.record_use(&a.id), Ok(()));
+        assert!(tokens.record_use(&a.id).is_err());
+        assert_eq!(tokens.record_use(&b.id), Ok(()));
+        let used = tokens.list("alice").into_iter().find(|token| token.id == a.id);
+        assert!(used.unwrap().last_used.is_some());
+    }
+
+    #[test]
+    fn saved_tokens() {
+        let path = ::std::env::temp_dir()
+            .join(format!("tamawiki-tokens-{}.json", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let tokens = ApiTokens::open(&path).unwrap();
+        let (kept, secret) = tokens.create("alice", "ci", Scope::Read, Some(5)).unwrap();
+        let (revoked, other) = tokens.create("alice", "old", Scope::Write, None).unwrap();
+        tokens.revoke("alice", &revoked.id).unwrap();
+
+        let reopened = ApiTokens::open(&path).unwrap();
+        assert_eq!(reopened.verify(&secret), Some(kept.clone()));
+        assert_eq!(reopened.verify(&other), None);
+        assert_eq!(reopened.list("alice"), vec![kept]);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn undo_changes_which_cannot_be_saved() {
+        let dir = ::std::env::temp_dir()
+            .join(format!("tamawiki-tokens-unsaved-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        let tokens = ApiTokens::open(&dir.join("tokens.json")).unwrap();
+        let (kept, secret) = tokens.create("alice", "ci", Scope::Read, None).unwrap();
+
+        // saving fails once the directory is gone
+        ::std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            tokens.create("alice", "deploy", Scope::Write, None),
+            Err(TokenError::Internal)
+        );
+        assert_eq!(tokens.revoke("alice", &kept.id), Err(TokenError::Internal));
+        assert_eq!(tokens.list("alice"), vec![kept.clone()]);
+        assert_eq!(tokens.verify(&secret), Some(kept));
+    }
+
+    #[test]
+    fn parse_scopes() {
+        assert_eq!("admin".parse(), Ok(Scope::Admin));
+        assert_eq!("owner".parse::<Scope>(), Err(()));
+        assert_eq!(Scope::Read.role(), Role::Reader);
+        assert!(Scope::Admin > Scope::Write);
+    }
+}
@@ -0,0 +1,578 @@
+//! Local user accounts, with passwords hashed using Argon2 and
+//! sessions held in signed cookies.
+//!
+//! Accounts implements Authenticator, resolving the value of the
+//! session cookie set when a user logs in, so the logged in user is
+//! recorded in the Join events of documents they edit.
+//!
+//! Logging out ends every session the user has: each account has a
+//! session generation which is signed into its session tokens, and
+//! logging out moves it on, so older tokens are refused.
+use argon2::{self, Config};
+use futures::future::{self, Future};
+use hmac::{Hmac, Mac};
+use rand::{self, Rng};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::auth::{AuthError, Authenticator, User};
+use crate::document::Role;
+use crate::persist;
+
+/// How long a session lasts by default before the user must log in
+/// again
+pub const DEFAULT_SESSION_LENGTH: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// The shortest password accepted on signup
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+// Checked against the password of unknown users, so logging in takes
+// as long whether or not the username exists. This is the hash of a
+// random password using the default Config.
+const DUMMY_PASSWORD_HASH: &str = "$argon2i$v=19$m=4096,t=3,p=1$\
+    IYQ8wJ9zlphYQBDjSCHyCA$+pjFUJTRCsAV3uAf87okXhfv6zrQ5j8Lhz9lA1RH9z4";
+
+/// A set of user accounts. This is a cloneable interface to accounts
+/// held in memory, and saved to a file if they were opened from one.
+#[derive(Clone)]
+pub struct Accounts {
+    users: Arc<RwLock<HashMap<String, Account>>>,
+    // File the accounts are saved to whenever they change
+    file: Option<Arc<PathBuf>>,
+    // Secret used to sign session tokens
+    key: Arc<Vec<u8>>,
+    session_length: Duration,
+    secure_cookies: bool,
+    // Usernames of the administrators
+    admins: Arc<HashSet<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Account {
+    // Argon2 hash of the password, including its salt and parameters,
+    // or None for users who log in using another service
+    password_hash: Option<String>,
+    role: Role,
+    // Only session tokens from this generation are accepted
+    #[serde(default)]
+    generation: u64,
+    // Who the user is at the service they log in with instead of a
+    // password, such as an OpenID Connect issuer and subject, or None
+    // for local accounts
+    #[serde(default)]
+    identity: Option<String>,
+}
+
+impl Accounts {
+    /// Creates an empty set of accounts. Session tokens are signed
+    /// using 'key', which should be a long random secret. Changing
+    /// the key logs out every user.
+    pub fn new(key: &[u8]) -> Self {
+        Accounts {
+            users: Default::default(),
+            file: None,
+            key: Arc::new(key.to_vec()),
+            session_length: DEFAULT_SESSION_LENGTH,
+            secure_cookies: false,
+            admins: Default::default(),
+        }
+    }
+
+    /// Opens the accounts saved in the file at 'path', which is
+    /// created when the first account is. Session tokens are signed
+    /// using 'key', as for `new`.
+    pub fn open(key: &[u8], path: &Path) -> io::Result<Self> {
+        let mut accounts = Accounts::new(key);
+        accounts.users = Arc::new(RwLock::new(persist::load(path)?));
+        accounts.file = Some(Arc::new(path.to_path_buf()));
+        Ok(accounts)
+    }
+
+    /// Makes the users named in 'usernames' administrators, who may
+    /// restore documents from the trash
+    pub fn with_admins<I: IntoIterator<Item = String>>(mut self, usernames: I) -> Self {
+        self.admins = Arc::new(usernames.into_iter().collect());
+        self
+    }
+
+    /// Returns true if 'username' has an account and is an
+    /// administrator
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admins.contains(username) && self.role(username).is_some()
+    }
+
+    /// Sets how long users stay logged in
+    pub fn with_session_length(mut self, length: Duration) -> Self {
+        self.session_length = length;
+        self
+    }
+
+    /// Returns how long users stay logged in
+    pub fn session_length(&self) -> Duration {
+        self.session_length
+    }
+
+    /// Marks session cookies Secure, so browsers only send them over
+    /// HTTPS. This should be set whenever the wiki is served over
+    /// TLS, including behind a proxy which terminates it.
+    pub fn with_secure_cookies(mut self, secure: bool) -> Self {
+        self.secure_cookies = secure;
+        self
+    }
+
+    /// Returns true if session cookies are marked Secure
+    pub fn secure_cookies(&self) -> bool {
+        self.secure_cookies
+    }
+
+    /// Creates an account for 'username' with the Editor role
+    pub fn signup(&self, username: &str, password: &str) -> Result<(), AccountError> {
+        if !valid_username(username) {
+            return Err(AccountError::InvalidUsername);
+        }
+        if password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(AccountError::WeakPassword);
+        }
+        let salt: [u8; 16] = rand::thread_rng().gen();
+        let password_hash = argon2::hash_encoded(password.as_bytes(), &salt, &Config::default())
+            .map_err(|_| AccountError::Internal)?;
+        let mut users = self.users.write().map_err(|_| AccountError::Internal)?;
+        if users.contains_key(username) {
+            return Err(AccountError::UsernameTaken);
+        }
+        users.insert(
+            String::from(username),
+            Account {
+                password_hash: Some(password_hash),
+                role: Role::Editor,
+                generation: 0,
+                identity: None,
+            },
+        );
+        if let Err(err) = self.save(&users) {
+            users.remove(username);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Checks the user's password, returning a new session token if
+    /// it is correct
+    pub fn login(&self, username: &str, password: &str) -> Result<String, AccountError> {
+        let users = self.users.read().map_err(|_| AccountError::Internal)?;
+        let account = users.get(username);
+        let valid = match account.and_then(|a| a.password_hash.as_ref()) {
+            Some(hash) => argon2::verify_encoded(hash, password.as_bytes())
+                .map_err(|_| AccountError::Internal)?,
+            None => {
+                let _ = argon2::verify_encoded(DUMMY_PASSWORD_HASH, password.as_bytes());
+                false
+            }
+        };
+        match account {
+            Some(account) if valid => Ok(self.session_token(
+                username,
+                account.generation,
+                SystemTime::now() + self.session_length,
+            )),
+            _ => Err(AccountError::InvalidCredentials),
+        }
+    }
+
+    /// Starts a session for 'username', who has been authenticated by
+    /// another service such as an OpenID Connect provider as
+    /// 'identity'. The first time they log in an account is created
+    /// without a password, so they cannot log in using the form, and
+    /// bound to 'identity'. Fails with UsernameTaken if a local
+    /// account, or one bound to another identity, has the username.
+    pub fn login_external(&self, username: &str, identity: &str) -> Result<String, AccountError> {
+        if !valid_username(username) {
+            return Err(AccountError::InvalidUsername);
+        }
+        let mut users = self.users.write().map_err(|_| AccountError::Internal)?;
+        let generation = match users.get(username) {
+            Some(account) if account.identity.as_deref() == Some(identity) => {
+                account.generation
+            }
+            Some(_) => return Err(AccountError::UsernameTaken),
+            None => {
+                users.insert(
+                    String::from(username),
+                    Account {
+                        password_hash: None,
+                        role: Role::Editor,
+                        generation: 0,
+                        identity: Some(String::from(identity)),
+                    },
+                );
+                if let Err(err) = self.save(&users) {
+                    users.remove(username);
+                    return Err(err);
+                }
+                0
+            }
+        };
+        Ok(self.session_token(username, generation, SystemTime::now() + self.session_length))
+    }
+
+    /// Ends every session of the user 'session' belongs to, including
+    /// those on other devices. Returns false if the session was
+    /// already invalid.
+    pub fn logout(&self, session: &str) -> bool {
+        let user = match self.session_user(session) {
+            Some(user) => user,
+            None => return false,
+        };
+        let mut users = match self.users.write() {
+            Ok(users) => users,
+            Err(_) => return false,
+        };
+        match users.get_mut(&user.id) {
+            Some(account) => account.generation += 1,
+            None => return false,
+        }
+        // the sessions have ended even if they would be accepted again
+        // after a restart
+        let _ = self.save(&users);
+        true
+    }
+
+    /// Returns the role of the account for 'username', or None if
+    /// there is no such account
+    pub fn role(&self, username: &str) -> Option<Role> {
+        let users = self.users.read().ok()?;
+        users.get(username).map(|account| account.role)
+    }
+
+    /// Changes the role of the account for 'username', which applies
+    /// to their existing sessions and API tokens. Returns false if
+    /// there is no such account.
+    pub fn set_role(&self, username: &str, role: Role) -> bool {
+        let mut users = match self.users.write() {
+            Ok(users) => users,
+            Err(_) => return false,
+        };
+        match users.get_mut(username) {
+            Some(account) => account.role = role,
+            None => return false,
+        }
+        let _ = self.save(&users);
+        true
+    }
+
+    /// Returns the user a session token belongs to, or None if the
+    /// token is invalid, has expired, or the account no longer exists
+    pub fn session_user(&self, token: &str) -> Option<User> {
+        let mut parts = token.splitn(2, '.');
+        let payload = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        let mut mac = self.mac();
+        mac.input(&payload);
+        mac.verify(&signature).ok()?;
+
+        let payload = String::from_utf8(payload).ok()?;
+        let mut parts = payload.splitn(3, ':');
+        let expires: u64 = parts.next()?.parse().ok()?;
+        let generation: u64 = parts.next()?.parse().ok()?;
+        let username = parts.next()?;
+        if UNIX_EPOCH + Duration::from_secs(expires) < SystemTime::now() {
+            return None;
+        }
+        let users = self.users.read().ok()?;
+        users
+            .get(username)
+            .filter(|account| account.generation == generation)
+            .map(|account| User {
+                id: String::from(username),
+                role: account.role,
+            })
+    }
+
+    /// Returns the token forms must include to act on behalf of the
+    /// session 'session', so other sites cannot submit forms using
+    /// the user's session cookie
+    pub fn csrf_token(&self, session: &str) -> String {
+        let mut mac = self.mac();
+        mac.input(b"csrf:");
+        mac.input(session.as_bytes());
+        base64::encode_config(&mac.result().code(), base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Returns true if 'token' is the CSRF token for 'session'
+    pub fn verify_csrf_token(&self, session: &str, token: &str) -> bool {
+        let token = match base64::decode_config(token, base64::URL_SAFE_NO_PAD) {
+            Ok(token) => token,
+            Err(_) => return false,
+        };
+        let mut mac = self.mac();
+        mac.input(b"csrf:");
+        mac.input(session.as_bytes());
+        mac.verify(&token).is_ok()
+    }
+
+    // Returns a token identifying 'username' until 'expires', or until
+    // they log out of the session 'generation', signed so it cannot be
+    // forged or altered by the client
+    fn session_token(&self, username: &str, generation: u64, expires: SystemTime) -> String {
+        let expires = expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let payload = format!("{}:{}:{}", expires, generation, username);
+        let mut mac = self.mac();
+        mac.input(payload.as_bytes());
+        format!(
+            "{}.{}",
+            base64::encode_config(payload.as_bytes(), base64::URL_SAFE_NO_PAD),
+            base64::encode_config(&mac.result().code(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    // Saves the accounts to their file, if they have one
+    fn save(&self, users: &HashMap<String, Account>) -> Result<(), AccountError> {
+        let path = match self.file {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        persist::save(path, users).map_err(|err| {
+            error!("Unable to save accounts to {}: {}", path.display(), err);
+            AccountError::Internal
+        })
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::new_varkey(&self.key).expect("HMAC accepts keys of any length")
+    }
+}
+
+// Usernames may only contain letters, numbers, '_', '-' and '.'
+fn valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+impl Authenticator for Accounts {
+    fn authenticate(
+        &self,
+        token: &str,
+    ) -> Box<dyn Future<Item = Option<User>, Error = AuthError> + Send> {
+        Box::new(future::ok(self.session_user(token)))
+    }
+}
+
+/// Reasons signing up or logging in may fail
+#[derive(Debug, PartialEq)]
+pub enum AccountError {
+    /// Usernames may only contain letters, numbers, '_', '-' and '.'
+    InvalidUsername,
+    /// Another account already has the username
+    UsernameTaken,
+    /// The password is too short
+    WeakPassword,
+    /// The username or password is incorrect
+    InvalidCredentials,
+    /// The service checking the user's credentials could not be
+    /// reached
+    Unavailable,
+    /// Hashing the password failed, or the accounts could not be read
+    Internal,
+}
+
+impl Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AccountError::InvalidUsername => write!(
+                f,
+                "Usernames may only contain letters, numbers, '_', '-' and '.'"
+            ),
+            AccountError::UsernameTaken => write!(f, "That username is already taken"),
+            AccountError::WeakPassword => write!(
+                f,
+                "Passwords must be at least {} characters long",
+                MIN_PASSWORD_LENGTH
+            ),
+            AccountError::InvalidCredentials => write!(f, "Incorrect username or password"),
+            AccountError::Unavailable => {
+                write!(f, "Logging in is unavailable right now, please try again later")
+            }
+            AccountError::Internal => write!(f, "Something went wrong, please try again"),
+        }
+    }
+}
+
+impl Error for AccountError {
+    fn description(&self) -> &str {
+        match *self {
+            AccountError::InvalidUsername => "AccountError: invalid username",
+            AccountError::UsernameTaken => "AccountError: username taken",
+            AccountError::WeakPassword => "AccountError: password too short",
+            AccountError::InvalidCredentials => "AccountError: invalid credentials",
+            AccountError::Unavailable => "AccountError: unavailable",
+            AccountError::Internal => "AccountError: internal error",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signup_and_login() {
+        let accounts = Accounts::new(b"test key");
+        assert_eq!(accounts.signup("alice", "password1"), Ok(()));
+        assert_eq!(
+            accounts.signup("alice", "password2"),
+            Err(AccountError::UsernameTaken)
+        );
+        assert_eq!(
+            accounts.signup("bob smith", "password1"),
+            Err(AccountError::InvalidUsername)
+        );
+        assert_eq!(
+            accounts.signup("bob", "short"),
+            Err(AccountError::WeakPassword)
+        );
+        assert_eq!(
+            accounts.login("alice", "password2"),
+            Err(AccountError::InvalidCredentials)
+        );
+        assert_eq!(
+            accounts.login("bob", "password1"),
+            Err(AccountError::InvalidCredentials)
+        );
+        // unknown users are checked against a real hash, which must
+        // parse for the check to take as long as for known users
+        assert_eq!(
+            argon2::verify_encoded(DUMMY_PASSWORD_HASH, b"password1"),
+            Ok(false)
+        );
+
+        let token = accounts.login("alice", "password1").unwrap();
+        assert_eq!(
+            accounts.authenticate(&token).wait(),
+            Ok(Some(User {
+                id: String::from("alice"),
+                role: Role::Editor,
+            }))
+        );
+    }
+
+    #[test]
+    fn logout() {
+        let accounts = Accounts::new(b"test key");
+        accounts.signup("alice", "password1").unwrap();
+        let laptop = accounts.login("alice", "password1").unwrap();
+        let phone = accounts.login("alice", "password1").unwrap();
+        assert!(accounts.logout(&laptop));
+        assert_eq!(accounts.session_user(&laptop), None);
+        assert_eq!(accounts.session_user(&phone), None);
+        assert!(!accounts.logout(&phone));
+
+        let session = accounts.login("alice", "password1").unwrap();
+        assert_eq!(accounts.session_user(&session).unwrap().id, "alice");
+    }
+
+    #[test]
+    fn saved_accounts() {
+        let path = ::std::env::temp_dir()
+            .join(format!("tamawiki-accounts-{}.json", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        let accounts = Accounts::open(b"test key", &path).unwrap();
+        accounts.signup("alice", "password1").unwrap();
+        accounts.login_external("bob", "oidc:https://id.example.com#1234").unwrap();
+        accounts.set_role("bob", Role::Reader);
+        let session = accounts.login("alice", "password1").unwrap();
+        accounts.logout(&session);
+
+        let reopened = Accounts::open(b"test key", &path).unwrap();
+        assert!(reopened.login("alice", "password1").is_ok());
+        assert_eq!(reopened.role("bob"), Some(Role::Reader));
+        assert!(reopened.login_external("bob", "oidc:https://id.example.com#1234").is_ok());
+        assert_eq!(
+            reopened.login_external("bob", "oidc:https://id.example.com#5678"),
+            Err(AccountError::UsernameTaken)
+        );
+        assert_eq!(reopened.session_user(&session), None);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn administrators() {
+        let accounts = Accounts::new(b"test key").with_admins(vec![String::from("alice")]);
+        // admins must have an account
+        assert!(!accounts.is_admin("alice"));
+        accounts.signup("alice", "password1").unwrap();
+        accounts.signup("bob", "password1").unwrap();
+        assert!(accounts.is_admin("alice"));
+        assert!(!accounts.is_admin("bob"));
+    }
+
+    #[test]
+    fn external_login() {
+        let accounts = Accounts::new(b"test key");
+        accounts.signup("alice", "password1").unwrap();
+        assert_eq!(
+            accounts.login_external("alice", "oidc:https://id.example.com#1234"),
+            Err(AccountError::UsernameTaken)
+        );
+        let token = accounts.login_external("bob", "ldap:bob").unwrap();
+        assert_eq!(accounts.session_user(&token).unwrap().id, "bob");
+        assert!(accounts.login_external("bob", "ldap:bob").is_ok());
+        // another identity, even at the same provider, is not bob
+        assert_eq!(
+            accounts.login_external("bob", "oidc:https://id.example.com#1234"),
+            Err(AccountError::UsernameTaken)
+        );
+        // external users have no password to log in with
+        assert_eq!(accounts.login("bob", ""), Err(AccountError::InvalidCredentials));
+        assert_eq!(
+            accounts.signup("bob", "password1"),
+            Err(AccountError::UsernameTaken)
+        );
+    }
+
+    #[test]
+    fn csrf_tokens() {
+        let accounts = Accounts::new(b"test key");
+        accounts.signup("alice", "password1").unwrap();
+        let session = accounts.login("alice", "password1").unwrap();
+        let token = accounts.csrf_token(&session);
+        assert!(accounts.verify_csrf_token(&session, &token));
+        assert!(!accounts.verify_csrf_token(&session, "forged"));
+        assert!(!accounts.verify_csrf_token(&session, &accounts.csrf_token("other")));
+    }
+
+    #[test]
+    fn invalid_session_tokens() {
+        let accounts = Accounts::new(b"test key");
+        accounts.signup("alice", "password1").unwrap();
+        let token = accounts.login("alice", "password1").unwrap();
+
+        // signed with a different key
+        let other = Accounts::new(b"other key");
+        other.signup("alice", "password1").unwrap();
+        assert_eq!(other.session_user(&token), None);
+
+        // altered by the client
+        accounts.signup("bob", "password2").unwrap();
+        let (_, signature) = token.split_once('.').unwrap();
+        let payload = base64::encode_config(b"99999999999:0:bob", base64::URL_SAFE_NO_PAD);
+        let forged = format!("{}.{}", payload, signature);
+        assert_eq!(accounts.session_user(&forged), None);
+        assert_eq!(accounts.session_user("garbage"), None);
+
+        // expired
+        let expired =
+            accounts.session_token("alice", 0, SystemTime::now() - Duration::from_secs(60));
+        assert_eq!(accounts.session_user(&expired), None);
+    }
+}
@@ -0,0 +1,119 @@
+//! Posts change notifications to a Matrix room as notices, sent by a
+//! bot account using its access token.
+use hyper::{Body, Request};
+use rand::{self, Rng};
+
+use super::{Hook, Notification};
+use crate::markup::escape;
+
+/// A Matrix room and the account which posts to it
+pub struct Matrix {
+    // The homeserver's URL, without a trailing "/"
+    homeserver: String,
+    room_id: String,
+    access_token: String,
+}
+
+impl Matrix {
+    /// Posts to the room with 'room_id', e.g. "!abc123:example.org",
+    /// on the homeserver at 'homeserver' as the account 'access_token'
+    /// belongs to, which must have joined the room
+    pub fn new<S: Into<String>>(homeserver: S, room_id: S, access_token: S) -> Self {
+        Matrix {
+            homeserver: homeserver.into().trim_end_matches('/').to_string(),
+            room_id: room_id.into(),
+            access_token: access_token.into(),
+        }
+    }
+}
+
+impl Hook for Matrix {
+    fn request(&self, notifications: &[Notification]) -> Result<Request<Body>, http::Error> {
+        let text: Vec<String> = notifications
+            .iter()
+            .map(|notification| format!("{}: {}", notification.summary(), notification.diff_url))
+            .collect();
+        let html: Vec<String> = notifications
+            .iter()
+            .map(|notification| {
+                let path = escape(&notification.path.to_string_lossy());
+                let page = format!("<a href=\"{}\">{}</a>", escape(&notification.url), path);
+                let summary = escape(&notification.summary()).replacen(&path, &page, 1);
+                format!(
+                    "{} (<a href=\"{}\">changes</a>)",
+                    summary,
+                    escape(&notification.diff_url)
+                )
+            }).collect();
+        let body = json!({
+            "msgtype": "m.notice",
+            "body": text.join("\n"),
+            "format": "org.matrix.custom.html",
+            "formatted_body": html.join("<br>"),
+        });
+        // the homeserver ignores a repeated transaction id
+        let txn_id = format!("tamawiki-{:016x}", rand::thread_rng().gen::<u64>());
+        let url = format!(
+            "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            self.homeserver,
+            percent_encode(&self.room_id),
+            txn_id
+        );
+        Request::put(url.as_str())
+            .header("authorization", format!("Bearer {}", self.access_token).as_str())
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+    }
+}
+
+// Encodes 'text' for use as one segment of a URL path
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            b if b.is_ascii_alphanumeric() => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::Future;
+    use futures::stream::Stream;
+    use serde_json::{self, Value};
+    use std::path::PathBuf;
+
+    #[test]
+    fn send_notices() {
+        let notification = Notification {
+            path: PathBuf::from("guide.html"),
+            url: String::from("https://w/guide.html"),
+            diff_url: String::from("https://w/guide.html?action=diff&from=1&to=3"),
+            from: 1,
+            to: 3,
+            authors: vec![String::from("alice")],
+        };
+        let req = Matrix::new("https://matrix.example.org/", "!room:example.org", "secret")
+            .request(&[notification])
+            .unwrap();
+        let uri = req.uri().to_string();
+        assert!(uri.starts_with(
+            "https://matrix.example.org/_matrix/client/v3/rooms/%21room%3Aexample.org/\
+             send/m.room.message/tamawiki-"
+        ));
+        assert_eq!(req.headers()["authorization"], "Bearer secret");
+        let body = req.into_body().concat2().wait().unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["msgtype"], "m.notice");
+        assert_eq!(
+            body["body"],
+            "guide.html was changed by alice: https://w/guide.html?action=diff&from=1&to=3"
+        );
+        assert_eq!(
+            body["formatted_body"],
+            "<a href=\"https://w/guide.html\">guide.html</a> was changed by alice \
+             (<a href=\"https://w/guide.html?action=diff&amp;from=1&amp;to=3\">changes</a>)"
+        );
+    }
+}
@@ -0,0 +1,320 @@
+//! Tells other services about changes to documents by posting them
+//! over HTTP.
+//!
+//! A Dispatcher reads the changes made since its last check and
+//! groups the edits to each page into a Notification. Each Hook is
+//! given the notifications for pages under its path prefix, and turns
+//! them into one request: a `Webhook` posts them as JSON, while the
+//! hooks in `slack` and `matrix` post a message to a chat room.
+//! Requests which fail are logged and not retried.
+use futures::future::{self, Future};
+use futures::stream::Stream;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::document::Event;
+use crate::slug::percent_encode;
+use crate::store::{Change, SequenceId, Store, StoreError};
+
+pub mod matrix;
+pub mod slack;
+
+/// The edits made to a page since the last check
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Notification {
+    /// The document which was edited
+    pub path: PathBuf,
+    /// The page's URL
+    pub url: String,
+    /// The URL of the page showing the changes
+    pub diff_url: String,
+    /// The revision before the first edit
+    pub from: SequenceId,
+    /// The revision after the last edit
+    pub to: SequenceId,
+    /// The users who made the edits, if they were logged in
+    pub authors: Vec<String>,
+}
+
+impl Notification {
+    /// Describes the change in a sentence, e.g. "guide.html was
+    /// changed by alice"
+    pub fn summary(&self) -> String {
+        if self.authors.is_empty() {
+            format!("{} was changed", self.path.display())
+        } else {
+            format!(
+                "{} was changed by {}",
+                self.path.display(),
+                self.authors.join(", ")
+            )
+        }
+    }
+}
+
+/// Formats notifications as a request to another service
+pub trait Hook: Send + Sync + 'static {
+    /// The request telling the service about 'notifications', of
+    /// which there is at least one
+    fn request(&self, notifications: &[Notification]) -> Result<Request<Body>, http::Error>;
+}
+
+/// Posts notifications to a URL as JSON, in the form
+/// `{"changes": [{"path": ..., "url": ..., ...}]}`
+pub struct Webhook {
+    url: String,
+}
+
+impl Webhook {
+    /// Posts notifications to 'url'
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        Webhook { url: url.into() }
+    }
+}
+
+impl Hook for Webhook {
+    fn request(&self, notifications: &[Notification]) -> Result<Request<Body>, http::Error> {
+        let body = json!({ "changes": notifications });
+        Request::post(self.url.as_str())
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+    }
+}
+
+/// Checks for changes and sends them to each Hook. Clones share the
+/// time of the last check.
+pub struct Dispatcher<T: Store> {
+    store: T,
+    // Each hook, with the prefix of the paths it is told about
+    hooks: Vec<(PathBuf, Arc<dyn Hook>)>,
+    // The URL the wiki is served at, ending with "/"
+    base_url: String,
+    client: Client<HttpsConnector<HttpConnector>>,
+    // Changes up to this time have been read
+    checked: Arc<Mutex<SystemTime>>,
+}
+
+impl<T: Store> Clone for Dispatcher<T> {
+    fn clone(&self) -> Self {
+        Dispatcher {
+            store: self.store.clone(),
+            hooks: self.hooks.clone(),
+            base_url: self.base_url.clone(),
+            client: self.client.clone(),
+            checked: self.checked.clone(),
+        }
+    }
+}
+
+impl<T: Store> Dispatcher<T> {
+    /// Creates a Dispatcher for changes made from now on. Links in
+    /// notifications start with 'base_url', the URL readers reach the
+    /// wiki's home page at.
+    pub fn new(store: T, base_url: &str) -> Self {
+        let mut base_url = String::from(base_url);
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+        Dispatcher {
+            store,
+            hooks: Vec::new(),
+            base_url,
+            client: Client::builder().build(HttpsConnector::new(4)),
+            checked: Arc::new(Mutex::new(SystemTime::now())),
+        }
+    }
+
+    /// Tells 'hook' about changes to the documents under 'prefix',
+    /// e.g. "guides", or every document if the prefix is empty
+    pub fn with_hook<H: Hook>(mut self, prefix: &str, hook: H) -> Self {
+        let prefix = PathBuf::from(prefix.trim_matches('/'));
+        self.hooks.push((prefix, Arc::new(hook)));
+        self
+    }
+
+    /// Reads the changes made since the last check, up to 'now', and
+    /// sends each hook the notifications for its pages. Resolves to
+    /// the number of requests which succeeded.
+    pub fn check(
+        &self,
+        now: SystemTime,
+    ) -> Box<dyn Future<Item = usize, Error = StoreError> + Send> {
+        let since = {
+            let mut checked = self.checked.lock().unwrap();
+            let since = *checked;
+            *checked = now;
+            since
+        };
+        let dispatcher = self.clone();
+        let changes = self
+            .store
+            .changes()
+            .take_while(move |change| Ok(change.time > since))
+            .filter(move |change| change.time <= now)
+            .collect();
+        Box::new(changes.and_then(move |changes| {
+            let notifications = notifications(&dispatcher.base_url, changes);
+            let sent: Vec<_> = dispatcher
+                .hooks
+                .iter()
+                .filter_map(|&(ref prefix, ref hook)| {
+                    let selected: Vec<Notification> = notifications
+                        .iter()
+                        .filter(|notification| notification.path.starts_with(prefix))
+                        .cloned()
+                        .collect();
+                    if selected.is_empty() {
+                        return None;
+                    }
+                    Some(dispatcher.send(hook.request(&selected)))
+                }).collect();
+            future::join_all(sent).map(|sent| sent.into_iter().filter(|&ok| ok).count())
+        }))
+    }
+
+    // Makes a hook's request, resolving to true if it succeeded
+    fn send(
+        &self,
+        req: Result<Request<Body>, http::Error>,
+    ) -> Box<dyn Future<Item = bool, Error = StoreError> + Send> {
+        let req = match req {
+            Ok(req) => req,
+            Err(err) => {
+                warn!("Invalid webhook request: {}", err);
+                return Box::new(future::ok(false));
+            }
+        };
+        let uri = req.uri().clone();
+        Box::new(self.client.request(req).then(move |result| {
+            match result {
+                Ok(ref res) if res.status().is_success() => return Ok(true),
+                Ok(res) => warn!("Webhook {} responded with {}", uri, res.status()),
+                Err(err) => warn!("Unable to send webhook to {}: {}", uri, err),
+            }
+            Ok(false)
+        }))
+    }
+}
+
+// Groups the edits among 'changes', which are newest first, by page
+fn notifications(base_url: &str, changes: Vec<Change>) -> Vec<Notification> {
+    let mut pages: BTreeMap<PathBuf, (SequenceId, SequenceId, BTreeSet<String>)> =
+        BTreeMap::new();
+    for change in changes.into_iter().rev() {
+        if let Event::Edit(_) = change.event {
+            let page = pages
+                .entry(change.path)
+                .or_insert((change.seq - 1, change.seq, BTreeSet::new()));
+            page.1 = change.seq;
+            if let Some(user) = change.user {
+                page.2.insert(user);
+            }
+        }
+    }
+    pages
+        .into_iter()
+        .map(|(path, (from, to, authors))| Notification {
+            url: page_url(base_url, &path),
+            diff_url: format!("{}?action=diff&from={}&to={}", page_url(base_url, &path), from, to),
+            path,
+            from,
+            to,
+            authors: authors.into_iter().collect(),
+        }).collect()
+}
+
+fn page_url(base_url: &str, path: &Path) -> String {
+    format!("{}{}", base_url, percent_encode(&path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Edit, Join, Role};
+    use std::time::UNIX_EPOCH;
+
+    fn change(path: &str, seq: SequenceId, user: Option<&str>, event: Event) -> Change {
+        Change {
+            path: PathBuf::from(path),
+            seq,
+            user: user.map(String::from),
+            event,
+            time: UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn group_edits_by_page() {
+        let edit = || {
+            Event::Edit(Edit {
+                author: 1,
+                operations: vec![],
+            })
+        };
+        let join = Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: Some(String::from("bob")),
+        });
+        // newest first, as Store::changes() returns them
+        let changes = vec![
+            change("guides/setup.html", 4, Some("bob"), edit()),
+            change("index.html", 2, None, edit()),
+            change("guides/setup.html", 3, Some("bob"), join),
+            change("guides/setup.html", 2, Some("alice"), edit()),
+        ];
+        let notifications = notifications("https://wiki.example.com/", changes);
+        assert_eq!(
+            notifications,
+            vec![
+                Notification {
+                    path: PathBuf::from("guides/setup.html"),
+                    url: String::from("https://wiki.example.com/guides/setup.html"),
+                    diff_url: String::from(
+                        "https://wiki.example.com/guides/setup.html?action=diff&from=1&to=4"
+                    ),
+                    from: 1,
+                    to: 4,
+                    authors: vec![String::from("alice"), String::from("bob")],
+                },
+                Notification {
+                    path: PathBuf::from("index.html"),
+                    url: String::from("https://wiki.example.com/index.html"),
+                    diff_url: String::from(
+                        "https://wiki.example.com/index.html?action=diff&from=1&to=2"
+                    ),
+                    from: 1,
+                    to: 2,
+                    authors: vec![],
+                },
+            ]
+        );
+        assert_eq!(notifications[0].summary(), "guides/setup.html was changed by alice, bob");
+        assert_eq!(notifications[1].summary(), "index.html was changed");
+    }
+
+    #[test]
+    fn post_json() {
+        let notification = Notification {
+            path: PathBuf::from("a.html"),
+            url: String::from("http://w/a.html"),
+            diff_url: String::from("http://w/a.html?action=diff&from=0&to=1"),
+            from: 0,
+            to: 1,
+            authors: vec![],
+        };
+        let req = Webhook::new("http://hooks.example.com/wiki")
+            .request(&[notification])
+            .unwrap();
+        assert_eq!(req.uri(), "http://hooks.example.com/wiki");
+        let body = req.into_body().concat2().wait().unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["changes"][0]["path"], "a.html");
+        assert_eq!(body["changes"][0]["to"], 1);
+    }
+}
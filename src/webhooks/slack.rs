@@ -0,0 +1,82 @@
+//! Posts change notifications to a Slack channel through an incoming
+//! webhook, with a line for each changed page linking to it and to
+//! its changes.
+use hyper::{Body, Request};
+
+use super::{Hook, Notification};
+
+/// A Slack incoming webhook
+pub struct Slack {
+    url: String,
+}
+
+impl Slack {
+    /// Posts messages to the incoming webhook at 'url', e.g.
+    /// "https://hooks.slack.com/services/..."
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        Slack { url: url.into() }
+    }
+}
+
+impl Hook for Slack {
+    fn request(&self, notifications: &[Notification]) -> Result<Request<Body>, http::Error> {
+        let lines: Vec<String> = notifications
+            .iter()
+            .map(|notification| {
+                let page = format!(
+                    "<{}|{}>",
+                    escape(&notification.url),
+                    escape(&notification.path.to_string_lossy())
+                );
+                let summary = escape(&notification.summary()).replacen(
+                    &escape(&notification.path.to_string_lossy()),
+                    &page,
+                    1,
+                );
+                format!("{} (<{}|changes>)", summary, escape(&notification.diff_url))
+            }).collect();
+        let body = json!({ "text": lines.join("\n") });
+        Request::post(self.url.as_str())
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+    }
+}
+
+// Escapes the characters Slack treats as markup in message text
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::Future;
+    use futures::stream::Stream;
+    use serde_json::{self, Value};
+    use std::path::PathBuf;
+
+    #[test]
+    fn link_changed_pages() {
+        let notification = Notification {
+            path: PathBuf::from("q&a.html"),
+            url: String::from("https://w/q&a.html"),
+            diff_url: String::from("https://w/q&a.html?action=diff&from=1&to=3"),
+            from: 1,
+            to: 3,
+            authors: vec![String::from("alice")],
+        };
+        let req = Slack::new("https://hooks.slack.com/services/T/B/X")
+            .request(&[notification])
+            .unwrap();
+        assert_eq!(req.uri(), "https://hooks.slack.com/services/T/B/X");
+        let body = req.into_body().concat2().wait().unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["text"],
+            "<https://w/q&amp;a.html|q&amp;a.html> was changed by alice \
+             (<https://w/q&amp;a.html?action=diff&amp;from=1&amp;to=3|changes>)"
+        );
+    }
+}
@@ -27,20 +27,68 @@
 
 use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
 use hyper::upgrade::Upgraded;
+use std::borrow::Cow;
 use std::fmt;
 use std::io::ErrorKind::WouldBlock;
+use std::sync::{Arc, Mutex};
 use tungstenite::protocol;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::frame::CloseFrame;
 
 // re-export tungstenite Message
 pub use tungstenite::protocol::Message;
 
+/// Application close code sent when a participant is removed from a
+/// document session
+pub const CLOSE_KICKED: u16 = 4001;
+
+/// Application close code sent when a client falls too far behind
+/// reading messages
+pub const CLOSE_TOO_SLOW: u16 = 4002;
+
+/// The log target of websocket connection errors, wherever they are
+/// logged from, so they can be quietened with one filter
+pub const LOG_TARGET: &str = "tamawiki::websocket";
+
 pub struct WebSocket {
     inner: protocol::WebSocket<Upgraded>,
+    // The close frame to send when the connection is closed
+    close_frame: Arc<Mutex<Option<CloseFrame<'static>>>>,
+}
+
+impl WebSocket {
+    /// Returns a handle for setting the close code sent when this
+    /// WebSocket is closed, which remains usable after the WebSocket
+    /// has been wrapped in other Streams and Sinks.
+    pub fn close_handle(&self) -> CloseHandle {
+        CloseHandle(self.close_frame.clone())
+    }
+
+    fn take_close_frame(&mut self) -> Option<CloseFrame<'static>> {
+        self.close_frame.lock().unwrap().take()
+    }
 }
 
 impl From<protocol::WebSocket<Upgraded>> for WebSocket {
     fn from(ws: protocol::WebSocket<Upgraded>) -> Self {
-        Self { inner: ws }
+        Self {
+            inner: ws,
+            close_frame: Default::default(),
+        }
+    }
+}
+
+/// Sets the code and reason sent in the Close frame for a WebSocket
+#[derive(Clone)]
+pub struct CloseHandle(Arc<Mutex<Option<CloseFrame<'static>>>>);
+
+impl CloseHandle {
+    /// Sets the close code and reason, replacing any previously set
+    pub fn set(&self, code: CloseCode, reason: &'static str) {
+        *self.0.lock().unwrap() = Some(CloseFrame {
+            code,
+            reason: Cow::Borrowed(reason),
+        });
     }
 }
 
@@ -54,7 +102,12 @@ impl Stream for WebSocket {
             Err(::tungstenite::Error::Io(ref err)) if err.kind() == WouldBlock => {
                 Ok(Async::NotReady)
             }
-            Err(::tungstenite::Error::ConnectionClosed(_frame)) => Ok(Async::Ready(None)),
+            Err(::tungstenite::Error::ConnectionClosed(_frame)) => {
+                // the client started the close handshake and a reply
+                // has been queued, try to send it before finishing
+                let _ = self.inner.write_pending();
+                Ok(Async::Ready(None))
+            }
             Err(e) => Err(e),
         }
     }
@@ -88,7 +141,8 @@ impl Sink for WebSocket {
     }
 
     fn close(&mut self) -> Poll<(), Self::SinkError> {
-        match self.inner.close(None) {
+        let frame = self.take_close_frame();
+        match self.inner.close(frame) {
             Ok(()) => Ok(Async::Ready(())),
             Err(::tungstenite::Error::Io(ref err)) if err.kind() == WouldBlock => {
                 Ok(Async::NotReady)
@@ -98,16 +152,39 @@ impl Sink for WebSocket {
     }
 }
 
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        // Make a best effort attempt to start the close handshake so
+        // the client receives a close code even when the connection
+        // ends because of an error. If the handshake has already
+        // started this has no effect.
+        let frame = self.take_close_frame();
+        let _ = self.inner.close(frame);
+        let _ = self.inner.write_pending();
+    }
+}
+
 impl fmt::Debug for WebSocket {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("WebSocket").finish()
     }
 }
 
-pub fn websocket_text(
+/// Converts the WebSocket into a Stream + Sink of message payloads.
+/// Outgoing payloads are sent as Text frames, unless 'binary' is
+/// true, in which case they are sent as Binary frames.
+pub fn websocket_frames(
     ws: WebSocket,
-) -> impl Stream<Item = String, Error = ::tungstenite::Error>
-         + Sink<SinkItem = String, SinkError = ::tungstenite::Error> {
-    ws.and_then(|msg| msg.into_text())
-        .with(|text| Ok(Message::Text(text)))
+    binary: bool,
+) -> impl Stream<Item = Vec<u8>, Error = ::tungstenite::Error>
+         + Sink<SinkItem = Vec<u8>, SinkError = ::tungstenite::Error> {
+    ws.map(|msg| msg.into_data()).with(move |data| {
+        if binary {
+            Ok(Message::Binary(data))
+        } else {
+            String::from_utf8(data)
+                .map(Message::Text)
+                .map_err(|_| ::tungstenite::Error::Utf8)
+        }
+    })
 }
@@ -0,0 +1 @@
+// fixture for golden tests
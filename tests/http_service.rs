@@ -3,14 +3,41 @@ extern crate tamawiki;
 extern crate futures;
 extern crate http;
 extern crate hyper;
+extern crate serde_json;
+extern crate tokio;
+extern crate zip;
 
 use futures::future::Future;
 use futures::stream::Stream;
 use http::{Request, StatusCode};
 use hyper::service::Service;
 use hyper::Body;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::runtime::current_thread::Runtime;
+use zip::read::ZipArchive;
 
+use tamawiki::acl::Acl;
+use tamawiki::auth::BasicAuth;
+use tamawiki::document::{Edit, Event, Insert, Join, Leave, Operation, Publish, Role};
+use tamawiki::i18n::{Locales, Messages};
+use tamawiki::ldap::Directory;
+use tamawiki::notify::{Frequency, Watchlists};
+use tamawiki::oidc::OidcProvider;
+use tamawiki::quota::Quotas;
+use tamawiki::robots::CrawlPolicy;
+use tamawiki::search::builtin::MemoryIndex;
+use tamawiki::search::Indexer;
+use tamawiki::service::cors::Cors;
+use tamawiki::service::ratelimit::RateLimiter;
+use tamawiki::slug::SlugPolicy;
 use tamawiki::store::memory::MemoryStore;
+use tamawiki::store::Store;
+use tamawiki::templates::Templates;
+use tamawiki::theme::Theme;
+use tamawiki::tokens::{ApiTokens, Scope};
+use tamawiki::users::Accounts;
 use tamawiki::TamaWiki;
 
 #[test]
@@ -75,6 +102,36 @@ fn get_static_file() {
     );
 }
 
+#[test]
+fn fingerprinted_static_files() {
+    // static files are read on the blocking thread pool
+    let mut rt = tokio::runtime::Runtime::new().expect("new test runtime");
+    let store = MemoryStore::default();
+    let mut service = TamaWiki::new(store, "public/dist");
+    let mut get = |uri: &str| {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        rt.block_on(service.call(request)).unwrap()
+    };
+
+    let response = get("/_assets.json");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().concat2().wait().unwrap();
+    let assets: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let url = assets["css/main.css"].as_str().unwrap().to_string();
+    assert!(url.starts_with("/_static/css/main.css?v="));
+
+    // only the current version may be cached for good
+    let response = get(&url);
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["cache-control"],
+        "public, max-age=31536000, immutable"
+    );
+    let response = get("/_static/css/main.css?v=outdated");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("cache-control").is_none());
+}
+
 #[test]
 fn request_missing_page_with_edit_action() {
     let store = MemoryStore::default();
@@ -138,3 +195,2513 @@ fn request_existing_page_with_edit_action() {
             .contains("tw-editor")
     );
 }
+
+#[test]
+fn get_session_metrics() {
+    let store = MemoryStore::default();
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::get("/_metrics").body(Body::from("")).unwrap();
+
+    let response = service.call(request).wait().unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+
+    let body = response
+        .into_body()
+        .fold(
+            Vec::new(),
+            |mut body, chunk| -> Result<Vec<u8>, hyper::Error> {
+                body.append(&mut chunk.to_vec());
+                Ok(body)
+            },
+        ).wait()
+        .unwrap();
+
+    assert_eq!(
+        String::from_utf8(body.to_vec()).unwrap(),
+        "{\"sessions\":{}}"
+    );
+}
+
+#[test]
+fn get_health() {
+    let store = MemoryStore::default();
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::get("/_health").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+    let body = response.into_body().concat2().wait().unwrap();
+    assert_eq!(
+        String::from_utf8(body.to_vec()).unwrap(),
+        "{\"checks\":{\"sessions\":\"ok\",\"store\":\"ok\"},\"live\":true,\"ready\":true}"
+    );
+
+    // stop routing new requests to a server which is shutting down
+    service.shutdown();
+    let request = Request::get("/_health").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[test]
+fn websocket_upgrade_with_invalid_seq() {
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let upgrade = |uri: &str| {
+        Request::get(uri)
+            .header("upgrade", "websocket")
+            .header("connection", "upgrade")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::from(""))
+            .unwrap()
+    };
+    let read_body = |response: http::Response<Body>| {
+        let body = response
+            .into_body()
+            .fold(
+                Vec::new(),
+                |mut body, chunk| -> Result<Vec<u8>, hyper::Error> {
+                    body.append(&mut chunk.to_vec());
+                    Ok(body)
+                },
+            ).wait()
+            .unwrap();
+        String::from_utf8(body).unwrap()
+    };
+
+    // seq is not a number
+    let response = service.call(upgrade("/test.html?seq=abc")).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+    assert_eq!(
+        read_body(response),
+        "{\"code\":\"InvalidSeq\",\"reason\":\"Invalid seq parameter: \\\"abc\\\"\"}"
+    );
+
+    // seq is ahead of the document
+    let response = service.call(upgrade("/test.html?seq=5")).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(
+        read_body(response),
+        "{\"code\":\"InvalidSeq\",\"reason\":\"seq 5 is ahead of the document (at 3)\"}"
+    );
+
+    // new documents start at seq 0
+    let response = service.call(upgrade("/missing.html?seq=1")).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[test]
+fn get_document_events() {
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let mut writer = store.clone();
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::get("/_events/test.html?seq=2")
+        .body(Body::from(""))
+        .unwrap();
+
+    let response = service.call(request).wait().unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "text/event-stream"
+    );
+
+    let next_chunk = |body: Body| {
+        let (chunk, body) = body.into_future().wait().map_err(|(err, _)| err).unwrap();
+        (String::from_utf8(chunk.unwrap().to_vec()).unwrap(), body)
+    };
+
+    // events already in the store
+    let (chunk, body) = next_chunk(response.into_body());
+    assert_eq!(
+        chunk,
+        "id: 3\ndata: {\"client_seq\":0,\"seq\":3,\"event\":{\"Leave\":{\"id\":1}}}\n\n"
+    );
+
+    // events pushed after the request was made
+    writer
+        .push(PathBuf::from("test.html"), Event::Leave(Leave { id: 2 }))
+        .wait()
+        .unwrap();
+    let (chunk, _body) = next_chunk(body);
+    assert_eq!(
+        chunk,
+        "id: 4\ndata: {\"client_seq\":0,\"seq\":4,\"event\":{\"Leave\":{\"id\":2}}}\n\n"
+    );
+
+    // reconnecting clients resume after the last event they received
+    let request = Request::get("/_events/test.html?seq=2")
+        .header("last-event-id", "3")
+        .body(Body::from(""))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    let (chunk, _body) = next_chunk(response.into_body());
+    assert!(chunk.starts_with("id: 4\n"));
+}
+
+#[test]
+fn get_document_history() {
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::get("/test.html?action=history")
+        .body(Body::from(""))
+        .unwrap();
+
+    let response = service.call(request).wait().unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = response
+        .into_body()
+        .fold(
+            Vec::new(),
+            |mut body, chunk| -> Result<Vec<u8>, hyper::Error> {
+                body.append(&mut chunk.to_vec());
+                Ok(body)
+            },
+        ).wait()
+        .unwrap();
+    let body = String::from_utf8(body).unwrap();
+
+    // the initial content was inserted at seq 2
+    assert!(body.contains("<a href=\"?rev=2\">2</a>"));
+    assert!(body.contains("+11"));
+
+    let request = Request::get("/missing.html?action=history")
+        .body(Body::from(""))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn get_document_diff() {
+    let store = memorystore! {
+        "test.html" => "Testing\n123"
+    };
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::get("/test.html?action=diff&from=0&to=2")
+        .body(Body::from(""))
+        .unwrap();
+
+    let response = service.call(request).wait().unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = response
+        .into_body()
+        .fold(
+            Vec::new(),
+            |mut body, chunk| -> Result<Vec<u8>, hyper::Error> {
+                body.append(&mut chunk.to_vec());
+                Ok(body)
+            },
+        ).wait()
+        .unwrap();
+    let body = String::from_utf8(body).unwrap();
+
+    assert!(body.contains("<div class=\"diff-insert\">+Testing</div>"));
+    assert!(body.contains("<div class=\"diff-insert\">+123</div>"));
+
+    // revisions must be given and exist
+    let request = Request::get("/test.html?action=diff&from=0")
+        .body(Body::from(""))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let request = Request::get("/test.html?action=diff&from=0&to=10")
+        .body(Body::from(""))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn view_and_restore_revision() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let mut writer = store.clone();
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let path = PathBuf::from("test.html");
+    for event in [
+        Event::Join(Join {
+            id: 5,
+            role: Role::Editor,
+            user: None,
+        }),
+        Event::Edit(Edit {
+            author: 5,
+            operations: vec![Operation::Insert(Insert {
+                pos: 7,
+                content: String::from(" one two"),
+            })],
+        }),
+        Event::Leave(Leave { id: 5 }),
+    ] {
+        writer.push(path.clone(), event).wait().unwrap();
+    }
+
+    let read_body = |response: http::Response<Body>| {
+        let body = response
+            .into_body()
+            .fold(
+                Vec::new(),
+                |mut body, chunk| -> Result<Vec<u8>, hyper::Error> {
+                    body.append(&mut chunk.to_vec());
+                    Ok(body)
+                },
+            ).wait()
+            .unwrap();
+        String::from_utf8(body).unwrap()
+    };
+
+    // view the revision before the second edit
+    let request = Request::get("/test.html?rev=2").body(Body::from("")).unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = read_body(response);
+    assert!(body.contains("You are viewing revision 2"));
+    assert!(body.contains("Testing 123"));
+
+    // restoring must be done with a POST request
+    let request = Request::get("/test.html?action=restore&rev=2")
+        .body(Body::from(""))
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+    let request = Request::post("/test.html?action=restore&rev=2")
+        .body(Body::from(""))
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(response.headers().get("location").unwrap(), "/test.html");
+
+    // the restore is appended as a new edit
+    let (_seq, doc) = rt.block_on(writer.content(&path)).unwrap();
+    assert_eq!(doc.content, "Testing 123");
+}
+
+#[test]
+fn get_recent_changes() {
+    let store = memorystore! {
+        "a/one.html" => "One",
+        "b/two.html" => "Two"
+    };
+    let mut writer = store.clone();
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let path = PathBuf::from("a/three.html");
+    for event in [
+        Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: Some(String::from("bob")),
+        }),
+        Event::Edit(Edit {
+            author: 1,
+            operations: vec![Operation::Insert(Insert {
+                pos: 0,
+                content: String::from("Three"),
+            })],
+        }),
+    ] {
+        writer.push(path.clone(), event).wait().unwrap();
+    }
+
+    let mut get_changes = |uri: &str| {
+        let request = Request::get(uri).body(Body::from("")).unwrap();
+        let response = service.call(request).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response
+            .into_body()
+            .fold(
+                Vec::new(),
+                |mut body, chunk| -> Result<Vec<u8>, hyper::Error> {
+                    body.append(&mut chunk.to_vec());
+                    Ok(body)
+                },
+            ).wait()
+            .unwrap();
+        String::from_utf8(body).unwrap()
+    };
+
+    let body = get_changes("/_changes");
+    assert!(body.contains("one.html"));
+    assert!(body.contains("two.html"));
+    assert!(body.contains("three.html"));
+
+    let body = get_changes("/_changes?prefix=/a");
+    assert!(body.contains("one.html"));
+    assert!(!body.contains("two.html"));
+    assert!(body.contains("three.html"));
+
+    let body = get_changes("/_changes?author=bob");
+    assert!(!body.contains("one.html"));
+    assert!(!body.contains("two.html"));
+    assert!(body.contains("three.html"));
+}
+
+#[test]
+fn get_recent_changes_atom_feed() {
+    let store = memorystore! {
+        "one.html" => "One"
+    };
+    let mut service =
+        TamaWiki::new(store, "public/dist").with_public_url("https://wiki.example.com/wiki");
+
+    // the Host header is chosen by the client, so is not trusted
+    let request = Request::get("/_changes.atom")
+        .header("host", "evil.example.com")
+        .body(Body::from(""))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/atom+xml"
+    );
+
+    let body = response
+        .into_body()
+        .fold(
+            Vec::new(),
+            |mut body, chunk| -> Result<Vec<u8>, hyper::Error> {
+                body.append(&mut chunk.to_vec());
+                Ok(body)
+            },
+        ).wait()
+        .unwrap();
+    let body = String::from_utf8(body).unwrap();
+
+    assert!(body.starts_with("<?xml"));
+    assert_eq!(body.matches("<entry>").count(), 1);
+    assert!(body.contains("one.html revision 2"));
+    assert!(body.contains("href=\"https://wiki.example.com/wiki/_changes.atom\""));
+    assert!(body.contains("https://wiki.example.com/wiki/one.html?rev=2"));
+    assert!(!body.contains("evil.example.com"));
+}
+
+#[test]
+fn save_document_with_form_post() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let writer = store.clone();
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let save = |seq: &str, content: &str| {
+        let body = format!("seq={}&content={}", seq, content);
+        Request::post("/test.html")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let response = rt
+        .block_on(service.call(save("3", "Testing%2C+testing%0D%0A123")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(response.headers().get("location").unwrap(), "/test.html");
+    let (_, doc) = rt.block_on(writer.content(&PathBuf::from("test.html"))).unwrap();
+    assert_eq!(doc.content, "Testing, testing\n123");
+
+    // a stale form is transformed against the changes made since
+    let response = rt
+        .block_on(service.call(save("3", "Testing 1234")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let (_, doc) = rt.block_on(writer.content(&PathBuf::from("test.html"))).unwrap();
+    assert_eq!(doc.content, "Testing, testing\n1234");
+
+    // the form must include the content
+    let request = Request::post("/test.html")
+        .body(Body::from("seq=3"))
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn upload_and_serve_attachment() {
+    // attachments are written on the blocking thread pool
+    let mut rt = tokio::runtime::Runtime::new().expect("new test runtime");
+    let root = std::env::temp_dir().join(format!("tamawiki-attachments-{}", std::process::id()));
+    let store = memorystore! {
+        "test.html" => "A cat: [[attachment:images/cat.png]]"
+    };
+    let mut service = TamaWiki::new(store, "public/dist")
+        .with_attachments(root.clone())
+        .with_max_attachment_size(16);
+
+    let request = Request::post("/_attachments/images/cat.png")
+        .body(Body::from("not really a png"))
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(
+        response.headers().get("location").unwrap(),
+        "/_attachments/images/cat.png"
+    );
+
+    let request = Request::get("/_attachments/images/cat.png")
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("content-type").unwrap(), "image/png");
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    assert_eq!(&body[..], b"not really a png");
+
+    // uploads larger than the limit are refused
+    let request = Request::post("/_attachments/big.txt")
+        .body(Body::from("this is more than sixteen bytes"))
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // paths outside the attachments directory are refused
+    let request = Request::post("/_attachments/../escape.txt")
+        .body(Body::from("escape"))
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // documents embed the attachment
+    let request = Request::get("/test.html").body(Body::empty()).unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    assert!(
+        String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains("<img src=\"/_attachments/images/cat.png\"")
+    );
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn serve_attachments_without_running_scripts() {
+    // attachments are written on the blocking thread pool
+    let mut rt = tokio::runtime::Runtime::new().expect("new test runtime");
+    let root = std::env::temp_dir().join(format!(
+        "tamawiki-attachment-headers-{}",
+        std::process::id()
+    ));
+    let mut service = TamaWiki::new(MemoryStore::default(), "public/dist")
+        .with_attachments(root.clone());
+
+    let upload = |path: &str, data: &'static str| {
+        Request::post(format!("/_attachments/{}", path).as_str())
+            .body(Body::from(data))
+            .unwrap()
+    };
+    let svg = "<svg xmlns=\"http://www.w3.org/2000/svg\"><script>alert(1)</script></svg>";
+    for &(path, data) in &[("cat.png", "not really a png"), ("cat.svg", svg)] {
+        let response = rt.block_on(service.call(upload(path, data))).unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    let get = |path: &str| {
+        Request::get(format!("/_attachments/{}", path).as_str())
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // raster images are shown in place, with sniffing and scripts
+    // turned off
+    let response = rt.block_on(service.call(get("cat.png"))).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(headers.get("x-content-type-options").unwrap(), "nosniff");
+    assert_eq!(
+        headers.get("content-security-policy").unwrap(),
+        "default-src 'none'; sandbox"
+    );
+    assert!(headers.get("content-disposition").is_none());
+
+    // SVG may contain scripts, so it is downloaded instead
+    let response = rt.block_on(service.call(get("cat.svg"))).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(headers.get("content-type").unwrap(), "image/svg+xml");
+    assert_eq!(headers.get("content-disposition").unwrap(), "attachment");
+    assert_eq!(headers.get("x-content-type-options").unwrap(), "nosniff");
+    assert_eq!(
+        headers.get("content-security-policy").unwrap(),
+        "default-src 'none'; sandbox"
+    );
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn attachment_permissions() {
+    // attachments are written on the blocking thread pool
+    let mut rt = tokio::runtime::Runtime::new().expect("new test runtime");
+    let root = std::env::temp_dir().join(format!(
+        "tamawiki-attachment-permissions-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(root.join("private")).unwrap();
+    std::fs::write(root.join("private/plans.txt"), "secret plans").unwrap();
+    let acl = || {
+        Acl::parse(
+            "/** -> * (read)
+             /private/** -> user:alice (read, write)",
+        ).unwrap()
+    };
+
+    // anonymous users may not upload where everyone may only read,
+    // nor read where only alice may
+    let mut anonymous = TamaWiki::new(MemoryStore::default(), "public/dist")
+        .with_attachments(root.clone())
+        .with_acl(acl());
+    let request = Request::post("/_attachments/cat.png")
+        .body(Body::from("uploaded"))
+        .unwrap();
+    let response = rt.block_on(anonymous.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(!root.join("cat.png").exists());
+    let request = Request::get("/_attachments/private/plans.txt")
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(anonymous.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    // the ACL applies to the decoded path, however it is written
+    for uri in &[
+        "/_attachments/%70rivate/plans.txt",
+        "/_attachments/x/../private/plans.txt",
+        "/_attachments/x/%2E%2E/private/plans.txt",
+    ] {
+        let request = Request::get(*uri).body(Body::empty()).unwrap();
+        let response = rt.block_on(anonymous.call(request)).unwrap();
+        assert_ne!(response.status(), StatusCode::OK, "{}", uri);
+    }
+
+    let mut auth = BasicAuth::new();
+    auth.insert("alice", "secret");
+    auth.insert("bob", "secret");
+    let mut service = TamaWiki::new(MemoryStore::default(), "public/dist")
+        .with_attachments(root.clone())
+        .with_basic_auth(auth)
+        .with_acl(acl());
+
+    // "alice:secret" and "bob:secret"
+    let alice = "Basic YWxpY2U6c2VjcmV0";
+    let bob = "Basic Ym9iOnNlY3JldA==";
+    let request = |method: &str, uri: &str, credentials: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("authorization", credentials)
+            .body(Body::from("uploaded"))
+            .unwrap()
+    };
+
+    // uploading needs write access to the path
+    let response = rt
+        .block_on(service.call(request("POST", "/_attachments/private/cat.png", bob)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    for uri in &[
+        "/_attachments/%70rivate/cat.png",
+        "/_attachments/x/../private/cat.png",
+    ] {
+        let response = rt.block_on(service.call(request("POST", uri, bob))).unwrap();
+        assert_ne!(response.status(), StatusCode::CREATED, "{}", uri);
+    }
+    assert!(!root.join("private/cat.png").exists());
+    let response = rt
+        .block_on(service.call(request("POST", "/_attachments/private/cat.png", alice)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // reading needs read access to the path
+    let response = rt
+        .block_on(service.call(request("GET", "/_attachments/private/plans.txt", bob)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = rt
+        .block_on(service.call(request("GET", "/_attachments/private/plans.txt", alice)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    assert_eq!(&body[..], b"secret plans");
+    let response = rt
+        .block_on(service.call(request("GET", "/_attachments/%70rivate/plans.txt", alice)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn attachments_disabled_by_default() {
+    let store = MemoryStore::default();
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::post("/_attachments/cat.png")
+        .body(Body::from("data"))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn conditional_get_document() {
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let mut writer = store.clone();
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::get("/test.html").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("cache-control").unwrap(), "no-cache");
+    let etag = response.headers().get("etag").unwrap().clone();
+
+    // the editor page is cached separately
+    let request = Request::get("/test.html?action=edit")
+        .header("if-none-match", etag.clone())
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers().get("etag").unwrap(), &etag);
+
+    let request = Request::get("/test.html")
+        .header("if-none-match", etag.clone())
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers().get("etag").unwrap(), &etag);
+
+    // once the document changes the page is served again
+    writer
+        .push(
+            PathBuf::from("test.html"),
+            Event::Join(Join {
+                id: 2,
+                role: Role::Editor,
+                user: None,
+            }),
+        ).wait()
+        .unwrap();
+    let request = Request::get("/test.html")
+        .header("if-none-match", etag.clone())
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers().get("etag").unwrap(), &etag);
+}
+
+#[test]
+fn get_document_as_json() {
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::get("/test.html")
+        .header("accept", "application/json")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+    assert_eq!(response.headers().get("vary").unwrap(), "Accept");
+    let etag = response.headers().get("etag").unwrap().clone();
+    let body = response.into_body().concat2().wait().unwrap();
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        doc,
+        serde_json::json!({
+            "content": "Testing 123",
+            "seq": 3,
+            "participants": []
+        })
+    );
+
+    // the JSON and HTML representations are cached separately
+    let request = Request::get("/test.html")
+        .header("if-none-match", etag.clone())
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("content-type").is_none());
+
+    // browsers which also accept JSON still get the page
+    let request = Request::get("/test.html")
+        .header("accept", "text/html, application/json;q=0.9")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert!(response.headers().get("content-type").is_none());
+
+    let request = Request::get("/missing.html")
+        .header("accept", "application/json")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+}
+
+#[test]
+fn export_documents() {
+    let store = memorystore! {
+        "guides/setup.html" => "Install <it>",
+        "guides/usage.html" => "Run it",
+        "index.html" => "Welcome",
+        "large.html" => "0123456789".repeat(20_000)
+    };
+    let mut service = TamaWiki::new(store, "public/dist");
+    let mut export = |uri: &str| {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = service.call(request).wait().unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.into_body().concat2().wait().unwrap().to_vec();
+        (status, headers, body)
+    };
+
+    let (status, headers, body) = export("/guides/setup.html?action=export&format=markdown");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["content-type"], "text/markdown; charset=utf-8");
+    assert_eq!(
+        headers["content-disposition"],
+        "attachment; filename=\"setup.md\""
+    );
+    assert_eq!(body, b"Install <it>");
+    assert_eq!(headers["content-length"], "12");
+
+    // large documents are sent in several chunks
+    let (status, headers, body) = export("/large.html?action=export");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["content-length"], "200000");
+    assert_eq!(body, "0123456789".repeat(20_000).into_bytes());
+
+    let (status, headers, body) = export("/guides/setup.html?action=export&format=html");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        headers["content-disposition"],
+        "attachment; filename=\"setup.html\""
+    );
+    assert!(String::from_utf8(body).unwrap().contains("Install &lt;it&gt;"));
+
+    let (status, headers, body) = export("/guides?action=export&bundle=zip");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["content-type"], "application/zip");
+    assert_eq!(
+        headers["content-disposition"],
+        "attachment; filename=\"guides.zip\""
+    );
+    assert!(body.starts_with(b"PK"));
+
+    let (status, _, _) = export("/guides/setup.html?action=export&format=pdf");
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _, _) = export("/missing.html?action=export");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _, _) = export("/missing?action=export&bundle=zip");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn export_bundles_published_revisions() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "guides/setup.html" => "Setup",
+        "guides/secret.html" => "Published"
+    };
+    // the first revision of secret.html is published, and a later
+    // edit is a draft
+    let mut writer = store.clone();
+    let events = vec![
+        Event::Join(Join {
+            id: 2,
+            role: Role::Editor,
+            user: None,
+        }),
+        Event::Publish(Publish { id: 2, seq: 3 }),
+        Event::Edit(Edit {
+            author: 2,
+            operations: vec![Operation::Insert(Insert {
+                pos: 9,
+                content: String::from(" and drafted"),
+            })],
+        }),
+        Event::Leave(Leave { id: 2 }),
+    ];
+    for event in events {
+        rt.block_on(writer.push(PathBuf::from("guides/secret.html"), event))
+            .unwrap();
+    }
+    // readers may edit the guides, but not secret.html
+    let acl = Acl::parse(
+        "/** -> * (read, write)
+         /guides/secret.html -> * (read)",
+    ).unwrap();
+    let mut service = TamaWiki::new(store, "public/dist")
+        .with_acl(acl)
+        .with_drafts();
+
+    let request = Request::get("/guides?action=export&bundle=zip")
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let mut archive = ZipArchive::new(Cursor::new(body.to_vec())).unwrap();
+    let mut read = |name: &str| {
+        let mut content = String::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        content
+    };
+    assert_eq!(read("guides/setup.md"), "Setup");
+    assert_eq!(read("guides/secret.md"), "Published");
+}
+
+#[test]
+fn serve_under_base_path() {
+    let store = memorystore! {
+        "test.html" => "Testing 123",
+        "moved.html" => "#REDIRECT [[test.html]]"
+    };
+    let mut service = TamaWiki::new(store, "public/dist").with_base_path("/wiki");
+    let mut get = |uri: &str| {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        service.call(request).wait().unwrap()
+    };
+
+    let response = get("/wiki/test.html");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().concat2().wait().unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("Testing 123"));
+    assert!(body.contains("href=\"/wiki/_static/css/main.css?v="));
+
+    // redirects stay under the base path
+    let response = get("/wiki/moved.html");
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(response.headers()["location"], "/wiki/test.html");
+
+    assert_eq!(get("/wiki/_health").status(), StatusCode::OK);
+    assert_eq!(get("/test.html").status(), StatusCode::NOT_FOUND);
+    assert_eq!(get("/_health").status(), StatusCode::NOT_FOUND);
+
+    let request = Request::post("/wiki/test.html")
+        .body(Body::from("seq=3&content=Testing+1234"))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(response.headers()["location"], "/wiki/test.html");
+}
+
+#[test]
+fn breadcrumb_navigation() {
+    let store = memorystore! {
+        "guides" => "All the guides",
+        "guides/setup/linux.html" => "Install it"
+    };
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::get("/guides/setup/linux.html")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().concat2().wait().unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("class=\"breadcrumbs\""));
+    // ancestors which are documents are linked to
+    assert!(body.contains("<a href=\"&#x2F;guides\">guides</a>"));
+    assert!(body.contains("<span>setup</span>"));
+
+    // top level documents have no breadcrumbs
+    let request = Request::get("/guides").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    let body = response.into_body().concat2().wait().unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(!body.contains("class=\"breadcrumbs\""));
+}
+
+#[test]
+fn navigation_sidebar() {
+    let store = memorystore! {
+        "sidebar.html" => "Guides",
+        "test.html" => "Testing 123"
+    };
+    let mut writer = store.clone();
+    let mut service = TamaWiki::new(store, "public/dist").with_sidebar("sidebar.html");
+    let mut get = |uri: &str| {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = service.call(request).wait().unwrap();
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+        let body = response.into_body().concat2().wait().unwrap();
+        (etag, String::from_utf8(body.to_vec()).unwrap())
+    };
+
+    let (etag, body) = get("/test.html");
+    assert!(body.contains("class=\"sidebar\"><pre class=\"page-content\">Guides</pre>"));
+
+    // editing the sidebar's page changes it everywhere
+    for event in [
+        Event::Join(Join {
+            id: 1,
+            role: Role::Editor,
+            user: None,
+        }),
+        Event::Edit(Edit {
+            author: 1,
+            operations: vec![Operation::Insert(Insert {
+                pos: 6,
+                content: String::from(" and FAQ"),
+            })],
+        }),
+    ] {
+        writer.push(PathBuf::from("sidebar.html"), event).wait().unwrap();
+    }
+    let (new_etag, body) = get("/test.html");
+    assert!(body.contains("<pre class=\"page-content\">Guides and FAQ</pre>"));
+    assert_ne!(etag, new_etag);
+}
+
+#[test]
+fn override_templates() {
+    let dir = std::env::temp_dir().join("tamawiki-override-templates");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("document.html"),
+        "{% extends \"base.html\" %}{% block content %}Custom: {{ content }}{% endblock %}",
+    ).unwrap();
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let templates = Templates::from_dir(&dir).unwrap();
+    let mut service = TamaWiki::new(store, "public/dist").with_templates(templates);
+
+    let request = Request::get("/test.html").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().concat2().wait().unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("Custom: Testing 123"));
+    // the built-in base template is still used
+    assert!(body.contains("<main>"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn choose_theme() {
+    let dir = std::env::temp_dir().join("tamawiki-theme");
+    std::fs::create_dir_all(dir.join("templates")).unwrap();
+    std::fs::create_dir_all(dir.join("static/css")).unwrap();
+    std::fs::write(
+        dir.join("templates/document.html"),
+        "{% extends \"base.html\" %}{% block content %}Dark: {{ content }}{% endblock %}",
+    ).unwrap();
+    std::fs::write(dir.join("static/css/main.css"), "body { color: white }").unwrap();
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let theme = Theme::from_dir("dark", &dir).unwrap();
+    let mut service = TamaWiki::new(store, "public/dist").with_theme(theme);
+    // static files are read on the blocking thread pool
+    let mut rt = tokio::runtime::Runtime::new().expect("new test runtime");
+    let mut get = |uri: &str, theme: &str| {
+        let request = Request::get(uri)
+            .header("cookie", format!("tamawiki_theme={}", theme).as_str())
+            .body(Body::empty())
+            .unwrap();
+        let response = rt.block_on(service.call(request)).unwrap();
+        let status = response.status();
+        let body = rt.block_on(response.into_body().concat2()).unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    };
+
+    let (status, body) = get("/test.html", "dark");
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("Dark: Testing 123"));
+    // readers can switch between themes
+    assert!(body.contains("<option value=\"dark\" selected>dark</option>"));
+    let (_, body) = get("/test.html", "");
+    assert!(!body.contains("Dark: Testing 123"));
+    assert!(body.contains("Testing 123"));
+    let (_, body) = get("/test.html", "unknown");
+    assert!(!body.contains("Dark: Testing 123"));
+
+    let (status, body) = get("/_static/css/main.css", "dark");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "body { color: white }");
+
+    let request = Request::post("/_theme")
+        .header("referer", "http://localhost/test.html")
+        .body(Body::from("theme=dark"))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(response.headers()["location"], "/test.html");
+    let cookie = response.headers()["set-cookie"].to_str().unwrap();
+    assert!(cookie.starts_with("tamawiki_theme=dark;"));
+
+    let request = Request::post("/_theme")
+        .body(Body::from("theme=unknown"))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn site_branding() {
+    let store = memorystore! {
+        "guides/getting-started.html" => "Hello",
+        "about.html" => "---\ntitle: About us\n---\nWe write wikis"
+    };
+    let mut service = TamaWiki::new(store, "public/dist")
+        .with_site_name("Acme Wiki")
+        .with_logo("/images/logo.png")
+        .with_footer("Copyright Acme");
+    let mut get = |uri: &str| {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = service.call(request).wait().unwrap();
+        let body = response.into_body().concat2().wait().unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    let body = get("/guides/getting-started.html");
+    assert!(body.contains("<title>getting started - Acme Wiki</title>"));
+    assert!(body.contains("src=\"&#x2F;_static/images&#x2F;logo.png\" alt=\"Acme Wiki\""));
+    assert!(body.contains("<p>Copyright Acme</p>"));
+
+    // titles set in front matter, which is not shown
+    let body = get("/about.html");
+    assert!(body.contains("<title>About us - Acme Wiki</title>"));
+    assert!(body.contains("<pre class=\"page-content\">We write wikis</pre>"));
+}
+
+#[test]
+fn translate_ui() {
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let mut messages = Messages::new();
+    messages.insert(String::from("edit"), String::from("Modifier"));
+    messages.insert(String::from("not_found"), String::from("Introuvable"));
+    let locales = Locales::new().with_catalogue("fr", messages);
+    let mut service = TamaWiki::new(store, "public/dist").with_locales(locales);
+    let mut get = |uri: &str, accept_language: &str, cookie: &str| {
+        let request = Request::get(uri)
+            .header("accept-language", accept_language)
+            .header("cookie", cookie)
+            .body(Body::empty())
+            .unwrap();
+        let response = service.call(request).wait().unwrap();
+        let body = response.into_body().concat2().wait().unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    let body = get("/test.html", "fr-FR, en;q=0.5", "");
+    assert!(body.contains("<html lang=\"fr\">"));
+    assert!(body.contains(">Modifier</a>"));
+    // readers can switch between locales
+    assert!(body.contains("<option value=\"fr\" selected>fr</option>"));
+    let body = get("/test.html", "en", "");
+    assert!(body.contains(">Edit</a>"));
+    let body = get("/test.html", "en", "tamawiki_locale=fr");
+    assert!(body.contains(">Modifier</a>"));
+
+    // page titles and messages missing from a catalogue
+    let body = get("/missing.html?rev=1", "fr", "");
+    assert!(body.contains("<title>Introuvable - TamaWiki</title>"));
+    assert!(body.contains("There is nothing at <code>&#x2F;missing.html</code>."));
+
+    let request = Request::post("/_locale")
+        .header("referer", "http://localhost/test.html")
+        .body(Body::from("locale=fr"))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let cookie = response.headers()["set-cookie"].to_str().unwrap();
+    assert!(cookie.starts_with("tamawiki_locale=fr;"));
+}
+
+#[test]
+fn cross_origin_requests() {
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let mut service = TamaWiki::new(store, "public/dist")
+        .with_cors(Cors::origins(vec!["https://tools.example.com"]));
+
+    let request = Request::options("/_changes")
+        .header("origin", "https://tools.example.com")
+        .header("access-control-request-method", "GET")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .unwrap(),
+        "https://tools.example.com"
+    );
+
+    let request = Request::get("/test.html")
+        .header("origin", "https://tools.example.com")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .unwrap(),
+        "https://tools.example.com"
+    );
+
+    // other origins are not allowed to read responses
+    let request = Request::get("/test.html")
+        .header("origin", "https://elsewhere.example.com")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert!(
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .is_none()
+    );
+
+    // nor may allowed origins make changes
+    let request = Request::post("/test.html")
+        .header("origin", "https://tools.example.com")
+        .body(Body::from("seq=3"))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert!(
+        response
+            .headers()
+            .get("access-control-allow-origin")
+            .is_none()
+    );
+}
+
+#[test]
+fn not_found_error_page() {
+    let store = MemoryStore::default();
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::get("/missing.html?rev=1")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.into_body().concat2().wait().unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("<code>&#x2F;missing.html</code>"));
+    assert!(body.contains("Create this page"));
+
+    // pages cannot be created under the reserved prefix
+    let request = Request::get("/_missing.html").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.into_body().concat2().wait().unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(!body.contains("Create this page"));
+}
+
+#[test]
+fn follow_redirect_pages() {
+    let store = memorystore! {
+        "old.html" => "#REDIRECT [[older.html]]",
+        "older.html" => "#REDIRECT [[new.html]]",
+        "new.html" => "New page",
+        "loop-a.html" => "#REDIRECT [[loop-b.html]]",
+        "loop-b.html" => "#REDIRECT [[loop-a.html]]"
+    };
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::get("/old.html").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(response.headers().get("location").unwrap(), "/new.html");
+
+    // the redirect page itself can still be viewed and edited
+    let request = Request::get("/old.html?redirect=no")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let request = Request::get("/old.html?action=edit")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // loops send the reader to the page they started from
+    let request = Request::get("/loop-a.html").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(
+        response.headers().get("location").unwrap(),
+        "/loop-a.html?redirect=no"
+    );
+}
+
+#[test]
+fn normalize_document_paths() {
+    let store = memorystore! {
+        "foo_bar" => "Foo bar"
+    };
+    let mut service = TamaWiki::new(store, "public/dist").with_slug_policy(
+        SlugPolicy::default()
+            .fold_case(true)
+            .spaces_to_underscores(true),
+    );
+
+    for path in &["/foo_bar", "/Foo%20Bar", "/FOO_bar"] {
+        let request = Request::get(*path).body(Body::empty()).unwrap();
+        let response = service.call(request).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().concat2().wait().unwrap();
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("Foo bar"));
+    }
+}
+
+#[test]
+fn unicode_document_paths() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "Caf\u{e9} Notes" => "All the notes",
+        "Caf\u{e9} Notes/Men\u{fc}.html" => "Coffee"
+    };
+    let writer = store.clone();
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let request = Request::get("/Caf%C3%A9%20Notes/Men%C3%BC.html")
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("Coffee"));
+    // links are encoded again
+    assert!(body.contains("<a href=\"&#x2F;Caf%C3%A9%20Notes\">Caf\u{e9} Notes</a>"));
+
+    let request = Request::get("/Caf%C3%A9%20Notes/Men%C3%BC.html?action=edit")
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("action=\"&#x2F;Caf%C3%A9%20Notes/Men%C3%BC.html\""));
+
+    let request = Request::post("/Caf%C3%A9%20Notes/Men%C3%BC.html")
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(Body::from("seq=3&content=Tea"))
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(
+        response.headers().get("location").unwrap(),
+        "/Caf%C3%A9%20Notes/Men%C3%BC.html"
+    );
+    let (_, doc) = rt
+        .block_on(writer.content(&PathBuf::from("Caf\u{e9} Notes/Men\u{fc}.html")))
+        .unwrap();
+    assert_eq!(doc.content, "Tea");
+}
+
+#[test]
+fn reject_unsafe_document_paths() {
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let writer = store.clone();
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    let paths = [
+        "/%2e%2e/secret",
+        "/test.html/%2E%2E%2F..%2Fsecret",
+        "/%2Fetc%2Fpasswd",
+        "/..%5Csecret",
+        "/test.html%00.png",
+    ];
+    for path in paths.iter() {
+        let request = Request::get(*path).body(Body::empty()).unwrap();
+        let response = service.call(request).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "GET {}", path);
+
+        let request = Request::post(*path)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(Body::from("seq=0&content=Gotcha"))
+            .unwrap();
+        let response = service.call(request).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "POST {}", path);
+
+        let request = Request::get(*path)
+            .header("upgrade", "websocket")
+            .header("connection", "upgrade")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap();
+        let response = service.call(request).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "upgrade {}", path);
+    }
+    // nothing was written to the store
+    let changes = writer.changes().collect().wait().unwrap();
+    assert_eq!(changes.len(), 3);
+}
+
+#[test]
+fn directory_index_and_listing() {
+    let store = memorystore! {
+        "guides/index" => "All the guides",
+        "docs/faq.html" => "Questions",
+        "docs/setup.html" => "Setup",
+        "docs/setup/linux.html" => "Linux",
+        "notes" => "Some notes"
+    };
+    let mut service = TamaWiki::new(store, "public/dist");
+    let mut get = |uri: &str| {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = service.call(request).wait().unwrap();
+        let status = response.status();
+        let location = response.headers().get("location").cloned();
+        let body = response.into_body().concat2().wait().unwrap();
+        (status, location, String::from_utf8(body.to_vec()).unwrap())
+    };
+
+    // directories with an index page redirect to it
+    for uri in &["/guides/", "/guides"] {
+        let (status, location, _) = get(*uri);
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(location.unwrap(), "/guides/index");
+    }
+
+    // others list the documents under them
+    for uri in &["/docs/", "/docs"] {
+        let (status, _, body) = get(*uri);
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("<a href=\"&#x2F;docs/faq.html\">faq.html</a>"));
+        assert!(body.contains("<a href=\"&#x2F;docs/setup.html\">setup.html</a>"));
+        assert!(body.contains("<a href=\"&#x2F;docs/setup/\">setup/</a>"));
+        assert!(!body.contains("linux.html"));
+    }
+
+    // documents are served as usual
+    let (status, _, body) = get("/notes");
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("Some notes"));
+    let (status, _, _) = get("/missing/");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn delete_and_restore_documents() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "guides/setup.html" => "Setup",
+        "guides/usage.html" => "Usage"
+    };
+    let writer = store.clone();
+    let tokens = ApiTokens::default();
+    let (_, admin) = tokens.create("alice", "admin", Scope::Admin, None).unwrap();
+    let (_, editor) = tokens.create("alice", "editor", Scope::Write, None).unwrap();
+    // anyone may create an admin token for themselves
+    let (_, bob) = tokens.create("bob", "admin", Scope::Admin, None).unwrap();
+    let accounts = Accounts::new(b"test key").with_admins(vec![String::from("alice")]);
+    accounts.signup("alice", "password1").unwrap();
+    accounts.signup("bob", "password1").unwrap();
+    let mut service = TamaWiki::new(store, "public/dist")
+        .with_accounts(accounts)
+        .with_api_tokens(tokens);
+    let mut call = |rt: &mut Runtime, request: Request<Body>| {
+        let response = rt.block_on(service.call(request)).unwrap();
+        let status = response.status();
+        let location = response.headers().get("location").cloned();
+        let body = rt.block_on(response.into_body().concat2()).unwrap();
+        // zip bundles are not text, but name their files in plain text
+        let body = String::from_utf8_lossy(&body).into_owned();
+        (status, location, body)
+    };
+    let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+    let post = |uri: &str, token: Option<&str>| {
+        let mut request = Request::post(uri);
+        if let Some(token) = token {
+            request.header("authorization", format!("Bearer {}", token).as_str());
+        }
+        request.body(Body::empty()).unwrap()
+    };
+    let restore = |token: Option<&str>| post("/_trash/guides/setup.html", token);
+
+    // only logged in editors are offered to delete the document
+    let (_, _, body) = call(&mut rt, get("/guides/setup.html"));
+    assert!(!body.contains("?action=delete"));
+    let request = Request::get("/guides/setup.html")
+        .header("authorization", format!("Bearer {}", editor).as_str())
+        .body(Body::empty())
+        .unwrap();
+    let (_, _, body) = call(&mut rt, request);
+    assert!(body.contains("?action=delete"));
+
+    // deleting must be done with a POST request
+    let (status, _, _) = call(&mut rt, get("/guides/setup.html?action=delete"));
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    // by a logged in user
+    let (status, _, _) = call(&mut rt, post("/guides/setup.html?action=delete", None));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let request = post("/guides/setup.html?action=delete", Some(&editor));
+    let (status, location, _) = call(&mut rt, request);
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(location.unwrap(), "/guides/setup.html");
+
+    // the document is gone, but its history is not
+    let (status, _, body) = call(&mut rt, get("/guides/setup.html"));
+    assert_eq!(status, StatusCode::GONE);
+    assert!(body.contains("This page has been deleted"));
+    assert!(!body.contains("page-content"));
+    let request = Request::get("/guides/setup.html")
+        .header("accept", "application/json")
+        .body(Body::empty())
+        .unwrap();
+    let (status, _, _) = call(&mut rt, request);
+    assert_eq!(status, StatusCode::GONE);
+    let (status, _, _) = call(&mut rt, get("/guides/setup.html?action=history"));
+    assert_eq!(status, StatusCode::OK);
+    // though it cannot be exported or read at an earlier revision
+    let (status, _, _) = call(&mut rt, get("/guides/setup.html?action=export"));
+    assert_eq!(status, StatusCode::GONE);
+    let (status, _, body) = call(&mut rt, get("/guides/setup.html?rev=2"));
+    assert_eq!(status, StatusCode::GONE);
+    assert!(!body.contains("Setup"));
+    let request = get("/guides/setup.html?action=diff&from=1&to=2");
+    let (status, _, _) = call(&mut rt, request);
+    assert_eq!(status, StatusCode::GONE);
+    let (status, _, body) = call(&mut rt, get("/guides?action=export&bundle=zip"));
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("guides/usage.md"));
+    assert!(!body.contains("guides/setup.md"));
+    let (status, _, body) = call(&mut rt, get("/guides/"));
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("usage.html"));
+    assert!(!body.contains("setup.html"));
+
+    // only admins may restore it
+    let (status, _, _) = call(&mut rt, restore(None));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, _) = call(&mut rt, restore(Some(&editor)));
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _, _) = call(&mut rt, restore(Some(&bob)));
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, location, _) = call(&mut rt, restore(Some(&admin)));
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(location.unwrap(), "/guides/setup.html");
+    let (status, _, body) = call(&mut rt, get("/guides/setup.html"));
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("Setup"));
+    let (status, _, _) = call(&mut rt, get("/guides/setup.html?rev=2"));
+    assert_eq!(status, StatusCode::OK);
+    let path = PathBuf::from("guides/setup.html");
+    let (_, doc) = rt.block_on(writer.content(&path)).unwrap();
+    assert!(!doc.deleted);
+    assert_eq!(doc.content, "Setup");
+
+    // documents which are not deleted cannot be restored
+    let (status, _, _) = call(&mut rt, restore(Some(&admin)));
+    assert_eq!(status, StatusCode::CONFLICT);
+}
+
+#[test]
+fn draft_and_publish_documents() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "test.html" => "Testing 123",
+        "sidebar.html" => "Draft navigation"
+    };
+    let tokens = ApiTokens::default();
+    let (_, alice) = tokens.create("alice", "editor", Scope::Write, None).unwrap();
+    let accounts = Accounts::new(b"test key");
+    accounts.signup("alice", "password1").unwrap();
+    let mut service = TamaWiki::new(store, "public/dist")
+        .with_accounts(accounts)
+        .with_api_tokens(tokens)
+        .with_sidebar("sidebar.html")
+        .with_drafts();
+    let mut call = |rt: &mut Runtime, request: Request<Body>| {
+        let response = rt.block_on(service.call(request)).unwrap();
+        let status = response.status();
+        let body = rt.block_on(response.into_body().concat2()).unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    };
+    let get = |uri: &str, token: Option<&str>| {
+        let mut request = Request::get(uri);
+        if let Some(token) = token {
+            request.header("authorization", format!("Bearer {}", token).as_str());
+        }
+        request.body(Body::empty()).unwrap()
+    };
+    let post = |uri: &str, token: Option<&str>, body: &str| {
+        let mut request = Request::post(uri);
+        if let Some(token) = token {
+            request.header("authorization", format!("Bearer {}", token).as_str());
+        }
+        request.body(Body::from(String::from(body))).unwrap()
+    };
+
+    // documents which have never been published are only shown to
+    // editors
+    let (status, _) = call(&mut rt, get("/test.html", None));
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, body) = call(&mut rt, get("/test.html", Some(&alice)));
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("Testing 123"));
+    assert!(body.contains("draft-banner"));
+    assert!(body.contains("?action=publish"));
+
+    // anonymous readers may not publish, edit or see the history
+    let (status, _) = call(&mut rt, post("/test.html?action=publish", None, "seq=3"));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call(&mut rt, post("/test.html", None, "seq=3&content=Vandalised"));
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call(&mut rt, get("/test.html?action=history", None));
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(&mut rt, get("/test.html?action=edit", None));
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, _) = call(&mut rt, post("/test.html?action=publish", Some(&alice), "seq=3"));
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    let (status, body) = call(&mut rt, get("/test.html", None));
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("Testing 123"));
+    assert!(!body.contains("draft-banner"));
+
+    // later changes are drafts until they are published too
+    let edit = "seq=3&content=Draft+changes%0A%23+Secret%0A";
+    let (status, _) = call(&mut rt, post("/test.html", Some(&alice), edit));
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    let (_, body) = call(&mut rt, get("/test.html", None));
+    assert!(body.contains("Testing 123"));
+    assert!(!body.contains("Draft changes"));
+    // nor in the sidebar, embeds or recent changes
+    assert!(!body.contains("Draft navigation"));
+    let (status, _) = call(&mut rt, get("/_embed?url=%2Ftest.html%23secret", None));
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, body) = call(&mut rt, get("/_changes", None));
+    assert!(!body.contains("test.html"));
+    let request = Request::get("/test.html")
+        .header("accept", "application/json")
+        .body(Body::empty())
+        .unwrap();
+    let (status, body) = call(&mut rt, request);
+    assert_eq!(status, StatusCode::OK);
+    let state: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(state["content"], "Testing 123");
+    assert_eq!(state["seq"], 3);
+    let request = Request::get("/test.html")
+        .header("accept", "application/json")
+        .header("authorization", format!("Bearer {}", alice).as_str())
+        .body(Body::empty())
+        .unwrap();
+    let (_, body) = call(&mut rt, request);
+    let state: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(state["content"], "Draft changes\n# Secret\n");
+    let (_, body) = call(&mut rt, get("/test.html", Some(&alice)));
+    assert!(body.contains("Draft changes"));
+    assert!(body.contains("draft-banner"));
+    assert!(body.contains("Draft navigation"));
+    let (status, _) = call(&mut rt, get("/_embed?url=%2Ftest.html%23secret", Some(&alice)));
+    assert_eq!(status, StatusCode::OK);
+    let (_, body) = call(&mut rt, get("/_changes", Some(&alice)));
+    assert!(body.contains("test.html"));
+
+    // earlier revisions cannot be published over later ones
+    let (status, body) = call(&mut rt, post("/test.html?action=publish", Some(&alice), "seq=3"));
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(body.contains("PublishRejected"));
+    let seq = format!("seq={}", state["seq"]);
+    let (status, _) = call(&mut rt, post("/test.html?action=publish", Some(&alice), &seq));
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    let (_, body) = call(&mut rt, get("/test.html", None));
+    assert!(body.contains("Draft changes"));
+}
+
+#[test]
+fn configurable_reserved_prefix() {
+    let store = memorystore! {
+        "_notes" => "Underscore notes"
+    };
+    let mut service = TamaWiki::new(store, "public/dist").with_reserved_prefix("-/");
+
+    // documents may now start with an underscore
+    let request = Request::get("/_notes").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().concat2().wait().unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("Underscore notes"));
+    assert!(body.contains("href=\"/-/static/css/main.css?v="));
+
+    let request = Request::get("/-/health").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let request = Request::get("/_health").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // unknown routes under the prefix are never documents
+    let request = Request::get("/-/notes").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.into_body().concat2().wait().unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(!body.contains("Create this page"));
+
+    // nor are they when the prefix is percent-encoded
+    let request = Request::get("/%2D/notes").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn encoded_reserved_prefix_is_not_a_document() {
+    let store = memorystore! {
+        "_notes" => "Hidden notes"
+    };
+    let mut service = TamaWiki::new(store, "public/dist");
+
+    for uri in &["/%5Fnotes", "/%5fnotes", "/%5Fnotes?action=history"] {
+        let request = Request::get(*uri).body(Body::empty()).unwrap();
+        let response = service.call(request).wait().unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+    }
+    let request = Request::post("/%5Fnotes")
+        .body(Body::from("action=save&content=Replaced"))
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn basic_authentication() {
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let mut auth = BasicAuth::new();
+    auth.insert("alice", "secret");
+    let mut service = TamaWiki::new(store, "public/dist").with_basic_auth(auth);
+
+    let request = Request::get("/test.html").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        response.headers().get("www-authenticate").unwrap(),
+        "Basic realm=\"TamaWiki\", charset=\"UTF-8\""
+    );
+
+    // "alice:guess"
+    let request = Request::get("/test.html")
+        .header("authorization", "Basic YWxpY2U6Z3Vlc3M=")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // "alice:secret"
+    let request = Request::get("/test.html")
+        .header("authorization", "Basic YWxpY2U6c2VjcmV0")
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // load balancers can check the server's health without signing in
+    let request = Request::get("/_health").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[test]
+fn user_accounts() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let writer = store.clone();
+    let mut service =
+        TamaWiki::new(store, "public/dist").with_accounts(Accounts::new(b"test key"));
+
+    let form = |route: &str, body: &str| {
+        Request::post(route)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(Body::from(String::from(body)))
+            .unwrap()
+    };
+
+    let request = Request::get("/_signup").body(Body::empty()).unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = rt
+        .block_on(service.call(form("/_signup", "username=alice&password=short")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("Passwords must be at least 8 characters long"));
+
+    let response = rt
+        .block_on(service.call(form("/_signup", "username=alice&password=password1")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+    let response = rt
+        .block_on(service.call(form("/_login", "username=alice&password=wrong")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = rt
+        .block_on(service.call(form("/_login", "username=alice&password=password1")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let cookie = response.headers().get("set-cookie").unwrap().to_str().unwrap();
+    assert!(cookie.contains("HttpOnly"));
+    assert!(!cookie.contains("Secure"));
+    let session = String::from(cookie.split(';').next().unwrap());
+
+    // pages show who is logged in
+    let request = Request::get("/test.html")
+        .header("cookie", session.as_str())
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("Logged in as alice"));
+    let csrf_token = csrf_token(&body);
+
+    // editing requires logging in
+    let response = rt
+        .block_on(service.call(form("/test.html", "seq=3&content=Testing+1234")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // edits are recorded on behalf of the logged in user
+    let body = format!("seq=3&content=Testing+1234&csrf_token={}", csrf_token);
+    let mut request = form("/test.html", &body);
+    request
+        .headers_mut()
+        .insert("cookie", session.parse().unwrap());
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let events = rt
+        .block_on(
+            writer
+                .since(&PathBuf::from("test.html"), 3, None)
+                .flatten_stream()
+                .collect(),
+        ).unwrap();
+    match events[0] {
+        (4, Event::Join(ref join)) => assert_eq!(join.user, Some(String::from("alice"))),
+        ref event => panic!("expected Join, got {:?}", event),
+    }
+
+    let response = rt
+        .block_on(service.call(form("/_logout", "")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let cookie = response.headers().get("set-cookie").unwrap().to_str().unwrap();
+    assert!(cookie.contains("Max-Age=0"));
+
+    // logging out ends the session, even if the cookie is kept
+    let mut request = form("/_logout", &format!("csrf_token={}", csrf_token));
+    request
+        .headers_mut()
+        .insert("cookie", session.parse().unwrap());
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let request = Request::get("/test.html")
+        .header("cookie", session.as_str())
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(!body.contains("Logged in as alice"));
+    let response = rt
+        .block_on(service.call(form("/_login", "username=alice&password=password1")))
+        .unwrap();
+    let cookie = response.headers().get("set-cookie").unwrap().to_str().unwrap();
+    let request = Request::get("/test.html")
+        .header("cookie", cookie.split(';').next().unwrap())
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("Logged in as alice"));
+
+    // wikis served over HTTPS keep session cookies off plain HTTP
+    let accounts = Accounts::new(b"test key").with_secure_cookies(true);
+    let mut service = TamaWiki::new(writer, "public/dist").with_accounts(accounts);
+    let response = rt
+        .block_on(service.call(form("/_signup", "username=bob&password=password1")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let cookie = response.headers().get("set-cookie").unwrap().to_str().unwrap();
+    assert!(cookie.ends_with("; Secure"));
+}
+
+#[test]
+fn oidc_login() {
+    let provider = OidcProvider::new(
+        "https://id.example.com",
+        "https://id.example.com/authorize",
+        "https://id.example.com/token",
+        "wiki",
+        "secret",
+        "https://wiki.example.com/_oidc/callback",
+    );
+    let mut service = TamaWiki::new(MemoryStore::default(), "public/dist")
+        .with_accounts(Accounts::new(b"test key"))
+        .with_oidc(provider);
+
+    let request = Request::get("/_login").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    let body = response.into_body().concat2().wait().unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("Log in with single sign-on"));
+
+    let request = Request::get("/_oidc/login").body(Body::empty()).unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::FOUND);
+    let location = response.headers().get("location").unwrap().to_str().unwrap();
+    assert!(location.starts_with("https://id.example.com/authorize?response_type=code"));
+    let cookie = response.headers().get("set-cookie").unwrap().to_str().unwrap();
+    assert!(cookie.starts_with("tamawiki_oidc="));
+    assert!(cookie.contains("Path=/_oidc/"));
+
+    // the callback must return the state stored in the cookie
+    let session = String::from(cookie.split(';').next().unwrap());
+    let request = Request::get("/_oidc/callback?state=forged&code=abc")
+        .header("cookie", session.as_str())
+        .body(Body::empty())
+        .unwrap();
+    let response = service.call(request).wait().unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn ldap_login_fails_closed_without_the_directory() {
+    // nothing listens on this port, so the directory cannot be reached
+    let directory = Directory::new("ldap://127.0.0.1:1", "dc=example,dc=com");
+    let accounts = Accounts::new(b"test key");
+    accounts.signup("alice", "password1").unwrap();
+    let mut service = TamaWiki::new(MemoryStore::default(), "public/dist")
+        .with_accounts(accounts)
+        .with_ldap(directory);
+
+    let form = |route: &str, body: &str| {
+        Request::post(route)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(Body::from(String::from(body)))
+            .unwrap()
+    };
+    // the directory may have a user with the same username, so
+    // local accounts can neither be created nor logged in to
+    let response = service
+        .call(form("/_signup", "username=bob&password=password1"))
+        .wait()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let response = service
+        .call(form("/_login", "username=alice&password=password1"))
+        .wait()
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[test]
+fn access_control_lists() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "index.html" => "Welcome",
+        "private/plans.html" => "Secret plans"
+    };
+    let acl = Acl::parse(
+        "group:staff = alice
+         /** -> * (read, write)
+         /private/** -> group:staff (read, write)",
+    ).unwrap();
+    let mut auth = BasicAuth::new();
+    auth.insert("alice", "secret");
+    auth.insert("bob", "secret");
+    let mut service = TamaWiki::new(store, "public/dist")
+        .with_basic_auth(auth)
+        .with_acl(acl);
+
+    // "alice:secret" and "bob:secret"
+    let alice = "Basic YWxpY2U6c2VjcmV0";
+    let bob = "Basic Ym9iOnNlY3JldA==";
+    let get = |uri: &str, credentials: &str| {
+        Request::get(uri)
+            .header("authorization", credentials)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = rt
+        .block_on(service.call(get("/private/plans.html", bob)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = rt
+        .block_on(service.call(get("/private/plans.html?action=history", bob)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = rt
+        .block_on(service.call(get("/private/plans.html", alice)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = rt
+        .block_on(service.call(get("/index.html", bob)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let request = Request::post("/private/plans.html")
+        .header("authorization", bob)
+        .body(Body::from("seq=3&content=Not+so+secret"))
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // recent changes only list documents the user may view
+    let changes = |service: &mut TamaWiki<MemoryStore>, rt: &mut Runtime, credentials| {
+        let response = rt
+            .block_on(service.call(get("/_changes", credentials)))
+            .unwrap();
+        let body = rt.block_on(response.into_body().concat2()).unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+    assert!(!changes(&mut service, &mut rt, bob).contains("plans.html"));
+    assert!(changes(&mut service, &mut rt, alice).contains("plans.html"));
+}
+
+// Returns the CSRF token from a page's csrf-token meta tag
+fn csrf_token(page: &str) -> String {
+    let attribute = "name=\"csrf-token\" content=\"";
+    let start = page.find(attribute).unwrap() + attribute.len();
+    let end = start + page[start..].find('"').unwrap();
+    String::from(&page[start..end])
+}
+
+#[test]
+fn csrf_protection() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let accounts = Accounts::new(b"test key");
+    accounts.signup("alice", "password1").unwrap();
+    let session = format!(
+        "tamawiki_session={}",
+        accounts.login("alice", "password1").unwrap()
+    );
+    let mut service = TamaWiki::new(store, "public/dist").with_accounts(accounts);
+
+    let request = Request::get("/test.html?action=edit")
+        .header("cookie", session.as_str())
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    let token = csrf_token(&body);
+    assert!(body.contains(&format!("name=\"csrf_token\" value=\"{}\"", token)));
+
+    let post = |uri: &str, body: String| {
+        Request::post(uri)
+            .header("cookie", session.as_str())
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    // forms posted with a session cookie must include the token
+    let response = rt
+        .block_on(service.call(post("/test.html", String::from("seq=3&content=Forged"))))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let forged = String::from("seq=3&content=Forged&csrf_token=forged");
+    let response = rt.block_on(service.call(post("/test.html", forged))).unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = rt
+        .block_on(service.call(post("/_logout", String::new())))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let body = format!("seq=3&content=Testing+1234&csrf_token={}", token);
+    let response = rt.block_on(service.call(post("/test.html", body))).unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+    // scripts may send the token in a header instead
+    let mut request = post("/test.html?action=restore&rev=3", String::new());
+    request
+        .headers_mut()
+        .insert("x-csrf-token", token.parse().unwrap());
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+}
+
+#[test]
+fn rate_limiting() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let mut service = TamaWiki::new(store, "public/dist")
+        .with_rate_limit(RateLimiter::new(2, Duration::from_secs(60)))
+        .with_remote_addr(([192, 0, 2, 1], 5000).into());
+
+    let post = |seq: usize| {
+        Request::post("/test.html")
+            .body(Body::from(format!("seq={}&content=Edit+{}", seq, seq)))
+            .unwrap()
+    };
+    for seq in 3..5 {
+        let response = rt.block_on(service.call(post(seq))).unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    }
+    let response = rt.block_on(service.call(post(5))).unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "30");
+
+    // viewing documents is not limited
+    let request = Request::get("/test.html").body(Body::empty()).unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // other clients have their own limit
+    let mut other = service.clone().with_remote_addr(([192, 0, 2, 2], 5000).into());
+    let response = rt.block_on(other.call(post(5))).unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+}
+
+#[test]
+fn warm_up_recently_changed_documents() {
+    let mut store = memorystore! {
+        "a.html" => "A"
+    };
+    for (id, path) in ["b.html", "a.html", "c.html", "a.html"].iter().enumerate() {
+        let join = Event::Join(Join {
+            id: id + 2,
+            role: Role::Editor,
+            user: None,
+        });
+        store.push(PathBuf::from(path), join).wait().unwrap();
+    }
+    let service = TamaWiki::new(store, "public/dist");
+    // each document is only counted once
+    assert_eq!(service.warm_up(2).wait(), Ok(2));
+    assert_eq!(service.warm_up(10).wait(), Ok(3));
+}
+
+#[test]
+fn watch_pages() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let accounts = Accounts::new(b"test key");
+    accounts.signup("alice", "password1").unwrap();
+    let session = format!(
+        "tamawiki_session={}",
+        accounts.login("alice", "password1").unwrap()
+    );
+    let watchlists = Watchlists::default();
+    let mut service = TamaWiki::new(store, "public/dist")
+        .with_accounts(accounts)
+        .with_watchlists(watchlists.clone());
+
+    // only logged in users have a watchlist
+    let request = Request::get("/_watchlist").body(Body::empty()).unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let get = |rt: &mut Runtime, service: &mut TamaWiki<MemoryStore>, uri: &str| {
+        let request = Request::get(uri)
+            .header("cookie", session.as_str())
+            .body(Body::empty())
+            .unwrap();
+        let response = rt.block_on(service.call(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = rt.block_on(response.into_body().concat2()).unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+    let body = get(&mut rt, &mut service, "/test.html");
+    assert!(body.contains("value=\"watch\""));
+    let token = csrf_token(&body);
+
+    let post = |body: &str| {
+        Request::post("/_watchlist")
+            .header("cookie", session.as_str())
+            .header("referer", "http://localhost/test.html")
+            .body(Body::from(format!("{}&csrf_token={}", body, token)))
+            .unwrap()
+    };
+    let response = rt
+        .block_on(service.call(post("action=watch&page=test.html")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(response.headers()["location"], "/test.html");
+    let response = rt
+        .block_on(service.call(post("action=settings&email=alice&frequency=daily")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let settings = "action=settings&email=alice%40example.com&frequency=hourly";
+    let response = rt.block_on(service.call(post(settings))).unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(response.headers()["location"], "/_watchlist");
+
+    let watcher = watchlists.get("alice");
+    assert_eq!(watcher.email, Some(String::from("alice@example.com")));
+    assert_eq!(watcher.frequency, Frequency::Hourly);
+    assert!(get(&mut rt, &mut service, "/test.html").contains("value=\"unwatch\""));
+    let body = get(&mut rt, &mut service, "/_watchlist");
+    assert!(body.contains("<a href=\"&#x2F;test.html\">test.html</a>"));
+    assert!(body.contains("<option value=\"hourly\" selected>"));
+}
+
+#[test]
+fn api_tokens() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "test.html" => "Testing 123"
+    };
+    let writer = store.clone();
+    let accounts = Accounts::new(b"test key");
+    accounts.signup("alice", "password1").unwrap();
+    let session = format!(
+        "tamawiki_session={}",
+        accounts.login("alice", "password1").unwrap()
+    );
+    let mut service = TamaWiki::new(store, "public/dist")
+        .with_accounts(accounts)
+        .with_api_tokens(ApiTokens::default());
+
+    let request = Request::get("/_tokens").body(Body::empty()).unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let request = Request::get("/_tokens")
+        .header("cookie", session.as_str())
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let csrf = csrf_token(std::str::from_utf8(&body).unwrap());
+
+    let post = |body: String| {
+        Request::post("/_tokens")
+            .header("cookie", session.as_str())
+            .body(Body::from(format!("{}&csrf_token={}", body, csrf)))
+            .unwrap()
+    };
+    // the token is shown once, when it is created
+    let create = |rt: &mut Runtime, service: &mut TamaWiki<MemoryStore>, scope: &str| {
+        let body = format!("action=create&name=ci&scope={}", scope);
+        let response = rt.block_on(service.call(post(body))).unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = rt.block_on(response.into_body().concat2()).unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let start = body.find("<pre class=\"token\">").unwrap() + "<pre class=\"token\">".len();
+        let end = start + body[start..].find('<').unwrap();
+        String::from(&body[start..end])
+    };
+    let reader = create(&mut rt, &mut service, "read");
+    let editor = create(&mut rt, &mut service, "write");
+
+    let save = |token: &str| {
+        Request::post("/test.html")
+            .header("authorization", format!("Bearer {}", token).as_str())
+            .body(Body::from("seq=3&content=Edited"))
+            .unwrap()
+    };
+    let response = rt.block_on(service.call(save(&reader))).unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = rt.block_on(service.call(save(&editor))).unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let (_, doc) = rt.block_on(writer.content(&PathBuf::from("test.html"))).unwrap();
+    assert_eq!(doc.content, "Edited");
+
+    // only admin tokens may manage tokens
+    let request = Request::get("/_tokens")
+        .header("authorization", format!("Bearer {}", editor).as_str())
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // the token's id follows its prefix
+    let id = reader["tw_".len()..].split('_').next().unwrap();
+    let response = rt
+        .block_on(service.call(post(format!("action=revoke&id={}", id))))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let request = Request::get("/test.html")
+        .header("authorization", format!("Bearer {}", reader).as_str())
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn storage_quotas() {
+    // attachments are written on the blocking thread pool
+    let mut rt = tokio::runtime::Runtime::new().expect("new test runtime");
+    let root = std::env::temp_dir().join(format!("tamawiki-quotas-{}", std::process::id()));
+    let store = memorystore! {
+        "a.html" => "Apples",
+        "b.html" => "Bananas"
+    };
+    let reader = store.clone();
+    let tokens = ApiTokens::default();
+    let (_, alice) = tokens.create("alice", "ci", Scope::Write, None).unwrap();
+    let (_, bob) = tokens.create("bob", "ci", Scope::Write, None).unwrap();
+    let accounts = Accounts::new(b"test key");
+    accounts.signup("alice", "password1").unwrap();
+    accounts.signup("bob", "password1").unwrap();
+    let mut service = TamaWiki::new(store, "public/dist")
+        .with_accounts(accounts)
+        .with_api_tokens(tokens)
+        .with_attachments(root.clone())
+        .with_quotas(Quotas::new(10).with_limit("bob", 100));
+    let mut call = |rt: &mut tokio::runtime::Runtime, request: Request<Body>| {
+        let response = rt.block_on(service.call(request)).unwrap();
+        let status = response.status();
+        let body = rt.block_on(response.into_body().concat2()).unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    };
+    let post = |uri: &str, token: &str, body: &str| {
+        Request::post(uri)
+            .header("authorization", format!("Bearer {}", token).as_str())
+            .body(Body::from(String::from(body)))
+            .unwrap()
+    };
+    let usage = |token: &str| {
+        Request::get("/_quota")
+            .header("authorization", format!("Bearer {}", token).as_str())
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let (status, _) = call(&mut rt, Request::get("/_quota").body(Body::empty()).unwrap());
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // anonymous uploads would count towards nobody's quota
+    let request = Request::post("/_attachments/cat.txt")
+        .body(Body::from("a cat"))
+        .unwrap();
+    let (status, _) = call(&mut rt, request);
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(!root.join("cat.txt").exists());
+    let (status, body) = call(&mut rt, usage(&alice));
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body, serde_json::json!({"user": "alice", "used": 0, "limit": 10}));
+
+    // edits count the bytes they insert
+    let (status, _) = call(&mut rt, post("/a.html", &alice, "seq=3&content=Green+Apples"));
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    let (_, body) = call(&mut rt, usage(&alice));
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["used"], 6);
+
+    // and are refused once they would exceed the quota
+    let (status, body) = call(&mut rt, post("/b.html", &alice, "seq=3&content=Yellow+Bananas"));
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["code"], "QuotaExceeded");
+    let (status, _) = call(&mut rt, post("/_attachments/cat.txt", &alice, "a cat"));
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let (_, doc) = rt.block_on(reader.content(&PathBuf::from("b.html"))).unwrap();
+    assert_eq!(doc.content, "Bananas");
+
+    // other users have quotas of their own
+    let (status, _) = call(&mut rt, post("/b.html", &bob, "seq=3&content=Yellow+Bananas"));
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    let (status, _) = call(&mut rt, post("/_attachments/cat.txt", &bob, "a cat"));
+    assert_eq!(status, StatusCode::CREATED);
+    let (_, body) = call(&mut rt, usage(&bob));
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body, serde_json::json!({"user": "bob", "used": 12, "limit": 100}));
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn embed_pages() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "guide.html" => "---\ntitle: Guide\n---\nIntro\n# Setup\nInstall it\n# Usage\nRun it\n"
+    };
+    let mut service = TamaWiki::new(store, "public/dist").with_site_name("Docs");
+
+    let oembed = |url: &str| {
+        Request::get(format!("/_embed?url={}&maxwidth=320", url).as_str())
+            .header("host", "wiki.example.com")
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = rt
+        .block_on(service.call(oembed("https%3A%2F%2Fwiki.example.com%2Fguide.html%23setup")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("\"title\":\"Guide\""));
+    assert!(body.contains("\"provider_name\":\"Docs\""));
+    assert!(body.contains("src=\\\"https://wiki.example.com/_embed/guide.html?section=setup\\\""));
+    assert!(body.contains("\"width\":320"));
+
+    // other sites' pages, missing pages and missing sections
+    for url in &[
+        "https%3A%2F%2Fother.example.com%2Fguide.html",
+        "https%3A%2F%2Fwiki.example.com%2Fmissing.html",
+        "https%3A%2F%2Fwiki.example.com%2Fguide.html%23faq",
+    ] {
+        let response = rt.block_on(service.call(oembed(url))).unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    let request = Request::get("/_embed/guide.html?section=setup")
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("# Setup\nInstall it\n</pre>"));
+    assert!(!body.contains("Run it"));
+    assert!(body.contains("<a href=\"&#x2F;guide.html#setup\">View on Docs</a>"));
+}
+
+#[test]
+fn crawl_controls() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "index.html" => "Welcome",
+        "drafts/plans.html" => "Plans"
+    };
+    let policy = CrawlPolicy::default().with_noindex("/drafts/**");
+    let mut service = TamaWiki::new(store, "public/dist").with_crawl_policy(policy);
+
+    let get = |rt: &mut Runtime, service: &mut TamaWiki<MemoryStore>, uri: &str| {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = rt.block_on(service.call(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let noindex = response.headers().get("x-robots-tag").is_some();
+        let body = rt.block_on(response.into_body().concat2()).unwrap();
+        (noindex, String::from_utf8(body.to_vec()).unwrap())
+    };
+    let (_, robots) = get(&mut rt, &mut service, "/robots.txt");
+    assert!(robots.starts_with("User-agent: *\n"));
+    assert!(robots.contains("Disallow: /_\n"));
+
+    let (noindex, body) = get(&mut rt, &mut service, "/index.html");
+    assert!(!noindex);
+    assert!(!body.contains("<meta name=\"robots\""));
+    let (noindex, body) = get(&mut rt, &mut service, "/drafts/plans.html");
+    assert!(noindex);
+    assert!(body.contains("<meta name=\"robots\" content=\"noindex\">"));
+    let (noindex, _) = get(&mut rt, &mut service, "/_embed/drafts/plans.html");
+    assert!(noindex);
+}
+
+#[test]
+fn search_documents() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "index.html" => "Welcome to the wiki",
+        "guides/setup.html" => "Install the wiki",
+        "guides/usage.html" => "Edit pages"
+    };
+    let index = MemoryIndex::default();
+    let indexer = Indexer::new(store.clone(), index.clone());
+    assert_eq!(rt.block_on(indexer.update()).unwrap(), 3);
+    let mut service = TamaWiki::new(store, "public/dist").with_search(index);
+
+    let request = Request::get("/_search?q=wiki").body(Body::empty()).unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("2 matching documents"));
+    assert!(body.contains("<a href=\"&#x2F;guides/setup.html\">setup</a>"));
+    assert!(body.contains("<p>Install the wiki</p>"));
+
+    let request = Request::get("/_search?q=wiki&dir=guides")
+        .header("accept", "application/json")
+        .body(Body::empty())
+        .unwrap();
+    let response = rt.block_on(service.call(request)).unwrap();
+    let body = rt.block_on(response.into_body().concat2()).unwrap();
+    let results: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(results["total"], 1);
+    assert_eq!(results["hits"][0]["path"], "guides/setup.html");
+    assert_eq!(results["directories"], serde_json::json!({"guides": 1}));
+}
@@ -0,0 +1,414 @@
+//! Simulates several clients editing the same document at once over
+//! real websocket connections to an in-process server, checking they
+//! all end up with the same content as the store.
+//!
+//! Each client follows the editing protocol: it keeps at most one
+//! ClientEdit waiting to be acknowledged, collects further local
+//! operations in an outbox, and transforms incoming events against
+//! both. Random delays are added to every message a client sends or
+//! receives. A websocket keeps the messages on a single connection in
+//! order, so the delays reorder edits between clients rather than
+//! within one.
+#[macro_use]
+extern crate tamawiki;
+#[macro_use]
+extern crate serde_json;
+extern crate futures;
+extern crate rand;
+extern crate tokio;
+extern crate tokio_tungstenite;
+extern crate url;
+
+use futures::future::{self, Future, Loop};
+use futures::sink::Sink;
+use futures::stream::{self, Stream};
+use futures::sync::mpsc::{self, UnboundedSender};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Delay;
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+use tamawiki::document::{
+    Delete, Document, DocumentParticipant, Edit, Event, Insert, Operation, ParticipantId,
+};
+use tamawiki::store::memory::MemoryStore;
+use tamawiki::store::{SequenceId, Store};
+use tamawiki::testing::TestServer;
+use tamawiki::TamaWiki;
+
+// The longest delay added to each message, in milliseconds
+const MAX_LATENCY: u64 = 20;
+
+// The longest pause between a client's edits, in milliseconds
+const MAX_THINK_TIME: u64 = 30;
+
+// How many times to check whether the clients have settled before
+// giving up
+const MAX_ROUNDS: usize = 200;
+
+// Characters random edits insert, including some outside the Basic
+// Multilingual Plane
+const ALPHABET: &[char] = &['a', 'b', 'c', ' ', '\n', 'é', 'ß', '🐈', '🦀'];
+
+/// An edit a client makes to its copy of the document
+#[derive(Debug, Clone)]
+enum Step {
+    /// Inserts the text at the position, or at the end of the
+    /// document if it is shorter
+    Insert(usize, &'static str),
+    /// Deletes the range, limited to the end of the document
+    Delete(usize, usize),
+    /// Makes a random insert or delete
+    Random,
+}
+
+impl Step {
+    // The operation making this step to 'content', or None if it
+    // would not change anything
+    fn operation<R: Rng>(&self, content: &str, rng: &mut R) -> Option<Operation> {
+        let length = content.chars().count();
+        match *self {
+            Step::Insert(pos, text) => Some(Operation::Insert(Insert {
+                pos: pos.min(length),
+                content: String::from(text),
+            })),
+            Step::Delete(start, end) if start.min(length) < end.min(length) => {
+                Some(Operation::Delete(Delete {
+                    start,
+                    end: end.min(length),
+                }))
+            }
+            Step::Delete(_, _) => None,
+            Step::Random if length > 0 && rng.gen_bool(0.4) => {
+                let start = rng.gen_range(0, length);
+                let end = rng.gen_range(start + 1, (start + 5).min(length) + 1);
+                Some(Operation::Delete(Delete { start, end }))
+            }
+            Step::Random => {
+                let size = rng.gen_range(1, 4);
+                Some(Operation::Insert(Insert {
+                    pos: rng.gen_range(0, length + 1),
+                    content: (0..size).map(|_| *rng.choose(ALPHABET).unwrap()).collect(),
+                }))
+            }
+        }
+    }
+}
+
+// A simulated editor, which is shared between the tasks sending and
+// receiving its messages
+struct Client {
+    id: ParticipantId,
+    // the last server SequenceId received
+    seq: SequenceId,
+    // the last local SequenceId sent
+    client_seq: SequenceId,
+    document: Document,
+    // the ClientEdit waiting to be acknowledged, and its client_seq
+    sent: Option<(SequenceId, Event)>,
+    // operations made since 'sent', not yet sent to the server
+    outbox: Vec<Operation>,
+    outgoing: UnboundedSender<Message>,
+    rng: StdRng,
+}
+
+impl Client {
+    fn new(
+        seq: SequenceId,
+        document: Document,
+        outgoing: UnboundedSender<Message>,
+        seed: [u8; 32],
+    ) -> Self {
+        Client {
+            id: 0,
+            seq,
+            client_seq: 0,
+            document,
+            sent: None,
+            outbox: Vec::new(),
+            outgoing,
+            rng: StdRng::from_seed(seed),
+        }
+    }
+
+    fn latency(&mut self) -> Duration {
+        Duration::from_millis(self.rng.gen_range(0, MAX_LATENCY + 1))
+    }
+
+    fn think_time(&mut self) -> Duration {
+        Duration::from_millis(self.rng.gen_range(0, MAX_THINK_TIME + 1))
+    }
+
+    // True once every local edit has been acknowledged
+    fn idle(&self) -> bool {
+        self.sent.is_none() && self.outbox.is_empty()
+    }
+
+    // Makes an edit locally, then sends it if no other edit is
+    // waiting to be acknowledged
+    fn edit(&mut self, step: &Step) {
+        let op = match step.operation(&self.document.content, &mut self.rng) {
+            Some(op) => op,
+            None => return,
+        };
+        self.document
+            .apply(&Event::Edit(Edit {
+                author: self.id,
+                operations: vec![op.clone()],
+            })).expect("local edit must apply");
+        self.outbox.push(op);
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        if self.sent.is_some() || self.outbox.is_empty() {
+            return;
+        }
+        let operations: Vec<Operation> = self.outbox.drain(..).collect();
+        self.client_seq += 1;
+        let msg = json!({
+            "ClientEdit": {
+                "client_seq": self.client_seq,
+                "parent_seq": self.seq,
+                "operations": operations
+            }
+        });
+        self.outgoing
+            .unbounded_send(Message::text(msg.to_string()))
+            .expect("send client edit");
+        self.sent = Some((
+            self.client_seq,
+            Event::Edit(Edit {
+                author: self.id,
+                operations,
+            }),
+        ));
+    }
+
+    fn receive(&mut self, text: &str) {
+        let msg: Value = serde_json::from_str(text).unwrap();
+        if let Some(connected) = msg.get("Connected") {
+            self.id = connected["id"].as_u64().unwrap() as ParticipantId;
+            self.document
+                .participants
+                .entries
+                .insert(self.id, DocumentParticipant::default());
+        } else if let Some(event) = msg.get("Event") {
+            self.event(event);
+        } else if let Some(events) = msg.get("Events") {
+            for event in events.as_array().unwrap() {
+                self.event(event);
+            }
+        } else {
+            panic!("client {} received unexpected message: {}", self.id, text);
+        }
+    }
+
+    fn event(&mut self, msg: &Value) {
+        let acknowledged = msg["client_seq"].as_u64().unwrap();
+        self.seq = msg["seq"].as_u64().unwrap();
+        if let Some((client_seq, _)) = self.sent {
+            if client_seq <= acknowledged {
+                self.sent = None;
+            }
+        }
+        let mut event: Event = serde_json::from_value(msg["event"].clone()).unwrap();
+        if let Some((_, ref mut sent)) = self.sent {
+            // the server will transform the sent edit in the same way
+            let concurrent = event.clone();
+            event.transform(sent);
+            sent.transform(&concurrent);
+        }
+        if !self.outbox.is_empty() {
+            let mut local = Event::Edit(Edit {
+                author: self.id,
+                operations: self.outbox.drain(..).collect(),
+            });
+            let concurrent = event.clone();
+            event.transform(&local);
+            local.transform(&concurrent);
+            if let Event::Edit(Edit { operations, .. }) = local {
+                self.outbox = operations;
+            }
+        }
+        if let Err(err) = self.document.apply(&event) {
+            panic!("client {} could not apply {:?}: {}", self.id, event, err);
+        }
+        self.flush();
+    }
+}
+
+fn delay(duration: Duration) -> impl Future<Item = (), Error = ()> {
+    Delay::new(Instant::now() + duration).map_err(|err| panic!("Timer error: {}", err))
+}
+
+// Connects a client to the document, returning once it has been
+// told its participant id. Messages it sends and receives are
+// delayed by a random latency.
+fn connect(
+    rt: &mut Runtime,
+    url: &str,
+    seq: SequenceId,
+    document: Document,
+    seed: [u8; 32],
+) -> Rc<RefCell<Client>> {
+    let url = Url::parse(&format!("{}?seq={}", url, seq)).unwrap();
+    let ws = rt
+        .block_on(tokio_tungstenite::connect_async(url).map(|(ws, _)| ws))
+        .expect("Could not establish websocket connection");
+    let (sink, stream) = ws.split();
+    let (tx, rx) = mpsc::unbounded();
+    let client = Rc::new(RefCell::new(Client::new(seq, document, tx, seed)));
+
+    let c = client.clone();
+    rt.spawn(
+        rx.and_then(move |msg| {
+            let latency = c.borrow_mut().latency();
+            delay(latency).map(|_| msg)
+        }).forward(sink.sink_map_err(|err| -> () { panic!("Websocket error: {}", err) }))
+        .map(|_| ()),
+    );
+
+    let (first, stream) = rt
+        .block_on(stream.into_future().map_err(|(err, _)| err))
+        .expect("read connected message");
+    client
+        .borrow_mut()
+        .receive(&first.unwrap().into_text().unwrap());
+
+    let c = client.clone();
+    rt.spawn(
+        stream
+            .map_err(|err| panic!("Websocket error: {}", err))
+            .for_each(move |msg| {
+                let c = c.clone();
+                let latency = c.borrow_mut().latency();
+                delay(latency).map(move |_| {
+                    if let Message::Text(text) = msg {
+                        c.borrow_mut().receive(&text);
+                    }
+                })
+            }),
+    );
+    client
+}
+
+// Runs each script as a separate client editing a document which
+// starts with 'content'. Returns the content of the document in the
+// store and of each client's copy once every edit has been
+// acknowledged and received by all clients.
+fn simulate(content: &str, scripts: Vec<Vec<Step>>, seed: u8) -> (String, Vec<String>) {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let store = memorystore! {
+        "index.html" => content
+    };
+    let path = PathBuf::from("index.html");
+
+    let server = TestServer::start(TamaWiki::new(store.clone(), "public/dist"));
+    let url = server.ws_url("index.html");
+
+    let clients: Vec<_> = (0..scripts.len())
+        .map(|i| {
+            let (seq, document) = rt.block_on(store.content(&path)).unwrap();
+            let mut seed = [seed; 32];
+            seed[0] = i as u8;
+            connect(&mut rt, &url, seq, document, seed)
+        }).collect();
+
+    let editors = clients.iter().zip(scripts).map(|(client, script)| {
+        let client = client.clone();
+        stream::iter_ok(script).for_each(move |step| {
+            let client = client.clone();
+            let think_time = client.borrow_mut().think_time();
+            delay(think_time).map(move |_| client.borrow_mut().edit(&step))
+        })
+    });
+    rt.block_on(future::join_all(editors)).unwrap();
+
+    // Edits are only acknowledged by later events, so a client
+    // joining and leaving the document prompts the server to
+    // acknowledge any remaining edits, until every client has made
+    // all of its edits and received every event.
+    let settled = {
+        let clients = clients.clone();
+        let store = store.clone();
+        let path = path.clone();
+        future::loop_fn(0, move |round| {
+            if round == MAX_ROUNDS {
+                panic!("Clients did not settle after {} rounds", MAX_ROUNDS);
+            }
+            let clients = clients.clone();
+            let (store, path) = (store.clone(), path.clone());
+            let ping = Url::parse(&url).unwrap();
+            delay(Duration::from_millis(50))
+                .and_then(move |_| store.seq(&path).map_err(|err| panic!("Store error: {}", err)))
+                .and_then(move |head| {
+                    let settled = clients.iter().all(|client| {
+                        let client = client.borrow();
+                        client.idle() && client.seq == head
+                    });
+                    if settled {
+                        return future::Either::A(future::ok(Loop::Break(())));
+                    }
+                    future::Either::B(
+                        tokio_tungstenite::connect_async(ping)
+                            .and_then(|(ws, _)| ws.into_future().map_err(|(err, _)| err))
+                            .map(move |_| Loop::Continue(round + 1))
+                            .map_err(|err| panic!("Websocket error: {}", err)),
+                    )
+                })
+        })
+    };
+    rt.block_on(settled).unwrap();
+
+    let (_, document) = rt.block_on(store.content(&path)).unwrap();
+    let copies = clients
+        .iter()
+        .map(|client| client.borrow().document.content.clone())
+        .collect();
+    (document.content, copies)
+}
+
+fn assert_converged(content: &str, copies: &[String], seed: u8) {
+    for (i, copy) in copies.iter().enumerate() {
+        assert_eq!(
+            copy, content,
+            "client {} diverged from the store (seed {})",
+            i, seed
+        );
+    }
+}
+
+#[test]
+fn scripted_clients_converge() {
+    // every client edits around the start of the document at once
+    let scripts = vec![
+        vec![Step::Insert(0, "one "), Step::Delete(0, 3), Step::Insert(100, "!")],
+        vec![Step::Insert(0, "two "), Step::Insert(5, "é"), Step::Delete(2, 8)],
+        vec![Step::Delete(0, 5), Step::Insert(3, "three"), Step::Insert(0, "🐈")],
+    ];
+    let (content, copies) = simulate("Hello, world\n", scripts, 0);
+    assert_converged(&content, &copies, 0);
+}
+
+#[test]
+fn random_clients_converge() {
+    for seed in 1..4 {
+        let scripts = vec![vec![Step::Random; 20]; 5];
+        // each simulation gets a thread of its own, since participants
+        // dropped along with a runtime leave the thread's default
+        // executor marked as in use, so no other runtime can start
+        let (content, copies) = thread::spawn(move || simulate("Hello, world\n", scripts, seed))
+            .join()
+            .unwrap();
+        assert_converged(&content, &copies, seed);
+    }
+}
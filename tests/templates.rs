@@ -0,0 +1,138 @@
+//! Renders pages through the service and compares them with the
+//! HTML fixtures in tests/golden, so changes to the templates or the
+//! context the service gives them show up as a diff.
+//!
+//! When a page is meant to change, or a new test is added, run the
+//! tests with TAMAWIKI_UPDATE_GOLDEN=1 to write the fixtures, and
+//! check them in. A missing fixture fails the test otherwise.
+#[macro_use]
+extern crate tamawiki;
+extern crate futures;
+extern crate http;
+extern crate hyper;
+
+use futures::future::Future;
+use futures::stream::Stream;
+use http::{Request, StatusCode};
+use hyper::service::Service;
+use hyper::Body;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tamawiki::acl::Acl;
+use tamawiki::store::memory::MemoryStore;
+use tamawiki::TamaWiki;
+
+// Set to rewrite the fixtures from the rendered pages
+const UPDATE_VAR: &str = "TAMAWIKI_UPDATE_GOLDEN";
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+// A wiki serving the fixture static files, so asset fingerprints do
+// not change when the frontend is rebuilt
+fn wiki() -> TamaWiki<MemoryStore> {
+    let store = memorystore! {
+        "index.html" => "Welcome to TamaWiki.\n\nSee [[attachment:cat.png]]\n",
+        "private/plans.html" => "Secret plans"
+    };
+    TamaWiki::new(store, golden_dir().join("static"))
+}
+
+fn get(mut service: TamaWiki<MemoryStore>, uri: &str) -> (StatusCode, String) {
+    let request = Request::get(uri).body(Body::from("")).unwrap();
+    let response = service.call(request).wait().unwrap();
+    let status = response.status();
+    let body = response.into_body().concat2().wait().unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+// Compares 'html' with the fixture 'name', writing the fixture
+// instead if updates were asked for
+fn assert_golden(name: &str, html: &str) {
+    let path = golden_dir().join(name);
+    if env::var_os(UPDATE_VAR).is_some() {
+        fs::write(&path, html).unwrap();
+        return;
+    }
+    match fs::read_to_string(&path) {
+        Ok(expected) => {
+            if expected == html {
+                return;
+            }
+            let (expected_lines, actual_lines): (Vec<_>, Vec<_>) =
+                (expected.lines().collect(), html.lines().collect());
+            let line = expected_lines
+                .iter()
+                .zip(actual_lines.iter())
+                .position(|(a, b)| a != b)
+                .unwrap_or_else(|| expected_lines.len().min(actual_lines.len()));
+            panic!(
+                "{} differs from the rendered page at line {}:\n\
+                 expected: {:?}\n  actual: {:?}\n\
+                 Run the tests with {}=1 to update it if the change is intended",
+                name,
+                line + 1,
+                expected_lines.get(line),
+                actual_lines.get(line),
+                UPDATE_VAR
+            );
+        }
+        Err(err) => panic!(
+            "Could not read {}: {}\n\
+             Run the tests with {}=1 to write it",
+            path.display(),
+            err,
+            UPDATE_VAR
+        ),
+    }
+}
+
+#[test]
+fn render_document() {
+    let (status, html) = get(wiki(), "/index.html");
+    assert_eq!(status, StatusCode::OK);
+    assert_golden("document.html", &html);
+}
+
+#[test]
+fn render_missing_document() {
+    let (status, html) = get(wiki(), "/missing.html");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_golden("new_document.html", &html);
+}
+
+#[test]
+fn render_editor() {
+    let (status, html) = get(wiki(), "/index.html?action=edit");
+    assert_eq!(status, StatusCode::OK);
+    assert_golden("editor.html", &html);
+}
+
+#[test]
+fn render_editor_for_missing_document() {
+    let (status, html) = get(wiki(), "/missing.html?action=edit");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_golden("editor_new_document.html", &html);
+}
+
+#[test]
+fn render_not_found_error() {
+    let (status, html) = get(wiki(), "/_missing");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_golden("404.html", &html);
+}
+
+#[test]
+fn render_forbidden_error() {
+    let acl = Acl::parse(
+        "group:staff = alice
+         /** -> * (read, write)
+         /private/** -> group:staff (read, write)",
+    ).unwrap();
+    let (status, html) = get(wiki().with_acl(acl), "/private/plans.html");
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_golden("403.html", &html);
+}
@@ -6,330 +6,694 @@ extern crate futures;
 extern crate hyper;
 extern crate serde;
 extern crate tokio;
-extern crate tokio_tls;
-extern crate tokio_tungstenite;
-extern crate url;
 
 use futures::future::Future;
-use futures::sink::Sink;
 use futures::stream::Stream;
-use hyper::server::Server;
+use hyper::service::Service;
+use hyper::{Body, StatusCode};
 use serde_json::Value;
-use tokio::net::TcpStream;
+use std::path::{Path, PathBuf};
 use tokio::runtime::current_thread::Runtime;
-use tokio_tls::TlsStream;
-use tokio_tungstenite::tungstenite::error::Error;
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::WebSocketStream;
-use url::Url;
 
+use tamawiki::acl::Acl;
+use tamawiki::auth::{StaticTokens, User};
+use tamawiki::document::{Edit, Event, Insert, Join, Operation, Participants, Role};
+use tamawiki::session::message::{
+    ClientEditMessage, ClientMessage, ConnectedMessage, ServerMessage,
+};
 use tamawiki::store::memory::MemoryStore;
+use tamawiki::store::Store;
+use tamawiki::testing::{TestClient, TestServer};
 use tamawiki::TamaWiki;
 
-type WsStream = WebSocketStream<tokio_tungstenite::stream::Stream<TcpStream, TlsStream<TcpStream>>>;
-
-fn read_message(ws: WsStream) -> impl Future<Item = (Option<Message>, WsStream), Error = Error> {
-    ws.into_future()
-        .map(|(msg, rest)| {
-            println!("client received: {:?}", msg);
-            (msg, rest)
-        }).map_err(|err| panic!(err))
-}
-
-fn connect_websocket(url: &str) -> impl Future<Item = WsStream, Error = Error> {
-    let url = Url::parse(url).unwrap();
-    tokio_tungstenite::connect_async(url)
-        .map(|(ws, _)| ws)
-        .map_err(|_| panic!("Could not establish websocket connection"))
-}
-
 #[test]
 fn connect_via_websocket() {
-    let mut rt = Runtime::new().expect("new test runtime");
-
-    // bind to port 0 to get random port assigned by OS
-    let addr = ([127, 0, 0, 1], 0).into();
     let store = memorystore! {
         "index.html" => "Welcome to TamaWiki.\n"
     };
-    let server = Server::bind(&addr).serve(TamaWiki::new(store, "public/dist"));
-
-    // find out which port number we got
-    let port = server.local_addr().port();
-
-    let url = format!("ws://127.0.0.1:{}/index.html", port);
-    let client = connect_websocket(&url)
-        .and_then(read_message)
-        .map(|(msg, _ws)| {
-            assert_eq!(
-                msg.unwrap().into_text().unwrap(),
-                "{\"Connected\":{\"id\":1}}"
-            );
-        });
-
-    rt.spawn(server.map_err(|err| panic!("Server error: {}", err)));
-    rt.block_on(client).unwrap();
+    let server = TestServer::start(TamaWiki::new(store, "public/dist"));
+    let mut client = server.connect("index.html");
+    client.expect(&ServerMessage::Connected(ConnectedMessage {
+        id: 1,
+        version: Some(4),
+        participants: Participants::default(),
+    }));
 }
 
 #[test]
 fn websocket_connections_get_different_ids() {
-    let mut rt = Runtime::new().expect("new test runtime");
-
-    // bind to port 0 to get random port assigned by OS
-    let addr = ([127, 0, 0, 1], 0).into();
     let store = memorystore! {
         "index.html" => "Welcome to TamaWiki.\n"
     };
-    let server = Server::bind(&addr).serve(TamaWiki::new(store, "public/dist"));
-
-    // find out which port number we got
-    let port = server.local_addr().port();
-
-    let url1 = format!("ws://127.0.0.1:{}/index.html", port);
-    let url2 = format!("ws://127.0.0.1:{}/other_page.html", port);
-
-    let client1 = connect_websocket(&url1)
-        .and_then(read_message)
-        .map(|(msg, ws)| {
-            assert_eq!(
-                msg.unwrap().into_text().unwrap(),
-                "{\"Connected\":{\"id\":1}}"
-            );
-            ws
-        });
-
-    let client2 = connect_websocket(&url1)
-        .and_then(read_message)
-        .map(|(msg, ws)| {
-            assert_eq!(
-                msg.unwrap().into_text().unwrap(),
-                "{\"Connected\":{\"id\":2}}"
-            );
-            ws
-        });
-
+    let server = TestServer::start(TamaWiki::new(store, "public/dist"));
+
+    // keep each client connected until all three have joined
+    let mut client1 = server.connect("index.html");
+    client1.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    let mut client2 = server.connect("index.html");
+    client2.expect_json(&json!({
+        "Connected": {"id": 2, "version": 4, "participants": [{"id": 1, "cursor_pos": 0}]}
+    }));
     // client 3 connects to a different page and should also get id=1
-    let client3 = connect_websocket(&url2)
-        .and_then(read_message)
-        .map(|(msg, ws)| {
-            assert_eq!(
-                msg.unwrap().into_text().unwrap(),
-                "{\"Connected\":{\"id\":1}}"
-            );
-            ws
-        });
-
-    rt.spawn(server.map_err(|err| panic!("Server error: {}", err)));
-
-    rt.block_on(
-        client1
-            // thread through the `ws` streams so they don't get dropped
-            // (and therefore close the websocket) before all three
-            // clients are connected.
-            .and_then(|ws1| client2.map(move |ws2| (ws1, ws2)))
-            .join(client3)
-            .map(|_| ()),
-    ).unwrap();
+    let mut client3 = server.connect("other_page.html");
+    client3.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
 }
 
 #[test]
 fn websocket_join_and_leave_notifications() {
-    let mut rt = Runtime::new().expect("new test runtime");
+    let server = TestServer::start(TamaWiki::new(MemoryStore::default(), "public/dist"));
+    let mut client1 = server.connect("index.html?seq=0");
+    client1.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    let mut client2 = server.connect("index.html?seq=1");
+    client2.expect_json(&json!({
+        "Connected": {"id": 2, "version": 4, "participants": [{"id": 1, "cursor_pos": 0}]}
+    }));
+    let mut client3 = server.connect("index.html?seq=2");
+    client3.expect_json(&json!({
+        "Connected": {
+            "id": 3,
+            "version": 4,
+            "participants": [{"id": 1, "cursor_pos": 0}, {"id": 2, "cursor_pos": 0}]
+        }
+    }));
+    client2.expect_json(&json!({
+        "Event": {"client_seq": 0, "seq": 3, "event": {"Join": {"id": 3, "role": "Editor"}}}
+    }));
+
+    // client 2 leaves first, then client 3 once it has seen client 2 go
+    client2.close();
+    client3.expect_json(&json!({
+        "Event": {"client_seq": 0, "seq": 4, "event": {"Leave": {"id": 2}}}
+    }));
+    client3.close();
+
+    // client 1 stays until the end, so sees everyone come and go
+    for event in &[
+        json!({"client_seq": 0, "seq": 2, "event": {"Join": {"id": 2, "role": "Editor"}}}),
+        json!({"client_seq": 0, "seq": 3, "event": {"Join": {"id": 3, "role": "Editor"}}}),
+        json!({"client_seq": 0, "seq": 4, "event": {"Leave": {"id": 2}}}),
+        json!({"client_seq": 0, "seq": 5, "event": {"Leave": {"id": 3}}}),
+    ] {
+        client1.expect_json(&json!({ "Event": event }));
+    }
+}
 
-    // bind to port 0 to get random port assigned by OS
-    let addr = ([127, 0, 0, 1], 0).into();
-    let store = MemoryStore::default();
-    let server = Server::bind(&addr).serve(TamaWiki::new(store, "public/dist"));
-
-    // find out which port number we got
-    let port = server.local_addr().port();
-
-    let client1 = connect_websocket(&format!("ws://127.0.0.1:{}/index.html?seq=0", port));
-    let client2 = connect_websocket(&format!("ws://127.0.0.1:{}/index.html?seq=1", port));
-    let client3 = connect_websocket(&format!("ws://127.0.0.1:{}/index.html?seq=2", port));
-
-    rt.spawn(server.map_err(|err| panic!("Server error: {}", err)));
-
-    rt.block_on(
-        // connect client 1
-        client1
-            .and_then(|ws1| {
-                // then connect client 2
-                client2.map(|ws2| (ws1, ws2))
-            }).and_then(|(ws1, ws2)| {
-                // then connect client 3
-                client3.map(|ws3| (ws1, ws2, ws3))
-            }).and_then(|(ws1, ws2, ws3)| {
-                let msgs1 = ws1.map(Message::into_text).map(Result::unwrap);
-                let msgs2 = ws2.map(Message::into_text).map(Result::unwrap);
-                let msgs3 = ws3.map(Message::into_text).map(Result::unwrap);
-
-                // the disconnect order is dictated by the order the underlying stream is dropped
-                // client2 reads only 3 messages then disconnects first
-                // client3 waits for leave messages from client 2 then disconnects second
-                // client1 waits for leave messages from clients 2 and 3, then disconnects last
-
-                // read desired messages from all streams
-                msgs1
-                    .take(5)
-                    .collect()
-                    .join3(msgs2.take(2).collect(), msgs3.take(2).collect())
-            }).map(|(msgs1, msgs2, msgs3)| {
-                assert_eq!(
-                    msgs1,
-                    vec![
-                        "{\"Connected\":{\"id\":1}}",
-                        "{\"Event\":{\"client_seq\":0,\"seq\":2,\"event\":{\"Join\":{\"id\":2}}}}",
-                        "{\"Event\":{\"client_seq\":0,\"seq\":3,\"event\":{\"Join\":{\"id\":3}}}}",
-                        "{\"Event\":{\"client_seq\":0,\"seq\":4,\"event\":{\"Leave\":{\"id\":2}}}}",
-                        "{\"Event\":{\"client_seq\":0,\"seq\":5,\"event\":{\"Leave\":{\"id\":3}}}}",
+#[test]
+fn websocket_edits() {
+    let server = TestServer::start(TamaWiki::new(MemoryStore::default(), "public/dist"));
+    let mut client1 = server.connect("index.html?seq=0");
+    client1.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    let mut client2 = server.connect("index.html?seq=1");
+    client1.expect_json(&json!({
+        "Event": {
+            "client_seq": 0,
+            "seq": 2,
+            "event": {
+                "Join": {
+                    "id": 2,
+                    "role": "Editor"
+                }
+            }
+        }
+    }));
+    client2.expect_json(&json!({
+        "Connected": {
+            "id": 2,
+            "version": 4,
+            "participants": [{"id": 1, "cursor_pos": 0}]
+        }
+    }));
+    client1.send(&ClientMessage::ClientEdit(ClientEditMessage {
+        client_seq: 1,
+        parent_seq: 2,
+        operations: vec![Operation::Insert(Insert {
+            pos: 0,
+            content: String::from("Hello"),
+        })],
+        author: Some(1),
+    }));
+    client2.expect_json(&json!({
+        "Event": {
+            "seq": 3,
+            "client_seq": 0,
+            "event": {
+                "Edit": {
+                    "author": 1,
+                    "operations": [
+                        {"Insert": {"pos":0, "content": "Hello"}}
                     ]
-                );
-                assert_eq!(
-                    msgs2,
-                    vec![
-                        "{\"Connected\":{\"id\":2}}",
-                        "{\"Event\":{\"client_seq\":0,\"seq\":3,\"event\":{\"Join\":{\"id\":3}}}}",
+                }
+            }
+        }
+    }));
+    client1.send_json(&json!({
+        "ClientEdit": {
+            "client_seq": 2,
+            "parent_seq": 3,
+            "operations": [{"Insert":{"pos":0,"content":"="}}]
+        }
+    }));
+    client2.expect_json(&json!({
+        "Event": {
+            "seq": 4,
+            "client_seq": 0,
+            "event": {
+                "Edit": {
+                    "author": 1,
+                    "operations": [
+                        {"Insert": {"pos":0, "content": "="}}
                     ]
-                );
-                assert_eq!(
-                    msgs3,
-                    vec![
-                        "{\"Connected\":{\"id\":3}}",
-                        "{\"Event\":{\"client_seq\":0,\"seq\":4,\"event\":{\"Leave\":{\"id\":2}}}}",
+                }
+            }
+        }
+    }));
+    client2.send_json(&json!({
+        "ClientEdit": {
+            "client_seq": 1,
+            "parent_seq": 3,
+            "operations": [{"Insert":{"pos":5,"content":", world"}}]
+        }
+    }));
+    client1.expect_json(&json!({
+        "Event": {
+            "seq": 5,
+            "client_seq": 2,
+            "event": {
+                "Edit": {
+                    "author": 2,
+                    "operations": [
+                        {"Insert": {"pos":6, "content": ", world"}}
                     ]
-                );
-            }).map(|_| ()),
-    ).unwrap();
+                }
+            }
+        }
+    }));
 }
 
 #[test]
-fn websocket_edits() {
-    let mut rt = Runtime::new().expect("new test runtime");
+fn websocket_edits_as_another_participant() {
+    let server = TestServer::start(TamaWiki::new(MemoryStore::default(), "public/dist"));
+    let mut client1 = server.connect("index.html?seq=0");
+    client1.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    let mut client2 = server.connect("index.html?seq=1");
+    client1.expect_json(&json!({
+        "Event": {
+            "client_seq": 0,
+            "seq": 2,
+            "event": {"Join": {"id": 2, "role": "Editor"}}
+        }
+    }));
+    client2.expect_json(&json!({
+        "Connected": {
+            "id": 2,
+            "version": 4,
+            "participants": [{"id": 1, "cursor_pos": 0}]
+        }
+    }));
+    let edit = |author| {
+        ClientMessage::ClientEdit(ClientEditMessage {
+            client_seq: 1,
+            parent_seq: 2,
+            operations: vec![Operation::Insert(Insert {
+                pos: 0,
+                content: String::from("Hello"),
+            })],
+            author: Some(author),
+        })
+    };
+    client2.send(&edit(1));
+    client2.expect_json(&json!({
+        "Error": {
+            "code": "InvalidAuthor",
+            "reason": "Participant 2 may not edit as participant 1"
+        }
+    }));
+    // the rejected edit was not written
+    client2.send(&edit(2));
+    client1.expect_json(&json!({
+        "Event": {
+            "seq": 3,
+            "client_seq": 0,
+            "event": {
+                "Edit": {
+                    "author": 2,
+                    "operations": [{"Insert": {"pos": 0, "content": "Hello"}}]
+                }
+            }
+        }
+    }));
+}
 
-    // bind to port 0 to get random port assigned by OS
-    let addr = ([127, 0, 0, 1], 0).into();
-    let store = MemoryStore::default();
-    let server = Server::bind(&addr).serve(TamaWiki::new(store, "public/dist"));
+#[test]
+fn websocket_shutdown_notification() {
+    let server = TestServer::start(TamaWiki::new(MemoryStore::default(), "public/dist"));
+    let mut client = server.connect("index.html");
+    client.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    server.wiki().shutdown();
+    client.expect(&ServerMessage::Shutdown);
+}
 
-    // find out which port number we got
-    let port = server.local_addr().port();
+#[test]
+fn websocket_kick_participant() {
+    let server = TestServer::start(TamaWiki::new(MemoryStore::default(), "public/dist"));
+    let mut client = server.connect("index.html");
+    client.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    assert!(server.wiki().kick(Path::new("index.html"), 1));
+    assert!(!server.wiki().kick(Path::new("missing.html"), 1));
+    client.expect(&ServerMessage::Kicked);
+}
 
-    let client1 = connect_websocket(&format!("ws://127.0.0.1:{}/index.html?seq=0", port));
-    let client2 = connect_websocket(&format!("ws://127.0.0.1:{}/index.html?seq=1", port));
+#[test]
+fn websocket_exclusive_lock() {
+    let server = TestServer::start(TamaWiki::new(MemoryStore::default(), "public/dist"));
+    let mut client1 = server.connect("index.html?seq=0");
+    client1.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    client1.send(&ClientMessage::Lock);
+    client1.expect_json(&json!({"Lock": {"holder": 1}}));
+
+    let mut client2 = server.connect("index.html?seq=1");
+    client2.expect_json(&json!({
+        "Connected": {
+            "id": 2,
+            "version": 4,
+            "participants": [{"id": 1, "cursor_pos": 0}]
+        }
+    }));
+    // participants joining a locked document are told who holds the
+    // lock
+    client2.expect_json(&json!({"Lock": {"holder": 1}}));
+
+    // disconnecting releases the lock
+    client1.close();
+    client2.expect_json(&json!({"Lock": {"holder": null}}));
+}
 
-    rt.spawn(server.map_err(|err| panic!("Server error: {}", err)));
+#[test]
+fn websocket_edits_across_processes() {
+    // two servers sharing the same backing store, as if they were
+    // separate processes using a networked store
+    let store = MemoryStore::default();
+    let server1 = TestServer::start(TamaWiki::new(store.clone(), "public/dist"));
+    let server2 = TestServer::start(TamaWiki::new(store, "public/dist"));
+
+    let mut client1 = server1.connect("index.html?seq=0");
+    client1.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    // participant ids are unique across both servers
+    let mut client2 = server2.connect("index.html?seq=1");
+    client2.expect_json(&json!({
+        "Connected": {
+            "id": 2,
+            "version": 4,
+            "participants": [{"id": 1, "cursor_pos": 0}]
+        }
+    }));
+    client1.expect_json(&json!({
+        "Event": {
+            "client_seq": 0,
+            "seq": 2,
+            "event": {"Join": {"id": 2, "role": "Editor"}}
+        }
+    }));
 
-    let client1_receives = |expected: Value| {
-        |(ws1, ws2)| {
-            read_message(ws1).map(move |(msg, ws1)| {
-                let msg: Value = serde_json::from_str(&msg.unwrap().into_text().unwrap()).unwrap();
-                assert_eq!(msg, expected);
-                (ws1, ws2)
-            })
+    client2.send_json(&json!({
+        "ClientEdit": {
+            "client_seq": 1,
+            "parent_seq": 2,
+            "operations": [{"Insert": {"pos": 0, "content": "Hello"}}]
         }
-    };
+    }));
+    client1.expect_json(&json!({
+        "Event": {
+            "client_seq": 0,
+            "seq": 3,
+            "event": {
+                "Edit": {
+                    "author": 2,
+                    "operations": [{"Insert": {"pos": 0, "content": "Hello"}}]
+                }
+            }
+        }
+    }));
+}
 
-    let client2_receives = |expected: Value| {
-        let expected = expected.to_owned();
-        |(ws1, ws2)| {
-            read_message(ws2).map(move |(msg, ws2)| {
-                let msg: Value = serde_json::from_str(&msg.unwrap().into_text().unwrap()).unwrap();
-                assert_eq!(msg, expected);
-                (ws1, ws2)
-            })
+#[test]
+fn websocket_read_only_participant() {
+    let server = TestServer::start(TamaWiki::new(MemoryStore::default(), "public/dist"));
+    let mut client1 = server.connect("index.html?seq=0");
+    client1.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    let mut client2 = server.connect("index.html?seq=1&role=reader");
+    client2.expect_json(&json!({
+        "Connected": {
+            "id": 2,
+            "version": 4,
+            "participants": [{"id": 1, "cursor_pos": 0}]
         }
-    };
+    }));
+    client1.expect_json(&json!({
+        "Event": {
+            "client_seq": 0,
+            "seq": 2,
+            "event": {"Join": {"id": 2, "role": "Reader"}}
+        }
+    }));
 
-    let client1_sends = |msg: Value| {
-        move |(ws1, ws2): (WsStream, WsStream)| {
-            ws1.send(Message::text(msg.to_string()))
-                .map(move |ws1| (ws1, ws2))
+    client2.send_json(&json!({
+        "ClientEdit": {
+            "client_seq": 1,
+            "parent_seq": 2,
+            "operations": [{"Insert": {"pos": 0, "content": "Hello"}}]
         }
-    };
+    }));
+    client2.expect_json(&json!({
+        "Error": {
+            "code": "PermissionDenied",
+            "reason": "Reader may not make this edit"
+        }
+    }));
+
+    // the reader's edit is discarded, so the next event client1 sees
+    // is the reader leaving
+    client2.close();
+    client1.expect_json(&json!({
+        "Event": {
+            "client_seq": 0,
+            "seq": 3,
+            "event": {"Leave": {"id": 2}}
+        }
+    }));
+}
 
-    let client2_sends = |msg: Value| {
-        move |(ws1, ws2): (WsStream, WsStream)| {
-            ws2.send(Message::text(msg.to_string()))
-                .map(move |ws2| (ws1, ws2))
+#[test]
+fn websocket_invalid_edits() {
+    let server = TestServer::start(TamaWiki::new(MemoryStore::default(), "public/dist"));
+    let mut client = server.connect("index.html?seq=0");
+    client.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    client.send_json(&json!({
+        "ClientEdit": {
+            "client_seq": 1,
+            "parent_seq": 99,
+            "operations": [{"Insert": {"pos": 0, "content": "Hello"}}]
+        }
+    }));
+    client.expect_json(&json!({
+        "Error": {
+            "code": "InvalidParentSeq",
+            "reason": "parent_seq 99 is ahead of the latest event 1"
+        }
+    }));
+    client.send_json(&json!({
+        "ClientEdit": {
+            "client_seq": 1,
+            "parent_seq": 1,
+            "operations": [{"Delete": {"start": 0, "end": 5}}]
+        }
+    }));
+    client.expect_json(&json!({
+        "Error": {
+            "code": "InvalidEdit",
+            "reason": "The operation's area of effect falls outside the document"
         }
+    }));
+}
+
+#[test]
+fn websocket_playback() {
+    let store = memorystore! {
+        "index.html" => "Welcome to TamaWiki.\n"
     };
+    let server = TestServer::start(TamaWiki::new(store, "public/dist"));
+    let mut client = server.connect("index.html?playback=100");
+
+    // spectators are not participants, so the history is replayed
+    // without a Connected message
+    client.expect_json(&json!({
+        "Event": {
+            "client_seq": 0,
+            "seq": 1,
+            "event": {"Join": {"id": 1, "role": "Editor"}}
+        }
+    }));
+    client.expect_json(&json!({
+        "Event": {
+            "client_seq": 0,
+            "seq": 2,
+            "event": {
+                "Edit": {
+                    "author": 1,
+                    "operations": [
+                        {"Insert": {"pos": 0, "content": "Welcome to TamaWiki.\n"}}
+                    ]
+                }
+            }
+        }
+    }));
+    client.expect_json(&json!({
+        "Event": {
+            "client_seq": 0,
+            "seq": 3,
+            "event": {"Leave": {"id": 1}}
+        }
+    }));
+}
+
+#[test]
+fn websocket_authentication() {
+    let mut tokens = StaticTokens::new();
+    tokens.insert(
+        "editor-token",
+        User {
+            id: String::from("bob"),
+            role: Role::Editor,
+        },
+    );
+    tokens.insert(
+        "commenter-token",
+        User {
+            id: String::from("alice"),
+            role: Role::Commenter,
+        },
+    );
+    let wiki = TamaWiki::new(MemoryStore::default(), "public/dist").with_authenticator(tokens);
+    let server = TestServer::start(wiki);
+
+    // connections without valid credentials are refused
+    let url = server.ws_url("index.html");
+    assert!(TestClient::connect(&url, None).is_err());
+    assert!(TestClient::connect(&url, Some("guess")).is_err());
+
+    let mut client1 = server.connect_as("index.html", "editor-token");
+    client1.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    let mut client2 = server.connect_as("index.html", "commenter-token");
+    client2.expect_json(&json!({
+        "Connected": {
+            "id": 2,
+            "version": 4,
+            "participants": [{"id": 1, "cursor_pos": 0, "user": "bob"}]
+        }
+    }));
+    // the Join event records the user, with the role granted to them
+    // by the authenticator
+    client1.expect_json(&json!({
+        "Event": {
+            "client_seq": 0,
+            "seq": 2,
+            "event": {"Join": {"id": 2, "role": "Commenter", "user": "alice"}}
+        }
+    }));
+}
 
-    rt.block_on(
-        // connect client 1
-        client1
-            .and_then(|ws1| {
-                // then connect client 2
-                client2.map(|ws2| (ws1, ws2))
-            }).and_then(client1_receives(json!({"Connected": {"id": 1}})))
-            .and_then(client1_receives(json!({
-                "Event": {
-                    "client_seq": 0,
-                    "seq": 2,
-                    "event": {
-                        "Join": {
-                            "id": 2
-                        }
+#[test]
+fn websocket_catchup_events_are_batched() {
+    let mut rt = Runtime::new().expect("new test runtime");
+    let mut store = MemoryStore::default();
+    let path = PathBuf::from("index.html");
+    let id = rt.block_on(store.next_participant_id(&path)).unwrap();
+    rt.block_on(store.push(
+        path.clone(),
+        Event::Join(Join {
+            id,
+            role: Role::Editor,
+            user: None,
+        }),
+    )).unwrap();
+    rt.block_on(store.push(
+        path.clone(),
+        Event::Edit(Edit {
+            author: id,
+            operations: vec![Operation::Insert(Insert {
+                pos: 0,
+                content: String::from("Hello"),
+            })],
+        }),
+    )).unwrap();
+    let server = TestServer::start(TamaWiki::new(store, "public/dist"));
+
+    let mut client = server.connect("index.html?seq=0");
+    client.expect_json(&json!({
+        "Connected": {"id": 2, "version": 4, "participants": [{"id": 1, "cursor_pos": 5}]}
+    }));
+    // both events the client missed arrive in a single message
+    client.expect_json(&json!({
+        "Events": [
+            {
+                "client_seq": 0,
+                "seq": 1,
+                "event": {"Join": {"id": 1, "role": "Editor"}}
+            },
+            {
+                "client_seq": 0,
+                "seq": 2,
+                "event": {
+                    "Edit": {
+                        "author": 1,
+                        "operations": [{"Insert": {"pos": 0, "content": "Hello"}}]
                     }
                 }
-            }))).and_then(client2_receives(json!({"Connected": {"id": 2}})))
-            .and_then(client1_sends(json!({
+            }
+        ]
+    }));
+}
+
+#[test]
+fn websocket_multiplexed_documents() {
+    let server = TestServer::start(TamaWiki::new(MemoryStore::default(), "public/dist"));
+    let mut client1 = server.connect("_ws");
+    client1.send_json(&json!({"Subscribe": {"path": "a.html"}}));
+    client1.expect_json(&json!({
+        "path": "a.html",
+        "message": {"Connected": {"id": 1, "version": 4}}
+    }));
+    client1.send_json(&json!({"Subscribe": {"path": "/b.html", "seq": 0}}));
+    client1.expect_json(&json!({
+        "path": "/b.html",
+        "message": {"Connected": {"id": 1, "version": 4}}
+    }));
+
+    let mut client2 = server.connect("b.html?seq=1");
+    client2.expect_json(&json!({
+        "Connected": {
+            "id": 2,
+            "version": 4,
+            "participants": [{"id": 1, "cursor_pos": 0}]
+        }
+    }));
+    // only the subscription to b.html sees the new participant
+    client1.expect_json(&json!({
+        "path": "/b.html",
+        "message": {
+            "Event": {
+                "client_seq": 0,
+                "seq": 2,
+                "event": {"Join": {"id": 2, "role": "Editor"}}
+            }
+        }
+    }));
+
+    client1.send_json(&json!({
+        "Message": {
+            "path": "/b.html",
+            "message": {
                 "ClientEdit": {
                     "client_seq": 1,
                     "parent_seq": 2,
-                    "operations": [{"Insert":{"pos":0,"content":"Hello"}}]
-                }
-            }))).and_then(client2_receives(json!({
-                "Event": {
-                    "seq": 3,
-                    "client_seq": 0,
-                    "event": {
-                        "Edit": {
-                            "author": 1,
-                            "operations": [
-                                {"Insert": {"pos":0, "content": "Hello"}}
-                            ]
-                        }
-                    }
-                }
-            }))).and_then(client1_sends(json!({
-                "ClientEdit": {
-                    "client_seq": 2,
-                    "parent_seq": 3,
-                    "operations": [{"Insert":{"pos":0,"content":"="}}]
-                }
-            }))).and_then(client2_receives(json!({
-                "Event": {
-                    "seq": 4,
-                    "client_seq": 0,
-                    "event": {
-                        "Edit": {
-                            "author": 1,
-                            "operations": [
-                                {"Insert": {"pos":0, "content": "="}}
-                            ]
-                        }
-                    }
+                    "operations": [{"Insert": {"pos": 0, "content": "Hello"}}]
                 }
-            }))).and_then(client2_sends(json!({
-                "ClientEdit": {
-                    "client_seq": 1,
-                    "parent_seq": 3,
-                    "operations": [{"Insert":{"pos":5,"content":", world"}}]
-                }
-            }))).and_then(client1_receives(json!({
-                "Event": {
-                    "seq": 5,
-                    "client_seq": 2,
-                    "event": {
-                        "Edit": {
-                            "author": 2,
-                            "operations": [
-                                {"Insert": {"pos":6, "content": ", world"}}
-                            ]
-                        }
-                    }
+            }
+        }
+    }));
+    client2.expect_json(&json!({
+        "Event": {
+            "client_seq": 0,
+            "seq": 3,
+            "event": {
+                "Edit": {
+                    "author": 1,
+                    "operations": [{"Insert": {"pos": 0, "content": "Hello"}}]
                 }
-            }))).map(|_| ()),
+            }
+        }
+    }));
+
+    // messages for documents which are not subscribed are rejected
+    client1.send_json(&json!({"Message": {"path": "c.html", "message": "Lock"}}));
+    client1.expect_json(&json!({
+        "path": "c.html",
+        "message": {
+            "Error": {
+                "code": "InvalidChannel",
+                "reason": "Not subscribed to \"c.html\""
+            }
+        }
+    }));
+}
+
+#[test]
+fn session_metrics_leave_out_unreadable_documents() {
+    let mut tokens = StaticTokens::new();
+    tokens.insert(
+        "alice-token",
+        User {
+            id: String::from("alice"),
+            role: Role::Editor,
+        },
+    );
+    tokens.insert(
+        "bob-token",
+        User {
+            id: String::from("bob"),
+            role: Role::Editor,
+        },
+    );
+    let acl = Acl::parse(
+        "/** -> * (read, write)
+         /private/** -> user:alice (read, write)",
     ).unwrap();
+    let wiki = TamaWiki::new(MemoryStore::default(), "public/dist")
+        .with_authenticator(tokens)
+        .with_acl(acl);
+    let server = TestServer::start(wiki);
+
+    let mut alice = server.connect_as("private/plans.html", "alice-token");
+    alice.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+    let mut bob = server.connect_as("index.html", "bob-token");
+    bob.expect_json(&json!({"Connected": {"id": 1, "version": 4}}));
+
+    let metrics = |token: Option<&str>| {
+        let mut request = hyper::Request::get("/_metrics");
+        if let Some(token) = token {
+            request.header("authorization", format!("Bearer {}", token));
+        }
+        let response = server
+            .wiki()
+            .clone()
+            .call(request.body(Body::empty()).unwrap())
+            .wait()
+            .unwrap();
+        let status = response.status();
+        let body = response.into_body().concat2().wait().unwrap();
+        let sessions = match serde_json::from_slice::<Value>(&body) {
+            Ok(Value::Object(mut body)) => match body.remove("sessions") {
+                Some(Value::Object(sessions)) => sessions.keys().cloned().collect(),
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+        (status, sessions)
+    };
+
+    assert_eq!(
+        metrics(Some("alice-token")),
+        (
+            StatusCode::OK,
+            vec![String::from("index.html"), String::from("private/plans.html")]
+        )
+    );
+    assert_eq!(
+        metrics(Some("bob-token")),
+        (StatusCode::OK, vec![String::from("index.html")])
+    );
+    assert_eq!(metrics(None).0, StatusCode::UNAUTHORIZED);
+
+    alice.close();
+    bob.close();
 }